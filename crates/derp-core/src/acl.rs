@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::protocol::{hex_encode, PeerKey};
+
+/// Times one peer's traffic was refused.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct PeerDenials {
+    /// Hex, as in "peer-present" events.
+    pub peer: String,
+    /// Announcements and packets from the peer that were dropped.
+    pub inbound: u64,
+    /// Packets addressed to the peer that were refused.
+    pub outbound: u64,
+}
+
+/// Returned by `DerpNetwork.getPeerDenials`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct AclStats {
+    pub peers: Vec<PeerDenials>,
+}
+
+/// Which peers may exchange traffic with this one, when
+/// `DerpConfig.allowedPeers` is set.
+///
+/// Packets are attributed by the sender's key the relay puts on each
+/// RecvFromPeer frame, never by addresses inside them, which the sender
+/// chooses.
+#[derive(Debug, Default)]
+pub struct PeerAcl {
+    allowed: Option<HashSet<PeerKey>>,
+    /// Inbound and outbound refusals per peer.
+    denials: HashMap<PeerKey, (u64, u64)>,
+}
+
+impl PeerAcl {
+    /// None allows every peer.
+    pub fn new(allowed: Option<HashSet<PeerKey>>) -> Self {
+        PeerAcl { allowed, ..PeerAcl::default() }
+    }
+
+    /// Replaces the allowlist. Counters are kept.
+    pub fn set_allowed(&mut self, allowed: Option<HashSet<PeerKey>>) {
+        self.allowed = allowed;
+    }
+
+    pub fn allows(&self, peer: &PeerKey) -> bool {
+        self.allowed.as_ref().is_none_or(|allowed| allowed.contains(peer))
+    }
+
+    /// Whether a packet from `sender` may be delivered; refusals are
+    /// counted against it.
+    pub fn admit(&mut self, sender: &PeerKey) -> bool {
+        if self.allows(sender) {
+            return true;
+        }
+        self.deny_inbound(sender);
+        false
+    }
+
+    pub fn deny_inbound(&mut self, peer: &PeerKey) {
+        self.denials.entry(*peer).or_default().0 += 1;
+    }
+
+    pub fn deny_outbound(&mut self, peer: &PeerKey) {
+        self.denials.entry(*peer).or_default().1 += 1;
+    }
+
+    /// Refusals so far, by peer key.
+    pub fn stats(&self) -> AclStats {
+        let mut peers: Vec<PeerDenials> = self.denials.iter()
+            .map(|(peer, &(inbound, outbound))| PeerDenials { peer: hex_encode(peer), inbound, outbound })
+            .collect();
+        peers.sort_by(|a, b| a.peer.cmp(&b.peer));
+        AclStats { peers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: PeerKey = [1; 32];
+    const MALLORY: PeerKey = [3; 32];
+
+    #[test]
+    fn test_admits_allowed_senders_only() {
+        let mut acl = PeerAcl::new(None);
+        assert!(acl.admit(&MALLORY));
+
+        acl.set_allowed(Some(HashSet::from([ALICE])));
+        assert!(acl.admit(&ALICE));
+        assert!(!acl.admit(&MALLORY));
+        assert!(!acl.admit(&MALLORY));
+
+        let stats = acl.stats();
+        assert_eq!(stats.peers, vec![PeerDenials { peer: hex_encode(&MALLORY), inbound: 2, outbound: 0 }]);
+    }
+
+    #[test]
+    fn test_counts_per_peer() {
+        let mut acl = PeerAcl::new(Some(HashSet::new()));
+        assert!(!acl.allows(&ALICE));
+        acl.deny_outbound(&MALLORY);
+        acl.deny_outbound(&MALLORY);
+        acl.deny_inbound(&ALICE);
+
+        let stats = acl.stats();
+        assert_eq!(stats.peers.len(), 2);
+        assert_eq!((stats.peers[0].inbound, stats.peers[0].outbound), (1, 0));
+        assert_eq!((stats.peers[1].inbound, stats.peers[1].outbound), (0, 2));
+    }
+}
@@ -0,0 +1,132 @@
+/// Shorter intervals are left to grow, since timer and `bufferedAmount`
+/// granularity would swamp them.
+pub const MIN_SAMPLE_MS: f64 = 20.0;
+/// Weight of each new sample in the smoothed estimate.
+const GAIN: f64 = 0.25;
+/// How long a batch should take to go out at the estimated rate. Longer
+/// batches hold up the interactive packets queued behind them.
+pub const BATCH_TARGET_MS: f64 = 10.0;
+/// Batches are never held below this, however slow the link.
+pub const MIN_BATCH_SIZE: usize = 4096;
+
+/// Estimates a socket's upload bandwidth from how fast its send buffer
+/// drains. Browsers don't say when bytes leave, but `bufferedAmount`
+/// counts what's still waiting, so everything handed to the socket minus
+/// that has gone. The rate only says anything while the buffer stays
+/// non-empty: otherwise the link was waiting on the sender, not the other
+/// way round, so idle stretches are skipped.
+#[derive(Debug, Default, Clone)]
+pub struct BandwidthEstimator {
+    /// Bytes handed to the socket.
+    written: u64,
+    /// When the current busy interval started, and what had gone by then.
+    mark: Option<(f64, u64)>,
+    /// Smoothed, in bits per second.
+    estimate: Option<f64>,
+    samples: u32,
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> Self {
+        BandwidthEstimator::default()
+    }
+
+    /// Notes the socket's `buffered` bytes at `now_ms`, before `len` more
+    /// are sent. Pass 0 for `len` when only polling.
+    pub fn on_send(&mut self, now_ms: f64, buffered: usize, len: usize) {
+        self.observe(now_ms, buffered);
+        self.written += len as u64;
+        // A send into an empty buffer starts a busy interval
+        if self.mark.is_none() && len > 0 {
+            self.mark = Some((now_ms, self.written.saturating_sub((buffered + len) as u64)));
+        }
+    }
+
+    fn observe(&mut self, now_ms: f64, buffered: usize) {
+        let delivered = self.written.saturating_sub(buffered as u64);
+        if buffered == 0 {
+            // Drained at some point since the mark, so the interval's
+            // length overstates how long the bytes took
+            self.mark = None;
+            return;
+        }
+        match self.mark {
+            Some((at, from)) if now_ms - at >= MIN_SAMPLE_MS => {
+                let rate = delivered.saturating_sub(from) as f64 * 8000.0 / (now_ms - at);
+                self.estimate = Some(match self.estimate {
+                    Some(estimate) => estimate + GAIN * (rate - estimate),
+                    None => rate,
+                });
+                self.samples += 1;
+                self.mark = Some((now_ms, delivered));
+            }
+            Some(_) => {}
+            None => self.mark = Some((now_ms, delivered)),
+        }
+    }
+
+    /// Kilobits per second, once the link has been busy long enough to
+    /// measure.
+    pub fn estimate_kbps(&self) -> Option<u32> {
+        self.estimate.map(|bps| (bps / 1000.0).round().clamp(1.0, u32::MAX as f64) as u32)
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// How large a batch may grow: what the link carries in
+    /// `BATCH_TARGET_MS`, within `MIN_BATCH_SIZE..=max`. `max` until
+    /// there's an estimate.
+    pub fn batch_limit(&self, max: usize) -> usize {
+        match self.estimate {
+            Some(bps) => ((bps / 8000.0 * BATCH_TARGET_MS) as usize).clamp(MIN_BATCH_SIZE.min(max), max),
+            None => max,
+        }
+    }
+
+    /// Forgets the link, for when the socket is replaced.
+    pub fn reset(&mut self) {
+        *self = BandwidthEstimator::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measures_a_busy_link() {
+        let mut estimator = BandwidthEstimator::new();
+        assert_eq!(estimator.estimate_kbps(), None);
+        assert_eq!(estimator.batch_limit(65536), 65536);
+
+        // 1 Mbit/s: 125 bytes leave the buffer each millisecond
+        estimator.on_send(0.0, 0, 100_000);
+        estimator.on_send(40.0, 100_000 - 5000, 0);
+        assert_eq!(estimator.estimate_kbps(), Some(1000));
+        estimator.on_send(80.0, 100_000 - 10_000, 10_000);
+        estimator.on_send(120.0, 100_000 - 5000, 0);
+        assert_eq!(estimator.estimate_kbps(), Some(1000));
+        assert_eq!(estimator.samples(), 3);
+        assert_eq!(estimator.batch_limit(65536), MIN_BATCH_SIZE);
+
+        estimator.reset();
+        assert_eq!(estimator.estimate_kbps(), None);
+    }
+
+    #[test]
+    fn test_idle_time_isnt_counted() {
+        let mut estimator = BandwidthEstimator::new();
+        // Drained long before the next look, so nothing can be told
+        estimator.on_send(0.0, 0, 1000);
+        estimator.on_send(1000.0, 0, 1000);
+        assert_eq!(estimator.estimate_kbps(), None);
+
+        // Too short an interval waits for more
+        estimator.on_send(1005.0, 500, 0);
+        assert_eq!(estimator.estimate_kbps(), None);
+        estimator.on_send(1020.0, 250, 0);
+        assert_eq!(estimator.estimate_kbps(), Some(300));
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// Failures kept for the "connection-failed" event; older ones are dropped.
+pub const MAX_FAILURE_HISTORY: usize = 16;
+
+/// How one connection ended, as "connection-failed" lists it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct FailureRecord {
+    /// On the page's `performance.now()` clock.
+    pub at_ms: f64,
+    /// The WebSocket close code, as in "disconnect".
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Payload of the "connection-failed" event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionFailedEvent {
+    /// Reconnects tried since the connection was last up.
+    pub attempts: u32,
+    /// Oldest first.
+    pub failures: Vec<FailureRecord>,
+    /// When the next attempt is made on its own, or null if only
+    /// `retryNow` will try again.
+    pub retry_in_ms: Option<u32>,
+}
+
+/// Payload of the "reconnected" event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectedEvent {
+    /// Reconnects since the connection was last stable.
+    pub attempts: u32,
+    /// From the first close of the outage until the handshake completed.
+    pub downtime_ms: f64,
+    /// The close code and reason that started the outage.
+    pub code: u16,
+    pub reason: String,
+}
+
+/// The reconnect budget. Each outage gets `maxReconnectAttempts`; once
+/// they're spent the breaker opens and nothing more is tried until the
+/// cool-down passes or `retryNow` is called. A connection that drops
+/// before it was up for `stable_ms` doesn't end the outage, so a relay
+/// that accepts and then closes can't reconnect forever.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    attempts: u32,
+    failures: VecDeque<FailureRecord>,
+    open: bool,
+    stable_ms: u32,
+    /// When the current connection came up, if it's up.
+    connected_at_ms: Option<f64>,
+    /// How often the breaker has opened, so a cool-down that outlived its
+    /// outage can tell.
+    trips: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(stable_ms: u32) -> Self {
+        CircuitBreaker { stable_ms, ..CircuitBreaker::default() }
+    }
+
+    pub fn record_failure(&mut self, failure: FailureRecord) {
+        if let Some(connected_at_ms) = self.connected_at_ms.take() {
+            if failure.at_ms - connected_at_ms >= self.stable_ms as f64 {
+                self.attempts = 0;
+            }
+        }
+        if self.failures.len() == MAX_FAILURE_HISTORY {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(failure);
+    }
+
+    /// Spends a reconnect from a budget of `max_attempts`, returning its
+    /// 1-based number, or None once the budget is spent.
+    pub fn next_attempt(&mut self, max_attempts: u32) -> Option<u32> {
+        if self.open || self.attempts >= max_attempts {
+            return None;
+        }
+        self.attempts += 1;
+        Some(self.attempts)
+    }
+
+    /// Opens the breaker, returning what to report. The next automatic
+    /// attempt is `cooldown_ms` away, or never if that's zero.
+    pub fn trip(&mut self, cooldown_ms: u32) -> ConnectionFailedEvent {
+        self.open = true;
+        self.trips += 1;
+        ConnectionFailedEvent {
+            attempts: self.attempts,
+            failures: self.failures.iter().cloned().collect(),
+            retry_in_ms: (cooldown_ms > 0).then_some(cooldown_ms),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn trips(&self) -> u32 {
+        self.trips
+    }
+
+    /// Closes the breaker once connected, returning what to report if the
+    /// connection came back from an outage. Only the first call after an
+    /// outage returns anything. The attempts spent are kept until the
+    /// connection has stayed up for `stable_ms`.
+    pub fn recover(&mut self, now_ms: f64) -> Option<ReconnectedEvent> {
+        let event = self.failures.front().map(|first| ReconnectedEvent {
+            attempts: self.attempts,
+            downtime_ms: (now_ms - first.at_ms).max(0.0),
+            code: first.code,
+            reason: first.reason.clone(),
+        });
+        self.failures.clear();
+        self.open = false;
+        self.connected_at_ms.get_or_insert(now_ms);
+        event
+    }
+
+    /// Closes the breaker with a fresh budget, once connected or when a
+    /// retry is asked for.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.failures.clear();
+        self.open = false;
+    }
+}
+
+/// The wait before reconnect `attempt`, counting from 1: `base_ms` for the
+/// first, doubled for each after up to `max_ms`, then moved by up to `jitter` of itself
+/// either way, `random` being uniform in [0, 1), so clients dropped
+/// together don't all come back at once.
+pub fn backoff_ms(attempt: u32, base_ms: u32, max_ms: u32, jitter: f64, random: f64) -> u32 {
+    let delay = (u64::from(base_ms) << attempt.saturating_sub(1).min(32)).min(u64::from(max_ms)) as f64;
+    (delay * (1.0 + jitter * (2.0 * random - 1.0))).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(code: u16) -> FailureRecord {
+        FailureRecord { at_ms: code as f64, code, reason: String::new() }
+    }
+
+    #[test]
+    fn test_budget_is_spent_then_reset() {
+        let mut breaker = CircuitBreaker::default();
+        assert_eq!(breaker.next_attempt(2), Some(1));
+        assert_eq!(breaker.next_attempt(2), Some(2));
+        assert_eq!(breaker.next_attempt(2), None);
+
+        let event = breaker.trip(30_000);
+        assert_eq!((event.attempts, event.retry_in_ms), (2, Some(30_000)));
+        assert!(breaker.is_open());
+        assert_eq!(breaker.next_attempt(u32::MAX), None);
+
+        breaker.reset();
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.next_attempt(2), Some(1));
+        assert_eq!((breaker.trip(0).retry_in_ms, breaker.trips()), (None, 2));
+    }
+
+    #[test]
+    fn test_recovery_is_reported_once() {
+        let mut breaker = CircuitBreaker::default();
+        assert_eq!(breaker.recover(0.0), None);
+
+        breaker.record_failure(FailureRecord { at_ms: 100.0, code: 1012, reason: "restarting".into() });
+        breaker.next_attempt(5);
+        breaker.record_failure(failure(1006));
+        breaker.next_attempt(5);
+        let event = breaker.recover(2100.0).unwrap();
+        assert_eq!((event.attempts, event.downtime_ms), (2, 2000.0));
+        assert_eq!((event.code, event.reason.as_str()), (1012, "restarting"));
+        assert_eq!(breaker.recover(2200.0), None);
+    }
+
+    #[test]
+    fn test_attempts_reset_once_stable() {
+        let mut breaker = CircuitBreaker::new(10_000);
+        breaker.record_failure(failure(0));
+        assert_eq!(breaker.next_attempt(3), Some(1));
+
+        // Up briefly: the outage goes on
+        breaker.recover(1000.0);
+        breaker.record_failure(FailureRecord { at_ms: 2000.0, code: 1006, reason: String::new() });
+        assert_eq!(breaker.next_attempt(3), Some(2));
+
+        breaker.recover(3000.0);
+        breaker.recover(5000.0);
+        breaker.record_failure(FailureRecord { at_ms: 13_000.0, code: 1006, reason: String::new() });
+        assert_eq!(breaker.next_attempt(3), Some(1));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff_ms(1, 1000, 30_000, 0.0, 0.5), 1000);
+        assert_eq!(backoff_ms(3, 1000, 30_000, 0.0, 0.9), 4000);
+        assert_eq!(backoff_ms(40, 1000, 30_000, 0.0, 0.0), 30_000);
+        assert_eq!(backoff_ms(1, 1000, 30_000, 0.5, 0.0), 500);
+        assert_eq!(backoff_ms(1, 1000, 30_000, 0.5, 0.5), 1000);
+        assert!(backoff_ms(1, 1000, 30_000, 0.5, 0.999) <= 1500);
+    }
+
+    #[test]
+    fn test_history_keeps_the_latest_failures() {
+        let mut breaker = CircuitBreaker::default();
+        for code in 0..MAX_FAILURE_HISTORY as u16 + 3 {
+            breaker.record_failure(failure(code));
+        }
+        let failures = breaker.trip(0).failures;
+        assert_eq!(failures.len(), MAX_FAILURE_HISTORY);
+        assert_eq!(failures[0], failure(3));
+        assert_eq!(failures.last(), Some(&failure(MAX_FAILURE_HISTORY as u16 + 2)));
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::OnceLock;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+// `performance` is a global in both windows and workers, like the timers.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    fn performance_now() -> f64;
+}
+
+/// Where the protocol and network read the time from, in milliseconds.
+/// Only differences between readings mean anything.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> f64;
+}
+
+/// Monotonic time from `performance.now()`, so keepalives and timeouts
+/// aren't thrown off when the wall clock is adjusted. Native builds, like
+/// the benches, count from the first reading instead.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now_ms(&self) -> f64 {
+        performance_now()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_ms(&self) -> f64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// A clock that only moves when told to, for tests.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    // The f64's bits, so the clock can be shared like `SystemClock`
+    now_ms: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now_ms: f64) -> Self {
+        MockClock { now_ms: AtomicU64::new(now_ms.to_bits()) }
+    }
+
+    pub fn set(&self, now_ms: f64) {
+        self.now_ms.store(now_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, ms: f64) {
+        self.set(self.now_ms() + ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> f64 {
+        f64::from_bits(self.now_ms.load(Ordering::Relaxed))
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock_moves_forward() {
+        let first = SystemClock.now_ms();
+        assert!(first >= 0.0);
+        assert!(SystemClock.now_ms() >= first);
+    }
+
+    #[test]
+    fn test_mock_clock_moves_when_told() {
+        let clock = MockClock::new(100.0);
+        assert_eq!(clock.now_ms(), 100.0);
+        clock.advance(250.5);
+        assert_eq!(clock.now_ms(), 350.5);
+        clock.set(0.0);
+        assert_eq!(clock.now_ms(), 0.0);
+    }
+}
@@ -0,0 +1,787 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::derpmap::{DerpMap, Region};
+use crate::driver::ReconnectPolicy;
+use crate::error::{DerpError, DerpResult};
+use crate::ip;
+use crate::mdns;
+use crate::padding::PaddingPolicy;
+use crate::protocol::{hex_decode_key, PeerKey};
+
+pub const DEFAULT_MTU: u16 = 1500;
+pub const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 5;
+pub const DEFAULT_RECONNECT_DELAY_MS: u32 = 1000;
+pub const DEFAULT_RECONNECT_COOLDOWN_MS: u32 = 60_000;
+pub const DEFAULT_MAX_RECONNECT_DELAY_MS: u32 = 30_000;
+pub const DEFAULT_STABLE_CONNECTION_MS: u32 = 10_000;
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+pub const DEFAULT_KEEPALIVE_INTERVAL_MS: u32 = 60_000;
+pub const DEFAULT_MISSED_KEEPALIVES: u32 = 3;
+pub const DEFAULT_RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+pub const DEFAULT_SEND_QUEUE_SIZE: usize = 64;
+pub const DEFAULT_RECEIVE_QUEUE_SIZE: usize = 64;
+pub const DEFAULT_SEND_HIGH_WATERMARK: usize = 1024 * 1024;
+pub const DEFAULT_SEND_QUEUE_BYTES: usize = 1024 * 1024;
+pub const DEFAULT_RECONNECT_QUEUE_SIZE: usize = 64;
+pub const DEFAULT_SHUTDOWN_TIMEOUT_MS: u32 = 5000;
+pub const DEFAULT_NETCHECK_INTERVAL_MS: u32 = 5 * 60_000;
+pub const DEFAULT_CONTROL_INTERVAL_MS: u32 = 60_000;
+pub const DEFAULT_IDLE_TIMEOUT_MS: u32 = 5 * 60_000;
+pub const DEFAULT_MAX_FORWARD_HOPS: u8 = 3;
+/// Same router address v86's other network adapters default to.
+pub const DEFAULT_GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 1);
+pub const DEFAULT_GUEST_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 100);
+pub const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+pub const DEFAULT_IPV6_PREFIX: Ipv6Addr = Ipv6Addr::new(0xfd86, 0x86, 0, 0, 0, 0, 0, 0);
+pub const DEFAULT_MDNS_HOSTNAME: &str = "host.local";
+pub const DEFAULT_DOH_ENDPOINT: &str = "https://cloudflare-dns.com/dns-query";
+
+const MIN_MTU: u16 = 576;
+const MAX_MTU: u16 = 9000;
+
+/// What happens to packets sent while the socket holds more than
+/// `sendHighWatermark` bytes it hasn't yet sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "lowercase")]
+pub enum BackpressurePolicy {
+    /// Held back, up to `sendQueueBytes`, and sent once the socket drains.
+    #[default]
+    Queue,
+    /// Discarded, as a congested link would.
+    Drop,
+}
+
+/// A SOCKS5 proxy (RFC 1928) reached through a WebSocket-to-TCP bridge such
+/// as websockify, for guest TCP that fetch() can't carry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct SocksConfig {
+    /// ws:// or wss:// URL of the bridge; each guest connection opens its own.
+    pub url: String,
+    /// Username/password authentication (RFC 1929), offered when both are set.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub username: Option<String>,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub password: Option<String>,
+}
+
+impl SocksConfig {
+    /// The username and password, if both are set.
+    pub fn credentials(&self) -> Option<(String, String)> {
+        Some((self.username.clone()?, self.password.clone()?))
+    }
+}
+
+/// Tunables for a `DerpNetwork` instance. Deserializes from a JS object with
+/// camelCase keys; missing keys take their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(default, rename_all = "camelCase")]
+pub struct DerpConfig {
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub mtu: u16,
+    /// Reconnects per outage before giving up until the cool-down. Null
+    /// never gives up.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "number | null"))]
+    pub max_reconnect_attempts: Option<u32>,
+    /// The first reconnect waits this long, and each further one twice
+    /// as long as the last, up to `maxReconnectDelayMs`.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub reconnect_delay_ms: u32,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_reconnect_delay_ms: u32,
+    /// Fraction, from 0 to 1, by which each reconnect delay is randomly
+    /// lengthened or shortened.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub reconnect_jitter: f64,
+    /// How long a connection has to stay up to end its outage, restoring
+    /// the full `maxReconnectAttempts`. Zero restores them on connect.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub stable_connection_ms: u32,
+    /// How long to wait, once `maxReconnectAttempts` are spent, before
+    /// trying again. Zero waits for `retryNow`.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub reconnect_cooldown_ms: u32,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub compression: bool,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub compression_threshold: usize,
+    /// Overrides the keepalive interval advertised by the server.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub keepalive_interval_ms: Option<u32>,
+    /// Keepalive intervals the relay may stay silent before the connection
+    /// is presumed dead and reopened. Zero waits for the browser to notice.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub missed_keepalives: u32,
+    /// Upper bound on a decoded (decompressed) packet.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub receive_buffer_size: usize,
+    /// Packets a `writable()` stream may queue before writers see backpressure.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub send_queue_size: usize,
+    /// Packets a `readable()` stream buffers before further ones are dropped.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub receive_queue_size: usize,
+    /// Offer to coalesce packets sent within one microtask into a single
+    /// WebSocket message. Only used if the server supports it.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub batching: bool,
+    /// End unencrypted frames, the handshake among them, in a CRC32 so
+    /// corruption shows up as such rather than as a decoding error.
+    /// Receivers verify any frame that's flagged as carrying one.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub control_checksums: bool,
+    /// Offer CBOR for control messages, which unlike bincode lets either
+    /// side add fields. Only used if the server supports it.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub cbor: bool,
+    /// Offer AES-GCM-SIV for relay frames, so a nonce repeated after a VM
+    /// snapshot is restored can't leak plaintext. Frames use AES-GCM unless
+    /// the relay agrees. Needs the siv feature.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub aes_gcm_siv: bool,
+    /// Split IPv4 packets over the relay's `max_packet_size` into fragments
+    /// rather than refusing them. Packets marked Don't Fragment are still
+    /// refused.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub fragment_oversized: bool,
+    /// Pad encrypted frames so their sizes say less about guest traffic.
+    /// Only used if the server supports it.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub padding: PaddingPolicy,
+    /// Unsent bytes the socket may buffer before `backpressure` applies.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub send_high_watermark: usize,
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub backpressure: BackpressurePolicy,
+    /// With the "queue" policy, packets beyond this many held-back bytes
+    /// are dropped.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub send_queue_bytes: usize,
+    /// Packets held while reconnecting and sent once the new handshake
+    /// completes. Zero fails such sends instead.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub reconnect_queue_size: usize,
+    /// Have `connect` only note the relay, opening the socket when the
+    /// first packet is sent. Packets sent meanwhile wait in the
+    /// `reconnectQueueSize` queue.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub lazy_connect: bool,
+    /// With `lazyConnect`, how long the connection may go without packets
+    /// before it's closed, to be reopened by the next send. Zero keeps it
+    /// open.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub idle_timeout_ms: u32,
+    /// How long `shutdown` waits for unsent bytes to drain before closing
+    /// regardless.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub shutdown_timeout_ms: u32,
+    /// Times a packet may be forwarded between relays by mesh nodes such
+    /// as `bridge` before it's dropped.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_forward_hops: u8,
+    /// Address of the virtual gateway the guest talks to, e.g. "192.168.86.1".
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string"))]
+    pub gateway_ip: Ipv4Addr,
+    /// Address handed to the guest over DHCP.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string"))]
+    pub guest_ip: Ipv4Addr,
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string"))]
+    pub netmask: Ipv4Addr,
+    /// DNS servers handed to the guest over DHCP. Empty means the gateway,
+    /// which answers through `dohEndpoint`.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string[]"))]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// DNS-over-HTTPS resolver for guest queries on UDP port 53. Null leaves
+    /// DNS to the relay.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub doh_endpoint: Option<String>,
+    /// /64 prefix advertised to the guest for SLAAC. Null leaves the guest
+    /// with only its link-local address.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string | null"))]
+    pub ipv6_prefix: Option<Ipv6Addr>,
+    /// DNS servers offered to the guest in router advertisements and over
+    /// DHCPv6. Empty means the gateway's address in `ipv6Prefix`, which
+    /// answers through `dohEndpoint`.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string[]"))]
+    pub ipv6_dns_servers: Vec<Ipv6Addr>,
+    /// Answer stateless DHCPv6 Information-Requests, and have router
+    /// advertisements tell the guest to send them, for guests that don't
+    /// take DNS servers from the advertisement itself.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub dhcpv6: bool,
+    /// Terminate guest TCP/UDP in a userspace stack and NAT it out through
+    /// backends, instead of forwarding raw IP to the relay.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub nat: bool,
+    /// In NAT mode, destinations (CIDR notation) whose flows go to the relay.
+    /// Empty sends every flow no other backend claims.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub relay_routes: Vec<String>,
+    /// In NAT mode, carry guest HTTP on port 80 over the browser's fetch().
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub fetch_egress: bool,
+    /// Address of an HTTP server inside the virtual network that serves the
+    /// files added with `addHttpFile`, for `wget http://<address>/<name>` in
+    /// the guest. Works with or without `nat`. Null turns it off.
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "string | null"))]
+    pub file_server_ip: Option<Ipv4Addr>,
+    /// CORS proxy the target URL is appended to, e.g. "https://proxy.example/?url=".
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub fetch_proxy: Option<String>,
+    /// Fetch guest http:// requests over HTTPS, which pages served over
+    /// HTTPS require.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub fetch_upgrade_https: bool,
+    /// In NAT mode, a SOCKS5 proxy behind a WebSocket bridge that carries
+    /// guest TCP the fetch backend doesn't, ahead of the relay. Null turns
+    /// it off.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub socks: Option<SocksConfig>,
+    /// Name in the .local domain the gateway answers multicast DNS queries
+    /// for. Null turns the responder off.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub mdns_hostname: Option<String>,
+    /// Relays `connectHome` chooses between by latency.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub regions: Vec<Region>,
+    /// A Tailscale DERPMap, parsed from its JSON, whose regions join
+    /// `regions`.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub derp_map: Option<DerpMap>,
+    /// How often `connectHome` re-probes the regions. Zero probes only once.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub netcheck_interval_ms: u32,
+    /// HTTPS endpoint of a coordination server, whose `ControlDocument`
+    /// replaces `derpMap` and routes guest packets for peers' virtual
+    /// addresses to those peers alone. Null turns it off.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub control_url: Option<String>,
+    /// How often the control document is fetched again. Zero fetches it
+    /// only once.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub control_interval_ms: u32,
+    /// Hex keys of the only peers whose traffic is delivered to the VMs and
+    /// who may be sent to directly. Null allows every peer on the relay.
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub allowed_peers: Option<Vec<String>>,
+}
+
+impl Default for DerpConfig {
+    fn default() -> Self {
+        DerpConfig {
+            mtu: DEFAULT_MTU,
+            max_reconnect_attempts: Some(DEFAULT_MAX_RECONNECT_ATTEMPTS),
+            reconnect_delay_ms: DEFAULT_RECONNECT_DELAY_MS,
+            max_reconnect_delay_ms: DEFAULT_MAX_RECONNECT_DELAY_MS,
+            reconnect_jitter: 0.0,
+            stable_connection_ms: DEFAULT_STABLE_CONNECTION_MS,
+            reconnect_cooldown_ms: DEFAULT_RECONNECT_COOLDOWN_MS,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            keepalive_interval_ms: None,
+            missed_keepalives: DEFAULT_MISSED_KEEPALIVES,
+            receive_buffer_size: DEFAULT_RECEIVE_BUFFER_SIZE,
+            send_queue_size: DEFAULT_SEND_QUEUE_SIZE,
+            receive_queue_size: DEFAULT_RECEIVE_QUEUE_SIZE,
+            batching: false,
+            control_checksums: false,
+            cbor: false,
+            aes_gcm_siv: false,
+            fragment_oversized: false,
+            padding: PaddingPolicy::Off,
+            send_high_watermark: DEFAULT_SEND_HIGH_WATERMARK,
+            backpressure: BackpressurePolicy::Queue,
+            send_queue_bytes: DEFAULT_SEND_QUEUE_BYTES,
+            reconnect_queue_size: DEFAULT_RECONNECT_QUEUE_SIZE,
+            lazy_connect: false,
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT_MS,
+            shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS,
+            max_forward_hops: DEFAULT_MAX_FORWARD_HOPS,
+            gateway_ip: DEFAULT_GATEWAY_IP,
+            guest_ip: DEFAULT_GUEST_IP,
+            netmask: DEFAULT_NETMASK,
+            dns_servers: Vec::new(),
+            doh_endpoint: Some(DEFAULT_DOH_ENDPOINT.to_string()),
+            ipv6_prefix: Some(DEFAULT_IPV6_PREFIX),
+            ipv6_dns_servers: Vec::new(),
+            dhcpv6: true,
+            nat: false,
+            relay_routes: Vec::new(),
+            fetch_egress: false,
+            file_server_ip: None,
+            fetch_proxy: None,
+            fetch_upgrade_https: true,
+            socks: None,
+            mdns_hostname: Some(DEFAULT_MDNS_HOSTNAME.to_string()),
+            regions: Vec::new(),
+            derp_map: None,
+            netcheck_interval_ms: DEFAULT_NETCHECK_INTERVAL_MS,
+            control_url: None,
+            control_interval_ms: DEFAULT_CONTROL_INTERVAL_MS,
+            allowed_peers: None,
+        }
+    }
+}
+
+impl DerpConfig {
+    pub fn builder() -> DerpConfigBuilder {
+        DerpConfigBuilder {
+            config: DerpConfig::default(),
+        }
+    }
+
+    /// `allowed_peers`, parsed. Keys that don't parse, which `validate`
+    /// refuses, allow nobody.
+    pub fn allowed_peer_keys(&self) -> Option<HashSet<PeerKey>> {
+        self.allowed_peers.as_ref().map(|keys| keys.iter().filter_map(|key| hex_decode_key(key).ok()).collect())
+    }
+
+    /// `regions` and those of `derp_map`.
+    pub fn relay_regions(&self) -> Vec<Region> {
+        let mut regions = self.regions.clone();
+        regions.extend(self.derp_map.iter().flat_map(DerpMap::regions));
+        regions
+    }
+
+    /// The reconnect settings, for the connection driver.
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: self.max_reconnect_attempts,
+            base_delay_ms: self.reconnect_delay_ms,
+            max_delay_ms: self.max_reconnect_delay_ms,
+            jitter: self.reconnect_jitter,
+            cooldown_ms: self.reconnect_cooldown_ms,
+            stable_ms: self.stable_connection_ms,
+        }
+    }
+
+    pub fn validate(&self) -> DerpResult<()> {
+        if self.mtu < MIN_MTU || self.mtu > MAX_MTU {
+            return Err(DerpError::InvalidState(format!("MTU must be between {} and {}", MIN_MTU, MAX_MTU)));
+        }
+        if self.receive_buffer_size < self.mtu as usize {
+            return Err(DerpError::InvalidState("Receive buffer must hold at least one MTU".into()));
+        }
+        if self.send_queue_size == 0 || self.receive_queue_size == 0 {
+            return Err(DerpError::InvalidState("Queue sizes must be non-zero".into()));
+        }
+        if self.max_reconnect_delay_ms < self.reconnect_delay_ms {
+            return Err(DerpError::InvalidState("Maximum reconnect delay must be at least the base delay".into()));
+        }
+        if !(0.0..=1.0).contains(&self.reconnect_jitter) {
+            return Err(DerpError::InvalidState("Reconnect jitter must be between 0 and 1".into()));
+        }
+        if self.send_high_watermark == 0 {
+            return Err(DerpError::InvalidState("Send high watermark must be non-zero".into()));
+        }
+        if self.guest_ip == self.gateway_ip || !same_subnet(self.guest_ip, self.gateway_ip, self.netmask) {
+            return Err(DerpError::InvalidState("Guest and gateway must be distinct addresses on the same subnet".into()));
+        }
+        if self.file_server_ip.is_some_and(|address| address == self.gateway_ip || address == self.guest_ip) {
+            return Err(DerpError::InvalidState("File server needs an address of its own".into()));
+        }
+        if let Some(socks) = self.socks.as_ref().filter(|socks| !socks.url.starts_with("ws://") && !socks.url.starts_with("wss://")) {
+            return Err(DerpError::InvalidState(format!("SOCKS bridge must be a ws:// or wss:// URL: {}", socks.url)));
+        }
+        if self.ipv6_prefix.is_some_and(|prefix| u128::from(prefix) as u64 != 0) {
+            return Err(DerpError::InvalidState("IPv6 prefix must be a /64".into()));
+        }
+        if let Some(route) = self.relay_routes.iter().find(|route| ip::parse_cidr(route).is_none()) {
+            return Err(DerpError::InvalidState(format!("Invalid relay route: {}", route)));
+        }
+        if self.compression && !cfg!(feature = "compression") {
+            return Err(DerpError::InvalidState("Compression needs the compression feature".into()));
+        }
+        if self.aes_gcm_siv && !cfg!(feature = "siv") {
+            return Err(DerpError::InvalidState("AES-GCM-SIV needs the siv feature".into()));
+        }
+        if self.keepalive_interval_ms == Some(0) {
+            return Err(DerpError::InvalidState("Keepalive interval must be non-zero".into()));
+        }
+        if let Some(hostname) = self.mdns_hostname.as_ref().filter(|name| !mdns::is_valid_name(name)) {
+            return Err(DerpError::InvalidState(format!("mDNS hostname must be a name in .local: {}", hostname)));
+        }
+        if let Some(url) = self.control_url.as_ref().filter(|url| !url.starts_with("https://")) {
+            return Err(DerpError::InvalidState(format!("Control URL must be https: {}", url)));
+        }
+        if let Some(keys) = &self.allowed_peers {
+            keys.iter().try_for_each(|key| hex_decode_key(key).map(drop))?;
+        }
+        let mut region_ids = HashSet::new();
+        if let Some(region) = self.relay_regions().iter().find(|region| !region_ids.insert(region.id)) {
+            return Err(DerpError::InvalidState(format!("Duplicate region id: {}", region.id)));
+        }
+        Ok(())
+    }
+}
+
+fn same_subnet(a: Ipv4Addr, b: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+    let mask = u32::from(netmask);
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+pub struct DerpConfigBuilder {
+    config: DerpConfig,
+}
+
+impl DerpConfigBuilder {
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    pub fn max_reconnect_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.config.max_reconnect_attempts = attempts;
+        self
+    }
+
+    pub fn reconnect_delay_ms(mut self, delay_ms: u32) -> Self {
+        self.config.reconnect_delay_ms = delay_ms;
+        self
+    }
+
+    pub fn max_reconnect_delay_ms(mut self, delay_ms: u32) -> Self {
+        self.config.max_reconnect_delay_ms = delay_ms;
+        self
+    }
+
+    pub fn reconnect_jitter(mut self, jitter: f64) -> Self {
+        self.config.reconnect_jitter = jitter;
+        self
+    }
+
+    pub fn stable_connection_ms(mut self, stable_ms: u32) -> Self {
+        self.config.stable_connection_ms = stable_ms;
+        self
+    }
+
+    pub fn reconnect_cooldown_ms(mut self, cooldown_ms: u32) -> Self {
+        self.config.reconnect_cooldown_ms = cooldown_ms;
+        self
+    }
+
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.config.compression = enabled;
+        self
+    }
+
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.config.compression_threshold = threshold;
+        self
+    }
+
+    pub fn keepalive_interval_ms(mut self, interval_ms: u32) -> Self {
+        self.config.keepalive_interval_ms = Some(interval_ms);
+        self
+    }
+
+    pub fn missed_keepalives(mut self, missed: u32) -> Self {
+        self.config.missed_keepalives = missed;
+        self
+    }
+
+    pub fn receive_buffer_size(mut self, size: usize) -> Self {
+        self.config.receive_buffer_size = size;
+        self
+    }
+
+    pub fn send_queue_size(mut self, size: usize) -> Self {
+        self.config.send_queue_size = size;
+        self
+    }
+
+    pub fn receive_queue_size(mut self, size: usize) -> Self {
+        self.config.receive_queue_size = size;
+        self
+    }
+
+    pub fn batching(mut self, enabled: bool) -> Self {
+        self.config.batching = enabled;
+        self
+    }
+
+    pub fn control_checksums(mut self, enabled: bool) -> Self {
+        self.config.control_checksums = enabled;
+        self
+    }
+
+    pub fn cbor(mut self, enabled: bool) -> Self {
+        self.config.cbor = enabled;
+        self
+    }
+
+    pub fn aes_gcm_siv(mut self, enabled: bool) -> Self {
+        self.config.aes_gcm_siv = enabled;
+        self
+    }
+
+    pub fn fragment_oversized(mut self, enabled: bool) -> Self {
+        self.config.fragment_oversized = enabled;
+        self
+    }
+
+    pub fn padding(mut self, policy: PaddingPolicy) -> Self {
+        self.config.padding = policy;
+        self
+    }
+
+    pub fn send_high_watermark(mut self, bytes: usize) -> Self {
+        self.config.send_high_watermark = bytes;
+        self
+    }
+
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.config.backpressure = policy;
+        self
+    }
+
+    pub fn send_queue_bytes(mut self, bytes: usize) -> Self {
+        self.config.send_queue_bytes = bytes;
+        self
+    }
+
+    pub fn reconnect_queue_size(mut self, size: usize) -> Self {
+        self.config.reconnect_queue_size = size;
+        self
+    }
+
+    pub fn lazy_connect(mut self, enabled: bool) -> Self {
+        self.config.lazy_connect = enabled;
+        self
+    }
+
+    pub fn idle_timeout_ms(mut self, timeout_ms: u32) -> Self {
+        self.config.idle_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn shutdown_timeout_ms(mut self, timeout_ms: u32) -> Self {
+        self.config.shutdown_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn max_forward_hops(mut self, hops: u8) -> Self {
+        self.config.max_forward_hops = hops;
+        self
+    }
+
+    pub fn gateway_ip(mut self, ip: Ipv4Addr) -> Self {
+        self.config.gateway_ip = ip;
+        self
+    }
+
+    pub fn guest_ip(mut self, ip: Ipv4Addr) -> Self {
+        self.config.guest_ip = ip;
+        self
+    }
+
+    pub fn netmask(mut self, netmask: Ipv4Addr) -> Self {
+        self.config.netmask = netmask;
+        self
+    }
+
+    pub fn dns_servers(mut self, servers: Vec<Ipv4Addr>) -> Self {
+        self.config.dns_servers = servers;
+        self
+    }
+
+    pub fn doh_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.config.doh_endpoint = endpoint;
+        self
+    }
+
+    pub fn ipv6_prefix(mut self, prefix: Option<Ipv6Addr>) -> Self {
+        self.config.ipv6_prefix = prefix;
+        self
+    }
+
+    pub fn ipv6_dns_servers(mut self, servers: Vec<Ipv6Addr>) -> Self {
+        self.config.ipv6_dns_servers = servers;
+        self
+    }
+
+    pub fn dhcpv6(mut self, enabled: bool) -> Self {
+        self.config.dhcpv6 = enabled;
+        self
+    }
+
+    pub fn nat(mut self, enabled: bool) -> Self {
+        self.config.nat = enabled;
+        self
+    }
+
+    pub fn file_server_ip(mut self, address: Option<Ipv4Addr>) -> Self {
+        self.config.file_server_ip = address;
+        self
+    }
+
+    pub fn relay_routes(mut self, routes: Vec<String>) -> Self {
+        self.config.relay_routes = routes;
+        self
+    }
+
+    pub fn fetch_egress(mut self, enabled: bool) -> Self {
+        self.config.fetch_egress = enabled;
+        self
+    }
+
+    pub fn fetch_proxy(mut self, proxy: Option<String>) -> Self {
+        self.config.fetch_proxy = proxy;
+        self
+    }
+
+    pub fn fetch_upgrade_https(mut self, enabled: bool) -> Self {
+        self.config.fetch_upgrade_https = enabled;
+        self
+    }
+
+    pub fn socks(mut self, socks: Option<SocksConfig>) -> Self {
+        self.config.socks = socks;
+        self
+    }
+
+    pub fn mdns_hostname(mut self, hostname: Option<String>) -> Self {
+        self.config.mdns_hostname = hostname;
+        self
+    }
+
+    pub fn regions(mut self, regions: Vec<Region>) -> Self {
+        self.config.regions = regions;
+        self
+    }
+
+    pub fn derp_map(mut self, map: DerpMap) -> Self {
+        self.config.derp_map = Some(map);
+        self
+    }
+
+    pub fn netcheck_interval_ms(mut self, interval_ms: u32) -> Self {
+        self.config.netcheck_interval_ms = interval_ms;
+        self
+    }
+
+    pub fn control_url(mut self, url: Option<String>) -> Self {
+        self.config.control_url = url;
+        self
+    }
+
+    pub fn control_interval_ms(mut self, interval_ms: u32) -> Self {
+        self.config.control_interval_ms = interval_ms;
+        self
+    }
+
+    pub fn allowed_peers(mut self, keys: Option<Vec<String>>) -> Self {
+        self.config.allowed_peers = keys;
+        self
+    }
+
+    pub fn build(self) -> DerpResult<DerpConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_builder() {
+        let config = DerpConfig::builder()
+            .mtu(1280)
+            .compression(true)
+            .keepalive_interval_ms(5000)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.mtu, 1280);
+        assert!(config.compression);
+        assert_eq!(config.keepalive_interval_ms, Some(5000));
+        assert_eq!(config.max_reconnect_attempts, Some(DEFAULT_MAX_RECONNECT_ATTEMPTS));
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        let config = DerpConfig::builder().max_reconnect_attempts(None).reconnect_jitter(0.2).build().unwrap();
+        assert_eq!(config.max_reconnect_attempts, None);
+        assert!(DerpConfig::builder().reconnect_jitter(1.5).build().is_err());
+        assert!(DerpConfig::builder().reconnect_delay_ms(5000).max_reconnect_delay_ms(1000).build().is_err());
+
+        let config: DerpConfig = serde_json::from_str(r#"{"maxReconnectAttempts": null}"#).unwrap();
+        assert_eq!(config.max_reconnect_attempts, None);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_mtu() {
+        assert!(DerpConfig::builder().mtu(100).build().is_err());
+        assert!(DerpConfig::builder().mtu(1500).receive_buffer_size(1000).build().is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_long_ipv6_prefix() {
+        assert!(DerpConfig::builder().ipv6_prefix("fd00:1::1".parse().ok()).build().is_err());
+        assert!(DerpConfig::builder().ipv6_prefix(None).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_relay_route() {
+        assert!(DerpConfig::builder().relay_routes(vec!["100.64.0.0/10".into()]).build().is_ok());
+        assert!(DerpConfig::builder().relay_routes(vec!["100.64.0.0/40".into()]).build().is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_guest_outside_subnet() {
+        assert!(DerpConfig::builder().guest_ip(Ipv4Addr::new(10, 0, 0, 2)).build().is_err());
+        assert!(DerpConfig::builder().guest_ip(DEFAULT_GATEWAY_IP).build().is_err());
+        assert!(DerpConfig::builder().guest_ip(Ipv4Addr::new(192, 168, 86, 2)).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_mdns_hostname_outside_local() {
+        assert!(DerpConfig::builder().mdns_hostname(Some("gateway.local".into())).build().is_ok());
+        assert!(DerpConfig::builder().mdns_hostname(Some("gateway.example".into())).build().is_err());
+        assert!(DerpConfig::builder().mdns_hostname(None).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_plain_http_control_url() {
+        assert!(DerpConfig::builder().control_url(Some("https://control.example/v86.json".into())).build().is_ok());
+        assert!(DerpConfig::builder().control_url(Some("http://control.example/v86.json".into())).build().is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_allowed_peers() {
+        let key = "01".repeat(32);
+        let config = DerpConfig::builder().allowed_peers(Some(vec![key])).build().unwrap();
+        assert_eq!(config.allowed_peer_keys(), Some(HashSet::from([[1u8; 32]])));
+        assert!(DerpConfig::builder().allowed_peers(Some(vec!["01".into()])).build().is_err());
+        assert_eq!(DerpConfig::default().allowed_peer_keys(), None);
+    }
+
+    #[test]
+    fn test_builder_rejects_duplicate_region_ids() {
+        let region = Region { id: 1, name: String::new(), url: "wss://relay.example/derp".into(), probe_url: None };
+        let map = DerpMap::from_json(r#"{"Regions": {"1": {"RegionID": 1, "Nodes": [{"Name": "1a", "RegionID": 1, "HostName": "derp1.example.com"}]}}}"#).unwrap();
+        assert_eq!(DerpConfig::builder().derp_map(map.clone()).build().unwrap().relay_regions().len(), 1);
+        assert!(DerpConfig::builder().regions(vec![region]).derp_map(map).build().is_err());
+    }
+
+    #[test]
+    fn test_from_js_object() {
+        let config: DerpConfig = serde_json::from_str(r#"{"mtu": 1400, "maxReconnectAttempts": 10}"#).unwrap();
+        assert_eq!(config.mtu, 1400);
+        assert_eq!(config.max_reconnect_attempts, Some(10));
+        assert_eq!(config.reconnect_delay_ms, DEFAULT_RECONNECT_DELAY_MS);
+        assert_eq!(config.gateway_ip, DEFAULT_GATEWAY_IP);
+
+        let config: DerpConfig = serde_json::from_str(r#"{"gatewayIp": "10.0.2.2"}"#).unwrap();
+        assert_eq!(config.gateway_ip, Ipv4Addr::new(10, 0, 2, 2));
+    }
+}
@@ -0,0 +1,517 @@
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadInPlace, KeyInit, OsRng},
+    AeadCore, Aes256Gcm,
+};
+#[cfg(feature = "siv")]
+use aes_gcm_siv::Aes256GcmSiv;
+#[cfg(feature = "passphrase")]
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+#[cfg(feature = "base64")]
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+#[cfg(feature = "pq")]
+use ml_kem::{
+    kem::{Decapsulate, DecapsulationKey, Encapsulate, EncapsulationKey},
+    Ciphertext, EncodedSizeUser, KemCore, MlKem768, MlKem768Params,
+};
+#[cfg(feature = "pq")]
+use sha2::Digest;
+#[cfg(feature = "pq")]
+use x25519_dalek::{PublicKey, StaticSecret};
+use super::error::{DerpError, DerpResult};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Bytes added by `CryptoState::encrypt` on top of the plaintext.
+pub const CIPHERTEXT_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+#[cfg(feature = "pq")]
+const X25519_LEN: usize = 32;
+#[cfg(feature = "pq")]
+const MLKEM768_PUBLIC_KEY_LEN: usize = 1184;
+#[cfg(feature = "pq")]
+const MLKEM768_CIPHERTEXT_LEN: usize = 1088;
+/// An X25519 public key, then an ML-KEM-768 encapsulation key.
+#[cfg(feature = "pq")]
+pub const HYBRID_PUBLIC_KEY_LEN: usize = X25519_LEN + MLKEM768_PUBLIC_KEY_LEN;
+/// An ephemeral X25519 public key, then an ML-KEM-768 ciphertext.
+#[cfg(feature = "pq")]
+pub const HYBRID_CIPHERTEXT_LEN: usize = X25519_LEN + MLKEM768_CIPHERTEXT_LEN;
+#[cfg(feature = "pq")]
+const HYBRID_LABEL: &[u8] = b"derp-x25519-mlkem768";
+/// Argon2id output for `from_passphrase`: the encryption key, then the
+/// signing key.
+#[cfg(feature = "passphrase")]
+pub const PASSPHRASE_KEYS_LEN: usize = 64;
+/// Argon2's lower bound.
+#[cfg(feature = "passphrase")]
+pub const MIN_SALT_LEN: usize = 8;
+#[cfg(feature = "siv")]
+const SIV_KEY_LABEL: &[u8] = b"derp-aes-gcm-siv";
+const NODE_SESSION_LABEL: &[u8] = b"derp-node-session";
+const NODE_SIGNING_LABEL: &[u8] = b"derp-node-signing";
+
+/// The AEAD a frame is sealed with. Both take the same nonce and tag sizes,
+/// so `CIPHERTEXT_OVERHEAD` holds for either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes256Gcm,
+    /// Nonce-misuse resistant: a repeated nonce, e.g. from a restored VM
+    /// snapshot replaying the random number generator, only reveals that
+    /// two messages were identical.
+    #[cfg(feature = "siv")]
+    Aes256GcmSiv,
+}
+
+pub struct CryptoState {
+    cipher: Aes256Gcm,
+    /// Keyed separately from `cipher`, from the same session key.
+    #[cfg(feature = "siv")]
+    siv: Aes256GcmSiv,
+    /// Only `sign` and `verify` use it, and they need the base64 feature.
+    #[cfg_attr(not(feature = "base64"), allow(dead_code))]
+    hmac_key: Vec<u8>,
+}
+
+impl CryptoState {
+    pub fn new() -> DerpResult<Self> {
+        CryptoState::with_key(&CryptoState::generate_key())
+    }
+
+    /// Uses `key`, 32 bytes both sides of a session agreed on, for
+    /// encryption. The signing key stays random, as for `new`.
+    pub fn with_key(key: &[u8]) -> DerpResult<Self> {
+        let cipher = Aes256Gcm::new_from_slice(key)
+            .map_err(|_| DerpError::CryptoError("Session keys are 32 bytes".into()))?;
+
+        let mut hmac_key = vec![0u8; 32];
+        getrandom::getrandom(&mut hmac_key)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to generate HMAC key: {}", e)))?;
+
+        Ok(CryptoState {
+            cipher,
+            #[cfg(feature = "siv")]
+            siv: Aes256GcmSiv::new(&siv_key(key)),
+            hmac_key,
+        })
+    }
+
+    /// Derives both keys from a 32-byte node private key issued elsewhere,
+    /// e.g. by a control plane. Neither is the private key itself, so it
+    /// stays fit for whatever else it was issued for.
+    pub fn from_private_key(private_key: &[u8]) -> DerpResult<Self> {
+        if private_key.len() != 32 {
+            return Err(DerpError::CryptoError("Private keys are 32 bytes".into()));
+        }
+        let hmac_key = derive_key(private_key, NODE_SIGNING_LABEL).to_vec();
+        Ok(CryptoState { hmac_key, ..CryptoState::with_key(&derive_key(private_key, NODE_SESSION_LABEL))? })
+    }
+
+    /// Derives both keys from a user-entered passphrase with Argon2id, so
+    /// two browsers given the same passphrase and salt share an identity
+    /// without exchanging keys. The salt needn't be secret, but should be
+    /// unique to the pairing and at least `MIN_SALT_LEN` bytes.
+    #[cfg(feature = "passphrase")]
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> DerpResult<Self> {
+        CryptoState::from_passphrase_keys(&passphrase_keys(passphrase, salt)?)
+    }
+
+    /// Like `from_passphrase`, from keys `passphrase_keys` already derived.
+    #[cfg(feature = "passphrase")]
+    pub fn from_passphrase_keys(keys: &[u8; PASSPHRASE_KEYS_LEN]) -> DerpResult<Self> {
+        let (key, hmac_key) = keys.split_at(32);
+        Ok(CryptoState { hmac_key: hmac_key.to_vec(), ..CryptoState::with_key(key)? })
+    }
+
+    /// Generates a random 256-bit key.
+    pub fn generate_key() -> Vec<u8> {
+        Aes256Gcm::generate_key(&mut OsRng).to_vec()
+    }
+
+    /// Encrypts `data`, authenticating `aad` alongside it. The same `aad` must
+    /// be passed to `decrypt`.
+    pub fn encrypt(&self, data: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+        let mut result = Vec::with_capacity(data.len() + CIPHERTEXT_OVERHEAD);
+        self.encrypt_into(data, aad, &mut result)?;
+        Ok(result)
+    }
+
+    /// Like `encrypt`, but appends nonce, ciphertext and tag to `out`,
+    /// encrypting in place so a reused buffer needs no further allocation.
+    pub fn encrypt_into(&self, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+        self.encrypt_into_with(CipherSuite::Aes256Gcm, data, aad, out)
+    }
+
+    /// Like `encrypt_into`, under `suite`. The receiver has to decrypt with
+    /// the same one.
+    pub fn encrypt_into_with(&self, suite: CipherSuite, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+        match suite {
+            CipherSuite::Aes256Gcm => seal_into(&self.cipher, data, aad, out),
+            #[cfg(feature = "siv")]
+            CipherSuite::Aes256GcmSiv => seal_into(&self.siv, data, aad, out),
+        }
+    }
+
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+        let mut result = Vec::with_capacity(data.len().saturating_sub(CIPHERTEXT_OVERHEAD));
+        self.decrypt_into(data, aad, &mut result)?;
+        Ok(result)
+    }
+
+    /// Like `decrypt`, but appends the plaintext to `out`. On failure `out`
+    /// is left as it was.
+    pub fn decrypt_into(&self, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+        self.decrypt_into_with(CipherSuite::Aes256Gcm, data, aad, out)
+    }
+
+    /// Like `decrypt_into`, for data encrypted under `suite`.
+    pub fn decrypt_into_with(&self, suite: CipherSuite, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+        match suite {
+            CipherSuite::Aes256Gcm => open_into(&self.cipher, data, aad, out),
+            #[cfg(feature = "siv")]
+            CipherSuite::Aes256GcmSiv => open_into(&self.siv, data, aad, out),
+        }
+    }
+
+    #[cfg(feature = "base64")]
+    pub fn sign(&self, data: &[u8]) -> DerpResult<String> {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.hmac_key)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to create HMAC: {}", e)))?;
+            
+        mac.update(data);
+        let result = mac.finalize();
+        Ok(BASE64.encode(result.into_bytes()))
+    }
+
+    /// A signature that isn't valid base64 doesn't verify, like any other
+    /// that doesn't match.
+    #[cfg(feature = "base64")]
+    pub fn verify(&self, data: &[u8], signature: &str) -> DerpResult<bool> {
+        let Ok(signature_bytes) = BASE64.decode(signature) else {
+            return Ok(false);
+        };
+
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.hmac_key)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to create HMAC: {}", e)))?;
+            
+        mac.update(data);
+
+        Ok(mac.verify_slice(&signature_bytes).is_ok())
+    }
+}
+
+/// Runs Argon2id with its default cost, about 19 MiB and two passes, which
+/// takes a fraction of a second in a browser.
+#[cfg(feature = "passphrase")]
+pub fn passphrase_keys(passphrase: &str, salt: &[u8]) -> DerpResult<[u8; PASSPHRASE_KEYS_LEN]> {
+    if passphrase.is_empty() {
+        return Err(DerpError::CryptoError("Passphrase is empty".into()));
+    }
+    if salt.len() < MIN_SALT_LEN {
+        return Err(DerpError::CryptoError(format!("Salt must be at least {} bytes", MIN_SALT_LEN)));
+    }
+    let mut keys = [0u8; PASSPHRASE_KEYS_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut keys)
+        .map_err(|e| DerpError::CryptoError(format!("Key derivation failed: {}", e)))?;
+    Ok(keys)
+}
+
+/// Derives the AES-GCM-SIV key, so the two modes never share one.
+#[cfg(feature = "siv")]
+fn siv_key(key: &[u8]) -> aes_gcm_siv::Key<Aes256GcmSiv> {
+    derive_key(key, SIV_KEY_LABEL).into()
+}
+
+/// HMAC-SHA256 of `label` under `key`: a key for that one purpose.
+fn derive_key(key: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// The receiving side of a hybrid key exchange: X25519 and ML-KEM-768
+/// (FIPS 203) at once, so the agreed secret stays safe while either holds.
+/// The sender encapsulates to `public_key` with `hybrid_encapsulate`.
+#[cfg(feature = "pq")]
+pub struct HybridKeyPair {
+    x25519: StaticSecret,
+    mlkem: DecapsulationKey<MlKem768Params>,
+    public_key: Vec<u8>,
+}
+
+#[cfg(feature = "pq")]
+impl HybridKeyPair {
+    pub fn generate() -> DerpResult<Self> {
+        let mut secret = [0u8; X25519_LEN];
+        getrandom::getrandom(&mut secret)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to generate an X25519 key: {}", e)))?;
+        let x25519 = StaticSecret::from(secret);
+        let (mlkem, encapsulation_key) = MlKem768::generate(&mut OsRng);
+
+        let mut public_key = Vec::with_capacity(HYBRID_PUBLIC_KEY_LEN);
+        public_key.extend_from_slice(PublicKey::from(&x25519).as_bytes());
+        public_key.extend_from_slice(&encapsulation_key.as_bytes());
+        Ok(HybridKeyPair { x25519, mlkem, public_key })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// The secret `hybrid_encapsulate` agreed on. A tampered ciphertext
+    /// gives a different secret rather than an error, which the first
+    /// message under it then fails to authenticate.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> DerpResult<[u8; 32]> {
+        if ciphertext.len() != HYBRID_CIPHERTEXT_LEN {
+            return Err(DerpError::CryptoError(format!("Hybrid ciphertexts are {} bytes", HYBRID_CIPHERTEXT_LEN)));
+        }
+        let (ephemeral, mlkem_ciphertext) = ciphertext.split_at(X25519_LEN);
+        let ephemeral = PublicKey::from(<[u8; X25519_LEN]>::try_from(ephemeral).unwrap());
+        let x25519_secret = self.x25519.diffie_hellman(&ephemeral);
+        if !x25519_secret.was_contributory() {
+            return Err(DerpError::CryptoError("Low-order X25519 key".into()));
+        }
+        let mlkem_secret = self.mlkem
+            .decapsulate(<&Ciphertext<MlKem768>>::try_from(mlkem_ciphertext).unwrap())
+            .map_err(|_| DerpError::CryptoError("ML-KEM decapsulation failed".into()))?;
+        Ok(combine(&mlkem_secret, x25519_secret.as_bytes(), ephemeral.as_bytes(), &self.public_key[..X25519_LEN]))
+    }
+}
+
+/// Agrees a secret with the holder of the hybrid `public_key`, returning
+/// the ciphertext to send them and the secret.
+#[cfg(feature = "pq")]
+pub fn hybrid_encapsulate(public_key: &[u8]) -> DerpResult<(Vec<u8>, [u8; 32])> {
+    if public_key.len() != HYBRID_PUBLIC_KEY_LEN {
+        return Err(DerpError::CryptoError(format!("Hybrid public keys are {} bytes", HYBRID_PUBLIC_KEY_LEN)));
+    }
+    let (x25519_key, mlkem_key) = public_key.split_at(X25519_LEN);
+    let x25519_key = PublicKey::from(<[u8; X25519_LEN]>::try_from(x25519_key).unwrap());
+    let encapsulation_key = EncapsulationKey::<MlKem768Params>::from_bytes(mlkem_key.try_into().unwrap());
+
+    let mut ephemeral = [0u8; X25519_LEN];
+    getrandom::getrandom(&mut ephemeral)
+        .map_err(|e| DerpError::CryptoError(format!("Failed to generate an X25519 key: {}", e)))?;
+    let ephemeral = StaticSecret::from(ephemeral);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let x25519_secret = ephemeral.diffie_hellman(&x25519_key);
+    if !x25519_secret.was_contributory() {
+        return Err(DerpError::CryptoError("Low-order X25519 key".into()));
+    }
+    let (mlkem_ciphertext, mlkem_secret) = encapsulation_key
+        .encapsulate(&mut OsRng)
+        .map_err(|_| DerpError::CryptoError("ML-KEM encapsulation failed".into()))?;
+
+    let mut ciphertext = Vec::with_capacity(HYBRID_CIPHERTEXT_LEN);
+    ciphertext.extend_from_slice(ephemeral_public.as_bytes());
+    ciphertext.extend_from_slice(&mlkem_ciphertext);
+    let secret = combine(&mlkem_secret, x25519_secret.as_bytes(), ephemeral_public.as_bytes(), x25519_key.as_bytes());
+    Ok((ciphertext, secret))
+}
+
+/// The X-Wing combiner, with SHA-256: both shared secrets, bound to the
+/// X25519 keys they came from.
+#[cfg(feature = "pq")]
+fn combine(mlkem_secret: &[u8], x25519_secret: &[u8], ephemeral: &[u8], x25519_key: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(HYBRID_LABEL);
+    hash.update(mlkem_secret);
+    hash.update(x25519_secret);
+    hash.update(ephemeral);
+    hash.update(x25519_key);
+    hash.finalize().into()
+}
+
+fn seal_into<A: AeadInPlace>(cipher: &A, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    out.extend_from_slice(&nonce);
+
+    let start = out.len();
+    out.extend_from_slice(data);
+    let tag = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(&nonce), aad, &mut out[start..])
+        .map_err(|e| DerpError::CryptoError(format!("Encryption failed: {}", e)))?;
+    out.extend_from_slice(&tag);
+    Ok(())
+}
+
+fn open_into<A: AeadInPlace>(cipher: &A, data: &[u8], aad: &[u8], out: &mut Vec<u8>) -> DerpResult<()> {
+    if data.len() < CIPHERTEXT_OVERHEAD {
+        return Err(DerpError::CryptoError("Data too short".into()));
+    }
+
+    let nonce = GenericArray::from_slice(&data[..NONCE_LEN]);
+    let (ciphertext, tag) = data[NONCE_LEN..].split_at(data.len() - CIPHERTEXT_OVERHEAD);
+
+    let start = out.len();
+    out.extend_from_slice(ciphertext);
+    let result = cipher
+        .decrypt_in_place_detached(nonce, aad, &mut out[start..], GenericArray::from_slice(tag));
+    if let Err(e) = result {
+        out.truncate(start);
+        return Err(DerpError::CryptoError(format!("Decryption failed: {}", e)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_decryption() {
+        let crypto = CryptoState::new().unwrap();
+        let data = b"Hello, World!";
+        
+        let encrypted = crypto.encrypt(data, &[]).unwrap();
+        let decrypted = crypto.decrypt(&encrypted, &[]).unwrap();
+        
+        assert_eq!(data, &decrypted[..]);
+        assert_eq!(encrypted.len(), data.len() + CIPHERTEXT_OVERHEAD);
+    }
+
+    #[test]
+    fn test_encrypt_into_appends() {
+        let crypto = CryptoState::new().unwrap();
+        let mut out = vec![0xAA, 0xBB];
+
+        crypto.encrypt_into(b"payload", b"aad", &mut out).unwrap();
+        assert_eq!(&out[..2], &[0xAA, 0xBB]);
+        assert_eq!(crypto.decrypt(&out[2..], b"aad").unwrap(), b"payload");
+    }
+
+    #[test]
+    fn test_associated_data_mismatch() {
+        let crypto = CryptoState::new().unwrap();
+        let encrypted = crypto.encrypt(b"payload", &[1, 4, 0, 0, 35]).unwrap();
+
+        assert!(crypto.decrypt(&encrypted, &[1, 4, 0, 0, 35]).is_ok());
+        assert!(crypto.decrypt(&encrypted, &[1, 5, 0, 0, 35]).is_err());
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn test_signing_verification() {
+        let crypto = CryptoState::new().unwrap();
+        let data = b"Hello, World!";
+        
+        let signature = crypto.sign(data).unwrap();
+        assert!(crypto.verify(data, &signature).unwrap());
+        
+        // Test invalid signature
+        assert!(!crypto.verify(data, "invalid-signature").unwrap_or(true));
+    }
+
+    #[test]
+    fn test_encryption_different_data() {
+        let crypto = CryptoState::new().unwrap();
+        let data1 = b"Hello";
+        let data2 = b"World";
+        
+        let encrypted1 = crypto.encrypt(data1, &[]).unwrap();
+        let encrypted2 = crypto.encrypt(data2, &[]).unwrap();
+        
+        assert_ne!(encrypted1, encrypted2);
+        
+        let decrypted1 = crypto.decrypt(&encrypted1, &[]).unwrap();
+        let decrypted2 = crypto.decrypt(&encrypted2, &[]).unwrap();
+        
+        assert_eq!(data1, &decrypted1[..]);
+        assert_eq!(data2, &decrypted2[..]);
+    }
+
+    #[test]
+    fn test_with_key_is_shared() {
+        let key = CryptoState::generate_key();
+        let sender = CryptoState::with_key(&key).unwrap();
+        let receiver = CryptoState::with_key(&key).unwrap();
+
+        let encrypted = sender.encrypt(b"payload", b"aad").unwrap();
+        assert_eq!(receiver.decrypt(&encrypted, b"aad").unwrap(), b"payload");
+        assert!(CryptoState::with_key(&key[..16]).is_err());
+    }
+
+    #[test]
+    fn test_private_key_is_not_the_session_key() {
+        let private_key = CryptoState::generate_key();
+        let node = CryptoState::from_private_key(&private_key).unwrap();
+        let again = CryptoState::from_private_key(&private_key).unwrap();
+        let raw = CryptoState::with_key(&private_key).unwrap();
+
+        let encrypted = node.encrypt(b"payload", b"aad").unwrap();
+        assert_eq!(again.decrypt(&encrypted, b"aad").unwrap(), b"payload");
+        assert!(raw.decrypt(&encrypted, b"aad").is_err());
+        assert!(CryptoState::from_private_key(&private_key[..16]).is_err());
+    }
+
+    #[cfg(all(feature = "passphrase", feature = "base64"))]
+    #[test]
+    fn test_from_passphrase() {
+        let salt = b"v86-pairing";
+        let first = CryptoState::from_passphrase("correct horse battery staple", salt).unwrap();
+        let second = CryptoState::from_passphrase("correct horse battery staple", salt).unwrap();
+
+        let encrypted = first.encrypt(b"payload", b"aad").unwrap();
+        assert_eq!(second.decrypt(&encrypted, b"aad").unwrap(), b"payload");
+        let signature = first.sign(b"identity").unwrap();
+        assert!(second.verify(b"identity", &signature).unwrap());
+
+        let other = CryptoState::from_passphrase("correct horse battery staple", b"another-salt").unwrap();
+        assert!(other.decrypt(&encrypted, b"aad").is_err());
+        assert!(CryptoState::from_passphrase("", salt).is_err());
+        assert!(CryptoState::from_passphrase("short salt", b"salt").is_err());
+    }
+
+    #[cfg(feature = "siv")]
+    #[test]
+    fn test_aes_gcm_siv() {
+        let key = CryptoState::generate_key();
+        let sender = CryptoState::with_key(&key).unwrap();
+        let receiver = CryptoState::with_key(&key).unwrap();
+
+        let mut encrypted = Vec::new();
+        sender.encrypt_into_with(CipherSuite::Aes256GcmSiv, b"payload", b"aad", &mut encrypted).unwrap();
+        let mut decrypted = Vec::new();
+        receiver.decrypt_into_with(CipherSuite::Aes256GcmSiv, &encrypted, b"aad", &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"payload");
+
+        // Suites don't decrypt each other's output
+        assert!(receiver.decrypt_into_with(CipherSuite::Aes256Gcm, &encrypted, b"aad", &mut decrypted).is_err());
+        assert!(receiver.decrypt_into_with(CipherSuite::Aes256GcmSiv, &encrypted, b"other", &mut decrypted).is_err());
+        assert_eq!(decrypted, b"payload");
+    }
+
+    #[cfg(feature = "pq")]
+    #[test]
+    fn test_hybrid_key_exchange() {
+        let receiver = HybridKeyPair::generate().unwrap();
+        assert_eq!(receiver.public_key().len(), HYBRID_PUBLIC_KEY_LEN);
+
+        let (ciphertext, secret) = hybrid_encapsulate(receiver.public_key()).unwrap();
+        assert_eq!(ciphertext.len(), HYBRID_CIPHERTEXT_LEN);
+        assert_eq!(receiver.decapsulate(&ciphertext).unwrap(), secret);
+
+        // Tampering with either half changes the secret
+        let mut tampered = ciphertext.clone();
+        tampered[X25519_LEN + 10] ^= 1;
+        assert_ne!(receiver.decapsulate(&tampered).unwrap(), secret);
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_ne!(receiver.decapsulate(&tampered).ok(), Some(secret));
+
+        assert!(receiver.decapsulate(&ciphertext[1..]).is_err());
+        assert!(hybrid_encapsulate(&receiver.public_key()[1..]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_decryption() {
+        let crypto = CryptoState::new().unwrap();
+        let result = crypto.decrypt(b"invalid data", &[]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+
+const DEFAULT_DERP_PORT: u16 = 443;
+/// Path derpers answer latency probes on.
+const LATENCY_CHECK_PATH: &str = "/derp/latency-check";
+
+/// A relay a client may make its home.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct Region {
+    pub id: u32,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub name: String,
+    /// The relay's WebSocket URL.
+    pub url: String,
+    /// Fetched to measure latency. Defaults to the relay's
+    /// `/derp/latency-check` over HTTP(S).
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub probe_url: Option<String>,
+}
+
+impl Region {
+    pub fn probe_url(&self) -> DerpResult<String> {
+        if let Some(url) = &self.probe_url {
+            return Ok(url.clone());
+        }
+        let (scheme, rest) = self.url.split_once("://")
+            .ok_or_else(|| DerpError::InvalidState(format!("Invalid relay URL: {}", self.url)))?;
+        let scheme = match scheme {
+            "wss" | "https" => "https",
+            "ws" | "http" => "http",
+            _ => return Err(DerpError::InvalidState(format!("Invalid relay URL: {}", self.url))),
+        };
+        let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        Ok(format!("{}://{}{}", scheme, host, LATENCY_CHECK_PATH))
+    }
+}
+
+/// Tailscale's DERPMap, as served by a control server or written for
+/// `derper` deployments, so existing relays can be used unchanged. Keys are
+/// Go's field names; unknown ones are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "PascalCase")]
+pub struct DerpMap {
+    /// Keyed by region id, as a string since JSON keys are.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional, type = "Record<string, DerpRegion>"))]
+    pub regions: BTreeMap<String, DerpRegion>,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub omit_default_regions: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "PascalCase")]
+pub struct DerpRegion {
+    #[serde(rename = "RegionID")]
+    pub region_id: u32,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub region_code: String,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub region_name: String,
+    /// Kept for existing connections but not chosen as a new home.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub avoid: bool,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub nodes: Vec<DerpNode>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "PascalCase")]
+pub struct DerpNode {
+    pub name: String,
+    #[serde(rename = "RegionID")]
+    pub region_id: u32,
+    pub host_name: String,
+    #[serde(default, rename = "IPv4")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub ipv4: String,
+    #[serde(default, rename = "IPv6")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub ipv6: String,
+    #[serde(default, rename = "STUNPort")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub stun_port: i32,
+    /// Only answers STUN, so there's no relay to connect to.
+    #[serde(default, rename = "STUNOnly")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub stun_only: bool,
+    /// Zero means 443.
+    #[serde(default, rename = "DERPPort")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub derp_port: u16,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub insecure_for_tests: bool,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub can_port80: bool,
+}
+
+impl DerpMap {
+    /// Parses DERPMap JSON as Tailscale writes it.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_json(json: &str) -> DerpResult<DerpMap> {
+        let value = js_sys::JSON::parse(json)
+            .map_err(|e| DerpError::SerializationError(format!("Invalid DERPMap JSON: {:?}", e)))?;
+        serde_wasm_bindgen::from_value(value)
+            .map_err(|e| DerpError::SerializationError(format!("Invalid DERPMap: {}", e)))
+    }
+
+    /// Parses DERPMap JSON as Tailscale writes it.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_json(json: &str) -> DerpResult<DerpMap> {
+        serde_json::from_str(json)
+            .map_err(|e| DerpError::SerializationError(format!("Invalid DERPMap: {}", e)))
+    }
+
+    /// The regions `connectHome` can choose between: those not marked
+    /// `Avoid`, each reached through its first node that relays.
+    pub fn regions(&self) -> Vec<Region> {
+        self.regions.values()
+            .filter(|region| !region.avoid)
+            .filter_map(|region| {
+                let node = region.nodes.iter().find(|node| !node.stun_only && !node.host_name.is_empty())?;
+                Some(Region {
+                    id: region.region_id,
+                    name: region.region_name.clone(),
+                    url: node.url(),
+                    probe_url: None,
+                })
+            })
+            .collect()
+    }
+}
+
+impl DerpNode {
+    /// The node's relay endpoint, which derpers serve on `/derp`.
+    pub fn url(&self) -> String {
+        match self.derp_port {
+            0 | DEFAULT_DERP_PORT => format!("wss://{}/derp", self.host_name),
+            port => format!("wss://{}:{}/derp", self.host_name, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DERP_MAP: &str = r#"{
+        "Regions": {
+            "1": {
+                "RegionID": 1,
+                "RegionCode": "nyc",
+                "RegionName": "New York City",
+                "Nodes": [
+                    {"Name": "1a", "RegionID": 1, "HostName": "stun.example.com", "STUNOnly": true},
+                    {"Name": "1b", "RegionID": 1, "HostName": "derp1.example.com", "IPv4": "192.0.2.1", "CanPort80": true}
+                ]
+            },
+            "900": {
+                "RegionID": 900,
+                "RegionCode": "home",
+                "RegionName": "Home lab",
+                "Nodes": [{"Name": "900a", "RegionID": 900, "HostName": "derp.home.arpa", "DERPPort": 8443, "STUNPort": -1}]
+            },
+            "2": {
+                "RegionID": 2,
+                "RegionCode": "sfo",
+                "Avoid": true,
+                "Nodes": [{"Name": "2a", "RegionID": 2, "HostName": "derp2.example.com"}]
+            }
+        },
+        "OmitDefaultRegions": true
+    }"#;
+
+    #[test]
+    fn test_parses_tailscale_json() {
+        let map = DerpMap::from_json(DERP_MAP).unwrap();
+        assert!(map.omit_default_regions);
+        let nyc = &map.regions["1"];
+        assert_eq!((nyc.region_id, nyc.region_code.as_str()), (1, "nyc"));
+        assert!(nyc.nodes[0].stun_only);
+        assert_eq!(nyc.nodes[1].ipv4, "192.0.2.1");
+        assert_eq!(map.regions["900"].nodes[0].stun_port, -1);
+
+        assert!(DerpMap::from_json("{").is_err());
+        assert!(DerpMap::from_json(r#"{"Regions": {"1": {"Nodes": 3}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_regions_skip_avoided_and_stun_only() {
+        let regions = DerpMap::from_json(DERP_MAP).unwrap().regions();
+        let urls: Vec<(u32, &str)> = regions.iter().map(|region| (region.id, region.url.as_str())).collect();
+        assert_eq!(urls, [
+            (1, "wss://derp1.example.com/derp"),
+            (900, "wss://derp.home.arpa:8443/derp"),
+        ]);
+        assert_eq!(regions[0].name, "New York City");
+    }
+}
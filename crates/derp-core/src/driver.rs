@@ -0,0 +1,346 @@
+use serde::Serialize;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::breaker::{self, CircuitBreaker, ConnectionFailedEvent, FailureRecord, ReconnectedEvent};
+use crate::crypto::CryptoState;
+use crate::error::DerpResult;
+use crate::protocol::{hex_encode, Frame, FrameType, HandshakeState, PeerKey, ProtocolState};
+
+/// Where the relay connection stands, as `getState` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// Not connected yet or, with `lazyConnect`, waiting for a packet to
+    /// send.
+    Idle,
+    /// Opening the socket.
+    Connecting,
+    /// The socket is open and ClientInfo sent; waiting for the relay to
+    /// accept.
+    Handshaking,
+    Connected,
+    /// The connection dropped and a retry is scheduled.
+    Reconnecting,
+    /// Reconnects ran out; waiting out `reconnectCooldownMs` or for
+    /// `retryNow`.
+    Cooldown,
+    /// Closed by `close` or `disconnect`.
+    Closed,
+    /// Given up: the relay refused the auth token or retries ran out.
+    Failed,
+}
+
+/// The reconnect settings from `DerpConfig`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// None retries without limit.
+    pub max_attempts: Option<u32>,
+    pub base_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub jitter: f64,
+    pub cooldown_ms: u32,
+    pub stable_ms: u32,
+}
+
+/// Something that happened to the relay connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// The relay accepted the handshake.
+    Connected,
+    /// The connection dropped.
+    Closed {
+        code: u16,
+        reason: String,
+        was_clean: bool,
+        /// The relay refused our token, or presented a key other than the
+        /// one given to `newWithKeys`.
+        rejected: bool,
+        /// How long a restarting relay said it would be away.
+        restart_delay_ms: Option<u32>,
+    },
+    /// The timer from the last `Action::StartTimer` fired.
+    TimerFired,
+}
+
+/// What the caller has to do, in order, after an `Input` or a relay frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    StateChanged(ConnectionState),
+    /// Report the close as "disconnect".
+    Disconnected { code: u16, reason: String, was_clean: bool },
+    /// Queue packets sent from now on for replay after the handshake.
+    HoldPackets,
+    /// Drop queued packets; nothing will send them.
+    DropPackets,
+    /// Report a reconnect attempt as "reconnecting".
+    Reconnecting { attempt: u32, delay_ms: u32, code: u16, reason: String },
+    /// Call back with `Input::TimerFired` after `delay_ms`.
+    StartTimer { delay_ms: u32 },
+    /// Open a new connection and start the handshake.
+    Reopen,
+    Failed(ConnectionFailedEvent),
+    Reconnected(ReconnectedEvent),
+    /// Send a frame to the relay.
+    SendBytes(Vec<u8>),
+    /// Hand a peer's decrypted packet up; the buffer can be recycled after.
+    Deliver { from: PeerKey, packet: Vec<u8> },
+    /// Close the connection, which the relay has refused.
+    Close,
+}
+
+/// Decides what a relay connection does next as it opens, drops and is
+/// retried, without doing any of it: callers feed in `Input`s and carry
+/// out the `Action`s that come back, so the policy can be tested without
+/// sockets or timers.
+#[derive(Debug)]
+pub struct Driver {
+    policy: ReconnectPolicy,
+    breaker: CircuitBreaker,
+    /// Returns a number in [0, 1) for jittering delays.
+    random: fn() -> f64,
+}
+
+impl Driver {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Driver {
+            breaker: CircuitBreaker::new(policy.stable_ms),
+            policy,
+            random: || 0.5,
+        }
+    }
+
+    pub fn set_random(&mut self, random: fn() -> f64) {
+        self.random = random;
+    }
+
+    pub fn handle_input(&mut self, input: Input, now_ms: f64) -> Vec<Action> {
+        match input {
+            Input::Connected => self.breaker.recover(now_ms)
+                .map(Action::Reconnected)
+                .into_iter()
+                .collect(),
+            Input::Closed { code, reason, was_clean, rejected, restart_delay_ms } => {
+                self.closed(code, reason, was_clean, rejected, restart_delay_ms, now_ms)
+            }
+            Input::TimerFired => vec![Action::StateChanged(ConnectionState::Connecting), Action::Reopen],
+        }
+    }
+
+    /// Handles one frame from the relay: the handshake, keepalive pings and
+    /// peers' packets. Other frames are the caller's. Actions are added to
+    /// `actions` even when the frame turns out to be an error, since a
+    /// refused handshake still has to close the connection.
+    pub fn handle_bytes(
+        &mut self,
+        data: &[u8],
+        protocol: &mut ProtocolState,
+        crypto: &CryptoState,
+        now_ms: f64,
+        actions: &mut Vec<Action>,
+    ) -> DerpResult<()> {
+        let frame = Frame::parse(data)?;
+        match frame.frame_type {
+            FrameType::ServerKey => {
+                let result = protocol.handle_server_key(frame.payload);
+                if result.is_err() && protocol.handshake_state() == HandshakeState::Rejected {
+                    actions.extend([Action::Close, Action::StateChanged(ConnectionState::Failed)]);
+                }
+                actions.extend(result?.map(Action::SendBytes));
+            }
+            FrameType::ServerInfo => {
+                actions.push(Action::SendBytes(protocol.handle_server_info_frame(&frame)?));
+                if protocol.is_connected() {
+                    self.connected(now_ms, actions);
+                }
+            }
+            FrameType::AuthResult => {
+                if let Err(e) = protocol.handle_auth_result(frame.payload) {
+                    actions.push(Action::Close);
+                    if protocol.handshake_state() == HandshakeState::Rejected {
+                        actions.push(Action::StateChanged(ConnectionState::Failed));
+                    }
+                    return Err(e);
+                }
+                self.connected(now_ms, actions);
+            }
+            FrameType::Ping => actions.push(Action::SendBytes(protocol.handle_ping())),
+            FrameType::RecvFromPeer => {
+                // Decrypt payload, authenticating the frame header
+                let mut packet = protocol.take_buffer();
+                let from = match protocol.decrypt_peer_frame_into(crypto, &frame, &mut packet) {
+                    Ok(from) => from,
+                    Err(e) => {
+                        log::debug!("Failed to open a {}-byte frame from a peer: {}", data.len(), e);
+                        protocol.recycle(packet);
+                        return Err(e);
+                    }
+                };
+                if protocol.admit(&from) {
+                    actions.push(Action::Deliver { from, packet });
+                } else {
+                    log::debug!("Dropped a {}-byte packet from {}, which isn't allowed", packet.len(), hex_encode(&from));
+                    protocol.recycle(packet);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn connected(&mut self, now_ms: f64, actions: &mut Vec<Action>) {
+        actions.push(Action::StateChanged(ConnectionState::Connected));
+        actions.extend(self.handle_input(Input::Connected, now_ms));
+    }
+
+    /// Starts afresh after `retryNow`.
+    pub fn reset(&mut self) {
+        self.breaker.reset();
+    }
+
+    /// How often reconnects have run out.
+    pub fn trips(&self) -> u32 {
+        self.breaker.trips()
+    }
+
+    fn closed(
+        &mut self,
+        code: u16,
+        reason: String,
+        was_clean: bool,
+        rejected: bool,
+        restart_delay_ms: Option<u32>,
+        now_ms: f64,
+    ) -> Vec<Action> {
+        self.breaker.record_failure(FailureRecord { at_ms: now_ms, code, reason: reason.clone() });
+        let mut actions = vec![Action::Disconnected { code, reason: reason.clone(), was_clean }];
+
+        // Retrying with a token the relay already refused, or to a relay
+        // presenting the wrong key, won't help
+        if rejected {
+            actions.extend([Action::DropPackets, Action::StateChanged(ConnectionState::Failed)]);
+            return actions;
+        }
+
+        // A restarting relay said when it'll be back, which beats guessing
+        let budget = match restart_delay_ms {
+            Some(_) => u32::MAX,
+            None => self.policy.max_attempts.unwrap_or(u32::MAX),
+        };
+        match self.breaker.next_attempt(budget) {
+            Some(attempt) => {
+                let delay_ms = restart_delay_ms.unwrap_or_else(|| breaker::backoff_ms(
+                    attempt,
+                    self.policy.base_delay_ms,
+                    self.policy.max_delay_ms,
+                    self.policy.jitter,
+                    (self.random)(),
+                ));
+                actions.extend([
+                    Action::HoldPackets,
+                    Action::StateChanged(ConnectionState::Reconnecting),
+                    Action::Reconnecting { attempt, delay_ms, code, reason },
+                    Action::StartTimer { delay_ms },
+                ]);
+            }
+            None => {
+                let failed = self.breaker.trip(self.policy.cooldown_ms);
+                actions.extend([
+                    Action::DropPackets,
+                    Action::StateChanged(ConnectionState::Cooldown),
+                    Action::Failed(failed),
+                ]);
+            }
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ServerInfo;
+    use crate::wire::WireFormat;
+
+    fn policy(max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+            jitter: 0.0,
+            cooldown_ms: 60_000,
+            stable_ms: 10_000,
+        }
+    }
+
+    fn closed(rejected: bool, restart_delay_ms: Option<u32>) -> Input {
+        Input::Closed { code: 1006, reason: String::new(), was_clean: false, rejected, restart_delay_ms }
+    }
+
+    #[test]
+    fn test_retries_then_gives_up() {
+        let mut driver = Driver::new(policy(Some(1)));
+        let actions = driver.handle_input(closed(false, None), 0.0);
+        assert_eq!(actions[1..], [
+            Action::HoldPackets,
+            Action::StateChanged(ConnectionState::Reconnecting),
+            Action::Reconnecting { attempt: 1, delay_ms: 1000, code: 1006, reason: String::new() },
+            Action::StartTimer { delay_ms: 1000 },
+        ]);
+        assert_eq!(driver.handle_input(Input::TimerFired, 1000.0), [
+            Action::StateChanged(ConnectionState::Connecting),
+            Action::Reopen,
+        ]);
+
+        let actions = driver.handle_input(closed(false, None), 1500.0);
+        assert!(matches!(actions.last(), Some(Action::Failed(failed)) if failed.attempts == 1));
+        assert_eq!(driver.trips(), 1);
+        driver.reset();
+
+        // Recovery is reported once, and only after an outage
+        driver.handle_input(closed(false, None), 2000.0);
+        assert!(matches!(driver.handle_input(Input::Connected, 3000.0)[..], [Action::Reconnected(_)]));
+        assert!(driver.handle_input(Input::Connected, 3100.0).is_empty());
+    }
+
+    #[test]
+    fn test_rejection_and_restart() {
+        let mut driver = Driver::new(policy(Some(0)));
+        let actions = driver.handle_input(closed(true, None), 0.0);
+        assert_eq!(actions.last(), Some(&Action::StateChanged(ConnectionState::Failed)));
+
+        // A restarting relay is waited for regardless of the budget
+        let actions = driver.handle_input(closed(false, Some(5000)), 0.0);
+        assert_eq!(actions.last(), Some(&Action::StartTimer { delay_ms: 5000 }));
+    }
+
+    #[test]
+    fn test_handles_relay_frames() {
+        let crypto = CryptoState::new().unwrap();
+        let relay = ProtocolState::new();
+        let mut driver = Driver::new(policy(None));
+        let mut actions = Vec::new();
+
+        let mut protocol = ProtocolState::new();
+        protocol.start_handshake().unwrap();
+        let server_key = relay.encode_frame(FrameType::ServerKey, &[7u8; 32]);
+        driver.handle_bytes(&server_key, &mut protocol, &crypto, 0.0, &mut actions).unwrap();
+        assert!(actions.is_empty());
+        let server_info = relay.encode_control_frame(FrameType::ServerInfo, WireFormat::Bincode, &ServerInfo::new("test", Vec::new())).unwrap();
+        driver.handle_bytes(&server_info, &mut protocol, &crypto, 0.0, &mut actions).unwrap();
+        assert!(matches!(actions[..], [Action::SendBytes(_), Action::StateChanged(ConnectionState::Connected)]));
+
+        actions.clear();
+        let ping = relay.encode_frame(FrameType::Ping, &[]);
+        driver.handle_bytes(&ping, &mut protocol, &crypto, 0.0, &mut actions).unwrap();
+        assert!(matches!(actions[..], [Action::SendBytes(_)]));
+
+        // A relay presenting another key is closed on, for good
+        actions.clear();
+        let mut protocol = ProtocolState::new();
+        protocol.pin_server_key([8u8; 32]);
+        protocol.start_handshake().unwrap();
+        assert!(driver.handle_bytes(&server_key, &mut protocol, &crypto, 0.0, &mut actions).is_err());
+        assert_eq!(actions, [Action::Close, Action::StateChanged(ConnectionState::Failed)]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::error::Error;
+use std::sync::PoisonError;
+use bincode;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+#[derive(Debug)]
+pub enum DerpError {
+    InvalidState(String),
+    InvalidProtocol(String),
+    WebSocketError(String),
+    CryptoError(String),
+    SerializationError(String),
+    AuthRejected(String),
+    Timeout(String),
+    PacketTooLarge(String),
+}
+
+/// The `code` of every error this package throws. The numbers are part of
+/// the API: new variants get new numbers and existing ones never change.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DerpErrorCode {
+    InvalidState = 1,
+    InvalidProtocol = 2,
+    WebSocket = 3,
+    Crypto = 4,
+    Serialization = 5,
+    AuthRejected = 6,
+    Timeout = 7,
+    PacketTooLarge = 8,
+}
+
+impl fmt::Display for DerpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DerpError::InvalidState(msg) => write!(f, "Invalid state: {}", msg),
+            DerpError::InvalidProtocol(msg) => write!(f, "Protocol error: {}", msg),
+            DerpError::WebSocketError(msg) => write!(f, "WebSocket error: {}", msg),
+            DerpError::CryptoError(msg) => write!(f, "Cryptography error: {}", msg),
+            DerpError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
+            DerpError::AuthRejected(msg) => write!(f, "Authentication rejected: {}", msg),
+            DerpError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            DerpError::PacketTooLarge(msg) => write!(f, "Packet too large: {}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen(typescript_custom_section)]
+const DERP_ERROR_TS: &'static str = r#"
+/** Identifies which `DerpError` variant an exception came from. */
+export type DerpErrorKind =
+    | "InvalidState"
+    | "InvalidProtocol"
+    | "WebSocketError"
+    | "CryptoError"
+    | "SerializationError"
+    | "AuthRejected"
+    | "Timeout"
+    | "PacketTooLarge";
+
+/** Shape of every error thrown or rejected by this package. */
+export interface DerpErrorShape extends Error {
+    name: DerpErrorKind;
+    code: DerpErrorCode;
+    /** The message without its kind prefix, e.g. the relay's reason for an `AuthRejected`. */
+    detail: string;
+}
+"#;
+
+impl DerpError {
+    /// Name of the variant; used as the JS `Error.name`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DerpError::InvalidState(_) => "InvalidState",
+            DerpError::InvalidProtocol(_) => "InvalidProtocol",
+            DerpError::WebSocketError(_) => "WebSocketError",
+            DerpError::CryptoError(_) => "CryptoError",
+            DerpError::SerializationError(_) => "SerializationError",
+            DerpError::AuthRejected(_) => "AuthRejected",
+            DerpError::Timeout(_) => "Timeout",
+            DerpError::PacketTooLarge(_) => "PacketTooLarge",
+        }
+    }
+
+    pub fn code(&self) -> DerpErrorCode {
+        match self {
+            DerpError::InvalidState(_) => DerpErrorCode::InvalidState,
+            DerpError::InvalidProtocol(_) => DerpErrorCode::InvalidProtocol,
+            DerpError::WebSocketError(_) => DerpErrorCode::WebSocket,
+            DerpError::CryptoError(_) => DerpErrorCode::Crypto,
+            DerpError::SerializationError(_) => DerpErrorCode::Serialization,
+            DerpError::AuthRejected(_) => DerpErrorCode::AuthRejected,
+            DerpError::Timeout(_) => DerpErrorCode::Timeout,
+            DerpError::PacketTooLarge(_) => DerpErrorCode::PacketTooLarge,
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            DerpError::InvalidState(msg)
+            | DerpError::InvalidProtocol(msg)
+            | DerpError::WebSocketError(msg)
+            | DerpError::CryptoError(msg)
+            | DerpError::SerializationError(msg)
+            | DerpError::AuthRejected(msg)
+            | DerpError::Timeout(msg)
+            | DerpError::PacketTooLarge(msg) => msg,
+        }
+    }
+}
+
+impl Error for DerpError {}
+
+impl From<bincode::Error> for DerpError {
+    fn from(err: bincode::Error) -> Self {
+        DerpError::SerializationError(err.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for DerpError {
+    fn from(err: PoisonError<T>) -> Self {
+        DerpError::InvalidState(err.to_string())
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl From<DerpError> for JsValue {
+    fn from(err: DerpError) -> Self {
+        let error = js_sys::Error::new(&err.to_string());
+        error.set_name(err.kind());
+        // Setting a fresh property on a plain Error can't fail
+        let _ = js_sys::Reflect::set(&error, &"code".into(), &(err.code() as u32).into());
+        let _ = js_sys::Reflect::set(&error, &"detail".into(), &err.detail().into());
+        error.into()
+    }
+}
+
+pub type DerpResult<T> = Result<T, DerpError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    #[wasm_bindgen_test::wasm_bindgen_test]
+    fn test_errors_carry_code_and_detail() {
+        use wasm_bindgen::JsCast;
+
+        let value = JsValue::from(DerpError::AuthRejected("bad token".into()));
+        let error: &js_sys::Error = value.unchecked_ref();
+        assert_eq!(error.name(), "AuthRejected");
+        assert_eq!(error.message(), "Authentication rejected: bad token");
+
+        let code = js_sys::Reflect::get(&value, &"code".into()).unwrap();
+        assert_eq!(code.as_f64(), Some(DerpErrorCode::AuthRejected as u32 as f64));
+        let detail = js_sys::Reflect::get(&value, &"detail".into()).unwrap();
+        assert_eq!(detail.as_string().as_deref(), Some("bad token"));
+    }
+
+    #[test]
+    fn test_codes_are_stable() {
+        let codes = [
+            DerpError::InvalidState(String::new()),
+            DerpError::InvalidProtocol(String::new()),
+            DerpError::WebSocketError(String::new()),
+            DerpError::CryptoError(String::new()),
+            DerpError::SerializationError(String::new()),
+            DerpError::AuthRejected(String::new()),
+            DerpError::Timeout(String::new()),
+            DerpError::PacketTooLarge(String::new()),
+        ].iter().map(|err| err.code() as u32).collect::<Vec<_>>();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
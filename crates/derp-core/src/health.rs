@@ -0,0 +1,101 @@
+use serde::Serialize;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+
+/// Retries within a Restarting window are spaced at least this far apart,
+/// even if the relay asked to be tried again at once.
+pub const MIN_RESTART_RETRY_MS: f64 = 250.0;
+
+/// Payload of the "health" event, from a Health frame.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct HealthEvent {
+    /// What's wrong with the relay, or null once it's healthy again.
+    pub problem: Option<String>,
+}
+
+impl HealthEvent {
+    /// A Health payload is the problem as UTF-8; empty means none.
+    pub fn parse(payload: &[u8]) -> HealthEvent {
+        let problem = String::from_utf8_lossy(payload);
+        HealthEvent {
+            problem: (!problem.is_empty()).then(|| problem.into_owned()),
+        }
+    }
+}
+
+/// Payload of the "restarting" event: the relay is going away and expects
+/// to be back in `reconnectInMs`, so try for `tryForMs` after that.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct RestartingEvent {
+    pub reconnect_in_ms: u32,
+    pub try_for_ms: u32,
+}
+
+impl RestartingEvent {
+    /// A Restarting payload is both durations as big-endian u32s.
+    pub fn parse(payload: &[u8]) -> DerpResult<RestartingEvent> {
+        if payload.len() < 8 {
+            return Err(DerpError::InvalidProtocol("Invalid Restarting length".into()));
+        }
+        Ok(RestartingEvent {
+            reconnect_in_ms: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            try_for_ms: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+        })
+    }
+}
+
+/// When a restarting relay said to come back, which overrides the
+/// client's own backoff until its window closes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartHint {
+    back_at_ms: f64,
+    give_up_at_ms: f64,
+}
+
+impl RestartHint {
+    pub fn new(event: RestartingEvent, now_ms: f64) -> Self {
+        let back_at_ms = now_ms + event.reconnect_in_ms as f64;
+        RestartHint {
+            back_at_ms,
+            give_up_at_ms: back_at_ms + event.try_for_ms as f64,
+        }
+    }
+
+    /// How long to wait before the next attempt, or None once the window
+    /// has closed.
+    pub fn delay_ms(&self, now_ms: f64) -> Option<u32> {
+        if now_ms >= self.give_up_at_ms {
+            return None;
+        }
+        Some((self.back_at_ms - now_ms).max(MIN_RESTART_RETRY_MS) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_frames() {
+        assert_eq!(HealthEvent::parse(b"overloaded").problem.as_deref(), Some("overloaded"));
+        assert_eq!(HealthEvent::parse(b"").problem, None);
+
+        let payload = [0, 0, 0x13, 0x88, 0, 0, 0x75, 0x30];
+        assert_eq!(RestartingEvent::parse(&payload).unwrap(), RestartingEvent { reconnect_in_ms: 5000, try_for_ms: 30_000 });
+        assert!(RestartingEvent::parse(&payload[..7]).is_err());
+    }
+
+    #[test]
+    fn test_hint_covers_its_window() {
+        let hint = RestartHint::new(RestartingEvent { reconnect_in_ms: 5000, try_for_ms: 10_000 }, 1000.0);
+        assert_eq!(hint.delay_ms(1000.0), Some(5000));
+        assert_eq!(hint.delay_ms(5900.0), Some(250));
+        assert_eq!(hint.delay_ms(15_999.0), Some(250));
+        assert_eq!(hint.delay_ms(16_000.0), None);
+    }
+}
@@ -0,0 +1,49 @@
+/// Tells when a connection has carried no packets for `timeout_ms`, from
+/// the running packet count sampled on each keepalive tick.
+#[derive(Debug, Clone)]
+pub struct IdleWatch {
+    timeout_ms: f64,
+    packets: u64,
+    last_active_ms: f64,
+}
+
+impl IdleWatch {
+    pub fn new(timeout_ms: u32, packets: u64, now_ms: f64) -> Self {
+        IdleWatch {
+            timeout_ms: timeout_ms as f64,
+            packets,
+            last_active_ms: now_ms,
+        }
+    }
+
+    /// Notes the packets sent and received so far, returning true once
+    /// none have moved for the timeout.
+    pub fn is_idle(&mut self, packets: u64, now_ms: f64) -> bool {
+        if packets != self.packets {
+            self.packets = packets;
+            self.last_active_ms = now_ms;
+        }
+        now_ms - self.last_active_ms >= self.timeout_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_postpones_idling() {
+        let mut watch = IdleWatch::new(1000, 5, 0.0);
+        assert!(!watch.is_idle(5, 999.0));
+        assert!(!watch.is_idle(6, 1500.0));
+        assert!(!watch.is_idle(6, 2499.0));
+        assert!(watch.is_idle(6, 2500.0));
+    }
+
+    #[test]
+    fn test_starts_counting_from_creation() {
+        let mut watch = IdleWatch::new(30_000, 0, 10_000.0);
+        assert!(!watch.is_idle(0, 39_999.0));
+        assert!(watch.is_idle(0, 40_000.0));
+    }
+}
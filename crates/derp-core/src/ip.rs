@@ -0,0 +1,300 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+pub const PROTO_ICMPV6: u8 = 58;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const DEFAULT_TTL: u8 = 64;
+
+/// Borrowed view of an IPv4 packet.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub header: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Parses an IPv4 packet, trimming any link-layer padding after the
+    /// total length.
+    pub fn parse(data: &'a [u8]) -> Option<Ipv4Packet<'a>> {
+        if data.len() < IPV4_HEADER_LEN || data[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = ((data[0] & 0x0F) as usize) * 4;
+        let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if header_len < IPV4_HEADER_LEN || total_len < header_len || data.len() < total_len {
+            return None;
+        }
+
+        Some(Ipv4Packet {
+            src: Ipv4Addr::new(data[12], data[13], data[14], data[15]),
+            dst: Ipv4Addr::new(data[16], data[17], data[18], data[19]),
+            protocol: data[9],
+            ttl: data[8],
+            header: &data[..header_len],
+            payload: &data[header_len..total_len],
+        })
+    }
+}
+
+/// Borrowed view of an IPv6 packet. Extension headers are not walked, so
+/// `next_header` is whatever follows the fixed header.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Packet<'a> {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv6Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Ipv6Packet<'a>> {
+        if data.len() < IPV6_HEADER_LEN || data[0] >> 4 != 6 {
+            return None;
+        }
+
+        let payload_len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let payload = data.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+        let mut src = [0u8; 16];
+        let mut dst = [0u8; 16];
+        src.copy_from_slice(&data[8..24]);
+        dst.copy_from_slice(&data[24..40]);
+
+        Some(Ipv6Packet {
+            src: Ipv6Addr::from(src),
+            dst: Ipv6Addr::from(dst),
+            next_header: data[6],
+            hop_limit: data[7],
+            payload,
+        })
+    }
+}
+
+/// Borrowed view of a UDP datagram.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<UdpDatagram<'a>> {
+        if data.len() < UDP_HEADER_LEN {
+            return None;
+        }
+
+        let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+        if length < UDP_HEADER_LEN || data.len() < length {
+            return None;
+        }
+
+        Some(UdpDatagram {
+            src_port: u16::from_be_bytes([data[0], data[1]]),
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            payload: &data[UDP_HEADER_LEN..length],
+        })
+    }
+}
+
+/// RFC 1071 Internet checksum.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds an IPv4 packet around `payload`.
+pub fn build_ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // Identification 0, Don't Fragment
+    packet.extend_from_slice(&[0, 0, 0x40, 0]);
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+
+    let header_checksum = checksum(&packet[..IPV4_HEADER_LEN]);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Builds a complete IPv4/UDP packet, checksum included.
+pub fn build_udp(src: Ipv4Addr, dst: Ipv4Addr, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut segment = udp_segment(src_port, dst_port, payload);
+    let udp_checksum = udp_checksum(&pseudo_header(src, dst, PROTO_UDP, &segment));
+    segment[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    build_ipv4(src, dst, PROTO_UDP, &segment)
+}
+
+/// Builds a complete IPv6/UDP packet, checksum included.
+pub fn build_udp_v6(src: Ipv6Addr, dst: Ipv6Addr, src_port: u16, dst_port: u16, hop_limit: u8, payload: &[u8]) -> Vec<u8> {
+    let mut segment = udp_segment(src_port, dst_port, payload);
+    let udp_checksum = udp_checksum(&pseudo_header_v6(src, dst, PROTO_UDP, &segment));
+    segment[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    build_ipv6(src, dst, PROTO_UDP, hop_limit, &segment)
+}
+
+/// A UDP header, checksum still zero, followed by `payload`.
+fn udp_segment(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let length = (UDP_HEADER_LEN + payload.len()) as u16;
+    let mut segment = Vec::with_capacity(length as usize);
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst_port.to_be_bytes());
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(payload);
+    segment
+}
+
+fn udp_checksum(pseudo_header: &[u8]) -> u16 {
+    // Zero means "no checksum" in UDP, so it's sent as all ones instead
+    match checksum(pseudo_header) {
+        0 => 0xFFFF,
+        sum => sum,
+    }
+}
+
+/// Rewrites the TTL of an IPv4 packet built here, fixing up the header checksum.
+pub fn set_ttl(packet: &mut [u8], ttl: u8) {
+    packet[8] = ttl;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let header_checksum = checksum(&packet[..IPV4_HEADER_LEN]);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+}
+
+/// The TCP/UDP pseudo-header followed by the segment, ready to checksum.
+pub fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(12 + segment.len());
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    data.extend_from_slice(&[0, protocol]);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    data
+}
+
+/// Builds an IPv6 packet around `payload`.
+pub fn build_ipv6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, hop_limit: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + payload.len());
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[next_header, hop_limit]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// The IPv6 pseudo-header followed by the upper-layer packet, ready to checksum.
+pub fn pseudo_header_v6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, segment: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(40 + segment.len());
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    data.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+    data.extend_from_slice(&[0, 0, 0, next_header]);
+    data.extend_from_slice(segment);
+    data
+}
+
+/// The source of an IPv4 or IPv6 packet.
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Ipv4Packet::parse(packet).map(|ip| ip.src.into()),
+        6 => Ipv6Packet::parse(packet).map(|ip| ip.src.into()),
+        _ => None,
+    }
+}
+
+/// The destination of an IPv4 or IPv6 packet.
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Ipv4Packet::parse(packet).map(|ip| ip.dst.into()),
+        6 => Ipv6Packet::parse(packet).map(|ip| ip.dst.into()),
+        _ => None,
+    }
+}
+
+/// Parses "10.0.0.0/8" notation. A bare address is a /32.
+pub fn parse_cidr(text: &str) -> Option<(Ipv4Addr, u8)> {
+    let (address, prefix) = match text.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse().ok()?),
+        None => (text, 32),
+    };
+    if prefix > 32 {
+        return None;
+    }
+    Some((address.parse().ok()?, prefix))
+}
+
+pub fn in_subnet(ip: Ipv4Addr, network: Ipv4Addr, prefix: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(network) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_roundtrip() {
+        let src = Ipv4Addr::new(192, 168, 86, 1);
+        let dst = Ipv4Addr::new(192, 168, 86, 100);
+        let mut packet = build_udp(src, dst, 67, 68, b"hello");
+        // Ethernet minimum-frame padding is ignored
+        packet.extend_from_slice(&[0; 4]);
+
+        let ip = Ipv4Packet::parse(&packet).unwrap();
+        assert_eq!(ip.src, src);
+        assert_eq!(ip.protocol, PROTO_UDP);
+        assert_eq!(checksum(ip.header), 0);
+
+        let udp = UdpDatagram::parse(ip.payload).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (67, 68));
+        assert_eq!(udp.payload, b"hello");
+        assert_eq!(checksum(&pseudo_header(src, dst, PROTO_UDP, ip.payload)), 0);
+    }
+
+    #[test]
+    fn test_rejects_truncated() {
+        let packet = build_ipv4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, PROTO_ICMP, &[0; 8]);
+        assert!(Ipv4Packet::parse(&packet[..packet.len() - 1]).is_none());
+        assert!(UdpDatagram::parse(&[0; 4]).is_none());
+    }
+
+    #[test]
+    fn test_cidr() {
+        let (network, prefix) = parse_cidr("100.64.0.0/10").unwrap();
+        assert!(in_subnet(Ipv4Addr::new(100, 100, 1, 1), network, prefix));
+        assert!(!in_subnet(Ipv4Addr::new(100, 128, 0, 1), network, prefix));
+        assert!(in_subnet(Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::UNSPECIFIED, 0));
+        assert_eq!(parse_cidr("10.0.0.1"), Some((Ipv4Addr::new(10, 0, 0, 1), 32)));
+        assert!(parse_cidr("10.0.0.0/33").is_none());
+    }
+}
@@ -0,0 +1,28 @@
+//! The relay protocol without the browser: frame encoding and the client
+//! handshake (`ProtocolState`), config, errors, session crypto, the
+//! reconnect driver and budget, idle tracking, bandwidth estimation,
+//! buffer pooling, callback-shared state, and the packet parsing the
+//! config's checks need. It builds and tests natively, so `derp-server`
+//! uses it directly; `derp-wasm` re-exports each module under its old path
+//! and adds the WebSocket and JS glue.
+
+pub mod acl;
+pub mod bandwidth;
+pub mod breaker;
+pub mod clock;
+pub mod config;
+pub mod crypto;
+pub mod derpmap;
+pub mod driver;
+pub mod error;
+pub mod health;
+pub mod idle;
+pub mod ip;
+pub mod mdns;
+pub mod padding;
+pub mod pool;
+pub mod protocol;
+pub mod shared;
+pub mod switchboard;
+pub mod timing;
+pub mod wire;
@@ -0,0 +1,205 @@
+use std::net::Ipv4Addr;
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The Ethernet address 224.0.0.251 maps to.
+pub const MDNS_MAC: [u8; 6] = [0x01, 0x00, 0x5E, 0x00, 0x00, 0xFB];
+/// RFC 6762 requires multicast responses to be sent with IP TTL 255.
+pub const MDNS_TTL: u8 = 255;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: QU in questions, cache-flush in answers.
+const CLASS_FLAG: u16 = 0x8000;
+const RECORD_TTL_SECS: u32 = 120;
+/// Legacy unicast answers must not be cached for longer than this.
+const LEGACY_TTL_SECS: u32 = 10;
+const MAX_POINTER_HOPS: usize = 16;
+
+/// Answers multicast DNS (RFC 6762) A queries for names the virtual network
+/// owns, such as "host.local" for the gateway. Queries for other names are
+/// left to the guests that own them.
+#[derive(Debug, Default)]
+pub struct MdnsResponder {
+    records: Vec<(String, Ipv4Addr)>,
+}
+
+impl MdnsResponder {
+    pub fn new() -> Self {
+        MdnsResponder::default()
+    }
+
+    /// Answers queries for `name` (case-insensitive, trailing dot optional)
+    /// with `address`.
+    pub fn add(&mut self, name: &str, address: Ipv4Addr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.records.retain(|(existing, _)| *existing != name);
+        self.records.push((name, address));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The response to `query`, if it asks about a name this responder owns.
+    /// A legacy query, one not sent from port 5353, gets a unicast-style
+    /// reply that echoes its ID and question, as a plain resolver expects.
+    pub fn answer(&self, query: &[u8], legacy: bool) -> Option<Vec<u8>> {
+        // QR clear, opcode QUERY
+        if query.len() < HEADER_LEN || query[2] & 0xF8 != 0 {
+            return None;
+        }
+
+        let questions = u16::from_be_bytes([query[4], query[5]]);
+        let mut offset = HEADER_LEN;
+        let mut answers = Vec::new();
+        let mut echoed = None;
+        for _ in 0..questions {
+            let (name, name_end) = read_name(query, offset)?;
+            let fields = query.get(name_end..name_end + 4)?;
+            let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+            let qclass = u16::from_be_bytes([fields[2], fields[3]]) & !CLASS_FLAG;
+            if qclass == CLASS_IN && (qtype == TYPE_A || qtype == TYPE_ANY) {
+                if let Some((owned, address)) = self.records.iter().find(|(owned, _)| *owned == name) {
+                    if !answers.iter().any(|&(answered, _)| answered == owned) {
+                        answers.push((owned, *address));
+                        echoed.get_or_insert(offset..name_end + 4);
+                    }
+                }
+            }
+            offset = name_end + 4;
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut response = Vec::new();
+        let id = if legacy { [query[0], query[1]] } else { [0, 0] };
+        response.extend_from_slice(&id);
+        // QR and AA set
+        response.extend_from_slice(&[0x84, 0x00]);
+        response.extend_from_slice(&[0, legacy as u8]);
+        response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        if legacy {
+            response.extend_from_slice(&query[echoed?]);
+        }
+
+        let (class, ttl) = if legacy {
+            (CLASS_IN, LEGACY_TTL_SECS)
+        } else {
+            // The gateway is the only owner, so caches may drop other answers
+            (CLASS_IN | CLASS_FLAG, RECORD_TTL_SECS)
+        };
+        for (name, address) in answers {
+            write_name(&mut response, name);
+            response.extend_from_slice(&TYPE_A.to_be_bytes());
+            response.extend_from_slice(&class.to_be_bytes());
+            response.extend_from_slice(&ttl.to_be_bytes());
+            response.extend_from_slice(&4u16.to_be_bytes());
+            response.extend_from_slice(&address.octets());
+        }
+        Some(response)
+    }
+}
+
+/// Reads the (possibly compressed) name at `offset`, lowercased. Returns it
+/// with the offset just past its encoding in place.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_POINTER_HOPS {
+        loop {
+            let len = *packet.get(offset)? as usize;
+            match len {
+                0 => {
+                    return Some((labels.join("."), end.unwrap_or(offset + 1)));
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let pointer = u16::from_be_bytes([len as u8, *packet.get(offset + 1)?]) & 0x3FFF;
+                    end.get_or_insert(offset + 2);
+                    offset = pointer as usize;
+                    break;
+                }
+                len if len & 0xC0 == 0 => {
+                    let label = packet.get(offset + 1..offset + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                    offset += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+    }
+    None
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Whether `name` can be answered over mDNS: in the .local domain, with
+/// labels DNS can encode.
+pub fn is_valid_name(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    name.to_ascii_lowercase().ends_with(".local")
+        && name.split('.').all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 1);
+
+    fn query(id: u16, names: &[&str]) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0, 0, 0, names.len() as u8, 0, 0, 0, 0, 0, 0]);
+        for name in names {
+            write_name(&mut packet, name);
+            // QTYPE A, QCLASS IN with the QU bit
+            packet.extend_from_slice(&[0, 1, 0x80, 1]);
+        }
+        packet
+    }
+
+    fn responder() -> MdnsResponder {
+        let mut responder = MdnsResponder::new();
+        responder.add("Host.local.", GATEWAY);
+        responder
+    }
+
+    #[test]
+    fn test_answers_owned_names() {
+        let responder = responder();
+        assert_eq!(responder.answer(&query(7, &["printer.local"]), false), None);
+
+        let response = responder.answer(&query(7, &["printer.local", "HOST.local"]), false).unwrap();
+        // Multicast responses carry ID 0, no questions and one answer
+        assert_eq!(&response[..HEADER_LEN], &[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let (name, end) = read_name(&response, HEADER_LEN).unwrap();
+        assert_eq!(name, "host.local");
+        assert_eq!(&response[end..end + 4], &[0, 1, 0x80, 1]);
+        assert_eq!(&response[response.len() - 4..], &GATEWAY.octets());
+    }
+
+    #[test]
+    fn test_legacy_query_gets_unicast_reply() {
+        let query = query(0x1234, &["host.local"]);
+        let response = responder().answer(&query, true).unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(&response[4..8], &[0, 1, 0, 1]);
+        assert_eq!(&response[HEADER_LEN..query.len()], &query[HEADER_LEN..]);
+        let ttl_at = response.len() - 10;
+        assert_eq!(&response[ttl_at..ttl_at + 4], &LEGACY_TTL_SECS.to_be_bytes());
+
+        assert!(is_valid_name("host.local"));
+        assert!(!is_valid_name("host.example"));
+        assert!(!is_valid_name("bad..local"));
+    }
+}
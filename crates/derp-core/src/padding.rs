@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+use crate::protocol::MAX_FRAME_PAYLOAD;
+
+/// Payload sizes frames are padded up to under `PaddingPolicy::Buckets`,
+/// so an observer of the socket sees one of a handful of sizes rather than
+/// each packet's own.
+pub const BUCKETS: [usize; 7] = [128, 256, 512, 1024, 1600, 4096, MAX_FRAME_PAYLOAD];
+
+/// Under `PaddingPolicy::Fixed`, every frame up to this size is padded to
+/// it: room for a full 1500-byte packet with its tags and overhead.
+pub const FIXED_PAYLOAD_LEN: usize = 1600;
+
+/// The trailer counting the padding, which the receiver strips.
+const TRAILER_LEN: usize = 2;
+
+/// How encrypted frames are padded, when the relay agrees to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "lowercase")]
+pub enum PaddingPolicy {
+    #[default]
+    Off,
+    /// Up to the next of `BUCKETS`.
+    Buckets,
+    /// Up to `FIXED_PAYLOAD_LEN`, so all but bulk transfers look alike;
+    /// larger frames go to buckets.
+    Fixed,
+}
+
+impl PaddingPolicy {
+    /// The payload length a frame whose payload would be `wire_len` bytes
+    /// is padded to, leaving room for the trailer. None when padding's off.
+    pub fn padded_len(self, wire_len: usize) -> Option<usize> {
+        let min_len = wire_len + TRAILER_LEN;
+        let bucket = || BUCKETS.iter().copied().find(|&bucket| bucket >= min_len).unwrap_or(min_len);
+        match self {
+            PaddingPolicy::Off => None,
+            PaddingPolicy::Buckets => Some(bucket()),
+            PaddingPolicy::Fixed if min_len <= FIXED_PAYLOAD_LEN => Some(FIXED_PAYLOAD_LEN),
+            PaddingPolicy::Fixed => Some(bucket()),
+        }
+    }
+}
+
+/// Appends `len` bytes of padding to `plaintext`, the last two counting them.
+pub fn pad(plaintext: &mut Vec<u8>, len: usize) {
+    let len = len.max(TRAILER_LEN);
+    plaintext.resize(plaintext.len() + len - TRAILER_LEN, 0);
+    plaintext.extend_from_slice(&(len as u16).to_be_bytes());
+}
+
+/// Strips what `pad` appended.
+pub fn unpad(plaintext: &mut Vec<u8>) -> DerpResult<()> {
+    let trailer = plaintext.len().checked_sub(TRAILER_LEN)
+        .ok_or_else(|| DerpError::InvalidProtocol("Padded frame too short".into()))?;
+    let len = u16::from_be_bytes([plaintext[trailer], plaintext[trailer + 1]]) as usize;
+    if len < TRAILER_LEN || len > plaintext.len() {
+        return Err(DerpError::InvalidProtocol("Invalid padding length".into()));
+    }
+    plaintext.truncate(plaintext.len() - len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_lengths() {
+        assert_eq!(PaddingPolicy::Off.padded_len(60), None);
+        assert_eq!(PaddingPolicy::Buckets.padded_len(60), Some(128));
+        assert_eq!(PaddingPolicy::Buckets.padded_len(126), Some(128));
+        assert_eq!(PaddingPolicy::Buckets.padded_len(127), Some(256));
+        assert_eq!(PaddingPolicy::Fixed.padded_len(60), Some(FIXED_PAYLOAD_LEN));
+        assert_eq!(PaddingPolicy::Fixed.padded_len(2000), Some(4096));
+    }
+
+    #[test]
+    fn test_pad_roundtrip() {
+        let mut plaintext = b"keystroke".to_vec();
+        pad(&mut plaintext, 100);
+        assert_eq!(plaintext.len(), 109);
+        unpad(&mut plaintext).unwrap();
+        assert_eq!(plaintext, b"keystroke");
+
+        assert!(unpad(&mut vec![1]).is_err());
+        assert!(unpad(&mut vec![0, 0, 0, 9]).is_err());
+        assert!(unpad(&mut vec![0, 0, 0, 1]).is_err());
+    }
+}
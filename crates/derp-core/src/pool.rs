@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+
+const MAX_POOLED: usize = 16;
+/// Buffers that grew beyond this are freed instead of being kept around.
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Free list of byte buffers reused for frames and plaintexts, so a
+/// long-running session stops allocating once the pool has warmed up.
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::default()
+    }
+
+    /// Returns an empty buffer, reusing a pooled allocation when available.
+    pub fn take(&self) -> Vec<u8> {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Hands a buffer back for reuse.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_RETAINED_CAPACITY {
+            return;
+        }
+        buffer.clear();
+
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_POOLED {
+            free.push(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes allocated by the pooled buffers.
+    pub fn retained_bytes(&self) -> usize {
+        self.free.lock().unwrap().iter().map(Vec::capacity).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuses_allocation() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let ptr = buffer.as_ptr();
+
+        pool.give(buffer);
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let pool = BufferPool::new();
+        pool.give(Vec::with_capacity(MAX_RETAINED_CAPACITY + 1));
+        assert!(pool.is_empty());
+
+        for _ in 0..MAX_POOLED + 4 {
+            pool.give(Vec::with_capacity(64));
+        }
+        assert_eq!(pool.len(), MAX_POOLED);
+    }
+}
@@ -0,0 +1,1594 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use crate::acl::{AclStats, PeerAcl};
+use crate::clock::{self, Clock};
+use crate::config::{DerpConfig, DEFAULT_KEEPALIVE_INTERVAL_MS};
+use crate::crypto::{CipherSuite, CryptoState, CIPHERTEXT_OVERHEAD};
+use crate::error::{DerpError, DerpResult};
+use crate::health::{HealthEvent, RestartHint, RestartingEvent};
+use crate::padding::{self, PaddingPolicy};
+use crate::pool::BufferPool;
+use crate::switchboard::Switchboard;
+use crate::timing::{Phase, Stopwatch};
+use crate::wire::{self, GoClientInfo, GoServerInfo, WireFormat, GO_PROTOCOL_VERSION};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const FRAME_HEADER_SIZE: usize = 5;
+pub const PEER_KEY_LEN: usize = 32;
+/// The header's length field is 16 bits.
+pub const MAX_FRAME_PAYLOAD: usize = u16::MAX as usize;
+pub const FLAG_COMPRESSED: u8 = 0x01;
+/// The plaintext ends in padding; see `padding::pad`.
+pub const FLAG_PADDED: u8 = 0x02;
+/// A control message encoded as CBOR; on ServerInfo, accepts the client's
+/// offer of it.
+pub const FLAG_CBOR: u8 = 0x04;
+/// The payload ends in a CRC32 of the frame before it. Only unencrypted
+/// frames carry one; the AEAD tag covers the rest.
+pub const FLAG_CHECKSUM: u8 = 0x08;
+/// The payload is sealed with AES-GCM-SIV rather than AES-GCM. Being in the
+/// authenticated header, it can't be flipped to make a peer try the other.
+pub const FLAG_SIV: u8 = 0x10;
+pub const CHECKSUM_LEN: usize = 4;
+pub const COMPRESSION_LEVEL: u8 = 6;
+
+/// Feature names exchanged in ClientInfo/ServerInfo.
+pub const FEATURE_BATCHING: &str = "batching";
+pub const FEATURE_PADDING: &str = "padding";
+pub const FEATURE_CBOR: &str = "cbor";
+pub const FEATURE_AES_GCM_SIV: &str = "aes-256-gcm-siv";
+
+pub type PeerKey = [u8; PEER_KEY_LEN];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum FrameType {
+    ServerKey = 1,
+    ClientInfo = 2,
+    ServerInfo = 3,
+    Send = 4,
+    RecvFromPeer = 5,
+    PeerPresent = 6,
+    PeerGone = 7,
+    KeepAlive = 8,
+    Ping = 9,
+    Pong = 10,
+    Auth = 11,
+    AuthResult = 12,
+    /// The sender's guest MACs; the relay prefixes the sender's key when
+    /// passing it on to the other peers.
+    MacAnnounce = 13,
+    /// Like Send, but for one peer: the destination key, then the ciphertext.
+    SendToPeer = 14,
+    /// Sent before a deliberate close, so the relay tells the other peers
+    /// at once instead of when it notices the socket has gone.
+    Goodbye = 15,
+    /// From the relay: what's degraded about its service, as UTF-8, or
+    /// nothing once it's recovered.
+    Health = 16,
+    /// From the relay, before it closes to restart: when to reconnect and
+    /// for how long to keep trying; see `RestartingEvent`.
+    Restarting = 17,
+    /// One byte, 1 if this relay is the client's home region. Relays
+    /// favour home connections when they have to shed load.
+    NotePreferred = 18,
+    /// Asks the relay for PeerPresent frames for everyone connected now,
+    /// on top of the ones it sends as peers come and go.
+    WatchConns = 19,
+    /// A peer's key. From a client, asks the relay to disconnect that peer;
+    /// from the relay, says a peer was disconnected that way.
+    ClosePeer = 20,
+    /// Encrypted like Send: a `mesh::ForwardHeader`, then a packet. From
+    /// the relay, a packet for a peer on another relay that this client
+    /// bridges to; from a client, one to deliver to a peer here.
+    ForwardPacket = 21,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = DerpError;
+
+    fn try_from(value: u8) -> DerpResult<Self> {
+        match value {
+            1 => Ok(FrameType::ServerKey),
+            2 => Ok(FrameType::ClientInfo),
+            3 => Ok(FrameType::ServerInfo),
+            4 => Ok(FrameType::Send),
+            5 => Ok(FrameType::RecvFromPeer),
+            6 => Ok(FrameType::PeerPresent),
+            7 => Ok(FrameType::PeerGone),
+            8 => Ok(FrameType::KeepAlive),
+            9 => Ok(FrameType::Ping),
+            10 => Ok(FrameType::Pong),
+            11 => Ok(FrameType::Auth),
+            12 => Ok(FrameType::AuthResult),
+            13 => Ok(FrameType::MacAnnounce),
+            14 => Ok(FrameType::SendToPeer),
+            15 => Ok(FrameType::Goodbye),
+            16 => Ok(FrameType::Health),
+            17 => Ok(FrameType::Restarting),
+            18 => Ok(FrameType::NotePreferred),
+            19 => Ok(FrameType::WatchConns),
+            20 => Ok(FrameType::ClosePeer),
+            21 => Ok(FrameType::ForwardPacket),
+            _ => Err(DerpError::InvalidProtocol(format!("Unknown frame type: {}", value))),
+        }
+    }
+}
+
+/// Borrowed view of one received frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub frame_type: FrameType,
+    pub flags: u8,
+    /// The raw header, authenticated as associated data on encrypted frames.
+    pub header: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parses the frame at the start of `data` without copying it.
+    /// A checksum, if flagged, is verified before anything else is trusted
+    /// and isn't part of the returned payload.
+    pub fn parse(data: &'a [u8]) -> DerpResult<Frame<'a>> {
+        let frame_len = ProtocolState::frame_len(data)?;
+        let flags = data[2];
+        let payload = if flags & FLAG_CHECKSUM != 0 {
+            verify_checksum(&data[..frame_len])?
+        } else {
+            &data[FRAME_HEADER_SIZE..frame_len]
+        };
+        Ok(Frame {
+            frame_type: FrameType::try_from(data[1])?,
+            flags,
+            header: &data[..FRAME_HEADER_SIZE],
+            payload,
+        })
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub fn is_padded(&self) -> bool {
+        self.flags & FLAG_PADDED != 0
+    }
+
+    pub fn is_cbor(&self) -> bool {
+        self.flags & FLAG_CBOR != 0
+    }
+
+    /// The AEAD the payload is sealed with, as the sender flagged it.
+    pub fn cipher_suite(&self) -> DerpResult<CipherSuite> {
+        cipher_suite_for(self.flags)
+    }
+}
+
+/// The cipher suite `flags` name: AES-GCM-SIV if `FLAG_SIV` is set.
+pub fn cipher_suite_for(flags: u8) -> DerpResult<CipherSuite> {
+    match flags & FLAG_SIV {
+        0 => Ok(CipherSuite::Aes256Gcm),
+        #[cfg(feature = "siv")]
+        _ => Ok(CipherSuite::Aes256GcmSiv),
+        #[cfg(not(feature = "siv"))]
+        _ => Err(DerpError::CryptoError("AES-GCM-SIV frames need the siv feature".into())),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientInfo {
+    version: u8,
+    token: String,
+    mac_address: String,
+    features: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerInfo {
+    version: u8,
+    name: String,
+    region: String,
+    keepalive_interval_ms: u32,
+    max_packet_size: u32,
+    features: Vec<String>,
+}
+
+impl ClientInfo {
+    pub fn new(features: Vec<String>) -> ClientInfo {
+        ClientInfo {
+            version: PROTOCOL_VERSION,
+            token: String::new(),
+            mac_address: String::new(),
+            features,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+}
+
+impl ServerInfo {
+    /// A relay's answer to ClientInfo, agreeing to `features`.
+    pub fn new(name: &str, features: Vec<String>) -> ServerInfo {
+        ServerInfo {
+            version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            region: String::new(),
+            keepalive_interval_ms: DEFAULT_KEEPALIVE_INTERVAL_MS,
+            max_packet_size: u16::MAX as u32,
+            features,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn with_region(mut self, region: &str) -> ServerInfo {
+        self.region = region.to_string();
+        self
+    }
+
+    /// How often the relay sends KeepAlive, which clients match.
+    pub fn with_keepalive_interval_ms(mut self, interval_ms: u32) -> ServerInfo {
+        self.keepalive_interval_ms = interval_ms;
+        self
+    }
+
+    /// Takes in what a Go server sent, filling in what Go doesn't say.
+    fn from_go(info: GoServerInfo) -> DerpResult<ServerInfo> {
+        if info.version != GO_PROTOCOL_VERSION {
+            return Err(DerpError::InvalidProtocol(format!("Unsupported server version: {}", info.version)));
+        }
+        let mut server_info = ServerInfo::new("", info.features);
+        server_info.max_packet_size = info.max_packet_size.unwrap_or(server_info.max_packet_size);
+        Ok(server_info)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeState {
+    Idle,
+    AwaitingServerKey,
+    AwaitingServerInfo,
+    AwaitingAuth,
+    Connected,
+    Rejected,
+}
+
+/// Client side of the relay framing and handshake, shared between
+/// `NetworkState` and its WebSocket callbacks.
+pub struct ProtocolState {
+    handshake: HandshakeState,
+    server_key: Option<Vec<u8>>,
+    /// The only key the relay may present, if the embedder supplied one.
+    pinned_server_key: Option<PeerKey>,
+    server_info: Option<ServerInfo>,
+    wire_format: WireFormat,
+    peers: HashSet<PeerKey>,
+    switchboard: Switchboard,
+    accept_new_peers: bool,
+    acl: PeerAcl,
+    auth_token: Option<String>,
+    rejection: Option<String>,
+    /// The relay's last Health report on this connection.
+    health_problem: Option<String>,
+    restart_hint: Option<RestartHint>,
+    /// Whether this is the home region's connection; see `set_preferred`.
+    preferred: bool,
+    /// Set by `watch_conns`, so the request is repeated on reconnect.
+    watching: bool,
+    config: DerpConfig,
+    clock: Arc<dyn Clock>,
+    stopwatch: Arc<Stopwatch>,
+    handshake_started_ms: f64,
+    last_sent_ms: f64,
+    last_received_ms: f64,
+    pool: BufferPool,
+}
+
+impl ProtocolState {
+    pub fn new() -> Self {
+        ProtocolState::with_config(DerpConfig::default())
+    }
+
+    pub fn with_config(config: DerpConfig) -> Self {
+        ProtocolState::with_clock(config, clock::system())
+    }
+
+    pub fn with_clock(config: DerpConfig, clock: Arc<dyn Clock>) -> Self {
+        ProtocolState {
+            handshake: HandshakeState::Idle,
+            server_key: None,
+            pinned_server_key: None,
+            server_info: None,
+            wire_format: WireFormat::Bincode,
+            peers: HashSet::new(),
+            switchboard: Switchboard::default(),
+            accept_new_peers: true,
+            acl: PeerAcl::new(config.allowed_peer_keys()),
+            auth_token: None,
+            rejection: None,
+            health_problem: None,
+            restart_hint: None,
+            preferred: false,
+            watching: false,
+            config,
+            stopwatch: Arc::new(Stopwatch::new(clock.clone())),
+            clock,
+            handshake_started_ms: 0.0,
+            last_sent_ms: 0.0,
+            last_received_ms: 0.0,
+            pool: BufferPool::new(),
+        }
+    }
+
+    /// Token sent in an Auth frame once the handshake completes. Browsers
+    /// can't set headers on WebSocket upgrades, so it travels in-band.
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token;
+    }
+
+    pub fn frame_header(&self, frame_type: FrameType, flags: u8, payload_len: usize) -> [u8; FRAME_HEADER_SIZE] {
+        let length = (payload_len as u16).to_be_bytes();
+        [PROTOCOL_VERSION, frame_type as u8, flags, length[0], length[1]]
+    }
+
+    /// Frames are built in pooled buffers; pass them to `recycle` once sent.
+    pub fn encode_frame(&self, frame_type: FrameType, payload: &[u8]) -> Vec<u8> {
+        self.encode_plain_frame(frame_type, 0, payload, self.config.control_checksums)
+    }
+
+    fn encode_plain_frame(&self, frame_type: FrameType, flags: u8, payload: &[u8], checksum: bool) -> Vec<u8> {
+        let (flags, payload_len) = match checksum {
+            true => (flags | FLAG_CHECKSUM, payload.len() + CHECKSUM_LEN),
+            false => (flags, payload.len()),
+        };
+        let mut frame = self.pool.take();
+        frame.reserve(FRAME_HEADER_SIZE + payload_len);
+        frame.extend_from_slice(&self.frame_header(frame_type, flags, payload_len));
+        frame.extend_from_slice(payload);
+        if checksum {
+            let crc = crc32fast::hash(&frame);
+            frame.extend_from_slice(&crc.to_be_bytes());
+        }
+        frame
+    }
+
+    /// Encodes a control message as `format` has it, flagging CBOR so the
+    /// other side needn't have tracked the negotiation.
+    pub fn encode_control_frame<T: Serialize>(&self, frame_type: FrameType, format: WireFormat, value: &T) -> DerpResult<Vec<u8>> {
+        let (flags, payload) = match format {
+            WireFormat::Bincode => (0, bincode::serialize(value)?),
+            WireFormat::Cbor => (FLAG_CBOR, wire::encode_cbor(value)?),
+            WireFormat::Json => (0, wire::encode_json(value)?),
+        };
+        // Go's JSON decoder would choke on the trailing checksum
+        let checksum = self.config.control_checksums && format != WireFormat::Json;
+        check_payload_len(payload.len() + if checksum { CHECKSUM_LEN } else { 0 })?;
+        Ok(self.encode_plain_frame(frame_type, flags, &payload, checksum))
+    }
+
+    /// Encrypts `data` into a frame whose header is authenticated as AEAD
+    /// associated data, so the frame type and length can't be altered in transit.
+    /// Compression, if enabled, is applied before encryption.
+    pub fn encode_encrypted_frame(&self, crypto: &CryptoState, frame_type: FrameType, data: &[u8]) -> DerpResult<Vec<u8>> {
+        let mut frame = self.pool.take();
+        if let Err(e) = self.encode_encrypted_frame_into(crypto, frame_type, data, &mut frame) {
+            self.pool.give(frame);
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    /// Like `encode_encrypted_frame`, but writes into `frame`, replacing its
+    /// contents. Used on the send path so one buffer serves every packet.
+    pub fn encode_encrypted_frame_into(
+        &self,
+        crypto: &CryptoState,
+        frame_type: FrameType,
+        data: &[u8],
+        frame: &mut Vec<u8>,
+    ) -> DerpResult<()> {
+        let (flags, plaintext) = self.prepare_plaintext(data, CIPHERTEXT_OVERHEAD);
+        let payload_len = check_payload_len(plaintext.len() + CIPHERTEXT_OVERHEAD)?;
+        let header = self.frame_header(frame_type, flags, payload_len);
+        frame.clear();
+        frame.extend_from_slice(&header);
+        self.stopwatch.time(Phase::Encrypt, || crypto.encrypt_into_with(self.cipher_suite(), &plaintext, &header, frame))
+    }
+
+    /// Encrypts `data` into a Send frame for every peer.
+    pub fn encode_packet_frame_into(&self, crypto: &CryptoState, data: &[u8], frame: &mut Vec<u8>) -> DerpResult<()> {
+        self.encode_for_peers(crypto, None, data, frame)
+    }
+
+    /// Encrypts `data` into a SendToPeer frame for `peer`. The destination
+    /// key travels in the clear for the relay to route on.
+    pub fn encode_peer_frame_into(
+        &self,
+        crypto: &CryptoState,
+        peer: &PeerKey,
+        data: &[u8],
+        frame: &mut Vec<u8>,
+    ) -> DerpResult<()> {
+        self.encode_for_peers(crypto, Some(peer), data, frame)
+    }
+
+    /// The relay hands packets on as RecvFromPeer frames, with the sender's
+    /// key in place of any destination, so they're sealed against the header
+    /// they'll arrive under rather than the one they're sent with.
+    fn encode_for_peers(
+        &self,
+        crypto: &CryptoState,
+        peer: Option<&PeerKey>,
+        data: &[u8],
+        frame: &mut Vec<u8>,
+    ) -> DerpResult<()> {
+        let (flags, plaintext) = self.prepare_plaintext(data, PEER_KEY_LEN + CIPHERTEXT_OVERHEAD);
+        let ciphertext_len = plaintext.len() + CIPHERTEXT_OVERHEAD;
+        let delivered_len = check_payload_len(PEER_KEY_LEN + ciphertext_len)?;
+        let aad = self.frame_header(FrameType::RecvFromPeer, flags, delivered_len);
+        frame.clear();
+        match peer {
+            Some(peer) => {
+                frame.extend_from_slice(&self.frame_header(FrameType::SendToPeer, flags, delivered_len));
+                frame.extend_from_slice(peer);
+            }
+            None => frame.extend_from_slice(&self.frame_header(FrameType::Send, flags, ciphertext_len)),
+        }
+        self.stopwatch.time(Phase::Encrypt, || crypto.encrypt_into_with(self.cipher_suite(), &plaintext, &aad, frame))
+    }
+
+    /// Decrypts the payload of an encrypted frame, checking the received
+    /// header against the AEAD tag.
+    pub fn decrypt_frame(&self, crypto: &CryptoState, frame: &[u8]) -> DerpResult<Vec<u8>> {
+        let mut plaintext = self.pool.take();
+        if let Err(e) = self.decrypt_frame_into(crypto, &Frame::parse(frame)?, &mut plaintext) {
+            self.pool.give(plaintext);
+            return Err(e);
+        }
+        Ok(plaintext)
+    }
+
+    /// Like `decrypt_frame`, but takes an already parsed frame and writes the
+    /// plaintext into `out`, replacing its contents. The ciphertext is read in
+    /// place; only decompression needs a second buffer.
+    pub fn decrypt_frame_into(&self, crypto: &CryptoState, frame: &Frame, out: &mut Vec<u8>) -> DerpResult<()> {
+        out.clear();
+        let suite = frame.cipher_suite()?;
+        self.stopwatch.time(Phase::Decrypt, || crypto.decrypt_into_with(suite, frame.payload, frame.header, out))?;
+
+        if frame.is_padded() {
+            padding::unpad(out)?;
+        }
+        let max_packet_size = self.max_packet_size();
+        if frame.is_compressed() {
+            // Capped by what the relay forwards, so a small frame can't
+            // inflate into more than any real packet
+            let limit = self.config.receive_buffer_size.min(max_packet_size);
+            let inflated = self.stopwatch.time(Phase::Decompress, || inflate(&out[..], limit))?;
+            self.pool.give(std::mem::replace(out, inflated));
+        } else if out.len() > max_packet_size {
+            return Err(DerpError::PacketTooLarge(format!("{}-byte packet exceeds the negotiated limit", out.len())));
+        }
+        Ok(())
+    }
+
+    /// Decrypts a RecvFromPeer frame into `out`, returning the key of the
+    /// peer that sent it.
+    pub fn decrypt_peer_frame_into(&self, crypto: &CryptoState, frame: &Frame, out: &mut Vec<u8>) -> DerpResult<PeerKey> {
+        if frame.payload.len() < PEER_KEY_LEN {
+            return Err(DerpError::InvalidProtocol("Invalid RecvFromPeer length".into()));
+        }
+        let (source, ciphertext) = frame.payload.split_at(PEER_KEY_LEN);
+        self.decrypt_frame_into(crypto, &Frame { payload: ciphertext, ..*frame }, out)?;
+        parse_peer_key(source)
+    }
+
+    /// Takes an empty buffer from the frame pool.
+    pub fn take_buffer(&self) -> Vec<u8> {
+        self.pool.take()
+    }
+
+    /// Buffers in the frame pool, and the bytes they hold.
+    pub fn pooled(&self) -> (usize, usize) {
+        (self.pool.len(), self.pool.retained_bytes())
+    }
+
+    /// Returns a frame or plaintext buffer to the pool once it's been sent or delivered.
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        self.pool.give(buffer);
+    }
+
+    /// Compresses and pads `data` as configured, returning the header flags
+    /// to go with it, which also name the cipher suite. `overhead` is what
+    /// the frame's payload adds around the plaintext, so padding rounds the
+    /// payload as observers will see it.
+    fn prepare_plaintext<'a>(&self, data: &'a [u8], overhead: usize) -> (u8, Cow<'a, [u8]>) {
+        let (mut flags, mut plaintext) = match self.compress(data) {
+            Some(compressed) => (FLAG_COMPRESSED, Cow::Owned(compressed)),
+            None => (0, Cow::Borrowed(data)),
+        };
+        #[cfg(feature = "siv")]
+        if self.cipher_suite() == CipherSuite::Aes256GcmSiv {
+            flags |= FLAG_SIV;
+        }
+
+        if self.padding_enabled() {
+            let wire_len = plaintext.len() + overhead;
+            if let Some(padded_len) = self.config.padding.padded_len(wire_len) {
+                padding::pad(plaintext.to_mut(), padded_len - wire_len);
+                flags |= FLAG_PADDED;
+            }
+        }
+        (flags, plaintext)
+    }
+
+    /// Returns the compressed form of `data` when compression is enabled and
+    /// actually saves space.
+    fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if !self.config.compression || data.len() < self.config.compression_threshold {
+            return None;
+        }
+
+        let compressed = self.stopwatch.time(Phase::Compress, || deflate(data))?;
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the frame type and a borrowed payload.
+    pub fn decode_frame(data: &[u8]) -> DerpResult<(FrameType, &[u8])> {
+        let frame = Frame::parse(data)?;
+        Ok((frame.frame_type, frame.payload))
+    }
+
+    /// Length of the frame at the start of `data`, header included. A batched
+    /// WebSocket message is several frames back to back.
+    pub fn frame_len(data: &[u8]) -> DerpResult<usize> {
+        if data.len() < FRAME_HEADER_SIZE {
+            return Err(DerpError::InvalidProtocol("Frame too short".into()));
+        }
+        if data[0] != PROTOCOL_VERSION {
+            return Err(DerpError::InvalidProtocol(format!("Unsupported version: {}", data[0])));
+        }
+
+        let frame_len = FRAME_HEADER_SIZE + u16::from_be_bytes([data[3], data[4]]) as usize;
+        if data.len() < frame_len {
+            return Err(DerpError::InvalidProtocol("Truncated frame".into()));
+        }
+        Ok(frame_len)
+    }
+
+    pub fn start_handshake(&mut self) -> DerpResult<Vec<u8>> {
+        let info = ClientInfo::new(self.offered_features());
+        let payload = bincode::serialize(&info)?;
+
+        self.handshake = HandshakeState::AwaitingServerKey;
+        self.wire_format = WireFormat::Bincode;
+        self.rejection = None;
+        self.health_problem = None;
+        // Peers re-announce once they see this connection again
+        self.switchboard.clear_remote();
+        self.handshake_started_ms = self.stopwatch.now_ms();
+        self.last_received_ms = self.handshake_started_ms;
+        self.stopwatch.mark_handshake_start();
+        log::debug!("Starting handshake, offering {:?}", info.features);
+        Ok(self.encode_frame(FrameType::ClientInfo, &payload))
+    }
+
+    /// Records the relay's key. A Go server, which leads with its magic,
+    /// has ignored the bincode ClientInfo, so a JSON one is returned to be
+    /// sent in its place.
+    pub fn handle_server_key(&mut self, payload: &[u8]) -> DerpResult<Option<Vec<u8>>> {
+        if self.handshake != HandshakeState::AwaitingServerKey {
+            return Err(DerpError::InvalidState("Unexpected ServerKey frame".into()));
+        }
+        let (format, key) = WireFormat::detect(payload);
+        if key.len() != PEER_KEY_LEN {
+            return Err(DerpError::InvalidProtocol("Invalid server key length".into()));
+        }
+        if self.pinned_server_key.is_some_and(|pinned| pinned[..] != *key) {
+            let reason = format!("Relay key {} isn't the one pinned", hex_encode(key));
+            log::error!("{}", reason);
+            self.handshake = HandshakeState::Rejected;
+            self.rejection = Some(reason.clone());
+            return Err(DerpError::AuthRejected(reason));
+        }
+
+        self.server_key = Some(key.to_vec());
+        self.wire_format = format;
+        self.handshake = HandshakeState::AwaitingServerInfo;
+        if format == WireFormat::Bincode {
+            return Ok(None);
+        }
+
+        log::debug!("Relay speaks Go's handshake; resending ClientInfo as JSON");
+        let info = GoClientInfo {
+            version: GO_PROTOCOL_VERSION,
+            features: self.offered_features(),
+            ..Default::default()
+        };
+        Ok(Some(self.encode_frame(FrameType::ClientInfo, &wire::encode_json(&info)?)))
+    }
+
+    /// Refuses relays presenting any other key, e.g. one issued by a control
+    /// plane. A mismatch fails the connection for good, like a rejected
+    /// auth token.
+    pub fn pin_server_key(&mut self, key: PeerKey) {
+        self.pinned_server_key = Some(key);
+    }
+
+    /// What the relay said about itself, once it has.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
+    }
+
+    /// How this connection's handshake messages are encoded.
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    /// Like `handle_server_info`, but first takes up CBOR if the frame's flag
+    /// says the relay accepted it.
+    pub fn handle_server_info_frame(&mut self, frame: &Frame) -> DerpResult<Vec<u8>> {
+        if frame.is_cbor() {
+            if !self.config.cbor {
+                return Err(DerpError::InvalidProtocol("CBOR ServerInfo without an offer".into()));
+            }
+            self.wire_format = WireFormat::Cbor;
+        }
+        self.handle_server_info(frame.payload)
+    }
+
+    /// Completes the handshake and returns the frame to send back: an Auth
+    /// frame if a token is configured, otherwise a plain acknowledgement.
+    pub fn handle_server_info(&mut self, payload: &[u8]) -> DerpResult<Vec<u8>> {
+        if self.handshake != HandshakeState::AwaitingServerInfo {
+            return Err(DerpError::InvalidState("Unexpected ServerInfo frame".into()));
+        }
+
+        let info = match self.wire_format {
+            WireFormat::Bincode => decode_handshake(payload)?,
+            WireFormat::Cbor => wire::decode_cbor(payload)?,
+            WireFormat::Json => ServerInfo::from_go(wire::decode_json(payload)?)?,
+        };
+        if info.version != PROTOCOL_VERSION {
+            return Err(DerpError::InvalidProtocol(format!("Unsupported server version: {}", info.version)));
+        }
+
+        log::debug!("Relay {:?} in region {:?} agreed to {:?}", info.name, info.region, info.features);
+        self.server_info = Some(info);
+
+        match &self.auth_token {
+            Some(token) => {
+                let frame = self.encode_frame(FrameType::Auth, token.as_bytes());
+                self.handshake = HandshakeState::AwaitingAuth;
+                Ok(frame)
+            }
+            None => {
+                self.connected();
+                Ok(self.encode_frame(FrameType::KeepAlive, &[]))
+            }
+        }
+    }
+
+    /// AuthResult payload is a status byte (0 = accepted) followed by an
+    /// optional UTF-8 reason.
+    pub fn handle_auth_result(&mut self, payload: &[u8]) -> DerpResult<()> {
+        if self.handshake != HandshakeState::AwaitingAuth {
+            return Err(DerpError::InvalidState("Unexpected AuthResult frame".into()));
+        }
+
+        match payload.split_first() {
+            Some((0, _)) => {
+                self.connected();
+                Ok(())
+            }
+            Some((_, reason)) => {
+                let reason = String::from_utf8_lossy(reason).into_owned();
+                log::error!("Relay rejected the auth token: {}", reason);
+                self.handshake = HandshakeState::Rejected;
+                self.rejection = Some(reason.clone());
+                Err(DerpError::AuthRejected(reason))
+            }
+            None => Err(DerpError::InvalidProtocol("Empty AuthResult frame".into())),
+        }
+    }
+
+    fn connected(&mut self) {
+        self.handshake = HandshakeState::Connected;
+        self.restart_hint = None;
+        self.stopwatch.record_handshake(self.handshake_started_ms);
+        log::info!("Connected to relay");
+    }
+
+    /// Time spent handshaking, encrypting, compressing and the like.
+    pub fn stopwatch(&self) -> Arc<Stopwatch> {
+        self.stopwatch.clone()
+    }
+
+    fn offered_features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.config.batching {
+            features.push(FEATURE_BATCHING.to_string());
+        }
+        if self.config.padding != PaddingPolicy::Off {
+            features.push(FEATURE_PADDING.to_string());
+        }
+        if self.config.cbor {
+            features.push(FEATURE_CBOR.to_string());
+        }
+        if self.config.aes_gcm_siv {
+            features.push(FEATURE_AES_GCM_SIV.to_string());
+        }
+        features
+    }
+
+    /// Whether both sides agreed to coalesce frames into shared messages.
+    pub fn batching_enabled(&self) -> bool {
+        self.config.batching && self.server_info.as_ref()
+            .is_some_and(|info| info.features.iter().any(|feature| feature == FEATURE_BATCHING))
+    }
+
+    /// Whether both sides agreed to pad frames, so the relay knows to
+    /// expect it.
+    pub fn padding_enabled(&self) -> bool {
+        self.config.padding != PaddingPolicy::Off && self.server_info.as_ref()
+            .is_some_and(|info| info.features.iter().any(|feature| feature == FEATURE_PADDING))
+    }
+
+    /// Whether both sides agreed to seal relay frames with AES-GCM-SIV.
+    pub fn aes_gcm_siv_enabled(&self) -> bool {
+        self.config.aes_gcm_siv && self.server_info.as_ref()
+            .is_some_and(|info| info.features.iter().any(|feature| feature == FEATURE_AES_GCM_SIV))
+    }
+
+    /// The cipher this side seals frames with: AES-GCM-SIV once agreed,
+    /// otherwise AES-GCM. Frames are opened by the suite they're flagged
+    /// with, whatever this side chose.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        #[cfg(feature = "siv")]
+        if self.aes_gcm_siv_enabled() {
+            return CipherSuite::Aes256GcmSiv;
+        }
+        CipherSuite::Aes256Gcm
+    }
+
+    /// The largest relay payload, VLAN or channel tag included, the relay
+    /// agreed to forward. Until it says, and for anything over what a frame
+    /// can carry, the frame limit applies.
+    pub fn max_packet_size(&self) -> usize {
+        self.server_info.as_ref()
+            .map_or(MAX_FRAME_PAYLOAD, |info| (info.max_packet_size as usize).min(MAX_FRAME_PAYLOAD))
+    }
+
+    /// Returns an error describing why packets can't be sent yet.
+    pub fn ensure_connected(&self) -> DerpResult<()> {
+        match (&self.handshake, &self.rejection) {
+            (HandshakeState::Connected, _) => Ok(()),
+            (HandshakeState::Rejected, Some(reason)) => Err(DerpError::AuthRejected(reason.clone())),
+            _ => Err(DerpError::InvalidState("Not connected".into())),
+        }
+    }
+
+    /// Keepalive interval in effect: the configured override, else what the
+    /// server advertised, else the default.
+    pub fn keepalive_interval_ms(&self) -> u32 {
+        self.config.keepalive_interval_ms
+            .or_else(|| self.server_info.as_ref()
+                .map(|info| info.keepalive_interval_ms)
+                .filter(|interval| *interval > 0))
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL_MS)
+    }
+
+    pub fn note_sent(&mut self) {
+        self.last_sent_ms = self.clock.now_ms();
+    }
+
+    pub fn note_received(&mut self) {
+        self.last_received_ms = self.clock.now_ms();
+    }
+
+    /// Whether the relay has been quiet for `missedKeepalives` of its own
+    /// keepalive intervals, meaning the connection is probably dead even if
+    /// the browser hasn't noticed. Our override doesn't apply here: the
+    /// relay keeps to the interval it advertised.
+    pub fn is_silent(&self) -> bool {
+        let missed = self.config.missed_keepalives;
+        if missed == 0 || !self.is_connected() {
+            return false;
+        }
+        let interval_ms = self.server_info.as_ref()
+            .map(|info| info.keepalive_interval_ms)
+            .filter(|interval| *interval > 0)
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL_MS);
+        self.clock.now_ms() - self.last_received_ms >= interval_ms as f64 * missed as f64
+    }
+
+    /// Returns a KeepAlive frame if nothing has been sent for a full interval.
+    pub fn poll_keepalive(&mut self) -> Option<Vec<u8>> {
+        let now_ms = self.clock.now_ms();
+        if !self.is_connected() || now_ms - self.last_sent_ms < self.keepalive_interval_ms() as f64 {
+            return None;
+        }
+
+        self.last_sent_ms = now_ms;
+        Some(self.encode_frame(FrameType::KeepAlive, &[]))
+    }
+
+    pub fn goodbye(&self) -> Vec<u8> {
+        self.encode_frame(FrameType::Goodbye, &[])
+    }
+
+    /// Marks this connection as the home region's, or not, for the
+    /// NotePreferred frame sent after each handshake.
+    pub fn set_preferred(&mut self, preferred: bool) {
+        self.preferred = preferred;
+    }
+
+    pub fn is_preferred(&self) -> bool {
+        self.preferred
+    }
+
+    pub fn note_preferred(&self) -> Vec<u8> {
+        self.encode_frame(FrameType::NotePreferred, &[self.preferred as u8])
+    }
+
+    /// Starts watching the relay's roster; see `FrameType::WatchConns`.
+    pub fn watch_conns(&mut self) -> Vec<u8> {
+        self.watching = true;
+        self.encode_frame(FrameType::WatchConns, &[])
+    }
+
+    pub fn is_watching(&self) -> bool {
+        self.watching
+    }
+
+    /// Hex keys of the peers the relay reports present, sorted.
+    pub fn peer_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.peers.iter().map(|key| hex_encode(key)).collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn close_peer(&self, peer: &PeerKey) -> Vec<u8> {
+        self.encode_frame(FrameType::ClosePeer, peer)
+    }
+
+    /// Forgets a peer the relay disconnected on someone's request, as if
+    /// it had gone, and returns its key.
+    pub fn handle_close_peer(&mut self, payload: &[u8]) -> DerpResult<PeerKey> {
+        self.handle_peer_gone(payload)?;
+        parse_peer_key(payload)
+    }
+
+    pub fn handle_ping(&self) -> Vec<u8> {
+        self.encode_frame(FrameType::Pong, &[])
+    }
+
+    /// Records a peer announced by the relay. Returns false if the peer was
+    /// refused because new peers are not being accepted or it isn't on the
+    /// allowlist.
+    pub fn handle_peer_present(&mut self, payload: &[u8]) -> DerpResult<bool> {
+        let key = parse_peer_key(payload)?;
+        if !self.acl.allows(&key) {
+            self.acl.deny_inbound(&key);
+            return Ok(false);
+        }
+        if !self.accept_new_peers && !self.peers.contains(&key) {
+            return Ok(false);
+        }
+
+        self.peers.insert(key);
+        Ok(true)
+    }
+
+    pub fn handle_peer_gone(&mut self, payload: &[u8]) -> DerpResult<()> {
+        let key = parse_peer_key(payload)?;
+        self.peers.remove(&key);
+        self.switchboard.forget(&key);
+        Ok(())
+    }
+
+    pub fn handle_health(&mut self, payload: &[u8]) -> HealthEvent {
+        let event = HealthEvent::parse(payload);
+        match &event.problem {
+            Some(problem) => log::warn!("Relay reports a problem: {}", problem),
+            None => log::info!("Relay reports it's healthy again"),
+        }
+        self.health_problem = event.problem.clone();
+        event
+    }
+
+    /// What the relay last said is wrong with it, if anything.
+    pub fn health_problem(&self) -> Option<&str> {
+        self.health_problem.as_deref()
+    }
+
+    /// Notes when the relay expects to be back, for `restart_delay_ms`.
+    pub fn handle_restarting(&mut self, payload: &[u8]) -> DerpResult<RestartingEvent> {
+        let event = RestartingEvent::parse(payload)?;
+        log::info!("Relay restarting; back in {} ms, trying for {} ms", event.reconnect_in_ms, event.try_for_ms);
+        self.restart_hint = Some(RestartHint::new(event, self.clock.now_ms()));
+        Ok(event)
+    }
+
+    /// The wait before reconnecting that a restarting relay asked for, or
+    /// None outside its window, when the client's own backoff applies.
+    pub fn restart_delay_ms(&mut self) -> Option<u32> {
+        let delay = self.restart_hint?.delay_ms(self.clock.now_ms());
+        if delay.is_none() {
+            self.restart_hint = None;
+        }
+        delay
+    }
+
+    /// Records the MACs a peer announced, unless the peer was refused.
+    pub fn handle_mac_announce(&mut self, payload: &[u8]) -> DerpResult<()> {
+        let sender = payload.get(..PEER_KEY_LEN)
+            .ok_or_else(|| DerpError::InvalidProtocol("Invalid MacAnnounce length".into()))?;
+        let sender = parse_peer_key(sender)?;
+        if !self.peers.contains(&sender) {
+            if !self.acl.allows(&sender) {
+                self.acl.deny_inbound(&sender);
+            }
+            return Ok(());
+        }
+        self.switchboard.learn(payload)
+    }
+
+    /// A MacAnnounce frame listing the local MACs, to send whenever they
+    /// change or a peer joins.
+    pub fn mac_announcement(&self) -> Vec<u8> {
+        self.encode_frame(FrameType::MacAnnounce, &self.switchboard.announcement())
+    }
+
+    pub fn switchboard(&self) -> &Switchboard {
+        &self.switchboard
+    }
+
+    pub fn switchboard_mut(&mut self) -> &mut Switchboard {
+        &mut self.switchboard
+    }
+
+    pub fn set_accept_new_peers(&mut self, accept: bool) {
+        self.accept_new_peers = accept;
+    }
+
+    /// Replaces the allowlist, forgetting present peers that are no
+    /// longer on it. None allows every peer.
+    pub fn set_allowed_peers(&mut self, allowed: Option<HashSet<PeerKey>>) {
+        self.acl.set_allowed(allowed);
+        let refused: Vec<PeerKey> = self.peers.iter().filter(|peer| !self.acl.allows(peer)).copied().collect();
+        for peer in refused {
+            self.peers.remove(&peer);
+            self.switchboard.forget(&peer);
+        }
+    }
+
+    /// Whether a packet from `sender` may be delivered: it has to be on the
+    /// allowlist and, while new peers are refused, already present.
+    pub fn admit(&mut self, sender: &PeerKey) -> bool {
+        if !self.accept_new_peers && !self.peers.contains(sender) {
+            return false;
+        }
+        self.acl.admit(sender)
+    }
+
+    /// Refuses, and counts, sends to a peer that isn't on the allowlist.
+    pub fn check_send(&mut self, peer: &PeerKey) -> DerpResult<()> {
+        if self.acl.allows(peer) {
+            return Ok(());
+        }
+        self.acl.deny_outbound(peer);
+        Err(DerpError::InvalidState(format!("Peer {} isn't on the allowlist", hex_encode(peer))))
+    }
+
+    pub fn acl_stats(&self) -> AclStats {
+        self.acl.stats()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.handshake == HandshakeState::Connected
+    }
+
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.handshake
+    }
+}
+
+/// Decodes a handshake message from the relay. Nothing it claims may
+/// reach past the payload, so a forged length can't size an allocation.
+pub fn decode_handshake<T: DeserializeOwned>(payload: &[u8]) -> DerpResult<T> {
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(payload.len() as u64)
+        .deserialize(payload)?)
+}
+
+/// Deflates `data`, or returns None when built without the compression
+/// feature, in which case nothing is ever sent compressed.
+#[cfg(feature = "compression")]
+pub fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    Some(miniz_oxide::deflate::compress_to_vec(data, COMPRESSION_LEVEL))
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn deflate(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Inflates `data` to at most `limit` bytes. Without the compression
+/// feature, compressed frames from peers that have it are refused.
+#[cfg(feature = "compression")]
+pub fn inflate(data: &[u8], limit: usize) -> DerpResult<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec_with_limit(data, limit)
+        .map_err(|e| DerpError::InvalidProtocol(format!("Decompression failed: {:?}", e)))
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn inflate(_data: &[u8], _limit: usize) -> DerpResult<Vec<u8>> {
+    Err(DerpError::InvalidProtocol("Compressed frame, but the compression feature is off".into()))
+}
+
+/// Longer payloads would wrap the header's length field and desync the
+/// stream for whoever parses it.
+fn check_payload_len(payload_len: usize) -> DerpResult<usize> {
+    if payload_len > MAX_FRAME_PAYLOAD {
+        return Err(DerpError::InvalidState(format!("Frame payload too large: {} bytes", payload_len)));
+    }
+    Ok(payload_len)
+}
+
+/// Checks the CRC32 ending `frame` and returns the payload before it.
+fn verify_checksum(frame: &[u8]) -> DerpResult<&[u8]> {
+    if frame.len() < FRAME_HEADER_SIZE + CHECKSUM_LEN {
+        return Err(DerpError::InvalidProtocol("Frame too short for its checksum".into()));
+    }
+    let (covered, crc) = frame.split_at(frame.len() - CHECKSUM_LEN);
+    if crc32fast::hash(covered).to_be_bytes() != crc {
+        return Err(DerpError::InvalidProtocol("Frame checksum mismatch".into()));
+    }
+    Ok(&covered[FRAME_HEADER_SIZE..])
+}
+
+pub fn parse_peer_key(payload: &[u8]) -> DerpResult<PeerKey> {
+    PeerKey::try_from(payload)
+        .map_err(|_| DerpError::InvalidProtocol("Invalid peer key length".into()))
+}
+
+impl Default for ProtocolState {
+    fn default() -> Self {
+        ProtocolState::new()
+    }
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a peer key as `hex_encode` writes it.
+pub fn hex_decode_key(hex: &str) -> DerpResult<PeerKey> {
+    let invalid = || DerpError::InvalidState(format!("Invalid peer key: {:?}", hex));
+    if hex.len() != PEER_KEY_LEN * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; PEER_KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_protocol_state_frame_roundtrip() {
+        let state = ProtocolState::new();
+        let frame = state.encode_frame(FrameType::Send, &[1, 2, 3]);
+
+        let (frame_type, payload) = ProtocolState::decode_frame(&frame).unwrap();
+        assert_eq!(frame_type, FrameType::Send);
+        assert_eq!(payload, vec![1, 2, 3]);
+
+        // Truncated payload is rejected
+        assert!(ProtocolState::decode_frame(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_frame_borrows_payload() {
+        let state = ProtocolState::new();
+        let frame = state.encode_frame(FrameType::Send, &[1, 2, 3]);
+
+        let (_, payload) = ProtocolState::decode_frame(&frame).unwrap();
+        assert_eq!(payload.as_ptr(), frame[FRAME_HEADER_SIZE..].as_ptr());
+
+        let parsed = Frame::parse(&frame).unwrap();
+        assert_eq!(parsed.header, &frame[..FRAME_HEADER_SIZE]);
+        assert!(!parsed.is_compressed());
+    }
+
+    #[test]
+    fn test_encrypted_frame_header_is_authenticated() {
+        let state = ProtocolState::new();
+        let crypto = CryptoState::new().unwrap();
+
+        let mut frame = state.encode_encrypted_frame(&crypto, FrameType::Send, b"packet").unwrap();
+        assert_eq!(state.decrypt_frame(&crypto, &frame).unwrap(), b"packet".to_vec());
+
+        // Flipping the frame type must break authentication
+        frame[1] = FrameType::RecvFromPeer as u8;
+        assert!(state.decrypt_frame(&crypto, &frame).is_err());
+    }
+
+    #[test]
+    fn test_frames_reuse_pooled_buffers() {
+        let state = ProtocolState::new();
+        let frame = state.encode_frame(FrameType::Ping, &[]);
+        let ptr = frame.as_ptr();
+        state.recycle(frame);
+
+        let frame = state.encode_frame(FrameType::Pong, &[]);
+        assert_eq!(frame.as_ptr(), ptr);
+        assert_eq!(ProtocolState::decode_frame(&frame).unwrap().0, FrameType::Pong);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_frame_roundtrip() {
+        let config = DerpConfig::builder().compression(true).build().unwrap();
+        let state = ProtocolState::with_config(config);
+        let crypto = CryptoState::new().unwrap();
+        let packet = vec![0u8; 1400];
+
+        let frame = state.encode_encrypted_frame(&crypto, FrameType::Send, &packet).unwrap();
+        assert_eq!(frame[2] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+        assert!(frame.len() < packet.len());
+        assert_eq!(state.decrypt_frame(&crypto, &frame).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_keepalive_override() {
+        let config = DerpConfig::builder().keepalive_interval_ms(1000).build().unwrap();
+        let clock = Arc::new(MockClock::new(0.0));
+        let mut state = ProtocolState::with_clock(config, clock.clone());
+        complete_server_handshake(&mut state);
+        assert_eq!(state.keepalive_interval_ms(), 1000);
+
+        state.note_sent();
+        clock.advance(500.0);
+        assert!(state.poll_keepalive().is_none());
+        clock.advance(500.0);
+        assert!(state.poll_keepalive().is_some());
+        clock.advance(500.0);
+        assert!(state.poll_keepalive().is_none());
+    }
+
+    #[test]
+    fn test_silent_relay() {
+        let config = DerpConfig::builder().keepalive_interval_ms(1000).missed_keepalives(2).build().unwrap();
+        let clock = Arc::new(MockClock::new(0.0));
+        let mut state = ProtocolState::with_clock(config, clock.clone());
+        assert!(!state.is_silent());
+        complete_server_handshake(&mut state);
+
+        // Measured against the relay's 30 s interval, not the override
+        clock.advance(59_000.0);
+        assert!(!state.is_silent());
+        state.note_received();
+        clock.advance(59_000.0);
+        assert!(!state.is_silent());
+        clock.advance(1000.0);
+        assert!(state.is_silent());
+
+        let config = DerpConfig::builder().missed_keepalives(0).build().unwrap();
+        let mut state = ProtocolState::with_clock(config, clock.clone());
+        complete_server_handshake(&mut state);
+        clock.advance(1_000_000.0);
+        assert!(!state.is_silent());
+    }
+
+    #[test]
+    fn test_protocol_state_refuses_new_peers() {
+        let mut state = ProtocolState::new();
+        let known = [1u8; 32];
+        let unknown = [2u8; 32];
+
+        assert!(state.handle_peer_present(&known).unwrap());
+        state.set_accept_new_peers(false);
+
+        assert!(!state.handle_peer_present(&unknown).unwrap());
+        assert!(state.handle_peer_present(&known).unwrap());
+        assert_eq!(state.peer_count(), 1);
+        assert!(!state.admit(&unknown));
+        assert!(state.admit(&known));
+
+        state.handle_peer_gone(&known).unwrap();
+        assert_eq!(state.peer_count(), 0);
+    }
+
+    #[test]
+    fn test_protocol_state_enforces_allowlist() {
+        let alice = [1u8; 32];
+        let mallory = [3u8; 32];
+        let config = DerpConfig::builder().allowed_peers(Some(vec![hex_encode(&alice)])).build().unwrap();
+        let mut state = ProtocolState::with_config(config);
+
+        assert!(state.handle_peer_present(&alice).unwrap());
+        assert!(!state.handle_peer_present(&mallory).unwrap());
+        assert!(state.check_send(&alice).is_ok());
+        assert!(state.check_send(&mallory).is_err());
+        assert_eq!(state.acl_stats().peers[0].peer, hex_encode(&mallory));
+        assert_eq!((state.acl_stats().peers[0].inbound, state.acl_stats().peers[0].outbound), (1, 1));
+
+        state.set_allowed_peers(Some(HashSet::from([mallory])));
+        assert_eq!(state.peer_count(), 0);
+        state.set_allowed_peers(None);
+        assert!(state.handle_peer_present(&alice).unwrap());
+    }
+
+    #[test]
+    fn test_mac_announcements_follow_peers() {
+        let mut state = ProtocolState::new();
+        let peer = [1u8; 32];
+        let mac = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0B];
+        let mut announce = peer.to_vec();
+        announce.extend_from_slice(&mac);
+
+        // Announcements from peers the relay never presented are ignored
+        state.handle_mac_announce(&announce).unwrap();
+        assert_eq!(state.switchboard().peer_for(&mac), None);
+
+        state.handle_peer_present(&peer).unwrap();
+        state.handle_mac_announce(&announce).unwrap();
+        assert_eq!(state.switchboard().peer_for(&mac), Some(peer));
+
+        state.handle_peer_gone(&peer).unwrap();
+        assert_eq!(state.switchboard().peer_for(&mac), None);
+    }
+
+    /// Rewrites a Send or SendToPeer frame as the relay delivers it.
+    fn deliver(state: &ProtocolState, frame: &[u8], source: &PeerKey) -> Vec<u8> {
+        let parsed = Frame::parse(frame).unwrap();
+        let ciphertext = match parsed.frame_type {
+            FrameType::SendToPeer => &parsed.payload[PEER_KEY_LEN..],
+            _ => parsed.payload,
+        };
+        let mut delivered = state.frame_header(FrameType::RecvFromPeer, parsed.flags, PEER_KEY_LEN + ciphertext.len()).to_vec();
+        delivered.extend_from_slice(source);
+        delivered.extend_from_slice(ciphertext);
+        delivered
+    }
+
+    #[test]
+    fn test_peer_frames_open_as_delivered() {
+        let state = ProtocolState::new();
+        let crypto = CryptoState::new().unwrap();
+        let mut frame = Vec::new();
+        state.encode_peer_frame_into(&crypto, &[3u8; 32], b"frame", &mut frame).unwrap();
+
+        let parsed = Frame::parse(&frame).unwrap();
+        assert_eq!(parsed.frame_type, FrameType::SendToPeer);
+        assert_eq!(&parsed.payload[..PEER_KEY_LEN], &[3u8; 32]);
+
+        let delivered = deliver(&state, &frame, &[5u8; 32]);
+        let mut out = Vec::new();
+        let source = state.decrypt_peer_frame_into(&crypto, &Frame::parse(&delivered).unwrap(), &mut out).unwrap();
+        assert_eq!((source, out.as_slice()), ([5u8; 32], &b"frame"[..]));
+
+        state.encode_packet_frame_into(&crypto, b"everyone", &mut frame).unwrap();
+        assert_eq!(Frame::parse(&frame).unwrap().frame_type, FrameType::Send);
+        let mut delivered = deliver(&state, &frame, &[5u8; 32]);
+        state.decrypt_peer_frame_into(&crypto, &Frame::parse(&delivered).unwrap(), &mut out).unwrap();
+        assert_eq!(out, b"everyone".to_vec());
+
+        // The flags are authenticated as delivered
+        delivered[2] ^= FLAG_PADDED;
+        assert!(state.decrypt_peer_frame_into(&crypto, &Frame::parse(&delivered).unwrap(), &mut out).is_err());
+    }
+
+    fn complete_server_handshake(state: &mut ProtocolState) -> Vec<u8> {
+        complete_server_handshake_with(state, Vec::new())
+    }
+
+    fn complete_server_handshake_with(state: &mut ProtocolState, features: Vec<String>) -> Vec<u8> {
+        complete_server_handshake_with_info(state, ServerInfo {
+            version: PROTOCOL_VERSION,
+            name: "test".into(),
+            region: "local".into(),
+            keepalive_interval_ms: 30_000,
+            max_packet_size: 65_535,
+            features,
+        })
+    }
+
+    fn complete_server_handshake_with_info(state: &mut ProtocolState, info: ServerInfo) -> Vec<u8> {
+        state.start_handshake().unwrap();
+        state.handle_server_key(&[7u8; 32]).unwrap();
+        state.handle_server_info(&bincode::serialize(&info).unwrap()).unwrap()
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_received_packets_are_held_to_the_negotiated_size() {
+        let config = DerpConfig::builder().compression(true).build().unwrap();
+        let crypto = CryptoState::new().unwrap();
+        let sender = ProtocolState::with_config(config.clone());
+        let mut receiver = ProtocolState::with_config(config);
+        assert_eq!(receiver.max_packet_size(), MAX_FRAME_PAYLOAD);
+
+        let mut info = ServerInfo::new("test", Vec::new());
+        info.max_packet_size = 1280;
+        complete_server_handshake_with_info(&mut receiver, info);
+        assert_eq!(receiver.max_packet_size(), 1280);
+
+        let fits = sender.encode_encrypted_frame(&crypto, FrameType::Send, &[1u8; 1000]).unwrap();
+        assert!(receiver.decrypt_frame(&crypto, &fits).is_ok());
+        for packet in [vec![1u8; 1281], vec![0u8; 60_000]] {
+            let frame = sender.encode_encrypted_frame(&crypto, FrameType::Send, &packet).unwrap();
+            assert!(matches!(receiver.decrypt_frame(&crypto, &frame), Err(DerpError::InvalidProtocol(_))));
+        }
+    }
+
+    #[test]
+    fn test_go_server_gets_json_info() {
+        let config = DerpConfig::builder().batching(true).build().unwrap();
+        let mut state = ProtocolState::with_config(config);
+        state.start_handshake().unwrap();
+
+        let mut server_key = wire::GO_MAGIC.to_vec();
+        server_key.extend_from_slice(&[7u8; 32]);
+        let client_info = state.handle_server_key(&server_key).unwrap().unwrap();
+        assert_eq!(state.wire_format(), WireFormat::Json);
+        let (frame_type, payload) = ProtocolState::decode_frame(&client_info).unwrap();
+        assert_eq!(frame_type, FrameType::ClientInfo);
+        let info: GoClientInfo = wire::decode_json(payload).unwrap();
+        assert_eq!((info.version, info.features), (GO_PROTOCOL_VERSION, vec![FEATURE_BATCHING.to_string()]));
+
+        state.handle_server_info(br#"{"version":2,"features":["batching"],"maxPacketSize":1400}"#).unwrap();
+        assert!(state.is_connected());
+        assert!(state.batching_enabled());
+        assert_eq!(state.max_packet_size(), 1400);
+    }
+
+    #[test]
+    fn test_cbor_negotiation() {
+        let config = DerpConfig::builder().cbor(true).build().unwrap();
+        let relay = ProtocolState::new();
+        let server_info = ServerInfo::new("test", vec![FEATURE_CBOR.into()]);
+        let cbor = relay.encode_control_frame(FrameType::ServerInfo, WireFormat::Cbor, &server_info).unwrap();
+
+        let mut state = ProtocolState::with_config(config);
+        state.start_handshake().unwrap();
+        state.handle_server_key(&[7u8; 32]).unwrap();
+        state.handle_server_info_frame(&Frame::parse(&cbor).unwrap()).unwrap();
+        assert!(state.is_connected());
+        assert_eq!(state.wire_format(), WireFormat::Cbor);
+
+        // Not offered, so not accepted
+        let mut state = ProtocolState::new();
+        state.start_handshake().unwrap();
+        state.handle_server_key(&[7u8; 32]).unwrap();
+        assert!(state.handle_server_info_frame(&Frame::parse(&cbor).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_restart_hint_lasts_until_reconnected() {
+        let clock = Arc::new(MockClock::new(0.0));
+        let mut state = ProtocolState::with_clock(DerpConfig::default(), clock.clone());
+        complete_server_handshake(&mut state);
+        assert_eq!(state.restart_delay_ms(), None);
+
+        let payload = [0, 0, 0x07, 0xD0, 0, 0, 0x27, 0x10];
+        assert_eq!(state.handle_restarting(&payload).unwrap().reconnect_in_ms, 2000);
+        clock.advance(500.0);
+        assert_eq!(state.restart_delay_ms(), Some(1500));
+
+        complete_server_handshake(&mut state);
+        assert_eq!(state.restart_delay_ms(), None);
+
+        state.handle_health(b"out of memory");
+        assert_eq!(state.health_problem(), Some("out of memory"));
+        state.handle_health(b"");
+        assert_eq!(state.health_problem(), None);
+    }
+
+    #[test]
+    fn test_close_peer_forgets_the_peer() {
+        let mut state = ProtocolState::new();
+        complete_server_handshake(&mut state);
+        let peer = [4u8; PEER_KEY_LEN];
+        state.handle_peer_present(&peer).unwrap();
+        assert_eq!(state.peer_count(), 1);
+
+        assert_eq!(state.handle_close_peer(&peer).unwrap(), peer);
+        assert_eq!(state.peer_count(), 0);
+        assert!(state.handle_close_peer(&peer[..5]).is_err());
+
+        assert_eq!(hex_decode_key(&hex_encode(&peer)).unwrap(), peer);
+        assert!(hex_decode_key("04").is_err());
+        assert!(hex_decode_key(&"zz".repeat(PEER_KEY_LEN)).is_err());
+    }
+
+    #[test]
+    fn test_batching_negotiation() {
+        let config = DerpConfig::builder().batching(true).build().unwrap();
+
+        let mut state = ProtocolState::with_config(config.clone());
+        complete_server_handshake(&mut state);
+        assert!(!state.batching_enabled());
+
+        let mut state = ProtocolState::with_config(config);
+        complete_server_handshake_with(&mut state, vec![FEATURE_BATCHING.into()]);
+        assert!(state.batching_enabled());
+
+        // The server offering it isn't enough on its own
+        let mut state = ProtocolState::new();
+        complete_server_handshake_with(&mut state, vec![FEATURE_BATCHING.into()]);
+        assert!(!state.batching_enabled());
+    }
+
+    #[cfg(feature = "siv")]
+    #[test]
+    fn test_aes_gcm_siv_negotiation() {
+        let config = DerpConfig::builder().aes_gcm_siv(true).build().unwrap();
+        let crypto = CryptoState::new().unwrap();
+
+        let mut state = ProtocolState::with_config(config.clone());
+        complete_server_handshake(&mut state);
+        assert_eq!(state.cipher_suite(), CipherSuite::Aes256Gcm);
+
+        let mut siv = ProtocolState::with_config(config);
+        complete_server_handshake_with(&mut siv, vec![FEATURE_AES_GCM_SIV.into()]);
+        assert_eq!(siv.cipher_suite(), CipherSuite::Aes256GcmSiv);
+
+        let mut frame = siv.encode_encrypted_frame(&crypto, FrameType::Send, b"frame").unwrap();
+        assert_eq!(Frame::parse(&frame).unwrap().cipher_suite().unwrap(), CipherSuite::Aes256GcmSiv);
+        assert_eq!(siv.decrypt_frame(&crypto, &frame).unwrap(), b"frame".to_vec());
+        // Opened by its flag, not by what the receiver negotiated
+        assert_eq!(state.decrypt_frame(&crypto, &frame).unwrap(), b"frame".to_vec());
+
+        frame[2] &= !FLAG_SIV;
+        assert!(siv.decrypt_frame(&crypto, &frame).is_err());
+    }
+
+    #[test]
+    fn test_padded_frame_roundtrip() {
+        let config = DerpConfig::builder().padding(PaddingPolicy::Buckets).build().unwrap();
+        let crypto = CryptoState::new().unwrap();
+        let packet = vec![7u8; 40];
+
+        let mut state = ProtocolState::with_config(config.clone());
+        complete_server_handshake(&mut state);
+        assert!(!state.padding_enabled());
+        let frame = state.encode_encrypted_frame(&crypto, FrameType::Send, &packet).unwrap();
+        assert_eq!(frame[2] & FLAG_PADDED, 0);
+
+        let mut state = ProtocolState::with_config(config);
+        complete_server_handshake_with(&mut state, vec![FEATURE_PADDING.into()]);
+        assert!(state.padding_enabled());
+        let frame = state.encode_encrypted_frame(&crypto, FrameType::Send, &packet).unwrap();
+        assert_eq!(frame[2] & FLAG_PADDED, FLAG_PADDED);
+        assert_eq!(frame.len(), FRAME_HEADER_SIZE + padding::BUCKETS[0]);
+        assert_eq!(state.decrypt_frame(&crypto, &frame).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_oversized_input_is_refused() {
+        let crypto = CryptoState::new().unwrap();
+        let state = ProtocolState::new();
+        let mut frame = Vec::new();
+        let packet = vec![0u8; MAX_FRAME_PAYLOAD];
+        assert!(state.encode_encrypted_frame_into(&crypto, FrameType::Send, &packet, &mut frame).is_err());
+        assert!(state.encode_peer_frame_into(&crypto, &[1; PEER_KEY_LEN], &packet, &mut frame).is_err());
+
+        // A ServerInfo whose name claims far more bytes than were sent
+        let mut state = ProtocolState::new();
+        state.start_handshake().unwrap();
+        state.handle_server_key(&[7u8; 32]).unwrap();
+        let mut payload = vec![PROTOCOL_VERSION];
+        payload.extend_from_slice(&u64::MAX.to_le_bytes());
+        payload.extend_from_slice(b"name");
+        assert!(matches!(state.handle_server_info(&payload), Err(DerpError::SerializationError(_))));
+        assert!(!state.is_connected());
+    }
+
+    #[test]
+    fn test_corrupted_control_frames_are_rejected() {
+        let config = DerpConfig::builder().control_checksums(true).build().unwrap();
+        let state = ProtocolState::with_config(config);
+        let frame = state.encode_frame(FrameType::ClientInfo, b"info");
+        assert_eq!(frame[2] & FLAG_CHECKSUM, FLAG_CHECKSUM);
+        assert_eq!(ProtocolState::decode_frame(&frame).unwrap(), (FrameType::ClientInfo, &b"info"[..]));
+
+        for i in 0..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x10;
+            assert!(Frame::parse(&corrupted).is_err(), "flipped byte {}", i);
+        }
+        // Without checksums, the same corruption goes unnoticed
+        let mut plain = ProtocolState::new().encode_frame(FrameType::ClientInfo, b"info");
+        plain[FRAME_HEADER_SIZE] ^= 0x10;
+        assert!(Frame::parse(&plain).is_ok());
+    }
+
+    #[test]
+    fn test_frame_len_splits_batch() {
+        let state = ProtocolState::new();
+        let mut batch = state.encode_frame(FrameType::Ping, &[]);
+        batch.extend_from_slice(&state.encode_frame(FrameType::Send, &[1, 2, 3]));
+
+        let first = ProtocolState::frame_len(&batch).unwrap();
+        assert_eq!(first, FRAME_HEADER_SIZE);
+        assert_eq!(ProtocolState::frame_len(&batch[first..]).unwrap(), FRAME_HEADER_SIZE + 3);
+    }
+
+    #[test]
+    fn test_auth_token_flow() {
+        let mut state = ProtocolState::new();
+        state.set_auth_token(Some("secret".into()));
+
+        let response = complete_server_handshake(&mut state);
+        let (frame_type, payload) = ProtocolState::decode_frame(&response).unwrap();
+        assert_eq!(frame_type, FrameType::Auth);
+        assert_eq!(payload, b"secret".to_vec());
+        assert!(!state.is_connected());
+
+        state.handle_auth_result(&[0]).unwrap();
+        assert!(state.ensure_connected().is_ok());
+    }
+
+    #[test]
+    fn test_auth_rejection() {
+        let mut state = ProtocolState::new();
+        state.set_auth_token(Some("wrong".into()));
+        complete_server_handshake(&mut state);
+
+        let result = state.handle_auth_result(b"\x01bad token");
+        assert!(matches!(result, Err(DerpError::AuthRejected(ref reason)) if reason == "bad token"));
+        assert!(matches!(state.ensure_connected(), Err(DerpError::AuthRejected(_))));
+    }
+
+    #[test]
+    fn test_pinned_server_key() {
+        let mut state = ProtocolState::new();
+        state.pin_server_key([7u8; 32]);
+        complete_server_handshake(&mut state);
+        assert!(state.is_connected());
+
+        let mut state = ProtocolState::new();
+        state.pin_server_key([8u8; 32]);
+        state.start_handshake().unwrap();
+        assert!(matches!(state.handle_server_key(&[7u8; 32]), Err(DerpError::AuthRejected(_))));
+        assert_eq!(state.handshake_state(), HandshakeState::Rejected);
+        assert!(matches!(state.ensure_connected(), Err(DerpError::AuthRejected(_))));
+    }
+}
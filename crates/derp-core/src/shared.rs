@@ -0,0 +1,139 @@
+//! State shared between a connection's callbacks. In the browser wasm runs
+//! them all on one thread, so there it's an `Rc<RefCell>`: borrowing costs
+//! a flag check and a panicking callback can't poison it. Native builds,
+//! and wasm built with atomics for threads, keep an `Arc<Mutex>`.
+
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+mod imp {
+    use std::cell::{RefCell, RefMut};
+    use std::rc::{Rc, Weak};
+
+    pub type Guard<'a, T> = RefMut<'a, T>;
+
+    pub struct Shared<T>(Rc<RefCell<T>>);
+
+    pub struct WeakShared<T>(Weak<RefCell<T>>);
+
+    impl<T> Shared<T> {
+        pub fn new(value: T) -> Self {
+            Shared(Rc::new(RefCell::new(value)))
+        }
+
+        /// Panics if already borrowed, where a `Mutex` would deadlock.
+        pub fn lock(&self) -> Guard<'_, T> {
+            self.0.borrow_mut()
+        }
+
+        /// `None` while already borrowed.
+        pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+            self.0.try_borrow_mut().ok()
+        }
+
+        pub fn downgrade(&self) -> WeakShared<T> {
+            WeakShared(Rc::downgrade(&self.0))
+        }
+    }
+
+    impl<T> Clone for Shared<T> {
+        fn clone(&self) -> Self {
+            Shared(self.0.clone())
+        }
+    }
+
+    impl<T> WeakShared<T> {
+        /// `None` once every `Shared` has been dropped.
+        pub fn upgrade(&self) -> Option<Shared<T>> {
+            self.0.upgrade().map(Shared)
+        }
+    }
+
+    impl<T> Clone for WeakShared<T> {
+        fn clone(&self) -> Self {
+            WeakShared(self.0.clone())
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+mod imp {
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+
+    pub type Guard<'a, T> = MutexGuard<'a, T>;
+
+    pub struct Shared<T>(Arc<Mutex<T>>);
+
+    pub struct WeakShared<T>(Weak<Mutex<T>>);
+
+    impl<T> Shared<T> {
+        pub fn new(value: T) -> Self {
+            Shared(Arc::new(Mutex::new(value)))
+        }
+
+        /// A panic while locked leaves the state as it was, not poisoned.
+        pub fn lock(&self) -> Guard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// `None` while locked elsewhere.
+        pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+            match self.0.try_lock() {
+                Ok(guard) => Some(guard),
+                Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            }
+        }
+
+        pub fn downgrade(&self) -> WeakShared<T> {
+            WeakShared(Arc::downgrade(&self.0))
+        }
+    }
+
+    impl<T> Clone for Shared<T> {
+        fn clone(&self) -> Self {
+            Shared(self.0.clone())
+        }
+    }
+
+    impl<T> WeakShared<T> {
+        /// `None` once every `Shared` has been dropped.
+        pub fn upgrade(&self) -> Option<Shared<T>> {
+            self.0.upgrade().map(Shared)
+        }
+    }
+
+    impl<T> Clone for WeakShared<T> {
+        fn clone(&self) -> Self {
+            WeakShared(self.0.clone())
+        }
+    }
+}
+
+pub use imp::{Guard, Shared, WeakShared};
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Shared::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_state() {
+        let shared = Shared::new(1);
+        let other = shared.clone();
+        *other.lock() += 1;
+        assert_eq!(*shared.lock(), 2);
+    }
+
+    #[test]
+    fn test_weak_does_not_keep_state_alive() {
+        let shared = Shared::new(1);
+        let weak = shared.downgrade();
+        assert_eq!(*weak.upgrade().unwrap().lock(), 1);
+        drop(shared);
+        assert!(weak.upgrade().is_none());
+    }
+}
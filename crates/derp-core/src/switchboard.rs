@@ -0,0 +1,118 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::error::{DerpError, DerpResult};
+use crate::protocol::{PeerKey, PEER_KEY_LEN};
+
+const MAC_LEN: usize = 6;
+/// More MACs than any page plausibly runs VMs for; larger announcements are
+/// refused rather than letting one peer fill the table.
+const MAX_ANNOUNCED_MACS: usize = 256;
+
+/// Which relay peer each remote guest MAC lives behind, learned from the
+/// MacAnnounce frames peers gossip through the relay, plus the MACs of the
+/// NICs on this connection that get announced in turn.
+#[derive(Debug, Default)]
+pub struct Switchboard {
+    local: BTreeSet<[u8; 6]>,
+    remote: HashMap<[u8; 6], PeerKey>,
+}
+
+impl Switchboard {
+    /// Returns false if the MAC was already registered.
+    pub fn add_local(&mut self, mac: [u8; 6]) -> bool {
+        self.local.insert(mac)
+    }
+
+    pub fn remove_local(&mut self, mac: [u8; 6]) -> bool {
+        self.local.remove(&mac)
+    }
+
+    /// The outbound MacAnnounce payload: every local MAC, back to back.
+    pub fn announcement(&self) -> Vec<u8> {
+        self.local.iter().flatten().copied().collect()
+    }
+
+    /// Applies an inbound MacAnnounce: the sender's key, stamped by the
+    /// relay, followed by the complete list of its MACs. Whatever the
+    /// sender announced before is replaced.
+    pub fn learn(&mut self, payload: &[u8]) -> DerpResult<()> {
+        if payload.len() < PEER_KEY_LEN || !(payload.len() - PEER_KEY_LEN).is_multiple_of(MAC_LEN) {
+            return Err(DerpError::InvalidProtocol("Invalid MacAnnounce length".into()));
+        }
+        let (peer, macs) = payload.split_at(PEER_KEY_LEN);
+        if macs.len() / MAC_LEN > MAX_ANNOUNCED_MACS {
+            return Err(DerpError::InvalidProtocol("Too many MACs announced".into()));
+        }
+
+        let peer = PeerKey::try_from(peer).expect("length checked above");
+        self.forget(&peer);
+        for mac in macs.chunks_exact(MAC_LEN) {
+            let mac: [u8; 6] = mac.try_into().expect("chunks are MAC_LEN long");
+            // Only unicast addresses can be owned by one peer
+            if mac[0] & 0x01 == 0 && !self.local.contains(&mac) {
+                self.remote.insert(mac, peer);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, peer: &PeerKey) {
+        self.remote.retain(|_, owner| owner != peer);
+    }
+
+    pub fn peer_for(&self, mac: &[u8; 6]) -> Option<PeerKey> {
+        self.remote.get(mac).copied()
+    }
+
+    pub fn clear_remote(&mut self) {
+        self.remote.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0A];
+    const B: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0B];
+
+    fn announce(peer: u8, macs: &[[u8; 6]]) -> Vec<u8> {
+        let mut payload = vec![peer; PEER_KEY_LEN];
+        payload.extend(macs.iter().flatten());
+        payload
+    }
+
+    #[test]
+    fn test_learn_replaces_and_forgets() {
+        let mut switchboard = Switchboard::default();
+        switchboard.learn(&announce(1, &[A, B])).unwrap();
+        assert_eq!(switchboard.peer_for(&A), Some([1; 32]));
+        assert_eq!(switchboard.peer_for(&B), Some([1; 32]));
+
+        // A moved to another page
+        switchboard.learn(&announce(1, &[B])).unwrap();
+        switchboard.learn(&announce(2, &[A, [0xFF; 6]])).unwrap();
+        assert_eq!(switchboard.peer_for(&A), Some([2; 32]));
+        assert_eq!(switchboard.peer_for(&[0xFF; 6]), None);
+
+        switchboard.forget(&[1; 32]);
+        assert_eq!(switchboard.peer_for(&B), None);
+        assert!(switchboard.learn(&announce(3, &[A])[..PEER_KEY_LEN + 3]).is_err());
+    }
+
+    #[test]
+    fn test_announcement_lists_local_macs() {
+        let mut switchboard = Switchboard::default();
+        assert!(switchboard.announcement().is_empty());
+        assert!(switchboard.add_local(B));
+        assert!(switchboard.add_local(A));
+        assert!(!switchboard.add_local(A));
+        assert_eq!(switchboard.announcement(), [A, B].concat());
+
+        // A peer can't claim a MAC that lives here
+        switchboard.learn(&announce(1, &[A])).unwrap();
+        assert_eq!(switchboard.peer_for(&A), None);
+
+        assert!(switchboard.remove_local(A));
+        assert_eq!(switchboard.announcement(), B.to_vec());
+    }
+}
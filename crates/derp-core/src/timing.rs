@@ -0,0 +1,250 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+use crate::clock::Clock;
+
+// `performance` is a global in both windows and workers, like the timers.
+// The marks are only for browser devtools, so native builds go without.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance, js_name = mark, catch)]
+    fn performance_mark(name: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_namespace = performance, js_name = measure, catch)]
+    fn performance_measure(name: &str, start_mark: &str) -> Result<JsValue, JsValue>;
+}
+
+#[cfg(target_arch = "wasm32")]
+const HANDSHAKE_MARK: &str = "derp-handshake-start";
+#[cfg(target_arch = "wasm32")]
+const HANDSHAKE_MEASURE: &str = "derp-handshake";
+
+/// Upper bounds of the histogram buckets, from the microseconds a small
+/// packet takes to encrypt to the seconds a slow handshake can take. A
+/// final bucket catches everything above the last bound.
+pub const BUCKET_BOUNDS_MS: [f64; 16] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0, 5000.0,
+];
+
+/// The stages of connecting and of moving a packet that get timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// From sending ClientInfo to the relay accepting the connection.
+    Handshake,
+    Encrypt,
+    Decrypt,
+    Compress,
+    Decompress,
+    /// Handling one relay message, from its arrival to its events having
+    /// been emitted.
+    Dispatch,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::Handshake,
+        Phase::Encrypt,
+        Phase::Decrypt,
+        Phase::Compress,
+        Phase::Decompress,
+        Phase::Dispatch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Handshake => "handshake",
+            Phase::Encrypt => "encrypt",
+            Phase::Decrypt => "decrypt",
+            Phase::Compress => "compress",
+            Phase::Decompress => "decompress",
+            Phase::Dispatch => "dispatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, ms: f64) {
+        let bucket = BUCKET_BOUNDS_MS.iter().position(|bound| ms <= *bound).unwrap_or(BUCKET_BOUNDS_MS.len());
+        let count = self.count();
+        self.counts[bucket] += 1;
+        self.sum_ms += ms;
+        self.min_ms = if count == 0 { ms } else { self.min_ms.min(ms) };
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = BUCKET_BOUNDS_MS.iter().zip(&self.counts).map(|(bound, count)| {
+            cumulative += count;
+            Bucket { le_ms: *bound, count: cumulative }
+        }).collect();
+        HistogramSnapshot {
+            count: self.count(),
+            sum_ms: self.sum_ms,
+            min_ms: self.min_ms,
+            max_ms: self.max_ms,
+            buckets,
+        }
+    }
+}
+
+/// One histogram as `getTimings` returns it.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    /// Cumulative, as in Prometheus: each counts the samples that took at
+    /// most `leMs`. Slower samples are only in `count`.
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify))]
+#[serde(rename_all = "camelCase")]
+pub struct Bucket {
+    pub le_ms: f64,
+    pub count: u64,
+}
+
+/// Where the time goes, one histogram per `Phase`. Per-packet phases are
+/// often below the resolution of `performance.now()`, which browsers
+/// coarsen to between 5 and 100 microseconds, so read them in aggregate.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi))]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    pub handshake: HistogramSnapshot,
+    pub encrypt: HistogramSnapshot,
+    pub decrypt: HistogramSnapshot,
+    pub compress: HistogramSnapshot,
+    pub decompress: HistogramSnapshot,
+    pub dispatch: HistogramSnapshot,
+}
+
+/// Times phases against a `Clock` and keeps their histograms. The handshake
+/// also shows up in the browser's performance timeline as a
+/// "derp-handshake" measure; per-packet phases are only kept here, since a
+/// timeline entry per packet would soon fill the browser's buffer.
+pub struct Stopwatch {
+    clock: Arc<dyn Clock>,
+    histograms: Mutex<[Histogram; Phase::ALL.len()]>,
+}
+
+impl Stopwatch {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Stopwatch {
+            clock,
+            histograms: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn now_ms(&self) -> f64 {
+        self.clock.now_ms()
+    }
+
+    /// Records a `phase` that began at `started_ms` and ends now.
+    pub fn record(&self, phase: Phase, started_ms: f64) {
+        let elapsed = (self.clock.now_ms() - started_ms).max(0.0);
+        self.histograms.lock().unwrap()[phase as usize].record(elapsed);
+    }
+
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = self.clock.now_ms();
+        let result = f();
+        self.record(phase, started);
+        result
+    }
+
+    pub fn mark_handshake_start(&self) {
+        #[cfg(target_arch = "wasm32")]
+        let _ = performance_mark(HANDSHAKE_MARK);
+    }
+
+    pub fn record_handshake(&self, started_ms: f64) {
+        self.record(Phase::Handshake, started_ms);
+        // Fails harmlessly if the mark was cleared by the page
+        #[cfg(target_arch = "wasm32")]
+        let _ = performance_measure(HANDSHAKE_MEASURE, HANDSHAKE_MARK);
+    }
+
+    pub fn histogram(&self, phase: Phase) -> HistogramSnapshot {
+        self.histograms.lock().unwrap()[phase as usize].snapshot()
+    }
+
+    pub fn timings(&self) -> Timings {
+        Timings {
+            handshake: self.histogram(Phase::Handshake),
+            encrypt: self.histogram(Phase::Encrypt),
+            decrypt: self.histogram(Phase::Decrypt),
+            compress: self.histogram(Phase::Compress),
+            decompress: self.histogram(Phase::Decompress),
+            dispatch: self.histogram(Phase::Dispatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        for ms in [0.005, 0.3, 0.3, 7.0, 9000.0] {
+            histogram.record(ms);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 5);
+        assert_eq!((snapshot.min_ms, snapshot.max_ms), (0.005, 9000.0));
+        let at_most = |le_ms: f64| snapshot.buckets.iter().find(|bucket| bucket.le_ms == le_ms).unwrap().count;
+        assert_eq!(at_most(0.01), 1);
+        assert_eq!(at_most(0.5), 3);
+        assert_eq!(at_most(10.0), 4);
+        // Above the last bound, only the total counts it
+        assert_eq!(at_most(5000.0), 4);
+    }
+
+    #[test]
+    fn test_stopwatch_times_phases() {
+        let clock = Arc::new(MockClock::new(1000.0));
+        let stopwatch = Stopwatch::new(clock.clone());
+
+        let value = stopwatch.time(Phase::Encrypt, || {
+            clock.advance(0.2);
+            42
+        });
+        assert_eq!(value, 42);
+
+        let started = stopwatch.now_ms();
+        clock.advance(150.0);
+        stopwatch.record_handshake(started);
+
+        let timings = stopwatch.timings();
+        assert_eq!(timings.encrypt.count, 1);
+        assert!((timings.encrypt.sum_ms - 0.2).abs() < 1e-9);
+        assert_eq!(timings.handshake.max_ms, 150.0);
+        assert_eq!(timings.decrypt.count, 0);
+    }
+}
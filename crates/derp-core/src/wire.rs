@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use crate::error::{DerpError, DerpResult};
+
+/// What Go's derp server puts before its key in the ServerKey frame: "DERP"
+/// and the key emoji.
+pub const GO_MAGIC: &[u8] = "DERP🔑".as_bytes();
+
+/// The protocol version Go servers speak and expect clients to claim.
+pub const GO_PROTOCOL_VERSION: u8 = 2;
+
+/// How ClientInfo, ServerInfo and other control messages are encoded.
+/// Picked per connection from the ServerKey frame, where a server that
+/// leads with `GO_MAGIC` gets JSON, or from the ServerInfo frame, whose
+/// CBOR flag answers the client's offer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    /// Self-describing, so either side may add fields the other skips.
+    Cbor,
+    /// The JSON Go's derp writes, with this crate's extra fields alongside
+    /// for servers that know them. Go ignores fields it doesn't know.
+    Json,
+}
+
+impl WireFormat {
+    /// The format a ServerKey payload asks for, and the key it carries.
+    pub fn detect(server_key: &[u8]) -> (WireFormat, &[u8]) {
+        match server_key.strip_prefix(GO_MAGIC) {
+            Some(key) => (WireFormat::Json, key),
+            None => (WireFormat::Bincode, server_key),
+        }
+    }
+}
+
+/// Go's `clientInfo`, whose keys mix tagged and untagged field names.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GoClientInfo {
+    #[serde(default, rename = "meshKey", skip_serializing_if = "String::is_empty")]
+    pub mesh_key: String,
+    #[serde(default, rename = "version")]
+    pub version: u8,
+    #[serde(default, rename = "CanAckPings")]
+    pub can_ack_pings: bool,
+    #[serde(default, rename = "IsProber", skip_serializing_if = "std::ops::Not::not")]
+    pub is_prober: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+/// Go's `serverInfo`. Only servers built with this crate in mind send
+/// `features` or `maxPacketSize`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GoServerInfo {
+    #[serde(default, rename = "version")]
+    pub version: u8,
+    #[serde(default, rename = "TokenBucketBytesPerSecond")]
+    pub token_bucket_bytes_per_second: u64,
+    #[serde(default, rename = "TokenBucketBytesBurst")]
+    pub token_bucket_bytes_burst: u64,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default, rename = "maxPacketSize")]
+    pub max_packet_size: Option<u32>,
+}
+
+pub fn encode_cbor<T: Serialize>(value: &T) -> DerpResult<Vec<u8>> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(value, &mut payload)
+        .map_err(|e| DerpError::SerializationError(e.to_string()))?;
+    Ok(payload)
+}
+
+/// Decodes CBOR, ignoring map keys `T` doesn't have.
+pub fn decode_cbor<T: for<'de> Deserialize<'de>>(payload: &[u8]) -> DerpResult<T> {
+    ciborium::from_reader(payload)
+        .map_err(|e| DerpError::SerializationError(e.to_string()))
+}
+
+/// In the browser, JSON goes through the page's own parser, so the wasm
+/// doesn't carry a second one. Native builds use serde_json.
+#[cfg(target_arch = "wasm32")]
+pub fn encode_json<T: Serialize>(value: &T) -> DerpResult<Vec<u8>> {
+    let value = serde_wasm_bindgen::to_value(value)
+        .map_err(|e| DerpError::SerializationError(e.to_string()))?;
+    let json = js_sys::JSON::stringify(&value)
+        .map_err(|e| DerpError::SerializationError(format!("{:?}", e)))?;
+    Ok(String::from(json).into_bytes())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn encode_json<T: Serialize>(value: &T) -> DerpResult<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| DerpError::SerializationError(e.to_string()))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn decode_json<T: for<'de> Deserialize<'de>>(payload: &[u8]) -> DerpResult<T> {
+    let json = std::str::from_utf8(payload)
+        .map_err(|_| DerpError::SerializationError("Handshake JSON isn't UTF-8".into()))?;
+    let value = js_sys::JSON::parse(json)
+        .map_err(|e| DerpError::SerializationError(format!("Invalid handshake JSON: {:?}", e)))?;
+    serde_wasm_bindgen::from_value(value)
+        .map_err(|e| DerpError::SerializationError(e.to_string()))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn decode_json<T: for<'de> Deserialize<'de>>(payload: &[u8]) -> DerpResult<T> {
+    serde_json::from_slice(payload)
+        .map_err(|e| DerpError::SerializationError(format!("Invalid handshake JSON: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_selects_json() {
+        let key = [7u8; 32];
+        assert_eq!(WireFormat::detect(&key), (WireFormat::Bincode, &key[..]));
+
+        let mut go_key = GO_MAGIC.to_vec();
+        go_key.extend_from_slice(&key);
+        assert_eq!(GO_MAGIC.len(), 8);
+        assert_eq!(WireFormat::detect(&go_key), (WireFormat::Json, &key[..]));
+    }
+
+    #[test]
+    fn test_go_field_names() {
+        let info = GoClientInfo { version: GO_PROTOCOL_VERSION, can_ack_pings: true, ..Default::default() };
+        let json = String::from_utf8(encode_json(&info).unwrap()).unwrap();
+        assert_eq!(json, r#"{"version":2,"CanAckPings":true}"#);
+
+        let info: GoServerInfo = decode_json(br#"{"version":2,"TokenBucketBytesBurst":1024,"Unknown":1}"#).unwrap();
+        assert_eq!((info.version, info.token_bucket_bytes_burst, info.max_packet_size), (2, 1024, None));
+        assert!(decode_json::<GoServerInfo>(b"{").is_err());
+    }
+
+    #[test]
+    fn test_cbor_skips_unknown_fields() {
+        #[derive(Serialize)]
+        struct Newer {
+            version: u8,
+            features: Vec<String>,
+            compression_levels: Vec<u8>,
+        }
+
+        let payload = encode_cbor(&Newer { version: 9, features: vec!["batching".into()], compression_levels: vec![1, 6] }).unwrap();
+        let info: GoServerInfo = decode_cbor(&payload).unwrap();
+        assert_eq!((info.version, info.features), (9, vec!["batching".to_string()]));
+        assert!(decode_cbor::<GoServerInfo>(&payload[..payload.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::process;
+use std::time::Duration;
+use derp_core::protocol::{hex_decode_key, PeerKey};
+use derp_server::probe::Probe;
+
+const USAGE: &str = "\
+Usage: derp-ping [options] URL
+
+Connects to the relay at URL (ws:// or wss://), completes the handshake,
+measures round trips with Ping frames and prints a report.
+
+Options:
+  --count N         Pings to send (default 5)
+  --interval MS     Milliseconds between pings (default 1000)
+  --timeout MS      Milliseconds to wait for the handshake or a Pong (default 5000)
+  --token TOKEN     Present TOKEN to the relay
+  --peer KEY        Also send test packets to the peer with this hex key
+  --packets N       Test packets to send to --peer (default 10)
+  --size BYTES      Size of each test packet (default 1200)";
+
+struct Options {
+    url: String,
+    count: u32,
+    interval: Duration,
+    timeout: Duration,
+    token: Option<String>,
+    peer: Option<PeerKey>,
+    packets: u32,
+    size: usize,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut url = None;
+    let mut options = Options {
+        url: String::new(),
+        count: 5,
+        interval: Duration::from_millis(1000),
+        timeout: Duration::from_millis(5000),
+        token: None,
+        peer: None,
+        packets: 10,
+        size: 1200,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--count" => options.count = number(&arg, value()?)?,
+            "--interval" => options.interval = Duration::from_millis(number(&arg, value()?)?),
+            "--timeout" => options.timeout = Duration::from_millis(number(&arg, value()?)?),
+            "--token" => options.token = Some(value()?),
+            "--peer" => options.peer = Some(hex_decode_key(&value()?).map_err(|e| e.to_string())?),
+            "--packets" => options.packets = number(&arg, value()?)?,
+            "--size" => options.size = number(&arg, value()?)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if url.is_none() => url = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    options.url = url.ok_or("No relay URL given")?;
+    Ok(options)
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: String) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid {}: {}", option, value))
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    if let Err(e) = run(options).await {
+        eprintln!("derp-ping: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(options: Options) -> derp_core::error::DerpResult<()> {
+    let mut probe = Probe::connect(&options.url, options.token, options.timeout).await?;
+    let rtt = probe.ping(options.count, options.interval, options.timeout).await?;
+    // Like ping, fail when nothing came back
+    let unanswered = rtt.sent() > 0 && rtt.received() == 0;
+    let packets = match options.peer {
+        Some(peer) => {
+            probe.send_packets(&peer, options.packets, options.size).await?;
+            Some((peer, options.packets, options.size))
+        }
+        None => None,
+    };
+    print!("{}", probe.report(&options.url, rtt, packets));
+    probe.close().await?;
+    if unanswered {
+        process::exit(1);
+    }
+    Ok(())
+}
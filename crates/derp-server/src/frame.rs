@@ -0,0 +1,56 @@
+use derp_core::error::{DerpError, DerpResult};
+use derp_core::protocol::{
+    FrameType, PeerKey, ProtocolState, FRAME_HEADER_SIZE, MAX_FRAME_PAYLOAD, PEER_KEY_LEN, PROTOCOL_VERSION,
+};
+
+/// Builds one of the relay's own frames, which are never encrypted.
+pub fn encode(frame_type: FrameType, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let length = (payload.len() as u16).to_be_bytes();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&[PROTOCOL_VERSION, frame_type as u8, flags, length[0], length[1]]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Builds the RecvFromPeer frame a packet from `source` is delivered in.
+/// The sender's flags are kept, as its ciphertext is sealed against them.
+pub fn from_peer(source: &PeerKey, flags: u8, ciphertext: &[u8]) -> DerpResult<Vec<u8>> {
+    if PEER_KEY_LEN + ciphertext.len() > MAX_FRAME_PAYLOAD {
+        return Err(DerpError::InvalidProtocol("Packet too large to deliver".into()));
+    }
+    let mut payload = Vec::with_capacity(PEER_KEY_LEN + ciphertext.len());
+    payload.extend_from_slice(source);
+    payload.extend_from_slice(ciphertext);
+    Ok(encode(FrameType::RecvFromPeer, flags, &payload))
+}
+
+/// Splits a WebSocket message into its frames; clients that agreed to
+/// batching may send several in one.
+pub fn split(mut message: &[u8]) -> DerpResult<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+    while !message.is_empty() {
+        let (frame, rest) = message.split_at(ProtocolState::frame_len(message)?);
+        frames.push(frame);
+        message = rest;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use derp_core::protocol::Frame;
+
+    #[test]
+    fn test_split_batch() {
+        let mut message = encode(FrameType::Ping, 0, b"12345678");
+        message.extend_from_slice(&encode(FrameType::KeepAlive, 0, &[]));
+
+        let frames = split(&message).unwrap();
+        assert_eq!(frames.len(), 2);
+        let ping = Frame::parse(frames[0]).unwrap();
+        assert_eq!((ping.frame_type, ping.payload), (FrameType::Ping, &b"12345678"[..]));
+        assert_eq!(Frame::parse(frames[1]).unwrap().frame_type, FrameType::KeepAlive);
+        assert!(split(&message[..message.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! A native relay for `derp-wasm` clients: hands out keys, introduces
+//! peers to each other, relays their frames and keeps connections alive.
+//! `probe` is the client side `derp-ping` checks relays with.
+
+pub mod frame;
+pub mod probe;
+pub mod registry;
+pub mod server;
+
+pub use server::{Server, ServerConfig};
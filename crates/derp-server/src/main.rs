@@ -0,0 +1,64 @@
+use std::process;
+use std::time::Duration;
+use derp_server::{Server, ServerConfig};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:3340";
+
+const USAGE: &str = "\
+Usage: derp-server [options]
+
+Options:
+  --listen ADDR       Address to accept WebSockets on (default 0.0.0.0:3340)
+  --name NAME         Name reported to clients
+  --region REGION     Region reported to clients
+  --token TOKEN       Require clients to present TOKEN; may be repeated
+  --keepalive SECS    Seconds between KeepAlive frames (default 60)
+  --allow-close-peer  Let clients disconnect each other";
+
+fn parse_args() -> Result<(String, ServerConfig), String> {
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut config = ServerConfig::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--listen" => listen = value()?,
+            "--name" => config.name = value()?,
+            "--region" => config.region = value()?,
+            "--token" => config.auth_tokens.push(value()?),
+            "--keepalive" => {
+                let secs = value()?;
+                let secs = secs.parse().map_err(|_| format!("Invalid --keepalive: {}", secs))?;
+                config.keepalive_interval = Duration::from_secs(secs);
+            }
+            "--allow-close-peer" => config.allow_close_peer = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    if config.keepalive_interval.is_zero() {
+        return Err("--keepalive must be at least 1".into());
+    }
+    Ok((listen, config))
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let (listen, config) = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    let result = match Server::bind(&listen, config).await {
+        Ok(server) => server.run().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("derp-server: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use derp_core::crypto::CryptoState;
+use derp_core::error::{DerpError, DerpResult};
+use derp_core::protocol::{hex_encode, Frame, FrameType, PeerKey, ProtocolState};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use crate::frame;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Round trips measured with Ping frames.
+#[derive(Debug, Clone, Default)]
+pub struct RttStats {
+    sent: u32,
+    samples: Vec<Duration>,
+}
+
+impl RttStats {
+    /// Records one ping and its round trip, or None if no Pong came back.
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        self.sent += 1;
+        self.samples.extend(rtt);
+    }
+
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+
+    pub fn received(&self) -> u32 {
+        self.samples.len() as u32
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => 100.0 * (sent - self.received()) as f64 / sent as f64,
+        }
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let total: Duration = self.samples.iter().sum();
+        (!self.samples.is_empty()).then(|| total / self.samples.len() as u32)
+    }
+}
+
+/// Everything `derp-ping` prints.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub url: String,
+    pub name: String,
+    pub region: String,
+    pub features: Vec<String>,
+    pub keepalive_interval_ms: u32,
+    pub max_packet_size: usize,
+    pub handshake: Duration,
+    pub rtt: RttStats,
+    /// Test packets sent: the peer, how many, and how big.
+    pub packets: Option<(PeerKey, u32, usize)>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Connected to {} in {:.1} ms", self.url, ms(self.handshake))?;
+        writeln!(f, "  relay      {:?} in region {:?}", self.name, self.region)?;
+        writeln!(f, "  features   {}", if self.features.is_empty() { "none".into() } else { self.features.join(", ") })?;
+        writeln!(f, "  keepalive  {} ms", self.keepalive_interval_ms)?;
+        writeln!(f, "  max packet {} bytes", self.max_packet_size)?;
+        writeln!(f, "Ping: {} sent, {} received, {:.0}% lost",
+            self.rtt.sent(), self.rtt.received(), self.rtt.loss_percent())?;
+        if let (Some(min), Some(mean), Some(max)) = (self.rtt.min(), self.rtt.mean(), self.rtt.max()) {
+            writeln!(f, "  rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", ms(min), ms(mean), ms(max))?;
+        }
+        if let Some((peer, count, size)) = &self.packets {
+            writeln!(f, "Packets: {} of {} bytes sent to {}", count, size, hex_encode(peer))?;
+        }
+        Ok(())
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A client connection made to check on a relay, using the same handshake
+/// and framing as the browser.
+pub struct Probe {
+    socket: Socket,
+    protocol: ProtocolState,
+    crypto: CryptoState,
+    handshake: Duration,
+    next_ping: u64,
+}
+
+impl Probe {
+    /// Connects to `url` and completes the handshake within `timeout`.
+    pub async fn connect(url: &str, token: Option<String>, timeout: Duration) -> DerpResult<Probe> {
+        let started = Instant::now();
+        let (socket, _) = time::timeout(timeout, connect_async(url)).await
+            .map_err(|_| DerpError::Timeout(format!("Connecting to {}", url)))?
+            .map_err(|e| DerpError::WebSocketError(e.to_string()))?;
+
+        let mut protocol = ProtocolState::new();
+        protocol.set_auth_token(token);
+        let mut probe = Probe {
+            socket,
+            protocol,
+            crypto: CryptoState::new()?,
+            handshake: Duration::ZERO,
+            next_ping: 0,
+        };
+
+        let hello = probe.protocol.start_handshake()?;
+        probe.send(hello).await?;
+        let deadline = started + timeout;
+        while !probe.protocol.is_connected() {
+            let message = probe.recv(deadline).await?
+                .ok_or_else(|| DerpError::Timeout("Waiting for the handshake".into()))?;
+            for data in frame::split(&message)? {
+                let frame = Frame::parse(data)?;
+                let reply = match frame.frame_type {
+                    FrameType::ServerKey => probe.protocol.handle_server_key(frame.payload)?,
+                    FrameType::ServerInfo => Some(probe.protocol.handle_server_info_frame(&frame)?),
+                    FrameType::AuthResult => {
+                        probe.protocol.handle_auth_result(frame.payload)?;
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    probe.send(reply).await?;
+                }
+            }
+        }
+        probe.handshake = started.elapsed();
+        Ok(probe)
+    }
+
+    /// Sends `count` pings, `interval` apart, each given `timeout` to be
+    /// answered.
+    pub async fn ping(&mut self, count: u32, interval: Duration, timeout: Duration) -> DerpResult<RttStats> {
+        let mut stats = RttStats::default();
+        for i in 0..count {
+            if i > 0 {
+                time::sleep(interval).await;
+            }
+            stats.record(self.ping_once(timeout).await?);
+        }
+        Ok(stats)
+    }
+
+    /// Pongs carry the ping's payload back, so late answers to earlier
+    /// pings aren't mistaken for this one's. An empty Pong, from a relay
+    /// that doesn't echo, counts for whichever ping is outstanding.
+    async fn ping_once(&mut self, timeout: Duration) -> DerpResult<Option<Duration>> {
+        let id = self.next_ping.to_be_bytes();
+        self.next_ping += 1;
+        let sent = Instant::now();
+        let ping = self.protocol.encode_frame(FrameType::Ping, &id);
+        self.send(ping).await?;
+
+        let deadline = sent + timeout;
+        while let Some(message) = self.recv(deadline).await? {
+            for data in frame::split(&message)? {
+                let frame = Frame::parse(data)?;
+                match frame.frame_type {
+                    FrameType::Pong if frame.payload == id || frame.payload.is_empty() => {
+                        return Ok(Some(sent.elapsed()));
+                    }
+                    FrameType::Ping => {
+                        let pong = self.protocol.encode_frame(FrameType::Pong, frame.payload);
+                        self.send(pong).await?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sends `count` packets of `size` bytes to `peer`, sealed as the
+    /// browser seals them.
+    pub async fn send_packets(&mut self, peer: &PeerKey, count: u32, size: usize) -> DerpResult<()> {
+        let packet = vec![0u8; size];
+        for _ in 0..count {
+            let mut frame = Vec::new();
+            self.protocol.encode_peer_frame_into(&self.crypto, peer, &packet, &mut frame)?;
+            self.send(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// What the relay reported in the handshake, with `rtt` and `packets`.
+    pub fn report(&self, url: &str, rtt: RttStats, packets: Option<(PeerKey, u32, usize)>) -> Report {
+        let info = self.protocol.server_info();
+        Report {
+            url: url.to_string(),
+            name: info.map(|info| info.name().to_string()).unwrap_or_default(),
+            region: info.map(|info| info.region().to_string()).unwrap_or_default(),
+            features: info.map(|info| info.features().to_vec()).unwrap_or_default(),
+            keepalive_interval_ms: self.protocol.keepalive_interval_ms(),
+            max_packet_size: self.protocol.max_packet_size(),
+            handshake: self.handshake,
+            rtt,
+            packets,
+        }
+    }
+
+    /// Says goodbye so the relay drops this connection at once.
+    pub async fn close(mut self) -> DerpResult<()> {
+        let goodbye = self.protocol.goodbye();
+        self.send(goodbye).await?;
+        // The relay may hang up first, which is just as good
+        let _ = self.socket.close(None).await;
+        Ok(())
+    }
+
+    async fn send(&mut self, frames: Vec<u8>) -> DerpResult<()> {
+        self.socket.send(Message::Binary(frames)).await
+            .map_err(|e| DerpError::WebSocketError(e.to_string()))
+    }
+
+    /// The next binary message, or None at `deadline`.
+    async fn recv(&mut self, deadline: Instant) -> DerpResult<Option<Vec<u8>>> {
+        loop {
+            let message = match time::timeout_at(deadline.into(), self.socket.next()).await {
+                Ok(message) => message,
+                Err(_) => return Ok(None),
+            };
+            match message {
+                Some(Ok(Message::Binary(data))) => return Ok(Some(data)),
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(DerpError::WebSocketError("Relay closed the connection".into()));
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(DerpError::WebSocketError(e.to_string())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, ServerConfig};
+
+    #[test]
+    fn test_rtt_stats() {
+        let mut stats = RttStats::default();
+        assert_eq!((stats.loss_percent(), stats.mean()), (0.0, None));
+
+        stats.record(Some(Duration::from_millis(10)));
+        stats.record(None);
+        stats.record(Some(Duration::from_millis(30)));
+        stats.record(Some(Duration::from_millis(20)));
+        assert_eq!((stats.sent(), stats.received()), (4, 3));
+        assert_eq!(stats.loss_percent(), 25.0);
+        assert_eq!(stats.min(), Some(Duration::from_millis(10)));
+        assert_eq!(stats.mean(), Some(Duration::from_millis(20)));
+        assert_eq!(stats.max(), Some(Duration::from_millis(30)));
+    }
+
+    #[tokio::test]
+    async fn test_probe_reports_on_a_relay() {
+        let config = ServerConfig { name: "test".into(), region: "lab".into(), ..Default::default() };
+        let server = Server::bind("127.0.0.1:0", config).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        tokio::spawn(server.run());
+
+        let mut probe = Probe::connect(&url, None, Duration::from_secs(5)).await.unwrap();
+        let rtt = probe.ping(3, Duration::ZERO, Duration::from_secs(5)).await.unwrap();
+        assert_eq!((rtt.sent(), rtt.received()), (3, 3));
+        probe.send_packets(&[7; 32], 2, 64).await.unwrap();
+
+        let report = probe.report(&url, rtt, Some(([7; 32], 2, 64))).to_string();
+        assert!(report.contains("relay      \"test\" in region \"lab\"\n"));
+        assert!(report.contains("Ping: 3 sent, 3 received, 0% lost\n"));
+        assert!(report.contains("Packets: 2 of 64 bytes sent to 0707"));
+        probe.close().await.unwrap();
+    }
+}
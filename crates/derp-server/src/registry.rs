@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use derp_core::protocol::{FrameType, PeerKey};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use crate::frame;
+
+/// Where a connection's outgoing messages are queued for its writer task.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    sender: mpsc::UnboundedSender<Message>,
+    /// The client spoke in base64 text messages, so it's answered in kind.
+    text: bool,
+}
+
+impl Outbox {
+    pub fn new(sender: mpsc::UnboundedSender<Message>, text: bool) -> Self {
+        Outbox { sender, text }
+    }
+
+    /// Queues `frames`, dropping them if the connection has closed.
+    pub fn send(&self, frames: Vec<u8>) {
+        let message = match self.text {
+            true => Message::Text(BASE64.encode(&frames)),
+            false => Message::Binary(frames),
+        };
+        let _ = self.sender.send(message);
+    }
+
+    /// Has the writer close the WebSocket after what's already queued.
+    pub fn close(&self) {
+        let _ = self.sender.send(Message::Close(None));
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    outbox: Outbox,
+    preferred: bool,
+}
+
+/// The clients that have completed the handshake, by the key the server
+/// gave each of them.
+#[derive(Debug, Default)]
+pub struct Registry {
+    clients: HashMap<PeerKey, Client>,
+}
+
+impl Registry {
+    /// Adds a client and introduces it and everyone already here to each
+    /// other with PeerPresent frames.
+    pub fn join(&mut self, key: PeerKey, outbox: Outbox) {
+        for (other, client) in &self.clients {
+            client.outbox.send(frame::encode(FrameType::PeerPresent, 0, &key));
+            outbox.send(frame::encode(FrameType::PeerPresent, 0, other));
+        }
+        self.clients.insert(key, Client { outbox, preferred: false });
+        log::info!("{} joined; {} connected", hex(&key), self.clients.len());
+    }
+
+    /// Removes a client and tells the rest it's gone. Returns false if it
+    /// had already left.
+    pub fn leave(&mut self, key: &PeerKey) -> bool {
+        if self.clients.remove(key).is_none() {
+            return false;
+        }
+        self.broadcast(key, frame::encode(FrameType::PeerGone, 0, key));
+        log::info!("{} left; {} connected", hex(key), self.clients.len());
+        true
+    }
+
+    /// Disconnects `key` on another client's request. The rest hear of it
+    /// by a ClosePeer frame rather than PeerGone.
+    pub fn close(&mut self, key: &PeerKey) -> bool {
+        let Some(client) = self.clients.remove(key) else { return false };
+        client.outbox.close();
+        self.broadcast(key, frame::encode(FrameType::ClosePeer, 0, key));
+        log::info!("{} was closed by a peer; {} connected", hex(key), self.clients.len());
+        true
+    }
+
+    /// Queues `frames` for every client but `from`.
+    pub fn broadcast(&self, from: &PeerKey, frames: Vec<u8>) {
+        for (key, client) in &self.clients {
+            if key != from {
+                client.outbox.send(frames.clone());
+            }
+        }
+    }
+
+    /// Queues `frames` for one client. Returns false if it isn't connected.
+    pub fn send(&self, to: &PeerKey, frames: Vec<u8>) -> bool {
+        match self.clients.get(to) {
+            Some(client) => {
+                client.outbox.send(frames);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Everyone connected but `key`, for answering WatchConns.
+    pub fn peers_of(&self, key: &PeerKey) -> Vec<PeerKey> {
+        self.clients.keys().filter(|other| *other != key).copied().collect()
+    }
+
+    pub fn set_preferred(&mut self, key: &PeerKey, preferred: bool) {
+        if let Some(client) = self.clients.get_mut(key) {
+            client.preferred = preferred;
+        }
+    }
+
+    pub fn is_preferred(&self, key: &PeerKey) -> bool {
+        self.clients.get(key).is_some_and(|client| client.preferred)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// Short form of a key for logs.
+pub fn hex(key: &PeerKey) -> String {
+    key[..4].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use derp_core::protocol::Frame;
+
+    fn client(text: bool) -> (Outbox, mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Outbox::new(sender, text), receiver)
+    }
+
+    fn next_frame(receiver: &mut mpsc::UnboundedReceiver<Message>) -> (FrameType, Vec<u8>) {
+        let data = match receiver.try_recv().unwrap() {
+            Message::Binary(data) => data,
+            Message::Text(text) => BASE64.decode(text).unwrap(),
+            other => panic!("unexpected message {:?}", other),
+        };
+        let frame = Frame::parse(&data).unwrap();
+        (frame.frame_type, frame.payload.to_vec())
+    }
+
+    #[test]
+    fn test_presence_is_announced_both_ways() {
+        let mut registry = Registry::default();
+        let (first, mut first_rx) = client(false);
+        let (second, mut second_rx) = client(true);
+
+        registry.join([1; 32], first);
+        registry.join([2; 32], second);
+        assert_eq!(next_frame(&mut first_rx), (FrameType::PeerPresent, vec![2; 32]));
+        assert_eq!(next_frame(&mut second_rx), (FrameType::PeerPresent, vec![1; 32]));
+        assert_eq!(registry.peers_of(&[1; 32]), vec![[2; 32]]);
+
+        assert!(registry.leave(&[2; 32]));
+        assert!(!registry.leave(&[2; 32]));
+        assert_eq!(next_frame(&mut first_rx), (FrameType::PeerGone, vec![2; 32]));
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_frames_reach_only_their_destination() {
+        let mut registry = Registry::default();
+        let (first, mut first_rx) = client(false);
+        let (second, mut second_rx) = client(false);
+        registry.join([1; 32], first);
+        registry.join([2; 32], second);
+        let _ = (next_frame(&mut first_rx), next_frame(&mut second_rx));
+
+        registry.broadcast(&[1; 32], frame::encode(FrameType::Send, 0, b"all"));
+        assert!(registry.send(&[1; 32], frame::encode(FrameType::SendToPeer, 0, b"one")));
+        assert!(!registry.send(&[3; 32], frame::encode(FrameType::SendToPeer, 0, b"none")));
+        assert_eq!(next_frame(&mut second_rx), (FrameType::Send, b"all".to_vec()));
+        assert_eq!(next_frame(&mut first_rx), (FrameType::SendToPeer, b"one".to_vec()));
+        assert!(first_rx.try_recv().is_err() && second_rx.try_recv().is_err());
+
+        assert!(registry.close(&[2; 32]));
+        assert!(matches!(second_rx.try_recv().unwrap(), Message::Close(None)));
+        assert_eq!(next_frame(&mut first_rx), (FrameType::ClosePeer, vec![2; 32]));
+    }
+}
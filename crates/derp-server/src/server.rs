@@ -0,0 +1,409 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use derp_core::error::{DerpError, DerpResult};
+use derp_core::protocol::{
+    decode_handshake, ClientInfo, Frame, FrameType, PeerKey, ServerInfo, FEATURE_AES_GCM_SIV,
+    FEATURE_BATCHING, FEATURE_PADDING, PEER_KEY_LEN, PROTOCOL_VERSION,
+};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+use crate::frame;
+use crate::registry::{self, Outbox, Registry};
+
+pub const DEFAULT_NAME: &str = "derp-server";
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Features the server will agree to. Batching and padding only change how
+/// clients frame what they send, and the cipher suite how it's encrypted,
+/// all of which the server passes on as it is.
+const SUPPORTED_FEATURES: [&str; 3] = [
+    FEATURE_BATCHING,
+    FEATURE_PADDING,
+    FEATURE_AES_GCM_SIV,
+];
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Reported to clients in ServerInfo.
+    pub name: String,
+    pub region: String,
+    /// Tokens clients may present in an Auth frame. When empty, anyone may
+    /// connect and Auth frames are accepted as they come.
+    pub auth_tokens: Vec<String>,
+    /// How often each client is sent a KeepAlive; also reported in
+    /// ServerInfo so clients send theirs as often.
+    pub keepalive_interval: Duration,
+    /// Whether clients may disconnect each other with ClosePeer.
+    pub allow_close_peer: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            name: DEFAULT_NAME.to_string(),
+            region: String::new(),
+            auth_tokens: Vec::new(),
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            allow_close_peer: false,
+        }
+    }
+}
+
+/// A relay for `derp-wasm` clients over WebSockets.
+///
+/// Each client is given a random key, by which the others address it.
+/// Packets are delivered as RecvFromPeer frames behind the sender's key,
+/// with the ciphertext as it was sent: they stay sealed end to end, and
+/// the server never holds a key to them.
+pub struct Server {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    registry: Arc<Mutex<Registry>>,
+    key: PeerKey,
+}
+
+impl Server {
+    pub async fn bind(addr: impl ToSocketAddrs, config: ServerConfig) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr).await?,
+            config: Arc::new(config),
+            registry: Arc::new(Mutex::new(Registry::default())),
+            key: rand::random(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts clients until the listener fails.
+    pub async fn run(self) -> io::Result<()> {
+        log::info!("Relaying on {}", self.local_addr()?);
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            let connection = Connection {
+                key: rand::random(),
+                server_key: self.key,
+                config: self.config.clone(),
+                registry: self.registry.clone(),
+                state: ConnectionState::AwaitingClientInfo,
+                outbox: None,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = connection.serve(stream).await {
+                    log::debug!("Connection from {} ended: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionState {
+    AwaitingClientInfo,
+    /// ServerInfo sent; waiting for Auth, or KeepAlive if no token is needed.
+    AwaitingAuth,
+    Joined,
+    Closed,
+}
+
+struct Connection {
+    key: PeerKey,
+    server_key: PeerKey,
+    config: Arc<ServerConfig>,
+    registry: Arc<Mutex<Registry>>,
+    state: ConnectionState,
+    /// Set by the first message, which decides between binary and text.
+    outbox: Option<Outbox>,
+}
+
+impl Connection {
+    async fn serve(mut self, stream: TcpStream) -> DerpResult<()> {
+        let socket = tokio_tungstenite::accept_async(stream).await
+            .map_err(|e| DerpError::WebSocketError(e.to_string()))?;
+        let (mut sink, mut messages) = socket.split();
+        let (sender, mut queue) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = queue.recv().await {
+                let closing = matches!(message, Message::Close(_));
+                if sink.send(message).await.is_err() || closing {
+                    break;
+                }
+            }
+        });
+
+        let mut keepalive = time::interval(self.config.keepalive_interval);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        keepalive.tick().await;
+
+        let result = loop {
+            tokio::select! {
+                message = messages.next() => {
+                    let data = match message {
+                        Some(Ok(Message::Binary(data))) => self.open(&sender, false, data),
+                        Some(Ok(Message::Text(text))) => match BASE64.decode(text) {
+                            Ok(data) => self.open(&sender, true, data),
+                            Err(e) => break Err(DerpError::InvalidProtocol(format!("Text message isn't base64: {}", e))),
+                        },
+                        Some(Ok(Message::Close(_))) | None => break Ok(()),
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => break Err(DerpError::WebSocketError(e.to_string())),
+                    };
+                    if let Err(e) = self.handle_message(&data) {
+                        break Err(e);
+                    }
+                    if self.state == ConnectionState::Closed {
+                        break Ok(());
+                    }
+                }
+                _ = keepalive.tick() => {
+                    if self.state == ConnectionState::Joined {
+                        self.send(frame::encode(FrameType::KeepAlive, 0, &[]));
+                    }
+                }
+            }
+        };
+
+        self.registry.lock().unwrap().leave(&self.key);
+        drop(sender);
+        self.outbox = None;
+        let _ = writer.await;
+        result
+    }
+
+    /// Fixes the connection's mode on its first message and passes `data` on.
+    fn open(&mut self, sender: &mpsc::UnboundedSender<Message>, text: bool, data: Vec<u8>) -> Vec<u8> {
+        if self.outbox.is_none() {
+            self.outbox = Some(Outbox::new(sender.clone(), text));
+        }
+        data
+    }
+
+    fn send(&self, frames: Vec<u8>) {
+        if let Some(outbox) = &self.outbox {
+            outbox.send(frames);
+        }
+    }
+
+    fn handle_message(&mut self, message: &[u8]) -> DerpResult<()> {
+        for data in frame::split(message)? {
+            if self.state == ConnectionState::Closed {
+                break;
+            }
+            self.handle_frame(data)?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, data: &[u8]) -> DerpResult<()> {
+        let frame = Frame::parse(data)?;
+        match (self.state, frame.frame_type) {
+            (ConnectionState::AwaitingClientInfo, FrameType::ClientInfo) => self.handle_client_info(frame.payload),
+            (ConnectionState::AwaitingAuth, FrameType::Auth) => self.handle_auth(frame.payload),
+            (ConnectionState::AwaitingAuth, FrameType::KeepAlive) => {
+                if !self.config.auth_tokens.is_empty() {
+                    log::info!("{} didn't present a token", registry::hex(&self.key));
+                    self.state = ConnectionState::Closed;
+                    return Ok(());
+                }
+                self.join();
+                Ok(())
+            }
+            (ConnectionState::Joined, _) => self.relay(&frame),
+            (state, frame_type) => Err(DerpError::InvalidState(format!("Unexpected {:?} frame while {:?}", frame_type, state))),
+        }
+    }
+
+    /// Answers with the server's key and the features both sides support.
+    fn handle_client_info(&mut self, payload: &[u8]) -> DerpResult<()> {
+        let info: ClientInfo = decode_handshake(payload)?;
+        if info.version() != PROTOCOL_VERSION {
+            return Err(DerpError::InvalidProtocol(format!("Unsupported client version: {}", info.version())));
+        }
+        let features = info.features().iter()
+            .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+        let server_info = ServerInfo::new(&self.config.name, features)
+            .with_region(&self.config.region)
+            .with_keepalive_interval_ms(self.config.keepalive_interval.as_millis() as u32);
+
+        // Separate messages, as batching isn't agreed until ServerInfo arrives
+        self.send(frame::encode(FrameType::ServerKey, 0, &self.server_key));
+        self.send(frame::encode(FrameType::ServerInfo, 0, &bincode::serialize(&server_info)?));
+        self.state = ConnectionState::AwaitingAuth;
+        Ok(())
+    }
+
+    /// AuthResult is 0 to accept, or 1 and a reason.
+    fn handle_auth(&mut self, payload: &[u8]) -> DerpResult<()> {
+        let token = String::from_utf8_lossy(payload);
+        if !self.config.auth_tokens.is_empty() && !self.config.auth_tokens.iter().any(|known| *known == token) {
+            log::info!("{} presented an unknown token", registry::hex(&self.key));
+            let mut result = vec![1];
+            result.extend_from_slice(b"unknown token");
+            self.send(frame::encode(FrameType::AuthResult, 0, &result));
+            if let Some(outbox) = &self.outbox {
+                outbox.close();
+            }
+            self.state = ConnectionState::Closed;
+            return Ok(());
+        }
+        self.send(frame::encode(FrameType::AuthResult, 0, &[0]));
+        self.join();
+        Ok(())
+    }
+
+    fn join(&mut self) {
+        if let Some(outbox) = &self.outbox {
+            self.registry.lock().unwrap().join(self.key, outbox.clone());
+        }
+        self.state = ConnectionState::Joined;
+    }
+
+    fn relay(&mut self, frame: &Frame) -> DerpResult<()> {
+        let mut clients = self.registry.lock().unwrap();
+        match frame.frame_type {
+            FrameType::Send => {
+                clients.broadcast(&self.key, frame::from_peer(&self.key, frame.flags, frame.payload)?);
+            }
+            FrameType::SendToPeer => {
+                let to = peer_key(frame.payload.get(..PEER_KEY_LEN))?;
+                let delivered = frame::from_peer(&self.key, frame.flags, &frame.payload[PEER_KEY_LEN..])?;
+                if !clients.send(&to, delivered) {
+                    log::debug!("Dropped a packet for {}, which isn't here", registry::hex(&to));
+                }
+            }
+            FrameType::MacAnnounce => {
+                let mut payload = self.key.to_vec();
+                payload.extend_from_slice(frame.payload);
+                clients.broadcast(&self.key, frame::encode(FrameType::MacAnnounce, 0, &payload));
+            }
+            FrameType::Ping => self.send(frame::encode(FrameType::Pong, 0, frame.payload)),
+            FrameType::WatchConns => {
+                let frames = clients.peers_of(&self.key).iter()
+                    .flat_map(|peer| frame::encode(FrameType::PeerPresent, 0, peer))
+                    .collect::<Vec<u8>>();
+                if !frames.is_empty() {
+                    self.send(frames);
+                }
+            }
+            FrameType::NotePreferred => {
+                clients.set_preferred(&self.key, frame.payload.first() == Some(&1));
+            }
+            FrameType::ClosePeer if self.config.allow_close_peer => {
+                clients.close(&peer_key(Some(frame.payload))?);
+            }
+            FrameType::Goodbye => {
+                clients.leave(&self.key);
+                self.state = ConnectionState::Closed;
+            }
+            FrameType::KeepAlive | FrameType::Pong => {}
+            other => log::debug!("Ignored a {:?} frame from {}", other, registry::hex(&self.key)),
+        }
+        Ok(())
+    }
+}
+
+fn peer_key(bytes: Option<&[u8]>) -> DerpResult<PeerKey> {
+    bytes.and_then(|bytes| PeerKey::try_from(bytes).ok())
+        .ok_or_else(|| DerpError::InvalidProtocol("Invalid peer key length".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use derp_core::protocol::FEATURE_CBOR;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start(config: ServerConfig) -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0", config).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        addr
+    }
+
+    async fn next_frame(socket: &mut Socket) -> (FrameType, Vec<u8>) {
+        loop {
+            let message = time::timeout(Duration::from_secs(5), socket.next()).await.unwrap().unwrap().unwrap();
+            if let Message::Binary(data) = message {
+                let frame = Frame::parse(&data).unwrap();
+                return (frame.frame_type, frame.payload.to_vec());
+            }
+        }
+    }
+
+    async fn send(socket: &mut Socket, frame_type: FrameType, payload: &[u8]) {
+        socket.send(Message::Binary(frame::encode(frame_type, 0, payload))).await.unwrap();
+    }
+
+    /// Connects and handshakes, presenting `token` if given.
+    async fn handshake(addr: SocketAddr, token: Option<&str>) -> Socket {
+        let (mut socket, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let info = ClientInfo::new(vec![FEATURE_BATCHING.to_string(), FEATURE_CBOR.to_string()]);
+        send(&mut socket, FrameType::ClientInfo, &bincode::serialize(&info).unwrap()).await;
+
+        let (frame_type, key) = next_frame(&mut socket).await;
+        assert_eq!((frame_type, key.len()), (FrameType::ServerKey, PEER_KEY_LEN));
+        let (frame_type, payload) = next_frame(&mut socket).await;
+        assert_eq!(frame_type, FrameType::ServerInfo);
+        let server_info: ServerInfo = decode_handshake(&payload).unwrap();
+        assert_eq!(server_info.features(), [FEATURE_BATCHING.to_string()]);
+
+        match token {
+            Some(token) => send(&mut socket, FrameType::Auth, token.as_bytes()).await,
+            None => send(&mut socket, FrameType::KeepAlive, &[]).await,
+        }
+        socket
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_relayed_between_peers() {
+        let addr = start(ServerConfig::default()).await;
+        let mut first = handshake(addr, None).await;
+        let mut second = handshake(addr, None).await;
+
+        let (frame_type, second_key) = next_frame(&mut first).await;
+        assert_eq!(frame_type, FrameType::PeerPresent);
+        let (_, first_key) = next_frame(&mut second).await;
+
+        send(&mut first, FrameType::Send, b"sealed").await;
+        let mut delivered = first_key.clone();
+        delivered.extend_from_slice(b"sealed");
+        assert_eq!(next_frame(&mut second).await, (FrameType::RecvFromPeer, delivered));
+
+        let mut payload = first_key.clone();
+        payload.extend_from_slice(b"direct");
+        send(&mut second, FrameType::SendToPeer, &payload).await;
+        let mut delivered = second_key.clone();
+        delivered.extend_from_slice(b"direct");
+        assert_eq!(next_frame(&mut first).await, (FrameType::RecvFromPeer, delivered));
+
+        send(&mut first, FrameType::Ping, b"12345678").await;
+        assert_eq!(next_frame(&mut first).await, (FrameType::Pong, b"12345678".to_vec()));
+
+        send(&mut second, FrameType::Goodbye, &[]).await;
+        assert_eq!(next_frame(&mut first).await, (FrameType::PeerGone, second_key));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tokens_are_refused() {
+        let config = ServerConfig { auth_tokens: vec!["secret".into()], ..Default::default() };
+        let addr = start(config).await;
+
+        let mut refused = handshake(addr, Some("guess")).await;
+        let (frame_type, result) = next_frame(&mut refused).await;
+        assert_eq!((frame_type, result[0]), (FrameType::AuthResult, 1));
+
+        let mut accepted = handshake(addr, Some("secret")).await;
+        assert_eq!(next_frame(&mut accepted).await, (FrameType::AuthResult, vec![0]));
+    }
+}
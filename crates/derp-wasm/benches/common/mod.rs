@@ -0,0 +1,150 @@
+//! The hot-path operations benchmarked natively by `hot_path` and in the
+//! browser by `tests/wasm_bench.rs`, so both measure the same thing.
+
+use std::hint::black_box;
+
+use derp_wasm::config::DerpConfig;
+use derp_wasm::crypto::CryptoState;
+use derp_wasm::protocol::{Frame, FrameType, ProtocolState, COMPRESSION_LEVEL, FRAME_HEADER_SIZE, PEER_KEY_LEN};
+
+/// From a bare TCP ACK to a jumbo frame.
+pub const PACKET_SIZES: [usize; 5] = [64, 576, 1400, 4096, 9000];
+
+pub const OPERATIONS: [Operation; 8] = [
+    Operation::Encode,
+    Operation::Decode,
+    Operation::Compress,
+    Operation::Decompress,
+    Operation::Encrypt,
+    Operation::Decrypt,
+    Operation::SendPath,
+    Operation::ReceivePath,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    /// Framing a plaintext control payload.
+    Encode,
+    Decode,
+    Compress,
+    Decompress,
+    /// AES-GCM alone, without framing.
+    Encrypt,
+    Decrypt,
+    /// A packet to a sealed, compressed SendToPeer frame, as `sendTo` does.
+    SendPath,
+    /// A received frame parsed, opened and inflated.
+    ReceivePath,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Encode => "encode",
+            Operation::Decode => "decode",
+            Operation::Compress => "compress",
+            Operation::Decompress => "decompress",
+            Operation::Encrypt => "encrypt",
+            Operation::Decrypt => "decrypt",
+            Operation::SendPath => "send_path",
+            Operation::ReceivePath => "receive_path",
+        }
+    }
+}
+
+/// Inputs for one packet size, prepared up front so only the operation
+/// itself is timed.
+pub struct Fixture {
+    protocol: ProtocolState,
+    crypto: CryptoState,
+    packet: Vec<u8>,
+    plain_frame: Vec<u8>,
+    compressed: Vec<u8>,
+    ciphertext: Vec<u8>,
+    sealed_frame: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl Fixture {
+    pub fn new(size: usize) -> Fixture {
+        let config = DerpConfig::builder().compression(true).build().unwrap();
+        let protocol = ProtocolState::with_config(config);
+        let crypto = CryptoState::new().unwrap();
+        let packet = packet(size);
+
+        // As the relay delivers it: RecvFromPeer, the sender's key in place
+        // of the destination's
+        let mut sealed_frame = Vec::new();
+        protocol.encode_peer_frame_into(&crypto, &[7; PEER_KEY_LEN], &packet, &mut sealed_frame).unwrap();
+        sealed_frame[1] = FrameType::RecvFromPeer as u8;
+        Fixture {
+            plain_frame: protocol.encode_frame(FrameType::Ping, &packet),
+            compressed: miniz_oxide::deflate::compress_to_vec(&packet, COMPRESSION_LEVEL),
+            ciphertext: crypto.encrypt(&packet, &[0; FRAME_HEADER_SIZE]).unwrap(),
+            sealed_frame,
+            buffer: Vec::new(),
+            protocol,
+            crypto,
+            packet,
+        }
+    }
+
+    pub fn run(&mut self, operation: Operation) {
+        match operation {
+            Operation::Encode => {
+                let frame = self.protocol.encode_frame(FrameType::Ping, &self.packet);
+                self.protocol.recycle(black_box(frame));
+            }
+            Operation::Decode => {
+                black_box(Frame::parse(&self.plain_frame).unwrap());
+            }
+            Operation::Compress => {
+                black_box(miniz_oxide::deflate::compress_to_vec(&self.packet, COMPRESSION_LEVEL));
+            }
+            Operation::Decompress => {
+                black_box(miniz_oxide::inflate::decompress_to_vec(&self.compressed).unwrap());
+            }
+            Operation::Encrypt => {
+                self.buffer.clear();
+                self.crypto.encrypt_into(&self.packet, &[0; FRAME_HEADER_SIZE], &mut self.buffer).unwrap();
+                black_box(&self.buffer);
+            }
+            Operation::Decrypt => {
+                self.buffer.clear();
+                self.crypto.decrypt_into(&self.ciphertext, &[0; FRAME_HEADER_SIZE], &mut self.buffer).unwrap();
+                black_box(&self.buffer);
+            }
+            Operation::SendPath => {
+                self.protocol.encode_peer_frame_into(&self.crypto, &[7; PEER_KEY_LEN], &self.packet, &mut self.buffer).unwrap();
+                black_box(&self.buffer);
+            }
+            Operation::ReceivePath => {
+                let frame = Frame::parse(&self.sealed_frame).unwrap();
+                self.protocol.decrypt_peer_frame_into(&self.crypto, &frame, &mut self.buffer).unwrap();
+                black_box(&self.buffer);
+            }
+        }
+    }
+
+    pub fn packet_len(&self) -> usize {
+        self.packet.len()
+    }
+}
+
+/// Something like real traffic: headers that repeat between packets and
+/// a body that doesn't compress.
+fn packet(size: usize) -> Vec<u8> {
+    const HEADER: &[u8] = b"GET /assets/app.js HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+    let mut state = 0x2545_f491_u32;
+    (0..size)
+        .map(|i| match i % 256 {
+            offset if offset < HEADER.len() => HEADER[offset],
+            _ => {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            }
+        })
+        .collect()
+}
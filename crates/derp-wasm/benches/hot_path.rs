@@ -0,0 +1,24 @@
+//! Hot-path benchmarks at packet sizes from 64 to 9000 bytes. Run natively
+//! with `cargo bench --bench hot_path`; `tests/wasm_bench.rs` runs the same
+//! operations in a browser.
+
+mod common;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use common::{Fixture, OPERATIONS, PACKET_SIZES};
+
+fn hot_path(c: &mut Criterion) {
+    for operation in OPERATIONS {
+        let mut group = c.benchmark_group(operation.name());
+        for size in PACKET_SIZES {
+            let mut fixture = Fixture::new(size);
+            group.throughput(Throughput::Bytes(fixture.packet_len() as u64));
+            group.bench_function(BenchmarkId::from_parameter(size), |b| b.iter(|| fixture.run(operation)));
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, hot_path);
+criterion_main!(benches);
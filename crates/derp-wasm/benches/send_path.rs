@@ -0,0 +1,49 @@
+//! Send-path throughput: the old allocate-and-copy path against encoding into
+//! a reused buffer. Run with `cargo bench --bench send_path`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use derp_wasm::crypto::CryptoState;
+use derp_wasm::protocol::{FrameType, ProtocolState};
+
+const PACKET_SIZES: [usize; 3] = [64, 576, 1400];
+const ITERATIONS: u32 = 20_000;
+
+fn throughput_mb_s(bytes: usize, elapsed: Duration) -> f64 {
+    (bytes as f64 * ITERATIONS as f64) / elapsed.as_secs_f64() / 1_000_000.0
+}
+
+fn main() {
+    let crypto = CryptoState::new().unwrap();
+    let protocol = ProtocolState::new();
+
+    for size in PACKET_SIZES {
+        let packet = vec![0x42u8; size];
+
+        // Previous behaviour: a fresh frame per packet, then copied into a
+        // Uint8Array and back out again before the socket saw it.
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let frame = protocol.encode_encrypted_frame(&crypto, FrameType::Send, &packet).unwrap();
+            let array = frame.to_vec();
+            black_box(array.to_vec());
+        }
+        let copying = start.elapsed();
+
+        let mut buffer = Vec::new();
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            protocol.encode_encrypted_frame_into(&crypto, FrameType::Send, &packet, &mut buffer).unwrap();
+            black_box(&buffer[..]);
+        }
+        let reused = start.elapsed();
+
+        println!(
+            "{:>5} byte packets: copying {:>8.1} MB/s, reused buffer {:>8.1} MB/s",
+            size,
+            throughput_mb_s(size, copying),
+            throughput_mb_s(size, reused),
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Splits a relay message into frames the way the receive path does, and
+//! re-encodes each one, which must reproduce it exactly.
+#![no_main]
+
+use derp_wasm::protocol::{Frame, ProtocolState};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let protocol = ProtocolState::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Ok(len) = ProtocolState::frame_len(rest) else { break };
+        let (bytes, tail) = rest.split_at(len);
+        if let Ok(frame) = Frame::parse(bytes) {
+            let (frame_type, payload) = ProtocolState::decode_frame(bytes).unwrap();
+            assert_eq!(frame_type, frame.frame_type);
+            assert_eq!(payload, frame.payload);
+            assert_eq!(protocol.frame_header(frame.frame_type, frame.flags, payload.len()), frame.header);
+        }
+        rest = tail;
+    }
+});
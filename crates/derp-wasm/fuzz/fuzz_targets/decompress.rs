@@ -0,0 +1,28 @@
+//! Inflates arbitrary bytes through the compressed-frame receive path. The
+//! input is encrypted first, since only authentic frames reach the inflater.
+#![no_main]
+
+use std::sync::OnceLock;
+
+use derp_wasm::crypto::{CryptoState, CIPHERTEXT_OVERHEAD};
+use derp_wasm::protocol::{FrameType, ProtocolState, FLAG_COMPRESSED, MAX_FRAME_PAYLOAD};
+use libfuzzer_sys::fuzz_target;
+
+static CRYPTO: OnceLock<CryptoState> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let payload_len = data.len() + CIPHERTEXT_OVERHEAD;
+    if payload_len > MAX_FRAME_PAYLOAD {
+        return;
+    }
+
+    let crypto = CRYPTO.get_or_init(|| CryptoState::new().unwrap());
+    let protocol = ProtocolState::new();
+    let header = protocol.frame_header(FrameType::RecvFromPeer, FLAG_COMPRESSED, payload_len);
+    let mut frame = header.to_vec();
+    crypto.encrypt_into(data, &header, &mut frame).unwrap();
+
+    if let Ok(packet) = protocol.decrypt_frame(crypto, &frame) {
+        assert!(packet.len() <= derp_wasm::config::DEFAULT_RECEIVE_BUFFER_SIZE);
+    }
+});
@@ -0,0 +1,44 @@
+//! Feeds ServerInfo payloads to a handshake waiting for one: arbitrary
+//! bytes, and well-formed messages cut short or with a byte changed, which
+//! get past the version check far more often.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use derp_wasm::protocol::{ProtocolState, ServerInfo};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Input {
+    Raw(Vec<u8>),
+    Mutated {
+        name: String,
+        features: Vec<String>,
+        truncate: Option<u16>,
+        replace: Option<(u16, u8)>,
+    },
+}
+
+fuzz_target!(|input: Input| {
+    let payload = match input {
+        Input::Raw(bytes) => bytes,
+        Input::Mutated { name, features, truncate, replace } => {
+            let mut bytes = bincode::serialize(&ServerInfo::new(&name, features)).unwrap();
+            if let Some(len) = truncate {
+                bytes.truncate(len as usize);
+            }
+            if let Some((at, byte)) = replace {
+                if let Some(target) = bytes.get_mut(at as usize) {
+                    *target = byte;
+                }
+            }
+            bytes
+        }
+    };
+
+    let mut protocol = ProtocolState::new();
+    protocol.start_handshake().unwrap();
+    protocol.handle_server_key(&[0; 32]).unwrap();
+    if protocol.handle_server_info(&payload).is_ok() {
+        assert!(protocol.is_connected());
+    }
+});
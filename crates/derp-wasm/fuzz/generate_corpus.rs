@@ -0,0 +1,52 @@
+//! Writes seed inputs for each fuzz target into `corpus/<target>`, built
+//! with the crate's own encoders so fuzzing starts from valid messages.
+//! Run with `cargo run --bin generate_corpus` from this directory.
+
+use std::fs;
+use std::path::Path;
+
+use derp_wasm::config::DerpConfig;
+use derp_wasm::crypto::CryptoState;
+use derp_wasm::protocol::{FrameType, ProtocolState, ServerInfo, FEATURE_BATCHING, FRAME_HEADER_SIZE};
+
+fn write(target: &str, name: &str, data: &[u8]) {
+    let dir = Path::new("corpus").join(target);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(name), data).unwrap();
+}
+
+fn main() {
+    let crypto = CryptoState::new().unwrap();
+    let protocol = ProtocolState::new();
+
+    let mut batch = Vec::new();
+    for (name, frame_type, payload) in [
+        ("server_key", FrameType::ServerKey, &[7u8; 32][..]),
+        ("ping", FrameType::Ping, &[][..]),
+        ("peer_present", FrameType::PeerPresent, &[1u8; 32][..]),
+        ("auth_result", FrameType::AuthResult, &b"\x01bad token"[..]),
+    ] {
+        let frame = protocol.encode_frame(frame_type, payload);
+        write("decode_frame", name, &frame);
+        batch.extend_from_slice(&frame);
+    }
+    let packet = protocol.encode_encrypted_frame(&crypto, FrameType::Send, &[0x45; 1400]).unwrap();
+    write("decode_frame", "send", &packet);
+    batch.extend_from_slice(&packet);
+    write("decode_frame", "batch", &batch);
+
+    for (name, features) in [("plain", vec![]), ("batching", vec![FEATURE_BATCHING.to_string()])] {
+        let info = bincode::serialize(&ServerInfo::new("relay", features)).unwrap();
+        write("server_info", name, &info);
+    }
+
+    // Deflate streams as the send path produces them
+    let config = DerpConfig { compression: true, ..DerpConfig::default() };
+    let compressing = ProtocolState::with_config(config);
+    for (name, size) in [("small", 300), ("mtu", 1500), ("large", 16 * 1024)] {
+        let data: Vec<u8> = (0..size).map(|i| (i % 7) as u8).collect();
+        let frame = compressing.encode_encrypted_frame(&crypto, FrameType::Send, &data).unwrap();
+        let plaintext = crypto.decrypt(&frame[FRAME_HEADER_SIZE..], &frame[..FRAME_HEADER_SIZE]).unwrap();
+        write("decompress", name, &plaintext);
+    }
+}
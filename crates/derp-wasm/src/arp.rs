@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ARP_PACKET_LEN: usize = 28;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// An Ethernet/IPv4 ARP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parses an ARP payload (the Ethernet header already stripped).
+    /// Returns None for anything other than Ethernet/IPv4 ARP.
+    pub fn parse(payload: &[u8]) -> Option<ArpPacket> {
+        if payload.len() < ARP_PACKET_LEN {
+            return None;
+        }
+
+        let htype = u16::from_be_bytes([payload[0], payload[1]]);
+        let ptype = u16::from_be_bytes([payload[2], payload[3]]);
+        if htype != HTYPE_ETHERNET || ptype != PTYPE_IPV4 || payload[4] != 6 || payload[5] != 4 {
+            return None;
+        }
+
+        let mut sender_mac = [0u8; 6];
+        let mut target_mac = [0u8; 6];
+        sender_mac.copy_from_slice(&payload[8..14]);
+        target_mac.copy_from_slice(&payload[18..24]);
+
+        Some(ArpPacket {
+            operation: u16::from_be_bytes([payload[6], payload[7]]),
+            sender_mac,
+            sender_ip: Ipv4Addr::new(payload[14], payload[15], payload[16], payload[17]),
+            target_mac,
+            target_ip: Ipv4Addr::new(payload[24], payload[25], payload[26], payload[27]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_PACKET_LEN] {
+        let mut packet = [0u8; ARP_PACKET_LEN];
+        packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac);
+        packet[14..18].copy_from_slice(&self.sender_ip.octets());
+        packet[18..24].copy_from_slice(&self.target_mac);
+        packet[24..28].copy_from_slice(&self.target_ip.octets());
+        packet
+    }
+
+    /// A gratuitous ARP announces the sender's own address.
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip
+    }
+}
+
+/// Answers guest ARP for the virtual gateway and remembers the addresses the
+/// guest announces.
+pub struct ArpResponder {
+    gateway_ip: Ipv4Addr,
+    gateway_mac: [u8; 6],
+    /// Other addresses the gateway answers for, such as the file server's.
+    aliases: Vec<Ipv4Addr>,
+    table: HashMap<Ipv4Addr, [u8; 6]>,
+}
+
+impl ArpResponder {
+    pub fn new(gateway_ip: Ipv4Addr, gateway_mac: [u8; 6]) -> Self {
+        ArpResponder {
+            gateway_ip,
+            gateway_mac,
+            aliases: Vec::new(),
+            table: HashMap::new(),
+        }
+    }
+
+    /// Also answers for `ip` with the gateway's MAC.
+    pub fn add_alias(&mut self, ip: Ipv4Addr) {
+        if !self.aliases.contains(&ip) {
+            self.aliases.push(ip);
+        }
+    }
+
+    /// Handles an ARP packet from the guest and returns the reply to send
+    /// back, if any. Every packet, gratuitous or not, refreshes the table.
+    pub fn handle(&mut self, payload: &[u8]) -> Option<ArpPacket> {
+        let packet = ArpPacket::parse(payload)?;
+
+        if !packet.sender_ip.is_unspecified() {
+            self.table.insert(packet.sender_ip, packet.sender_mac);
+        }
+
+        let owned = packet.target_ip == self.gateway_ip || self.aliases.contains(&packet.target_ip);
+        if packet.operation != OP_REQUEST || packet.is_gratuitous() || !owned {
+            return None;
+        }
+
+        Some(ArpPacket {
+            operation: OP_REPLY,
+            sender_mac: self.gateway_mac,
+            sender_ip: packet.target_ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        })
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.table.get(&ip).copied()
+    }
+
+    /// Everything the table has learned, in no particular order.
+    pub fn entries(&self) -> Vec<(Ipv4Addr, [u8; 6])> {
+        self.table.iter().map(|(&ip, &mac)| (ip, mac)).collect()
+    }
+
+    pub fn restore(&mut self, entries: &[(Ipv4Addr, [u8; 6])]) {
+        self.table = entries.iter().copied().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const GATEWAY_MAC: [u8; 6] = [0x02, 0x86, 0, 0, 0, 1];
+
+    fn request(sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> [u8; ARP_PACKET_LEN] {
+        ArpPacket {
+            operation: OP_REQUEST,
+            sender_mac: GUEST_MAC,
+            sender_ip,
+            target_mac: [0; 6],
+            target_ip,
+        }.to_bytes()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_answers_for_gateway() {
+        let gateway = Ipv4Addr::new(192, 168, 86, 1);
+        let guest = Ipv4Addr::new(192, 168, 86, 100);
+        let mut responder = ArpResponder::new(gateway, GATEWAY_MAC);
+
+        let reply = responder.handle(&request(guest, gateway)).unwrap();
+        assert_eq!(reply.operation, OP_REPLY);
+        assert_eq!(reply.sender_mac, GATEWAY_MAC);
+        assert_eq!(reply.target_ip, guest);
+        assert_eq!(responder.lookup(guest), Some(GUEST_MAC));
+
+        // Other addresses aren't ours to answer for
+        assert!(responder.handle(&request(guest, Ipv4Addr::new(192, 168, 86, 7))).is_none());
+
+        // Unless they're an alias, answered with the gateway's MAC
+        responder.add_alias(Ipv4Addr::new(192, 168, 86, 7));
+        let reply = responder.handle(&request(guest, Ipv4Addr::new(192, 168, 86, 7))).unwrap();
+        assert_eq!((reply.sender_mac, reply.sender_ip), (GATEWAY_MAC, Ipv4Addr::new(192, 168, 86, 7)));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_gratuitous_arp_is_learned() {
+        let mut responder = ArpResponder::new(Ipv4Addr::new(192, 168, 86, 1), GATEWAY_MAC);
+        let guest = Ipv4Addr::new(192, 168, 86, 50);
+
+        assert!(responder.handle(&request(guest, guest)).is_none());
+        assert_eq!(responder.lookup(guest), Some(GUEST_MAC));
+        assert!(ArpPacket::parse(&[0u8; 10]).is_none());
+    }
+}
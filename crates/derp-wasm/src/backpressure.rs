@@ -0,0 +1,304 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use crate::bandwidth::BandwidthEstimator;
+use crate::clock::Clock;
+use crate::config::{BackpressurePolicy, DerpConfig};
+use crate::error::DerpResult;
+use crate::events::{EventDispatcher, EventKind};
+use crate::network::StatsCounters;
+use crate::priority::Priority;
+use crate::shared::Shared;
+use crate::timer;
+use crate::transport::Transport;
+
+/// WebSockets have no drain event, so a congested socket is polled.
+pub const DRAIN_POLL_MS: i32 = 50;
+
+/// Payload of the "backpressure" event, emitted when the socket starts and
+/// stops holding back packets.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct BackpressureEvent {
+    pub congested: bool,
+    /// The socket's `bufferedAmount` when the condition changed.
+    pub buffered_amount: usize,
+    pub queued_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Send,
+    Queued,
+    Dropped,
+}
+
+/// The bookkeeping behind `SendGate`, apart from the socket and timers.
+pub struct Backlog {
+    policy: BackpressurePolicy,
+    high_watermark: usize,
+    max_queued_bytes: usize,
+    /// One per `Priority`, though control frames are never queued.
+    queues: [VecDeque<Vec<u8>>; Priority::COUNT],
+    queued_bytes: usize,
+    congested: bool,
+    poll_timer: Option<i32>,
+}
+
+impl Backlog {
+    pub fn new(policy: BackpressurePolicy, high_watermark: usize, max_queued_bytes: usize) -> Self {
+        Backlog {
+            policy,
+            high_watermark,
+            max_queued_bytes,
+            queues: Default::default(),
+            queued_bytes: 0,
+            congested: false,
+            poll_timer: None,
+        }
+    }
+
+    /// Decides what to do with `message` while the socket holds `buffered`
+    /// unsent bytes, keeping it if it's queued.
+    pub fn offer(&mut self, buffered: usize, message: &[u8], priority: Priority) -> Decision {
+        if priority == Priority::Control {
+            return Decision::Send;
+        }
+        // Once congested, everything waits its turn behind the queue
+        if !self.congested && buffered <= self.high_watermark {
+            return Decision::Send;
+        }
+        self.congested = true;
+
+        if self.policy == BackpressurePolicy::Queue && self.queued_bytes + message.len() <= self.max_queued_bytes {
+            self.queued_bytes += message.len();
+            self.queues[priority as usize].push_back(message.to_vec());
+            Decision::Queued
+        } else {
+            Decision::Dropped
+        }
+    }
+
+    /// Once the socket is down to half the watermark, returns the queued
+    /// messages that fit under it again, higher priorities first.
+    /// Congestion ends with the queues.
+    pub fn drain(&mut self, buffered: usize) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        if buffered > self.high_watermark / 2 {
+            return ready;
+        }
+
+        let mut room = self.high_watermark - buffered;
+        while let Some(queue) = self.queues.iter_mut().find(|queue| !queue.is_empty()) {
+            let size = queue.front().map_or(0, Vec::len);
+            // An empty socket takes even a message larger than the watermark
+            if size > room && !(ready.is_empty() && buffered == 0) {
+                break;
+            }
+            room = room.saturating_sub(size);
+            self.queued_bytes -= size;
+            ready.extend(queue.pop_front());
+        }
+        self.congested = self.queues.iter().any(|queue| !queue.is_empty());
+        ready
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.queues.iter_mut().for_each(VecDeque::clear);
+        self.queued_bytes = 0;
+        self.congested = false;
+    }
+}
+
+/// Stands between `NetworkState` and the transport so a slow relay link
+/// can't buffer without bound: past `sendHighWatermark` unsent bytes,
+/// packets are queued or dropped per `backpressure` until the socket drains.
+/// Watching the socket drain also gives the link's bandwidth.
+#[derive(Clone)]
+pub struct SendGate {
+    backlog: Shared<Backlog>,
+    bandwidth: Shared<BandwidthEstimator>,
+    clock: Arc<dyn Clock>,
+    events: EventDispatcher,
+    stats: Arc<StatsCounters>,
+}
+
+impl SendGate {
+    pub fn new(config: &DerpConfig, events: EventDispatcher, stats: Arc<StatsCounters>, clock: Arc<dyn Clock>) -> Self {
+        let backlog = Backlog::new(config.backpressure, config.send_high_watermark, config.send_queue_bytes);
+        SendGate {
+            backlog: Shared::new(backlog),
+            bandwidth: Shared::default(),
+            clock,
+            events,
+            stats,
+        }
+    }
+
+    pub fn send(&self, transport: &Transport, message: &[u8], priority: Priority) -> DerpResult<()> {
+        let buffered = transport.buffered_amount();
+        let (decision, became_congested, queued_bytes) = {
+            let mut backlog = self.backlog.lock();
+            let was_congested = backlog.is_congested();
+            let decision = backlog.offer(buffered, message, priority);
+            (decision, !was_congested && backlog.is_congested(), backlog.queued_bytes())
+        };
+
+        if became_congested {
+            log::warn!("Relay link congested with {} bytes unsent", buffered);
+            self.stats.record_congestion();
+            self.announce(true, buffered, queued_bytes);
+            self.poll(transport.clone());
+        }
+        match decision {
+            Decision::Send => {
+                self.bandwidth.lock().on_send(self.clock.now_ms(), buffered, message.len());
+                transport.send(message)
+            }
+            Decision::Queued => Ok(()),
+            Decision::Dropped => {
+                self.stats.record_backpressure_drop();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.backlog.lock().is_congested()
+    }
+
+    /// Packets queued behind the congested socket, and their bytes.
+    pub fn queued(&self) -> (usize, usize) {
+        let backlog = self.backlog.lock();
+        (backlog.queued_packets(), backlog.queued_bytes())
+    }
+
+    /// The link's estimated upload bandwidth in kilobits per second, once
+    /// it's been busy long enough to tell.
+    pub fn estimated_kbps(&self) -> Option<u32> {
+        self.bandwidth.lock().estimate_kbps()
+    }
+
+    /// How large a batch of frames may grow, given the link's bandwidth.
+    pub fn batch_limit(&self, max: usize) -> usize {
+        self.bandwidth.lock().batch_limit(max)
+    }
+
+    /// Forgets anything queued, for when the transport goes away.
+    pub fn clear(&self) {
+        self.bandwidth.lock().reset();
+        let mut backlog = self.backlog.lock();
+        backlog.clear();
+        if let Some(handle) = backlog.poll_timer.take() {
+            timer::clear_timeout(handle);
+        }
+    }
+
+    fn poll(&self, transport: Transport) {
+        let gate = self.clone();
+        let callback = Closure::once_into_js(move || gate.resume(transport));
+        let handle = timer::set_timeout(callback.unchecked_ref(), DRAIN_POLL_MS);
+        self.backlog.lock().poll_timer = Some(handle);
+    }
+
+    fn resume(&self, transport: Transport) {
+        let buffered = transport.buffered_amount();
+        let (ready, congested) = {
+            let mut backlog = self.backlog.lock();
+            backlog.poll_timer = None;
+            let ready = backlog.drain(buffered);
+            (ready, backlog.is_congested())
+        };
+        let ready_bytes = ready.iter().map(Vec::len).sum();
+        self.bandwidth.lock().on_send(self.clock.now_ms(), buffered, ready_bytes);
+
+        for message in ready {
+            if let Err(e) = transport.send(&message) {
+                self.events.emit(EventKind::Error, &e.into());
+                break;
+            }
+        }
+
+        if congested {
+            self.poll(transport);
+        } else {
+            log::info!("Relay link drained");
+            self.announce(false, transport.buffered_amount(), 0);
+        }
+    }
+
+    fn announce(&self, congested: bool, buffered_amount: usize, queued_bytes: usize) {
+        self.events.emit_serialized(EventKind::Backpressure, &BackpressureEvent {
+            congested,
+            buffered_amount,
+            queued_bytes,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_queue_holds_messages_until_drained() {
+        let mut backlog = Backlog::new(BackpressurePolicy::Queue, 100, 50);
+        assert_eq!(backlog.offer(100, &[0; 30], Priority::Bulk), Decision::Send);
+        assert_eq!(backlog.offer(101, &[1; 30], Priority::Bulk), Decision::Queued);
+        // Below the watermark again, but still behind the queue
+        assert_eq!(backlog.offer(0, &[2; 20], Priority::Bulk), Decision::Queued);
+        assert_eq!(backlog.offer(0, &[3; 1], Priority::Bulk), Decision::Dropped);
+        assert_eq!(backlog.queued_bytes(), 50);
+
+        assert!(backlog.drain(60).is_empty());
+        let ready = backlog.drain(60 - 30);
+        assert_eq!(ready, vec![vec![1; 30], vec![2; 20]]);
+        assert!(!backlog.is_congested());
+        assert_eq!(backlog.offer(0, &[4; 10], Priority::Bulk), Decision::Send);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_drop_policy_discards_while_congested() {
+        let mut backlog = Backlog::new(BackpressurePolicy::Drop, 100, 1000);
+        assert_eq!(backlog.offer(200, &[0; 10], Priority::Bulk), Decision::Dropped);
+        assert!(backlog.is_congested());
+        assert!(backlog.drain(80).is_empty());
+        assert!(backlog.is_congested());
+
+        assert!(backlog.drain(10).is_empty());
+        assert!(!backlog.is_congested());
+        assert_eq!(backlog.offer(10, &[0; 10], Priority::Bulk), Decision::Send);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_interactive_overtakes_bulk() {
+        let mut backlog = Backlog::new(BackpressurePolicy::Queue, 100, 1000);
+        assert_eq!(backlog.offer(200, &[0; 60], Priority::Bulk), Decision::Queued);
+        assert_eq!(backlog.offer(200, &[1; 60], Priority::Bulk), Decision::Queued);
+        assert_eq!(backlog.offer(200, &[2; 10], Priority::Interactive), Decision::Queued);
+        assert_eq!(backlog.offer(200, &[3; 5], Priority::Control), Decision::Send);
+
+        // The interactive packet goes first; the second bulk one waits for room
+        assert_eq!(backlog.drain(20), vec![vec![2; 10], vec![0; 60]]);
+        assert_eq!(backlog.drain(0), vec![vec![1; 60]]);
+        assert!(!backlog.is_congested());
+    }
+}
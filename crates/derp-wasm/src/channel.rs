@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use js_sys::{Function, Uint8Array};
+use crate::error::{DerpError, DerpResult};
+use crate::network::NetworkState;
+use crate::shared::Shared;
+
+/// IEEE 802 local experimental EtherType, marking a payload as belonging to
+/// a channel. Like a VLAN tag, it can't be confused with IP, which starts
+/// with its version nibble.
+pub const ETHERTYPE_CHANNEL: u16 = 0x88B5;
+const HEADER_LEN: usize = 4;
+
+/// Untagged payloads: the VMs' traffic.
+pub const DEFAULT_CHANNEL: u16 = 0;
+
+/// Prefixes a relay payload with the header for `channel`.
+pub fn tag(channel: u16, payload: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(HEADER_LEN + payload.len());
+    tagged.extend_from_slice(&ETHERTYPE_CHANNEL.to_be_bytes());
+    tagged.extend_from_slice(&channel.to_be_bytes());
+    tagged.extend_from_slice(payload);
+    tagged
+}
+
+/// The channel a received payload was sent on and what it carries, or None
+/// for untagged VM traffic.
+pub fn split(payload: &[u8]) -> Option<(u16, &[u8])> {
+    if payload.len() < HEADER_LEN || payload[..2] != ETHERTYPE_CHANNEL.to_be_bytes() {
+        return None;
+    }
+    Some((u16::from_be_bytes([payload[2], payload[3]]), &payload[HEADER_LEN..]))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct ChannelStats {
+    pub channel: u16,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Received while no listener was set.
+    pub unheard: u64,
+}
+
+struct ChannelState {
+    stats: ChannelStats,
+    listener: Option<Function>,
+}
+
+/// The channels open on a connection, shared with the socket callbacks.
+#[derive(Clone, Default)]
+pub struct Channels {
+    channels: Arc<Mutex<HashMap<u16, ChannelState>>>,
+}
+
+impl Channels {
+    pub fn open(&self, channel: u16) -> DerpResult<()> {
+        if channel == DEFAULT_CHANNEL {
+            return Err(DerpError::InvalidState("Channel 0 carries VM traffic".into()));
+        }
+        let mut channels = self.channels.lock()?;
+        if channels.contains_key(&channel) {
+            return Err(DerpError::InvalidState(format!("Channel {} is already open", channel)));
+        }
+        channels.insert(channel, ChannelState {
+            stats: ChannelStats { channel, ..Default::default() },
+            listener: None,
+        });
+        Ok(())
+    }
+
+    pub fn close(&self, channel: u16) -> bool {
+        self.channels.lock().unwrap().remove(&channel).is_some()
+    }
+
+    pub fn is_open(&self, channel: u16) -> bool {
+        self.channels.lock().unwrap().contains_key(&channel)
+    }
+
+    pub fn set_listener(&self, channel: u16, listener: Option<Function>) {
+        if let Some(state) = self.channels.lock().unwrap().get_mut(&channel) {
+            state.listener = listener;
+        }
+    }
+
+    pub fn record_sent(&self, channel: u16, bytes: usize) {
+        if let Some(state) = self.channels.lock().unwrap().get_mut(&channel) {
+            state.stats.packets_sent += 1;
+            state.stats.bytes_sent += bytes as u64;
+        }
+    }
+
+    /// Hands `payload` to the channel's listener once the current task is
+    /// done, so it never runs under the caller's locks. Returns false if the
+    /// channel isn't open here.
+    pub fn deliver(&self, channel: u16, payload: &[u8]) -> bool {
+        let listener = {
+            let mut channels = self.channels.lock().unwrap();
+            let Some(state) = channels.get_mut(&channel) else { return false };
+            state.stats.packets_received += 1;
+            state.stats.bytes_received += payload.len() as u64;
+            if state.listener.is_none() {
+                state.stats.unheard += 1;
+            }
+            state.listener.clone()
+        };
+
+        if let Some(listener) = listener {
+            let message = Uint8Array::from(payload);
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Err(e) = listener.call1(&JsValue::NULL, &message) {
+                    log::warn!("Channel {} listener threw: {:?}", channel, e);
+                }
+            });
+        }
+        true
+    }
+
+    pub fn stats(&self, channel: u16) -> Option<ChannelStats> {
+        self.channels.lock().unwrap().get(&channel).map(|state| state.stats.clone())
+    }
+}
+
+/// One stream sharing the relay connection with the VMs and other
+/// channels, returned by `DerpNetwork.openChannel`. Both ends open the same
+/// id; messages for a channel a peer hasn't opened are dropped there.
+#[wasm_bindgen]
+pub struct Channel {
+    id: u16,
+    network: Shared<NetworkState>,
+    channels: Channels,
+}
+
+#[wasm_bindgen]
+impl Channel {
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), JsValue> {
+        Ok(NetworkState::lock(&self.network).send_on_channel(self.id, data)?)
+    }
+
+    /// Called with each message as a Uint8Array; null stops listening.
+    #[wasm_bindgen(setter)]
+    pub fn set_onmessage(&self, listener: Option<Function>) {
+        self.channels.set_listener(self.id, listener);
+    }
+
+    #[wasm_bindgen(js_name = getStats)]
+    pub fn get_stats(&self) -> ChannelStats {
+        self.channels.stats(self.id).unwrap_or(ChannelStats { channel: self.id, ..Default::default() })
+    }
+
+    /// Frees the id; later messages on it are dropped.
+    pub fn close(&self) {
+        self.channels.close(self.id);
+    }
+}
+
+impl Channel {
+    pub fn open(network: Shared<NetworkState>, id: u16) -> DerpResult<Channel> {
+        let channels = NetworkState::lock(&network).channels();
+        channels.open(id)?;
+        Ok(Channel { id, network, channels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_tag_roundtrip() {
+        let tagged = tag(7, b"hello");
+        assert_eq!(split(&tagged), Some((7, &b"hello"[..])));
+        assert_eq!(split(&[0x45, 0, 0, 20]), None);
+        assert_eq!(split(&[0x88, 0xB5, 0]), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_channels_count_their_traffic() {
+        let channels = Channels::default();
+        assert!(channels.open(DEFAULT_CHANNEL).is_err());
+        channels.open(3).unwrap();
+        assert!(channels.open(3).is_err());
+
+        channels.record_sent(3, 10);
+        assert!(channels.deliver(3, b"abc"));
+        assert!(!channels.deliver(4, b"abc"));
+
+        let stats = channels.stats(3).unwrap();
+        assert_eq!((stats.packets_sent, stats.bytes_sent), (1, 10));
+        assert_eq!((stats.packets_received, stats.bytes_received, stats.unheard), (1, 3, 1));
+        assert!(channels.close(3));
+        assert!(!channels.is_open(3));
+    }
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use tsify::Tsify;
+use crate::events::{EventDispatcher, EventKind};
+use crate::shared::Shared;
+
+pub use derp_core::driver::ConnectionState;
+
+/// Payload of the "state" event.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct StateChangeEvent {
+    pub state: ConnectionState,
+    pub previous: ConnectionState,
+}
+
+/// The current `ConnectionState`, shared with the socket callbacks, which
+/// announces each change.
+#[derive(Clone)]
+pub struct ConnectionStatus {
+    state: Shared<ConnectionState>,
+    events: EventDispatcher,
+}
+
+impl ConnectionStatus {
+    pub fn new(events: EventDispatcher) -> Self {
+        ConnectionStatus {
+            state: Shared::new(ConnectionState::Idle),
+            events,
+        }
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        *self.state.lock()
+    }
+
+    /// Moves to `state`, emitting "state" unless already there.
+    pub fn set(&self, state: ConnectionState) {
+        let previous = std::mem::replace(&mut *self.state.lock(), state);
+        if previous != state {
+            log::debug!("Connection state {:?} -> {:?}", previous, state);
+            self.events.emit_serialized(EventKind::State, &StateChangeEvent { state, previous });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_only_changes_are_announced() {
+        let events = EventDispatcher::new();
+        let mut changes = events.subscribe(EventKind::State, 4);
+        let status = ConnectionStatus::new(events);
+        assert_eq!(status.get(), ConnectionState::Idle);
+
+        status.set(ConnectionState::Connecting);
+        status.set(ConnectionState::Connecting);
+        status.set(ConnectionState::Handshaking);
+        assert_eq!(status.get(), ConnectionState::Handshaking);
+
+        let change = changes.try_recv().unwrap();
+        let state = js_sys::Reflect::get(&change, &"state".into()).unwrap();
+        let previous = js_sys::Reflect::get(&change, &"previous".into()).unwrap();
+        assert_eq!(state.as_string().as_deref(), Some("connecting"));
+        assert_eq!(previous.as_string().as_deref(), Some("idle"));
+        assert!(changes.try_recv().is_ok());
+        assert!(changes.try_recv().unwrap_err().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use js_sys::Promise;
+use web_sys::{Request, RequestCache, RequestInit, RequestMode, Response};
+use crate::derpmap::DerpMap;
+use crate::error::{DerpError, DerpResult};
+use crate::events::{EventDispatcher, EventKind};
+use crate::network::NetworkState;
+use crate::protocol::{hex_decode_key, PeerKey};
+use crate::shared::WeakShared;
+use crate::timer;
+
+const FETCH_TIMEOUT_MS: i32 = 10_000;
+
+/// Peers are named `<name>.derp` in the guests' DNS.
+pub const PEER_DOMAIN: &str = "derp";
+
+// fetch is a global in both windows and workers, like the timer functions.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(request: &Request) -> Promise;
+}
+
+/// What a coordination server at `DerpConfig.controlUrl` serves: the relays
+/// to choose between and the peers on the network, with their addresses.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct ControlDocument {
+    /// Replaces `DerpConfig.derpMap` when present.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub derp_map: Option<DerpMap>,
+    #[serde(default)]
+    #[tsify(optional)]
+    pub peers: Vec<ControlPeer>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct ControlPeer {
+    /// Hex, as in "peer-present" events.
+    pub public_key: String,
+    /// Virtual IPv4 or IPv6 addresses assigned to the peer. Guest packets
+    /// for them go to this peer alone rather than every peer.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub addresses: Vec<String>,
+    /// A single DNS label; guests resolve `<name>.derp` to `addresses`.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub name: String,
+}
+
+/// Payload of the "control" event, sent after each update is applied.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct ControlEvent {
+    pub peers: u32,
+    pub regions: u32,
+}
+
+impl ControlDocument {
+    pub fn from_json(json: &str) -> DerpResult<ControlDocument> {
+        let value = js_sys::JSON::parse(json)
+            .map_err(|e| DerpError::SerializationError(format!("Invalid control JSON: {:?}", e)))?;
+        serde_wasm_bindgen::from_value(value)
+            .map_err(|e| DerpError::SerializationError(format!("Invalid control document: {}", e)))
+    }
+
+    /// Each assigned address and the peer it belongs to. A malformed key or
+    /// address fails the whole document, so a half-applied update can't
+    /// route packets to the wrong peer.
+    pub fn routes(&self) -> DerpResult<HashMap<IpAddr, PeerKey>> {
+        let mut routes = HashMap::new();
+        for peer in &self.peers {
+            let key = hex_decode_key(&peer.public_key)?;
+            for address in &peer.addresses {
+                let address: IpAddr = address.parse()
+                    .map_err(|_| DerpError::InvalidState(format!("Invalid address for peer {}: {}", peer.public_key, address)))?;
+                if matches!(routes.insert(address, key), Some(other) if other != key) {
+                    return Err(DerpError::InvalidState(format!("Address {} is assigned twice", address)));
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Each named peer's DNS name in `PEER_DOMAIN`, lowercased, and its
+    /// addresses. Unnamed peers are left out; names that aren't a single
+    /// label, or that two peers share, fail the document.
+    pub fn names(&self) -> DerpResult<HashMap<String, Vec<IpAddr>>> {
+        let mut names = HashMap::new();
+        for peer in self.peers.iter().filter(|peer| !peer.name.is_empty()) {
+            if !is_valid_label(&peer.name) {
+                return Err(DerpError::InvalidState(format!("Invalid peer name: {:?}", peer.name)));
+            }
+            let addresses = peer.addresses.iter()
+                .map(|address| address.parse().map_err(|_| DerpError::InvalidState(format!("Invalid address for peer {}: {}", peer.name, address))))
+                .collect::<DerpResult<Vec<IpAddr>>>()?;
+            let name = format!("{}.{}", peer.name.to_ascii_lowercase(), PEER_DOMAIN);
+            if names.insert(name, addresses).is_some() {
+                return Err(DerpError::InvalidState(format!("Peer name {:?} is used twice", peer.name)));
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Letters, digits and inner hyphens, as RFC 1123 allows in a host name.
+fn is_valid_label(label: &str) -> bool {
+    label.len() <= 63
+        && label.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+/// Fetches the document now and then every `interval_ms`, or only once if
+/// that's zero, applying each to `network` until it's dropped. Failed
+/// fetches keep the last document and are reported as "error" events.
+pub fn start(network: WeakShared<NetworkState>, events: EventDispatcher, url: String, interval_ms: u32) {
+    wasm_bindgen_futures::spawn_local(async move {
+        loop {
+            let result = match fetch(&url).await {
+                Ok(document) => match network.upgrade() {
+                    Some(network) => NetworkState::lock(&network).apply_control(&document),
+                    None => break,
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(event) => events.emit_serialized(EventKind::Control, &event),
+                Err(e) => {
+                    log::warn!("Control update from {} failed: {}", url, e);
+                    events.emit(EventKind::Error, &e.into());
+                }
+            }
+
+            if interval_ms == 0 {
+                break;
+            }
+            timer::sleep(interval_ms as i32).await;
+            if network.upgrade().is_none() {
+                break;
+            }
+        }
+    });
+}
+
+async fn fetch(url: &str) -> DerpResult<ControlDocument> {
+    let init = RequestInit::new();
+    init.set_method("GET");
+    init.set_mode(RequestMode::Cors);
+    init.set_cache(RequestCache::NoStore);
+    let request = Request::new_with_str_and_init(url, &init)
+        .map_err(|e| DerpError::InvalidState(format!("Invalid control URL {}: {:?}", url, e)))?;
+
+    let timeout = Promise::new(&mut |resolve, _| {
+        timer::set_timeout(&resolve, FETCH_TIMEOUT_MS);
+    });
+    let winner = JsFuture::from(Promise::race(&js_sys::Array::of2(&fetch_with_request(&request), &timeout))).await
+        .map_err(|e| DerpError::WebSocketError(format!("Control fetch failed: {:?}", e)))?;
+    let response: Response = winner.dyn_into()
+        .map_err(|_| DerpError::Timeout(format!("No answer within {} ms", FETCH_TIMEOUT_MS)))?;
+    if !response.ok() {
+        return Err(DerpError::WebSocketError(format!("Control server answered {}", response.status())));
+    }
+
+    let text = response.text()
+        .map_err(|e| DerpError::SerializationError(format!("Unreadable control response: {:?}", e)))?;
+    let text = JsFuture::from(text).await
+        .map_err(|e| DerpError::SerializationError(format!("Unreadable control response: {:?}", e)))?;
+    ControlDocument::from_json(&text.as_string().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const ALICE: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const BOB: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    #[wasm_bindgen_test]
+    fn test_parses_document() {
+        let json = format!(r#"{{
+            "derpMap": {{"Regions": {{"1": {{"RegionID": 1, "Nodes": [{{"Name": "1a", "RegionID": 1, "HostName": "derp1.example.com"}}]}}}}}},
+            "peers": [
+                {{"publicKey": "{}", "addresses": ["100.64.0.1", "fd7a:115c:a1e0::1"], "name": "alice"}},
+                {{"publicKey": "{}"}}
+            ]
+        }}"#, ALICE, BOB);
+        let document = ControlDocument::from_json(&json).unwrap();
+        assert_eq!(document.derp_map.unwrap().regions().len(), 1);
+        assert_eq!(document.peers[0].name, "alice");
+        assert!(document.peers[1].addresses.is_empty());
+
+        assert!(ControlDocument::from_json("3").is_err());
+        assert!(ControlDocument::from_json(r#"{"peers": [{"addresses": []}]}"#).is_err());
+        assert_eq!(ControlDocument::from_json("{}").unwrap(), ControlDocument::default());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_routes() {
+        let peer = |key: &str, addresses: &[&str]| ControlPeer {
+            public_key: key.into(),
+            addresses: addresses.iter().map(|address| address.to_string()).collect(),
+            name: String::new(),
+        };
+        let document = ControlDocument { derp_map: None, peers: vec![peer(ALICE, &["100.64.0.1"]), peer(BOB, &["100.64.0.2"])] };
+        let routes = document.routes().unwrap();
+        assert_eq!(routes[&"100.64.0.2".parse::<IpAddr>().unwrap()], [2u8; 32]);
+
+        let clash = ControlDocument { derp_map: None, peers: vec![peer(ALICE, &["100.64.0.1"]), peer(BOB, &["100.64.0.1"])] };
+        assert!(clash.routes().is_err());
+        let invalid = ControlDocument { derp_map: None, peers: vec![peer(ALICE, &["100.64.0"])] };
+        assert!(invalid.routes().is_err());
+        assert!(ControlDocument { derp_map: None, peers: vec![peer("beef", &[])] }.routes().is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_names() {
+        let peer = |name: &str, address: &str| ControlPeer {
+            public_key: ALICE.into(),
+            addresses: vec![address.into()],
+            name: name.into(),
+        };
+        let document = ControlDocument { derp_map: None, peers: vec![peer("Alice", "100.64.0.1"), peer("", "100.64.0.2")] };
+        let names = document.names().unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names["alice.derp"], vec!["100.64.0.1".parse::<IpAddr>().unwrap()]);
+
+        for invalid in ["alice.lab", "-alice", "al ice"] {
+            assert!(ControlDocument { derp_map: None, peers: vec![peer(invalid, "100.64.0.1")] }.names().is_err());
+        }
+        let twice = ControlDocument { derp_map: None, peers: vec![peer("alice", "100.64.0.1"), peer("ALICE", "100.64.0.2")] };
+        assert!(twice.names().is_err());
+    }
+}
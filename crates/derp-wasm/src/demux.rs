@@ -0,0 +1,148 @@
+use wasm_bindgen::prelude::*;
+use crate::ethernet::{self, Cast};
+use crate::switch::{Port, SwitchHandle};
+use crate::vm_network::NicHandle;
+
+/// 802.1Q tag protocol identifier.
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+const VLAN_ID_MASK: u16 = 0x0FFF;
+const TAG_LEN: usize = 4;
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Prefixes a relay payload with the 802.1Q tag for `vlan`.
+pub fn tag(vlan: u16, packet: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(TAG_LEN + packet.len());
+    tagged.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    tagged.extend_from_slice(&(vlan & VLAN_ID_MASK).to_be_bytes());
+    tagged.extend_from_slice(packet);
+    tagged
+}
+
+/// What a payload received from the relay is addressed to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inbound<'a> {
+    /// An IP packet behind an 802.1Q tag.
+    Vlan(u16, &'a [u8]),
+    /// A whole Ethernet frame, sent by a peer that bridges at layer 2.
+    Ethernet([u8; 6], &'a [u8]),
+    /// A bare IP packet, as a single-NIC peer sends.
+    Ip(&'a [u8]),
+}
+
+impl<'a> Inbound<'a> {
+    /// IP is recognized by its version nibble, so a frame whose destination
+    /// MAC starts with 4 or 6 reads as IP.
+    pub fn classify(payload: &'a [u8]) -> Option<Inbound<'a>> {
+        match payload.first()? >> 4 {
+            4 | 6 => return Some(Inbound::Ip(payload)),
+            _ => {}
+        }
+        if payload.len() >= TAG_LEN && payload[..2] == ETHERTYPE_VLAN.to_be_bytes() {
+            let vlan = u16::from_be_bytes([payload[2], payload[3]]) & VLAN_ID_MASK;
+            return Some(Inbound::Vlan(vlan, &payload[TAG_LEN..]));
+        }
+        if payload.len() >= ETHERNET_HEADER_LEN {
+            let mut dst = [0u8; 6];
+            dst.copy_from_slice(&payload[..6]);
+            return Some(Inbound::Ethernet(dst, payload));
+        }
+        None
+    }
+}
+
+/// Hands packets from one relay connection to the NICs sharing it: tagged
+/// packets by VLAN, Ethernet frames by destination MAC (every untagged NIC
+/// for broadcast and multicast, and promiscuous ones for everything), and
+/// bare IP to the first NIC without a VLAN. With a switch uplinked to the connection, the switch takes every
+/// untagged Ethernet frame instead.
+#[derive(Default)]
+pub struct Demux {
+    nics: Vec<NicHandle>,
+    uplink: Option<SwitchHandle>,
+}
+
+impl Demux {
+    pub fn add(&mut self, nic: NicHandle) {
+        self.nics.retain(NicHandle::is_alive);
+        self.nics.push(nic);
+    }
+
+    pub fn set_uplink(&mut self, switch: SwitchHandle) {
+        self.uplink = Some(switch);
+    }
+
+    /// Trace summaries kept by the attached NICs, and how many they hold.
+    pub fn trace_usage(&self) -> (usize, usize) {
+        self.nics.iter()
+            .map(NicHandle::trace_usage)
+            .fold((0, 0), |(frames, capacity), (more, room)| (frames + more, capacity + room))
+    }
+
+    /// Delivers `payload` to the NIC it's addressed to. Returns false if
+    /// there is none.
+    pub fn route(&self, payload: &[u8]) -> Result<bool, JsValue> {
+        let Some(inbound) = Inbound::classify(payload) else { return Ok(false) };
+        if let (Inbound::Ethernet(_, frame), Some(switch)) = (&inbound, &self.uplink) {
+            switch.forward(Port::Uplink, frame)?;
+            return Ok(true);
+        }
+
+        let mut nics = self.nics.iter().filter(|nic| nic.is_alive());
+        match inbound {
+            Inbound::Vlan(vlan, packet) => {
+                let Some(nic) = nics.find(|nic| nic.vlan() == Some(vlan)) else { return Ok(false) };
+                // Broadcasts travel as whole frames behind the tag
+                match Inbound::classify(packet) {
+                    Some(Inbound::Ethernet(..)) => nic.receive_frame(packet)?,
+                    _ => nic.receive_packet(packet)?,
+                }
+            }
+            Inbound::Ip(packet) => {
+                let Some(nic) = nics.find(|nic| nic.vlan().is_none()) else { return Ok(false) };
+                nic.receive_packet(packet)?;
+            }
+            Inbound::Ethernet(dst, frame) if ethernet::cast(&dst) != Cast::Unicast => {
+                let mut delivered = false;
+                for nic in nics.filter(|nic| nic.vlan().is_none()) {
+                    nic.receive_frame(frame)?;
+                    delivered = true;
+                }
+                return Ok(delivered);
+            }
+            Inbound::Ethernet(dst, frame) => {
+                let mut delivered = false;
+                for nic in nics.filter(|nic| nic.mac() == dst || (nic.vlan().is_none() && nic.is_promiscuous())) {
+                    nic.receive_frame(frame)?;
+                    delivered = true;
+                }
+                return Ok(delivered);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_tag_roundtrip() {
+        let packet = [0x45, 0, 0, 20];
+        let tagged = tag(42, &packet);
+        assert_eq!(Inbound::classify(&tagged), Some(Inbound::Vlan(42, &packet[..])));
+        assert_eq!(Inbound::classify(&packet), Some(Inbound::Ip(&packet[..])));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_classify_ethernet() {
+        let mut frame = vec![0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        frame.extend_from_slice(&[0x02, 0x86, 0, 0, 0, 1, 0x08, 0x00]);
+        assert_eq!(Inbound::classify(&frame), Some(Inbound::Ethernet([0x52, 0x54, 0x00, 0x12, 0x34, 0x56], &frame[..])));
+        assert_eq!(Inbound::classify(&frame[..8]), None);
+        assert_eq!(Inbound::classify(&[]), None);
+    }
+}
@@ -0,0 +1,286 @@
+use std::net::Ipv4Addr;
+use crate::config::DerpConfig;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const FILE_OFFSET: usize = 108;
+const FILE_LEN: usize = 128;
+const OPTIONS_OFFSET: usize = 240;
+const LEASE_SECS: u32 = 86_400;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_INTERFACE_MTU: u8 = 26;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_VENDOR_CLASS: u8 = 60;
+const OPT_VENDOR_SPECIFIC: u8 = 43;
+const OPT_TFTP_SERVER: u8 = 66;
+const OPT_BOOT_FILE: u8 = 67;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const PXE_CLIENT: &[u8] = b"PXEClient";
+/// PXE_DISCOVERY_CONTROL = 8: skip boot server discovery and fetch the boot
+/// file named in the reply.
+const PXE_VENDOR_OPTIONS: [u8; 4] = [6, 1, 8, OPT_END];
+
+/// A BOOTP reply and the MAC address it should be delivered to.
+pub struct DhcpReply {
+    pub client_mac: [u8; 6],
+    pub payload: Vec<u8>,
+}
+
+/// Single-lease DHCP server: the guest always gets the configured address.
+/// With a boot file set, replies also point PXE clients at the gateway's
+/// TFTP server.
+pub struct DhcpServer {
+    server_ip: Ipv4Addr,
+    lease_ip: Ipv4Addr,
+    netmask: Ipv4Addr,
+    dns_servers: Vec<Ipv4Addr>,
+    mtu: u16,
+    boot_file: Option<String>,
+}
+
+impl DhcpServer {
+    pub fn from_config(config: &DerpConfig) -> Self {
+        DhcpServer {
+            server_ip: config.gateway_ip,
+            lease_ip: config.guest_ip,
+            netmask: config.netmask,
+            // The gateway proxies DNS unless the config names servers
+            dns_servers: if config.dns_servers.is_empty() {
+                vec![config.gateway_ip]
+            } else {
+                config.dns_servers.clone()
+            },
+            mtu: config.mtu,
+            boot_file: None,
+        }
+    }
+
+    pub fn server_ip(&self) -> Ipv4Addr {
+        self.server_ip
+    }
+
+    /// The address the guest is given.
+    pub fn lease_ip(&self) -> Ipv4Addr {
+        self.lease_ip
+    }
+
+    pub fn boot_file(&self) -> Option<&str> {
+        self.boot_file.as_deref()
+    }
+
+    /// Names the file network-booting guests should fetch over TFTP, or
+    /// stops offering one. Names longer than the BOOTP file field are
+    /// refused.
+    pub fn set_boot_file(&mut self, boot_file: Option<String>) -> bool {
+        if boot_file.as_ref().is_some_and(|name| name.len() >= FILE_LEN) {
+            return false;
+        }
+        self.boot_file = boot_file;
+        true
+    }
+
+    /// Answers DISCOVER with an OFFER and REQUEST with an ACK, or a NAK if
+    /// the guest asks for a different address. Returns None for anything
+    /// that needs no reply, including requests aimed at another server.
+    pub fn handle(&self, request: &[u8]) -> Option<DhcpReply> {
+        if request.len() < OPTIONS_OFFSET || request[0] != BOOTREQUEST || request[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let mut client_mac = [0u8; 6];
+        client_mac.copy_from_slice(&request[28..34]);
+        let options = parse_options(&request[OPTIONS_OFFSET..]);
+
+        let reply_type = match options.message_type? {
+            DHCPDISCOVER => DHCPOFFER,
+            DHCPREQUEST => {
+                if options.server_id.is_some_and(|id| id != self.server_ip) {
+                    return None;
+                }
+                let ciaddr = Ipv4Addr::new(request[12], request[13], request[14], request[15]);
+                let requested = options.requested_ip
+                    .or_else(|| Some(ciaddr).filter(|ip| !ip.is_unspecified()));
+                if requested.is_none_or(|ip| ip == self.lease_ip) { DHCPACK } else { DHCPNAK }
+            }
+            _ => return None,
+        };
+
+        Some(DhcpReply {
+            client_mac,
+            payload: self.build_reply(request, &options, reply_type),
+        })
+    }
+
+    fn build_reply(&self, request: &[u8], options: &DhcpOptions, message_type: u8) -> Vec<u8> {
+        let mut reply = vec![0u8; OPTIONS_OFFSET];
+        reply[0] = BOOTREPLY;
+        // htype, hlen, hops
+        reply[1..4].copy_from_slice(&request[1..4]);
+        // xid, secs and flags are echoed back
+        reply[4..12].copy_from_slice(&request[4..12]);
+        if message_type != DHCPNAK {
+            reply[16..20].copy_from_slice(&self.lease_ip.octets());
+            reply[20..24].copy_from_slice(&self.server_ip.octets());
+        }
+        // giaddr and chaddr
+        reply[24..44].copy_from_slice(&request[24..44]);
+        reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut reply, OPT_MESSAGE_TYPE, &[message_type]);
+        push_option(&mut reply, OPT_SERVER_ID, &self.server_ip.octets());
+        if message_type != DHCPNAK {
+            push_option(&mut reply, OPT_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+            push_option(&mut reply, OPT_SUBNET_MASK, &self.netmask.octets());
+            push_option(&mut reply, OPT_ROUTER, &self.server_ip.octets());
+            push_option(&mut reply, OPT_INTERFACE_MTU, &self.mtu.to_be_bytes());
+            if !self.dns_servers.is_empty() {
+                let dns: Vec<u8> = self.dns_servers.iter().flat_map(|ip| ip.octets()).collect();
+                push_option(&mut reply, OPT_DNS, &dns);
+            }
+            if let Some(boot_file) = &self.boot_file {
+                // Older ROMs read the BOOTP fields, newer ones the options
+                reply[FILE_OFFSET..FILE_OFFSET + boot_file.len()].copy_from_slice(boot_file.as_bytes());
+                push_option(&mut reply, OPT_TFTP_SERVER, self.server_ip.to_string().as_bytes());
+                push_option(&mut reply, OPT_BOOT_FILE, boot_file.as_bytes());
+                if options.vendor_class.as_ref().is_some_and(|class| class.starts_with(PXE_CLIENT)) {
+                    push_option(&mut reply, OPT_VENDOR_CLASS, PXE_CLIENT);
+                    push_option(&mut reply, OPT_VENDOR_SPECIFIC, &PXE_VENDOR_OPTIONS);
+                }
+            }
+        }
+        reply.push(OPT_END);
+        reply
+    }
+}
+
+#[derive(Default)]
+struct DhcpOptions {
+    message_type: Option<u8>,
+    requested_ip: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+    vendor_class: Option<Vec<u8>>,
+}
+
+fn parse_options(mut options: &[u8]) -> DhcpOptions {
+    let mut parsed = DhcpOptions::default();
+
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+
+        let Some((&len, rest)) = rest.split_first() else { break };
+        let Some(value) = rest.get(..len as usize) else { break };
+        match (code, value) {
+            (OPT_MESSAGE_TYPE, [message_type]) => parsed.message_type = Some(*message_type),
+            (OPT_REQUESTED_IP, [a, b, c, d]) => parsed.requested_ip = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            (OPT_SERVER_ID, [a, b, c, d]) => parsed.server_id = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            (OPT_VENDOR_CLASS, class) => parsed.vendor_class = Some(class.to_vec()),
+            _ => {}
+        }
+        options = &rest[len as usize..];
+    }
+
+    parsed
+}
+
+fn push_option(packet: &mut Vec<u8>, code: u8, value: &[u8]) {
+    packet.push(code);
+    packet.push(value.len() as u8);
+    packet.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const CLIENT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn request(message_type: u8, extra: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut packet = vec![0u8; OPTIONS_OFFSET];
+        packet[0] = BOOTREQUEST;
+        packet[1] = 1;
+        packet[2] = 6;
+        packet[4..8].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        packet[28..34].copy_from_slice(&CLIENT_MAC);
+        packet[236..240].copy_from_slice(&MAGIC_COOKIE);
+        push_option(&mut packet, OPT_MESSAGE_TYPE, &[message_type]);
+        for (code, value) in extra {
+            push_option(&mut packet, *code, value);
+        }
+        packet.push(OPT_END);
+        packet
+    }
+
+    #[wasm_bindgen_test]
+    fn test_discover_and_request() {
+        let config = DerpConfig::default();
+        let server = DhcpServer::from_config(&config);
+
+        let offer = server.handle(&request(DHCPDISCOVER, &[])).unwrap();
+        assert_eq!(offer.client_mac, CLIENT_MAC);
+        assert_eq!(&offer.payload[4..8], &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(&offer.payload[16..20], &config.guest_ip.octets());
+        let options = parse_options(&offer.payload[OPTIONS_OFFSET..]);
+        assert_eq!(options.message_type, Some(DHCPOFFER));
+        assert_eq!(options.server_id, Some(config.gateway_ip));
+
+        let ack = server.handle(&request(DHCPREQUEST, &[(OPT_REQUESTED_IP, &config.guest_ip.octets())])).unwrap();
+        assert_eq!(parse_options(&ack.payload[OPTIONS_OFFSET..]).message_type, Some(DHCPACK));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_pxe_client_is_given_boot_file() {
+        let config = DerpConfig::default();
+        let mut server = DhcpServer::from_config(&config);
+        assert!(server.set_boot_file(Some("pxelinux.0".into())));
+        assert!(!server.set_boot_file(Some("x".repeat(FILE_LEN))));
+
+        let offer = server.handle(&request(DHCPDISCOVER, &[(OPT_VENDOR_CLASS, b"PXEClient:Arch:00000")])).unwrap();
+        assert_eq!(&offer.payload[20..24], &config.gateway_ip.octets());
+        assert_eq!(&offer.payload[FILE_OFFSET..FILE_OFFSET + 11], b"pxelinux.0\0");
+        assert_eq!(parse_options(&offer.payload[OPTIONS_OFFSET..]).vendor_class, Some(PXE_CLIENT.to_vec()));
+
+        server.set_boot_file(None);
+        let offer = server.handle(&request(DHCPDISCOVER, &[(OPT_VENDOR_CLASS, b"PXEClient")])).unwrap();
+        assert_eq!(parse_options(&offer.payload[OPTIONS_OFFSET..]).vendor_class, None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_request_for_other_address_is_refused() {
+        let server = DhcpServer::from_config(&DerpConfig::default());
+
+        let nak = server.handle(&request(DHCPREQUEST, &[(OPT_REQUESTED_IP, &[10, 0, 0, 5])])).unwrap();
+        assert_eq!(parse_options(&nak.payload[OPTIONS_OFFSET..]).message_type, Some(DHCPNAK));
+        assert_eq!(&nak.payload[16..20], &[0, 0, 0, 0]);
+
+        // Selecting a different server needs no answer
+        assert!(server.handle(&request(DHCPREQUEST, &[(OPT_SERVER_ID, &[10, 0, 0, 1])])).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::Ipv6Addr;
+
+pub const DHCPV6_CLIENT_PORT: u16 = 546;
+pub const DHCPV6_SERVER_PORT: u16 = 547;
+/// All_DHCP_Relay_Agents_and_Servers, where clients send their requests.
+pub const ALL_DHCP_SERVERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+
+const HEADER_LEN: usize = 4;
+const REPLY: u8 = 7;
+const INFORMATION_REQUEST: u8 = 11;
+
+const OPT_CLIENTID: u16 = 1;
+const OPT_SERVERID: u16 = 2;
+const OPT_IA_NA: u16 = 3;
+const OPT_IA_TA: u16 = 4;
+const OPT_DNS_SERVERS: u16 = 23;
+const OPT_INFORMATION_REFRESH_TIME: u16 = 32;
+
+const DUID_LL: u16 = 3;
+const HARDWARE_ETHERNET: u16 = 1;
+/// How long the guest may keep what it was told before asking again.
+const INFORMATION_REFRESH_SECS: u32 = 3600;
+
+/// Stateless DHCPv6 server (RFC 8415 §6.1): hands out DNS servers, while
+/// addresses come from SLAAC. Solicits are left unanswered, so a client
+/// looking for an address lease keeps its SLAAC one.
+pub struct Dhcpv6Server {
+    duid: Vec<u8>,
+    dns_servers: Vec<Ipv6Addr>,
+}
+
+impl Dhcpv6Server {
+    /// Identifies itself by the gateway's MAC.
+    pub fn new(gateway_mac: [u8; 6], dns_servers: Vec<Ipv6Addr>) -> Self {
+        let mut duid = Vec::with_capacity(10);
+        duid.extend_from_slice(&DUID_LL.to_be_bytes());
+        duid.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        duid.extend_from_slice(&gateway_mac);
+        Dhcpv6Server { duid, dns_servers }
+    }
+
+    /// Answers an Information-Request with a Reply. Returns None for other
+    /// messages and for requests aimed at another server or asking for
+    /// addresses, which RFC 8415 §16.12 has servers discard.
+    pub fn handle(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < HEADER_LEN || message[0] != INFORMATION_REQUEST {
+            return None;
+        }
+
+        let mut client_id = None;
+        for (code, data) in options(&message[HEADER_LEN..])? {
+            match code {
+                OPT_CLIENTID => client_id = Some(data),
+                OPT_SERVERID if data != self.duid.as_slice() => return None,
+                OPT_IA_NA | OPT_IA_TA => return None,
+                _ => {}
+            }
+        }
+
+        let mut reply = vec![REPLY];
+        reply.extend_from_slice(&message[1..HEADER_LEN]);
+        put_option(&mut reply, OPT_SERVERID, &self.duid);
+        if let Some(client_id) = client_id {
+            put_option(&mut reply, OPT_CLIENTID, client_id);
+        }
+        if !self.dns_servers.is_empty() {
+            let servers: Vec<u8> = self.dns_servers.iter().flat_map(|server| server.octets()).collect();
+            put_option(&mut reply, OPT_DNS_SERVERS, &servers);
+        }
+        put_option(&mut reply, OPT_INFORMATION_REFRESH_TIME, &INFORMATION_REFRESH_SECS.to_be_bytes());
+        Some(reply)
+    }
+}
+
+/// Splits `data` into (code, value) options, or None if one runs past the end.
+fn options(mut data: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        let header = data.get(..4)?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        options.push((code, data.get(4..4 + len)?));
+        data = &data[4 + len..];
+    }
+    Some(options)
+}
+
+fn put_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_be_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GATEWAY_MAC: [u8; 6] = [0x02, 0x86, 0, 0, 0, 1];
+
+    fn information_request(extra: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut message = vec![INFORMATION_REQUEST, 0xAB, 0xCD, 0xEF];
+        put_option(&mut message, OPT_CLIENTID, &[0, 3, 0, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        for (code, value) in extra {
+            put_option(&mut message, *code, value);
+        }
+        message
+    }
+
+    #[wasm_bindgen_test]
+    fn test_information_request_gets_dns_servers() {
+        let dns: Ipv6Addr = "fd86:86::1".parse().unwrap();
+        let server = Dhcpv6Server::new(GATEWAY_MAC, vec![dns]);
+
+        let reply = server.handle(&information_request(&[])).unwrap();
+        assert_eq!(&reply[..4], &[REPLY, 0xAB, 0xCD, 0xEF]);
+        let options = options(&reply[HEADER_LEN..]).unwrap();
+        assert!(options.contains(&(OPT_SERVERID, &[0, 3, 0, 1, 0x02, 0x86, 0, 0, 0, 1][..])));
+        assert!(options.contains(&(OPT_CLIENTID, &[0, 3, 0, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x56][..])));
+        assert!(options.contains(&(OPT_DNS_SERVERS, &dns.octets()[..])));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_requests_it_must_not_answer() {
+        let server = Dhcpv6Server::new(GATEWAY_MAC, Vec::new());
+        let other_server = [0, 3, 0, 1, 0x02, 0x86, 0, 0, 0, 2];
+        assert!(server.handle(&information_request(&[(OPT_SERVERID, &other_server)])).is_none());
+        assert!(server.handle(&information_request(&[(OPT_IA_NA, &[0; 12])])).is_none());
+
+        let mut solicit = information_request(&[]);
+        solicit[0] = 1;
+        assert!(server.handle(&solicit).is_none());
+        let mut truncated = information_request(&[]);
+        truncated.pop();
+        assert!(server.handle(&truncated).is_none());
+    }
+}
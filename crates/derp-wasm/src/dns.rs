@@ -0,0 +1,523 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use js_sys::{ArrayBuffer, Promise, Uint8Array};
+use web_sys::{Request, RequestInit, Response};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::Range;
+use crate::error::{DerpError, DerpResult};
+
+pub const DNS_PORT: u16 = 53;
+pub use crate::config::DEFAULT_DOH_ENDPOINT;
+
+const HEADER_LEN: usize = 12;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// How long NXDOMAIN and empty answers are remembered when the upstream
+/// didn't include the zone's SOA to say.
+const NEGATIVE_TTL_SECS: u32 = 30;
+/// Kept short so a guest notices when the page changes a static host.
+const STATIC_TTL_SECS: u32 = 10;
+/// Upper bound on any cached answer, however long the record's TTL.
+const MAX_TTL_SECS: u32 = 3600;
+const MAX_CACHE_ENTRIES: usize = 256;
+
+// fetch is a global in both windows and workers, like the timer functions.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(request: &Request) -> Promise;
+}
+
+/// Resolves guest DNS queries over DNS-over-HTTPS (RFC 8484), remembering
+/// answers for their TTL and failures for as long as their zone allows
+/// (RFC 2308). Names the page maps itself are answered locally.
+pub struct DnsProxy {
+    endpoint: String,
+    cache: RefCell<DnsCache>,
+    /// Static entries from `addDnsHost`, by lowercased name.
+    hosts: RefCell<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl DnsProxy {
+    pub fn new(endpoint: String) -> Self {
+        DnsProxy {
+            endpoint,
+            cache: RefCell::new(DnsCache::default()),
+            hosts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Answers `name` (case-insensitive, trailing dot optional) with
+    /// `address`, replacing any earlier address of the same family, and
+    /// never asks upstream about it again.
+    pub fn add_host(&self, name: &str, address: IpAddr) -> DerpResult<()> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if !is_valid_name(&name) {
+            return Err(DerpError::InvalidState(format!("Invalid host name: {:?}", name)));
+        }
+        let mut hosts = self.hosts.borrow_mut();
+        let addresses = hosts.entry(name).or_default();
+        addresses.retain(|existing| existing.is_ipv4() != address.is_ipv4());
+        addresses.push(address);
+        Ok(())
+    }
+
+    /// Removes every address added for `name`. Returns false if it had none.
+    pub fn remove_host(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.borrow_mut().remove(&name).is_some()
+    }
+
+    /// The answer to `query` if it asks about a static host: its addresses
+    /// of the type asked for, or none at all for other types, as an
+    /// authoritative server would say.
+    pub fn answer_static(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (name, end) = question_name(query)?;
+        let hosts = self.hosts.borrow();
+        answer_with(query, end, hosts.get(&name)?)
+    }
+
+    /// Returns a cached response for `query`, with its transaction ID.
+    pub fn cached(&self, query: &[u8], now: f64) -> Option<Vec<u8>> {
+        self.cache.borrow_mut().get(query, now)
+    }
+
+    /// Resolves `query` over DoH. Upstream failures become a SERVFAIL so the
+    /// guest's resolver moves on instead of waiting for its own timeout.
+    pub async fn resolve(&self, query: &[u8]) -> Vec<u8> {
+        let mut upstream = query.to_vec();
+        // RFC 8484 recommends ID 0 so identical queries share HTTP caches
+        upstream[0..2].copy_from_slice(&[0, 0]);
+
+        match self.post(&upstream).await {
+            Ok(mut response) if response.len() >= HEADER_LEN => {
+                self.cache.borrow_mut().insert(query, &response, js_sys::Date::now());
+                response[0..2].copy_from_slice(&query[0..2]);
+                response
+            }
+            Ok(_) => error_response(query, RCODE_SERVFAIL),
+            Err(e) => {
+                log::warn!("DoH lookup failed: {}", e);
+                error_response(query, RCODE_SERVFAIL)
+            }
+        }
+    }
+
+    async fn post(&self, query: &[u8]) -> DerpResult<Vec<u8>> {
+        let init = RequestInit::new();
+        init.set_method("POST");
+        init.set_body(&Uint8Array::from(query));
+        let request = Request::new_with_str_and_init(&self.endpoint, &init)
+            .map_err(|e| DerpError::InvalidState(format!("Invalid DoH endpoint: {:?}", e)))?;
+        let headers = request.headers();
+        let _ = headers.set("Content-Type", "application/dns-message");
+        let _ = headers.set("Accept", "application/dns-message");
+
+        let response: Response = JsFuture::from(fetch_with_request(&request)).await
+            .map_err(|e| DerpError::InvalidState(format!("DoH request failed: {:?}", e)))?
+            .unchecked_into();
+        if !response.ok() {
+            return Err(DerpError::InvalidProtocol(format!("DoH server returned {}", response.status())));
+        }
+
+        let body = response.array_buffer()
+            .map_err(|e| DerpError::InvalidState(format!("DoH response unreadable: {:?}", e)))?;
+        let body: ArrayBuffer = JsFuture::from(body).await
+            .map_err(|e| DerpError::InvalidState(format!("DoH response unreadable: {:?}", e)))?
+            .unchecked_into();
+        Ok(Uint8Array::new(&body).to_vec())
+    }
+}
+
+/// Whether `packet` looks like a standard query this proxy can answer.
+pub fn is_query(packet: &[u8]) -> bool {
+    // QR clear, opcode QUERY, exactly one question
+    packet.len() > HEADER_LEN && packet[2] & 0xF8 == 0 && packet[4..6] == [0, 1]
+}
+
+/// The answer to `query` if it asks about a name in `zone`, all of whose
+/// names `names` holds by lowercased name: their addresses, or NXDOMAIN
+/// for the rest, so they're never asked about upstream.
+pub fn answer_zone(query: &[u8], zone: &str, names: &HashMap<String, Vec<IpAddr>>) -> Option<Vec<u8>> {
+    let (name, end) = question_name(query)?;
+    let in_zone = name.strip_suffix(zone).is_some_and(|rest| rest.is_empty() || rest.ends_with('.'));
+    if !in_zone {
+        return None;
+    }
+    match names.get(&name) {
+        Some(addresses) => answer_with(query, end, addresses),
+        None => {
+            let mut response = error_response(query, RCODE_NXDOMAIN);
+            response[2] |= 0x04; // AA
+            Some(response)
+        }
+    }
+}
+
+/// An authoritative answer to `query`, whose question ends at `end`, from
+/// `addresses`: those of the type asked for, or none at all for other
+/// types.
+fn answer_with(query: &[u8], end: usize, addresses: &[IpAddr]) -> Option<Vec<u8>> {
+    let fields = &query[end - 4..end];
+    let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+    if u16::from_be_bytes([fields[2], fields[3]]) != CLASS_IN {
+        return None;
+    }
+
+    let answers: Vec<&IpAddr> = addresses.iter().filter(|address| match qtype {
+        TYPE_A => address.is_ipv4(),
+        TYPE_AAAA => address.is_ipv6(),
+        TYPE_ANY => true,
+        _ => false,
+    }).collect();
+    let mut response = query[..end].to_vec();
+    // QR, AA and RA set, opcode and RD echoed
+    response[2] = 0x84 | (query[2] & 0x79);
+    response[3] = 0x80 | RCODE_NOERROR;
+    response[4..12].copy_from_slice(&[0, 1, 0, answers.len() as u8, 0, 0, 0, 0]);
+    for address in answers {
+        let (rtype, octets) = match address {
+            IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+        };
+        // A pointer to the question's name
+        response.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&STATIC_TTL_SECS.to_be_bytes());
+        response.extend_from_slice(&(octets.len() as u16).to_be_bytes());
+        response.extend_from_slice(&octets);
+    }
+    Some(response)
+}
+
+/// A reply to `query` carrying only the question and `rcode`.
+fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    let question_end = question_end(query).unwrap_or(HEADER_LEN);
+    let mut response = query[..question_end].to_vec();
+    // QR and RA set, opcode and RD echoed
+    response[2] = 0x80 | (query[2] & 0x79);
+    response[3] = 0x80 | rcode;
+    let question_count: &[u8] = if question_end > HEADER_LEN { &[0, 1] } else { &[0, 0] };
+    response[4..6].copy_from_slice(question_count);
+    response[6..12].copy_from_slice(&[0; 6]);
+    response
+}
+
+#[derive(Default)]
+struct DnsCache {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    stored_at: f64,
+    expires_at: f64,
+}
+
+impl DnsCache {
+    fn get(&mut self, query: &[u8], now: f64) -> Option<Vec<u8>> {
+        let key = cache_key(query)?;
+        let entry = self.entries.get(&key)?;
+        if entry.expires_at <= now {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        response[0..2].copy_from_slice(&query[0..2]);
+        // The guest's own cache shouldn't keep it past our expiry
+        age_ttls(&mut response, ((now - entry.stored_at) / 1000.0) as u32);
+        Some(response)
+    }
+
+    fn insert(&mut self, query: &[u8], response: &[u8], now: f64) {
+        let (Some(key), Some(ttl)) = (cache_key(query), response_ttl(response)) else { return };
+        if ttl == 0 {
+            return;
+        }
+
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            // Still full of live answers; any one of them will do
+            if let Some(victim) = self.entries.keys().next().cloned() {
+                self.entries.remove(&victim);
+            }
+        }
+
+        self.entries.insert(key, CacheEntry {
+            response: response.to_vec(),
+            stored_at: now,
+            expires_at: now + ttl as f64 * 1000.0,
+        });
+    }
+}
+
+/// The question section with the name lowercased, so "Example.com" and
+/// "example.com" share an entry.
+fn cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    let end = question_end(query)?;
+    Some(query[HEADER_LEN..end].to_ascii_lowercase())
+}
+
+/// The first question's name, lowercased, and the offset just past the
+/// question. Names in queries aren't compressed.
+fn question_name(packet: &[u8]) -> Option<(String, usize)> {
+    let end = question_end(packet)?;
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LEN;
+    while packet[offset] != 0 {
+        let len = packet[offset] as usize;
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(&packet[offset + 1..offset + 1 + len]).to_ascii_lowercase());
+        offset += 1 + len;
+    }
+    Some((labels.join("."), end))
+}
+
+/// Whether `name` can be encoded in a DNS question.
+fn is_valid_name(name: &str) -> bool {
+    name.len() <= 253 && name.split('.').all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// Offset just past the first question.
+fn question_end(packet: &[u8]) -> Option<usize> {
+    let name_end = skip_name(packet, HEADER_LEN)?;
+    let end = name_end + 4;
+    (end <= packet.len()).then_some(end)
+}
+
+/// Offset just past the (possibly compressed) name starting at `offset`.
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            // A compression pointer ends the name
+            len if len & 0xC0 == 0xC0 => return (offset + 2 <= packet.len()).then_some(offset + 2),
+            len if len & 0xC0 == 0 => offset += 1 + len as usize,
+            _ => return None,
+        }
+    }
+}
+
+/// A resource record's type, where its TTL is, and its data.
+struct Record {
+    rtype: u16,
+    ttl_at: usize,
+    rdata: Range<usize>,
+    answer: bool,
+}
+
+impl Record {
+    fn ttl(&self, packet: &[u8]) -> u32 {
+        let ttl = &packet[self.ttl_at..self.ttl_at + 4];
+        u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]])
+    }
+}
+
+/// Every record in the answer, authority and additional sections, or None
+/// if they run past the end of `packet`.
+fn records(packet: &[u8]) -> Option<Vec<Record>> {
+    let count = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]) as usize;
+    let answers = count(6);
+    let total = answers + count(8) + count(10);
+    let mut offset = question_end(packet)?;
+    let mut records = Vec::with_capacity(total);
+    for i in 0..total {
+        offset = skip_name(packet, offset)?;
+        let fixed = packet.get(offset..offset + 10)?;
+        let rdata = offset + 10..offset + 10 + u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if rdata.end > packet.len() {
+            return None;
+        }
+        records.push(Record {
+            rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            ttl_at: offset + 4,
+            rdata: rdata.clone(),
+            answer: i < answers,
+        });
+        offset = rdata.end;
+    }
+    Some(records)
+}
+
+/// How long `response` may be cached, or None if it shouldn't be.
+fn response_ttl(response: &[u8]) -> Option<u32> {
+    if response.len() < HEADER_LEN {
+        return None;
+    }
+
+    let rcode = response[3] & 0x0F;
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let records = records(response)?;
+    match rcode {
+        RCODE_NXDOMAIN => return Some(negative_ttl(response, &records)),
+        RCODE_NOERROR if answers == 0 => return Some(negative_ttl(response, &records)),
+        RCODE_NOERROR => {}
+        _ => return None,
+    }
+
+    let ttl = records.iter()
+        .filter(|record| record.answer)
+        .map(|record| record.ttl(response))
+        .fold(MAX_TTL_SECS, u32::min);
+    Some(ttl)
+}
+
+/// RFC 2308: the lesser of the SOA record's own TTL and its MINIMUM field,
+/// which ends its data.
+fn negative_ttl(response: &[u8], records: &[Record]) -> u32 {
+    let soa = records.iter().find(|record| !record.answer && record.rtype == TYPE_SOA && record.rdata.len() >= 22);
+    match soa {
+        Some(soa) => {
+            let minimum = &response[soa.rdata.end - 4..soa.rdata.end];
+            let minimum = u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]]);
+            soa.ttl(response).min(minimum).min(MAX_TTL_SECS)
+        }
+        None => NEGATIVE_TTL_SECS,
+    }
+}
+
+/// Counts `elapsed` seconds off every record's TTL. The OPT pseudo-record
+/// keeps flags where the TTL would be, so it's left alone.
+fn age_ttls(response: &mut [u8], elapsed: u32) {
+    if elapsed == 0 {
+        return;
+    }
+    for record in records(response).unwrap_or_default() {
+        if record.rtype != TYPE_OPT {
+            let ttl = record.ttl(response).saturating_sub(elapsed);
+            response[record.ttl_at..record.ttl_at + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        // QTYPE A, QCLASS IN
+        packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+        packet
+    }
+
+    fn answer(query: &[u8], ttl: u32) -> Vec<u8> {
+        let mut packet = query.to_vec();
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = 1;
+        // Pointer to the question name, A/IN, TTL, 4-byte address
+        packet.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+        packet.extend_from_slice(&ttl.to_be_bytes());
+        packet.extend_from_slice(&[0, 4, 93, 184, 216, 34]);
+        packet
+    }
+
+    #[wasm_bindgen_test]
+    fn test_cache_honours_ttl() {
+        let mut cache = DnsCache::default();
+        let upstream = answer(&query(0, "example.com"), 60);
+        cache.insert(&query(0, "example.com"), &upstream, 0.0);
+
+        let fresh = cache.get(&query(0x1234, "Example.COM"), 999.0).unwrap();
+        assert_eq!(&fresh[0..2], &[0x12, 0x34]);
+        assert_eq!(&fresh[2..], &upstream[2..]);
+        // Served with what's left of the TTL
+        let hit = cache.get(&query(0x1234, "example.com"), 59_000.0).unwrap();
+        let ttl_at = query(0, "example.com").len() + 6;
+        assert_eq!(&hit[ttl_at..ttl_at + 4], &1u32.to_be_bytes());
+        assert_eq!(&hit[ttl_at + 4..], &upstream[ttl_at + 4..]);
+        assert!(cache.get(&query(0x1234, "example.com"), 60_000.0).is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_negative_answers_and_failures() {
+        let mut nxdomain = query(0, "missing.example");
+        nxdomain[2] = 0x81;
+        nxdomain[3] = 0x80 | RCODE_NXDOMAIN;
+        assert_eq!(response_ttl(&nxdomain), Some(NEGATIVE_TTL_SECS));
+        assert_eq!(response_ttl(&error_response(&query(7, "example.com"), RCODE_SERVFAIL)), None);
+        assert_eq!(response_ttl(&answer(&query(0, "example.com"), 86_400)), Some(MAX_TTL_SECS));
+
+        let servfail = error_response(&query(7, "example.com"), RCODE_SERVFAIL);
+        assert_eq!(&servfail[0..2], &[0, 7]);
+        assert_eq!(servfail[3] & 0x0F, RCODE_SERVFAIL);
+        assert!(is_query(&query(7, "example.com")));
+        assert!(!is_query(&servfail));
+
+        // With the zone's SOA, its MINIMUM field says how long
+        let mut nodata = query(0, "example.com");
+        nodata[2] = 0x81;
+        nodata[3] = 0x80;
+        nodata[9] = 1;
+        nodata.extend_from_slice(&[0xC0, 0x0C, 0, 6, 0, 1, 0, 0, 0x0E, 0x10, 0, 22]);
+        nodata.extend_from_slice(&[0, 0]);
+        for field in [1u32, 7200, 3600, 1_209_600, 300] {
+            nodata.extend_from_slice(&field.to_be_bytes());
+        }
+        assert_eq!(response_ttl(&nodata), Some(300));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_static_hosts() {
+        let proxy = DnsProxy::new(DEFAULT_DOH_ENDPOINT.into());
+        proxy.add_host("MyApp.test.", "192.168.86.101".parse().unwrap()).unwrap();
+        proxy.add_host("myapp.test", "fd86:86::101".parse().unwrap()).unwrap();
+        assert!(proxy.add_host("bad..name", "10.0.0.1".parse().unwrap()).is_err());
+
+        let response = proxy.answer_static(&query(0x4242, "myapp.TEST")).unwrap();
+        assert_eq!(&response[0..4], &[0x42, 0x42, 0x85, 0x80]);
+        assert_eq!(u16::from_be_bytes([response[6], response[7]]), 1);
+        assert_eq!(&response[response.len() - 4..], &[192, 168, 86, 101]);
+        assert_eq!(response_ttl(&response), Some(STATIC_TTL_SECS));
+
+        // MX for a static name: no such record, and no upstream lookup
+        let mut mx = query(1, "myapp.test");
+        let qtype_at = mx.len() - 3;
+        mx[qtype_at] = 15;
+        let response = proxy.answer_static(&mx).unwrap();
+        assert_eq!((response[3], response[7]), (0x80, 0));
+
+        assert!(proxy.answer_static(&query(1, "other.test")).is_none());
+        assert!(proxy.remove_host("myapp.test"));
+        assert!(proxy.answer_static(&query(1, "myapp.test")).is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_zone_answers() {
+        let names = HashMap::from([("alice.derp".to_string(), vec!["100.64.0.1".parse().unwrap()])]);
+        let response = answer_zone(&query(7, "Alice.derp"), "derp", &names).unwrap();
+        assert_eq!(&response[response.len() - 4..], &[100, 64, 0, 1]);
+
+        // Unknown names in the zone don't go upstream
+        let response = answer_zone(&query(7, "bob.derp"), "derp", &names).unwrap();
+        assert_eq!((response[2] & 0x04, response[3] & 0x0F), (0x04, RCODE_NXDOMAIN));
+        assert!(answer_zone(&query(7, "alice.example"), "derp", &names).is_none());
+        assert!(answer_zone(&query(7, "notderp"), "derp", &names).is_none());
+    }
+}
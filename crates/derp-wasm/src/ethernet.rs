@@ -0,0 +1,34 @@
+/// How an Ethernet frame is addressed, from its destination MAC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cast {
+    Unicast,
+    Broadcast,
+    /// IPv4 (01:00:5e:...), IPv6 (33:33:...) or any other group address.
+    Multicast,
+}
+
+pub fn cast(dst_mac: &[u8]) -> Cast {
+    if dst_mac == [0xFF; 6] {
+        Cast::Broadcast
+    } else if dst_mac.first().is_some_and(|octet| octet & 0x01 != 0) {
+        Cast::Multicast
+    } else {
+        Cast::Unicast
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_cast() {
+        assert_eq!(cast(&[0xFF; 6]), Cast::Broadcast);
+        assert_eq!(cast(&[0x01, 0x00, 0x5E, 0x00, 0x00, 0xFB]), Cast::Multicast);
+        assert_eq!(cast(&[0x33, 0x33, 0x00, 0x00, 0x00, 0x01]), Cast::Multicast);
+        assert_eq!(cast(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]), Cast::Unicast);
+    }
+}
@@ -0,0 +1,394 @@
+use wasm_bindgen::prelude::*;
+use futures::channel::mpsc;
+use js_sys::Function;
+use serde::Serialize;
+use tsify::Tsify;
+use std::collections::{HashMap, VecDeque};
+use crate::shared::Shared;
+
+/// Defined with the circuit breaker that produces it.
+pub use crate::breaker::ReconnectedEvent;
+
+#[wasm_bindgen(typescript_custom_section)]
+const EVENTS_TS: &'static str = r#"
+export type DerpEventName =
+    | "connect"
+    | "disconnect"
+    | "reconnecting"
+    | "reconnected"
+    | "connection-failed"
+    | "state"
+    | "backpressure"
+    | "home"
+    | "health"
+    | "restarting"
+    | "peer-present"
+    | "peer-gone"
+    | "peer-closed"
+    | "control"
+    | "packet"
+    | "forward"
+    | "error";
+
+/** Payload passed to listeners of each event. */
+export interface DerpEventMap {
+    "connect": undefined;
+    "disconnect": DisconnectEvent;
+    "reconnecting": ReconnectingEvent;
+    "reconnected": ReconnectedEvent;
+    "connection-failed": ConnectionFailedEvent;
+    "state": StateChangeEvent;
+    "backpressure": BackpressureEvent;
+    "home": HomeEvent;
+    "health": HealthEvent;
+    "restarting": RestartingEvent;
+    "peer-present": PeerEvent;
+    "peer-gone": PeerEvent;
+    "peer-closed": PeerEvent;
+    "control": ControlEvent;
+    "packet": Uint8Array;
+    "forward": Uint8Array;
+    "error": DerpErrorShape;
+}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    Reconnecting,
+    /// The handshake completed again after reconnecting.
+    Reconnected,
+    /// Reconnects ran out and the circuit breaker opened.
+    ConnectionFailed,
+    State,
+    Backpressure,
+    Home,
+    Health,
+    Restarting,
+    PeerPresent,
+    PeerGone,
+    /// Disconnected by the relay at someone's request; see `closePeer`.
+    PeerClosed,
+    /// A coordination server's document was applied.
+    Control,
+    Packet,
+    /// A ForwardPacket payload, for a mesh bridge to pass on.
+    Forward,
+    Error,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 17] = [
+        EventKind::Connect,
+        EventKind::Disconnect,
+        EventKind::Reconnecting,
+        EventKind::Reconnected,
+        EventKind::ConnectionFailed,
+        EventKind::State,
+        EventKind::Backpressure,
+        EventKind::Home,
+        EventKind::Health,
+        EventKind::Restarting,
+        EventKind::PeerPresent,
+        EventKind::PeerGone,
+        EventKind::PeerClosed,
+        EventKind::Control,
+        EventKind::Packet,
+        EventKind::Forward,
+        EventKind::Error,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Connect => "connect",
+            EventKind::Disconnect => "disconnect",
+            EventKind::Reconnecting => "reconnecting",
+            EventKind::Reconnected => "reconnected",
+            EventKind::ConnectionFailed => "connection-failed",
+            EventKind::State => "state",
+            EventKind::Backpressure => "backpressure",
+            EventKind::Home => "home",
+            EventKind::Health => "health",
+            EventKind::Restarting => "restarting",
+            EventKind::PeerPresent => "peer-present",
+            EventKind::PeerGone => "peer-gone",
+            EventKind::PeerClosed => "peer-closed",
+            EventKind::Control => "control",
+            EventKind::Packet => "packet",
+            EventKind::Forward => "forward",
+            EventKind::Error => "error",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        match name {
+            "connect" => Some(EventKind::Connect),
+            "disconnect" => Some(EventKind::Disconnect),
+            "reconnecting" => Some(EventKind::Reconnecting),
+            "reconnected" => Some(EventKind::Reconnected),
+            "connection-failed" => Some(EventKind::ConnectionFailed),
+            "state" => Some(EventKind::State),
+            "backpressure" => Some(EventKind::Backpressure),
+            "home" => Some(EventKind::Home),
+            "health" => Some(EventKind::Health),
+            "restarting" => Some(EventKind::Restarting),
+            "peer-present" => Some(EventKind::PeerPresent),
+            "peer-gone" => Some(EventKind::PeerGone),
+            "peer-closed" => Some(EventKind::PeerClosed),
+            "control" => Some(EventKind::Control),
+            "packet" => Some(EventKind::Packet),
+            "forward" => Some(EventKind::Forward),
+            "error" => Some(EventKind::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct DisconnectEvent {
+    pub code: u16,
+    pub reason: String,
+    pub was_clean: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectingEvent {
+    pub attempt: u32,
+    pub delay_ms: u32,
+    /// The WebSocket close code and the relay's reason, if it gave one,
+    /// from the close that prompted this attempt.
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerEvent {
+    /// Hex-encoded public key of the peer.
+    pub peer_key: String,
+}
+
+/// Listener registry shared between `DerpNetwork` and the socket callbacks.
+/// Besides JS callbacks, Rust code can subscribe with a bounded channel.
+#[derive(Clone, Default)]
+pub struct EventDispatcher {
+    listeners: Shared<HashMap<EventKind, Vec<Function>>>,
+    subscribers: Shared<HashMap<EventKind, Vec<mpsc::Sender<JsValue>>>>,
+    packets: Shared<Vec<mpsc::Sender<Vec<u8>>>>,
+    deferred: Shared<Deferred>,
+}
+
+/// Events emitted while a `Deferral` is held, in order.
+#[derive(Default)]
+struct Deferred {
+    holds: u32,
+    queue: VecDeque<(EventKind, JsValue)>,
+}
+
+/// Holds back events until dropped; see `EventDispatcher::defer`.
+pub struct Deferral(EventDispatcher);
+
+impl EventDispatcher {
+    pub fn new() -> Self {
+        EventDispatcher::default()
+    }
+
+    pub fn on(&self, kind: EventKind, callback: Function) {
+        self.listeners.lock().entry(kind).or_default().push(callback);
+    }
+
+    /// Removes a previously registered callback. Returns false if it wasn't registered.
+    pub fn off(&self, kind: EventKind, callback: &Function) -> bool {
+        let mut listeners = self.listeners.lock();
+        let callbacks = match listeners.get_mut(&kind) {
+            Some(callbacks) => callbacks,
+            None => return false,
+        };
+
+        let before = callbacks.len();
+        callbacks.retain(|registered| registered != callback);
+        before != callbacks.len()
+    }
+
+    /// Returns a channel receiving every `kind` payload. Payloads are dropped
+    /// while the channel is full; dropping the receiver unsubscribes.
+    pub fn subscribe(&self, kind: EventKind, capacity: usize) -> mpsc::Receiver<JsValue> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.subscribers.lock().entry(kind).or_default().push(sender);
+        receiver
+    }
+
+    /// Like `subscribe(EventKind::Packet, ..)`, but receives the decrypted
+    /// bytes themselves rather than the `Uint8Array` listeners get.
+    pub fn subscribe_packets(&self, capacity: usize) -> mpsc::Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.packets.lock().push(sender);
+        receiver
+    }
+
+    /// Hands a copy of `packet` to every `subscribe_packets` channel.
+    pub fn deliver_packet(&self, packet: &[u8]) {
+        self.packets.lock().retain_mut(|subscriber| match subscriber.try_send(packet.to_vec()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        });
+    }
+
+    /// Queues events emitted from now until the returned `Deferral` is
+    /// dropped, then delivers them in order. Callers holding state that
+    /// listeners may call back into take one for as long as they hold it.
+    pub fn defer(&self) -> Deferral {
+        self.deferred.lock().holds += 1;
+        Deferral(self.clone())
+    }
+
+    /// Calls every listener for `kind`, or queues the call while deferred.
+    pub fn emit(&self, kind: EventKind, payload: &JsValue) {
+        {
+            let mut deferred = self.deferred.lock();
+            if deferred.holds > 0 {
+                deferred.queue.push_back((kind, payload.clone()));
+                return;
+            }
+        }
+        self.dispatch(kind, payload);
+    }
+
+    /// The listener list is copied first so callbacks may register or
+    /// remove listeners while being dispatched.
+    fn dispatch(&self, kind: EventKind, payload: &JsValue) {
+        if let Some(subscribers) = self.subscribers.lock().get_mut(&kind) {
+            subscribers.retain_mut(|subscriber| match subscriber.try_send(payload.clone()) {
+                Ok(()) => true,
+                Err(e) => !e.is_disconnected(),
+            });
+        }
+
+        let callbacks = match self.listeners.lock().get(&kind) {
+            Some(callbacks) => callbacks.clone(),
+            None => return,
+        };
+
+        for callback in callbacks {
+            if let Err(e) = callback.call1(&JsValue::NULL, payload) {
+                log::warn!("{} listener threw: {:?}", kind.name(), e);
+            }
+        }
+    }
+
+    pub fn emit_serialized<T: Serialize>(&self, kind: EventKind, payload: &T) {
+        if let Ok(payload) = serde_wasm_bindgen::to_value(payload) {
+            self.emit(kind, &payload);
+        }
+    }
+}
+
+impl Drop for Deferral {
+    fn drop(&mut self) {
+        let events = &self.0;
+        {
+            let mut deferred = events.deferred.lock();
+            deferred.holds -= 1;
+            if deferred.holds > 0 {
+                return;
+            }
+        }
+        // A listener deferring in turn delivers whatever is left itself
+        loop {
+            let next = {
+                let mut deferred = events.deferred.lock();
+                if deferred.holds > 0 {
+                    return;
+                }
+                deferred.queue.pop_front()
+            };
+            match next {
+                Some((kind, payload)) => events.dispatch(kind, &payload),
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+    use wasm_bindgen::JsCast;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn recorder(sink: &js_sys::Array) -> (Closure<dyn FnMut(JsValue)>, Function) {
+        let sink = sink.clone();
+        let closure = Closure::wrap(Box::new(move |payload: JsValue| {
+            sink.push(&payload);
+        }) as Box<dyn FnMut(JsValue)>);
+        let function = closure.as_ref().unchecked_ref::<Function>().clone();
+        (closure, function)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_on_emit_off() {
+        let events = EventDispatcher::new();
+        let received = js_sys::Array::new();
+        let (_closure, callback) = recorder(&received);
+
+        events.on(EventKind::Packet, callback.clone());
+        events.emit(EventKind::Packet, &JsValue::from(1));
+        events.emit(EventKind::Connect, &JsValue::UNDEFINED);
+        assert_eq!(received.length(), 1);
+
+        assert!(events.off(EventKind::Packet, &callback));
+        assert!(!events.off(EventKind::Packet, &callback));
+        events.emit(EventKind::Packet, &JsValue::from(2));
+        assert_eq!(received.length(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_deferred_until_released() {
+        let events = EventDispatcher::new();
+        let received = js_sys::Array::new();
+        let (_closure, callback) = recorder(&received);
+        events.on(EventKind::Packet, callback);
+
+        let outer = events.defer();
+        let inner = events.defer();
+        events.emit(EventKind::Packet, &JsValue::from(1));
+        drop(inner);
+        events.emit(EventKind::Packet, &JsValue::from(2));
+        assert_eq!(received.length(), 0);
+
+        drop(outer);
+        assert_eq!(received.to_vec(), [JsValue::from(1), JsValue::from(2)]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_subscribe() {
+        let events = EventDispatcher::new();
+        let mut receiver = events.subscribe(EventKind::Packet, 1);
+
+        events.emit(EventKind::Packet, &JsValue::from(1));
+        // Queue is full, so this one is dropped
+        events.emit(EventKind::Packet, &JsValue::from(2));
+
+        assert_eq!(receiver.try_recv().unwrap(), JsValue::from(1));
+        assert!(receiver.try_recv().unwrap_err().is_empty());
+
+        drop(receiver);
+        events.emit(EventKind::Packet, &JsValue::from(3));
+        assert!(events.subscribers.lock()[&EventKind::Packet].is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_event_names() {
+        assert_eq!(EventKind::from_name("peer-present"), Some(EventKind::PeerPresent));
+        assert_eq!(EventKind::from_name("bogus"), None);
+
+        for kind in EventKind::ALL {
+            assert_eq!(EventKind::from_name(kind.name()), Some(kind));
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use futures::StreamExt;
+use js_sys::{Array, Promise, Uint8Array};
+use web_sys::{Headers, Request, RequestInit, Response};
+use std::net::Ipv4Addr;
+use crate::error::{DerpError, DerpResult};
+use crate::flow::FlowKey;
+use crate::ip::PROTO_TCP;
+use crate::nat::{Egress, NatBackend, NatHandle, NatStream};
+
+pub const HTTP_PORT: u16 = 80;
+
+/// Largest request head or buffered request body accepted from the guest.
+pub const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Hop-by-hop and length headers that belong to the guest's connection, not
+/// to the request fetch() makes.
+const SKIPPED_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "content-length",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+];
+
+// fetch is a global in both windows and workers, like the timer functions.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(request: &Request) -> Promise;
+}
+
+/// Carries the guest's plain-HTTP connections over the browser's fetch().
+///
+/// Requests are sent over HTTPS unless `upgrade_https` is off, since pages
+/// served over HTTPS may not fetch http:// URLs. The target site has to allow
+/// CORS, or `proxy` must name a CORS proxy that takes the target URL appended
+/// to it. Guest TLS on port 443 can't be carried this way without
+/// terminating it, so those flows are left to the next backend.
+pub struct FetchBackend {
+    proxy: Option<String>,
+    upgrade_https: bool,
+}
+
+impl FetchBackend {
+    pub fn new(proxy: Option<String>, upgrade_https: bool) -> Self {
+        FetchBackend { proxy, upgrade_https }
+    }
+}
+
+impl NatBackend for FetchBackend {
+    fn open(&self, flow: &FlowKey) -> Option<Egress> {
+        if flow.protocol != PROTO_TCP || flow.dst_port != HTTP_PORT {
+            return None;
+        }
+
+        Some(Egress::Stream(Box::new(FetchStream {
+            proxy: self.proxy.clone(),
+            scheme: if self.upgrade_https { "https" } else { "http" },
+            destination: Ipv4Addr::from(flow.dst),
+            buffer: Vec::new(),
+            sent: false,
+        })))
+    }
+}
+
+/// One guest connection. Buffers a single request, answers it, and closes;
+/// the response carries `Connection: close` so the guest doesn't pipeline.
+struct FetchStream {
+    proxy: Option<String>,
+    scheme: &'static str,
+    destination: Ipv4Addr,
+    buffer: Vec<u8>,
+    sent: bool,
+}
+
+impl NatStream for FetchStream {
+    fn on_data(&mut self, data: &[u8], handle: &NatHandle) {
+        if self.sent {
+            return;
+        }
+
+        self.buffer.extend_from_slice(data);
+        let request = match HttpRequest::parse(&self.buffer) {
+            Ok(Some(request)) => request,
+            Ok(None) if self.buffer.len() <= MAX_REQUEST_SIZE => return,
+            Ok(None) => {
+                self.sent = true;
+                respond_with_error(handle, 413, "Payload Too Large");
+                return;
+            }
+            Err(_) => {
+                self.sent = true;
+                respond_with_error(handle, 400, "Bad Request");
+                return;
+            }
+        };
+        self.sent = true;
+
+        let host = request.header("host")
+            .map(str::to_string)
+            .unwrap_or_else(|| self.destination.to_string());
+        let mut url = format!("{}://{}{}", self.scheme, host, request.target);
+        if let Some(proxy) = &self.proxy {
+            url = format!("{}{}", proxy, url);
+        }
+
+        let handle = handle.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Err(e) = forward(&url, &request, &handle).await {
+                log::warn!("fetch egress for {} failed: {}", url, e);
+                respond_with_error(&handle, 502, "Bad Gateway");
+            }
+        });
+    }
+}
+
+/// Makes the request and streams the response back to the guest.
+async fn forward(url: &str, request: &HttpRequest, handle: &NatHandle) -> DerpResult<()> {
+    let headers = Headers::new().map_err(js_error)?;
+    for (name, value) in &request.headers {
+        if !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            // Browsers silently drop forbidden headers such as Cookie
+            let _ = headers.append(name, value);
+        }
+    }
+
+    let init = RequestInit::new();
+    init.set_method(&request.method);
+    init.set_headers(&headers);
+    if !request.body.is_empty() {
+        init.set_body(&Uint8Array::from(request.body.as_slice()));
+    }
+
+    let fetch_request = Request::new_with_str_and_init(url, &init).map_err(js_error)?;
+    let response: Response = JsFuture::from(fetch_with_request(&fetch_request)).await
+        .map_err(js_error)?
+        .unchecked_into();
+
+    handle.send(&response_head(&response));
+    if let Some(body) = response.body() {
+        let mut chunks = wasm_streams::ReadableStream::from_raw(body).into_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk: Uint8Array = chunk.map_err(js_error)?.unchecked_into();
+            handle.send(&chunk.to_vec());
+        }
+    }
+    handle.close();
+    Ok(())
+}
+
+/// Status line and headers for the guest. fetch() has already decoded any
+/// content encoding and the body length isn't known up front, so the body
+/// is delimited by closing the connection.
+fn response_head(response: &Response) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status(), response.status_text());
+    if let Ok(Some(entries)) = js_sys::try_iter(&response.headers()) {
+        for entry in entries.flatten() {
+            let entry: Array = entry.unchecked_into();
+            let (Some(name), Some(value)) = (entry.get(0).as_string(), entry.get(1).as_string()) else { continue };
+            let skipped = matches!(name.as_str(), "content-encoding" | "content-length" | "transfer-encoding" | "connection");
+            if !skipped {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+fn respond_with_error(handle: &NatHandle, status: u16, reason: &str) {
+    handle.send(format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason).as_bytes());
+    handle.close();
+}
+
+fn js_error(e: JsValue) -> DerpError {
+    DerpError::InvalidState(format!("{:?}", e))
+}
+
+/// A complete HTTP/1.x request read off the guest connection.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns None until the head and a Content-Length body have arrived.
+    pub fn parse(data: &[u8]) -> DerpResult<Option<HttpRequest>> {
+        let Some(head_end) = data.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let head = std::str::from_utf8(&data[..head_end])
+            .map_err(|_| DerpError::InvalidProtocol("Request head is not UTF-8".into()))?;
+
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(_version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+            return Err(DerpError::InvalidProtocol("Malformed request line".into()));
+        };
+
+        let headers = lines
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| DerpError::InvalidProtocol("Malformed header".into()))
+            })
+            .collect::<DerpResult<Vec<_>>>()?;
+
+        let mut request = HttpRequest {
+            method: method.to_string(),
+            target: target.to_string(),
+            headers,
+            body: Vec::new(),
+        };
+        if request.header("transfer-encoding").is_some() {
+            return Err(DerpError::InvalidProtocol("Chunked request bodies are not supported".into()));
+        }
+
+        let body_len = match request.header("content-length") {
+            Some(len) => len.parse::<usize>()
+                .map_err(|_| DerpError::InvalidProtocol("Invalid Content-Length".into()))?,
+            None => 0,
+        };
+        let body = &data[head_end + 4..];
+        if body.len() < body_len {
+            return Ok(None);
+        }
+        request.body = body[..body_len].to_vec();
+        Ok(Some(request))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_parse_request() {
+        let partial = b"POST /submit HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(HttpRequest::parse(partial).unwrap().is_none());
+
+        let complete = b"POST /submit HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello";
+        let request = HttpRequest::parse(complete).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/submit");
+        assert_eq!(request.header("HOST"), Some("example.com"));
+        assert_eq!(request.body, b"hello");
+
+        assert!(HttpRequest::parse(b"GET\r\n\r\n").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_claims_only_http() {
+        let backend = FetchBackend::new(None, true);
+        let flow = |protocol, dst_port| FlowKey {
+            protocol,
+            src: [192, 168, 86, 100],
+            dst: [93, 184, 216, 34],
+            src_port: 40000,
+            dst_port,
+        };
+
+        assert!(backend.open(&flow(PROTO_TCP, HTTP_PORT)).is_some());
+        assert!(backend.open(&flow(PROTO_TCP, 443)).is_none());
+        assert!(backend.open(&flow(crate::ip::PROTO_UDP, HTTP_PORT)).is_none());
+    }
+}
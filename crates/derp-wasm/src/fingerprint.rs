@@ -0,0 +1,143 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+use crate::protocol::hex_encode;
+
+/// Bytes of a key's hash shown in its fingerprint.
+const FINGERPRINT_BYTES: usize = 16;
+/// Seven emoji of six bits each.
+const SAS_EMOJI: usize = 7;
+const SAS_BYTES: usize = 6;
+const SAS_LABEL: &[u8] = b"derp-sas";
+
+/// The 64 emoji of Matrix's SAS verification, chosen to be told apart at a
+/// glance and named the same way by most people.
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"), ("🐱", "Cat"), ("🦁", "Lion"), ("🐎", "Horse"),
+    ("🦄", "Unicorn"), ("🐷", "Pig"), ("🐘", "Elephant"), ("🐰", "Rabbit"),
+    ("🐼", "Panda"), ("🐓", "Rooster"), ("🐧", "Penguin"), ("🐢", "Turtle"),
+    ("🐟", "Fish"), ("🐙", "Octopus"), ("🦋", "Butterfly"), ("🌷", "Flower"),
+    ("🌳", "Tree"), ("🌵", "Cactus"), ("🍄", "Mushroom"), ("🌏", "Globe"),
+    ("🌙", "Moon"), ("☁️", "Cloud"), ("🔥", "Fire"), ("🍌", "Banana"),
+    ("🍎", "Apple"), ("🍓", "Strawberry"), ("🌽", "Corn"), ("🍕", "Pizza"),
+    ("🎂", "Cake"), ("❤️", "Heart"), ("😀", "Smiley"), ("🤖", "Robot"),
+    ("🎩", "Hat"), ("👓", "Glasses"), ("🔧", "Spanner"), ("🎅", "Santa"),
+    ("👍", "Thumbs Up"), ("☂️", "Umbrella"), ("⌛", "Hourglass"), ("⏰", "Clock"),
+    ("🎁", "Gift"), ("💡", "Light Bulb"), ("📕", "Book"), ("✏️", "Pencil"),
+    ("📎", "Paperclip"), ("✂️", "Scissors"), ("🔒", "Lock"), ("🔑", "Key"),
+    ("🔨", "Hammer"), ("☎️", "Telephone"), ("🏁", "Flag"), ("🚂", "Train"),
+    ("🚲", "Bicycle"), ("✈️", "Aeroplane"), ("🚀", "Rocket"), ("🏆", "Trophy"),
+    ("⚽", "Ball"), ("🎸", "Guitar"), ("🎺", "Trumpet"), ("🔔", "Bell"),
+    ("⚓", "Anchor"), ("🎧", "Headphones"), ("📁", "Folder"), ("📌", "Pin"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+pub struct SasEmoji {
+    pub symbol: String,
+    /// What to say aloud when comparing.
+    pub name: String,
+}
+
+/// Returned by `DerpNetwork.identityFingerprint`. Both ends of a pairing
+/// get the same `sas` and `emoji`, whichever key they pass as local; any
+/// difference means someone in between substituted a key.
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct IdentityFingerprint {
+    /// Fingerprint of the local key, e.g. "3f2a 9c41 ...".
+    pub local: String,
+    pub remote: String,
+    /// Short authentication string over both keys, as three hex groups.
+    pub sas: String,
+    pub emoji: Vec<SasEmoji>,
+}
+
+impl IdentityFingerprint {
+    pub fn new(local_key: &[u8], remote_key: &[u8]) -> DerpResult<IdentityFingerprint> {
+        if local_key.is_empty() || remote_key.is_empty() {
+            return Err(DerpError::InvalidState("Fingerprints need both keys".into()));
+        }
+        let sas = short_auth_bytes(local_key, remote_key);
+        Ok(IdentityFingerprint {
+            local: fingerprint(local_key),
+            remote: fingerprint(remote_key),
+            sas: group(&hex_encode(&sas), 4).join("-"),
+            emoji: emoji(&sas),
+        })
+    }
+}
+
+/// A key's SHA-256, truncated and grouped for reading out.
+pub fn fingerprint(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    group(&hex_encode(&digest[..FINGERPRINT_BYTES]), 4).join(" ")
+}
+
+/// Hashes the two keys in a fixed order, so it doesn't matter which side
+/// calls itself local.
+fn short_auth_bytes(local_key: &[u8], remote_key: &[u8]) -> [u8; SAS_BYTES] {
+    let (first, second) = if local_key <= remote_key { (local_key, remote_key) } else { (remote_key, local_key) };
+    let digest = Sha256::new()
+        .chain_update(SAS_LABEL)
+        .chain_update((first.len() as u32).to_be_bytes())
+        .chain_update(first)
+        .chain_update(second)
+        .finalize();
+    let mut sas = [0u8; SAS_BYTES];
+    sas.copy_from_slice(&digest[..SAS_BYTES]);
+    sas
+}
+
+/// Splits the 48 bits into seven 6-bit emoji indices, dropping the last six.
+fn emoji(sas: &[u8; SAS_BYTES]) -> Vec<SasEmoji> {
+    let mut bits = [0u8; 8];
+    bits[2..].copy_from_slice(sas);
+    let bits = u64::from_be_bytes(bits);
+    (0..SAS_EMOJI)
+        .map(|i| {
+            let (symbol, name) = EMOJI[((bits >> (42 - 6 * i)) & 0x3f) as usize];
+            SasEmoji { symbol: symbol.into(), name: name.into() }
+        })
+        .collect()
+}
+
+fn group(hex: &str, size: usize) -> Vec<&str> {
+    (0..hex.len()).step_by(size).map(|i| &hex[i..(i + size).min(hex.len())]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_both_sides_agree() {
+        let alice = [1u8; 32];
+        let bob = [2u8; 32];
+        let mallory = [3u8; 32];
+
+        let ours = IdentityFingerprint::new(&alice, &bob).unwrap();
+        let theirs = IdentityFingerprint::new(&bob, &alice).unwrap();
+        assert_eq!((&ours.sas, &ours.emoji), (&theirs.sas, &theirs.emoji));
+        assert_eq!((&ours.local, &ours.remote), (&theirs.remote, &theirs.local));
+
+        let intercepted = IdentityFingerprint::new(&alice, &mallory).unwrap();
+        assert_ne!(ours.sas, intercepted.sas);
+        assert!(IdentityFingerprint::new(&alice, &[]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_formatting() {
+        let fingerprint = IdentityFingerprint::new(&[1u8; 32], &[2u8; 32]).unwrap();
+        assert_eq!(fingerprint.local.len(), 8 * 4 + 7);
+        assert_eq!(fingerprint.sas.len(), 3 * 4 + 2);
+        assert_eq!(fingerprint.emoji.len(), SAS_EMOJI);
+
+        assert_eq!(emoji(&[0; SAS_BYTES])[0].name, "Dog");
+        assert!(emoji(&[0xff; SAS_BYTES]).iter().all(|emoji| emoji.name == "Pin"));
+        assert_eq!(group("abcdef", 4), vec!["abcd", "ef"]);
+    }
+}
@@ -0,0 +1,332 @@
+use serde::{Serialize, Deserialize};
+use std::net::IpAddr;
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+use crate::ip::{Ipv4Packet, Ipv6Packet, PROTO_TCP, PROTO_UDP};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Which way a frame crosses the VM boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent by the guest.
+    Outbound,
+    /// Delivered to the guest.
+    Inbound,
+}
+
+/// Matches frames on every field that is set. Addresses and ports are those
+/// of the far end: the destination of outbound traffic and the source of
+/// inbound traffic, so one rule also covers the replies to what it allows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct FirewallRule {
+    pub action: Action,
+    /// Unset matches both directions.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub direction: Option<Direction>,
+    #[serde(default)]
+    #[tsify(optional)]
+    pub ethertype: Option<u16>,
+    /// IPv4 or IPv6 range in CIDR notation, e.g. "10.0.0.0/8".
+    #[serde(default)]
+    #[tsify(optional)]
+    pub remote: Option<String>,
+    /// IP protocol number, e.g. 6 for TCP.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub protocol: Option<u8>,
+    /// Inclusive TCP or UDP port range.
+    #[serde(default)]
+    #[tsify(optional, type = "[number, number]")]
+    pub ports: Option<(u16, u16)>,
+}
+
+/// Rules are tried in order and the first match decides; frames no rule
+/// matches get `defaultAction`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(from_wasm_abi)]
+pub struct FirewallConfig {
+    #[serde(default = "default_action")]
+    #[tsify(optional)]
+    pub default_action: Action,
+    #[serde(default)]
+    #[tsify(optional)]
+    pub rules: Vec<FirewallRule>,
+}
+
+fn default_action() -> Action {
+    Action::Allow
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        FirewallConfig {
+            default_action: default_action(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// How many frames each rule decided, in rule order, and how many fell
+/// through to the default action.
+#[derive(Debug, Clone, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct FirewallHits {
+    pub rules: Vec<u64>,
+    pub default_action: u64,
+}
+
+struct Rule {
+    action: Action,
+    direction: Option<Direction>,
+    ethertype: Option<u16>,
+    remote: Option<(IpAddr, u8)>,
+    protocol: Option<u8>,
+    ports: Option<(u16, u16)>,
+    hits: u64,
+}
+
+/// The fields of a frame rules can match on.
+struct FrameInfo {
+    ethertype: u16,
+    remote: Option<IpAddr>,
+    protocol: Option<u8>,
+    remote_port: Option<u16>,
+}
+
+/// Stateless packet filter for frames crossing the VM boundary.
+pub struct Firewall {
+    /// Kept so the rules can be saved with the rest of the adapter's state.
+    config: FirewallConfig,
+    rules: Vec<Rule>,
+    default_action: Action,
+    default_hits: u64,
+}
+
+/// Allows everything.
+impl Default for Firewall {
+    fn default() -> Self {
+        Firewall {
+            config: FirewallConfig::default(),
+            rules: Vec::new(),
+            default_action: Action::Allow,
+            default_hits: 0,
+        }
+    }
+}
+
+impl Firewall {
+    pub fn new(config: &FirewallConfig) -> DerpResult<Firewall> {
+        let rules = config.rules.iter()
+            .map(|rule| {
+                let remote = match &rule.remote {
+                    Some(range) => Some(parse_range(range)
+                        .ok_or_else(|| DerpError::InvalidState(format!("Invalid firewall range: {}", range)))?),
+                    None => None,
+                };
+                Ok(Rule {
+                    action: rule.action,
+                    direction: rule.direction,
+                    ethertype: rule.ethertype,
+                    remote,
+                    protocol: rule.protocol,
+                    ports: rule.ports,
+                    hits: 0,
+                })
+            })
+            .collect::<DerpResult<Vec<_>>>()?;
+
+        Ok(Firewall {
+            config: config.clone(),
+            rules,
+            default_action: config.default_action,
+            default_hits: 0,
+        })
+    }
+
+    /// Whether a frame with this ethertype and payload may cross.
+    pub fn allows(&mut self, direction: Direction, ethertype: u16, payload: &[u8]) -> bool {
+        // Nothing to decide, and nothing worth counting
+        if self.rules.is_empty() && self.default_action == Action::Allow {
+            return true;
+        }
+
+        let frame = FrameInfo::parse(direction, ethertype, payload);
+        match self.rules.iter_mut().find(|rule| rule.matches(direction, &frame)) {
+            Some(rule) => {
+                rule.hits += 1;
+                rule.action == Action::Allow
+            }
+            None => {
+                self.default_hits += 1;
+                self.default_action == Action::Allow
+            }
+        }
+    }
+
+    pub fn hits(&self) -> FirewallHits {
+        FirewallHits {
+            rules: self.rules.iter().map(|rule| rule.hits).collect(),
+            default_action: self.default_hits,
+        }
+    }
+
+    pub fn config(&self) -> &FirewallConfig {
+        &self.config
+    }
+
+    /// Carries counts over from `hits()` of a firewall with the same rules.
+    pub fn restore_hits(&mut self, hits: &FirewallHits) {
+        for (rule, &count) in self.rules.iter_mut().zip(&hits.rules) {
+            rule.hits = count;
+        }
+        self.default_hits = hits.default_action;
+    }
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, frame: &FrameInfo) -> bool {
+        if self.direction.is_some_and(|wanted| wanted != direction) {
+            return false;
+        }
+        if self.ethertype.is_some_and(|wanted| wanted != frame.ethertype) {
+            return false;
+        }
+        if let Some((network, prefix)) = self.remote {
+            if !frame.remote.is_some_and(|address| in_range(address, network, prefix)) {
+                return false;
+            }
+        }
+        if self.protocol.is_some() && self.protocol != frame.protocol {
+            return false;
+        }
+        if let Some((low, high)) = self.ports {
+            if !frame.remote_port.is_some_and(|port| (low..=high).contains(&port)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl FrameInfo {
+    fn parse(direction: Direction, ethertype: u16, payload: &[u8]) -> FrameInfo {
+        let mut frame = FrameInfo { ethertype, remote: None, protocol: None, remote_port: None };
+        let (src, dst, protocol, transport) = match ethertype {
+            ETHERTYPE_IPV4 => match Ipv4Packet::parse(payload) {
+                Some(ip) => (IpAddr::V4(ip.src), IpAddr::V4(ip.dst), ip.protocol, ip.payload),
+                None => return frame,
+            },
+            ETHERTYPE_IPV6 => match Ipv6Packet::parse(payload) {
+                Some(ip) => (IpAddr::V6(ip.src), IpAddr::V6(ip.dst), ip.next_header, ip.payload),
+                None => return frame,
+            },
+            _ => return frame,
+        };
+
+        let outbound = direction == Direction::Outbound;
+        frame.remote = Some(if outbound { dst } else { src });
+        frame.protocol = Some(protocol);
+        if (protocol == PROTO_TCP || protocol == PROTO_UDP) && transport.len() >= 4 {
+            let at = if outbound { 2 } else { 0 };
+            frame.remote_port = Some(u16::from_be_bytes([transport[at], transport[at + 1]]));
+        }
+        frame
+    }
+}
+
+fn parse_range(text: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match text.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (text.parse::<IpAddr>().ok()?, None),
+    };
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((address, prefix))
+}
+
+fn in_range(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::ip;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GUEST: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 100);
+
+    fn rule(action: Action) -> FirewallRule {
+        FirewallRule { action, direction: None, ethertype: None, remote: None, protocol: None, ports: None }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_first_match_wins_in_both_directions() {
+        let config = FirewallConfig {
+            default_action: Action::Deny,
+            rules: vec![
+                FirewallRule { remote: Some("10.0.0.0/8".into()), ..rule(Action::Deny) },
+                FirewallRule { protocol: Some(PROTO_UDP), ports: Some((53, 53)), ..rule(Action::Allow) },
+            ],
+        };
+        let mut firewall = Firewall::new(&config).unwrap();
+        let server = Ipv4Addr::new(9, 9, 9, 9);
+
+        let query = ip::build_udp(GUEST, server, 40000, 53, b"query");
+        let answer = ip::build_udp(server, GUEST, 53, 40000, b"answer");
+        let blocked = ip::build_udp(GUEST, Ipv4Addr::new(10, 1, 2, 3), 40000, 53, b"query");
+        assert!(firewall.allows(Direction::Outbound, ETHERTYPE_IPV4, &query));
+        assert!(firewall.allows(Direction::Inbound, ETHERTYPE_IPV4, &answer));
+        assert!(!firewall.allows(Direction::Outbound, ETHERTYPE_IPV4, &blocked));
+        assert!(!firewall.allows(Direction::Outbound, 0x0806, &[0; 28]));
+
+        let hits = firewall.hits();
+        assert_eq!(hits.rules, vec![1, 2]);
+        assert_eq!(hits.default_action, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_direction_and_invalid_range() {
+        let config = FirewallConfig {
+            default_action: Action::Allow,
+            rules: vec![FirewallRule { direction: Some(Direction::Inbound), ..rule(Action::Deny) }],
+        };
+        let mut firewall = Firewall::new(&config).unwrap();
+        let packet = ip::build_udp(GUEST, Ipv4Addr::new(9, 9, 9, 9), 40000, 53, b"query");
+        assert!(firewall.allows(Direction::Outbound, ETHERTYPE_IPV4, &packet));
+        assert!(!firewall.allows(Direction::Inbound, ETHERTYPE_IPV4, &packet));
+
+        let invalid = FirewallConfig {
+            rules: vec![FirewallRule { remote: Some("10.0.0.0/33".into()), ..rule(Action::Deny) }],
+            ..FirewallConfig::default()
+        };
+        assert!(Firewall::new(&invalid).is_err());
+    }
+}
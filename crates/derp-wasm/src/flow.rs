@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use crate::ip::{Ipv4Packet, PROTO_TCP, PROTO_UDP};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const TCP_FIN: u8 = 0x01;
+const TCP_RST: u8 = 0x04;
+
+const IDLE_TIMEOUT_MS: f64 = 60_000.0;
+const CLOSING_TIMEOUT_MS: f64 = 5_000.0;
+
+/// What a packet on the send path starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// A VM's Ethernet frame.
+    Ethernet,
+    /// A bare IP packet, as routed VMs send them.
+    Ip,
+}
+
+impl Framing {
+    /// The IPv4 packet in `packet`, if it carries one.
+    fn ipv4(self, packet: &[u8]) -> Option<Ipv4Packet<'_>> {
+        match self {
+            Framing::Ethernet if packet.get(12..ETHERNET_HEADER_LEN)? == ETHERTYPE_IPV4.to_be_bytes() => {
+                Ipv4Packet::parse(&packet[ETHERNET_HEADER_LEN..])
+            }
+            Framing::Ethernet => None,
+            Framing::Ip => Ipv4Packet::parse(packet),
+        }
+    }
+
+    /// The VM that sent `packet`. Bare IP packets don't say.
+    fn source_mac(self, packet: &[u8]) -> Option<[u8; 6]> {
+        match self {
+            Framing::Ethernet => packet.get(6..12)?.try_into().ok(),
+            Framing::Ip => None,
+        }
+    }
+}
+
+/// 5-tuple identifying a guest flow on the send path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub src: [u8; 4],
+    pub dst: [u8; 4],
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    /// Parses the flow key out of a raw IPv4 packet. Ports are zero for
+    /// protocols other than TCP and UDP.
+    pub fn from_ipv4(packet: &[u8]) -> Option<FlowKey> {
+        FlowKey::from_parsed(&Ipv4Packet::parse(packet)?)
+    }
+
+    /// Parses the flow key out of a packet on the send path.
+    pub fn from_packet(framing: Framing, packet: &[u8]) -> Option<FlowKey> {
+        FlowKey::from_parsed(&framing.ipv4(packet)?)
+    }
+
+    fn from_parsed(packet: &Ipv4Packet) -> Option<FlowKey> {
+        let (src_port, dst_port) = match packet.protocol {
+            PROTO_TCP | PROTO_UDP => {
+                let ports = packet.payload.get(..4)?;
+                (
+                    u16::from_be_bytes([ports[0], ports[1]]),
+                    u16::from_be_bytes([ports[2], ports[3]]),
+                )
+            }
+            _ => (0, 0),
+        };
+
+        Some(FlowKey {
+            protocol: packet.protocol,
+            src: packet.src.octets(),
+            dst: packet.dst.octets(),
+            src_port,
+            dst_port,
+        })
+    }
+}
+
+fn tcp_flags(packet: &Ipv4Packet) -> u8 {
+    match packet.protocol {
+        PROTO_TCP => packet.payload.get(13).copied().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+struct FlowEntry {
+    last_seen_ms: f64,
+    closing: bool,
+    /// The VM the flow's frames come from.
+    guest: Option<[u8; 6]>,
+}
+
+/// Tracks which guest flows are currently active so draining can let them
+/// finish while refusing new ones.
+pub struct FlowTable {
+    flows: HashMap<FlowKey, FlowEntry>,
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        FlowTable {
+            flows: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, key: &FlowKey) -> bool {
+        self.flows.contains_key(key)
+    }
+
+    /// Whether `packet` may go out while draining: it belongs to a known
+    /// flow or, having none, like ARP or IPv6, comes from a VM that still
+    /// has one. Everything from VMs without flows is refused.
+    pub fn admits(&self, framing: Framing, packet: &[u8]) -> bool {
+        match (FlowKey::from_packet(framing, packet), framing.source_mac(packet)) {
+            (Some(key), _) => self.contains(&key),
+            (None, Some(mac)) => self.flows.values().any(|entry| entry.guest == Some(mac)),
+            (None, None) => false,
+        }
+    }
+
+    /// Records a packet belonging to `key`. TCP RST ends the flow immediately,
+    /// FIN moves it to a short closing timeout.
+    pub fn track(&mut self, key: FlowKey, framing: Framing, packet: &[u8], now_ms: f64) {
+        let flags = framing.ipv4(packet).map_or(0, |packet| tcp_flags(&packet));
+
+        if flags & TCP_RST != 0 {
+            self.flows.remove(&key);
+            return;
+        }
+
+        let entry = self.flows.entry(key).or_insert(FlowEntry {
+            last_seen_ms: now_ms,
+            closing: false,
+            guest: framing.source_mac(packet),
+        });
+        entry.last_seen_ms = now_ms;
+        entry.closing |= flags & TCP_FIN != 0;
+    }
+
+    pub fn expire(&mut self, now_ms: f64) {
+        self.flows.retain(|_, entry| {
+            let timeout = if entry.closing { CLOSING_TIMEOUT_MS } else { IDLE_TIMEOUT_MS };
+            now_ms - entry.last_seen_ms < timeout
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+impl Default for FlowTable {
+    fn default() -> Self {
+        FlowTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn tcp_packet(flags: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45; // IPv4, 20 byte header
+        packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+        packet[9] = PROTO_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 2, 15]);
+        packet[16..20].copy_from_slice(&[93, 184, 216, 34]);
+        packet[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&80u16.to_be_bytes());
+        packet[33] = flags;
+        packet
+    }
+
+    #[wasm_bindgen_test]
+    fn test_flow_key_parsing() {
+        let key = FlowKey::from_ipv4(&tcp_packet(0)).unwrap();
+        assert_eq!(key.protocol, PROTO_TCP);
+        assert_eq!(key.src, [10, 0, 2, 15]);
+        assert_eq!(key.src_port, 40000);
+        assert_eq!(key.dst_port, 80);
+
+        assert!(FlowKey::from_ipv4(&[0u8; 10]).is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_flow_lifecycle() {
+        let mut table = FlowTable::new();
+        let packet = tcp_packet(0);
+        let key = FlowKey::from_ipv4(&packet).unwrap();
+
+        table.track(key, Framing::Ip, &packet, 0.0);
+        assert!(table.contains(&key));
+
+        // FIN shortens the timeout
+        table.track(key, Framing::Ip, &tcp_packet(TCP_FIN), 1000.0);
+        table.expire(1000.0 + CLOSING_TIMEOUT_MS);
+        assert!(table.is_empty());
+
+        // RST removes the flow immediately
+        table.track(key, Framing::Ip, &packet, 0.0);
+        table.track(key, Framing::Ip, &tcp_packet(TCP_RST), 1.0);
+        assert!(!table.contains(&key));
+    }
+
+    fn frame(src_mac: u8, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xFF; 6];
+        frame.extend_from_slice(&[0x52, 0x54, 0, 0, 0, src_mac]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[wasm_bindgen_test]
+    fn test_draining_admits_only_active_guests() {
+        let mut table = FlowTable::new();
+        let packet = frame(1, ETHERTYPE_IPV4, &tcp_packet(0));
+        let key = FlowKey::from_packet(Framing::Ethernet, &packet).unwrap();
+        assert_eq!(key, FlowKey::from_ipv4(&tcp_packet(0)).unwrap());
+        assert_eq!(FlowKey::from_packet(Framing::Ip, &packet), None);
+
+        assert!(!table.admits(Framing::Ethernet, &packet));
+        table.track(key, Framing::Ethernet, &packet, 0.0);
+        assert!(table.admits(Framing::Ethernet, &packet));
+        assert!(table.admits(Framing::Ip, &tcp_packet(0)));
+
+        // ARP and IPv6 only from the VM whose flow is still open
+        let arp = [0u8; 28];
+        assert!(table.admits(Framing::Ethernet, &frame(1, 0x0806, &arp)));
+        assert!(!table.admits(Framing::Ethernet, &frame(2, 0x0806, &arp)));
+        assert!(!table.admits(Framing::Ethernet, &frame(2, 0x86DD, &[0u8; 40])));
+        assert!(!table.admits(Framing::Ethernet, &frame(2, ETHERTYPE_IPV4, &tcp_packet(0)[..10])));
+        assert!(!table.admits(Framing::Ip, &[0x60; 40]));
+    }
+}
@@ -0,0 +1,195 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{Function, Uint8Array};
+use std::cell::RefCell;
+use std::rc::Rc;
+use web_sys::{MessageEvent, MessagePort};
+use crate::error::DerpError;
+use crate::nat::{NatHandle, NatStream};
+
+/// Connects a forwarded guest connection to a MessagePort. Data travels as
+/// Uint8Arrays in both directions (ArrayBuffers and strings are accepted
+/// from the page too), and a `null` message closes the stream.
+pub struct PortStream {
+    port: MessagePort,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl PortStream {
+    pub fn new(port: MessagePort, handle: &NatHandle) -> Self {
+        let handle = handle.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            match message_bytes(&event.data()) {
+                Some(data) => handle.send(&data),
+                None => handle.close(),
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        // Assigning onmessage also starts the port
+        port.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        PortStream { port, _on_message: on_message }
+    }
+}
+
+impl NatStream for PortStream {
+    fn on_data(&mut self, data: &[u8], _handle: &NatHandle) {
+        if let Err(e) = self.port.post_message(&Uint8Array::from(data)) {
+            log::warn!("Failed to post forwarded data: {:?}", e);
+        }
+    }
+
+    fn on_close(&mut self, _handle: &NatHandle) {
+        let _ = self.port.post_message(&JsValue::NULL);
+    }
+}
+
+impl Drop for PortStream {
+    fn drop(&mut self) {
+        // The closure is about to be freed, so the port must stop calling it
+        self.port.set_onmessage(None);
+        self.port.close();
+    }
+}
+
+/// A TCP connection from the gateway to a service in the guest, returned by
+/// `VmNetwork.connectToGuest`, for pages that speak SSH, VNC or Redis to
+/// the VM themselves. Data the guest sends before `ondata` is set is held
+/// for the listener.
+#[wasm_bindgen]
+pub struct GuestConnection {
+    handle: NatHandle,
+    state: Rc<RefCell<GuestState>>,
+}
+
+#[derive(Default)]
+struct GuestState {
+    on_data: Option<Function>,
+    on_close: Option<Function>,
+    held: Vec<u8>,
+    /// The guest's side has closed.
+    guest_closed: bool,
+    /// `close` was called.
+    closed: bool,
+}
+
+#[wasm_bindgen]
+impl GuestConnection {
+    /// Queues a Uint8Array or string for the guest.
+    pub fn send(&self, data: &JsValue) -> Result<(), JsValue> {
+        if self.state.borrow().closed {
+            return Err(DerpError::InvalidState("Connection is closed".into()).into());
+        }
+        if let Some(data) = message_bytes(data) {
+            self.handle.send(&data);
+        }
+        Ok(())
+    }
+
+    /// Closes the connection once what was sent has been delivered.
+    pub fn close(&self) {
+        let mut state = self.state.borrow_mut();
+        if !state.closed {
+            state.closed = true;
+            self.handle.close();
+        }
+    }
+
+    /// Called with each chunk from the guest as a Uint8Array; null stops
+    /// listening.
+    #[wasm_bindgen(setter)]
+    pub fn set_ondata(&self, listener: Option<Function>) {
+        let held = {
+            let mut state = self.state.borrow_mut();
+            state.on_data = listener.clone();
+            std::mem::take(&mut state.held)
+        };
+        match listener {
+            Some(listener) if !held.is_empty() => deliver(&listener, &held),
+            Some(_) => {}
+            None => self.state.borrow_mut().held = held,
+        }
+    }
+
+    /// Called once the guest has closed its side, or the connection was
+    /// reset or timed out.
+    #[wasm_bindgen(setter)]
+    pub fn set_onclose(&self, listener: Option<Function>) {
+        self.state.borrow_mut().on_close = listener;
+    }
+
+    /// Whether the guest has closed its side.
+    #[wasm_bindgen(getter, js_name = guestClosed)]
+    pub fn guest_closed(&self) -> bool {
+        self.state.borrow().guest_closed
+    }
+}
+
+impl GuestConnection {
+    /// The connection and the stream the gateway drives it with.
+    pub fn open(handle: &NatHandle) -> (GuestConnection, Box<dyn NatStream>) {
+        let state = Rc::new(RefCell::new(GuestState::default()));
+        let stream = GuestStream { state: state.clone() };
+        (GuestConnection { handle: handle.clone(), state }, Box::new(stream))
+    }
+}
+
+struct GuestStream {
+    state: Rc<RefCell<GuestState>>,
+}
+
+impl NatStream for GuestStream {
+    fn on_data(&mut self, data: &[u8], _handle: &NatHandle) {
+        // The listener may call back into the connection, so no borrow is held
+        let listener = self.state.borrow().on_data.clone();
+        match listener {
+            Some(listener) => deliver(&listener, data),
+            None => self.state.borrow_mut().held.extend_from_slice(data),
+        }
+    }
+
+    fn on_close(&mut self, _handle: &NatHandle) {
+        let listener = {
+            let mut state = self.state.borrow_mut();
+            state.guest_closed = true;
+            state.on_close.clone()
+        };
+        if let Some(listener) = listener {
+            if let Err(e) = listener.call0(&JsValue::NULL) {
+                log::warn!("Guest connection close listener threw: {:?}", e);
+            }
+        }
+    }
+}
+
+fn deliver(listener: &Function, data: &[u8]) {
+    if let Err(e) = listener.call1(&JsValue::NULL, &Uint8Array::from(data)) {
+        log::warn!("Guest connection data listener threw: {:?}", e);
+    }
+}
+
+/// The bytes a message from the page carries, or None if it asks to close.
+fn message_bytes(data: &JsValue) -> Option<Vec<u8>> {
+    if data.is_null() || data.is_undefined() {
+        return None;
+    }
+    if let Some(text) = data.as_string() {
+        return Some(text.into_bytes());
+    }
+    Some(Uint8Array::new(data).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_message_bytes() {
+        assert_eq!(message_bytes(&Uint8Array::from(&[1u8, 2, 3][..]).into()), Some(vec![1, 2, 3]));
+        assert_eq!(message_bytes(&Uint8Array::from(&[4u8][..]).buffer().into()), Some(vec![4]));
+        assert_eq!(message_bytes(&"ls\n".into()), Some(b"ls\n".to_vec()));
+        assert_eq!(message_bytes(&JsValue::NULL), None);
+    }
+}
@@ -0,0 +1,242 @@
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use crate::error::{DerpError, DerpResult};
+
+pub const GROUP_KEY_LEN: usize = 32;
+pub const MEMBER_KEY_LEN: usize = 32;
+/// The sender's signing key and the message's number in its sequence.
+pub const HEADER_LEN: usize = MEMBER_KEY_LEN + 8;
+/// Header, nonce, tag and signature.
+pub const OVERHEAD: usize = HEADER_LEN + NONCE_LEN + TAG_LEN + SIGNATURE_LENGTH;
+
+const CIPHER_INFO: &[u8] = b"derp-group-cipher";
+const ID_LABEL: &[u8] = b"derp-group-id";
+const GROUP_ID_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+type MemberKey = [u8; MEMBER_KEY_LEN];
+
+/// A broadcast group whose members share one 32-byte key, handed out by
+/// the coordination server or agreed beforehand. Each broadcast is
+/// encrypted once for every member, instead of once per peer session.
+///
+/// The shared key only proves a message came from someone in the group, so
+/// each member also signs what it sends with its own Ed25519 key. Messages
+/// are accepted only from members added with `add_member`, and each
+/// sender's sequence numbers must rise, so a member can neither pose as
+/// another nor replay what another sent.
+pub struct GroupSession {
+    group_id: [u8; GROUP_ID_LEN],
+    cipher: Aes256Gcm,
+    signing_key: SigningKey,
+    next_seq: u64,
+    /// Each member's verifying key, with the last sequence number heard.
+    members: HashMap<MemberKey, (VerifyingKey, Option<u64>)>,
+}
+
+impl GroupSession {
+    /// Joins the group keyed by `group_key`, signing with the 32-byte
+    /// `signing_seed` if given, so the member key others were told stays
+    /// the same, and with a fresh key otherwise.
+    pub fn new(group_key: &[u8], signing_seed: Option<&[u8]>) -> DerpResult<GroupSession> {
+        let group_key: [u8; GROUP_KEY_LEN] = group_key.try_into()
+            .map_err(|_| DerpError::CryptoError(format!("Group keys are {} bytes", GROUP_KEY_LEN)))?;
+        let seed = match signing_seed {
+            Some(seed) => parse_member_key(seed)?,
+            None => random_seed()?,
+        };
+
+        let mut cipher_key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &group_key)
+            .expand(CIPHER_INFO, &mut cipher_key)
+            .expect("32 bytes is a valid HKDF-SHA256 length");
+        let digest = Sha256::new().chain_update(ID_LABEL).chain_update(group_key).finalize();
+        let mut group_id = [0u8; GROUP_ID_LEN];
+        group_id.copy_from_slice(&digest[..GROUP_ID_LEN]);
+
+        Ok(GroupSession {
+            group_id,
+            cipher: Aes256Gcm::new_from_slice(&cipher_key).expect("32-byte key"),
+            signing_key: SigningKey::from_bytes(&seed),
+            next_seq: 0,
+            members: HashMap::new(),
+        })
+    }
+
+    /// This member's verifying key, for the others to `add_member`.
+    pub fn public_key(&self) -> MemberKey {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Accepts messages signed by `key`. Returns false if it already was.
+    pub fn add_member(&mut self, key: &[u8]) -> DerpResult<bool> {
+        let bytes = parse_member_key(key)?;
+        let verifying_key = VerifyingKey::from_bytes(&bytes)
+            .map_err(|_| DerpError::CryptoError("Not an Ed25519 public key".into()))?;
+        Ok(self.members.insert(bytes, (verifying_key, None)).is_none())
+    }
+
+    pub fn remove_member(&mut self, key: &[u8]) -> bool {
+        match parse_member_key(key) {
+            Ok(bytes) => self.members.remove(&bytes).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Encrypts and signs `plaintext` for every member, authenticating
+    /// `aad` alongside it.
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to generate a nonce: {}", e)))?;
+
+        let mut message = Vec::with_capacity(plaintext.len() + OVERHEAD);
+        message.extend_from_slice(&self.public_key());
+        message.extend_from_slice(&seq.to_be_bytes());
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload {
+                msg: plaintext,
+                aad: &self.associated_data(&message, aad),
+            })
+            .map_err(|_| DerpError::CryptoError("Group encryption failed".into()))?;
+        message.extend_from_slice(&nonce);
+        message.extend_from_slice(&ciphertext);
+
+        let signature = self.signing_key.sign(&self.signed_data(&message));
+        message.extend_from_slice(&signature.to_bytes());
+        Ok(message)
+    }
+
+    /// Checks a member's message and decrypts it, returning the sender's
+    /// key with the plaintext.
+    pub fn decrypt(&mut self, message: &[u8], aad: &[u8]) -> DerpResult<(MemberKey, Vec<u8>)> {
+        if message.len() < OVERHEAD {
+            return Err(DerpError::CryptoError("Group message too short".into()));
+        }
+        let (signed, signature) = message.split_at(message.len() - SIGNATURE_LENGTH);
+        let (header, sealed) = signed.split_at(HEADER_LEN);
+        let (sender, seq) = header.split_at(MEMBER_KEY_LEN);
+        let sender = parse_member_key(sender)?;
+        let seq = u64::from_be_bytes(seq.try_into().expect("header holds a u64"));
+
+        let signed_data = self.signed_data(signed);
+        let (verifying_key, last_seq) = self.members.get_mut(&sender)
+            .ok_or_else(|| DerpError::CryptoError("Group message from a non-member".into()))?;
+        let signature = Signature::from_slice(signature)
+            .map_err(|_| DerpError::CryptoError("Malformed group signature".into()))?;
+        verifying_key.verify(&signed_data, &signature)
+            .map_err(|_| DerpError::CryptoError("Group message signature doesn't verify".into()))?;
+        if last_seq.is_some_and(|last| seq <= last) {
+            return Err(DerpError::CryptoError("Replayed group message".into()));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload {
+                msg: ciphertext,
+                aad: &self.associated_data(header, aad),
+            })
+            .map_err(|_| DerpError::CryptoError("Group decryption failed".into()))?;
+
+        // Only counted once it decrypts, so a forgery can't burn numbers
+        if let Some((_, last_seq)) = self.members.get_mut(&sender) {
+            *last_seq = Some(seq);
+        }
+        Ok((sender, plaintext))
+    }
+
+    fn associated_data(&self, header: &[u8], aad: &[u8]) -> Vec<u8> {
+        [&self.group_id[..], header, aad].concat()
+    }
+
+    /// Signatures cover the group id too, so they don't carry over to
+    /// another group the sender is in.
+    fn signed_data(&self, message: &[u8]) -> Vec<u8> {
+        [&self.group_id[..], message].concat()
+    }
+}
+
+fn parse_member_key(key: &[u8]) -> DerpResult<MemberKey> {
+    key.try_into()
+        .map_err(|_| DerpError::CryptoError(format!("Group member keys are {} bytes", MEMBER_KEY_LEN)))
+}
+
+fn random_seed() -> DerpResult<MemberKey> {
+    let mut seed = [0u8; MEMBER_KEY_LEN];
+    getrandom::getrandom(&mut seed)
+        .map_err(|e| DerpError::CryptoError(format!("Failed to generate a signing key: {}", e)))?;
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GROUP_KEY: [u8; 32] = [9; 32];
+
+    fn group(size: usize) -> Vec<GroupSession> {
+        let mut members: Vec<_> = (0..size).map(|_| GroupSession::new(&GROUP_KEY, None).unwrap()).collect();
+        let keys: Vec<_> = members.iter().map(GroupSession::public_key).collect();
+        for member in &mut members {
+            for key in &keys {
+                if *key != member.public_key() {
+                    member.add_member(key).unwrap();
+                }
+            }
+        }
+        members
+    }
+
+    #[wasm_bindgen_test]
+    fn test_one_message_reaches_every_member() {
+        let mut members = group(3);
+        let sender = members[0].public_key();
+        let message = members[0].encrypt(b"hello", b"aad").unwrap();
+        for member in &mut members[1..] {
+            assert_eq!(member.decrypt(&message, b"aad").unwrap(), (sender, b"hello".to_vec()));
+        }
+
+        // Seen already, or tampered with
+        assert!(members[1].decrypt(&message, b"aad").is_err());
+        let mut tampered = members[0].encrypt(b"hello", b"aad").unwrap();
+        tampered[HEADER_LEN + NONCE_LEN] ^= 1;
+        assert!(members[2].decrypt(&tampered, b"aad").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_outsiders_are_refused() {
+        let mut members = group(2);
+
+        // Holds the group key, but nobody added its signing key
+        let mut outsider = GroupSession::new(&GROUP_KEY, None).unwrap();
+        let message = outsider.encrypt(b"hi", &[]).unwrap();
+        assert!(members[1].decrypt(&message, &[]).is_err());
+
+        // A member's key in the header doesn't help without its signature
+        let mut forged = message.clone();
+        forged[..MEMBER_KEY_LEN].copy_from_slice(&members[0].public_key());
+        assert!(members[1].decrypt(&forged, &[]).is_err());
+
+        // Nor does a member's signature from another group
+        let mut elsewhere = GroupSession::new(&[1; 32], Some(&[5; 32])).unwrap();
+        let mut member = GroupSession::new(&GROUP_KEY, Some(&[5; 32])).unwrap();
+        member.add_member(&members[1].public_key()).unwrap();
+        members[1].add_member(&member.public_key()).unwrap();
+        assert!(members[1].decrypt(&elsewhere.encrypt(b"hi", &[]).unwrap(), &[]).is_err());
+        assert!(members[1].decrypt(&member.encrypt(b"hi", &[]).unwrap(), &[]).is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+use crate::fetch::{HttpRequest, HTTP_PORT, MAX_REQUEST_SIZE};
+use crate::flow::FlowKey;
+use crate::ip::PROTO_TCP;
+use crate::nat::{Egress, NatBackend, NatHandle, NatStream};
+
+/// Minimal HTTP/1.1 server at a virtual address, serving files the page
+/// hands over, so the guest can `wget http://<address>/<name>`. Only GET
+/// and HEAD are supported, one request per connection. "/" lists the files.
+pub struct HttpFileServer {
+    address: Ipv4Addr,
+    /// Shared with open connections, which see files added after they opened.
+    files: Rc<RefCell<Files>>,
+}
+
+type Files = BTreeMap<String, Rc<[u8]>>;
+
+impl HttpFileServer {
+    pub fn new(address: Ipv4Addr) -> Self {
+        HttpFileServer {
+            address,
+            files: Rc::new(RefCell::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// Serves `data` at `/name`; a leading slash on `name` is optional.
+    pub fn add_file(&self, name: &str, data: Vec<u8>) {
+        self.files.borrow_mut().insert(name.trim_start_matches('/').to_string(), data.into());
+    }
+
+    pub fn remove_file(&self, name: &str) -> bool {
+        self.files.borrow_mut().remove(name.trim_start_matches('/')).is_some()
+    }
+}
+
+/// The whole response to `request`.
+fn respond(files: &Files, request: &HttpRequest) -> Vec<u8> {
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return response(405, "Method Not Allowed", "Allow: GET, HEAD\r\n", b"", false),
+    };
+
+    let path = request.target.split(['?', '#']).next().unwrap_or_default();
+    let Some(name) = percent_decode(path.trim_start_matches('/')) else {
+        return response(400, "Bad Request", "", b"", head_only);
+    };
+    if name.is_empty() {
+        let listing: String = files.keys().map(|name| format!("{}\n", name)).collect();
+        return response(200, "OK", "Content-Type: text/plain; charset=utf-8\r\n", listing.as_bytes(), head_only);
+    }
+    match files.get(&name) {
+        Some(data) => response(200, "OK", "Content-Type: application/octet-stream\r\n", data, head_only),
+        None => response(404, "Not Found", "", b"", head_only),
+    }
+}
+
+impl NatBackend for HttpFileServer {
+    fn open(&self, flow: &FlowKey) -> Option<Egress> {
+        if flow.protocol != PROTO_TCP || flow.dst_port != HTTP_PORT || Ipv4Addr::from(flow.dst) != self.address {
+            return None;
+        }
+        Some(Egress::Stream(Box::new(FileStream {
+            files: self.files.clone(),
+            buffer: Vec::new(),
+            answered: false,
+        })))
+    }
+}
+
+/// One guest connection, answered once its request is complete.
+struct FileStream {
+    files: Rc<RefCell<Files>>,
+    buffer: Vec<u8>,
+    answered: bool,
+}
+
+impl NatStream for FileStream {
+    fn on_data(&mut self, data: &[u8], handle: &NatHandle) {
+        if self.answered {
+            return;
+        }
+
+        self.buffer.extend_from_slice(data);
+        let reply = match HttpRequest::parse(&self.buffer) {
+            Ok(Some(request)) => respond(&self.files.borrow(), &request),
+            Ok(None) if self.buffer.len() <= MAX_REQUEST_SIZE => return,
+            Ok(None) => response(413, "Payload Too Large", "", b"", false),
+            Err(_) => response(400, "Bad Request", "", b"", false),
+        };
+        self.answered = true;
+        handle.send(&reply);
+        handle.close();
+    }
+}
+
+/// A response with a Content-Length, closing the connection after it.
+/// `head_only` leaves out the body but not its length, as HEAD requires.
+fn response(status: u16, reason: &str, headers: &str, body: &[u8], head_only: bool) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason, headers, body.len(),
+    ).into_bytes();
+    if !head_only {
+        response.extend_from_slice(body);
+    }
+    response
+}
+
+/// Decodes %XX escapes, or None if one is malformed or the result isn't UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn get(server: &HttpFileServer, head: &str) -> Vec<u8> {
+        let request = HttpRequest::parse(head.as_bytes()).unwrap().unwrap();
+        respond(&server.files.borrow(), &request)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_serves_registered_files() {
+        let server = HttpFileServer::new(Ipv4Addr::new(192, 168, 86, 2));
+        server.add_file("/my file.bin", vec![1, 2, 3]);
+
+        let ok = get(&server, "GET /my%20file.bin?x=1 HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(ok.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with(b"Content-Length: 3\r\nConnection: close\r\n\r\n\x01\x02\x03"));
+        let head = get(&server, "HEAD /my%20file.bin HTTP/1.1\r\n\r\n");
+        assert!(head.ends_with(b"Content-Length: 3\r\nConnection: close\r\n\r\n"));
+
+        let listing = get(&server, "GET / HTTP/1.0\r\n\r\n");
+        assert!(listing.ends_with(b"\r\n\r\nmy file.bin\n"));
+        assert!(get(&server, "GET /other HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 404 "));
+        assert!(get(&server, "PUT /my%20file.bin HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 405 "));
+        assert!(get(&server, "GET /%zz HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 400 "));
+
+        assert!(server.remove_file("my file.bin"));
+        assert!(!server.remove_file("my file.bin"));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_claims_only_its_address() {
+        let server = HttpFileServer::new(Ipv4Addr::new(192, 168, 86, 2));
+        let flow = |dst, dst_port| FlowKey {
+            protocol: PROTO_TCP,
+            src: [192, 168, 86, 100],
+            dst,
+            src_port: 40000,
+            dst_port,
+        };
+
+        assert!(server.open(&flow([192, 168, 86, 2], HTTP_PORT)).is_some());
+        assert!(server.open(&flow([192, 168, 86, 2], 8080)).is_none());
+        assert!(server.open(&flow([93, 184, 216, 34], HTTP_PORT)).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::ip::{self, Ipv4Packet, Ipv6Packet, PROTO_ICMP, PROTO_ICMPV6};
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const ECHOV6_REQUEST: u8 = 128;
+const ECHOV6_REPLY: u8 = 129;
+
+/// Whether `packet` is an ICMP echo request addressed to `gateway`.
+pub fn is_echo_request(packet: &[u8], gateway: Ipv4Addr) -> bool {
+    Ipv4Packet::parse(packet).is_some_and(|ip| {
+        ip.protocol == PROTO_ICMP && ip.dst == gateway && ip.payload.first() == Some(&ECHO_REQUEST)
+    })
+}
+
+/// Whether `packet` is an ICMPv6 echo request for one of `addresses`.
+pub fn is_echo_request_v6(packet: &[u8], addresses: &[Ipv6Addr]) -> bool {
+    Ipv6Packet::parse(packet).is_some_and(|ip| {
+        ip.next_header == PROTO_ICMPV6 && addresses.contains(&ip.dst) && ip.payload.first() == Some(&ECHOV6_REQUEST)
+    })
+}
+
+/// Builds the reply to an echo request, echoing its identifier, sequence
+/// number and data. Returns None if the request's checksum is wrong.
+pub fn echo_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let ip = Ipv4Packet::parse(packet)?;
+    if ip.payload.len() < 8 || ip::checksum(ip.payload) != 0 {
+        return None;
+    }
+
+    let mut message = ip.payload.to_vec();
+    message[0] = ECHO_REPLY;
+    message[2..4].copy_from_slice(&[0, 0]);
+    let checksum = ip::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(ip::build_ipv4(ip.dst, ip.src, PROTO_ICMP, &message))
+}
+
+pub fn echo_reply_v6(packet: &[u8]) -> Option<Vec<u8>> {
+    let ip = Ipv6Packet::parse(packet)?;
+    if ip.payload.len() < 8 || ip::checksum(&ip::pseudo_header_v6(ip.src, ip.dst, PROTO_ICMPV6, ip.payload)) != 0 {
+        return None;
+    }
+
+    let mut message = ip.payload.to_vec();
+    message[0] = ECHOV6_REPLY;
+    message[2..4].copy_from_slice(&[0, 0]);
+    let checksum = ip::checksum(&ip::pseudo_header_v6(ip.dst, ip.src, PROTO_ICMPV6, &message));
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(ip::build_ipv6(ip.dst, ip.src, PROTO_ICMPV6, 64, &message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_echo_reply() {
+        let guest = Ipv4Addr::new(192, 168, 86, 100);
+        let gateway = Ipv4Addr::new(192, 168, 86, 1);
+        let mut message = vec![ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        let checksum = ip::checksum(&message);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let request = ip::build_ipv4(guest, gateway, PROTO_ICMP, &message);
+
+        assert!(is_echo_request(&request, gateway));
+        assert!(!is_echo_request(&request, guest));
+
+        let reply = echo_reply(&request).unwrap();
+        let ip = Ipv4Packet::parse(&reply).unwrap();
+        assert_eq!((ip.src, ip.dst), (gateway, guest));
+        assert_eq!(ip.payload[0], ECHO_REPLY);
+        assert_eq!(&ip.payload[4..], &message[4..]);
+        assert_eq!(ip::checksum(ip.payload), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_echo_reply_v6() {
+        let guest: Ipv6Addr = "fd86:86::2".parse().unwrap();
+        let gateway: Ipv6Addr = "fd86:86::1".parse().unwrap();
+        let mut message = vec![ECHOV6_REQUEST, 0, 0, 0, 0, 7, 0, 1];
+        let checksum = ip::checksum(&ip::pseudo_header_v6(guest, gateway, PROTO_ICMPV6, &message));
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let request = ip::build_ipv6(guest, gateway, PROTO_ICMPV6, 64, &message);
+
+        assert!(is_echo_request_v6(&request, &[gateway]));
+        let reply = echo_reply_v6(&request).unwrap();
+        let ip = Ipv6Packet::parse(&reply).unwrap();
+        assert_eq!(ip.dst, guest);
+        assert_eq!(ip.payload[0], ECHOV6_REPLY);
+    }
+}
@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+use crate::events::DisconnectEvent;
+use crate::transport::{CloseHandler, Receiver};
+
+/// What a connection's callbacks report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inbound<'a> {
+    /// A message from the relay.
+    Message(&'a [u8]),
+    Closed(&'a DisconnectEvent),
+    /// The reconnect timer fired.
+    ReconnectDue,
+}
+
+/// An `Inbound` copied to wait its turn.
+#[derive(Debug, Clone, PartialEq)]
+enum Queued {
+    Message(Vec<u8>),
+    Closed(DisconnectEvent),
+    ReconnectDue,
+}
+
+impl From<Inbound<'_>> for Queued {
+    fn from(input: Inbound<'_>) -> Self {
+        match input {
+            Inbound::Message(data) => Queued::Message(data.to_vec()),
+            Inbound::Closed(event) => Queued::Closed(event.clone()),
+            Inbound::ReconnectDue => Queued::ReconnectDue,
+        }
+    }
+}
+
+impl Queued {
+    fn as_inbound(&self) -> Inbound<'_> {
+        match self {
+            Queued::Message(data) => Inbound::Message(data),
+            Queued::Closed(event) => Inbound::Closed(event),
+            Queued::ReconnectDue => Inbound::ReconnectDue,
+        }
+    }
+}
+
+type Handler = Rc<dyn Fn(Inbound<'_>)>;
+
+/// Serializes a connection's callbacks. A report that finds nothing being
+/// handled is handled in place and then drains the queue; one that arrives
+/// mid-handling is copied into the queue. So handling never nests and
+/// happens in arrival order: a listener that closes the socket while a
+/// message is being handled has the close handled after it.
+#[derive(Clone, Default)]
+pub struct Inbox {
+    queue: Rc<RefCell<Queue>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Queued>,
+    draining: bool,
+    handler: Option<Handler>,
+}
+
+/// An `Inbox` reference that doesn't keep it alive, for the handler's own
+/// timers.
+#[derive(Clone)]
+pub struct WeakInbox(Weak<RefCell<Queue>>);
+
+impl Inbox {
+    pub fn new() -> Self {
+        Inbox::default()
+    }
+
+    pub fn set_handler(&self, handler: impl Fn(Inbound<'_>) + 'static) {
+        self.queue.borrow_mut().handler = Some(Rc::new(handler));
+    }
+
+    pub fn push(&self, input: Inbound<'_>) {
+        // Not borrowed while handling, so the handler can push
+        let handler = {
+            let mut queue = self.queue.borrow_mut();
+            if queue.draining {
+                queue.pending.push_back(input.into());
+                return;
+            }
+            queue.draining = true;
+            queue.handler.clone()
+        };
+        if let Some(handler) = handler {
+            handler(input);
+        }
+
+        loop {
+            let (queued, handler) = {
+                let mut queue = self.queue.borrow_mut();
+                match queue.pending.pop_front() {
+                    Some(queued) => (queued, queue.handler.clone()),
+                    None => {
+                        queue.draining = false;
+                        return;
+                    }
+                }
+            };
+            if let Some(handler) = handler {
+                handler(queued.as_inbound());
+            }
+        }
+    }
+
+    /// Hands over each message a transport receives.
+    pub fn receiver(&self) -> Receiver {
+        let inbox = self.clone();
+        Rc::new(move |data: &[u8]| inbox.push(Inbound::Message(data)))
+    }
+
+    /// Hands over the transport's close.
+    pub fn close_handler(&self) -> CloseHandler {
+        let inbox = self.clone();
+        Rc::new(move |event: &DisconnectEvent| inbox.push(Inbound::Closed(event)))
+    }
+
+    pub fn downgrade(&self) -> WeakInbox {
+        WeakInbox(Rc::downgrade(&self.queue))
+    }
+}
+
+impl WeakInbox {
+    /// Does nothing once the inbox is gone.
+    pub fn push(&self, input: Inbound<'_>) {
+        if let Some(queue) = self.0.upgrade() {
+            Inbox { queue }.push(input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_handling_never_nests() {
+        let inbox = Inbox::new();
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let weak = inbox.downgrade();
+        let log = handled.clone();
+        inbox.set_handler(move |input| {
+            // Whatever's reported while the message is handled waits its turn
+            if input == Inbound::Message(b"first") {
+                weak.push(Inbound::ReconnectDue);
+                assert_eq!(log.borrow().len(), 0);
+            }
+            log.borrow_mut().push(Queued::from(input));
+        });
+
+        inbox.receiver()(b"first");
+        assert_eq!(*handled.borrow(), vec![Queued::Message(b"first".to_vec()), Queued::ReconnectDue]);
+
+        let weak = inbox.downgrade();
+        drop(inbox);
+        weak.push(Inbound::ReconnectDue);
+    }
+}
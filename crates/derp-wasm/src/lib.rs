@@ -0,0 +1,804 @@
+pub mod arp;
+pub mod backpressure;
+pub mod channel;
+pub mod connection;
+pub mod control;
+pub mod demux;
+pub mod dhcp;
+pub mod dhcpv6;
+pub mod dns;
+pub mod ethernet;
+pub mod events;
+pub mod fetch;
+pub mod fingerprint;
+pub mod firewall;
+pub mod icmp;
+pub mod flow;
+pub mod forward;
+#[cfg(feature = "group")]
+pub mod group;
+pub mod httpd;
+pub mod inbox;
+pub mod logger;
+pub mod mesh;
+pub mod meter;
+pub mod metrics;
+pub mod nat;
+pub mod ndp;
+pub mod netcheck;
+pub mod network;
+pub mod outbox;
+pub mod pairing;
+pub mod pmtu;
+pub mod priority;
+pub mod protocol;
+pub mod publish;
+#[cfg(feature = "ratchet")]
+pub mod ratchet;
+pub mod registry;
+pub mod ring;
+pub mod shape;
+pub mod simulate;
+pub mod snapshot;
+pub mod socks;
+pub mod switch;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod tftp;
+pub mod timer;
+pub mod trace;
+pub mod transport;
+pub mod vm_network;
+pub mod worker;
+
+// Moved to derp-core; re-exported so their paths are unchanged
+pub use derp_core::{
+    acl, bandwidth, breaker, clock, config, crypto, derpmap, driver, error, health, idle, ip, mdns, padding, pool, shared,
+    switchboard, timing, wire,
+};
+
+#[cfg(test)]
+mod protocol_test;
+
+use wasm_bindgen::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use acl::AclStats;
+use channel::Channel;
+use config::DerpConfig;
+use connection::ConnectionState;
+use demux::Demux;
+use crypto::CryptoState;
+use network::{ConnectOptions, DrainProgress, Locked, MemoryStats, NetworkState, NetworkStats, StatsCounters};
+use error::{DerpError, DerpResult};
+use events::{EventDispatcher, EventKind};
+use fingerprint::IdentityFingerprint;
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use js_sys::{Function, Uint8Array};
+use logger::LogLevel;
+use mesh::MeshBridge;
+use netcheck::HomeEvent;
+use pairing::PairingInfo;
+use registry::InstanceId;
+use shared::{Shared, WeakShared};
+use simulate::NetworkConditions;
+use timing::Timings;
+use transport::Loopback;
+use vm_network::{VmNetwork, VmNetworkOptions};
+
+#[wasm_bindgen]
+pub struct DerpNetwork {
+    id: InstanceId,
+    network: Shared<NetworkState>,
+    events: EventDispatcher,
+    stats: Arc<StatsCounters>,
+    /// NICs created by `createVmNetwork`, which received packets are routed to.
+    nics: Rc<RefCell<Demux>>,
+    /// Bumped by each `connectHome`, which stops the re-probing of the last.
+    home_generation: Rc<Cell<u32>>,
+}
+
+#[wasm_bindgen]
+impl DerpNetwork {
+    /// Accepts an optional config object (see `DerpConfig`); missing keys
+    /// take their defaults.
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: Option<DerpConfig>) -> Result<DerpNetwork, JsValue> {
+        Ok(DerpNetwork::with_config(config.unwrap_or_default())?)
+    }
+
+    /// Like the constructor, but with keys issued elsewhere, e.g. by a
+    /// control plane, rather than generated: the node's 32-byte private
+    /// key, which frames are sealed under a key derived from, and the
+    /// relay's public key, which it must present during the handshake.
+    #[wasm_bindgen(js_name = newWithKeys)]
+    pub fn new_with_keys(private_key: &[u8], server_public_key: &[u8], config: Option<DerpConfig>) -> Result<DerpNetwork, JsValue> {
+        Ok(DerpNetwork::with_keys(config.unwrap_or_default(), private_key, server_public_key)?)
+    }
+
+    /// Page-unique id of this instance.
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> InstanceId {
+        self.id
+    }
+
+    /// Sets how much every instance on the page logs to the console.
+    /// Defaults to "warn".
+    #[wasm_bindgen(js_name = setLogLevel)]
+    pub fn set_log_level(level: LogLevel) {
+        logger::set_level(level);
+    }
+
+    /// Ids of all instances on the page that haven't been freed.
+    #[wasm_bindgen(js_name = liveInstances)]
+    pub fn live_instances() -> Vec<InstanceId> {
+        registry::live_instances()
+    }
+
+    /// Fingerprints of two peers' keys, e.g. from `generateKeyPair` or
+    /// `getRatchetKey`, and a short authentication string over both. Users
+    /// pairing two VMs read the string or emoji to each other: if they
+    /// match, no relay in between swapped the keys.
+    #[wasm_bindgen(js_name = identityFingerprint)]
+    pub fn identity_fingerprint(local_key: &[u8], remote_key: &[u8]) -> Result<IdentityFingerprint, JsValue> {
+        Ok(IdentityFingerprint::new(local_key, remote_key)?)
+    }
+
+    /// Packs a public key and the relay it can be reached through into a
+    /// short code, for the other user to scan as a QR code or type in.
+    /// Compare `identityFingerprint` once connected to check no one
+    /// swapped the code.
+    #[wasm_bindgen(js_name = pairingCode)]
+    pub fn pairing_code(public_key: &[u8], relay_url: &str) -> Result<String, JsValue> {
+        Ok(PairingInfo::new(public_key, relay_url)?.encode()?)
+    }
+
+    /// Reads a code from `pairingCode`.
+    #[wasm_bindgen(js_name = parsePairingCode)]
+    pub fn parse_pairing_code(code: &str) -> Result<PairingInfo, JsValue> {
+        Ok(PairingInfo::decode(code)?)
+    }
+
+    /// Registers `callback` for a `DerpEventName` event.
+    pub fn on(&self, event: &str, callback: Function) -> Result<(), JsValue> {
+        self.events.on(parse_event(event)?, callback);
+        Ok(())
+    }
+
+    /// Removes a callback registered with `on`. Returns false if it wasn't registered.
+    pub fn off(&self, event: &str, callback: &Function) -> Result<bool, JsValue> {
+        Ok(self.events.off(parse_event(event)?, callback))
+    }
+
+    /// Received packets as a `ReadableStream` of `Uint8Array`s. Packets that
+    /// arrive while the stream's queue is full are dropped.
+    pub fn readable(&self) -> web_sys::ReadableStream {
+        let capacity = self.lock().config().receive_queue_size;
+        let packets = self.events.subscribe(EventKind::Packet, capacity);
+        wasm_streams::ReadableStream::from_stream(packets.map(Ok)).into_raw()
+    }
+
+    /// A `WritableStream` accepting outgoing packets as `Uint8Array`s. Writes
+    /// wait while the send queue is full; send failures surface as "error" events.
+    pub fn writable(&self) -> web_sys::WritableStream {
+        let capacity = self.lock().config().send_queue_size;
+        let (sender, mut queue) = mpsc::channel::<JsValue>(capacity);
+
+        let network = self.network.clone();
+        let events = self.events.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(chunk) = queue.next().await {
+                let packet = Uint8Array::new(&chunk).to_vec();
+                let result = NetworkState::lock(&network).send_packet(&packet);
+                if let Err(e) = result {
+                    events.emit(EventKind::Error, &e.into());
+                }
+            }
+        });
+
+        let sink = sender.sink_map_err(|e| JsValue::from(DerpError::InvalidState(e.to_string())));
+        wasm_streams::WritableStream::from_sink(sink).into_raw()
+    }
+
+    /// Creates a virtual NIC bound to this instance's relay connection.
+    /// Several NICs may share it; received packets are routed to each by
+    /// the VLAN it was given or, for Ethernet frames, by its MAC.
+    #[wasm_bindgen(js_name = createVmNetwork)]
+    pub fn create_vm_network(&self, mac_address: &[u8], options: Option<VmNetworkOptions>) -> Result<VmNetwork, JsValue> {
+        let options = options.unwrap_or_default();
+        let mut config = self.lock().config().clone();
+        config.mtu = options.mtu.unwrap_or(config.mtu);
+        config.guest_ip = options.guest_ip.unwrap_or(config.guest_ip);
+        config.validate()?;
+
+        let vm = VmNetwork::new_on_vlan(self.network.clone(), mac_address, registry::gateway_mac(self.id), &config, options.vlan)?;
+        vm.set_promiscuous(options.promiscuous);
+        self.nics.borrow_mut().add(vm.handle());
+        Ok(vm)
+    }
+
+    /// Opens a stream that shares the relay connection with the VMs,
+    /// e.g. for control messages or file transfers. Peers exchange
+    /// messages on the ids they have both opened; id 0 is the VMs'.
+    #[wasm_bindgen(js_name = openChannel)]
+    pub fn open_channel(&self, id: u16) -> Result<Channel, JsValue> {
+        Ok(Channel::open(self.network.clone(), id)?)
+    }
+
+    /// Resolves once the relay accepts the handshake.
+    pub async fn connect(&self, url: &str) -> Result<(), JsValue> {
+        let connected = self.lock().connect(url);
+        Ok(connected.await?)
+    }
+
+    /// Connects with an options object, e.g. `{ authToken: "..." }`.
+    #[wasm_bindgen(js_name = connectWithOptions)]
+    pub async fn connect_with_options(&self, url: &str, options: ConnectOptions) -> Result<(), JsValue> {
+        let connected = self.lock().connect_with_options(url, options);
+        Ok(connected.await?)
+    }
+
+    /// Probes the configured `regions` and `derpMap` and reports their latencies.
+    #[wasm_bindgen(unchecked_return_type = "NetcheckReport")]
+    pub async fn netcheck(&self) -> Result<JsValue, JsValue> {
+        let regions = self.lock().config().relay_regions();
+        let report = netcheck::run(&regions, &*clock::system()).await;
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
+    /// Connects to the fastest of the configured relay regions, then re-probes
+    /// every `netcheckIntervalMs` and moves to a region that has become
+    /// clearly faster. Each move is announced with a "home" event.
+    #[wasm_bindgen(js_name = connectHome, unchecked_return_type = "NetcheckReport")]
+    pub async fn connect_home(&self, options: Option<ConnectOptions>) -> Result<JsValue, JsValue> {
+        let generation = self.home_generation.get().wrapping_add(1);
+        self.home_generation.set(generation);
+
+        let (regions, interval_ms) = {
+            let network = self.lock();
+            (network.config().relay_regions(), network.config().netcheck_interval_ms)
+        };
+        let report = netcheck::run(&regions, &*clock::system()).await;
+        let home = report.preferred
+            .and_then(|id| regions.iter().find(|region| region.id == id))
+            .ok_or_else(|| DerpError::InvalidState("No relay region is reachable".into()))?;
+
+        let options = options.unwrap_or_default();
+        self.lock().set_preferred(true)?;
+        let connected = self.lock().connect_with_options(&home.url, options.clone());
+        connected.await?;
+        self.events.emit_serialized(EventKind::Home, &HomeEvent {
+            region_id: home.id,
+            previous: None,
+            latency_ms: report.latency(home.id),
+        });
+
+        if interval_ms > 0 {
+            self.keep_home(home.id, options, interval_ms);
+        }
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
+    /// Tells the relay whether it's this client's home region, which it
+    /// takes into account when shedding load. `connectHome` sets this; set
+    /// it yourself when choosing relays some other way.
+    #[wasm_bindgen(js_name = setPreferred)]
+    pub fn set_preferred(&self, preferred: bool) -> Result<(), JsValue> {
+        Ok(self.lock().set_preferred(preferred)?)
+    }
+
+    #[wasm_bindgen(js_name = isPreferred)]
+    pub fn is_preferred(&self) -> bool {
+        self.lock().is_preferred()
+    }
+
+    /// Whether this connection negotiated `aesGcmSiv`, so relay frames are
+    /// sealed with AES-GCM-SIV rather than AES-GCM.
+    #[wasm_bindgen(js_name = isAesGcmSivEnabled)]
+    pub fn is_aes_gcm_siv_enabled(&self) -> bool {
+        self.lock().aes_gcm_siv_enabled()
+    }
+
+    /// Makes this page a mesh node between two relays: packets either one
+    /// asks to have forwarded are sent on through the other, each at most
+    /// `maxForwardHops` times and never twice, so loops die out.
+    pub fn bridge(&self, other: &DerpNetwork) -> MeshBridge {
+        let max_hops = self.lock().config().max_forward_hops;
+        MeshBridge::start(
+            (self.network.downgrade(), self.events.clone()),
+            (other.network.downgrade(), other.events.clone()),
+            max_hops,
+        )
+    }
+
+    /// Asks the relay to disconnect the peer with this hex key. Only mesh
+    /// and admin connections are allowed to; the other peers get a
+    /// "peer-closed" event.
+    #[wasm_bindgen(js_name = closePeer)]
+    pub fn close_peer(&self, peer_key: &str) -> Result<(), JsValue> {
+        let peer = protocol::hex_decode_key(peer_key)?;
+        Ok(self.lock().close_peer(&peer)?)
+    }
+
+    /// Replaces `allowedPeers` with these hex keys, or allows every peer
+    /// given null. Present peers no longer allowed are forgotten.
+    #[wasm_bindgen(js_name = setAllowedPeers)]
+    pub fn set_allowed_peers(&self, keys: Option<js_sys::Array>) -> Result<(), JsValue> {
+        let allowed = match keys {
+            Some(keys) => Some(keys.iter()
+                .map(|key| protocol::hex_decode_key(&key.as_string().unwrap_or_default()))
+                .collect::<DerpResult<_>>()?),
+            None => None,
+        };
+        self.lock().set_allowed_peers(allowed);
+        Ok(())
+    }
+
+    /// Announcements and packets dropped, and sends refused, per peer key
+    /// because of `allowedPeers`.
+    #[wasm_bindgen(js_name = getPeerDenials)]
+    pub fn get_peer_denials(&self) -> AclStats {
+        self.lock().acl_stats()
+    }
+
+    /// Starts a live roster: returns the hex keys of the peers known now,
+    /// and the relay reports every other one with a "peer-present" event,
+    /// and departures with "peer-gone", from now on and across reconnects.
+    #[wasm_bindgen(js_name = watchPeers)]
+    pub fn watch_peers(&self) -> Result<js_sys::Array, JsValue> {
+        let keys = self.lock().watch_peers()?;
+        Ok(keys.into_iter().map(JsValue::from).collect())
+    }
+
+    pub fn send_packet(&self, data: &[u8]) -> Result<(), JsValue> {
+        Ok(self.lock().send_packet(data)?)
+    }
+
+    /// Reconnects at once after "connection-failed", rather than waiting
+    /// out `reconnectCooldownMs`, or after the relay refused the auth
+    /// token. Throws unless the connection is in "cooldown" or "failed".
+    #[wasm_bindgen(js_name = retryNow)]
+    pub fn retry_now(&self) -> Result<(), JsValue> {
+        Ok(self.lock().retry_now()?)
+    }
+
+    /// Where the relay connection stands. Every change is also emitted as
+    /// a "state" event.
+    #[wasm_bindgen(js_name = getState)]
+    pub fn get_state(&self) -> ConnectionState {
+        self.lock().state()
+    }
+
+    #[wasm_bindgen(js_name = getStats)]
+    pub fn get_stats(&self) -> NetworkStats {
+        self.stats.snapshot()
+    }
+
+    /// The relay link's upload bandwidth in kilobits per second, measured
+    /// from how fast the socket's send buffer drains while it has a
+    /// backlog. Undefined until the link has been busy long enough to
+    /// tell, and reset on each reconnect. Batching sizes itself to it, as
+    /// does egress shaping with `matchRelay`.
+    #[wasm_bindgen(js_name = estimatedBandwidth)]
+    pub fn estimated_bandwidth(&self) -> Option<u32> {
+        self.lock().estimated_bandwidth_kbps()
+    }
+
+    /// Queue lengths, pooled buffers, trace-ring usage and roughly how many
+    /// bytes this instance and the NICs attached to it hold, for pages
+    /// running several VMs to spot one that keeps too much.
+    #[wasm_bindgen(js_name = memoryStats)]
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = self.lock().memory_stats();
+        stats.add_traces(self.nics.borrow().trace_usage());
+        stats
+    }
+
+    /// Histograms of handshake, crypto, compression and dispatch times
+    /// since this instance was created.
+    #[wasm_bindgen(js_name = getTimings)]
+    pub fn get_timings(&self) -> Timings {
+        self.lock().timings()
+    }
+
+    /// Stats, connection gauges and timings in the Prometheus text
+    /// exposition format, labelled with this instance's id, for serving
+    /// from a scrape endpoint as is.
+    #[wasm_bindgen(js_name = metricsText)]
+    pub fn metrics_text(&self) -> String {
+        let labels = [("instance", self.id.to_string())];
+        self.lock().metrics_text(&labels)
+    }
+
+    /// Stops accepting new peers and guest flows and returns the drain progress.
+    pub fn drain(&self) -> DrainProgress {
+        self.lock().drain()
+    }
+
+    #[wasm_bindgen(js_name = drainProgress)]
+    pub fn drain_progress(&self) -> DrainProgress {
+        self.lock().drain_progress()
+    }
+
+    /// Refuses further packets, sends what's still queued and a Goodbye,
+    /// and closes once the socket has drained, so a page that's going away
+    /// doesn't lose the last packets the VM sent.
+    pub async fn shutdown(&self) -> Result<(), JsValue> {
+        // Not locked while draining, so the VMs and timers can still reach
+        // the network meanwhile
+        let drain = self.lock().begin_shutdown();
+        let result = drain.wait().await;
+        self.lock().close();
+        Ok(result?)
+    }
+
+    /// Adds latency, jitter, loss, duplication and reordering to relay
+    /// traffic, for testing how guests and reconnects cope. Set `seed` for
+    /// repeatable runs; null restores the connection.
+    #[wasm_bindgen(js_name = simulateConditions)]
+    pub fn simulate_conditions(&self, conditions: Option<NetworkConditions>) -> Result<(), JsValue> {
+        Ok(self.lock().simulate_conditions(conditions)?)
+    }
+
+    /// Saves counters and drain state to keep alongside a v86 snapshot. The
+    /// relay session isn't included: a restored instance connects afresh.
+    #[wasm_bindgen(js_name = serializeState)]
+    pub fn serialize_state(&self) -> Result<Vec<u8>, JsValue> {
+        Ok(snapshot::encode(&self.lock().save())?)
+    }
+
+    #[wasm_bindgen(js_name = restoreState)]
+    pub fn restore_state(&self, bytes: &[u8]) -> Result<(), JsValue> {
+        let state = snapshot::decode(bytes)?;
+        self.lock().restore(&state);
+        Ok(())
+    }
+}
+
+impl DerpNetwork {
+    /// See `NetworkState::lock`.
+    fn lock(&self) -> Locked<'_> {
+        NetworkState::lock(&self.network)
+    }
+
+    pub fn with_config(config: DerpConfig) -> DerpResult<DerpNetwork> {
+        config.validate()?;
+        DerpNetwork::with_crypto(config, CryptoState::new()?)
+    }
+
+    pub fn with_keys(config: DerpConfig, private_key: &[u8], server_public_key: &[u8]) -> DerpResult<DerpNetwork> {
+        config.validate()?;
+        let server_key = protocol::PeerKey::try_from(server_public_key)
+            .map_err(|_| DerpError::InvalidState("Server public keys are 32 bytes".into()))?;
+        let network = DerpNetwork::with_crypto(config, CryptoState::from_private_key(private_key)?)?;
+        network.lock().pin_server_key(server_key);
+        Ok(network)
+    }
+
+    fn with_crypto(config: DerpConfig, crypto_state: CryptoState) -> DerpResult<DerpNetwork> {
+        logger::init();
+        let capacity = config.receive_queue_size;
+        let network = NetworkState::with_config(Arc::new(crypto_state), config);
+        let events = network.events();
+
+        // Ends when the dispatcher, and with it the subscription, is dropped
+        let nics = Rc::new(RefCell::new(Demux::default()));
+        let mut packets = network.packets(capacity);
+        let demux = nics.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(packet) = packets.next().await {
+                if let Err(e) = demux.borrow().route(&packet) {
+                    log::warn!("Failed to route a packet to its NIC: {:?}", e);
+                }
+            }
+        });
+
+        let cooldown_ms = network.config().reconnect_cooldown_ms;
+        let control = network.config().control_url.clone().map(|url| (url, network.config().control_interval_ms));
+        let stats = network.stats();
+        let network = network.into_shared();
+        if cooldown_ms > 0 {
+            retry_after_cooldown(network.downgrade(), &events, cooldown_ms);
+        }
+        if let Some((url, interval_ms)) = control {
+            control::start(network.downgrade(), events.clone(), url, interval_ms);
+        }
+
+        Ok(DerpNetwork {
+            id: registry::register(),
+            stats,
+            events,
+            network,
+            nics,
+            home_generation: Rc::new(Cell::new(0)),
+        })
+    }
+
+    /// Re-probes every `interval_ms`, re-homing when `choose_home` says to,
+    /// for as long as this instance lives, stays open, and hasn't had
+    /// `connectHome` called again.
+    fn keep_home(&self, mut home: u32, options: ConnectOptions, interval_ms: u32) {
+        let network = self.network.downgrade();
+        let generation = self.home_generation.get();
+        let latest_generation = self.home_generation.clone();
+        let events = self.events.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            loop {
+                timer::sleep(interval_ms as i32).await;
+                let Some(network) = network.upgrade() else { break };
+                if latest_generation.get() != generation
+                    || matches!(NetworkState::lock(&network).state(), ConnectionState::Closed | ConnectionState::Failed)
+                {
+                    break;
+                }
+
+                // The coordination server may have changed them since
+                let regions = NetworkState::lock(&network).config().relay_regions();
+                let report = netcheck::run(&regions, &*clock::system()).await;
+                let next = match report.choose_home(Some(home)) {
+                    Some(next) if next != home && latest_generation.get() == generation => next,
+                    _ => continue,
+                };
+                let Some(region) = regions.iter().find(|region| region.id == next) else { continue };
+
+                log::info!("Re-homing from region {} to {}", home, next);
+                let connected = {
+                    let mut network = NetworkState::lock(&network);
+                    network.close();
+                    network.connect_with_options(&region.url, options.clone())
+                };
+                match connected.await {
+                    Ok(()) => {
+                        events.emit_serialized(EventKind::Home, &HomeEvent {
+                            region_id: next,
+                            previous: Some(home),
+                            latency_ms: report.latency(next),
+                        });
+                        home = next;
+                    }
+                    Err(e) => events.emit(EventKind::Error, &e.into()),
+                }
+            }
+        });
+    }
+
+    /// Connects through an in-memory `Loopback` instead of a relay.
+    pub fn connect_loopback(&self, loopback: Loopback) -> DerpResult<()> {
+        self.lock().connect_loopback(loopback)
+    }
+}
+
+/// Retries `cooldown_ms` after each "connection-failed", unless `retryNow`
+/// or a new connection got there first.
+fn retry_after_cooldown(network: WeakShared<NetworkState>, events: &EventDispatcher, cooldown_ms: u32) {
+    let mut failures = events.subscribe(EventKind::ConnectionFailed, 1);
+    wasm_bindgen_futures::spawn_local(async move {
+        while failures.next().await.is_some() {
+            let Some(trips) = network.upgrade().map(|network| NetworkState::lock(&network).breaker_trips()) else { break };
+            timer::sleep(cooldown_ms as i32).await;
+            let Some(network) = network.upgrade() else { break };
+            let mut network = NetworkState::lock(&network);
+            if network.state() != ConnectionState::Cooldown || network.breaker_trips() != trips {
+                continue;
+            }
+            if let Err(e) = network.retry_now() {
+                log::warn!("Failed to retry after the cool-down: {}", e);
+            }
+        }
+    });
+}
+
+fn parse_event(name: &str) -> DerpResult<EventKind> {
+    EventKind::from_name(name)
+        .ok_or_else(|| DerpError::InvalidState(format!("Unknown event: {}", name)))
+}
+
+impl Drop for DerpNetwork {
+    fn drop(&mut self) {
+        registry::unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+    use wasm_bindgen::JsCast;
+    use js_sys::{Object, Reflect};
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    async fn test_derp_network() {
+        // Test creation
+        let derp = DerpNetwork::new(None).unwrap();
+        
+        // Test invalid connection
+        let result = derp.connect("invalid-url").await;
+        assert!(result.is_err());
+        
+        // Test valid connection; lazily, so it doesn't wait on a handshake
+        let config = DerpConfig::builder().lazy_connect(true).build().unwrap();
+        let derp = DerpNetwork::new(Some(config)).unwrap();
+        let result = derp.connect("wss://test.example.com").await;
+        assert!(result.is_ok());
+        
+        // Test sending packet
+        let test_data = b"test packet";
+        let result = derp.send_packet(test_data);
+        assert!(result.is_ok());
+        
+        // Test stats
+        let stats = serde_wasm_bindgen::to_value(&derp.get_stats()).unwrap();
+        let stats_obj: Object = stats.unchecked_into();
+        
+        let bytes_sent = Reflect::get(&stats_obj, &JsValue::from_str("bytes_sent")).unwrap();
+        let packets_sent = Reflect::get(&stats_obj, &JsValue::from_str("packets_sent")).unwrap();
+        
+        assert_eq!(bytes_sent.as_f64().unwrap() as u64, test_data.len() as u64);
+        assert_eq!(packets_sent.as_f64().unwrap() as u64, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_error_handling() {
+        let derp = DerpNetwork::new(None).unwrap();
+        
+        // Test sending before connection
+        let result = derp.send_packet(b"test");
+        assert!(result.is_err());
+        
+        // Test stats before any activity
+        let stats = serde_wasm_bindgen::to_value(&derp.get_stats()).unwrap();
+        let stats_obj: Object = stats.unchecked_into();
+        
+        let bytes_sent = Reflect::get(&stats_obj, &JsValue::from_str("bytes_sent")).unwrap();
+        let packets_sent = Reflect::get(&stats_obj, &JsValue::from_str("packets_sent")).unwrap();
+        let reconnect_attempts = Reflect::get(&stats_obj, &JsValue::from_str("reconnect_attempts")).unwrap();
+        
+        assert_eq!(bytes_sent.as_f64().unwrap() as u64, 0);
+        assert_eq!(packets_sent.as_f64().unwrap() as u64, 0);
+        assert_eq!(reconnect_attempts.as_f64().unwrap() as u32, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_config_flows_into_vm_network() {
+        let config = DerpConfig::builder().mtu(1280).build().unwrap();
+        let derp = DerpNetwork::with_config(config).unwrap();
+
+        let vm = derp.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], None).unwrap();
+        assert_eq!(vm.get_mtu(), 1280);
+
+        // Invalid configs are rejected up front
+        let config = DerpConfig { mtu: 10, ..DerpConfig::default() };
+        assert!(DerpNetwork::new(Some(config)).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_vm_networks_talk_over_loopback() {
+        let (first_end, second_end) = Loopback::pair();
+        let first = DerpNetwork::new(None).unwrap();
+        let second = DerpNetwork::new(None).unwrap();
+        first.connect_loopback(first_end).unwrap();
+        second.connect_loopback(second_end).unwrap();
+        let sender = first.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], None).unwrap();
+        let receiver = second.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x57], None).unwrap();
+
+        let received = js_sys::Array::new();
+        let sink = received.clone();
+        let callback = Closure::wrap(Box::new(move |frame: Uint8Array| {
+            sink.push(&frame);
+        }) as Box<dyn FnMut(Uint8Array)>);
+        receiver.set_receive_callback(callback.as_ref().unchecked_ref::<Function>().clone());
+
+        // Lets the handshakes and everything they set off run to completion
+        let settle = || wasm_bindgen_futures::JsFuture::from(js_sys::Promise::new(&mut |resolve, _| {
+            timer::set_timeout(&resolve, 0);
+        }));
+        settle().await.unwrap();
+
+        let mut frame = sender.get_gateway_mac().to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&0x0800u16.to_be_bytes());
+        frame.extend_from_slice(&ip::build_udp(config::DEFAULT_GUEST_IP, "10.0.0.2".parse().unwrap(), 4000, 4000, b"over loopback"));
+        sender.send_packet(&frame).unwrap();
+        settle().await.unwrap();
+
+        assert_eq!(received.length(), 1);
+        let delivered = Uint8Array::new(&received.get(0)).to_vec();
+        assert!(delivered.ends_with(b"over loopback"));
+        assert_eq!(second.get_stats().packets_received, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_packets_routed_by_vlan() {
+        let derp = DerpNetwork::new(None).unwrap();
+        let options = |vlan, mtu| Some(VmNetworkOptions { mtu, vlan, ..VmNetworkOptions::default() });
+        let first = derp.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], options(None, None)).unwrap();
+        let second = derp.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x57], options(Some(7), Some(1280))).unwrap();
+        assert_eq!(second.get_mtu(), 1280);
+        assert!(derp.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x58], options(None, Some(10))).is_err());
+
+        let received = js_sys::Array::new();
+        let record = |vm: &VmNetwork, name: &'static str| {
+            let sink = received.clone();
+            let callback = Closure::wrap(Box::new(move |_frame: Uint8Array| {
+                sink.push(&name.into());
+            }) as Box<dyn FnMut(Uint8Array)>);
+            vm.set_receive_callback(callback.as_ref().unchecked_ref::<Function>().clone());
+            callback
+        };
+        let _first = record(&first, "first");
+        let _second = record(&second, "second");
+
+        let packet = ip::build_udp("10.0.0.1".parse().unwrap(), "192.168.86.100".parse().unwrap(), 1000, 2000, b"hi");
+        assert!(derp.nics.borrow().route(&demux::tag(7, &packet)).unwrap());
+        assert!(derp.nics.borrow().route(&packet).unwrap());
+        assert!(!derp.nics.borrow().route(&demux::tag(8, &packet)).unwrap());
+        assert_eq!(received.to_vec(), vec![JsValue::from("second"), JsValue::from("first")]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_errors_carry_kind() {
+        let derp = DerpNetwork::new(None).unwrap();
+
+        let error: js_sys::Error = derp.send_packet(b"test").unwrap_err().unchecked_into();
+        assert_eq!(error.name(), "InvalidState");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_event_registration() {
+        let derp = DerpNetwork::new(None).unwrap();
+        let callback = Function::new_no_args("");
+
+        assert!(derp.on("packet", callback.clone()).is_ok());
+        assert!(derp.off("packet", &callback).unwrap());
+        assert!(derp.on("not-an-event", callback).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_readable_stream_yields_packets() {
+        let derp = DerpNetwork::new(None).unwrap();
+        let readable = derp.readable();
+
+        derp.events.emit(EventKind::Packet, &Uint8Array::from(&[1u8, 2, 3][..]).into());
+
+        let mut stream = wasm_streams::ReadableStream::from_raw(readable).into_stream();
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(Uint8Array::new(&chunk).to_vec(), vec![1, 2, 3]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_state_listeners_can_call_back_in() {
+        let derp = Rc::new(DerpNetwork::new(None).unwrap());
+        let seen = js_sys::Array::new();
+        let (handle, sink) = (Rc::downgrade(&derp), seen.clone());
+        let listener = Closure::wrap(Box::new(move |_change: JsValue| {
+            let state = handle.upgrade().unwrap().get_state();
+            sink.push(&serde_wasm_bindgen::to_value(&state).unwrap());
+        }) as Box<dyn FnMut(JsValue)>);
+        derp.on("state", listener.as_ref().unchecked_ref::<Function>().clone()).unwrap();
+
+        // Each change is reported once the network is released
+        derp.connect_loopback(Loopback::new()).unwrap();
+        let seen: Vec<_> = seen.iter().map(|state| state.as_string().unwrap()).collect();
+        assert_eq!(seen, ["handshaking", "handshaking"]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_independent_instances() {
+        let first = DerpNetwork::new(None).unwrap();
+        let second = DerpNetwork::new(None).unwrap();
+        assert_ne!(first.id(), second.id());
+
+        let first_vm = first.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], None).unwrap();
+        let second_vm = second.create_vm_network(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], None).unwrap();
+        assert_ne!(first_vm.get_gateway_mac().to_vec(), second_vm.get_gateway_mac().to_vec());
+
+        let first_id = first.id();
+        drop(first);
+        assert!(!DerpNetwork::live_instances().contains(&first_id));
+        assert!(DerpNetwork::live_instances().contains(&second.id()));
+    }
+}
@@ -0,0 +1,100 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Serialize, Deserialize};
+use tsify::Tsify;
+use wasm_bindgen::JsValue;
+
+/// Until `setLogLevel` says otherwise, only problems are logged.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// How much the package logs to the console, from nothing to everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "lowercase")]
+#[tsify(from_wasm_abi)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Sends `log` records to the matching `console` method, prefixed with the
+/// module they came from.
+struct ConsoleLogger;
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = JsValue::from(format!("[{}] {}", record.target(), record.args()));
+        match record.level() {
+            Level::Error => web_sys::console::error_1(&message),
+            Level::Warn => web_sys::console::warn_1(&message),
+            Level::Info => web_sys::console::info_1(&message),
+            Level::Debug | Level::Trace => web_sys::console::debug_1(&message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the console logger unless the page's wasm already has a
+/// logger, in which case records go there instead. Safe to call repeatedly.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    init();
+    log::set_max_level(level.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_level_filters_records() {
+        set_level(LogLevel::Info);
+        assert!(log::log_enabled!(Level::Info));
+        assert!(!log::log_enabled!(Level::Debug));
+
+        set_level(LogLevel::Off);
+        assert!(!log::log_enabled!(Level::Error));
+        set_level(LogLevel::Warn);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_init_keeps_the_chosen_level() {
+        set_level(LogLevel::Trace);
+        init();
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+        set_level(LogLevel::Warn);
+    }
+}
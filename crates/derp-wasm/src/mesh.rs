@@ -0,0 +1,220 @@
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::rc::Rc;
+use futures::StreamExt;
+use js_sys::Uint8Array;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use crate::error::{DerpError, DerpResult};
+use crate::events::{EventDispatcher, EventKind};
+use crate::network::NetworkState;
+use crate::protocol::{PeerKey, PEER_KEY_LEN};
+use crate::shared::WeakShared;
+
+/// Source and destination keys, hop count and packet id.
+pub const FORWARD_HEADER_LEN: usize = 2 * PEER_KEY_LEN + 1 + 8;
+
+/// Packet ids remembered for loop detection. A packet circling back after
+/// this many others have passed is stopped by its hop count instead.
+const SEEN_CAPACITY: usize = 4096;
+
+/// Forwarded packets waiting for a bridge to pass them on.
+const FORWARD_QUEUE: usize = 256;
+
+/// The header of a ForwardPacket payload, ahead of the packet itself. The
+/// id is chosen by the first node to forward a packet and kept by the rest,
+/// so a node can tell when a packet comes round again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardHeader {
+    pub src: PeerKey,
+    pub dst: PeerKey,
+    pub hops: u8,
+    pub id: u64,
+}
+
+impl ForwardHeader {
+    pub fn new(src: PeerKey, dst: PeerKey) -> DerpResult<ForwardHeader> {
+        let mut id = [0u8; 8];
+        getrandom::getrandom(&mut id)
+            .map_err(|e| DerpError::CryptoError(format!("Failed to pick a packet id: {}", e)))?;
+        Ok(ForwardHeader { src, dst, hops: 0, id: u64::from_be_bytes(id) })
+    }
+
+    pub fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(FORWARD_HEADER_LEN + packet.len());
+        payload.extend_from_slice(&self.src);
+        payload.extend_from_slice(&self.dst);
+        payload.push(self.hops);
+        payload.extend_from_slice(&self.id.to_be_bytes());
+        payload.extend_from_slice(packet);
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> DerpResult<(ForwardHeader, &[u8])> {
+        if payload.len() < FORWARD_HEADER_LEN {
+            return Err(DerpError::InvalidProtocol("Invalid ForwardPacket length".into()));
+        }
+        let (header, packet) = payload.split_at(FORWARD_HEADER_LEN);
+        let mut src = [0u8; PEER_KEY_LEN];
+        let mut dst = [0u8; PEER_KEY_LEN];
+        let mut id = [0u8; 8];
+        src.copy_from_slice(&header[..PEER_KEY_LEN]);
+        dst.copy_from_slice(&header[PEER_KEY_LEN..2 * PEER_KEY_LEN]);
+        id.copy_from_slice(&header[2 * PEER_KEY_LEN + 1..]);
+        Ok((ForwardHeader { src, dst, hops: header[2 * PEER_KEY_LEN], id: u64::from_be_bytes(id) }, packet))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct MeshStats {
+    pub forwarded: u64,
+    /// Seen here before, so going round in a loop.
+    pub dropped_loops: u64,
+    /// Already forwarded `maxForwardHops` times.
+    pub dropped_hops: u64,
+}
+
+/// Decides which forwarded packets a mesh node passes on.
+#[derive(Debug)]
+pub struct Mesh {
+    max_hops: u8,
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+    stats: MeshStats,
+}
+
+impl Mesh {
+    pub fn new(max_hops: u8) -> Self {
+        Mesh {
+            max_hops,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            stats: MeshStats::default(),
+        }
+    }
+
+    /// The header to pass a packet on with, or None if it has been here
+    /// before or has no hops left.
+    pub fn admit(&mut self, header: &ForwardHeader) -> Option<ForwardHeader> {
+        if header.hops >= self.max_hops {
+            self.stats.dropped_hops += 1;
+            return None;
+        }
+        if !self.seen.insert(header.id) {
+            self.stats.dropped_loops += 1;
+            return None;
+        }
+        self.order.push_back(header.id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.stats.forwarded += 1;
+        Some(ForwardHeader { hops: header.hops + 1, ..*header })
+    }
+
+    pub fn stats(&self) -> MeshStats {
+        self.stats.clone()
+    }
+}
+
+/// Two relay connections joined as a mesh node, returned by
+/// `DerpNetwork.bridge`: packets either relay asks to have forwarded are
+/// sent on through the other.
+#[wasm_bindgen]
+pub struct MeshBridge {
+    mesh: Rc<RefCell<Mesh>>,
+    open: Rc<Cell<bool>>,
+}
+
+#[wasm_bindgen]
+impl MeshBridge {
+    #[wasm_bindgen(js_name = getStats)]
+    pub fn get_stats(&self) -> MeshStats {
+        self.mesh.borrow().stats()
+    }
+
+    /// Stops forwarding; packets already queued are dropped.
+    pub fn close(&self) {
+        self.open.set(false);
+    }
+}
+
+impl MeshBridge {
+    /// Forwards between the two connections until closed or until either
+    /// is dropped. Both directions share one `Mesh`, so a packet looping
+    /// back through this node is caught.
+    pub fn start(
+        (first, first_events): (WeakShared<NetworkState>, EventDispatcher),
+        (second, second_events): (WeakShared<NetworkState>, EventDispatcher),
+        max_hops: u8,
+    ) -> MeshBridge {
+        let bridge = MeshBridge {
+            mesh: Rc::new(RefCell::new(Mesh::new(max_hops))),
+            open: Rc::new(Cell::new(true)),
+        };
+        bridge.forward(&first_events, second);
+        bridge.forward(&second_events, first);
+        bridge
+    }
+
+    fn forward(&self, from: &EventDispatcher, to: WeakShared<NetworkState>) {
+        let mut forwards = from.subscribe(EventKind::Forward, FORWARD_QUEUE);
+        let mesh = self.mesh.clone();
+        let open = self.open.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(payload) = forwards.next().await {
+                if !open.get() {
+                    break;
+                }
+                let Some(to) = to.upgrade() else { break };
+                let payload = Uint8Array::new(&payload).to_vec();
+                let (header, packet) = match ForwardHeader::decode(&payload) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::debug!("Dropped a forwarded packet: {}", e);
+                        continue;
+                    }
+                };
+                let Some(next) = mesh.borrow_mut().admit(&header) else { continue };
+                let result = NetworkState::lock(&to).forward_packet(&next, packet);
+                if let Err(e) = result {
+                    log::debug!("Failed to forward a packet: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_header_roundtrip() {
+        let header = ForwardHeader::new([1; PEER_KEY_LEN], [2; PEER_KEY_LEN]).unwrap();
+        let payload = header.encode(b"packet");
+        assert_eq!(payload.len(), FORWARD_HEADER_LEN + 6);
+        assert_eq!(ForwardHeader::decode(&payload).unwrap(), (header, &b"packet"[..]));
+        assert!(ForwardHeader::decode(&payload[..FORWARD_HEADER_LEN - 1]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_loops_and_long_paths_are_stopped() {
+        let mut mesh = Mesh::new(2);
+        let header = ForwardHeader { src: [1; PEER_KEY_LEN], dst: [2; PEER_KEY_LEN], hops: 0, id: 7 };
+
+        let next = mesh.admit(&header).unwrap();
+        assert_eq!((next.hops, next.id), (1, 7));
+        assert_eq!(mesh.admit(&next), None);
+        assert_eq!(mesh.admit(&ForwardHeader { hops: 2, id: 8, ..header }), None);
+        assert_eq!(mesh.stats(), MeshStats { forwarded: 1, dropped_loops: 1, dropped_hops: 1 });
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tsify::Tsify;
+use crate::firewall::Direction;
+use crate::ip::{Ipv4Packet, Ipv6Packet, PROTO_TCP, PROTO_UDP};
+use crate::ndp::ETHERTYPE_IPV6;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// Ports below this are counted one by one; higher ones by IANA range.
+const WELL_KNOWN_PORTS: u16 = 1024;
+const DYNAMIC_PORTS: u16 = 49152;
+
+/// Frames and bytes, Ethernet headers included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Tsify)]
+pub struct Usage {
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// Traffic both ways: sent by the guest and delivered to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Tsify)]
+pub struct Counters {
+    pub sent: Usage,
+    pub received: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct EthertypeUsage {
+    pub ethertype: u16,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// IPv4 and IPv6 traffic by protocol number, e.g. 6 for TCP, 17 for UDP
+/// and 1 or 58 for ICMP.
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolUsage {
+    pub protocol: u8,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// TCP or UDP traffic whose service port, the lower of its two, falls in
+/// `low..=high`. Well-known ports get a bucket each; the rest share the
+/// registered (1024-49151) and dynamic (49152-65535) ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct PortUsage {
+    pub protocol: u8,
+    pub low: u16,
+    pub high: u16,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// Returned by `VmNetwork.getTrafficStats`, each list in ascending order.
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct TrafficStats {
+    pub by_ethertype: Vec<EthertypeUsage>,
+    pub by_protocol: Vec<ProtocolUsage>,
+    pub by_port: Vec<PortUsage>,
+}
+
+/// Breaks a NIC's traffic down by ethertype, IP protocol and port bucket.
+#[derive(Debug, Default)]
+pub struct TrafficMeter {
+    ethertypes: BTreeMap<u16, Counters>,
+    protocols: BTreeMap<u8, Counters>,
+    ports: BTreeMap<(u8, u16), Counters>,
+}
+
+impl TrafficMeter {
+    /// Counts `frame`, which must hold at least an Ethernet header.
+    pub fn record(&mut self, direction: Direction, frame: &[u8]) {
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let len = frame.len();
+        add(self.ethertypes.entry(ethertype).or_default(), direction, len);
+
+        let (protocol, transport) = match ethertype {
+            ETHERTYPE_IPV4 => match Ipv4Packet::parse(&frame[14..]) {
+                Some(ip) => (ip.protocol, ip.payload),
+                None => return,
+            },
+            ETHERTYPE_IPV6 => match Ipv6Packet::parse(&frame[14..]) {
+                Some(ip) => (ip.next_header, ip.payload),
+                None => return,
+            },
+            _ => return,
+        };
+        add(self.protocols.entry(protocol).or_default(), direction, len);
+
+        if (protocol == PROTO_TCP || protocol == PROTO_UDP) && transport.len() >= 4 {
+            let src = u16::from_be_bytes([transport[0], transport[1]]);
+            let dst = u16::from_be_bytes([transport[2], transport[3]]);
+            let (low, _) = bucket(src.min(dst));
+            add(self.ports.entry((protocol, low)).or_default(), direction, len);
+        }
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            by_ethertype: self.ethertypes.iter()
+                .map(|(&ethertype, &counters)| EthertypeUsage { ethertype, counters })
+                .collect(),
+            by_protocol: self.protocols.iter()
+                .map(|(&protocol, &counters)| ProtocolUsage { protocol, counters })
+                .collect(),
+            by_port: self.ports.iter()
+                .map(|(&(protocol, low), &counters)| PortUsage { protocol, low, high: bucket(low).1, counters })
+                .collect(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = TrafficMeter::default();
+    }
+}
+
+fn add(counters: &mut Counters, direction: Direction, bytes: usize) {
+    let usage = match direction {
+        Direction::Outbound => &mut counters.sent,
+        Direction::Inbound => &mut counters.received,
+    };
+    usage.frames += 1;
+    usage.bytes += bytes as u64;
+}
+
+/// The bounds of the bucket `port` is counted in.
+fn bucket(port: u16) -> (u16, u16) {
+    if port < WELL_KNOWN_PORTS {
+        (port, port)
+    } else if port < DYNAMIC_PORTS {
+        (WELL_KNOWN_PORTS, DYNAMIC_PORTS - 1)
+    } else {
+        (DYNAMIC_PORTS, u16::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip::{self, PROTO_ICMP};
+    use std::net::Ipv4Addr;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn udp(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let packet = ip::build_udp(Ipv4Addr::new(192, 168, 86, 100), Ipv4Addr::new(1, 1, 1, 1), src_port, dst_port, b"query");
+        frame(ETHERTYPE_IPV4, &packet)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_breakdown() {
+        let mut meter = TrafficMeter::default();
+        let query = udp(40000, 53);
+        let answer = udp(53, 40000);
+        meter.record(Direction::Outbound, &query);
+        meter.record(Direction::Inbound, &answer);
+        meter.record(Direction::Outbound, &udp(50000, 8080));
+        meter.record(Direction::Outbound, &frame(0x0806, &[0; 28]));
+
+        let stats = meter.stats();
+        assert_eq!(stats.by_ethertype.iter().map(|usage| usage.ethertype).collect::<Vec<_>>(), vec![0x0800, 0x0806]);
+        let udp_usage = &stats.by_protocol[0];
+        assert_eq!(udp_usage.protocol, PROTO_UDP);
+        assert_eq!(udp_usage.counters.sent.frames, 2);
+        assert_eq!(udp_usage.counters.received, Usage { frames: 1, bytes: answer.len() as u64 });
+
+        let dns = &stats.by_port[0];
+        assert_eq!((dns.protocol, dns.low, dns.high), (PROTO_UDP, 53, 53));
+        assert_eq!((dns.counters.sent.frames, dns.counters.received.frames), (1, 1));
+        let registered = &stats.by_port[1];
+        assert_eq!((registered.low, registered.high), (1024, 49151));
+        assert!(!stats.by_protocol.iter().any(|usage| usage.protocol == PROTO_ICMP));
+
+        meter.reset();
+        assert!(meter.stats().by_ethertype.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_port_buckets() {
+        assert_eq!(bucket(0), (0, 0));
+        assert_eq!(bucket(1023), (1023, 1023));
+        assert_eq!(bucket(1024), (1024, 49151));
+        assert_eq!(bucket(49151), (1024, 49151));
+        assert_eq!(bucket(49152), (49152, 65535));
+        assert_eq!(bucket(65535), (49152, 65535));
+    }
+}
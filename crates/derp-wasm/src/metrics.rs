@@ -0,0 +1,191 @@
+use std::fmt::Write;
+use crate::network::NetworkStats;
+use crate::timing::{HistogramSnapshot, Phase, Timings};
+
+const PREFIX: &str = "derp";
+
+/// Point-in-time values that go up and down, unlike the counters in
+/// `NetworkStats`.
+#[derive(Debug, Clone, Default)]
+pub struct Gauges {
+    pub connected: bool,
+    /// Whether the connection is to the home region.
+    pub preferred: bool,
+    pub draining: bool,
+    pub congested: bool,
+    pub active_peers: usize,
+    pub active_flows: usize,
+}
+
+/// Writes metrics in the Prometheus text exposition format, each sample
+/// labelled with `labels`.
+pub struct Exposition {
+    text: String,
+    labels: String,
+}
+
+impl Exposition {
+    pub fn new(labels: &[(&str, String)]) -> Self {
+        let labels = labels.iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        Exposition { text: String::new(), labels }
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        self.sample(name, "", &value.to_string());
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        self.sample(name, "", &format_value(value));
+    }
+
+    /// Prometheus measures time in seconds, so the millisecond histograms
+    /// are scaled on the way out.
+    pub fn histogram_seconds(&mut self, name: &str, help: &str, histogram: &HistogramSnapshot) {
+        self.header(name, help, "histogram");
+        let bucket = format!("{}_bucket", name);
+        for b in &histogram.buckets {
+            self.sample(&bucket, &format!("le=\"{}\"", format_value(b.le_ms / 1000.0)), &b.count.to_string());
+        }
+        self.sample(&bucket, "le=\"+Inf\"", &histogram.count.to_string());
+        self.sample(&format!("{}_sum", name), "", &format_value(histogram.sum_ms / 1000.0));
+        self.sample(&format!("{}_count", name), "", &histogram.count.to_string());
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.text, "# HELP {}_{} {}", PREFIX, name, help);
+        let _ = writeln!(self.text, "# TYPE {}_{} {}", PREFIX, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, extra_label: &str, value: &str) {
+        let labels = [self.labels.as_str(), extra_label].iter()
+            .filter(|labels| !labels.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join(",");
+        if labels.is_empty() {
+            let _ = writeln!(self.text, "{}_{} {}", PREFIX, name, value);
+        } else {
+            let _ = writeln!(self.text, "{}_{}{{{}}} {}", PREFIX, name, labels, value);
+        }
+    }
+}
+
+/// Everything `DerpNetwork.metricsText` exports.
+pub fn render(labels: &[(&str, String)], stats: &NetworkStats, gauges: &Gauges, timings: &Timings) -> String {
+    let mut out = Exposition::new(labels);
+
+    out.counter("received_bytes_total", "Packet bytes received from the relay.", stats.bytes_received);
+    out.counter("sent_bytes_total", "Packet bytes sent to the relay.", stats.bytes_sent);
+    out.counter("received_packets_total", "Packets received from the relay.", stats.packets_received);
+    out.counter("sent_packets_total", "Packets sent to the relay.", stats.packets_sent);
+    out.counter("reconnect_attempts_total", "Reconnects attempted after the relay connection dropped.", stats.reconnect_attempts.into());
+    out.counter("echo_requests_total", "Guest pings to the virtual gateway.", stats.echo_requests);
+    out.counter("echo_replies_total", "Replies the virtual gateway sent to guest pings.", stats.echo_replies);
+    out.counter("broadcast_sent_total", "Broadcast frames sent by guests.", stats.broadcast_sent);
+    out.counter("multicast_sent_total", "Multicast frames sent by guests.", stats.multicast_sent);
+    out.counter("broadcast_received_total", "Broadcast frames delivered to guests.", stats.broadcast_received);
+    out.counter("multicast_received_total", "Multicast frames delivered to guests.", stats.multicast_received);
+    out.counter("congestion_events_total", "Times the relay socket passed its send high watermark.", stats.congestion_events);
+    out.counter("backpressure_drops_total", "Packets dropped while the relay socket was congested.", stats.backpressure_drops);
+    out.counter("decode_errors_total", "Relay messages that weren't valid frames.", stats.decode_errors);
+    out.counter("decrypt_failures_total", "Frames from peers that failed to authenticate.", stats.decrypt_failures);
+    out.counter("oversized_frames_total", "Packets from peers larger than the negotiated limit.", stats.oversized_frames);
+    out.counter("send_failures_total", "Writes to the relay socket that failed.", stats.send_failures);
+
+    out.gauge("connected", "Whether the relay handshake has completed.", bool_value(gauges.connected));
+    out.gauge("preferred", "Whether the relay is the home region's.", bool_value(gauges.preferred));
+    out.gauge("draining", "Whether the network is draining.", bool_value(gauges.draining));
+    out.gauge("congested", "Whether packets are being held back or dropped for a full socket.", bool_value(gauges.congested));
+    out.gauge("active_peers", "Peers the relay reports present.", gauges.active_peers as f64);
+    out.gauge("active_flows", "Guest flows being tracked.", gauges.active_flows as f64);
+
+    for phase in Phase::ALL {
+        let histogram = match phase {
+            Phase::Handshake => &timings.handshake,
+            Phase::Encrypt => &timings.encrypt,
+            Phase::Decrypt => &timings.decrypt,
+            Phase::Compress => &timings.compress,
+            Phase::Decompress => &timings.decompress,
+            Phase::Dispatch => &timings.dispatch,
+        };
+        let name = format!("{}_duration_seconds", phase.name());
+        let help = format!("Time spent in the {} phase.", phase.name());
+        out.histogram_seconds(&name, &help, histogram);
+    }
+
+    out.finish()
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Go-style float formatting, which Prometheus parses: integers without a
+/// fraction, and the special values by name.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".into() } else { "-Inf".into() }
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::timing::Stopwatch;
+    use std::sync::Arc;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_exposition_format() {
+        let mut out = Exposition::new(&[("instance", "3".into())]);
+        out.counter("sent_packets_total", "Packets sent.", 7);
+        out.gauge("connected", "Connected.", 1.0);
+        let text = out.finish();
+        assert_eq!(text, "\
+# HELP derp_sent_packets_total Packets sent.
+# TYPE derp_sent_packets_total counter
+derp_sent_packets_total{instance=\"3\"} 7
+# HELP derp_connected Connected.
+# TYPE derp_connected gauge
+derp_connected{instance=\"3\"} 1
+");
+        assert_eq!(Exposition::new(&[("page", "a\"b".into())]).labels, "page=\"a\\\"b\"");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_render_includes_histograms() {
+        let clock = Arc::new(MockClock::new(0.0));
+        let stopwatch = Stopwatch::new(clock.clone());
+        stopwatch.time(Phase::Decrypt, || clock.advance(2.0));
+
+        let stats = NetworkStats { packets_sent: 4, ..Default::default() };
+        let text = render(&[], &stats, &Gauges::default(), &stopwatch.timings());
+        assert!(text.contains("derp_sent_packets_total 4\n"));
+        assert!(text.contains("# TYPE derp_decrypt_duration_seconds histogram\n"));
+        assert!(text.contains("derp_decrypt_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("derp_decrypt_duration_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(text.contains("derp_decrypt_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("derp_decrypt_duration_seconds_sum 0.002\n"));
+        assert!(text.contains("derp_handshake_duration_seconds_count 0\n"));
+    }
+}
@@ -0,0 +1,627 @@
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, Ipv4Address};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use crate::error::{DerpError, DerpResult};
+use crate::flow::{FlowKey, FlowTable, Framing};
+use crate::ip::{self, Ipv4Packet, PROTO_TCP, PROTO_UDP};
+
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 16 * 1024;
+const UDP_PACKET_SLOTS: usize = 32;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// Local ports for connections the gateway opens toward the guest.
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// UDP has no close, so terminated UDP flows end after this much silence.
+const UDP_IDLE_TIMEOUT_MS: f64 = 60_000.0;
+
+/// A guest flow terminated by the gateway and handed to a backend.
+pub trait NatStream {
+    /// The flow was accepted. `handle` is the one later calls get, for
+    /// streams that may have something to send before the guest does.
+    fn on_open(&mut self, _handle: &NatHandle) {}
+
+    /// Data from the guest: the next chunk of a TCP stream, or one UDP datagram.
+    fn on_data(&mut self, data: &[u8], handle: &NatHandle);
+
+    /// The guest closed or reset the flow, or it timed out. Data already
+    /// queued on `handle` is still delivered.
+    fn on_close(&mut self, _handle: &NatHandle) {}
+}
+
+/// Where a backend sends a flow it claims.
+pub enum Egress {
+    /// Forward the guest's packets to the DERP relay unchanged.
+    Relay,
+    /// Terminate the flow locally and hand its payload to the stream.
+    Stream(Box<dyn NatStream>),
+}
+
+/// Egress for guest flows. Backends are offered each new TCP connection or
+/// UDP flow in order until one claims it; unclaimed TCP is reset and
+/// unclaimed UDP dropped.
+pub trait NatBackend {
+    fn open(&self, flow: &FlowKey) -> Option<Egress>;
+}
+
+/// Sends flows to the relay when their destination matches one of `routes`.
+pub struct RelayBackend {
+    routes: Vec<(Ipv4Addr, u8)>,
+}
+
+impl RelayBackend {
+    pub fn new(routes: Vec<(Ipv4Addr, u8)>) -> Self {
+        RelayBackend { routes }
+    }
+
+    /// Claims every flow; the catch-all behind more specific backends.
+    pub fn all() -> Self {
+        RelayBackend::new(vec![(Ipv4Addr::UNSPECIFIED, 0)])
+    }
+}
+
+impl NatBackend for RelayBackend {
+    fn open(&self, flow: &FlowKey) -> Option<Egress> {
+        let dst = Ipv4Addr::from(flow.dst);
+        self.routes.iter()
+            .any(|&(network, prefix)| ip::in_subnet(dst, network, prefix))
+            .then_some(Egress::Relay)
+    }
+}
+
+/// A backend's side of a terminated flow. Sends and closes are queued and
+/// picked up on the gateway's next poll, which the handle schedules, so it
+/// may be used from async tasks.
+#[derive(Clone)]
+pub struct NatHandle {
+    io: Rc<RefCell<PendingIo>>,
+    wake: Rc<dyn Fn()>,
+}
+
+#[derive(Default)]
+struct PendingIo {
+    to_guest: VecDeque<Vec<u8>>,
+    close: bool,
+}
+
+impl NatHandle {
+    /// Queues data for the guest: stream bytes for TCP, one datagram for UDP.
+    pub fn send(&self, data: &[u8]) {
+        self.io.borrow_mut().to_guest.push_back(data.to_vec());
+        (self.wake)();
+    }
+
+    /// Closes the flow once queued data has been delivered.
+    pub fn close(&self) {
+        self.io.borrow_mut().close = true;
+        (self.wake)();
+    }
+}
+
+/// Whether `route` handed a packet to the gateway or left it for the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Relay,
+    Terminated,
+}
+
+struct Connection {
+    socket: SocketHandle,
+    stream: Box<dyn NatStream>,
+    handle: NatHandle,
+    last_seen_ms: f64,
+    guest_closed: bool,
+}
+
+/// Userspace TCP/IP stack that terminates guest flows and NATs them out
+/// through pluggable backends. The guest sees every remote address answered
+/// by this gateway.
+pub struct NatGateway {
+    gateway_ip: Ipv4Addr,
+    iface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    backends: Vec<Rc<dyn NatBackend>>,
+    connections: HashMap<FlowKey, Connection>,
+    /// UDP sockets by remote endpoint; guest flows to one endpoint share it.
+    udp_sockets: HashMap<([u8; 4], u16), SocketHandle>,
+    relayed: FlowTable,
+    next_port: u16,
+    wake: Rc<dyn Fn()>,
+}
+
+impl NatGateway {
+    /// `wake` is called whenever a backend queues I/O outside a poll; it
+    /// should arrange for `poll` to run soon.
+    pub fn new(gateway_ip: Ipv4Addr, netmask: Ipv4Addr, mtu: u16, wake: Rc<dyn Fn()>) -> Self {
+        let mut device = QueueDevice {
+            mtu: mtu as usize,
+            ..QueueDevice::default()
+        };
+        let gateway = Ipv4Address(gateway_ip.octets());
+        let prefix = u32::from(netmask).leading_ones() as u8;
+
+        let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::from_millis(0));
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(gateway), prefix));
+        });
+        // Accept traffic for every destination, as a NAT router would
+        iface.set_any_ip(true);
+        let _ = iface.routes_mut().add_default_ipv4_route(gateway);
+
+        NatGateway {
+            gateway_ip,
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            backends: Vec::new(),
+            connections: HashMap::new(),
+            udp_sockets: HashMap::new(),
+            relayed: FlowTable::new(),
+            next_port: *EPHEMERAL_PORTS.start(),
+            wake,
+        }
+    }
+
+    /// Appends a backend; earlier backends get first refusal.
+    pub fn add_backend(&mut self, backend: Rc<dyn NatBackend>) {
+        self.backends.push(backend);
+    }
+
+    /// Takes an IPv4 packet from the guest. Terminated packets are queued
+    /// for the next `poll`; relayed ones are left for the caller to send.
+    pub fn route(&mut self, packet: &[u8], now_ms: f64) -> Verdict {
+        let Some(flow) = FlowKey::from_ipv4(packet) else { return Verdict::Relay };
+        if flow.protocol != PROTO_TCP && flow.protocol != PROTO_UDP {
+            return Verdict::Relay;
+        }
+
+        if self.relayed.contains(&flow) {
+            self.relayed.track(flow, Framing::Ip, packet, now_ms);
+            return Verdict::Relay;
+        }
+
+        if !self.connections.contains_key(&flow) && opens_flow(&flow, packet) {
+            match self.backends.iter().find_map(|backend| backend.open(&flow)) {
+                Some(Egress::Relay) => {
+                    self.relayed.track(flow, Framing::Ip, packet, now_ms);
+                    return Verdict::Relay;
+                }
+                Some(Egress::Stream(stream)) => {
+                    self.accept(flow, stream, now_ms);
+                    // Listeners match on the remote endpoint alone, so the SYN
+                    // must reach its socket before another flow adds one
+                    self.device.rx.push_back(packet.to_vec());
+                    self.iface.poll(Instant::from_millis(now_ms as i64), &mut self.device, &mut self.sockets);
+                    return Verdict::Terminated;
+                }
+                None => {}
+            }
+        }
+
+        self.device.rx.push_back(packet.to_vec());
+        Verdict::Terminated
+    }
+
+    /// Runs the stack: feeds queued guest packets through it, moves data
+    /// between sockets and backends, and returns IPv4 packets for the guest.
+    pub fn poll(&mut self, now_ms: f64) -> Vec<Vec<u8>> {
+        let now = Instant::from_millis(now_ms as i64);
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+        self.service(now_ms);
+        // Push out whatever the backends queued during service
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+        self.expire(now_ms);
+        std::mem::take(&mut self.device.tx)
+    }
+
+    /// Milliseconds until the stack next needs polling for retransmits and
+    /// delayed ACKs, if at all.
+    pub fn poll_delay(&mut self, now_ms: f64) -> Option<u64> {
+        self.iface
+            .poll_delay(Instant::from_millis(now_ms as i64), &self.sockets)
+            .map(|delay| delay.total_millis())
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Opens a TCP connection from the gateway to a service in the guest.
+    /// `open` builds the stream for the guest's side of it, given the handle
+    /// that sends toward the guest. The SYN goes out on the next `poll`.
+    pub fn connect(
+        &mut self,
+        guest: SocketAddrV4,
+        now_ms: f64,
+        open: impl FnOnce(&NatHandle) -> Box<dyn NatStream>,
+    ) -> DerpResult<()> {
+        let local_port = self.allocate_port()?;
+        // Keyed as the guest sees it, so its replies find the connection
+        let flow = FlowKey {
+            protocol: PROTO_TCP,
+            src: guest.ip().octets(),
+            dst: self.gateway_ip.octets(),
+            src_port: guest.port(),
+            dst_port: local_port,
+        };
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        let local = IpListenEndpoint {
+            addr: Some(IpAddress::Ipv4(Ipv4Address(flow.dst))),
+            port: local_port,
+        };
+        socket.connect(self.iface.context(), endpoint(flow.src, flow.src_port), local)
+            .map_err(|e| DerpError::InvalidState(format!("Cannot connect to {}: {}", guest, e)))?;
+
+        let socket = self.sockets.add(socket);
+        let handle = self.new_handle();
+        let stream = open(&handle);
+        self.connections.insert(flow, Connection {
+            socket,
+            stream,
+            handle,
+            last_seen_ms: now_ms,
+            guest_closed: false,
+        });
+        Ok(())
+    }
+
+    /// Picks the next gateway port no open connection toward the guest uses.
+    fn allocate_port(&mut self) -> DerpResult<u16> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = if port == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { port + 1 };
+            let in_use = self.connections.keys()
+                .any(|flow| flow.dst == self.gateway_ip.octets() && flow.dst_port == port);
+            if !in_use {
+                return Ok(port);
+            }
+        }
+        Err(DerpError::InvalidState("No free ports for forwarded connections".into()))
+    }
+
+    fn new_handle(&self) -> NatHandle {
+        NatHandle {
+            io: Rc::new(RefCell::new(PendingIo::default())),
+            wake: self.wake.clone(),
+        }
+    }
+
+    fn accept(&mut self, flow: FlowKey, mut stream: Box<dyn NatStream>, now_ms: f64) {
+        let remote = IpListenEndpoint {
+            addr: Some(IpAddress::Ipv4(Ipv4Address(flow.dst))),
+            port: flow.dst_port,
+        };
+
+        let socket = if flow.protocol == PROTO_TCP {
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            );
+            if socket.listen(remote).is_err() {
+                return;
+            }
+            self.sockets.add(socket)
+        } else {
+            match self.udp_sockets.get(&(flow.dst, flow.dst_port)) {
+                Some(&socket) => socket,
+                None => {
+                    let mut socket = udp::Socket::new(
+                        udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKET_SLOTS], vec![0; UDP_BUFFER_SIZE]),
+                        udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKET_SLOTS], vec![0; UDP_BUFFER_SIZE]),
+                    );
+                    if socket.bind(remote).is_err() {
+                        return;
+                    }
+                    let socket = self.sockets.add(socket);
+                    self.udp_sockets.insert((flow.dst, flow.dst_port), socket);
+                    socket
+                }
+            }
+        };
+
+        let handle = self.new_handle();
+        stream.on_open(&handle);
+        self.connections.insert(flow, Connection {
+            socket,
+            stream,
+            handle,
+            last_seen_ms: now_ms,
+            guest_closed: false,
+        });
+    }
+
+    fn service(&mut self, now_ms: f64) {
+        // UDP sockets are shared, so datagrams are matched to flows by sender
+        for (&(dst, dst_port), &socket) in &self.udp_sockets {
+            let socket = self.sockets.get_mut::<udp::Socket>(socket);
+            while let Ok((data, meta)) = socket.recv() {
+                let flow = self.connections.iter_mut().find(|(flow, _)| {
+                    flow.protocol == PROTO_UDP
+                        && flow.dst == dst
+                        && flow.dst_port == dst_port
+                        && meta.endpoint == endpoint(flow.src, flow.src_port)
+                });
+                if let Some((_, connection)) = flow {
+                    connection.last_seen_ms = now_ms;
+                    connection.stream.on_data(data, &connection.handle);
+                }
+            }
+        }
+
+        for (flow, connection) in self.connections.iter_mut() {
+            if flow.protocol == PROTO_UDP {
+                let socket = self.sockets.get_mut::<udp::Socket>(connection.socket);
+                let mut io = connection.handle.io.borrow_mut();
+                while let Some(datagram) = io.to_guest.front() {
+                    if socket.send_slice(datagram, endpoint(flow.src, flow.src_port)).is_err() {
+                        break;
+                    }
+                    io.to_guest.pop_front();
+                }
+                continue;
+            }
+
+            let socket = self.sockets.get_mut::<tcp::Socket>(connection.socket);
+            while socket.can_recv() {
+                connection.last_seen_ms = now_ms;
+                let stream = &mut connection.stream;
+                let handle = &connection.handle;
+                let _ = socket.recv(|data| {
+                    stream.on_data(data, handle);
+                    (data.len(), ())
+                });
+            }
+
+            if !connection.guest_closed && guest_done(socket.state()) {
+                connection.guest_closed = true;
+                connection.stream.on_close(&connection.handle);
+            }
+
+            let mut io = connection.handle.io.borrow_mut();
+            while socket.may_send() {
+                let Some(chunk) = io.to_guest.front_mut() else { break };
+                let sent = socket.send_slice(chunk).unwrap_or(0);
+                if sent < chunk.len() {
+                    chunk.drain(..sent);
+                    break;
+                }
+                io.to_guest.pop_front();
+            }
+            if io.close && io.to_guest.is_empty() {
+                socket.close();
+            }
+        }
+    }
+
+    fn expire(&mut self, now_ms: f64) {
+        self.relayed.expire(now_ms);
+
+        let mut finished = Vec::new();
+        for (flow, connection) in &self.connections {
+            let done = if flow.protocol == PROTO_UDP {
+                connection.handle.io.borrow().close || now_ms - connection.last_seen_ms >= UDP_IDLE_TIMEOUT_MS
+            } else {
+                // Listen means the handshake was reset before it completed
+                matches!(
+                    self.sockets.get::<tcp::Socket>(connection.socket).state(),
+                    tcp::State::Closed | tcp::State::TimeWait | tcp::State::Listen
+                )
+            };
+            if done {
+                finished.push(*flow);
+            }
+        }
+
+        for flow in finished {
+            let Some(mut connection) = self.connections.remove(&flow) else { continue };
+            if !connection.guest_closed {
+                connection.stream.on_close(&connection.handle);
+            }
+
+            if flow.protocol == PROTO_TCP {
+                self.sockets.remove(connection.socket);
+                continue;
+            }
+            let in_use = self.connections.values().any(|other| other.socket == connection.socket);
+            if !in_use {
+                self.sockets.remove(connection.socket);
+                self.udp_sockets.remove(&(flow.dst, flow.dst_port));
+            }
+        }
+    }
+}
+
+/// Whether this packet may start a flow: a bare SYN for TCP, anything for UDP.
+fn opens_flow(flow: &FlowKey, packet: &[u8]) -> bool {
+    if flow.protocol != PROTO_TCP {
+        return true;
+    }
+    let flags = Ipv4Packet::parse(packet)
+        .and_then(|ip| ip.payload.get(13).copied())
+        .unwrap_or(0);
+    flags & TCP_SYN != 0 && flags & TCP_ACK == 0
+}
+
+/// The guest has sent its FIN, or the connection is gone altogether.
+fn guest_done(state: tcp::State) -> bool {
+    matches!(
+        state,
+        tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait | tcp::State::Closed
+    )
+}
+
+fn endpoint(address: [u8; 4], port: u16) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(address)), port)
+}
+
+/// An IP-medium device backed by packet queues.
+#[derive(Default)]
+struct QueueDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = QueueRxToken where Self: 'a;
+    type TxToken<'a> = QueueTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((QueueRxToken(packet), QueueTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(QueueTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = self.mtu;
+        capabilities
+    }
+}
+
+struct QueueRxToken(Vec<u8>);
+
+impl phy::RxToken for QueueRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct QueueTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl<'a> phy::TxToken for QueueTxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push(packet);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GUEST: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 100);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    struct Echo;
+
+    impl NatStream for Echo {
+        fn on_data(&mut self, data: &[u8], handle: &NatHandle) {
+            handle.send(data);
+        }
+    }
+
+    struct EchoBackend;
+
+    impl NatBackend for EchoBackend {
+        fn open(&self, flow: &FlowKey) -> Option<Egress> {
+            (flow.dst_port == 7).then(|| Egress::Stream(Box::new(Echo)))
+        }
+    }
+
+    fn gateway() -> NatGateway {
+        let mut gateway = NatGateway::new(
+            Ipv4Addr::new(192, 168, 86, 1),
+            Ipv4Addr::new(255, 255, 255, 0),
+            1500,
+            Rc::new(|| {}),
+        );
+        gateway.add_backend(Rc::new(EchoBackend));
+        gateway.add_backend(Rc::new(RelayBackend::new(vec![(Ipv4Addr::new(100, 64, 0, 0), 10)])));
+        gateway
+    }
+
+    fn syn(dst: Ipv4Addr, dst_port: u16) -> Vec<u8> {
+        let mut segment = vec![0u8; 20];
+        segment[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        segment[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&1u32.to_be_bytes());
+        segment[12] = 5 << 4;
+        segment[13] = TCP_SYN;
+        segment[14..16].copy_from_slice(&8192u16.to_be_bytes());
+        let checksum = ip::checksum(&ip::pseudo_header(GUEST, dst, PROTO_TCP, &segment));
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        ip::build_ipv4(GUEST, dst, PROTO_TCP, &segment)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_claimed_syn_is_answered_by_remote() {
+        let mut gateway = gateway();
+        assert_eq!(gateway.route(&syn(REMOTE, 7), 0.0), Verdict::Terminated);
+        assert_eq!(gateway.connection_count(), 1);
+
+        let replies = gateway.poll(0.0);
+        let reply = Ipv4Packet::parse(&replies[0]).unwrap();
+        assert_eq!((reply.src, reply.dst), (REMOTE, GUEST));
+        // SYN-ACK
+        assert_eq!(reply.payload[13] & (TCP_SYN | TCP_ACK), TCP_SYN | TCP_ACK);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_connect_to_guest() {
+        let mut gateway = gateway();
+        gateway.connect(SocketAddrV4::new(GUEST, 22), 0.0, |_| Box::new(Echo)).unwrap();
+        assert_eq!(gateway.connection_count(), 1);
+
+        let packets = gateway.poll(0.0);
+        let syn = Ipv4Packet::parse(&packets[0]).unwrap();
+        assert_eq!((syn.src, syn.dst), (Ipv4Addr::new(192, 168, 86, 1), GUEST));
+        assert_eq!(&syn.payload[2..4], &22u16.to_be_bytes());
+        assert_eq!(syn.payload[13] & (TCP_SYN | TCP_ACK), TCP_SYN);
+
+        // The guest refuses, which ends the connection
+        let mut rst = vec![0u8; 20];
+        rst[0..2].copy_from_slice(&22u16.to_be_bytes());
+        rst[2..4].copy_from_slice(&syn.payload[0..2]);
+        let ack = u32::from_be_bytes([syn.payload[4], syn.payload[5], syn.payload[6], syn.payload[7]]) + 1;
+        rst[8..12].copy_from_slice(&ack.to_be_bytes());
+        rst[12] = 5 << 4;
+        rst[13] = 0x04 | TCP_ACK;
+        let checksum = ip::checksum(&ip::pseudo_header(GUEST, syn.src, PROTO_TCP, &rst));
+        rst[16..18].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(gateway.route(&ip::build_ipv4(GUEST, syn.src, PROTO_TCP, &rst), 0.0), Verdict::Terminated);
+        gateway.poll(0.0);
+        assert_eq!(gateway.connection_count(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_relay_routes_and_unclaimed_flows() {
+        let mut gateway = gateway();
+        assert_eq!(gateway.route(&syn(Ipv4Addr::new(100, 64, 1, 2), 22), 0.0), Verdict::Relay);
+
+        // Nobody claims port 22 elsewhere, so the guest gets a reset
+        assert_eq!(gateway.route(&syn(REMOTE, 22), 0.0), Verdict::Terminated);
+        let replies = gateway.poll(0.0);
+        let reply = Ipv4Packet::parse(&replies[0]).unwrap();
+        assert_ne!(reply.payload[13] & 0x04, 0);
+        assert_eq!(gateway.connection_count(), 0);
+    }
+}
@@ -0,0 +1,273 @@
+use std::net::Ipv6Addr;
+use crate::ip::{self, Ipv6Packet, PROTO_ICMPV6};
+
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+const OPT_SOURCE_LINK_ADDR: u8 = 1;
+const OPT_TARGET_LINK_ADDR: u8 = 2;
+const OPT_PREFIX_INFO: u8 = 3;
+const OPT_MTU: u8 = 5;
+/// Recursive DNS servers (RFC 8106).
+const OPT_RDNSS: u8 = 25;
+
+const NA_ROUTER: u8 = 0x80;
+const NA_SOLICITED: u8 = 0x40;
+const NA_OVERRIDE: u8 = 0x20;
+/// Other configuration, DNS servers included, is available over DHCPv6.
+const RA_OTHER_CONFIG: u8 = 0x40;
+/// Prefix usable for SLAAC. The on-link flag stays clear so the guest sends
+/// everything, including other prefix addresses, through the gateway.
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+/// NDP packets must arrive with the maximum hop limit (RFC 4861 §7.1).
+const NDP_HOP_LIMIT: u8 = 255;
+const ROUTER_LIFETIME_SECS: u16 = 1800;
+const PREFIX_VALID_SECS: u32 = 86_400;
+const PREFIX_PREFERRED_SECS: u32 = 14_400;
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// An IPv6 packet for the guest and the MAC address to send it to.
+pub struct NdpReply {
+    pub dst_mac: [u8; 6],
+    pub packet: Vec<u8>,
+}
+
+/// Plays the IPv6 router: answers neighbor solicitations for the gateway and
+/// router solicitations with an advertisement carrying the SLAAC prefix and,
+/// if set, the DNS servers.
+pub struct NdpResponder {
+    gateway_mac: [u8; 6],
+    link_local: Ipv6Addr,
+    prefix: Option<Ipv6Addr>,
+    mtu: u16,
+    dns_servers: Vec<Ipv6Addr>,
+    /// Whether advertisements point the guest at DHCPv6 for the rest.
+    other_config: bool,
+}
+
+impl NdpResponder {
+    /// Without a `prefix` the gateway still advertises itself as the default
+    /// router, but the guest only has its link-local address.
+    pub fn new(gateway_mac: [u8; 6], prefix: Option<Ipv6Addr>, mtu: u16) -> Self {
+        NdpResponder {
+            gateway_mac,
+            link_local: link_local(gateway_mac),
+            prefix,
+            mtu,
+            dns_servers: Vec::new(),
+            other_config: false,
+        }
+    }
+
+    /// Advertises `dns_servers`, and with `dhcpv6` sets the flag telling
+    /// the guest to ask for them over stateless DHCPv6 too.
+    pub fn with_dns(mut self, dns_servers: Vec<Ipv6Addr>, dhcpv6: bool) -> Self {
+        self.dns_servers = dns_servers;
+        self.other_config = dhcpv6;
+        self
+    }
+
+    /// Handles an IPv6 packet from the guest. Returns the reply if it was a
+    /// solicitation the gateway should answer.
+    pub fn handle(&self, packet: &[u8], sender_mac: [u8; 6]) -> Option<NdpReply> {
+        let ip = Ipv6Packet::parse(packet)?;
+        if ip.next_header != PROTO_ICMPV6 || ip.hop_limit != NDP_HOP_LIMIT || ip.payload.len() < 8 {
+            return None;
+        }
+        if ip::checksum(&ip::pseudo_header_v6(ip.src, ip.dst, PROTO_ICMPV6, ip.payload)) != 0 {
+            return None;
+        }
+
+        // Solicitations from an unspecified source (address autoconfiguration
+        // in progress) can only be answered by multicast
+        let (dst, dst_mac) = if ip.src.is_unspecified() {
+            (ALL_NODES, multicast_mac(ALL_NODES))
+        } else {
+            (ip.src, sender_mac)
+        };
+
+        match ip.payload[0] {
+            ROUTER_SOLICITATION => {
+                let message = self.router_advertisement();
+                Some(self.reply(self.link_local, dst, dst_mac, message))
+            }
+            NEIGHBOR_SOLICITATION if ip.payload.len() >= 24 => {
+                let mut target = [0u8; 16];
+                target.copy_from_slice(&ip.payload[8..24]);
+                let target = Ipv6Addr::from(target);
+                if !self.is_gateway(target) {
+                    return None;
+                }
+
+                let mut flags = NA_ROUTER | NA_OVERRIDE;
+                if !ip.src.is_unspecified() {
+                    flags |= NA_SOLICITED;
+                }
+                let mut message = vec![NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+                message.extend_from_slice(&target.octets());
+                message.extend_from_slice(&[OPT_TARGET_LINK_ADDR, 1]);
+                message.extend_from_slice(&self.gateway_mac);
+                Some(self.reply(target, dst, dst_mac, message))
+            }
+            _ => None,
+        }
+    }
+
+    /// The gateway's global address, the first in the advertised prefix.
+    pub fn gateway_address(&self) -> Option<Ipv6Addr> {
+        self.prefix.map(|prefix| {
+            let mut octets = prefix.octets();
+            octets[15] = 1;
+            Ipv6Addr::from(octets)
+        })
+    }
+
+    /// Every address the gateway answers to.
+    pub fn addresses(&self) -> Vec<Ipv6Addr> {
+        std::iter::once(self.link_local).chain(self.gateway_address()).collect()
+    }
+
+    fn is_gateway(&self, address: Ipv6Addr) -> bool {
+        address == self.link_local || Some(address) == self.gateway_address()
+    }
+
+    fn router_advertisement(&self) -> Vec<u8> {
+        let flags = if self.other_config { RA_OTHER_CONFIG } else { 0 };
+        let mut message = vec![ROUTER_ADVERTISEMENT, 0, 0, 0, 64, flags];
+        message.extend_from_slice(&ROUTER_LIFETIME_SECS.to_be_bytes());
+        // Reachable time and retransmit timer left to the guest
+        message.extend_from_slice(&[0; 8]);
+
+        message.extend_from_slice(&[OPT_SOURCE_LINK_ADDR, 1]);
+        message.extend_from_slice(&self.gateway_mac);
+        message.extend_from_slice(&[OPT_MTU, 1, 0, 0]);
+        message.extend_from_slice(&(self.mtu as u32).to_be_bytes());
+
+        if let Some(prefix) = self.prefix {
+            message.extend_from_slice(&[OPT_PREFIX_INFO, 4, 64, PREFIX_AUTONOMOUS]);
+            message.extend_from_slice(&PREFIX_VALID_SECS.to_be_bytes());
+            message.extend_from_slice(&PREFIX_PREFERRED_SECS.to_be_bytes());
+            message.extend_from_slice(&[0; 4]);
+            message.extend_from_slice(&prefix.octets());
+        }
+
+        if !self.dns_servers.is_empty() {
+            // Length in units of 8 octets: the header, then 16 per address
+            message.extend_from_slice(&[OPT_RDNSS, 1 + 2 * self.dns_servers.len() as u8, 0, 0]);
+            message.extend_from_slice(&(ROUTER_LIFETIME_SECS as u32).to_be_bytes());
+            for server in &self.dns_servers {
+                message.extend_from_slice(&server.octets());
+            }
+        }
+        message
+    }
+
+    fn reply(&self, src: Ipv6Addr, dst: Ipv6Addr, dst_mac: [u8; 6], mut message: Vec<u8>) -> NdpReply {
+        let checksum = ip::checksum(&ip::pseudo_header_v6(src, dst, PROTO_ICMPV6, &message));
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        NdpReply {
+            dst_mac,
+            packet: ip::build_ipv6(src, dst, PROTO_ICMPV6, NDP_HOP_LIMIT, &message),
+        }
+    }
+}
+
+/// The EUI-64 link-local address for `mac`.
+pub fn link_local(mac: [u8; 6]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfe;
+    octets[1] = 0x80;
+    octets[8..11].copy_from_slice(&[mac[0] ^ 0x02, mac[1], mac[2]]);
+    octets[11..13].copy_from_slice(&[0xff, 0xfe]);
+    octets[13..16].copy_from_slice(&mac[3..6]);
+    Ipv6Addr::from(octets)
+}
+
+/// The Ethernet address an IPv6 multicast group maps to (RFC 2464 §7).
+pub fn multicast_mac(group: Ipv6Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x33, 0x33, octets[12], octets[13], octets[14], octets[15]]
+}
+
+pub fn is_multicast_mac(mac: &[u8]) -> bool {
+    mac.starts_with(&[0x33, 0x33])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GATEWAY_MAC: [u8; 6] = [0x02, 0x86, 0, 0, 0, 1];
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn icmp(src: Ipv6Addr, dst: Ipv6Addr, mut message: Vec<u8>) -> Vec<u8> {
+        let checksum = ip::checksum(&ip::pseudo_header_v6(src, dst, PROTO_ICMPV6, &message));
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        ip::build_ipv6(src, dst, PROTO_ICMPV6, NDP_HOP_LIMIT, &message)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_router_solicitation_gets_prefix() {
+        let prefix: Ipv6Addr = "fd86:86::".parse().unwrap();
+        let responder = NdpResponder::new(GATEWAY_MAC, Some(prefix), 1500);
+        let solicitation = icmp(
+            link_local(GUEST_MAC),
+            "ff02::2".parse().unwrap(),
+            vec![ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0],
+        );
+
+        let reply = responder.handle(&solicitation, GUEST_MAC).unwrap();
+        assert_eq!(reply.dst_mac, GUEST_MAC);
+        let ip = Ipv6Packet::parse(&reply.packet).unwrap();
+        assert_eq!(ip.src, link_local(GATEWAY_MAC));
+        assert_eq!(ip.payload[0], ROUTER_ADVERTISEMENT);
+        assert_eq!(ip::checksum(&ip::pseudo_header_v6(ip.src, ip.dst, PROTO_ICMPV6, ip.payload)), 0);
+        assert_eq!(&ip.payload[ip.payload.len() - 16..], &prefix.octets());
+        assert_eq!(ip.payload[5], 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_router_advertisement_carries_dns() {
+        let dns: Ipv6Addr = "fd86:86::1".parse().unwrap();
+        let responder = NdpResponder::new(GATEWAY_MAC, None, 1500).with_dns(vec![dns], true);
+        let solicitation = icmp(
+            link_local(GUEST_MAC),
+            "ff02::2".parse().unwrap(),
+            vec![ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0],
+        );
+
+        let reply = responder.handle(&solicitation, GUEST_MAC).unwrap();
+        let ip = Ipv6Packet::parse(&reply.packet).unwrap();
+        assert_eq!(ip.payload[5], RA_OTHER_CONFIG);
+        let rdnss = &ip.payload[ip.payload.len() - 24..];
+        assert_eq!(&rdnss[..4], &[OPT_RDNSS, 3, 0, 0]);
+        assert_eq!(&rdnss[8..], &dns.octets());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_neighbor_solicitation_for_gateway() {
+        let responder = NdpResponder::new(GATEWAY_MAC, None, 1500);
+        let gateway = link_local(GATEWAY_MAC);
+        let solicit = |target: Ipv6Addr| {
+            let mut message = vec![NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+            message.extend_from_slice(&target.octets());
+            icmp(link_local(GUEST_MAC), target, message)
+        };
+
+        let reply = responder.handle(&solicit(gateway), GUEST_MAC).unwrap();
+        let ip = Ipv6Packet::parse(&reply.packet).unwrap();
+        assert_eq!(ip.payload[0], NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(ip.payload[4], NA_ROUTER | NA_SOLICITED | NA_OVERRIDE);
+        assert_eq!(&ip.payload[26..32], &GATEWAY_MAC);
+
+        assert!(responder.handle(&solicit("fe80::1234".parse().unwrap()), GUEST_MAC).is_none());
+    }
+}
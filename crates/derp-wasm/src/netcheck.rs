@@ -0,0 +1,181 @@
+use serde::Serialize;
+use std::cmp::Ordering;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use js_sys::Promise;
+use web_sys::{Request, RequestCache, RequestInit, RequestMode, Response};
+use crate::clock::Clock;
+use crate::error::{DerpError, DerpResult};
+use crate::timer;
+
+pub use crate::derpmap::Region;
+
+/// Probes per region; the fastest counts, as the first may pay for DNS and
+/// the TLS handshake.
+const PROBES_PER_REGION: usize = 3;
+const PROBE_TIMEOUT_MS: i32 = 3000;
+
+/// A better region must beat the current home by this fraction of its
+/// latency, and by `REHOME_MIN_MS`, before the connection moves. Probes are
+/// noisy, and re-homing drops every peer for a moment.
+const REHOME_FRACTION: f64 = 1.0 / 3.0;
+const REHOME_MIN_MS: f64 = 10.0;
+
+// fetch is a global in both windows and workers, like the timer functions.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(request: &Request) -> Promise;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct RegionLatency {
+    pub region_id: u32,
+    pub name: String,
+    /// Null when every probe failed or timed out.
+    pub latency_ms: Option<f64>,
+}
+
+/// What `netcheck` found, fastest region first and unreachable ones last.
+#[derive(Debug, Clone, Default, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct NetcheckReport {
+    pub regions: Vec<RegionLatency>,
+    /// The fastest reachable region.
+    pub preferred: Option<u32>,
+}
+
+impl NetcheckReport {
+    pub fn new(mut regions: Vec<RegionLatency>) -> Self {
+        regions.sort_by(|a, b| match (a.latency_ms, b.latency_ms) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+        let preferred = regions.first().filter(|region| region.latency_ms.is_some()).map(|region| region.region_id);
+        NetcheckReport { regions, preferred }
+    }
+
+    pub fn latency(&self, region_id: u32) -> Option<f64> {
+        self.regions.iter().find(|region| region.region_id == region_id)?.latency_ms
+    }
+
+    /// The region to be homed on, given the current home: the preferred one
+    /// if it's clearly faster or the current home has become unreachable.
+    pub fn choose_home(&self, current: Option<u32>) -> Option<u32> {
+        let preferred = self.preferred?;
+        let current_ms = match current.and_then(|id| self.latency(id)) {
+            Some(ms) => ms,
+            None => return Some(preferred),
+        };
+        let preferred_ms = self.latency(preferred)?;
+        let saving = current_ms - preferred_ms;
+        if saving > REHOME_MIN_MS && saving > current_ms * REHOME_FRACTION {
+            Some(preferred)
+        } else {
+            current
+        }
+    }
+}
+
+/// Payload of the "home" event, emitted when the connection moves to a
+/// different region.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct HomeEvent {
+    pub region_id: u32,
+    pub previous: Option<u32>,
+    pub latency_ms: Option<f64>,
+}
+
+/// Probes every region at once.
+pub async fn run(regions: &[Region], clock: &dyn Clock) -> NetcheckReport {
+    let probes = regions.iter().map(|region| async move {
+        let latency_ms = match region.probe_url() {
+            Ok(url) => probe_region(&url, clock).await,
+            Err(e) => {
+                log::warn!("Not probing region {}: {}", region.id, e);
+                None
+            }
+        };
+        RegionLatency { region_id: region.id, name: region.name.clone(), latency_ms }
+    });
+    let report = NetcheckReport::new(futures::future::join_all(probes).await);
+    log::debug!("Netcheck: {:?}", report.regions);
+    report
+}
+
+async fn probe_region(url: &str, clock: &dyn Clock) -> Option<f64> {
+    let mut best: Option<f64> = None;
+    for _ in 0..PROBES_PER_REGION {
+        match probe(url, clock).await {
+            Ok(ms) => best = Some(best.map_or(ms, |best| best.min(ms))),
+            Err(e) => log::debug!("Probe of {} failed: {}", url, e),
+        }
+    }
+    best
+}
+
+/// Times one uncached request. The response is opaque, since relays don't
+/// send CORS headers, but its arrival is all that's measured.
+async fn probe(url: &str, clock: &dyn Clock) -> DerpResult<f64> {
+    let init = RequestInit::new();
+    init.set_method("GET");
+    init.set_mode(RequestMode::NoCors);
+    init.set_cache(RequestCache::NoStore);
+    let request = Request::new_with_str_and_init(url, &init)
+        .map_err(|e| DerpError::InvalidState(format!("Invalid probe URL {}: {:?}", url, e)))?;
+
+    let timeout = Promise::new(&mut |resolve, _| {
+        timer::set_timeout(&resolve, PROBE_TIMEOUT_MS);
+    });
+    let started = clock.now_ms();
+    let winner = JsFuture::from(Promise::race(&js_sys::Array::of2(&fetch_with_request(&request), &timeout))).await
+        .map_err(|e| DerpError::WebSocketError(format!("Probe failed: {:?}", e)))?;
+    if !winner.is_instance_of::<Response>() {
+        return Err(DerpError::Timeout(format!("No answer within {} ms", PROBE_TIMEOUT_MS)));
+    }
+    Ok(clock.now_ms() - started)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn latency(region_id: u32, latency_ms: Option<f64>) -> RegionLatency {
+        RegionLatency { region_id, name: String::new(), latency_ms }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_report_ranks_reachable_regions_first() {
+        let report = NetcheckReport::new(vec![latency(1, None), latency(2, Some(80.0)), latency(3, Some(20.0))]);
+        let order: Vec<u32> = report.regions.iter().map(|region| region.region_id).collect();
+        assert_eq!(order, [3, 2, 1]);
+        assert_eq!(report.preferred, Some(3));
+        assert_eq!(NetcheckReport::new(vec![latency(1, None)]).preferred, None);
+
+        let region = Region { id: 1, name: String::new(), url: "wss://relay.example:8443/derp?x=1".into(), probe_url: None };
+        assert_eq!(region.probe_url().unwrap(), "https://relay.example:8443/derp/latency-check");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_rehoming_needs_a_clear_improvement() {
+        let report = NetcheckReport::new(vec![latency(1, Some(50.0)), latency(2, Some(40.0)), latency(3, None)]);
+        // 10 ms faster, but not by a third
+        assert_eq!(report.choose_home(Some(1)), Some(1));
+        assert_eq!(report.choose_home(None), Some(2));
+        // The current home no longer answers
+        assert_eq!(report.choose_home(Some(3)), Some(2));
+
+        let report = NetcheckReport::new(vec![latency(1, Some(90.0)), latency(2, Some(40.0))]);
+        assert_eq!(report.choose_home(Some(1)), Some(2));
+    }
+}
@@ -0,0 +1,2010 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{MessageEvent, CloseEvent, CloseEventInit, ErrorEvent};
+use js_sys::Uint8Array;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use tsify::Tsify;
+use super::{
+    acl::AclStats,
+    backpressure::{SendGate, DRAIN_POLL_MS},
+    channel::{self, Channels},
+    clock::{self, Clock},
+    config::DerpConfig,
+    connection::{ConnectionState, ConnectionStatus},
+    control::{ControlDocument, ControlEvent},
+    crypto::CryptoState,
+    demux,
+    driver::{Action, Driver, Input},
+    ethernet::Cast,
+    events::{Deferral, DisconnectEvent, EventDispatcher, EventKind, PeerEvent, ReconnectedEvent, ReconnectingEvent},
+    flow::{FlowKey, FlowTable, Framing},
+    idle::IdleWatch,
+    inbox::{Inbox, Inbound, WeakInbox},
+    mesh::ForwardHeader,
+    metrics::{self, Gauges},
+    outbox::Outbox,
+    pmtu,
+    priority::Priority,
+    protocol::{hex_encode, Frame, PeerKey, ProtocolState, FrameType, HandshakeState},
+    snapshot::NetworkSnapshot,
+    shared::{Guard, Shared, WeakShared},
+    simulate::{NetworkConditions, SimulatedTransport, Simulation},
+    timer,
+    timing::{Phase, Stopwatch, Timings},
+    trace,
+    transport::{self, Loopback, Receiver, SocketMode, Transport},
+    error::{DerpError, DerpResult},
+};
+#[cfg(any(test, feature = "test-support"))]
+use crate::test_support::MockWebSocket;
+
+const KEEPALIVE_TICK_MS: i32 = 1000;
+/// Close code reported when a silent relay is given up on; 4000–4999 are
+/// left to applications.
+const KEEPALIVE_TIMEOUT_CODE: u16 = 4000;
+/// A pending batch is flushed early rather than grown past this size, or
+/// past what the link carries in `bandwidth::BATCH_TARGET_MS` once that's
+/// known.
+const MAX_BATCH_SIZE: usize = 16 * 1024;
+
+#[derive(Default, Clone, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct NetworkStats {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub packets_sent: u64,
+    pub reconnect_attempts: u32,
+    /// Pings to the virtual gateway, and the replies it sent.
+    pub echo_requests: u64,
+    pub echo_replies: u64,
+    /// Broadcast and multicast frames from the guest, and those delivered to it.
+    pub broadcast_sent: u64,
+    pub multicast_sent: u64,
+    pub broadcast_received: u64,
+    pub multicast_received: u64,
+    /// Times the relay socket passed `sendHighWatermark`, and packets
+    /// dropped while it was over.
+    pub congestion_events: u64,
+    pub backpressure_drops: u64,
+    /// Relay messages that weren't valid frames, frames that didn't
+    /// authenticate, and packets larger than was negotiated. Each is also
+    /// reported as an "error" event.
+    pub decode_errors: u64,
+    pub decrypt_failures: u64,
+    pub oversized_frames: u64,
+    /// Writes to the relay socket that failed.
+    pub send_failures: u64,
+}
+
+/// Lock-free counters behind `NetworkStats`, so the send path, the receive
+/// handler and `get_stats` never wait on each other.
+#[derive(Default)]
+pub struct StatsCounters {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: AtomicU64,
+    packets_sent: AtomicU64,
+    reconnect_attempts: AtomicU32,
+    echo_requests: AtomicU64,
+    echo_replies: AtomicU64,
+    broadcast_sent: AtomicU64,
+    multicast_sent: AtomicU64,
+    broadcast_received: AtomicU64,
+    multicast_received: AtomicU64,
+    congestion_events: AtomicU64,
+    backpressure_drops: AtomicU64,
+    decode_errors: AtomicU64,
+    decrypt_failures: AtomicU64,
+    oversized_frames: AtomicU64,
+    send_failures: AtomicU64,
+}
+
+impl StatsCounters {
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_echo_request(&self) {
+        self.echo_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_echo_reply(&self) {
+        self.echo_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a guest frame sent with `cast` addressing. Unicast isn't counted.
+    pub fn record_cast_sent(&self, cast: Cast) {
+        match cast {
+            Cast::Broadcast => self.broadcast_sent.fetch_add(1, Ordering::Relaxed),
+            Cast::Multicast => self.multicast_sent.fetch_add(1, Ordering::Relaxed),
+            Cast::Unicast => 0,
+        };
+    }
+
+    pub fn record_cast_received(&self, cast: Cast) {
+        match cast {
+            Cast::Broadcast => self.broadcast_received.fetch_add(1, Ordering::Relaxed),
+            Cast::Multicast => self.multicast_received.fetch_add(1, Ordering::Relaxed),
+            Cast::Unicast => 0,
+        };
+    }
+
+    pub fn record_congestion(&self) {
+        self.congestion_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_backpressure_drop(&self) {
+        self.backpressure_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an error that dropped a relay message under its category.
+    /// Errors that fit none, like a refused handshake, aren't counted.
+    pub fn record_error(&self, error: &DerpError) {
+        match error {
+            DerpError::InvalidProtocol(_) | DerpError::SerializationError(_) => self.decode_errors.fetch_add(1, Ordering::Relaxed),
+            DerpError::CryptoError(_) => self.decrypt_failures.fetch_add(1, Ordering::Relaxed),
+            DerpError::PacketTooLarge(_) => self.oversized_frames.fetch_add(1, Ordering::Relaxed),
+            DerpError::WebSocketError(_) => self.send_failures.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub fn record_send_failure(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnect_attempts(&self) -> u32 {
+        self.reconnect_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Counts a reconnect attempt and returns its 1-based number.
+    pub fn next_reconnect_attempt(&self) -> u32 {
+        self.reconnect_attempts.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn snapshot(&self) -> NetworkStats {
+        NetworkStats {
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            reconnect_attempts: self.reconnect_attempts(),
+            echo_requests: self.echo_requests.load(Ordering::Relaxed),
+            echo_replies: self.echo_replies.load(Ordering::Relaxed),
+            broadcast_sent: self.broadcast_sent.load(Ordering::Relaxed),
+            multicast_sent: self.multicast_sent.load(Ordering::Relaxed),
+            broadcast_received: self.broadcast_received.load(Ordering::Relaxed),
+            multicast_received: self.multicast_received.load(Ordering::Relaxed),
+            congestion_events: self.congestion_events.load(Ordering::Relaxed),
+            backpressure_drops: self.backpressure_drops.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Relaxed),
+            oversized_frames: self.oversized_frames.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets every counter to its value in `stats`.
+    pub fn restore(&self, stats: &NetworkStats) {
+        self.bytes_received.store(stats.bytes_received, Ordering::Relaxed);
+        self.bytes_sent.store(stats.bytes_sent, Ordering::Relaxed);
+        self.packets_received.store(stats.packets_received, Ordering::Relaxed);
+        self.packets_sent.store(stats.packets_sent, Ordering::Relaxed);
+        self.reconnect_attempts.store(stats.reconnect_attempts, Ordering::Relaxed);
+        self.echo_requests.store(stats.echo_requests, Ordering::Relaxed);
+        self.echo_replies.store(stats.echo_replies, Ordering::Relaxed);
+        self.broadcast_sent.store(stats.broadcast_sent, Ordering::Relaxed);
+        self.multicast_sent.store(stats.multicast_sent, Ordering::Relaxed);
+        self.broadcast_received.store(stats.broadcast_received, Ordering::Relaxed);
+        self.multicast_received.store(stats.multicast_received, Ordering::Relaxed);
+        self.congestion_events.store(stats.congestion_events, Ordering::Relaxed);
+        self.backpressure_drops.store(stats.backpressure_drops, Ordering::Relaxed);
+        self.decode_errors.store(stats.decode_errors, Ordering::Relaxed);
+        self.decrypt_failures.store(stats.decrypt_failures, Ordering::Relaxed);
+        self.oversized_frames.store(stats.oversized_frames, Ordering::Relaxed);
+        self.send_failures.store(stats.send_failures, Ordering::Relaxed);
+    }
+}
+
+/// Options accepted by `NetworkState::connect_with_options`.
+#[derive(Default, Clone, Serialize, Deserialize, Tsify)]
+#[tsify(from_wasm_abi)]
+pub struct ConnectOptions {
+    #[serde(default, rename = "authToken")]
+    #[tsify(optional)]
+    pub auth_token: Option<String>,
+    /// WebSocket subprotocols to offer, e.g. `["derp"]`, for servers that
+    /// insist on negotiating one.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub protocols: Vec<String>,
+    /// Added to the URL's query string, encoded.
+    #[serde(default)]
+    #[tsify(optional, type = "Record<string, string>")]
+    pub query: BTreeMap<String, String>,
+    #[serde(default)]
+    #[tsify(optional)]
+    pub mode: SocketMode,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct DrainProgress {
+    pub draining: bool,
+    pub active_peers: usize,
+    pub active_flows: usize,
+    pub complete: bool,
+}
+
+/// Returned by `DerpNetwork.memoryStats`. Byte counts are allocations, so
+/// they include spare capacity, and `approximate_bytes` leaves out fixed
+/// state like keys and config.
+#[derive(Default, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct MemoryStats {
+    /// The module's linear memory, shared by every instance on the page.
+    pub wasm_memory_bytes: usize,
+    /// Packets held for replay while reconnecting.
+    pub reconnect_queue_packets: usize,
+    pub reconnect_queue_bytes: usize,
+    /// Packets queued behind a congested relay socket.
+    pub send_queue_packets: usize,
+    pub send_queue_bytes: usize,
+    /// Frames waiting for the batch flush, and the reused send buffer.
+    pub batch_bytes: usize,
+    pub send_buffer_bytes: usize,
+    /// Free buffers in the frame pool.
+    pub pooled_buffers: usize,
+    pub pooled_bytes: usize,
+    /// Summaries in the attached NICs' trace rings, and how many fit.
+    pub trace_frames: usize,
+    pub trace_capacity: usize,
+    pub tracked_flows: usize,
+    /// This instance's share of the above.
+    pub approximate_bytes: usize,
+}
+
+impl MemoryStats {
+    /// Adds the trace rings of the NICs sharing the connection.
+    pub fn add_traces(&mut self, (frames, capacity): (usize, usize)) {
+        self.trace_frames += frames;
+        self.trace_capacity += capacity;
+        self.approximate_bytes += frames * trace::SUMMARY_BYTES;
+    }
+}
+
+/// What's left of a `shutdown` once the Goodbye is sent: waiting for the
+/// socket to drain.
+pub struct ShutdownDrain {
+    transport: Option<Transport>,
+    gate: SendGate,
+    clock: Arc<dyn Clock>,
+    timeout_ms: u32,
+    result: DerpResult<()>,
+}
+
+impl ShutdownDrain {
+    /// Resolves with the Goodbye's result once the socket's buffer is
+    /// empty or the timeout has passed.
+    pub async fn wait(self) -> DerpResult<()> {
+        if let Some(transport) = &self.transport {
+            let deadline = self.clock.now_ms() + self.timeout_ms as f64;
+            while self.gate.is_congested() || transport.buffered_amount() > 0 {
+                if self.clock.now_ms() >= deadline {
+                    log::warn!("Closing with {} bytes unsent after {} ms", transport.buffered_amount(), self.timeout_ms);
+                    break;
+                }
+                timer::sleep(DRAIN_POLL_MS).await;
+            }
+        }
+        self.result
+    }
+}
+
+pub struct NetworkState {
+    stats: Arc<StatsCounters>,
+    transport: Option<Transport>,
+    /// Impairments applied to whichever transport is open; see `simulate_conditions`.
+    simulation: Rc<RefCell<Simulation>>,
+    crypto_state: Arc<CryptoState>,
+    protocol_state: Shared<ProtocolState>,
+    url: Option<String>,
+    /// Subprotocols, query and mode to reopen the socket with.
+    options: ConnectOptions,
+    flows: FlowTable,
+    draining: bool,
+    /// Set by `shutdown`, after which nothing more is sent.
+    shutting_down: bool,
+    reconnect_timer: Shared<Option<i32>>,
+    /// Decides when to reconnect and when to give up.
+    driver: Shared<Driver>,
+    /// Shared with the tick itself, which stops when the link goes idle.
+    keepalive_timer: Shared<Option<i32>>,
+    config: DerpConfig,
+    /// Peers' virtual addresses, from the coordination server.
+    peer_routes: HashMap<IpAddr, PeerKey>,
+    /// Peers' names in `control::PEER_DOMAIN`, and their addresses.
+    peer_names: HashMap<String, Vec<IpAddr>>,
+    events: EventDispatcher,
+    status: ConnectionStatus,
+    /// Holds back or drops packets while the socket is congested.
+    gate: SendGate,
+    /// Packets sent while reconnecting, replayed after the handshake.
+    outbox: Shared<Outbox>,
+    /// Streams opened with `openChannel`, beside the VMs' traffic.
+    channels: Channels,
+    /// Shared with the protocol, for keepalives and flow expiry.
+    clock: Arc<dyn Clock>,
+    /// The protocol's, with dispatch timed here.
+    stopwatch: Arc<Stopwatch>,
+    /// Reused for every outgoing data frame.
+    send_buffer: Vec<u8>,
+    /// Frames waiting for the end-of-microtask flush when batching is negotiated.
+    batch: Shared<Vec<u8>>,
+    /// Set by `into_shared`, so a reconnect can open a new socket.
+    this: Option<WeakShared<NetworkState>>,
+}
+
+/// `NetworkState` borrowed from outside its own callbacks. Events it emits
+/// meanwhile reach listeners once it's released, so they can call straight
+/// back in, where on wasm a second borrow would panic.
+pub struct Locked<'a> {
+    // Declared first, so it's released before the events are delivered
+    state: Guard<'a, NetworkState>,
+    _events: Deferral,
+}
+
+impl Deref for Locked<'_> {
+    type Target = NetworkState;
+
+    fn deref(&self) -> &NetworkState {
+        &self.state
+    }
+}
+
+impl DerefMut for Locked<'_> {
+    fn deref_mut(&mut self) -> &mut NetworkState {
+        &mut self.state
+    }
+}
+
+impl NetworkState {
+    /// Borrows `network`, holding back its events until released.
+    pub fn lock(network: &Shared<NetworkState>) -> Locked<'_> {
+        let state = network.lock();
+        let events = state.events.defer();
+        Locked { state, _events: events }
+    }
+
+    pub fn new(crypto_state: Arc<CryptoState>) -> Self {
+        NetworkState::with_config(crypto_state, DerpConfig::default())
+    }
+
+    pub fn with_config(crypto_state: Arc<CryptoState>, config: DerpConfig) -> Self {
+        NetworkState::with_clock(crypto_state, config, clock::system())
+    }
+
+    pub fn with_clock(crypto_state: Arc<CryptoState>, config: DerpConfig, clock: Arc<dyn Clock>) -> Self {
+        let protocol_state = ProtocolState::with_clock(config.clone(), clock.clone());
+        let events = EventDispatcher::new();
+        let stats = Arc::new(StatsCounters::default());
+        let mut driver = Driver::new(config.reconnect_policy());
+        driver.set_random(js_sys::Math::random);
+        NetworkState {
+            gate: SendGate::new(&config, events.clone(), stats.clone(), clock.clone()),
+            outbox: Shared::new(Outbox::new(config.reconnect_queue_size)),
+            channels: Channels::default(),
+            stats,
+            transport: None,
+            simulation: Rc::new(RefCell::new(Simulation::default())),
+            crypto_state,
+            stopwatch: protocol_state.stopwatch(),
+            protocol_state: Shared::new(protocol_state),
+            url: None,
+            options: ConnectOptions::default(),
+            flows: FlowTable::new(),
+            draining: false,
+            shutting_down: false,
+            reconnect_timer: Shared::new(None),
+            driver: Shared::new(driver),
+            keepalive_timer: Shared::new(None),
+            config,
+            peer_routes: HashMap::new(),
+            peer_names: HashMap::new(),
+            status: ConnectionStatus::new(events.clone()),
+            events,
+            clock,
+            send_buffer: Vec::new(),
+            batch: Shared::new(Vec::new()),
+            this: None,
+        }
+    }
+
+    /// Shares the state between the callbacks and timers that drive it.
+    /// Reconnects reach it through here to open their new socket.
+    pub fn into_shared(self) -> Shared<NetworkState> {
+        let network = Shared::new(self);
+        network.lock().this = Some(network.downgrade());
+        network
+    }
+
+    /// Takes up a coordination server's document: its relay map for the
+    /// next `connectHome` or re-probe, and its peers' addresses and names
+    /// for routing guest packets and answering their DNS. A document that
+    /// doesn't check out changes nothing.
+    pub fn apply_control(&mut self, document: &ControlDocument) -> DerpResult<ControlEvent> {
+        let routes = document.routes()?;
+        let names = document.names()?;
+        if let Some(map) = &document.derp_map {
+            let mut config = self.config.clone();
+            config.derp_map = Some(map.clone());
+            config.validate()?;
+            self.config = config;
+        }
+        self.peer_routes = routes;
+        self.peer_names = names;
+        Ok(ControlEvent {
+            peers: document.peers.len() as u32,
+            regions: self.config.relay_regions().len() as u32,
+        })
+    }
+
+    /// The peer the coordination server assigned `address` to, if any.
+    pub fn peer_for_ip(&self, address: IpAddr) -> Option<PeerKey> {
+        self.peer_routes.get(&address).copied()
+    }
+
+    /// Peers' DNS names, e.g. "alice.derp", and their addresses.
+    pub fn peer_names(&self) -> &HashMap<String, Vec<IpAddr>> {
+        &self.peer_names
+    }
+
+    /// See `ProtocolState::set_allowed_peers`.
+    pub fn set_allowed_peers(&self, allowed: Option<HashSet<PeerKey>>) {
+        self.protocol_state.lock().set_allowed_peers(allowed);
+    }
+
+    pub fn acl_stats(&self) -> AclStats {
+        self.protocol_state.lock().acl_stats()
+    }
+
+    /// See `ProtocolState::pin_server_key`.
+    pub fn pin_server_key(&self, key: PeerKey) {
+        self.protocol_state.lock().pin_server_key(key);
+    }
+
+    pub fn stats(&self) -> Arc<StatsCounters> {
+        self.stats.clone()
+    }
+
+    pub fn events(&self) -> EventDispatcher {
+        self.events.clone()
+    }
+
+    pub fn channels(&self) -> Channels {
+        self.channels.clone()
+    }
+
+    pub fn config(&self) -> &DerpConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.status.get()
+    }
+
+    /// Resolves once the relay accepts the handshake, or fails if the
+    /// connection is refused, closes, is given up on or is closed first.
+    /// Reconnecting after a close carries on regardless. `connect` awaits
+    /// it; after `connect_loopback` or `connect_mock`, await it yourself.
+    pub fn connected(&self) -> impl Future<Output = DerpResult<()>> + 'static {
+        let changes = self.events.subscribe(EventKind::State, 8).map(|_| false);
+        let closes = self.events.subscribe(EventKind::Disconnect, 1).map(|_| true);
+        let mut updates = futures::stream::select(changes, closes);
+        let status = self.status.clone();
+        let protocol_state = self.protocol_state.clone();
+        async move {
+            let mut closed = false;
+            loop {
+                // Checked each time round, as changes are dropped when the channel is full
+                match status.get() {
+                    ConnectionState::Connected => return Ok(()),
+                    ConnectionState::Failed => return protocol_state.lock().ensure_connected(),
+                    ConnectionState::Cooldown => return Err(DerpError::InvalidState("Reconnects ran out".into())),
+                    ConnectionState::Closed => return Err(DerpError::InvalidState("Closed before connecting".into())),
+                    _ if closed => return Err(DerpError::WebSocketError("Closed before the relay accepted the handshake".into())),
+                    _ => {}
+                }
+                match updates.next().await {
+                    Some(close) => closed |= close,
+                    None => return Err(DerpError::InvalidState("Event channel closed".into())),
+                }
+            }
+        }
+    }
+
+    /// Packets from the relay, as "packet" listeners get them. Up to
+    /// `capacity` wait unread; more are dropped until the stream catches up.
+    pub fn packets(&self, capacity: usize) -> impl Stream<Item = Vec<u8>> + 'static {
+        self.events.subscribe_packets(capacity)
+    }
+
+    /// Opens the socket, and resolves once the relay accepts the handshake.
+    /// With `lazyConnect` it resolves straight away, as the socket waits
+    /// for the first packet. The future holds no borrow.
+    pub fn connect(&mut self, url: &str) -> impl Future<Output = DerpResult<()>> + 'static {
+        self.connect_with_options(url, ConnectOptions::default())
+    }
+
+    pub fn connect_with_options(&mut self, url: &str, options: ConnectOptions) -> impl Future<Output = DerpResult<()>> + 'static {
+        let opened = self.open_with_options(url, options);
+        let connected = match opened {
+            Ok(true) => Some(self.connected()),
+            _ => None,
+        };
+        async move {
+            match connected {
+                Some(connected) => connected.await,
+                None => opened.map(|_| ()),
+            }
+        }
+    }
+
+    /// Returns whether a socket was opened, rather than left for `lazyConnect`.
+    fn open_with_options(&mut self, url: &str, options: ConnectOptions) -> DerpResult<bool> {
+        #[cfg(not(feature = "base64"))]
+        if options.mode == SocketMode::Text {
+            return Err(transport::text_mode_unavailable());
+        }
+        self.url = Some(url.to_string());
+        self.protocol_state.lock().set_auth_token(options.auth_token.clone());
+        self.options = options;
+        if self.config.lazy_connect {
+            log::info!("Connecting to {} once there's a packet to send", url);
+            return Ok(false);
+        }
+        self.open_socket().map(|()| true)
+    }
+
+    fn open_socket(&mut self) -> DerpResult<()> {
+        let url = self.url.as_ref().ok_or_else(|| 
+            DerpError::InvalidState("No URL configured".into())
+        )?;
+
+        self.status.set(ConnectionState::Connecting);
+        let ws = transport::open_websocket(url, &self.options.protocols, &self.options.query)?;
+        
+        // Setup message handler
+        let transport = self.simulated(Transport::WebSocket(ws.clone(), self.options.mode));
+        let this = self.this.clone();
+        let inbox = self.inbox(transport.clone(), Rc::new(move || {
+            let Some(network) = this.as_ref().and_then(WeakShared::upgrade) else { return };
+            let result = NetworkState::lock(&network).reopen_socket();
+            if let Err(e) = result {
+                log::warn!("Failed to reopen the relay socket: {}", e);
+            }
+        }));
+        let receiver = self.receiver(&inbox);
+        let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
+            let data = e.data();
+            if let Some(text) = data.as_string() {
+                match transport::decode_text(&text) {
+                    Ok(message) => receiver(&message),
+                    Err(e) => log::warn!("Dropped a relay message: {}", e),
+                }
+            } else if let Ok(array_buffer) = data.dyn_into::<js_sys::ArrayBuffer>() {
+                receiver(&Uint8Array::new(&array_buffer).to_vec());
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        
+        // Setup error handler
+        let events = self.events.clone();
+        let error_callback = Closure::wrap(Box::new(move |e: ErrorEvent| {
+            log::warn!("WebSocket error: {}", e.message());
+            let error = DerpError::WebSocketError(format!("WebSocket error: {}", e.message()));
+            events.emit(EventKind::Error, &error.into());
+        }) as Box<dyn FnMut(ErrorEvent)>);
+        
+        // Setup close handler with reconnection logic
+        let on_close = inbox.close_handler();
+        let close_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
+            on_close(&DisconnectEvent {
+                code: e.code(),
+                reason: e.reason(),
+                was_clean: e.was_clean(),
+            });
+        }) as Box<dyn FnMut(CloseEvent)>);
+        
+        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        ws.set_onerror(Some(error_callback.as_ref().unchecked_ref()));
+        ws.set_onclose(Some(close_callback.as_ref().unchecked_ref()));
+        
+        onmessage_callback.forget();
+        error_callback.forget();
+        close_callback.forget();
+
+        self.start(transport)
+    }
+
+    /// Replaces the closed socket with a new one, handlers and all, for a
+    /// reconnect.
+    fn reopen_socket(&mut self) -> DerpResult<()> {
+        if let Some(transport) = self.transport.take() {
+            detach(&transport);
+        }
+        self.open_socket()
+    }
+
+    /// Routes a connection's callbacks through one `Inbox`, so its
+    /// messages, its close and the reconnect timer are handled one at a
+    /// time, in order. `reopen` is what a reconnect does.
+    fn inbox(&self, transport: Transport, reopen: Rc<dyn Fn()>) -> Inbox {
+        let inbox = Inbox::new();
+        let receive = self.message_handler(transport);
+        let lifecycle = Lifecycle {
+            protocol_state: self.protocol_state.clone(),
+            inbox: inbox.downgrade(),
+            driver: self.driver.clone(),
+            events: self.events.clone(),
+            status: self.status.clone(),
+            outbox: self.outbox.clone(),
+            stats: self.stats.clone(),
+            clock: self.clock.clone(),
+            reconnect_timer: self.reconnect_timer.clone(),
+            max_attempts: self.config.max_reconnect_attempts,
+            reopen,
+        };
+        inbox.set_handler(move |input| match input {
+            Inbound::Message(data) => receive(data),
+            Inbound::Closed(event) => lifecycle.closed(event),
+            Inbound::ReconnectDue => lifecycle.handle(Input::TimerFired),
+        });
+        inbox
+    }
+
+    /// What a transport calls with each message, which then goes through
+    /// any simulated impairment to `inbox`.
+    fn receiver(&self, inbox: &Inbox) -> Receiver {
+        SimulatedTransport::receiver(self.simulation.clone(), inbox.receiver())
+    }
+
+    /// Connects through an in-memory `Loopback` instead of a relay, for
+    /// tests. As over a WebSocket, the handshake completes asynchronously.
+    pub fn connect_loopback(&mut self, loopback: Loopback) -> DerpResult<()> {
+        self.close();
+        self.status.set(ConnectionState::Connecting);
+        let transport = self.simulated(Transport::Loopback(loopback.clone()));
+        // Nothing closes a loopback but `close`, which detaches it first
+        let inbox = self.inbox(transport.clone(), Rc::new(|| {}));
+        loopback.attach(self.crypto_state.clone(), self.receiver(&inbox));
+        self.start(transport)
+    }
+
+    /// Connects through a scripted `MockWebSocket`, for tests. Its drops
+    /// are handled as a WebSocket's closes are, and each reconnect reopens
+    /// it and starts the handshake again.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn connect_mock(&mut self, mock: MockWebSocket) -> DerpResult<()> {
+        self.close();
+        self.status.set(ConnectionState::Connecting);
+        let transport = self.simulated(Transport::Mock(mock.clone()));
+        let reopen = {
+            let mock = mock.clone();
+            let transport = transport.clone();
+            let protocol_state = self.protocol_state.clone();
+            let gate = self.gate.clone();
+            let status = self.status.clone();
+            Rc::new(move || {
+                mock.reopen();
+                let result = protocol_state.lock().start_handshake()
+                    .and_then(|frame| gate.send(&transport, &frame, Priority::Control));
+                match result {
+                    Ok(()) => status.set(ConnectionState::Handshaking),
+                    Err(e) => log::warn!("Failed to restart the handshake: {}", e),
+                }
+            })
+        };
+        let inbox = self.inbox(transport.clone(), reopen);
+        mock.attach(self.receiver(&inbox), inbox.close_handler());
+        self.start(transport)
+    }
+
+    /// Degrades relay traffic in both directions from now on, across
+    /// reconnects, until called with None.
+    pub fn simulate_conditions(&mut self, conditions: Option<NetworkConditions>) -> DerpResult<()> {
+        self.simulation.borrow_mut().set_conditions(conditions)
+    }
+
+    fn simulated(&self, transport: Transport) -> Transport {
+        Transport::Simulated(SimulatedTransport::new(transport, self.simulation.clone()))
+    }
+
+    /// Starts keepalives and the handshake on a freshly opened transport.
+    fn start(&mut self, transport: Transport) -> DerpResult<()> {
+        self.start_keepalive(&transport);
+        self.transport = Some(transport);
+        
+        // Start handshake using crypto state
+        let handshake_frame = {
+            let mut protocol = self.protocol_state.lock();
+            protocol.start_handshake()?
+        };
+        self.send_raw(&handshake_frame, Priority::Control)?;
+        self.protocol_state.lock().recycle(handshake_frame);
+        self.status.set(ConnectionState::Handshaking);
+        
+        Ok(())
+    }
+
+    /// Handles each message `transport` receives.
+    fn message_handler(&self, transport: Transport) -> Receiver {
+        let stats = self.stats.clone();
+        let protocol_state = self.protocol_state.clone();
+        let crypto_state = self.crypto_state.clone();
+        let events = self.events.clone();
+        let stopwatch = self.stopwatch.clone();
+        let status = self.status.clone();
+        let gate = self.gate.clone();
+        let outbox = self.outbox.clone();
+        let channels = self.channels.clone();
+        let driver = self.driver.clone();
+        let clock = self.clock.clone();
+        Rc::new(move |data: &[u8]| {
+            let started = stopwatch.now_ms();
+            // Listeners run only after the protocol lock is released, so
+            // they are free to call back into the network.
+            let mut pending = Vec::new();
+            let mut later = Vec::new();
+            let result = {
+                let mut protocol = protocol_state.lock();
+                protocol.note_received();
+                let context = Dispatch {
+                    crypto_state: &crypto_state,
+                    stats: &stats,
+                    channels: &channels,
+                    transport: &transport,
+                    events: &events,
+                    driver: &driver,
+                    now_ms: clock.now_ms(),
+                };
+                handle_message(data, &mut protocol, context, &mut pending, &mut later)
+                    .and_then(|()| match protocol.is_connected() {
+                        true => replay(&outbox, &mut protocol, &crypto_state, &transport, &gate, &stats),
+                        false => Ok(()),
+                    })
+            };
+
+            let mut reconnected = None;
+            for action in later {
+                match action {
+                    Action::StateChanged(state) => status.set(state),
+                    Action::Reconnected(event) => reconnected = Some(event),
+                    _ => {}
+                }
+            }
+            for (kind, payload) in pending {
+                events.emit(kind, &payload);
+            }
+            if let Some(event) = reconnected {
+                report_reconnected(&events, &event);
+            }
+            if let Err(e) = result {
+                log::warn!("Dropped relay message: {}", e);
+                stats.record_error(&e);
+                events.emit(EventKind::Error, &e.into());
+            }
+            stopwatch.record(Phase::Dispatch, started);
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.protocol_state.lock().is_connected()
+    }
+
+    /// Sends a VM's Ethernet frame to every peer.
+    pub fn send_packet(&mut self, data: &[u8]) -> DerpResult<()> {
+        self.send_packet_on_vlan(None, Framing::Ethernet, data)
+    }
+
+    /// Registers a guest MAC on this connection and announces it to peers.
+    pub fn add_local_mac(&mut self, mac: [u8; 6]) -> DerpResult<()> {
+        let changed = self.protocol_state.lock().switchboard_mut().add_local(mac);
+        if changed {
+            self.announce_macs()?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_local_mac(&mut self, mac: [u8; 6]) -> DerpResult<()> {
+        let changed = self.protocol_state.lock().switchboard_mut().remove_local(mac);
+        if changed {
+            self.announce_macs()?;
+        }
+        Ok(())
+    }
+
+    /// The peer a remote guest MAC was announced by, if any.
+    pub fn peer_for_mac(&self, mac: &[u8; 6]) -> Option<PeerKey> {
+        self.protocol_state.lock().switchboard().peer_for(mac)
+    }
+
+    /// Sends a whole Ethernet frame to its destination MAC's peer when the
+    /// switchboard knows it, and to every peer otherwise.
+    pub fn send_frame_to_mac(&mut self, vlan: Option<u16>, frame: &[u8]) -> DerpResult<()> {
+        let mut dst = [0u8; 6];
+        dst.copy_from_slice(&frame[..6]);
+        match self.peer_for_mac(&dst) {
+            Some(peer) => self.send_to_peer(&peer, vlan, Framing::Ethernet, frame),
+            None => self.send_packet_on_vlan(vlan, Framing::Ethernet, frame),
+        }
+    }
+
+    /// Sends the current list of local MACs, if connected. Until then the
+    /// list goes out when the handshake completes.
+    fn announce_macs(&self) -> DerpResult<()> {
+        let protocol = self.protocol_state.lock();
+        if !protocol.is_connected() {
+            return Ok(());
+        }
+        let frame = protocol.mac_announcement();
+        let result = self.send_raw(&frame, Priority::Control);
+        protocol.recycle(frame);
+        result
+    }
+
+    /// Marks this connection as the home region's, telling the relay at
+    /// once if connected and otherwise once the handshake completes.
+    pub fn set_preferred(&mut self, preferred: bool) -> DerpResult<()> {
+        let mut protocol = self.protocol_state.lock();
+        if protocol.is_preferred() == preferred {
+            return Ok(());
+        }
+        protocol.set_preferred(preferred);
+        if !protocol.is_connected() {
+            return Ok(());
+        }
+        let frame = protocol.note_preferred();
+        let result = self.send_raw(&frame, Priority::Control);
+        protocol.recycle(frame);
+        result
+    }
+
+    pub fn is_preferred(&self) -> bool {
+        self.protocol_state.lock().is_preferred()
+    }
+
+    pub fn aes_gcm_siv_enabled(&self) -> bool {
+        self.protocol_state.lock().aes_gcm_siv_enabled()
+    }
+
+    /// Hands the relay a packet a mesh node is forwarding from another
+    /// relay, for it to deliver to `header.dst`.
+    pub fn forward_packet(&mut self, header: &ForwardHeader, packet: &[u8]) -> DerpResult<()> {
+        let payload = header.encode(packet);
+        {
+            let protocol = self.protocol_state.lock();
+            protocol.ensure_connected()?;
+            protocol.encode_encrypted_frame_into(&self.crypto_state, FrameType::ForwardPacket, &payload, &mut self.send_buffer)?;
+        }
+        self.send_raw(&self.send_buffer, Priority::classify(packet))
+    }
+
+    /// Asks the relay to disconnect `peer`, which relays allow only on
+    /// mesh or admin connections. The others hear of it as "peer-closed".
+    pub fn close_peer(&self, peer: &PeerKey) -> DerpResult<()> {
+        let protocol = self.protocol_state.lock();
+        protocol.ensure_connected()?;
+        let frame = protocol.close_peer(peer);
+        let result = self.send_raw(&frame, Priority::Control);
+        protocol.recycle(frame);
+        result
+    }
+
+    /// Asks the relay to report every peer, now and after each reconnect,
+    /// and returns those already known. The rest arrive as "peer-present"
+    /// events.
+    pub fn watch_peers(&mut self) -> DerpResult<Vec<String>> {
+        let mut protocol = self.protocol_state.lock();
+        let frame = protocol.watch_conns();
+        let result = if protocol.is_connected() {
+            self.send_raw(&frame, Priority::Control)
+        } else {
+            Ok(())
+        };
+        protocol.recycle(frame);
+        result.map(|()| protocol.peer_keys())
+    }
+
+    /// Sends a packet, behind an 802.1Q tag if `vlan` is set so the far end
+    /// can tell which of its NICs it's for. Flows are tracked untagged.
+    pub fn send_packet_on_vlan(&mut self, vlan: Option<u16>, framing: Framing, data: &[u8]) -> DerpResult<()> {
+        self.send_to(None, vlan, Some(framing), data)
+    }
+
+    /// Like `send_packet_on_vlan`, but the relay passes it to `peer` only.
+    pub fn send_to_peer(&mut self, peer: &PeerKey, vlan: Option<u16>, framing: Framing, data: &[u8]) -> DerpResult<()> {
+        self.send_to(Some(peer), vlan, Some(framing), data)
+    }
+
+    /// Sends `data` to every peer on an open channel.
+    pub fn send_on_channel(&mut self, id: u16, data: &[u8]) -> DerpResult<()> {
+        if !self.channels.is_open(id) {
+            return Err(DerpError::InvalidState(format!("Channel {} isn't open", id)));
+        }
+        self.send_to(None, None, None, &channel::tag(id, data))?;
+        self.channels.record_sent(id, data.len());
+        Ok(())
+    }
+
+    /// Sends an IPv4 packet the relay won't take whole as fragments of at
+    /// most `room` bytes, if configured to; anything else is refused.
+    fn send_oversized(&mut self, peer: Option<&PeerKey>, vlan: Option<u16>, data: &[u8], room: usize) -> DerpResult<()> {
+        let too_large = || DerpError::PacketTooLarge(format!("{} bytes, but the relay takes at most {}", data.len(), room));
+        if !self.config.fragment_oversized || pmtu::dont_fragment(data) {
+            return Err(too_large());
+        }
+        let mtu = u16::try_from(room).unwrap_or(u16::MAX);
+        for fragment in pmtu::fragment_ipv4(data, mtu).ok_or_else(too_large)? {
+            self.send_to(peer, vlan, Some(Framing::Ip), &fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Sends to one peer if `peer` is set, else to every peer. `framing` is
+    /// None for payloads that aren't guest traffic, like channel messages.
+    fn send_to(&mut self, peer: Option<&PeerKey>, vlan: Option<u16>, framing: Option<Framing>, data: &[u8]) -> DerpResult<()> {
+        if self.shutting_down {
+            return Err(DerpError::InvalidState("Shutting down".into()));
+        }
+        if let Some(peer) = peer {
+            self.protocol_state.lock().check_send(peer)?;
+        }
+        if self.config.lazy_connect && self.url.is_some() && self.status.get() == ConnectionState::Idle {
+            // Held until the handshake completes, like during a reconnect
+            self.outbox.lock().hold();
+            self.open_socket()?;
+        }
+
+        let connected = self.protocol_state.lock().ensure_connected();
+        if let Err(e) = connected {
+            return self.hold_back(e, peer, vlan, data);
+        }
+
+        // While draining only the flows, and the VMs, already active get
+        // through. Channels aren't guest traffic, so their messages still do.
+        let flow = framing.and_then(|framing| FlowKey::from_packet(framing, data));
+        if self.draining && framing.is_some_and(|framing| !self.flows.admits(framing, data)) {
+            return Err(DerpError::InvalidState("Draining: not accepting new flows".into()));
+        }
+
+        let tagged = vlan.map(|vlan| demux::tag(vlan, data));
+        let payload = tagged.as_deref().unwrap_or(data);
+        let max_packet_size = self.protocol_state.lock().max_packet_size();
+        if payload.len() > max_packet_size {
+            let room = max_packet_size - (payload.len() - data.len());
+            return self.send_oversized(peer, vlan, data, room);
+        }
+        let priority = Priority::classify(data);
+
+        // Encrypt data before sending, binding the frame header as AAD
+        let batching = {
+            let mut protocol = self.protocol_state.lock();
+            protocol.note_sent();
+            match peer {
+                Some(peer) => protocol.encode_peer_frame_into(&self.crypto_state, peer, payload, &mut self.send_buffer)?,
+                None => protocol.encode_packet_frame_into(&self.crypto_state, payload, &mut self.send_buffer)?,
+            }
+            protocol.batching_enabled()
+        };
+
+        // Interactive packets skip the batch, so they needn't wait for it
+        if batching && priority == Priority::Bulk {
+            self.queue_batched(&self.send_buffer)?;
+        } else {
+            self.send_raw(&self.send_buffer, priority)?;
+        }
+        
+        self.stats.record_sent(payload.len());
+
+        if let (Some(framing), Some(key)) = (framing, flow) {
+            self.flows.track(key, framing, data, self.clock.now_ms());
+        }
+        
+        Ok(())
+    }
+
+    /// Stops accepting new peers and new guest flows. Existing flows keep
+    /// working until they close or go idle.
+    pub fn drain(&mut self) -> DrainProgress {
+        self.draining = true;
+        self.protocol_state.lock().set_accept_new_peers(false);
+        self.drain_progress()
+    }
+
+    /// The state `DerpNetwork.serializeState` keeps; see `NetworkSnapshot`.
+    pub fn save(&self) -> NetworkSnapshot {
+        NetworkSnapshot {
+            stats: self.stats.snapshot(),
+            draining: self.draining,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &NetworkSnapshot) {
+        self.stats.restore(&snapshot.stats);
+        if snapshot.draining {
+            self.drain();
+        }
+    }
+
+    pub fn drain_progress(&mut self) -> DrainProgress {
+        self.flows.expire(self.clock.now_ms());
+
+        let active_peers = self.protocol_state.lock().peer_count();
+        let active_flows = self.flows.len();
+
+        DrainProgress {
+            draining: self.draining,
+            active_peers,
+            active_flows,
+            complete: self.draining && active_flows == 0,
+        }
+    }
+
+    /// What this connection's queues and buffers hold. The NICs' traces
+    /// are theirs to add.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let (reconnect_queue_packets, reconnect_queue_bytes) = {
+            let outbox = self.outbox.lock();
+            (outbox.len(), outbox.bytes())
+        };
+        let (send_queue_packets, send_queue_bytes) = self.gate.queued();
+        let (pooled_buffers, pooled_bytes) = self.protocol_state.lock().pooled();
+        let batch_bytes = self.batch.lock().capacity();
+        let send_buffer_bytes = self.send_buffer.capacity();
+        MemoryStats {
+            wasm_memory_bytes: wasm_memory_bytes(),
+            reconnect_queue_packets,
+            reconnect_queue_bytes,
+            send_queue_packets,
+            send_queue_bytes,
+            batch_bytes,
+            send_buffer_bytes,
+            pooled_buffers,
+            pooled_bytes,
+            trace_frames: 0,
+            trace_capacity: 0,
+            tracked_flows: self.flows.len(),
+            approximate_bytes: reconnect_queue_bytes + send_queue_bytes + batch_bytes + send_buffer_bytes + pooled_bytes
+                + self.flows.len() * std::mem::size_of::<FlowKey>(),
+        }
+    }
+
+    fn send_raw(&self, data: &[u8], priority: Priority) -> DerpResult<()> {
+        let transport = self.transport.as_ref()
+            .ok_or_else(|| DerpError::InvalidState("WebSocket not initialized".into()))?;
+        self.gate.send(transport, data, priority).inspect_err(|_| {
+            self.stats.record_send_failure();
+        })
+    }
+
+    /// Queues a packet `send_to` can't send yet if a reconnect is under
+    /// way, and otherwise fails with `error`.
+    fn hold_back(&self, error: DerpError, peer: Option<&PeerKey>, vlan: Option<u16>, data: &[u8]) -> DerpResult<()> {
+        let mut outbox = self.outbox.lock();
+        if !outbox.is_holding() {
+            return Err(error);
+        }
+        let tagged = vlan.map(|vlan| demux::tag(vlan, data));
+        outbox.push(peer.copied(), tagged.as_deref().unwrap_or(data))
+    }
+
+    /// Appends `frame` to the pending batch. The first frame of a batch
+    /// schedules a flush once the current task's microtasks have run, so
+    /// packets sent back to back share one WebSocket message.
+    fn queue_batched(&self, frame: &[u8]) -> DerpResult<()> {
+        let transport = self.transport.as_ref()
+            .ok_or_else(|| DerpError::InvalidState("WebSocket not initialized".into()))?;
+
+        let mut batch = self.batch.lock();
+        if !batch.is_empty() && batch.len() + frame.len() > self.gate.batch_limit(MAX_BATCH_SIZE) {
+            if let Err(e) = self.gate.send(transport, &batch, Priority::Bulk) {
+                self.stats.record_send_failure();
+                return Err(e);
+            }
+            batch.clear();
+        }
+
+        let schedule_flush = batch.is_empty();
+        batch.extend_from_slice(frame);
+        drop(batch);
+
+        if schedule_flush {
+            let batch = self.batch.clone();
+            let events = self.events.clone();
+            let transport = transport.clone();
+            let gate = self.gate.clone();
+            let stats = self.stats.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let result = {
+                    let mut batch = batch.lock();
+                    let result = if batch.is_empty() { Ok(()) } else { gate.send(&transport, &batch, Priority::Bulk) };
+                    batch.clear();
+                    result
+                };
+                if let Err(e) = result {
+                    stats.record_send_failure();
+                    events.emit(EventKind::Error, &e.into());
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_stats(&self) -> NetworkStats {
+        self.stats.snapshot()
+    }
+
+    /// The relay link's upload bandwidth in kilobits per second, estimated
+    /// from how fast the socket drains while busy.
+    pub fn estimated_bandwidth_kbps(&self) -> Option<u32> {
+        self.gate.estimated_kbps()
+    }
+
+    pub fn timings(&self) -> Timings {
+        self.stopwatch.timings()
+    }
+
+    /// Every counter, gauge and histogram in Prometheus text format, each
+    /// sample carrying `labels`.
+    pub fn metrics_text(&mut self, labels: &[(&str, String)]) -> String {
+        let progress = self.drain_progress();
+        let gauges = Gauges {
+            connected: self.is_connected(),
+            preferred: self.is_preferred(),
+            draining: progress.draining,
+            congested: self.gate.is_congested(),
+            active_peers: progress.active_peers,
+            active_flows: progress.active_flows,
+        };
+        metrics::render(labels, &self.get_stats(), &gauges, &self.timings())
+    }
+
+    /// Polls the protocol once a second and sends a KeepAlive whenever the
+    /// link has been idle for the negotiated interval. With `lazyConnect`
+    /// and an `idleTimeoutMs`, a link that stays idle that long is closed
+    /// instead, to be reopened by the next send.
+    fn start_keepalive(&mut self, transport: &Transport) {
+        self.stop_keepalive();
+
+        let protocol_state = self.protocol_state.clone();
+        let transport = transport.clone();
+        let stats = self.stats.clone();
+        let events = self.events.clone();
+        let status = self.status.clone();
+        let gate = self.gate.clone();
+        let clock = self.clock.clone();
+        let keepalive_timer = self.keepalive_timer.clone();
+        let packets = |stats: &StatsCounters| {
+            let stats = stats.snapshot();
+            stats.packets_sent + stats.packets_received
+        };
+        let mut idle = Some(self.config.idle_timeout_ms)
+            .filter(|&timeout_ms| self.config.lazy_connect && timeout_ms > 0)
+            .map(|timeout_ms| IdleWatch::new(timeout_ms, packets(&stats), clock.now_ms()));
+        let keepalive_callback = Closure::wrap(Box::new(move || {
+            if let Some(idle) = &mut idle {
+                if idle.is_idle(packets(&stats), clock.now_ms()) {
+                    log::info!("Closing the relay connection after it went idle");
+                    if let Some(handle) = keepalive_timer.lock().take() {
+                        timer::clear_interval(handle);
+                    }
+                    gate.clear();
+                    detach(&transport);
+                    status.set(ConnectionState::Idle);
+                    return;
+                }
+            }
+
+            let mut protocol = protocol_state.lock();
+            if protocol.is_silent() {
+                drop(protocol);
+                log::warn!("The relay missed its keepalives; reconnecting");
+                if let Some(handle) = keepalive_timer.lock().take() {
+                    timer::clear_interval(handle);
+                }
+                gate.clear();
+                abandon(&transport, KEEPALIVE_TIMEOUT_CODE, "keepalive timeout");
+                return;
+            }
+            if let Some(frame) = protocol.poll_keepalive() {
+                let result = transport.send(&frame);
+                protocol.recycle(frame);
+                if let Err(e) = result {
+                    log::warn!("Keepalive failed: {}", e);
+                    stats.record_send_failure();
+                    events.emit(EventKind::Error, &e.into());
+                }
+            }
+        }) as Box<dyn FnMut()>);
+
+        *self.keepalive_timer.lock() = Some(timer::set_interval(
+            keepalive_callback.as_ref().unchecked_ref(),
+            KEEPALIVE_TICK_MS,
+        ));
+        keepalive_callback.forget();
+    }
+
+    fn stop_keepalive(&mut self) {
+        if let Some(handle) = self.keepalive_timer.lock().take() {
+            timer::clear_interval(handle);
+        }
+    }
+
+    /// Closes without losing what's already been sent: new packets are
+    /// refused, the pending batch goes out behind any congestion queue, the
+    /// relay gets a Goodbye, and the socket closes once its buffer has
+    /// drained or `shutdownTimeoutMs` has passed.
+    pub async fn shutdown(&mut self) -> DerpResult<()> {
+        let result = self.begin_shutdown().wait().await;
+        self.close();
+        result
+    }
+
+    /// The part of `shutdown` that needs the network: refusing packets and
+    /// sending the batch and Goodbye. The returned drain doesn't borrow it,
+    /// so a shared network can be released while waiting and `close`d after.
+    pub fn begin_shutdown(&mut self) -> ShutdownDrain {
+        self.shutting_down = true;
+        let (transport, result) = match &self.transport {
+            Some(transport) if self.is_connected() => {
+                let result = self.say_goodbye(transport);
+                (result.is_ok().then(|| transport.clone()), result)
+            }
+            _ => (None, Ok(())),
+        };
+        ShutdownDrain {
+            transport,
+            gate: self.gate.clone(),
+            clock: self.clock.clone(),
+            timeout_ms: self.config.shutdown_timeout_ms,
+            result,
+        }
+    }
+
+    fn say_goodbye(&self, transport: &Transport) -> DerpResult<()> {
+        let batch = std::mem::take(&mut *self.batch.lock());
+        if !batch.is_empty() {
+            self.gate.send(transport, &batch, Priority::Bulk)?;
+        }
+        let protocol = self.protocol_state.lock();
+        let goodbye = protocol.goodbye();
+        self.gate.send(transport, &goodbye, Priority::Control)?;
+        protocol.recycle(goodbye);
+        Ok(())
+    }
+
+    /// Tries the relay again at once, with a fresh reconnect budget, after
+    /// the circuit breaker opened or the relay refused the connection.
+    pub fn retry_now(&mut self) -> DerpResult<()> {
+        let state = self.status.get();
+        if !matches!(state, ConnectionState::Cooldown | ConnectionState::Failed) {
+            return Err(DerpError::InvalidState(format!("Nothing to retry while {:?}", state)));
+        }
+        if let Some(handle) = self.reconnect_timer.lock().take() {
+            timer::clear_timeout(handle);
+        }
+        if let Some(transport) = self.transport.take() {
+            detach(&transport);
+        }
+        self.driver.lock().reset();
+        log::info!("Retrying the relay connection");
+        self.open_socket()
+    }
+
+    /// How often reconnects have run out, so a scheduled retry can tell
+    /// whether it's still the one wanted.
+    pub fn breaker_trips(&self) -> u32 {
+        self.driver.lock().trips()
+    }
+
+    /// Cancels any pending reconnect and closes the socket without touching
+    /// other instances on the page.
+    pub fn close(&mut self) {
+        self.stop_keepalive();
+
+        if let Some(handle) = self.reconnect_timer.lock().take() {
+            timer::clear_timeout(handle);
+        }
+
+        self.batch.lock().clear();
+        self.gate.clear();
+        self.outbox.lock().clear();
+        // Forgotten so a lazy connection isn't reopened by the next send
+        let lazy = self.url.take().is_some() && self.config.lazy_connect;
+        if lazy || self.status.get() != ConnectionState::Idle {
+            self.status.set(ConnectionState::Closed);
+        }
+
+        if let Some(transport) = self.transport.take() {
+            detach(&transport);
+        }
+    }
+}
+
+/// Carries out what the connection driver decides after a close.
+struct Lifecycle {
+    protocol_state: Shared<ProtocolState>,
+    /// Where the reconnect timer reports.
+    inbox: WeakInbox,
+    driver: Shared<Driver>,
+    events: EventDispatcher,
+    status: ConnectionStatus,
+    outbox: Shared<Outbox>,
+    stats: Arc<StatsCounters>,
+    clock: Arc<dyn Clock>,
+    reconnect_timer: Shared<Option<i32>>,
+    max_attempts: Option<u32>,
+    reopen: Rc<dyn Fn()>,
+}
+
+impl Lifecycle {
+    /// Reports the close, then schedules a reconnect under the policy or
+    /// gives up.
+    fn closed(&self, event: &DisconnectEvent) {
+        let (rejected, restart_delay_ms) = {
+            let mut protocol = self.protocol_state.lock();
+            (protocol.handshake_state() == HandshakeState::Rejected, protocol.restart_delay_ms())
+        };
+        if rejected {
+            log::warn!("Not reconnecting: the relay rejected our auth token, or presented a key other than the one we were given");
+        }
+        self.handle(Input::Closed {
+            code: event.code,
+            reason: event.reason.clone(),
+            was_clean: event.was_clean,
+            rejected,
+            restart_delay_ms,
+        });
+    }
+
+    fn handle(&self, input: Input) {
+        // Released before acting, since listeners may call back in
+        let actions = self.driver.lock().handle_input(input, self.clock.now_ms());
+        for action in actions {
+            self.run(action);
+        }
+    }
+
+    fn run(&self, action: Action) {
+        match action {
+            Action::StateChanged(state) => self.status.set(state),
+            Action::Disconnected { code, reason, was_clean } => {
+                log::info!("Relay connection closed: code {} {:?}", code, reason);
+                self.events.emit_serialized(EventKind::Disconnect, &DisconnectEvent { code, reason, was_clean });
+            }
+            Action::HoldPackets => self.outbox.lock().hold(),
+            Action::DropPackets => self.outbox.lock().clear(),
+            Action::Reconnecting { attempt, delay_ms, code, reason } => {
+                self.stats.next_reconnect_attempt();
+                match self.max_attempts {
+                    Some(max) => log::info!("Reconnecting in {} ms, attempt {} of {}", delay_ms, attempt, max),
+                    None => log::info!("Reconnecting in {} ms, attempt {}", delay_ms, attempt),
+                }
+                self.events.emit_serialized(EventKind::Reconnecting, &ReconnectingEvent { attempt, delay_ms, code, reason });
+            }
+            Action::StartTimer { delay_ms } => {
+                let inbox = self.inbox.clone();
+                let callback = Closure::wrap(Box::new(move || {
+                    inbox.push(Inbound::ReconnectDue);
+                }) as Box<dyn FnMut()>);
+                // Keep the handle so this instance can cancel its own timer
+                let handle = timer::set_timeout(callback.as_ref().unchecked_ref(), delay_ms as i32);
+                *self.reconnect_timer.lock() = Some(handle);
+                callback.forget();
+            }
+            Action::Reopen => (self.reopen)(),
+            Action::Failed(failed) => {
+                match failed.retry_in_ms {
+                    Some(ms) => log::error!("Giving up after {} reconnect attempts; trying again in {} ms", failed.attempts, ms),
+                    None => log::error!("Giving up after {} reconnect attempts until retried", failed.attempts),
+                }
+                self.events.emit_serialized(EventKind::ConnectionFailed, &failed);
+            }
+            Action::Reconnected(event) => report_reconnected(&self.events, &event),
+            // Only relay frames ask for these, and those go through `handle_frame`
+            Action::SendBytes(_) | Action::Deliver { .. } | Action::Close => {}
+        }
+    }
+}
+
+fn report_reconnected(events: &EventDispatcher, event: &ReconnectedEvent) {
+    log::info!("Reconnected after {} attempts and {:.0} ms", event.attempts, event.downtime_ms);
+    events.emit_serialized(EventKind::Reconnected, event);
+}
+
+/// Closes `transport` without its close handler scheduling a reconnect.
+fn detach(transport: &Transport) {
+    if let Some(ws) = transport.websocket() {
+        ws.set_onclose(None);
+        ws.set_onmessage(None);
+        ws.set_onerror(None);
+    }
+    transport.close();
+}
+
+/// Closes a connection that has gone quiet and runs its close handler
+/// straight away, with a synthetic event, so reconnecting starts now
+/// rather than whenever the browser notices the dead socket.
+fn abandon(transport: &Transport, code: u16, reason: &str) {
+    let onclose = transport.websocket().and_then(|ws| ws.onclose());
+    detach(transport);
+    let onclose = match onclose {
+        Some(onclose) => onclose,
+        None => return,
+    };
+
+    let init = CloseEventInit::new();
+    init.set_code(code);
+    init.set_reason(reason);
+    init.set_was_clean(false);
+    match CloseEvent::new_with_event_init_dict("close", &init) {
+        Ok(event) => {
+            if let Err(e) = onclose.call1(&JsValue::NULL, &event) {
+                log::warn!("Close handler threw: {:?}", e);
+            }
+        }
+        Err(e) => log::warn!("Failed to create a close event: {:?}", e),
+    }
+}
+
+/// Sends what the outbox held while reconnecting, encrypted under the
+/// session that's just been established.
+fn replay(
+    outbox: &Shared<Outbox>,
+    protocol: &mut ProtocolState,
+    crypto_state: &CryptoState,
+    transport: &Transport,
+    gate: &SendGate,
+    stats: &StatsCounters,
+) -> DerpResult<()> {
+    let packets = outbox.lock().release();
+    if packets.is_empty() {
+        return Ok(());
+    }
+    log::info!("Replaying {} packets queued while reconnecting", packets.len());
+    protocol.note_sent();
+
+    let mut frame = protocol.take_buffer();
+    let result = packets.iter().try_for_each(|packet| {
+        match &packet.peer {
+            Some(peer) => protocol.encode_peer_frame_into(crypto_state, peer, &packet.payload, &mut frame)?,
+            None => protocol.encode_packet_frame_into(crypto_state, &packet.payload, &mut frame)?,
+        }
+        gate.send(transport, &frame, Priority::classify(&packet.payload))?;
+        stats.record_sent(packet.payload.len());
+        Ok(())
+    });
+    protocol.recycle(frame);
+    result
+}
+
+/// Handles one inbound WebSocket message, which holds one frame or, with
+/// batching, several. Events to emit are queued in `pending` so the caller
+/// can dispatch them after releasing the protocol lock.
+fn handle_message(
+    data: &[u8],
+    protocol: &mut ProtocolState,
+    context: Dispatch,
+    pending: &mut Vec<(EventKind, JsValue)>,
+    later: &mut Vec<Action>,
+) -> DerpResult<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (frame, tail) = rest.split_at(ProtocolState::frame_len(rest)?);
+        handle_frame(frame, protocol, context, pending, later)?;
+        rest = tail;
+    }
+    Ok(())
+}
+
+/// What handling a relay message needs besides the protocol state.
+#[derive(Clone, Copy)]
+struct Dispatch<'a> {
+    crypto_state: &'a CryptoState,
+    stats: &'a StatsCounters,
+    channels: &'a Channels,
+    transport: &'a Transport,
+    /// Takes packets for `NetworkState::packets` straight away.
+    events: &'a EventDispatcher,
+    /// Handles the handshake, pings and peers' packets.
+    driver: &'a Shared<Driver>,
+    now_ms: f64,
+}
+
+fn handle_frame(
+    data: &[u8],
+    protocol: &mut ProtocolState,
+    context: Dispatch,
+    pending: &mut Vec<(EventKind, JsValue)>,
+    later: &mut Vec<Action>,
+) -> DerpResult<()> {
+    let Dispatch { crypto_state, stats, channels, transport, events, driver, now_ms } = context;
+    let frame = Frame::parse(data)?;
+    let payload = frame.payload;
+
+    match frame.frame_type {
+        FrameType::ServerKey | FrameType::ServerInfo | FrameType::AuthResult | FrameType::Ping | FrameType::RecvFromPeer => {
+            let mut actions = Vec::new();
+            let result = driver.lock().handle_bytes(data, protocol, crypto_state, now_ms, &mut actions);
+            for action in actions {
+                match action {
+                    Action::SendBytes(frame) => {
+                        transport.send(&frame)?;
+                        protocol.recycle(frame);
+                    }
+                    Action::Deliver { packet, .. } => {
+                        deliver(&packet, stats, channels, events, pending);
+                        protocol.recycle(packet);
+                    }
+                    Action::Close => transport.close(),
+                    Action::StateChanged(ConnectionState::Connected) => {
+                        greet_relay(protocol, transport)?;
+                        pending.push((EventKind::Connect, JsValue::UNDEFINED));
+                        later.push(action);
+                    }
+                    // Reported once the protocol lock is released
+                    action => later.push(action),
+                }
+            }
+            result?;
+        }
+        FrameType::PeerPresent if protocol.handle_peer_present(payload)? => {
+            // The newcomer missed earlier announcements
+            announce_macs(protocol, transport)?;
+            push_peer_event(pending, EventKind::PeerPresent, payload);
+        }
+        FrameType::PeerPresent => {}
+        FrameType::PeerGone => {
+            protocol.handle_peer_gone(payload)?;
+            push_peer_event(pending, EventKind::PeerGone, payload);
+        }
+        FrameType::MacAnnounce => {
+            protocol.handle_mac_announce(payload)?;
+        }
+        FrameType::ForwardPacket => {
+            let mut decrypted = protocol.take_buffer();
+            if let Err(e) = protocol.decrypt_frame_into(crypto_state, &frame, &mut decrypted) {
+                protocol.recycle(decrypted);
+                return Err(e);
+            }
+            pending.push((EventKind::Forward, Uint8Array::from(&decrypted[..]).into()));
+            protocol.recycle(decrypted);
+        }
+        FrameType::ClosePeer => {
+            let peer = protocol.handle_close_peer(payload)?;
+            log::info!("Relay closed peer {}", hex_encode(&peer));
+            push_peer_event(pending, EventKind::PeerClosed, &peer);
+        }
+        FrameType::Health => {
+            push_event(pending, EventKind::Health, &protocol.handle_health(payload));
+        }
+        FrameType::Restarting => {
+            let event = protocol.handle_restarting(payload)?;
+            push_event(pending, EventKind::Restarting, &event);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Hands a peer's packet to the channel it's for or, if none, to the VMs.
+fn deliver(
+    packet: &[u8],
+    stats: &StatsCounters,
+    channels: &Channels,
+    events: &EventDispatcher,
+    pending: &mut Vec<(EventKind, JsValue)>,
+) {
+    stats.record_received(packet.len());
+    match channel::split(packet) {
+        Some((id, message)) => {
+            if !channels.deliver(id, message) {
+                log::debug!("Dropped a message for channel {}, which isn't open", id);
+            }
+        }
+        None => {
+            events.deliver_packet(packet);
+            pending.push((EventKind::Packet, Uint8Array::from(packet).into()));
+        }
+    }
+}
+
+/// Tells the other peers which MACs live behind this connection. Nothing is
+/// sent while there are none, since peers start out knowing none.
+fn announce_macs(protocol: &ProtocolState, transport: &Transport) -> DerpResult<()> {
+    if protocol.switchboard().announcement().is_empty() {
+        return Ok(());
+    }
+    let frame = protocol.mac_announcement();
+    transport.send(&frame)?;
+    protocol.recycle(frame);
+    Ok(())
+}
+
+/// Tells a relay that's just accepted us what it needs to know: our MACs,
+/// whether it's our home, and whether we're watching its roster. Relays
+/// assume a connection isn't home or watching, so those go only if so.
+fn greet_relay(protocol: &mut ProtocolState, transport: &Transport) -> DerpResult<()> {
+    announce_macs(protocol, transport)?;
+    let mut frames = Vec::new();
+    if protocol.is_preferred() {
+        frames.push(protocol.note_preferred());
+    }
+    if protocol.is_watching() {
+        frames.push(protocol.watch_conns());
+    }
+    for frame in frames {
+        transport.send(&frame)?;
+        protocol.recycle(frame);
+    }
+    Ok(())
+}
+
+fn push_peer_event(pending: &mut Vec<(EventKind, JsValue)>, kind: EventKind, peer_key: &[u8]) {
+    push_event(pending, kind, &PeerEvent { peer_key: hex_encode(peer_key) });
+}
+
+fn push_event<T: Serialize>(pending: &mut Vec<(EventKind, JsValue)>, kind: EventKind, event: &T) {
+    if let Ok(payload) = serde_wasm_bindgen::to_value(event) {
+        pending.push((kind, payload));
+    }
+}
+
+/// Read through `byteLength`, which both `ArrayBuffer` and the
+/// `SharedArrayBuffer` of a threaded build have.
+fn wasm_memory_bytes() -> usize {
+    let memory: js_sys::WebAssembly::Memory = wasm_bindgen::memory().unchecked_into();
+    js_sys::Reflect::get(&memory.buffer(), &"byteLength".into())
+        .ok()
+        .and_then(|length| length.as_f64())
+        .unwrap_or(0.0) as usize
+}
+
+impl Drop for NetworkState {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcheck::Region;
+    use crate::protocol::PEER_KEY_LEN;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    async fn test_reconnection() {
+        let crypto_state = Arc::new(CryptoState::new().unwrap());
+        let mut network = NetworkState::new(crypto_state);
+
+        // Simulate connection failure
+        drop(network.connect("ws://invalid-url"));
+        
+        // Wait for reconnection attempt
+        let window = web_sys::window().unwrap();
+        let closure = Closure::wrap(Box::new(|| {}) as Box<dyn FnMut()>);
+        let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+            closure.as_ref().unchecked_ref(),
+            network.config().reconnect_delay_ms as i32 * 2,
+        );
+        closure.forget();
+        
+        assert!(network.get_stats().reconnect_attempts > 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_stats_counters() {
+        let stats = StatsCounters::default();
+        stats.record_sent(100);
+        stats.record_sent(50);
+        stats.record_received(20);
+        assert_eq!(stats.next_reconnect_attempt(), 1);
+        stats.record_echo_request();
+        stats.record_echo_reply();
+        stats.record_cast_sent(Cast::Multicast);
+        stats.record_cast_received(Cast::Broadcast);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_sent, 150);
+        assert_eq!(snapshot.packets_sent, 2);
+        assert_eq!(snapshot.packets_received, 1);
+        assert_eq!(snapshot.reconnect_attempts, 1);
+        assert_eq!((snapshot.echo_requests, snapshot.echo_replies), (1, 1));
+        assert_eq!((snapshot.multicast_sent, snapshot.broadcast_received), (1, 1));
+        assert_eq!((snapshot.broadcast_sent, snapshot.multicast_received), (0, 0));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_drain_without_flows() {
+        let crypto_state = Arc::new(CryptoState::new().unwrap());
+        let mut network = NetworkState::new(crypto_state);
+
+        assert!(!network.drain_progress().draining);
+
+        let progress = network.drain();
+        assert!(progress.draining);
+        assert_eq!(progress.active_flows, 0);
+        assert!(progress.complete);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_state_follows_the_connection() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut changes = network.events().subscribe(EventKind::State, 8);
+        assert_eq!(network.state(), ConnectionState::Idle);
+
+        network.connect_loopback(Loopback::new()).unwrap();
+        assert_eq!(network.state(), ConnectionState::Handshaking);
+        let settled = js_sys::Promise::new(&mut |resolve, _| {
+            timer::set_timeout(&resolve, 0);
+        });
+        wasm_bindgen_futures::JsFuture::from(settled).await.unwrap();
+        assert_eq!(network.state(), ConnectionState::Connected);
+
+        network.close();
+        assert_eq!(network.state(), ConnectionState::Closed);
+        let states: Vec<String> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| js_sys::Reflect::get(&change, &"state".into()).unwrap().as_string().unwrap())
+            .collect();
+        assert_eq!(states, ["connecting", "handshaking", "connected", "closed"]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_packets_sent_while_reconnecting_are_replayed() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut packets = network.events().subscribe(EventKind::Packet, 4);
+        network.connect_loopback(Loopback::new()).unwrap();
+        assert!(network.send_packet(b"too early").is_err());
+
+        // As the close handler does when it schedules a reconnect
+        network.outbox.lock().hold();
+        network.send_packet(b"held").unwrap();
+        let settled = js_sys::Promise::new(&mut |resolve, _| {
+            timer::set_timeout(&resolve, 0);
+        });
+        wasm_bindgen_futures::JsFuture::from(settled).await.unwrap();
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(Uint8Array::new(&packet).to_vec(), b"held");
+        assert!(!network.outbox.lock().is_holding());
+        assert_eq!(network.get_stats().packets_sent, 1);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_connected_and_packets() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut packets = network.packets(4);
+        network.connect_loopback(Loopback::new()).unwrap();
+        network.connected().await.unwrap();
+
+        network.send_packet(b"echo").unwrap();
+        assert_eq!(packets.next().await.unwrap(), b"echo");
+
+        network.close();
+        assert!(network.connected().await.is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_connected_fails_on_a_close_before_the_handshake() {
+        let config = DerpConfig::builder().reconnect_delay_ms(10).build().unwrap();
+        let mut network = NetworkState::with_config(Arc::new(CryptoState::new().unwrap()), config);
+        let mock = MockWebSocket::new();
+        network.connect_mock(mock.clone()).unwrap();
+        let connected = network.connected();
+
+        mock.drop_connection(1006, "refused");
+        assert!(matches!(connected.await, Err(DerpError::WebSocketError(_))));
+        assert_eq!(network.state(), ConnectionState::Reconnecting);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_reconnects_through_a_mock() {
+        let config = DerpConfig::builder().reconnect_delay_ms(10).build().unwrap();
+        let mut network = NetworkState::with_config(Arc::new(CryptoState::new().unwrap()), config);
+        let mut reconnected = network.events().subscribe(EventKind::Reconnected, 1);
+        let mock = MockWebSocket::new();
+        mock.delay_open(true);
+
+        network.connect_mock(mock.clone()).unwrap();
+        assert_eq!(network.state(), ConnectionState::Handshaking);
+        assert!(mock.take_sent().is_empty());
+        mock.open();
+        assert_eq!(mock.take_sent().len(), 1);
+        mock.complete_handshake().unwrap();
+        assert_eq!(network.state(), ConnectionState::Connected);
+
+        mock.delay_open(false);
+        mock.drop_connection(1006, "gone");
+        assert_eq!(network.state(), ConnectionState::Reconnecting);
+        let waited = js_sys::Promise::new(&mut |resolve, _| {
+            timer::set_timeout(&resolve, 50);
+        });
+        wasm_bindgen_futures::JsFuture::from(waited).await.unwrap();
+        assert_eq!((mock.connections(), network.state()), (2, ConnectionState::Handshaking));
+
+        mock.complete_handshake().unwrap();
+        assert_eq!(network.state(), ConnectionState::Connected);
+        assert!(reconnected.next().await.is_some());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_shutdown_says_goodbye() {
+        let (first_end, second_end) = Loopback::pair();
+        let mut first = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut second = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut gone = second.events().subscribe(EventKind::PeerGone, 4);
+        first.connect_loopback(first_end).unwrap();
+        second.connect_loopback(second_end).unwrap();
+        timer::sleep(0).await;
+
+        first.send_packet(b"last words").unwrap();
+        first.shutdown().await.unwrap();
+        assert_eq!(first.state(), ConnectionState::Closed);
+        assert!(first.send_packet(b"too late").is_err());
+
+        // Announced by the Goodbye, ahead of the socket closing
+        assert!(gone.next().await.is_some());
+        assert_eq!(second.get_stats().packets_received, 1);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_channels_bypass_the_vms() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut packets = network.events().subscribe(EventKind::Packet, 4);
+        network.connect_loopback(Loopback::new()).unwrap();
+        timer::sleep(0).await;
+
+        assert!(network.send_on_channel(5, b"control").is_err());
+        network.channels().open(5).unwrap();
+        network.send_on_channel(5, b"control").unwrap();
+        network.send_packet(b"ethernet").unwrap();
+        timer::sleep(0).await;
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(Uint8Array::new(&packet).to_vec(), b"ethernet");
+        let stats = network.channels().stats(5).unwrap();
+        assert_eq!((stats.packets_sent, stats.packets_received, stats.bytes_received), (1, 1, 7));
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_preferred_is_reported() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        network.set_preferred(true).unwrap();
+        network.connect_loopback(Loopback::new()).unwrap();
+        timer::sleep(0).await;
+
+        assert!(network.is_connected() && network.is_preferred());
+        assert!(network.metrics_text(&[]).contains("derp_preferred 1"));
+        network.set_preferred(false).unwrap();
+        assert!(network.metrics_text(&[]).contains("derp_preferred 0"));
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_watch_peers_reports_the_roster() {
+        let (first_end, second_end) = Loopback::pair();
+        let mut first = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut second = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut present = first.events().subscribe(EventKind::PeerPresent, 4);
+        first.connect_loopback(first_end).unwrap();
+        second.connect_loopback(second_end).unwrap();
+        timer::sleep(0).await;
+
+        assert_eq!(first.watch_peers().unwrap().len(), 1);
+        timer::sleep(0).await;
+        // Once on joining, and again in answer to WatchConns
+        assert!(present.next().await.is_some());
+        assert!(present.next().await.is_some());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_apply_control() {
+        let region = Region { id: 1, name: String::new(), url: "wss://relay.example/derp".into(), probe_url: None };
+        let config = DerpConfig::builder().regions(vec![region]).build().unwrap();
+        let mut network = NetworkState::with_config(Arc::new(CryptoState::new().unwrap()), config);
+        let peer = "02".repeat(PEER_KEY_LEN);
+
+        let document = ControlDocument::from_json(&format!(r#"{{
+            "derpMap": {{"Regions": {{"2": {{"RegionID": 2, "Nodes": [{{"Name": "2a", "RegionID": 2, "HostName": "derp2.example.com"}}]}}}}}},
+            "peers": [{{"publicKey": "{}", "addresses": ["100.64.0.2"], "name": "bob"}}]
+        }}"#, peer)).unwrap();
+        let event = network.apply_control(&document).unwrap();
+        assert_eq!((event.peers, event.regions), (1, 2));
+        assert_eq!(network.peer_for_ip("100.64.0.2".parse().unwrap()), Some([2u8; PEER_KEY_LEN]));
+        assert_eq!(network.peer_for_ip("100.64.0.3".parse().unwrap()), None);
+        assert!(network.peer_names().contains_key("bob.derp"));
+
+        // A map clashing with the configured regions changes nothing
+        let clash = ControlDocument::from_json(
+            r#"{"derpMap": {"Regions": {"1": {"RegionID": 1, "Nodes": [{"Name": "1a", "RegionID": 1, "HostName": "derp1.example.com"}]}}}}"#,
+        ).unwrap();
+        assert!(network.apply_control(&clash).is_err());
+        assert_eq!(network.config().relay_regions().len(), 2);
+        assert!(network.peer_for_ip("100.64.0.2".parse().unwrap()).is_some());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_refuses_sends_to_disallowed_peers() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        network.set_allowed_peers(Some(HashSet::from([[1u8; PEER_KEY_LEN]])));
+
+        let refused = network.send_to_peer(&[2u8; PEER_KEY_LEN], None, Framing::Ip, b"packet");
+        assert!(matches!(refused, Err(DerpError::InvalidState(message)) if message.contains("allowlist")));
+        assert_eq!(network.acl_stats().peers[0].outbound, 1);
+        assert_eq!(network.get_stats().packets_sent, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_save_and_restore() {
+        let network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        network.stats.record_sent(100);
+        network.stats.record_echo_request();
+
+        let mut restored = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        restored.restore(&network.save());
+        let stats = restored.get_stats();
+        assert_eq!((stats.bytes_sent, stats.packets_sent, stats.echo_requests), (100, 1, 1));
+        assert!(!restored.drain_progress().draining);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_memory_stats() {
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let empty = network.memory_stats();
+        assert!(empty.wasm_memory_bytes > 0);
+        assert_eq!((empty.reconnect_queue_packets, empty.send_queue_packets, empty.trace_frames), (0, 0, 0));
+
+        network.outbox.lock().hold();
+        network.send_packet(&[0u8; 100]).unwrap();
+        let mut stats = network.memory_stats();
+        assert_eq!(stats.reconnect_queue_packets, 1);
+        assert!(stats.reconnect_queue_bytes >= 100);
+        assert!(stats.approximate_bytes >= stats.reconnect_queue_bytes);
+
+        stats.add_traces((3, 256));
+        assert_eq!((stats.trace_frames, stats.trace_capacity), (3, 256));
+        assert!(stats.approximate_bytes >= stats.reconnect_queue_bytes + 3 * trace::SUMMARY_BYTES);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_error_counters() {
+        let stats = StatsCounters::default();
+        stats.record_error(&DerpError::InvalidProtocol("Truncated frame".into()));
+        stats.record_error(&DerpError::SerializationError("Invalid ServerInfo".into()));
+        stats.record_error(&DerpError::CryptoError("Decryption failed".into()));
+        stats.record_error(&DerpError::PacketTooLarge("2000-byte packet".into()));
+        stats.record_error(&DerpError::AuthRejected("unknown token".into()));
+        stats.record_send_failure();
+
+        let stats = stats.snapshot();
+        assert_eq!((stats.decode_errors, stats.decrypt_failures), (2, 1));
+        assert_eq!((stats.oversized_frames, stats.send_failures), (1, 1));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use crate::error::{DerpError, DerpResult};
+use crate::protocol::PeerKey;
+
+/// A packet sent while reconnecting, kept in the clear so it's encrypted
+/// under whichever session it finally goes out on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedPacket {
+    pub peer: Option<PeerKey>,
+    pub payload: Vec<u8>,
+}
+
+/// Packets held between a dropped connection and the handshake that
+/// replaces it. Outside that window sends fail as usual, so nothing is
+/// queued before the first connect or after giving up.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    capacity: usize,
+    holding: bool,
+    packets: VecDeque<QueuedPacket>,
+}
+
+impl Outbox {
+    /// A capacity of zero turns queueing off.
+    pub fn new(capacity: usize) -> Self {
+        Outbox {
+            capacity,
+            holding: false,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Starts holding packets, for when a reconnect is scheduled.
+    pub fn hold(&mut self) {
+        self.holding = self.capacity > 0;
+    }
+
+    pub fn is_holding(&self) -> bool {
+        self.holding
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Bytes held by the queued packets.
+    pub fn bytes(&self) -> usize {
+        self.packets.iter().map(|packet| packet.payload.capacity()).sum()
+    }
+
+    pub fn push(&mut self, peer: Option<PeerKey>, payload: &[u8]) -> DerpResult<()> {
+        if self.packets.len() >= self.capacity {
+            return Err(DerpError::InvalidState("Reconnecting: outbound queue full".into()));
+        }
+        self.packets.push_back(QueuedPacket { peer, payload: payload.to_vec() });
+        Ok(())
+    }
+
+    /// Stops holding and hands back what was queued, oldest first.
+    pub fn release(&mut self) -> Vec<QueuedPacket> {
+        self.holding = false;
+        self.packets.drain(..).collect()
+    }
+
+    /// Stops holding and discards what was queued.
+    pub fn clear(&mut self) {
+        self.holding = false;
+        self.packets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_holds_only_while_reconnecting() {
+        let mut outbox = Outbox::new(2);
+        assert!(!outbox.is_holding());
+
+        outbox.hold();
+        outbox.push(None, b"first").unwrap();
+        outbox.push(Some([1; 32]), b"second").unwrap();
+        assert!(outbox.push(None, b"third").is_err());
+
+        let packets = outbox.release();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].payload, b"first");
+        assert_eq!(packets[1].peer, Some([1; 32]));
+        assert!(!outbox.is_holding());
+        assert!(outbox.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_zero_capacity_never_holds() {
+        let mut outbox = Outbox::new(0);
+        outbox.hold();
+        assert!(!outbox.is_holding());
+
+        let mut outbox = Outbox::new(4);
+        outbox.hold();
+        outbox.push(None, b"lost").unwrap();
+        outbox.clear();
+        assert!(!outbox.is_holding());
+        assert_eq!(outbox.len(), 0);
+    }
+}
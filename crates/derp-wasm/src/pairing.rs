@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+use crate::protocol::{hex_decode_key, hex_encode, PEER_KEY_LEN};
+
+/// Starts every pairing code, naming its format.
+pub const PAIRING_PREFIX: &str = "DERP1:";
+
+/// RFC 4648 base32. Upper case letters and digits are all in the QR
+/// alphanumeric set, whose codes are far smaller than binary ones.
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const SCHEME_WSS: u8 = 0;
+const SCHEME_WS: u8 = 1;
+const CHECKSUM_LEN: usize = 4;
+
+/// What a pairing code carries: a peer's key and the relay it can be
+/// reached through. Returned by `DerpNetwork.parsePairingCode`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingInfo {
+    /// Hex, as in "peer-present" events.
+    pub public_key: String,
+    /// A ws:// or wss:// URL.
+    pub relay_url: String,
+}
+
+impl PairingInfo {
+    pub fn new(public_key: &[u8], relay_url: &str) -> DerpResult<PairingInfo> {
+        if public_key.len() != PEER_KEY_LEN {
+            return Err(DerpError::InvalidState(format!("Public keys are {} bytes", PEER_KEY_LEN)));
+        }
+        split_scheme(relay_url)?;
+        Ok(PairingInfo { public_key: hex_encode(public_key), relay_url: relay_url.to_string() })
+    }
+
+    /// A code for showing as a QR code or typing in, e.g.
+    /// "DERP1:AEBAGBAF...". The relay's scheme is packed into a byte and a
+    /// checksum catches typing mistakes.
+    pub fn encode(&self) -> DerpResult<String> {
+        let key = hex_decode_key(&self.public_key)?;
+        let (scheme, rest) = split_scheme(&self.relay_url)?;
+
+        let mut payload = Vec::with_capacity(PEER_KEY_LEN + 1 + rest.len() + CHECKSUM_LEN);
+        payload.extend_from_slice(&key);
+        payload.push(scheme);
+        payload.extend_from_slice(rest.as_bytes());
+        payload.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        Ok(format!("{}{}", PAIRING_PREFIX, base32_encode(&payload)))
+    }
+
+    /// Reads a code from `encode`. Case, spaces and dashes are ignored, so
+    /// codes read out in groups can be typed back as they were heard.
+    pub fn decode(code: &str) -> DerpResult<PairingInfo> {
+        let invalid = |why: &str| DerpError::InvalidProtocol(format!("Invalid pairing code: {}", why));
+        let code: String = code.chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_ascii_uppercase();
+        let body = code.strip_prefix(PAIRING_PREFIX).ok_or_else(|| invalid("not a DERP1 code"))?;
+
+        let payload = base32_decode(body).ok_or_else(|| invalid("not base32"))?;
+        if payload.len() < PEER_KEY_LEN + 1 + CHECKSUM_LEN {
+            return Err(invalid("too short"));
+        }
+        let (data, checksum) = payload.split_at(payload.len() - CHECKSUM_LEN);
+        if crc32fast::hash(data).to_be_bytes() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let (key, rest) = data.split_at(PEER_KEY_LEN);
+        let scheme = match rest[0] {
+            SCHEME_WSS => "wss://",
+            SCHEME_WS => "ws://",
+            _ => return Err(invalid("unknown relay scheme")),
+        };
+        let rest = std::str::from_utf8(&rest[1..]).map_err(|_| invalid("relay URL isn't UTF-8"))?;
+        PairingInfo::new(key, &format!("{}{}", scheme, rest))
+    }
+}
+
+fn split_scheme(url: &str) -> DerpResult<(u8, &str)> {
+    if let Some(rest) = url.strip_prefix("wss://") {
+        Ok((SCHEME_WSS, rest))
+    } else if let Some(rest) = url.strip_prefix("ws://") {
+        Ok((SCHEME_WS, rest))
+    } else {
+        Err(DerpError::InvalidState(format!("Relay must be a ws:// or wss:// URL: {}", url)))
+    }
+}
+
+/// Unpadded, since the length is never ambiguous here.
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_round_trip() {
+        let info = PairingInfo::new(&[7u8; 32], "wss://derp1.example.com/derp").unwrap();
+        let code = info.encode().unwrap();
+        assert!(code.starts_with(PAIRING_PREFIX));
+        assert!(code.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b':'));
+        assert_eq!(PairingInfo::decode(&code).unwrap(), info);
+
+        // As typed back by someone reading it out in groups
+        let typed = code.to_lowercase().as_bytes().chunks(4)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(PairingInfo::decode(&typed).unwrap(), info);
+
+        let local = PairingInfo::new(&[8u8; 32], "ws://localhost:3340/derp").unwrap();
+        assert_eq!(PairingInfo::decode(&local.encode().unwrap()).unwrap(), local);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_mistakes_are_caught() {
+        let code = PairingInfo::new(&[7u8; 32], "wss://relay.example/derp").unwrap().encode().unwrap();
+        let mut typo = code.into_bytes();
+        let i = PAIRING_PREFIX.len() + 10;
+        typo[i] = if typo[i] == b'A' { b'B' } else { b'A' };
+        assert!(PairingInfo::decode(std::str::from_utf8(&typo).unwrap()).is_err());
+
+        assert!(PairingInfo::decode("DERP1:0189").is_err());
+        assert!(PairingInfo::decode("hello").is_err());
+        assert!(PairingInfo::new(&[7u8; 31], "wss://relay.example/derp").is_err());
+        assert!(PairingInfo::new(&[7u8; 32], "https://relay.example/derp").is_err());
+    }
+}
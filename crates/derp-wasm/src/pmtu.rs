@@ -0,0 +1,203 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::ip::{self, Ipv4Packet, Ipv6Packet, PROTO_ICMP, PROTO_ICMPV6, PROTO_TCP};
+
+const IPV4_DONT_FRAGMENT: u8 = 0x40;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1FFF;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+/// ICMPv6 errors may quote the offending packet up to the minimum IPv6 MTU.
+const IPV6_MIN_MTU: usize = 1280;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_HEADER_LEN: u16 = 20;
+const IPV4_HEADER_LEN: u16 = 20;
+const IPV6_HEADER_LEN: u16 = 40;
+
+/// Returns a copy of `packet` with the MSS option of a TCP SYN lowered to
+/// fit `mtu`, or None if it's not a SYN or already fits.
+pub fn clamp_mss(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    let (header_len, protocol, overhead) = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::parse(packet)?;
+            (ip.header.len(), ip.protocol, IPV4_HEADER_LEN + TCP_HEADER_LEN)
+        }
+        6 => {
+            let ip = Ipv6Packet::parse(packet)?;
+            (IPV6_HEADER_LEN as usize, ip.next_header, IPV6_HEADER_LEN + TCP_HEADER_LEN)
+        }
+        _ => return None,
+    };
+    if protocol != PROTO_TCP {
+        return None;
+    }
+
+    let segment = packet.get(header_len..)?;
+    let data_offset = (*segment.get(12)? >> 4) as usize * 4;
+    if segment[13] & TCP_SYN == 0 || data_offset < TCP_HEADER_LEN as usize || segment.len() < data_offset {
+        return None;
+    }
+
+    let limit = mtu.saturating_sub(overhead);
+    let mut offset = TCP_HEADER_LEN as usize;
+    while offset + 1 < data_offset {
+        match segment[offset] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => offset += 1,
+            TCP_OPTION_MSS if segment[offset + 1] == 4 && offset + 4 <= data_offset => {
+                let mss = u16::from_be_bytes([segment[offset + 2], segment[offset + 3]]);
+                if mss <= limit {
+                    return None;
+                }
+                let mut clamped = packet.to_vec();
+                let mss_at = header_len + offset + 2;
+                clamped[mss_at..mss_at + 2].copy_from_slice(&limit.to_be_bytes());
+                rewrite_tcp_checksum(&mut clamped, header_len);
+                return Some(clamped);
+            }
+            _ => {
+                let len = segment[offset + 1] as usize;
+                if len < 2 {
+                    return None;
+                }
+                offset += len;
+            }
+        }
+    }
+    None
+}
+
+fn rewrite_tcp_checksum(packet: &mut [u8], header_len: usize) {
+    let checksum_at = header_len + 16;
+    packet[checksum_at..checksum_at + 2].copy_from_slice(&[0, 0]);
+    let checksum = match Ipv4Packet::parse(packet) {
+        Some(ip) => ip::checksum(&ip::pseudo_header(ip.src, ip.dst, PROTO_TCP, ip.payload)),
+        None => match Ipv6Packet::parse(packet) {
+            Some(ip) => ip::checksum(&ip::pseudo_header_v6(ip.src, ip.dst, PROTO_TCP, ip.payload)),
+            None => return,
+        },
+    };
+    packet[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Whether an IPv4 packet forbids fragmentation.
+pub fn dont_fragment(packet: &[u8]) -> bool {
+    packet.get(6).is_some_and(|flags| flags & IPV4_DONT_FRAGMENT != 0)
+}
+
+/// ICMP Fragmentation Needed from `gateway` back to the sender of `packet`.
+pub fn fragmentation_needed(packet: &[u8], mtu: u16, gateway: Ipv4Addr) -> Option<Vec<u8>> {
+    let ip = Ipv4Packet::parse(packet)?;
+
+    let mut message = vec![ICMP_DEST_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED, 0, 0, 0, 0];
+    message.extend_from_slice(&mtu.to_be_bytes());
+    // The original header and the first 8 bytes of its payload
+    let quoted = (ip.header.len() + 8).min(packet.len());
+    message.extend_from_slice(&packet[..quoted]);
+
+    let checksum = ip::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(ip::build_ipv4(gateway, ip.src, PROTO_ICMP, &message))
+}
+
+/// ICMPv6 Packet Too Big from `gateway` back to the sender of `packet`.
+pub fn packet_too_big(packet: &[u8], mtu: u16, gateway: Ipv6Addr) -> Option<Vec<u8>> {
+    let ip = Ipv6Packet::parse(packet)?;
+
+    let mut message = vec![ICMPV6_PACKET_TOO_BIG, 0, 0, 0];
+    message.extend_from_slice(&(mtu as u32).to_be_bytes());
+    let room = IPV6_MIN_MTU - IPV6_HEADER_LEN as usize - message.len();
+    message.extend_from_slice(&packet[..packet.len().min(room)]);
+
+    let checksum = ip::checksum(&ip::pseudo_header_v6(gateway, ip.src, PROTO_ICMPV6, &message));
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(ip::build_ipv6(gateway, ip.src, PROTO_ICMPV6, 64, &message))
+}
+
+/// Splits an IPv4 packet into fragments of at most `mtu` bytes. Header
+/// options are repeated in every fragment.
+pub fn fragment_ipv4(packet: &[u8], mtu: u16) -> Option<Vec<Vec<u8>>> {
+    let ip = Ipv4Packet::parse(packet)?;
+    let header = ip.header;
+    let chunk_len = (mtu as usize).checked_sub(header.len())? / 8 * 8;
+    if chunk_len == 0 {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([header[6], header[7]]);
+    let base_offset = flags & IPV4_OFFSET_MASK;
+    let more_after = flags & IPV4_MORE_FRAGMENTS != 0;
+
+    let chunks: Vec<&[u8]> = ip.payload.chunks(chunk_len).collect();
+    let last = chunks.len().saturating_sub(1);
+    let fragments = chunks.iter().enumerate().map(|(i, chunk)| {
+        let mut fragment = Vec::with_capacity(header.len() + chunk.len());
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(chunk);
+
+        let total_len = fragment.len() as u16;
+        fragment[2..4].copy_from_slice(&total_len.to_be_bytes());
+        let mut field = base_offset + (i * chunk_len / 8) as u16;
+        if i < last || more_after {
+            field |= IPV4_MORE_FRAGMENTS;
+        }
+        fragment[6..8].copy_from_slice(&field.to_be_bytes());
+        fragment[10..12].copy_from_slice(&[0, 0]);
+        let checksum = ip::checksum(&fragment[..header.len()]);
+        fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+        fragment
+    });
+    Some(fragments.collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const GUEST: Ipv4Addr = Ipv4Addr::new(192, 168, 86, 100);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    fn syn_with_mss(mss: u16) -> Vec<u8> {
+        let mut segment = vec![0u8; 24];
+        segment[12] = 6 << 4;
+        segment[13] = TCP_SYN;
+        segment[20..24].copy_from_slice(&[TCP_OPTION_MSS, 4, (mss >> 8) as u8, mss as u8]);
+        let checksum = ip::checksum(&ip::pseudo_header(GUEST, REMOTE, PROTO_TCP, &segment));
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        ip::build_ipv4(GUEST, REMOTE, PROTO_TCP, &segment)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_clamp_mss() {
+        let clamped = clamp_mss(&syn_with_mss(1460), 1280).unwrap();
+        let ip = Ipv4Packet::parse(&clamped).unwrap();
+        assert_eq!(&ip.payload[22..24], &1240u16.to_be_bytes());
+        assert_eq!(ip::checksum(&ip::pseudo_header(ip.src, ip.dst, PROTO_TCP, ip.payload)), 0);
+
+        assert!(clamp_mss(&syn_with_mss(1200), 1280).is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_fragmentation() {
+        let packet = ip::build_ipv4(GUEST, REMOTE, ip::PROTO_UDP, &[7; 1000]);
+        let fragments = fragment_ipv4(&packet, 576).unwrap();
+        assert_eq!(fragments.len(), 2);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 576));
+        assert_eq!(u16::from_be_bytes([fragments[1][6], fragments[1][7]]), 552 / 8);
+        assert_eq!(ip::checksum(&fragments[1][..20]), 0);
+
+        assert!(dont_fragment(&packet));
+        let error = fragmentation_needed(&packet, 576, Ipv4Addr::new(192, 168, 86, 1)).unwrap();
+        let icmp = Ipv4Packet::parse(&error).unwrap();
+        assert_eq!(icmp.dst, GUEST);
+        assert_eq!(&icmp.payload[..2], &[ICMP_DEST_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED]);
+        assert_eq!(&icmp.payload[6..8], &576u16.to_be_bytes());
+    }
+}
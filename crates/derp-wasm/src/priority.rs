@@ -0,0 +1,72 @@
+use crate::flow::FlowKey;
+use crate::ip::PROTO_ICMP;
+
+/// Guest packets up to this size are taken for keystrokes, acks and other
+/// chatter that someone is waiting on.
+pub const SMALL_PACKET_BYTES: usize = 160;
+
+/// Ports whose traffic is interactive whatever its size: SSH, telnet, DNS,
+/// RDP and VNC.
+pub const INTERACTIVE_PORTS: [u16; 5] = [22, 23, 53, 3389, 5900];
+
+/// Send classes, highest first. While the socket is congested, each class
+/// waits only behind the classes above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Protocol frames: handshake, MAC announcements, Goodbye. Never held
+    /// back or dropped.
+    Control,
+    Interactive,
+    Bulk,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    /// Classifies a guest packet, before it's tagged and encrypted.
+    pub fn classify(packet: &[u8]) -> Priority {
+        if packet.len() <= SMALL_PACKET_BYTES {
+            return Priority::Interactive;
+        }
+        match FlowKey::from_ipv4(packet) {
+            Some(flow) if flow.protocol == PROTO_ICMP => Priority::Interactive,
+            Some(flow) if INTERACTIVE_PORTS.contains(&flow.dst_port) || INTERACTIVE_PORTS.contains(&flow.src_port) => {
+                Priority::Interactive
+            }
+            _ => Priority::Bulk,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip::{self, PROTO_TCP};
+    use std::net::Ipv4Addr;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn tcp(src_port: u16, dst_port: u16, len: usize) -> Vec<u8> {
+        let mut segment = vec![0; len];
+        segment[..2].copy_from_slice(&src_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        ip::build_ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), PROTO_TCP, &segment)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_small_and_ssh_packets_are_interactive() {
+        assert_eq!(Priority::classify(&tcp(40000, 443, 40)), Priority::Interactive);
+        assert_eq!(Priority::classify(&tcp(40000, 443, 1400)), Priority::Bulk);
+        assert_eq!(Priority::classify(&tcp(40000, 22, 1400)), Priority::Interactive);
+        assert_eq!(Priority::classify(&tcp(22, 40000, 1400)), Priority::Interactive);
+        assert_eq!(Priority::classify(&[0u8; 1400]), Priority::Bulk);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_classes_are_ordered() {
+        assert!(Priority::Control < Priority::Interactive);
+        assert!(Priority::Interactive < Priority::Bulk);
+        assert_eq!(Priority::Bulk as usize, Priority::COUNT - 1);
+    }
+}
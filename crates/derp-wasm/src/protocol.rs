@@ -0,0 +1,439 @@
+use wasm_bindgen::prelude::*;
+use js_sys::{Uint8Array, Object};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use crate::crypto::CryptoState;
+#[cfg(feature = "pq")]
+use crate::crypto::{hybrid_encapsulate, HybridKeyPair};
+#[cfg(feature = "passphrase")]
+use crate::crypto::passphrase_keys;
+use crate::error::{DerpError, DerpResult};
+#[cfg(feature = "group")]
+use crate::group::GroupSession;
+#[cfg(feature = "ratchet")]
+use crate::ratchet::RatchetSession;
+
+// Framing and the relay handshake are in derp-core; re-exported so their
+// paths are unchanged
+pub use derp_core::protocol::*;
+
+/// A UUID with the uuid feature, otherwise as many random bytes in hex.
+#[cfg(feature = "uuid")]
+fn new_session_id() -> DerpResult<String> {
+    Ok(uuid::Uuid::new_v4().to_string())
+}
+
+#[cfg(not(feature = "uuid"))]
+fn new_session_id() -> DerpResult<String> {
+    let mut id = [0u8; 16];
+    getrandom::getrandom(&mut id)
+        .map_err(|e| DerpError::CryptoError(format!("Failed to pick a session id: {}", e)))?;
+    Ok(hex_encode(&id))
+}
+
+/// Owns a set of isolated crypto sessions keyed by session id. Packets are
+/// deflated when that makes them smaller, then encrypted under the session's
+/// own key with the session id bound as associated data, or for ratchet
+/// sessions under the next message key.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct DerpProtocol {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    peers: Arc<Mutex<HashSet<String>>>,
+    /// Key pairs from `createKemOffer`, until the peer's answer completes them.
+    #[cfg(feature = "pq")]
+    offers: Arc<Mutex<HashMap<String, HybridKeyPair>>>,
+}
+
+#[derive(Clone)]
+enum Session {
+    Static(Arc<CryptoState>),
+    #[cfg(feature = "ratchet")]
+    Ratchet(Arc<Mutex<RatchetSession>>),
+    #[cfg(feature = "group")]
+    Group(Arc<Mutex<GroupSession>>),
+}
+
+const PACKET_RAW: u8 = 0;
+const PACKET_DEFLATE: u8 = 1;
+const MAX_DECOMPRESSED_SIZE: usize = u16::MAX as usize;
+
+#[wasm_bindgen]
+impl DerpProtocol {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        DerpProtocol {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            peers: Arc::new(Mutex::new(HashSet::new())),
+            #[cfg(feature = "pq")]
+            offers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Generates fresh 256-bit key material for a new identity.
+    #[wasm_bindgen(js_name = generateKeyPair)]
+    pub async fn generate_key_pair(&self) -> Vec<u8> {
+        CryptoState::generate_key()
+    }
+
+    #[wasm_bindgen(js_name = createSession)]
+    pub async fn create_session(&self) -> DerpResult<String> {
+        let session_id = new_session_id()?;
+        let crypto = CryptoState::new()?;
+
+        self.sessions.lock().unwrap().insert(session_id.clone(), Session::Static(Arc::new(crypto)));
+        Ok(session_id)
+    }
+
+    /// Creates a double-ratchet session with a peer, from a 32-byte secret
+    /// both sides already share. The side given the other's ratchet key
+    /// (from `getRatchetKey`) is the initiator and must send first. Peers
+    /// name their sessions independently, so unlike other sessions the id
+    /// isn't bound into the ciphertext.
+    #[cfg(feature = "ratchet")]
+    #[wasm_bindgen(js_name = createRatchetSession)]
+    pub async fn create_ratchet_session(&self, shared_secret: &[u8], peer_ratchet_key: Option<Vec<u8>>) -> DerpResult<String> {
+        let session_id = new_session_id()?;
+        let ratchet = match peer_ratchet_key {
+            Some(key) => RatchetSession::initiator(shared_secret, &key)?,
+            None => RatchetSession::responder(shared_secret)?,
+        };
+
+        self.sessions.lock().unwrap().insert(session_id.clone(), Session::Ratchet(Arc::new(Mutex::new(ratchet))));
+        Ok(session_id)
+    }
+
+    /// The ratchet session's current public key, for the responder to hand
+    /// the initiator.
+    #[cfg(feature = "ratchet")]
+    #[wasm_bindgen(js_name = getRatchetKey)]
+    pub fn get_ratchet_key(&self, session_id: &str) -> DerpResult<Vec<u8>> {
+        match self.session(session_id)? {
+            Session::Ratchet(ratchet) => Ok(ratchet.lock().unwrap().public_key().to_vec()),
+            _ => Err(DerpError::InvalidState(format!("Not a ratchet session: {}", session_id))),
+        }
+    }
+
+    /// Joins a broadcast group from its 32-byte key, which every member
+    /// gets from the coordination server or beforehand. A packet encrypted
+    /// in it is sent once for the relay to pass to every peer, and only
+    /// members added with `addGroupMember` are accepted as its senders.
+    /// Give `signingKey`, 32 secret bytes, to keep the same member key
+    /// across sessions; otherwise a fresh one is made.
+    #[cfg(feature = "group")]
+    #[wasm_bindgen(js_name = createGroupSession)]
+    pub fn create_group_session(&self, group_key: &[u8], signing_key: Option<Vec<u8>>) -> DerpResult<String> {
+        let session_id = new_session_id()?;
+        let group = GroupSession::new(group_key, signing_key.as_deref())?;
+
+        self.sessions.lock().unwrap().insert(session_id.clone(), Session::Group(Arc::new(Mutex::new(group))));
+        Ok(session_id)
+    }
+
+    /// This side's member key in a group session, for the other members
+    /// to add.
+    #[cfg(feature = "group")]
+    #[wasm_bindgen(js_name = getGroupMemberKey)]
+    pub fn get_group_member_key(&self, session_id: &str) -> DerpResult<Vec<u8>> {
+        Ok(self.group(session_id)?.lock().unwrap().public_key().to_vec())
+    }
+
+    /// Accepts packets signed by `memberKey`. Returns false if they already
+    /// were.
+    #[cfg(feature = "group")]
+    #[wasm_bindgen(js_name = addGroupMember)]
+    pub fn add_group_member(&self, session_id: &str, member_key: &[u8]) -> DerpResult<bool> {
+        self.group(session_id)?.lock().unwrap().add_member(member_key)
+    }
+
+    #[cfg(feature = "group")]
+    #[wasm_bindgen(js_name = removeGroupMember)]
+    pub fn remove_group_member(&self, session_id: &str, member_key: &[u8]) -> DerpResult<bool> {
+        Ok(self.group(session_id)?.lock().unwrap().remove_member(member_key))
+    }
+
+    /// Like `decryptPacket` for a group session, also naming the member
+    /// who sent it: `{ sender, packet }`, with `sender` in hex.
+    #[cfg(feature = "group")]
+    #[wasm_bindgen(js_name = decryptGroupPacket)]
+    pub fn decrypt_group_packet(&self, session_id: &str, data: &[u8]) -> Result<Object, JsValue> {
+        let (sender, plaintext) = self.group(session_id)?.lock().unwrap().decrypt(data, &[])?;
+        let packet = unframe_packet(&plaintext)?;
+        let result = Object::new();
+        js_sys::Reflect::set(&result, &JsValue::from_str("sender"), &JsValue::from_str(&hex_encode(&sender)))?;
+        js_sys::Reflect::set(&result, &JsValue::from_str("packet"), &Uint8Array::from(&packet[..]))?;
+        Ok(result)
+    }
+
+    #[wasm_bindgen(js_name = closeSession)]
+    pub fn close_session(&self, session_id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(session_id).is_some()
+    }
+
+    /// Starts a session keyed by hybrid X25519 + ML-KEM-768 exchange.
+    /// Returns `{ offerId, publicKey }`; the peer answers the public key
+    /// with `acceptKemOffer`, and its ciphertext goes to `completeKemOffer`.
+    #[cfg(feature = "pq")]
+    #[wasm_bindgen(js_name = createKemOffer)]
+    pub fn create_kem_offer(&self) -> Result<Object, JsValue> {
+        let (offer_id, public_key) = self.kem_offer()?;
+        let result = Object::new();
+        js_sys::Reflect::set(&result, &JsValue::from_str("offerId"), &JsValue::from_str(&offer_id))?;
+        js_sys::Reflect::set(&result, &JsValue::from_str("publicKey"), &Uint8Array::from(&public_key[..]))?;
+        Ok(result)
+    }
+
+    /// Answers a peer's `createKemOffer`, creating the session on this side.
+    /// Returns `{ sessionId, ciphertext }`; the ciphertext goes back to the
+    /// peer. Both sides end up with the same session id.
+    #[cfg(feature = "pq")]
+    #[wasm_bindgen(js_name = acceptKemOffer)]
+    pub fn accept_kem_offer(&self, public_key: &[u8]) -> Result<Object, JsValue> {
+        let (session_id, ciphertext) = self.kem_accept(public_key)?;
+        let result = Object::new();
+        js_sys::Reflect::set(&result, &JsValue::from_str("sessionId"), &JsValue::from_str(&session_id))?;
+        js_sys::Reflect::set(&result, &JsValue::from_str("ciphertext"), &Uint8Array::from(&ciphertext[..]))?;
+        Ok(result)
+    }
+
+    /// Creates a session from a passphrase both sides were given, e.g.
+    /// typed into each browser, with a salt naming the pairing. Peers with
+    /// the same passphrase and salt get the same session id, with nothing
+    /// exchanged first. Deriving the key is deliberately slow.
+    #[cfg(feature = "passphrase")]
+    #[wasm_bindgen(js_name = createPassphraseSession)]
+    pub async fn create_passphrase_session(&self, passphrase: &str, salt: &[u8]) -> DerpResult<String> {
+        let keys = passphrase_keys(passphrase, salt)?;
+        let crypto = CryptoState::from_passphrase_keys(&keys)?;
+        Ok(self.insert_shared_session(b"derp-passphrase-session", &keys, crypto))
+    }
+
+    /// Creates the session from the peer's answer to an offer, returning
+    /// its id. Each offer can be completed once.
+    #[cfg(feature = "pq")]
+    #[wasm_bindgen(js_name = completeKemOffer)]
+    pub fn complete_kem_offer(&self, offer_id: &str, ciphertext: &[u8]) -> DerpResult<String> {
+        let key_pair = self.offers.lock().unwrap().remove(offer_id)
+            .ok_or_else(|| DerpError::InvalidState(format!("Unknown offer: {}", offer_id)))?;
+        let secret = key_pair.decapsulate(ciphertext)?;
+        Ok(self.insert_shared_session(b"derp-kem-session", &secret, CryptoState::with_key(&secret)?))
+    }
+
+    #[wasm_bindgen(js_name = encryptPacket)]
+    pub async fn encrypt_packet(&self, session_id: &str, packet: &[u8]) -> DerpResult<Vec<u8>> {
+        let session = self.session(session_id)?;
+
+        let mut plaintext = Vec::with_capacity(1 + packet.len());
+        match deflate(packet).filter(|compressed| compressed.len() < packet.len()) {
+            Some(compressed) => {
+                plaintext.push(PACKET_DEFLATE);
+                plaintext.extend_from_slice(&compressed);
+            }
+            None => {
+                plaintext.push(PACKET_RAW);
+                plaintext.extend_from_slice(packet);
+            }
+        }
+
+        match session {
+            Session::Static(crypto) => crypto.encrypt(&plaintext, session_id.as_bytes()),
+            #[cfg(feature = "ratchet")]
+            Session::Ratchet(ratchet) => ratchet.lock().unwrap().encrypt(&plaintext, &[]),
+            #[cfg(feature = "group")]
+            Session::Group(group) => group.lock().unwrap().encrypt(&plaintext, &[]),
+        }
+    }
+
+    #[wasm_bindgen(js_name = decryptPacket)]
+    pub async fn decrypt_packet(&self, session_id: &str, data: &[u8]) -> DerpResult<Vec<u8>> {
+        let plaintext = match self.session(session_id)? {
+            Session::Static(crypto) => crypto.decrypt(data, session_id.as_bytes())?,
+            #[cfg(feature = "ratchet")]
+            Session::Ratchet(ratchet) => ratchet.lock().unwrap().decrypt(data, &[])?,
+            #[cfg(feature = "group")]
+            Session::Group(group) => group.lock().unwrap().decrypt(data, &[])?.1,
+        };
+        unframe_packet(&plaintext)
+    }
+
+    pub fn create_frame(&self, frame_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.push(PROTOCOL_VERSION);
+        frame.push(frame_type);
+        frame.push(0); // flags
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[wasm_bindgen(js_name = handlePeerState)]
+    pub fn handle_peer_state(&self, frame_type: u8, payload: &[u8]) -> DerpResult<()> {
+        let key = parse_peer_key(payload)?;
+        let peer_key = hex_encode(&key);
+        let mut peers = self.peers.lock().unwrap();
+
+        match frame_type {
+            x if x == FrameType::PeerPresent as u8 => {
+                peers.insert(peer_key);
+            }
+            x if x == FrameType::PeerGone as u8 => {
+                peers.remove(&peer_key);
+            }
+            _ => return Err(DerpError::InvalidProtocol("Invalid peer state frame type".into()))
+        }
+
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = createPacketFrame)]
+    pub fn create_packet_frame(&self, packet: &[u8], dest_key: &[u8]) -> DerpResult<Uint8Array> {
+        let dest_key = parse_peer_key(dest_key)?;
+
+        let mut payload = Vec::with_capacity(PEER_KEY_LEN + packet.len());
+        payload.extend_from_slice(&dest_key);
+        payload.extend_from_slice(packet);
+
+        let frame = self.create_frame(FrameType::Send as u8, &payload);
+        Ok(Uint8Array::from(&frame[..]))
+    }
+
+    #[wasm_bindgen(js_name = handleRecvPacket)]
+    pub fn handle_recv_packet(&self, payload: &[u8]) -> Result<Object, JsValue> {
+        if payload.len() < PEER_KEY_LEN {
+            return Err(DerpError::InvalidProtocol("Invalid packet payload length".into()).into());
+        }
+
+        let (src_key, packet) = payload.split_at(PEER_KEY_LEN);
+        let result = Object::new();
+
+        js_sys::Reflect::set(
+            &result,
+            &JsValue::from_str("srcKey"),
+            &JsValue::from_str(&hex_encode(src_key))
+        )?;
+
+        js_sys::Reflect::set(
+            &result,
+            &JsValue::from_str("packet"),
+            &Uint8Array::from(packet)
+        )?;
+
+        Ok(result)
+    }
+}
+
+impl DerpProtocol {
+    pub fn decode_frame_header(&self, data: &[u8]) -> DerpResult<(u8, u8, u8, usize)> {
+        if data.len() < FRAME_HEADER_SIZE {
+            return Err(DerpError::InvalidProtocol("Frame too short".into()));
+        }
+
+        let version = data[0];
+        let frame_type = data[1];
+        let flags = data[2];
+        let length = ((data[3] as usize) << 8) | (data[4] as usize);
+
+        Ok((version, frame_type, flags, length))
+    }
+
+    #[cfg(feature = "pq")]
+    pub(crate) fn kem_offer(&self) -> DerpResult<(String, Vec<u8>)> {
+        let offer_id = new_session_id()?;
+        let key_pair = HybridKeyPair::generate()?;
+        let public_key = key_pair.public_key().to_vec();
+        self.offers.lock().unwrap().insert(offer_id.clone(), key_pair);
+        Ok((offer_id, public_key))
+    }
+
+    #[cfg(feature = "pq")]
+    pub(crate) fn kem_accept(&self, public_key: &[u8]) -> DerpResult<(String, Vec<u8>)> {
+        let (ciphertext, secret) = hybrid_encapsulate(public_key)?;
+        let crypto = CryptoState::with_key(&secret)?;
+        Ok((self.insert_shared_session(b"derp-kem-session", &secret, crypto), ciphertext))
+    }
+
+    /// Both sides name the session after a hash of the secret, so its id
+    /// can be bound into packets like any other session's.
+    #[cfg(any(feature = "pq", feature = "passphrase"))]
+    fn insert_shared_session(&self, label: &[u8], secret: &[u8], crypto: CryptoState) -> String {
+        use sha2::{Digest, Sha256};
+        let digest = Sha256::new().chain_update(label).chain_update(secret).finalize();
+        let session_id = hex_encode(&digest[..16]);
+        self.sessions.lock().unwrap().insert(session_id.clone(), Session::Static(Arc::new(crypto)));
+        session_id
+    }
+
+    fn session(&self, session_id: &str) -> DerpResult<Session> {
+        self.sessions.lock().unwrap()
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| DerpError::InvalidState(format!("Unknown session: {}", session_id)))
+    }
+
+    #[cfg(feature = "group")]
+    fn group(&self, session_id: &str) -> DerpResult<Arc<Mutex<GroupSession>>> {
+        match self.session(session_id)? {
+            Session::Group(group) => Ok(group),
+            _ => Err(DerpError::InvalidState(format!("Not a group session: {}", session_id))),
+        }
+    }
+}
+
+/// Undoes the encoding `encryptPacket` gives a packet before encrypting it.
+fn unframe_packet(plaintext: &[u8]) -> DerpResult<Vec<u8>> {
+    match plaintext.split_first() {
+        Some((&PACKET_RAW, packet)) => Ok(packet.to_vec()),
+        Some((&PACKET_DEFLATE, compressed)) => inflate(compressed, MAX_DECOMPRESSED_SIZE),
+        _ => Err(DerpError::InvalidProtocol("Unknown packet encoding".into())),
+    }
+}
+
+impl Default for DerpProtocol {
+    fn default() -> Self {
+        DerpProtocol::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    async fn create_test_protocol() -> DerpProtocol {
+        DerpProtocol::new()
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_frame_creation() {
+        let protocol = create_test_protocol().await;
+        let payload = vec![1, 2, 3, 4];
+        let frame = protocol.create_frame(FrameType::Send as u8, &payload);
+        
+        let (version, frame_type, flags, length) = protocol.decode_frame_header(&frame).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(frame_type, FrameType::Send as u8);
+        assert_eq!(flags, 0);
+        assert_eq!(length, payload.len());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_peer_state() {
+        let protocol = create_test_protocol().await;
+        let peer_key = vec![0u8; 32];
+        
+        protocol.handle_peer_state(FrameType::PeerPresent as u8, &peer_key).unwrap();
+        
+        let peers = protocol.peers.lock().unwrap();
+        assert!(peers.contains(&hex_encode(&peer_key)));
+        
+        drop(peers);
+        
+        protocol.handle_peer_state(FrameType::PeerGone as u8, &peer_key).unwrap();
+        
+        let peers = protocol.peers.lock().unwrap();
+        assert!(!peers.contains(&hex_encode(&peer_key)));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::protocol::DerpProtocol;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+    use rand::Rng;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn create_test_packet() -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let mut packet = vec![0u8; 1500]; // Standard MTU size
+        rng.fill(&mut packet[..]);
+        packet
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_key_generation() {
+        let protocol = DerpProtocol::new();
+        let key_pair = protocol.generate_key_pair().await;
+        assert!(!key_pair.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_session_creation() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        assert!(!session_id.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_packet_encryption_decryption() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        let original_packet = create_test_packet();
+
+        // Encrypt packet
+        let encrypted = protocol.encrypt_packet(&session_id, &original_packet).await.unwrap();
+        assert!(!encrypted.is_empty());
+        assert_ne!(encrypted, original_packet);
+
+        // Decrypt packet
+        let decrypted = protocol.decrypt_packet(&session_id, &encrypted).await.unwrap();
+        assert_eq!(decrypted, original_packet);
+    }
+
+    #[cfg(feature = "ratchet")]
+    #[wasm_bindgen_test]
+    async fn test_ratchet_sessions_between_peers() {
+        let secret = [9u8; 32];
+        let bob = DerpProtocol::new();
+        let bob_session = bob.create_ratchet_session(&secret, None).await.unwrap();
+        let alice = DerpProtocol::new();
+        let bob_key = bob.get_ratchet_key(&bob_session).unwrap();
+        let alice_session = alice.create_ratchet_session(&secret, Some(bob_key)).await.unwrap();
+
+        let packet = create_test_packet();
+        let encrypted = alice.encrypt_packet(&alice_session, &packet).await.unwrap();
+        assert_eq!(bob.decrypt_packet(&bob_session, &encrypted).await.unwrap(), packet);
+        assert!(bob.decrypt_packet(&bob_session, &encrypted).await.is_err());
+
+        let reply = bob.encrypt_packet(&bob_session, b"pong").await.unwrap();
+        assert_eq!(alice.decrypt_packet(&alice_session, &reply).await.unwrap(), b"pong");
+
+        let plain = alice.create_session().await.unwrap();
+        assert!(alice.get_ratchet_key(&plain).is_err());
+    }
+
+    #[cfg(feature = "group")]
+    #[wasm_bindgen_test]
+    async fn test_group_broadcast_between_peers() {
+        let group_key = [4u8; 32];
+        let peers: Vec<_> = (0..3).map(|_| DerpProtocol::new()).collect();
+        let sessions: Vec<_> = peers.iter().map(|peer| peer.create_group_session(&group_key, None).unwrap()).collect();
+        let sender_key = peers[0].get_group_member_key(&sessions[0]).unwrap();
+        for (peer, session) in peers.iter().zip(&sessions).skip(1) {
+            assert!(peer.add_group_member(session, &sender_key).unwrap());
+        }
+
+        // Encrypted once, and every member can read it
+        let packet = create_test_packet();
+        let encrypted = peers[0].encrypt_packet(&sessions[0], &packet).await.unwrap();
+        for (peer, session) in peers.iter().zip(&sessions).skip(1) {
+            assert_eq!(peer.decrypt_packet(session, &encrypted).await.unwrap(), packet);
+        }
+
+        // Only from members
+        assert!(peers[1].remove_group_member(&sessions[1], &sender_key).unwrap());
+        let next = peers[0].encrypt_packet(&sessions[0], &packet).await.unwrap();
+        assert!(peers[1].decrypt_packet(&sessions[1], &next).await.is_err());
+        assert!(peers[2].decrypt_packet(&sessions[2], &next).await.is_ok());
+    }
+
+    #[cfg(feature = "pq")]
+    #[wasm_bindgen_test]
+    async fn test_kem_sessions_between_peers() {
+        let alice = DerpProtocol::new();
+        let bob = DerpProtocol::new();
+        let (offer_id, public_key) = alice.kem_offer().unwrap();
+        let (bob_session, ciphertext) = bob.kem_accept(&public_key).unwrap();
+        let alice_session = alice.complete_kem_offer(&offer_id, &ciphertext).unwrap();
+        assert_eq!(alice_session, bob_session);
+        assert!(alice.complete_kem_offer(&offer_id, &ciphertext).is_err());
+
+        let packet = create_test_packet();
+        let encrypted = bob.encrypt_packet(&bob_session, &packet).await.unwrap();
+        assert_eq!(alice.decrypt_packet(&alice_session, &encrypted).await.unwrap(), packet);
+    }
+
+    #[cfg(feature = "passphrase")]
+    #[wasm_bindgen_test]
+    async fn test_passphrase_sessions_between_peers() {
+        let alice = DerpProtocol::new();
+        let bob = DerpProtocol::new();
+        let alice_session = alice.create_passphrase_session("blue-otter-42", b"pairing-salt").await.unwrap();
+        let bob_session = bob.create_passphrase_session("blue-otter-42", b"pairing-salt").await.unwrap();
+        assert_eq!(alice_session, bob_session);
+
+        let packet = create_test_packet();
+        let encrypted = alice.encrypt_packet(&alice_session, &packet).await.unwrap();
+        assert_eq!(bob.decrypt_packet(&bob_session, &encrypted).await.unwrap(), packet);
+
+        let other = bob.create_passphrase_session("blue-otter-43", b"pairing-salt").await.unwrap();
+        assert_ne!(other, alice_session);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_packet_integrity() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        let original_packet = create_test_packet();
+
+        // Encrypt packet
+        let mut encrypted = protocol.encrypt_packet(&session_id, &original_packet).await.unwrap();
+        
+        // Tamper with encrypted data
+        let middle = encrypted.len() / 2;
+        if let Some(byte) = encrypted.get_mut(middle) {
+            *byte ^= 0xFF;
+        }
+
+        // Decryption should fail
+        let result = protocol.decrypt_packet(&session_id, &encrypted).await;
+        assert!(result.is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_session_isolation() {
+        let protocol = DerpProtocol::new();
+        let session_1 = protocol.create_session().await.unwrap();
+        let session_2 = protocol.create_session().await.unwrap();
+        let packet = create_test_packet();
+
+        // Encrypt with session 1
+        let encrypted = protocol.encrypt_packet(&session_1, &packet).await.unwrap();
+
+        // Attempt to decrypt with session 2 should fail
+        let result = protocol.decrypt_packet(&session_2, &encrypted).await;
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[wasm_bindgen_test]
+    async fn test_packet_compression() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        
+        // Create a highly compressible packet
+        let packet: Vec<u8> = (0..1500).map(|i| (i % 4) as u8).collect();
+
+        // Encrypt packet
+        let encrypted = protocol.encrypt_packet(&session_id, &packet).await.unwrap();
+
+        // Encrypted size should be smaller than original due to compression
+        assert!(encrypted.len() < packet.len());
+
+        // Decrypt and verify
+        let decrypted = protocol.decrypt_packet(&session_id, &encrypted).await.unwrap();
+        assert_eq!(decrypted, packet);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_large_packets() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        
+        // Test with various packet sizes
+        for size in [64, 512, 1500, 4096, 9000] {
+            let packet = vec![0u8; size];
+            let encrypted = protocol.encrypt_packet(&session_id, &packet).await.unwrap();
+            let decrypted = protocol.decrypt_packet(&session_id, &encrypted).await.unwrap();
+            assert_eq!(decrypted, packet);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_concurrent_operations() {
+        let protocol = DerpProtocol::new();
+        let session_id = protocol.create_session().await.unwrap();
+        let packet = create_test_packet();
+
+        // Perform multiple encryption/decryption operations concurrently
+        let mut handles = vec![];
+        for _ in 0..5 {
+            let protocol_clone = protocol.clone();
+            let session_id_clone = session_id.clone();
+            let packet_clone = packet.clone();
+            
+            handles.push(async move {
+                let encrypted = protocol_clone.encrypt_packet(&session_id_clone, &packet_clone).await.unwrap();
+                let decrypted = protocol_clone.decrypt_packet(&session_id_clone, &encrypted).await.unwrap();
+                assert_eq!(decrypted, packet_clone);
+            });
+        }
+
+        // Wait for all operations to complete
+        futures::future::join_all(handles).await;
+    }
+}
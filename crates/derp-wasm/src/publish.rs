@@ -0,0 +1,254 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{ArrayBuffer, Uint8Array};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+use std::rc::{Rc, Weak};
+use web_sys::{MessageEvent, WebSocket};
+use crate::error::DerpResult;
+use crate::nat::{NatHandle, NatStream};
+use crate::timer;
+use crate::transport;
+use crate::vm_network::NicHandle;
+
+/// How long to wait before reopening a waiting socket the relay closed or
+/// refused.
+pub const RELISTEN_DELAY_MS: i32 = 5_000;
+
+/// A guest TCP port published through a WebSocket relay, returned by
+/// `VmNetwork.publishPort`.
+///
+/// One socket to the relay is kept waiting for a client. The relay pairs it
+/// with the next outside connection and announces that with a text message,
+/// after which binary messages carry the stream both ways and closing the
+/// socket closes the connection. The bridge then opens a connection to the
+/// guest port and a fresh waiting socket. A relay that doesn't announce
+/// clients may start with the client's data instead.
+#[wasm_bindgen]
+pub struct PublishedPort {
+    listener: Rc<Listener>,
+}
+
+#[wasm_bindgen]
+impl PublishedPort {
+    /// Stops accepting clients. Connections already bridged stay open.
+    pub fn close(&self) {
+        self.listener.closed.set(true);
+        self.listener.waiting.borrow_mut().take();
+    }
+
+    /// Clients bridged to the guest so far.
+    #[wasm_bindgen(getter)]
+    pub fn accepted(&self) -> u32 {
+        self.listener.accepted.get()
+    }
+
+    /// Clients bridged to the guest right now.
+    #[wasm_bindgen(getter)]
+    pub fn active(&self) -> u32 {
+        self.listener.active.get()
+    }
+}
+
+impl PublishedPort {
+    pub fn new(url: &str, guest: SocketAddrV4, nic: NicHandle) -> DerpResult<PublishedPort> {
+        let listener = Rc::new_cyclic(|this| Listener {
+            this: this.clone(),
+            url: url.to_string(),
+            guest,
+            nic,
+            waiting: RefCell::new(None),
+            accepted: Cell::new(0),
+            active: Cell::new(0),
+            closed: Cell::new(false),
+        });
+        listener.listen()?;
+        Ok(PublishedPort { listener })
+    }
+}
+
+struct Listener {
+    this: Weak<Listener>,
+    url: String,
+    guest: SocketAddrV4,
+    nic: NicHandle,
+    /// The socket waiting for the relay to pair it with a client.
+    waiting: RefCell<Option<Bridge>>,
+    accepted: Cell<u32>,
+    active: Cell<u32>,
+    closed: Cell<bool>,
+}
+
+/// A relay socket and the guest connection it's bridged to, once paired.
+struct Bridge {
+    socket: WebSocket,
+    handle: Rc<RefCell<Option<NatHandle>>>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+/// What a message from the relay is.
+#[derive(Debug, PartialEq)]
+enum Message {
+    /// A client was paired with the socket.
+    Announce,
+    Data(Vec<u8>),
+}
+
+fn classify(data: &JsValue) -> Message {
+    match data.dyn_ref::<ArrayBuffer>() {
+        Some(buffer) => Message::Data(Uint8Array::new(buffer).to_vec()),
+        None => Message::Announce,
+    }
+}
+
+impl Listener {
+    /// Opens the next waiting socket.
+    fn listen(&self) -> DerpResult<()> {
+        let socket = transport::open_websocket(&self.url, &[], &BTreeMap::new())?;
+        let handle = Rc::new(RefCell::new(None::<NatHandle>));
+
+        let (listener, guest) = (self.this.clone(), handle.clone());
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let message = classify(&event.data());
+            let paired = guest.borrow().clone();
+            match (paired, message) {
+                (Some(handle), Message::Data(data)) => handle.send(&data),
+                (Some(_), Message::Announce) => {}
+                (None, message) => {
+                    let Some(listener) = listener.upgrade() else { return };
+                    if let Some(handle) = listener.accept() {
+                        if let Message::Data(data) = message {
+                            handle.send(&data);
+                        }
+                    }
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+
+        let (listener, guest, closed) = (self.this.clone(), handle.clone(), socket.clone());
+        let on_close = Closure::wrap(Box::new(move || {
+            // An error is followed by a close; only the first is handled
+            closed.set_onclose(None);
+            closed.set_onerror(None);
+            if let Some(handle) = guest.borrow().as_ref() {
+                handle.close();
+            } else if let Some(listener) = listener.upgrade() {
+                listener.relisten();
+            }
+        }) as Box<dyn FnMut()>);
+
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_close.as_ref().unchecked_ref()));
+        *self.waiting.borrow_mut() = Some(Bridge { socket, handle, _on_message: on_message, _on_close: on_close });
+        Ok(())
+    }
+
+    /// Bridges the waiting socket to a new guest connection and opens the
+    /// next one. Returns the connection's handle, or None if the guest
+    /// couldn't be reached, in which case the client is turned away.
+    fn accept(&self) -> Option<NatHandle> {
+        let mut bridge = Some(self.waiting.borrow_mut().take()?);
+        let mut opened = None;
+        let result = self.nic.connect(self.guest, |handle| {
+            let bridge = bridge.take().expect("connect opens one stream");
+            *bridge.handle.borrow_mut() = Some(handle.clone());
+            opened = Some(handle.clone());
+            Box::new(BridgeStream { bridge, listener: self.this.clone() })
+        });
+
+        if let Err(e) = result {
+            log::warn!("Failed to bridge a client to {}: {:?}", self.guest, e);
+            if let Some(bridge) = bridge {
+                close(&bridge.socket);
+                // Its handler is running; free it once that returns
+                wasm_bindgen_futures::spawn_local(async move { drop(bridge) });
+            }
+        } else {
+            self.accepted.set(self.accepted.get() + 1);
+            self.active.set(self.active.get() + 1);
+        }
+        if !self.closed.get() {
+            if let Err(e) = self.listen() {
+                log::warn!("Failed to reopen the relay socket: {}", e);
+                self.relisten();
+            }
+        }
+        opened
+    }
+
+    /// Replaces the waiting socket after a delay. Called from its handlers,
+    /// so it's only dropped once they've returned.
+    fn relisten(&self) {
+        let listener = self.this.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            timer::sleep(RELISTEN_DELAY_MS).await;
+            let Some(listener) = listener.upgrade() else { return };
+            listener.waiting.borrow_mut().take();
+            if listener.closed.get() {
+                return;
+            }
+            if let Err(e) = listener.listen() {
+                log::warn!("Failed to reopen the relay socket: {}", e);
+                listener.relisten();
+            }
+        });
+    }
+}
+
+/// The guest's side of a bridged client.
+struct BridgeStream {
+    bridge: Bridge,
+    listener: Weak<Listener>,
+}
+
+impl NatStream for BridgeStream {
+    fn on_data(&mut self, data: &[u8], _handle: &NatHandle) {
+        if let Err(e) = self.bridge.socket.send_with_u8_array(data) {
+            log::warn!("Failed to send to the relay: {:?}", e);
+        }
+    }
+
+    fn on_close(&mut self, _handle: &NatHandle) {
+        close(&self.bridge.socket);
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        close(&self.socket);
+    }
+}
+
+impl Drop for BridgeStream {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.upgrade() {
+            listener.active.set(listener.active.get().saturating_sub(1));
+        }
+    }
+}
+
+/// Closes a relay socket without its handlers, which are about to be freed.
+fn close(socket: &WebSocket) {
+    socket.set_onmessage(None);
+    socket.set_onclose(None);
+    socket.set_onerror(None);
+    let _ = socket.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_classify_relay_messages() {
+        let data = Uint8Array::from(&b"SSH-2.0"[..]);
+        assert_eq!(classify(&data.buffer().into()), Message::Data(b"SSH-2.0".to_vec()));
+        assert_eq!(classify(&"client 203.0.113.7:50412".into()), Message::Announce);
+    }
+}
@@ -0,0 +1,376 @@
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::error::{DerpError, DerpResult};
+
+pub const RATCHET_KEY_LEN: usize = 32;
+/// The sender's ratchet key, the length of its previous chain and the
+/// message's number in the current one.
+pub const HEADER_LEN: usize = RATCHET_KEY_LEN + 4 + 4;
+/// Messages a single header may skip past, so a forged counter can't make
+/// the receiver derive keys without end.
+pub const MAX_SKIP: u32 = 1000;
+/// Keys kept for skipped messages that may still arrive; the oldest go first.
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+const ROOT_INFO: &[u8] = b"derp-ratchet-root";
+const MESSAGE_INFO: &[u8] = b"derp-ratchet-message";
+const NONCE_LEN: usize = 12;
+
+type Key = [u8; 32];
+type SkippedId = ([u8; RATCHET_KEY_LEN], u32);
+
+/// The Double Ratchet (Signal's, with X25519, HKDF-SHA256 and AES-256-GCM)
+/// for a long-lived peer session. Every message gets its own key, and each
+/// change of speaker mixes a fresh Diffie-Hellman result into the chain, so
+/// keys taken from a compromised session can't decrypt what came before.
+///
+/// Both sides start from a secret they already share. The responder's
+/// ratchet key has to reach the initiator, which then speaks first.
+pub struct RatchetSession {
+    dh_self: StaticSecret,
+    dh_remote: Option<PublicKey>,
+    root_key: Key,
+    send_chain: Option<Key>,
+    recv_chain: Option<Key>,
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    skipped: HashMap<SkippedId, Key>,
+    skipped_order: VecDeque<SkippedId>,
+}
+
+/// What `decrypt` may change before a message has authenticated, so the
+/// session can be put back if it doesn't.
+struct Checkpoint {
+    dh_self: StaticSecret,
+    dh_remote: Option<PublicKey>,
+    root_key: Key,
+    send_chain: Option<Key>,
+    recv_chain: Option<Key>,
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    /// Skipped keys kept since, which are the newest.
+    added: usize,
+    /// Skipped keys dropped to make room, oldest first.
+    evicted: Vec<(SkippedId, Key)>,
+}
+
+impl RatchetSession {
+    /// The side that knows the other's ratchet key and sends first.
+    pub fn initiator(shared_secret: &[u8], remote_key: &[u8]) -> DerpResult<RatchetSession> {
+        let remote = PublicKey::from(parse_key(remote_key)?);
+        let dh_self = random_secret()?;
+        let (root_key, send_chain) = kdf_root(&parse_key(shared_secret)?, dh_self.diffie_hellman(&remote).as_bytes());
+        Ok(RatchetSession {
+            dh_remote: Some(remote),
+            root_key,
+            send_chain: Some(send_chain),
+            ..RatchetSession::new(dh_self, shared_secret)?
+        })
+    }
+
+    /// The side whose ratchet key, `public_key`, the initiator was given.
+    /// It can't send until the initiator's first message arrives.
+    pub fn responder(shared_secret: &[u8]) -> DerpResult<RatchetSession> {
+        RatchetSession::new(random_secret()?, shared_secret)
+    }
+
+    fn new(dh_self: StaticSecret, shared_secret: &[u8]) -> DerpResult<RatchetSession> {
+        Ok(RatchetSession {
+            dh_self,
+            dh_remote: None,
+            root_key: parse_key(shared_secret)?,
+            send_chain: None,
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        })
+    }
+
+    /// This side's current ratchet key. It changes each time this side
+    /// starts speaking after the other has.
+    pub fn public_key(&self) -> [u8; RATCHET_KEY_LEN] {
+        PublicKey::from(&self.dh_self).to_bytes()
+    }
+
+    /// Encrypts `plaintext` under the next message key, authenticating
+    /// `aad` and the header, which is sent in front of the ciphertext.
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+        let Some(chain) = self.send_chain else {
+            return Err(DerpError::InvalidState("Can't send until the initiator's first message arrives".into()));
+        };
+        let (chain, message_key) = kdf_chain(&chain);
+        self.send_chain = Some(chain);
+
+        let mut message = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        message.extend_from_slice(&self.public_key());
+        message.extend_from_slice(&self.prev_send_n.to_be_bytes());
+        message.extend_from_slice(&self.send_n.to_be_bytes());
+        self.send_n += 1;
+
+        let ciphertext = seal(&message_key, plaintext, &associated_data(aad, &message))?;
+        message.extend_from_slice(&ciphertext);
+        Ok(message)
+    }
+
+    /// Decrypts a message from `encrypt`. The session only moves on once a
+    /// message has authenticated, so forgeries and replays leave it as it was.
+    pub fn decrypt(&mut self, message: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+        if message.len() < HEADER_LEN {
+            return Err(DerpError::CryptoError("Ratchet message too short".into()));
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LEN);
+        let remote: [u8; RATCHET_KEY_LEN] = header[..RATCHET_KEY_LEN].try_into().unwrap();
+        let prev_n = u32::from_be_bytes(header[RATCHET_KEY_LEN..RATCHET_KEY_LEN + 4].try_into().unwrap());
+        let n = u32::from_be_bytes(header[RATCHET_KEY_LEN + 4..].try_into().unwrap());
+        let aad = associated_data(aad, header);
+
+        if let Some(message_key) = self.skipped.get(&(remote, n)) {
+            let plaintext = open(message_key, ciphertext, &aad)?;
+            self.skipped.remove(&(remote, n));
+            self.skipped_order.retain(|key| *key != (remote, n));
+            return Ok(plaintext);
+        }
+
+        let mut checkpoint = self.checkpoint();
+        let result = self.advance(remote, prev_n, n, &mut checkpoint)
+            .and_then(|message_key| open(&message_key, ciphertext, &aad));
+        if result.is_err() {
+            self.restore(checkpoint);
+        }
+        result
+    }
+
+    /// Moves the receiving chain on to message `n` under `remote`, returning
+    /// its key.
+    fn advance(&mut self, remote: [u8; RATCHET_KEY_LEN], prev_n: u32, n: u32, checkpoint: &mut Checkpoint) -> DerpResult<Key> {
+        if self.dh_remote.map(|key| key.to_bytes()) != Some(remote) {
+            self.skip_until(prev_n, checkpoint)?;
+            self.step(PublicKey::from(remote))?;
+        }
+        self.skip_until(n, checkpoint)?;
+        let Some(chain) = self.recv_chain else {
+            return Err(DerpError::CryptoError("Ratchet message on a chain that hasn't started".into()));
+        };
+        let (chain, message_key) = kdf_chain(&chain);
+        self.recv_chain = Some(chain);
+        self.recv_n += 1;
+        Ok(message_key)
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            dh_self: self.dh_self.clone(),
+            dh_remote: self.dh_remote,
+            root_key: self.root_key,
+            send_chain: self.send_chain,
+            recv_chain: self.recv_chain,
+            send_n: self.send_n,
+            recv_n: self.recv_n,
+            prev_send_n: self.prev_send_n,
+            added: 0,
+            evicted: Vec::new(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.dh_self = checkpoint.dh_self;
+        self.dh_remote = checkpoint.dh_remote;
+        self.root_key = checkpoint.root_key;
+        self.send_chain = checkpoint.send_chain;
+        self.recv_chain = checkpoint.recv_chain;
+        self.send_n = checkpoint.send_n;
+        self.recv_n = checkpoint.recv_n;
+        self.prev_send_n = checkpoint.prev_send_n;
+        for _ in 0..checkpoint.added {
+            if let Some(id) = self.skipped_order.pop_back() {
+                self.skipped.remove(&id);
+            }
+        }
+        for (id, message_key) in checkpoint.evicted.into_iter().rev() {
+            self.skipped_order.push_front(id);
+            self.skipped.insert(id, message_key);
+        }
+    }
+
+    /// Keeps the keys for messages of the current receiving chain before
+    /// number `until`, in case they arrive late.
+    fn skip_until(&mut self, until: u32, checkpoint: &mut Checkpoint) -> DerpResult<()> {
+        let Some(mut chain) = self.recv_chain else { return Ok(()) };
+        if until > self.recv_n.saturating_add(MAX_SKIP) {
+            return Err(DerpError::CryptoError("Ratchet message skips too far ahead".into()));
+        }
+        let remote = self.dh_remote.expect("a receiving chain has a remote key").to_bytes();
+        while self.recv_n < until {
+            let (next, message_key) = kdf_chain(&chain);
+            chain = next;
+            if self.skipped_order.len() == MAX_SKIPPED_KEYS {
+                if let Some(oldest) = self.skipped_order.pop_front() {
+                    if let Some(key) = self.skipped.remove(&oldest) {
+                        checkpoint.evicted.push((oldest, key));
+                    }
+                }
+            }
+            self.skipped.insert((remote, self.recv_n), message_key);
+            self.skipped_order.push_back((remote, self.recv_n));
+            checkpoint.added += 1;
+            self.recv_n += 1;
+        }
+        self.recv_chain = Some(chain);
+        Ok(())
+    }
+
+    /// The other side has a new ratchet key: finish receiving on the old
+    /// chain, and start new chains both ways under a new key of our own.
+    fn step(&mut self, remote: PublicKey) -> DerpResult<()> {
+        self.prev_send_n = self.send_n;
+        self.send_n = 0;
+        self.recv_n = 0;
+        self.dh_remote = Some(remote);
+
+        let (root_key, recv_chain) = kdf_root(&self.root_key, self.dh_self.diffie_hellman(&remote).as_bytes());
+        self.dh_self = random_secret()?;
+        let (root_key, send_chain) = kdf_root(&root_key, self.dh_self.diffie_hellman(&remote).as_bytes());
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+}
+
+fn parse_key(key: &[u8]) -> DerpResult<Key> {
+    key.try_into()
+        .map_err(|_| DerpError::CryptoError(format!("Ratchet keys and secrets are {} bytes", RATCHET_KEY_LEN)))
+}
+
+fn random_secret() -> DerpResult<StaticSecret> {
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret)
+        .map_err(|e| DerpError::CryptoError(format!("Failed to generate a ratchet key: {}", e)))?;
+    Ok(StaticSecret::from(secret))
+}
+
+/// Mixes a Diffie-Hellman result into the root key, giving the next root
+/// key and a chain key.
+fn kdf_root(root_key: &Key, dh_output: &[u8]) -> (Key, Key) {
+    let mut output = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_output)
+        .expand(ROOT_INFO, &mut output)
+        .expect("64 bytes is a valid HKDF-SHA256 length");
+    (output[..32].try_into().unwrap(), output[32..].try_into().unwrap())
+}
+
+/// Advances a chain, giving the next chain key and a message key.
+fn kdf_chain(chain_key: &Key) -> (Key, Key) {
+    let derive = |constant: u8| -> Key {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC takes any key length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+    (derive(2), derive(1))
+}
+
+/// The AEAD key and nonce a message key stands for. Each is used once.
+fn message_cipher(message_key: &Key) -> (Aes256Gcm, [u8; NONCE_LEN]) {
+    let mut output = [0u8; 32 + NONCE_LEN];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(MESSAGE_INFO, &mut output)
+        .expect("44 bytes is a valid HKDF-SHA256 length");
+    let cipher = Aes256Gcm::new_from_slice(&output[..32]).expect("32-byte key");
+    (cipher, output[32..].try_into().unwrap())
+}
+
+fn associated_data(aad: &[u8], header: &[u8]) -> Vec<u8> {
+    [aad, &header[..HEADER_LEN]].concat()
+}
+
+fn seal(message_key: &Key, plaintext: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|e| DerpError::CryptoError(format!("Encryption failed: {}", e)))
+}
+
+fn open(message_key: &Key, ciphertext: &[u8], aad: &[u8]) -> DerpResult<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad })
+        .map_err(|e| DerpError::CryptoError(format!("Decryption failed: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    fn pair() -> (RatchetSession, RatchetSession) {
+        let bob = RatchetSession::responder(&SECRET).unwrap();
+        let alice = RatchetSession::initiator(&SECRET, &bob.public_key()).unwrap();
+        (alice, bob)
+    }
+
+    #[wasm_bindgen_test]
+    fn test_conversation_ratchets_keys() {
+        let (mut alice, mut bob) = pair();
+        assert!(bob.encrypt(b"too early", &[]).is_err());
+
+        let first_key = bob.public_key();
+        let hello = alice.encrypt(b"hello", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&hello, b"ad").unwrap(), b"hello");
+        assert_ne!(bob.public_key(), first_key);
+
+        let reply = bob.encrypt(b"hi", b"ad").unwrap();
+        assert_eq!(&reply[..RATCHET_KEY_LEN], &bob.public_key());
+        assert_eq!(alice.decrypt(&reply, b"ad").unwrap(), b"hi");
+
+        // Alice speaks under a new key of her own, so Bob's chain moves on too
+        let alice_key = alice.public_key();
+        let again = alice.encrypt(b"again", b"ad").unwrap();
+        assert_ne!(&again[..RATCHET_KEY_LEN], &hello[..RATCHET_KEY_LEN]);
+        assert_eq!(&again[..RATCHET_KEY_LEN], &alice_key);
+        assert!(bob.decrypt(&again, b"other ad").is_err());
+        assert_eq!(bob.decrypt(&again, b"ad").unwrap(), b"again");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_out_of_order_replay_and_forgery() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<_> = (0u8..4).map(|i| alice.encrypt(&[i], &[]).unwrap()).collect();
+
+        assert_eq!(bob.decrypt(&messages[2], &[]).unwrap(), [2]);
+        assert_eq!(bob.decrypt(&messages[0], &[]).unwrap(), [0]);
+        assert!(bob.decrypt(&messages[0], &[]).is_err());
+
+        // A forgery neither decrypts nor disturbs the session
+        let mut forged = messages[3].clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(bob.decrypt(&forged, &[]).is_err());
+        assert_eq!(bob.decrypt(&messages[3], &[]).unwrap(), [3]);
+        assert_eq!(bob.decrypt(&messages[1], &[]).unwrap(), [1]);
+
+        // Nor does one that skips ahead under a new ratchet key
+        let skipped = bob.skipped.len();
+        let mut stepped = alice.encrypt(b"stepped", &[]).unwrap();
+        stepped[..RATCHET_KEY_LEN].copy_from_slice(&RatchetSession::responder(&SECRET).unwrap().public_key());
+        stepped[RATCHET_KEY_LEN..RATCHET_KEY_LEN + 4].copy_from_slice(&8u32.to_be_bytes());
+        assert!(bob.decrypt(&stepped, &[]).is_err());
+        assert_eq!(bob.skipped.len(), skipped);
+        stepped[..RATCHET_KEY_LEN].copy_from_slice(&alice.public_key());
+        stepped[RATCHET_KEY_LEN..RATCHET_KEY_LEN + 4].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(bob.decrypt(&stepped, &[]).unwrap(), b"stepped");
+
+        let mut far = alice.encrypt(b"far", &[]).unwrap();
+        far[RATCHET_KEY_LEN + 4..HEADER_LEN].copy_from_slice(&(MAX_SKIP + 10).to_be_bytes());
+        assert!(bob.decrypt(&far, &[]).is_err());
+    }
+}
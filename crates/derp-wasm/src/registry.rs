@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+pub type InstanceId = u32;
+
+struct Registry {
+    next_id: InstanceId,
+    live: BTreeSet<InstanceId>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = const { RefCell::new(Registry {
+        next_id: 1,
+        live: BTreeSet::new(),
+    }) };
+}
+
+/// Allocates a page-unique id for a new `DerpNetwork` instance.
+pub fn register() -> InstanceId {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let id = registry.next_id;
+        registry.next_id = registry.next_id.wrapping_add(1).max(1);
+        registry.live.insert(id);
+        id
+    })
+}
+
+pub fn unregister(id: InstanceId) {
+    REGISTRY.with(|registry| {
+        registry.borrow_mut().live.remove(&id);
+    });
+}
+
+pub fn live_instances() -> Vec<InstanceId> {
+    REGISTRY.with(|registry| registry.borrow().live.iter().copied().collect())
+}
+
+/// Locally administered unicast MAC unique to an instance, used as the
+/// virtual gateway address so bridged VMs never share one.
+pub fn gateway_mac(id: InstanceId) -> [u8; 6] {
+    let id = id.to_be_bytes();
+    [0x02, 0x86, id[0], id[1], id[2], id[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_register_unique_ids() {
+        let a = register();
+        let b = register();
+        assert_ne!(a, b);
+        assert!(live_instances().contains(&a));
+
+        unregister(a);
+        assert!(!live_instances().contains(&a));
+        assert!(live_instances().contains(&b));
+        unregister(b);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_gateway_macs_differ() {
+        assert_ne!(gateway_mac(1), gateway_mac(2));
+        // Locally administered, unicast
+        assert_eq!(gateway_mac(1)[0] & 0x03, 0x02);
+    }
+}
@@ -0,0 +1,199 @@
+use wasm_bindgen::prelude::*;
+use js_sys::{Atomics, Int32Array, Reflect, SharedArrayBuffer, Uint8Array};
+use crate::error::{DerpError, DerpResult};
+
+// Layout: two Int32 indices followed by the data region. Both indices count
+// bytes ever written/read and wrap at 2^32; positions are taken modulo the
+// power-of-two capacity. Each record is a little-endian u16 length followed
+// by the frame. src/browser/derp_shared_ring.js implements the same layout.
+const HEAD: u32 = 0;
+const TAIL: u32 = 1;
+const HEADER_LEN: u32 = 8;
+const LEN_PREFIX: u32 = 2;
+
+/// Whether the page is cross-origin isolated, which browsers require before
+/// exposing `SharedArrayBuffer`.
+pub fn shared_memory_available() -> bool {
+    Reflect::get(&js_sys::global(), &"crossOriginIsolated".into())
+        .map(|isolated| isolated.is_truthy())
+        .unwrap_or(false)
+}
+
+/// Single-producer, single-consumer packet queue in a `SharedArrayBuffer`.
+/// The producer only moves `HEAD` and the consumer only moves `TAIL`, so
+/// each side can run on its own thread.
+pub struct SharedRing {
+    buffer: SharedArrayBuffer,
+    indices: Int32Array,
+    data: Uint8Array,
+    capacity: u32,
+}
+
+impl SharedRing {
+    /// Allocates a ring with room for `capacity` bytes of records.
+    pub fn new(capacity: u32) -> DerpResult<SharedRing> {
+        if !shared_memory_available() {
+            return Err(DerpError::InvalidState("SharedArrayBuffer requires cross-origin isolation".into()));
+        }
+        if !capacity.is_power_of_two() || capacity <= LEN_PREFIX {
+            return Err(DerpError::InvalidState("Ring capacity must be a power of two".into()));
+        }
+        SharedRing::from_buffer(SharedArrayBuffer::new(HEADER_LEN + capacity))
+    }
+
+    /// Wraps a ring allocated elsewhere, e.g. by the emulator side.
+    pub fn from_buffer(buffer: SharedArrayBuffer) -> DerpResult<SharedRing> {
+        let capacity = buffer.byte_length().saturating_sub(HEADER_LEN);
+        if !capacity.is_power_of_two() || capacity <= LEN_PREFIX {
+            return Err(DerpError::InvalidState("Ring capacity must be a power of two".into()));
+        }
+
+        Ok(SharedRing {
+            indices: Int32Array::new_with_byte_offset_and_length(&buffer, 0, 2),
+            data: Uint8Array::new_with_byte_offset(&buffer, HEADER_LEN),
+            buffer,
+            capacity,
+        })
+    }
+
+    pub fn buffer(&self) -> SharedArrayBuffer {
+        self.buffer.clone()
+    }
+
+    /// Appends one record. Returns false if the ring doesn't have room.
+    pub fn push(&self, record: &[u8]) -> DerpResult<bool> {
+        let len = u16::try_from(record.len())
+            .map_err(|_| DerpError::InvalidState("Record too large for ring".into()))?;
+
+        let head = self.load(HEAD)?;
+        let tail = self.load(TAIL)?;
+        let needed = LEN_PREFIX + len as u32;
+        if needed > self.capacity - head.wrapping_sub(tail) {
+            return Ok(false);
+        }
+
+        self.write_at(head, &len.to_le_bytes());
+        self.write_at(head.wrapping_add(LEN_PREFIX), record);
+        self.store(HEAD, head.wrapping_add(needed))?;
+        Ok(true)
+    }
+
+    /// Moves the oldest record into `out`. Returns false if the ring is empty.
+    pub fn pop(&self, out: &mut Vec<u8>) -> DerpResult<bool> {
+        let head = self.load(HEAD)?;
+        let tail = self.load(TAIL)?;
+        if head == tail {
+            return Ok(false);
+        }
+
+        let mut prefix = [0u8; LEN_PREFIX as usize];
+        self.read_at(tail, &mut prefix);
+        let len = u16::from_le_bytes(prefix) as u32;
+        if LEN_PREFIX + len > head.wrapping_sub(tail) {
+            return Err(DerpError::InvalidProtocol("Corrupt ring record".into()));
+        }
+
+        out.resize(len as usize, 0);
+        self.read_at(tail.wrapping_add(LEN_PREFIX), out);
+        self.store(TAIL, tail.wrapping_add(LEN_PREFIX + len))?;
+        Ok(true)
+    }
+
+    fn load(&self, index: u32) -> DerpResult<u32> {
+        Atomics::load(&self.indices, index)
+            .map(|value| value as u32)
+            .map_err(|_| DerpError::InvalidState("Ring index unavailable".into()))
+    }
+
+    /// Publishes an index and wakes a peer blocked in `Atomics.wait` on it.
+    fn store(&self, index: u32, value: u32) -> DerpResult<()> {
+        Atomics::store(&self.indices, index, value as i32)
+            .and_then(|_| Atomics::notify(&self.indices, index))
+            .map(|_| ())
+            .map_err(|_| DerpError::InvalidState("Ring index unavailable".into()))
+    }
+
+    // Byte by byte through the one view: `subarray` and `copy_from` would
+    // each make a JS object per record.
+    fn write_at(&self, position: u32, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.data.set_index(position.wrapping_add(offset as u32) % self.capacity, byte);
+        }
+    }
+
+    fn read_at(&self, position: u32, out: &mut [u8]) {
+        for (offset, byte) in out.iter_mut().enumerate() {
+            *byte = self.data.get_index(position.wrapping_add(offset as u32) % self.capacity);
+        }
+    }
+}
+
+/// The pair of rings returned by `VmNetwork.enableSharedRings`.
+#[wasm_bindgen]
+pub struct SharedRings {
+    to_vm: SharedArrayBuffer,
+    from_vm: SharedArrayBuffer,
+}
+
+#[wasm_bindgen]
+impl SharedRings {
+    /// Frames for the emulator to consume.
+    #[wasm_bindgen(getter, js_name = toVm)]
+    pub fn to_vm(&self) -> SharedArrayBuffer {
+        self.to_vm.clone()
+    }
+
+    /// Frames the emulator produces; call `VmNetwork.pollSharedRing` after writing.
+    #[wasm_bindgen(getter, js_name = fromVm)]
+    pub fn from_vm(&self) -> SharedArrayBuffer {
+        self.from_vm.clone()
+    }
+}
+
+impl SharedRings {
+    pub fn new(to_vm: &SharedRing, from_vm: &SharedRing) -> SharedRings {
+        SharedRings {
+            to_vm: to_vm.buffer(),
+            from_vm: from_vm.buffer(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_push_pop_wraps() {
+        if !shared_memory_available() {
+            return;
+        }
+
+        let ring = SharedRing::new(16).unwrap();
+        let mut out = Vec::new();
+
+        // 2 + 9 bytes each; the second record straddles the end of the ring
+        for round in 0..4u8 {
+            let record = [round; 9];
+            assert!(ring.push(&record).unwrap());
+            assert!(ring.pop(&mut out).unwrap());
+            assert_eq!(out, record);
+        }
+        assert!(!ring.pop(&mut out).unwrap());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_push_when_full() {
+        if !shared_memory_available() {
+            return;
+        }
+
+        let ring = SharedRing::new(16).unwrap();
+        assert!(ring.push(&[1; 10]).unwrap());
+        assert!(!ring.push(&[2; 10]).unwrap());
+        assert!(SharedRing::new(24).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use tsify::Tsify;
+use crate::error::{DerpError, DerpResult};
+
+/// Frames arriving behind more than this much queued transmission time are
+/// dropped, as a router with a full buffer would.
+const MAX_BACKLOG_MS: f64 = 1000.0;
+
+/// One direction of an emulated link. Every field is optional; the default
+/// profile passes frames straight through.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LinkProfile {
+    /// Bandwidth in kilobits per second. Unset means unlimited.
+    #[tsify(optional)]
+    pub rate_kbps: Option<u32>,
+    /// Added one-way delay.
+    #[tsify(optional)]
+    pub latency_ms: f64,
+    /// The delay varies uniformly by up to this much either side of
+    /// `latencyMs`. Frames are never reordered.
+    #[tsify(optional)]
+    pub jitter_ms: f64,
+    /// Share of frames dropped at random, from 0 to 100.
+    #[tsify(optional)]
+    pub loss_percent: f64,
+    /// With `rateKbps` unset, limits the rate to the relay link's
+    /// `estimatedBandwidth` once there is one. On egress, the guest's TCP
+    /// then backs off at the real bottleneck instead of filling the
+    /// socket's buffer.
+    #[tsify(optional)]
+    pub match_relay: bool,
+}
+
+/// Link emulation for a `VmNetwork`. Unset directions aren't shaped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(default, rename_all = "camelCase")]
+#[tsify(from_wasm_abi)]
+pub struct ShapingConfig {
+    /// Frames the guest sends.
+    #[tsify(optional)]
+    pub egress: Option<LinkProfile>,
+    /// Frames delivered to the guest.
+    #[tsify(optional)]
+    pub ingress: Option<LinkProfile>,
+}
+
+/// Paces the frames of one direction: each occupies the link for its
+/// serialization time at the configured rate, then arrives after the
+/// latency. Frames waiting to arrive are held here.
+pub struct Link {
+    profile: LinkProfile,
+    /// The relay's estimated rate, for `matchRelay`.
+    relay_kbps: Option<u32>,
+    /// When the frames admitted so far have all been put on the wire.
+    busy_until: f64,
+    last_release: f64,
+    queue: VecDeque<(f64, Vec<u8>)>,
+    /// Pending `setTimeout` that releases the head of the queue.
+    timer: Option<i32>,
+}
+
+impl Link {
+    pub fn new(profile: LinkProfile) -> DerpResult<Link> {
+        let valid = profile.rate_kbps != Some(0)
+            && profile.latency_ms.is_finite() && profile.latency_ms >= 0.0
+            && profile.jitter_ms.is_finite() && profile.jitter_ms >= 0.0
+            && (0.0..=100.0).contains(&profile.loss_percent);
+        if !valid {
+            return Err(DerpError::InvalidState(format!("Invalid link profile: {:?}", profile)));
+        }
+
+        Ok(Link {
+            profile,
+            relay_kbps: None,
+            busy_until: 0.0,
+            last_release: 0.0,
+            queue: VecDeque::new(),
+            timer: None,
+        })
+    }
+
+    pub fn profile(&self) -> &LinkProfile {
+        &self.profile
+    }
+
+    /// Whether the rate follows the relay's, so needs `set_relay_kbps`.
+    pub fn matches_relay(&self) -> bool {
+        self.profile.match_relay && self.profile.rate_kbps.is_none()
+    }
+
+    pub fn set_relay_kbps(&mut self, kbps: Option<u32>) {
+        self.relay_kbps = kbps;
+    }
+
+    /// Decides when a frame of `len` bytes sent at `now` arrives, or None if
+    /// it's lost. `random` returns values in [0, 1).
+    pub fn admit(&mut self, len: usize, now: f64, mut random: impl FnMut() -> f64) -> Option<f64> {
+        if self.profile.loss_percent > 0.0 && random() * 100.0 < self.profile.loss_percent {
+            return None;
+        }
+
+        let start = self.busy_until.max(now);
+        if start - now > MAX_BACKLOG_MS {
+            return None;
+        }
+        let rate = match self.profile.match_relay {
+            true => self.profile.rate_kbps.or(self.relay_kbps),
+            false => self.profile.rate_kbps,
+        };
+        let serialization = match rate {
+            Some(rate) => len as f64 * 8.0 / rate as f64,
+            None => 0.0,
+        };
+        self.busy_until = start + serialization;
+
+        let jitter = self.profile.jitter_ms * (2.0 * random() - 1.0);
+        let delay = (self.profile.latency_ms + jitter).max(0.0);
+        self.last_release = (self.busy_until + delay).max(self.last_release);
+        Some(self.last_release)
+    }
+
+    /// Whether no frames are waiting, so one due now can skip the queue.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, at: f64, frame: Vec<u8>) {
+        self.queue.push_back((at, frame));
+    }
+
+    /// The next frame due by `now`.
+    pub fn pop_due(&mut self, now: f64) -> Option<Vec<u8>> {
+        match self.queue.front() {
+            Some((at, _)) if *at <= now => self.queue.pop_front().map(|(_, frame)| frame),
+            _ => None,
+        }
+    }
+
+    pub fn next_due(&self) -> Option<f64> {
+        self.queue.front().map(|(at, _)| *at)
+    }
+
+    pub fn set_timer(&mut self, handle: Option<i32>) {
+        self.timer = handle;
+    }
+
+    pub fn take_timer(&mut self) -> Option<i32> {
+        self.timer.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn link(profile: LinkProfile) -> Link {
+        Link::new(profile).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_rate_and_latency() {
+        // 1000 bytes take 8ms at 1 Mbit/s
+        let mut link = link(LinkProfile { rate_kbps: Some(1000), latency_ms: 20.0, ..LinkProfile::default() });
+        assert_eq!(link.admit(1000, 0.0, || 0.5), Some(28.0));
+        assert_eq!(link.admit(1000, 1.0, || 0.5), Some(36.0));
+        // An idle link starts afresh
+        assert_eq!(link.admit(1000, 100.0, || 0.5), Some(128.0));
+
+        // Jitter never lets a frame overtake the one before it
+        let mut link = self::link(LinkProfile { latency_ms: 20.0, jitter_ms: 10.0, ..LinkProfile::default() });
+        assert_eq!(link.admit(100, 0.0, || 1.0), Some(30.0));
+        assert_eq!(link.admit(100, 1.0, || 0.0), Some(30.0));
+
+        link.push(30.0, vec![1]);
+        link.push(30.0, vec![2]);
+        assert_eq!(link.pop_due(29.0), None);
+        assert_eq!(link.pop_due(30.0), Some(vec![1]));
+        assert_eq!(link.next_due(), Some(30.0));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_loss_and_backlog() {
+        let mut link = link(LinkProfile { loss_percent: 25.0, ..LinkProfile::default() });
+        assert_eq!(link.admit(100, 0.0, || 0.2), None);
+        assert_eq!(link.admit(100, 0.0, || 0.3), Some(0.0));
+
+        // 8 kbit/s: each 1000-byte frame occupies the link for a second
+        let mut link = self::link(LinkProfile { rate_kbps: Some(8), ..LinkProfile::default() });
+        assert_eq!(link.admit(1000, 0.0, || 0.5), Some(1000.0));
+        assert_eq!(link.admit(1000, 0.0, || 0.5), Some(2000.0));
+        assert_eq!(link.admit(1000, 0.0, || 0.5), None);
+
+        // Following the relay, once its rate is known
+        let mut link = self::link(LinkProfile { match_relay: true, ..LinkProfile::default() });
+        assert!(link.matches_relay());
+        assert_eq!(link.admit(1000, 0.0, || 0.5), Some(0.0));
+        link.set_relay_kbps(Some(8));
+        assert_eq!(link.admit(1000, 0.0, || 0.5), Some(1000.0));
+
+        assert!(Link::new(LinkProfile { rate_kbps: Some(0), ..LinkProfile::default() }).is_err());
+        assert!(Link::new(LinkProfile { loss_percent: 101.0, ..LinkProfile::default() }).is_err());
+    }
+}
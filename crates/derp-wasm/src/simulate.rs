@@ -0,0 +1,260 @@
+use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use crate::error::{DerpError, DerpResult};
+use crate::timer;
+use crate::transport::{Receiver, Transport};
+
+/// A message picked for reordering is held back at least this long, so
+/// the ones after it get ahead even without configured latency.
+const REORDER_HOLD_MS: f64 = 10.0;
+
+/// Impairments applied to relay traffic in both directions, for testing how
+/// the network copes with a bad connection. Every field is optional; the
+/// default passes messages through untouched.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Tsify)]
+#[serde(default, rename_all = "camelCase")]
+#[tsify(from_wasm_abi)]
+pub struct NetworkConditions {
+    /// Added one-way delay.
+    #[tsify(optional)]
+    pub latency_ms: f64,
+    /// The delay varies uniformly by up to this much either side of
+    /// `latencyMs`, without reordering messages.
+    #[tsify(optional)]
+    pub jitter_ms: f64,
+    /// Each of these is a share of messages, from 0 to 100.
+    #[tsify(optional)]
+    pub loss_percent: f64,
+    #[tsify(optional)]
+    pub duplicate_percent: f64,
+    /// Messages held back so that later ones overtake them.
+    #[tsify(optional)]
+    pub reorder_percent: f64,
+    /// Makes the sequence of random decisions repeatable.
+    #[tsify(optional)]
+    pub seed: Option<u32>,
+}
+
+impl NetworkConditions {
+    pub fn validate(&self) -> DerpResult<()> {
+        let delays = [self.latency_ms, self.jitter_ms];
+        let shares = [self.loss_percent, self.duplicate_percent, self.reorder_percent];
+        if delays.iter().all(|delay| delay.is_finite() && *delay >= 0.0)
+            && shares.iter().all(|share| (0.0..=100.0).contains(share))
+        {
+            Ok(())
+        } else {
+            Err(DerpError::InvalidState(format!("Invalid network conditions: {:?}", self)))
+        }
+    }
+}
+
+/// Decides the fate of each message under the current conditions. Shared by
+/// every transport a `NetworkState` opens, so the conditions outlive
+/// reconnects.
+#[derive(Default)]
+pub struct Simulation {
+    conditions: Option<NetworkConditions>,
+    rng: u64,
+    /// When the last message that wasn't reordered arrives, per direction.
+    last_sent_at: f64,
+    last_received_at: f64,
+    /// Bumped on close, so messages still in flight are dropped.
+    generation: u64,
+}
+
+impl Simulation {
+    /// None restores an unimpaired connection.
+    pub fn set_conditions(&mut self, conditions: Option<NetworkConditions>) -> DerpResult<()> {
+        if let Some(conditions) = &conditions {
+            conditions.validate()?;
+            let seed = conditions.seed.map_or_else(|| js_sys::Math::random() * u32::MAX as f64, f64::from);
+            // xorshift never leaves zero
+            self.rng = (seed as u64) << 32 | 0x9E37_79B9;
+        }
+        self.conditions = conditions;
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.conditions.is_some()
+    }
+
+    /// When each copy of a message passed at `now` arrives: none if it's
+    /// lost, two if it's duplicated.
+    pub fn plan(&mut self, inbound: bool, now: f64) -> Vec<f64> {
+        let Some(conditions) = self.conditions.clone() else { return vec![now] };
+        if self.chance(conditions.loss_percent) {
+            return Vec::new();
+        }
+
+        let copies = if self.chance(conditions.duplicate_percent) { 2 } else { 1 };
+        (0..copies).map(|_| {
+            let jitter = conditions.jitter_ms * (2.0 * self.random() - 1.0);
+            let at = now + (conditions.latency_ms + jitter).max(0.0);
+            if self.chance(conditions.reorder_percent) {
+                return at + REORDER_HOLD_MS.max(conditions.latency_ms);
+            }
+            let last = if inbound { &mut self.last_received_at } else { &mut self.last_sent_at };
+            *last = at.max(*last);
+            *last
+        }).collect()
+    }
+
+    fn chance(&mut self, percent: f64) -> bool {
+        percent > 0.0 && self.random() * 100.0 < percent
+    }
+
+    /// Uniform in [0, 1).
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Sits between `NetworkState` and the socket, putting messages in both
+/// directions through the shared `Simulation`.
+#[derive(Clone)]
+pub struct SimulatedTransport {
+    inner: Box<Transport>,
+    simulation: Rc<RefCell<Simulation>>,
+}
+
+impl SimulatedTransport {
+    pub fn new(inner: Transport, simulation: Rc<RefCell<Simulation>>) -> Self {
+        SimulatedTransport { inner: Box::new(inner), simulation }
+    }
+
+    pub fn inner(&self) -> &Transport {
+        &self.inner
+    }
+
+    pub fn send(&self, message: &[u8]) -> DerpResult<()> {
+        if !self.simulation.borrow().is_active() {
+            return self.inner.send(message);
+        }
+
+        let inner = self.inner.clone();
+        schedule(&self.simulation, false, message, move |message: &[u8]| {
+            // Failures surface as the socket closing, as for a real network
+            let _ = inner.send(message);
+        });
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        self.simulation.borrow_mut().generation += 1;
+        self.inner.close();
+    }
+
+    /// Wraps `receiver` so inbound messages go through `simulation` too.
+    pub fn receiver(simulation: Rc<RefCell<Simulation>>, receiver: Receiver) -> Receiver {
+        Rc::new(move |message: &[u8]| {
+            if !simulation.borrow().is_active() {
+                return receiver(message);
+            }
+            let receiver = receiver.clone();
+            schedule(&simulation, true, message, move |message: &[u8]| receiver(message));
+        })
+    }
+}
+
+/// Hands each planned copy of `message` to `deliver` when it's due, right
+/// away if that's now.
+fn schedule(simulation: &Rc<RefCell<Simulation>>, inbound: bool, message: &[u8], deliver: impl Fn(&[u8]) + 'static) {
+    let now = js_sys::Date::now();
+    let (plan, generation) = {
+        let mut simulation = simulation.borrow_mut();
+        (simulation.plan(inbound, now), simulation.generation)
+    };
+
+    let deliver = Rc::new(deliver);
+    for at in plan {
+        if at <= now {
+            deliver(message);
+            continue;
+        }
+        let simulation = simulation.clone();
+        let deliver = deliver.clone();
+        let message = message.to_vec();
+        let callback = Closure::once_into_js(move || {
+            if simulation.borrow().generation == generation {
+                deliver(&message);
+            }
+        });
+        timer::set_timeout(callback.unchecked_ref(), (at - now).ceil() as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoState;
+    use crate::events::EventKind;
+    use crate::network::NetworkState;
+    use crate::transport::Loopback;
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn simulation(conditions: NetworkConditions) -> Simulation {
+        let mut simulation = Simulation::default();
+        simulation.set_conditions(Some(NetworkConditions { seed: Some(7), ..conditions })).unwrap();
+        simulation
+    }
+
+    #[wasm_bindgen_test]
+    fn test_plans_follow_conditions() {
+        assert_eq!(Simulation::default().plan(false, 5.0), vec![5.0]);
+        assert!(simulation(NetworkConditions { loss_percent: 100.0, ..Default::default() }).plan(false, 0.0).is_empty());
+        assert_eq!(simulation(NetworkConditions { duplicate_percent: 100.0, ..Default::default() }).plan(true, 0.0), vec![0.0, 0.0]);
+
+        // Jitter alone never reorders
+        let mut jittery = simulation(NetworkConditions { latency_ms: 20.0, jitter_ms: 15.0, ..Default::default() });
+        let times: Vec<f64> = (0..50).flat_map(|i| jittery.plan(false, i as f64)).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        let mut reordering = simulation(NetworkConditions { reorder_percent: 100.0, ..Default::default() });
+        assert_eq!(reordering.plan(false, 0.0), vec![REORDER_HOLD_MS]);
+
+        // The same seed gives the same decisions
+        let conditions = NetworkConditions { latency_ms: 5.0, jitter_ms: 5.0, loss_percent: 30.0, ..Default::default() };
+        let (mut first, mut second) = (simulation(conditions.clone()), simulation(conditions));
+        let plans = |simulation: &mut Simulation| (0..20).map(|i| simulation.plan(false, i as f64)).collect::<Vec<_>>();
+        assert_eq!(plans(&mut first), plans(&mut second));
+
+        assert!(Simulation::default().set_conditions(Some(NetworkConditions { loss_percent: 150.0, ..Default::default() })).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_duplicates_reach_the_peer() {
+        let settle = || JsFuture::from(js_sys::Promise::new(&mut |resolve, _| {
+            timer::set_timeout(&resolve, 0);
+        }));
+
+        let mut network = NetworkState::new(Arc::new(CryptoState::new().unwrap()));
+        let mut packets = network.events().subscribe(EventKind::Packet, 4);
+        network.connect_loopback(Loopback::new()).unwrap();
+        settle().await.unwrap();
+
+        let conditions = NetworkConditions { duplicate_percent: 100.0, seed: Some(1), ..Default::default() };
+        network.simulate_conditions(Some(conditions)).unwrap();
+        network.send_packet(b"twice").unwrap();
+        settle().await.unwrap();
+
+        // Duplicated on the way out and again on the way back
+        for _ in 0..4 {
+            assert!(packets.next().await.is_some());
+        }
+        assert_eq!(network.get_stats().packets_received, 4);
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use std::net::Ipv4Addr;
+use crate::error::{DerpError, DerpResult};
+use crate::firewall::{FirewallConfig, FirewallHits};
+use crate::network::NetworkStats;
+use crate::shape::ShapingConfig;
+
+/// Leads every snapshot so one from an incompatible build is refused
+/// instead of misread.
+const SNAPSHOT_VERSION: u8 = 4;
+
+/// What `DerpNetwork.serializeState` keeps. The relay session itself, with
+/// its peers and crypto keys, is never written: a snapshot may be stored
+/// anywhere, and a restored instance handshakes afresh when it connects.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NetworkSnapshot {
+    pub stats: NetworkStats,
+    pub draining: bool,
+}
+
+/// What `VmNetwork.serializeState` keeps. Open NAT connections can't
+/// outlive the page that held their far ends, and TFTP files are the
+/// page's to provide again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NicSnapshot {
+    pub mac_address: [u8; 6],
+    pub promiscuous: bool,
+    pub arp_table: Vec<(Ipv4Addr, [u8; 6])>,
+    pub firewall: FirewallConfig,
+    pub firewall_hits: FirewallHits,
+    pub shaping: ShapingConfig,
+    pub boot_file: Option<String>,
+}
+
+pub fn encode<T: Serialize>(snapshot: &T) -> DerpResult<Vec<u8>> {
+    let mut bytes = vec![SNAPSHOT_VERSION];
+    bytes.extend_from_slice(&bincode::serialize(snapshot)?);
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> DerpResult<T> {
+    match bytes.split_first() {
+        Some((&SNAPSHOT_VERSION, state)) => Ok(bincode::deserialize(state)?),
+        Some((version, _)) => Err(DerpError::InvalidState(format!("Unsupported snapshot version: {}", version))),
+        None => Err(DerpError::InvalidState("Empty snapshot".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::{Action, FirewallRule};
+    use crate::shape::LinkProfile;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_nic_snapshot_roundtrip() {
+        let snapshot = NicSnapshot {
+            mac_address: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            promiscuous: true,
+            arp_table: vec![(Ipv4Addr::new(192, 168, 86, 100), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56])],
+            firewall: FirewallConfig {
+                default_action: Action::Deny,
+                rules: vec![FirewallRule {
+                    action: Action::Allow,
+                    direction: None,
+                    ethertype: None,
+                    remote: Some("10.0.0.0/8".into()),
+                    protocol: None,
+                    ports: Some((53, 53)),
+                }],
+            },
+            firewall_hits: FirewallHits { rules: vec![3], default_action: 1 },
+            shaping: ShapingConfig {
+                egress: Some(LinkProfile { rate_kbps: Some(256), latency_ms: 40.0, ..LinkProfile::default() }),
+                ingress: None,
+            },
+            boot_file: Some("pxelinux.0".into()),
+        };
+
+        let restored: NicSnapshot = decode(&encode(&snapshot).unwrap()).unwrap();
+        assert_eq!(restored.arp_table, snapshot.arp_table);
+        assert_eq!(restored.firewall, snapshot.firewall);
+        assert_eq!(restored.firewall_hits.rules, vec![3]);
+        assert_eq!(restored.shaping, snapshot.shaping);
+        assert_eq!(restored.boot_file, snapshot.boot_file);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_other_versions_are_refused() {
+        let snapshot = NetworkSnapshot { stats: NetworkStats::default(), draining: true };
+        let mut bytes = encode(&snapshot).unwrap();
+        assert!(decode::<NetworkSnapshot>(&bytes).unwrap().draining);
+
+        bytes[0] = SNAPSHOT_VERSION + 1;
+        assert!(decode::<NetworkSnapshot>(&bytes).is_err());
+        assert!(decode::<NetworkSnapshot>(&[]).is_err());
+        assert!(decode::<NetworkSnapshot>(&[SNAPSHOT_VERSION, 1]).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{ArrayBuffer, Uint8Array};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use web_sys::{MessageEvent, WebSocket};
+use crate::error::{DerpError, DerpResult};
+use crate::flow::FlowKey;
+use crate::ip::PROTO_TCP;
+use crate::nat::{Egress, NatBackend, NatHandle, NatStream};
+use crate::transport;
+
+pub use crate::config::SocksConfig;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+
+/// Tunnels every guest TCP connection it's offered through the proxy. UDP
+/// is left to the next backend.
+pub struct SocksBackend {
+    config: SocksConfig,
+}
+
+impl SocksBackend {
+    pub fn new(config: SocksConfig) -> Self {
+        SocksBackend { config }
+    }
+}
+
+impl NatBackend for SocksBackend {
+    fn open(&self, flow: &FlowKey) -> Option<Egress> {
+        if flow.protocol != PROTO_TCP {
+            return None;
+        }
+        let target = SocketAddrV4::new(Ipv4Addr::from(flow.dst), flow.dst_port);
+        Some(Egress::Stream(Box::new(SocksStream {
+            url: self.config.url.clone(),
+            handshake: Some(Handshake::new(target, self.config.credentials())),
+            tunnel: None,
+        })))
+    }
+}
+
+/// What the proxy's latest bytes called for.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// Needs more bytes from the proxy.
+    Pending,
+    /// Send this to the proxy.
+    Reply(Vec<u8>),
+    /// The tunnel is open; anything the proxy sent past its reply is
+    /// already the far end's data, for the guest.
+    Open(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Method,
+    Auth,
+    Connect,
+    Open,
+}
+
+/// The client side of a SOCKS5 CONNECT, fed the proxy's bytes as they come.
+pub struct Handshake {
+    target: SocketAddrV4,
+    credentials: Option<(String, String)>,
+    stage: Stage,
+    buffer: Vec<u8>,
+}
+
+impl Handshake {
+    pub fn new(target: SocketAddrV4, credentials: Option<(String, String)>) -> Self {
+        Handshake { target, credentials, stage: Stage::Method, buffer: Vec::new() }
+    }
+
+    /// The greeting, offering password authentication when there are
+    /// credentials.
+    pub fn greeting(&self) -> Vec<u8> {
+        match self.credentials {
+            Some(_) => vec![SOCKS_VERSION, 2, METHOD_NONE, METHOD_PASSWORD],
+            None => vec![SOCKS_VERSION, 1, METHOD_NONE],
+        }
+    }
+
+    pub fn receive(&mut self, data: &[u8]) -> DerpResult<Step> {
+        self.buffer.extend_from_slice(data);
+        match self.stage {
+            Stage::Method => {
+                let Some(reply) = self.take(2) else { return Ok(Step::Pending) };
+                match (reply[0], reply[1], &self.credentials) {
+                    (SOCKS_VERSION, METHOD_NONE, _) => Ok(self.connect()),
+                    (SOCKS_VERSION, METHOD_PASSWORD, Some((username, password))) => {
+                        let mut request = vec![AUTH_VERSION, username.len() as u8];
+                        request.extend_from_slice(username.as_bytes());
+                        request.push(password.len() as u8);
+                        request.extend_from_slice(password.as_bytes());
+                        self.stage = Stage::Auth;
+                        Ok(Step::Reply(request))
+                    }
+                    (SOCKS_VERSION, METHOD_UNACCEPTABLE, _) => {
+                        Err(DerpError::AuthRejected("SOCKS proxy accepts none of our methods".into()))
+                    }
+                    _ => Err(DerpError::InvalidProtocol("Not a SOCKS5 proxy".into())),
+                }
+            }
+            Stage::Auth => {
+                let Some(reply) = self.take(2) else { return Ok(Step::Pending) };
+                if reply[1] != 0 {
+                    return Err(DerpError::AuthRejected("SOCKS proxy refused our credentials".into()));
+                }
+                Ok(self.connect())
+            }
+            Stage::Connect => {
+                let Some(&atyp) = self.buffer.get(3) else { return Ok(Step::Pending) };
+                let address_len = match atyp {
+                    ATYP_IPV4 => 4,
+                    ATYP_IPV6 => 16,
+                    ATYP_DOMAIN => match self.buffer.get(4) {
+                        Some(&len) => 1 + len as usize,
+                        None => return Ok(Step::Pending),
+                    },
+                    _ => return Err(DerpError::InvalidProtocol("Unknown SOCKS address type".into())),
+                };
+                let Some(reply) = self.take(4 + address_len + 2) else { return Ok(Step::Pending) };
+                if reply[0] != SOCKS_VERSION {
+                    return Err(DerpError::InvalidProtocol("Not a SOCKS5 proxy".into()));
+                }
+                if reply[1] != REPLY_SUCCEEDED {
+                    return Err(DerpError::WebSocketError(format!("SOCKS connect to {} failed: {}", self.target, reply_text(reply[1]))));
+                }
+                self.stage = Stage::Open;
+                Ok(Step::Open(std::mem::take(&mut self.buffer)))
+            }
+            Stage::Open => Ok(Step::Open(std::mem::take(&mut self.buffer))),
+        }
+    }
+
+    fn connect(&mut self) -> Step {
+        self.stage = Stage::Connect;
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0, ATYP_IPV4];
+        request.extend_from_slice(&self.target.ip().octets());
+        request.extend_from_slice(&self.target.port().to_be_bytes());
+        Step::Reply(request)
+    }
+
+    /// The first `len` buffered bytes, once there are that many.
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        (self.buffer.len() >= len).then(|| self.buffer.drain(..len).collect())
+    }
+}
+
+fn reply_text(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// One guest connection. Its data is held until the proxy has connected,
+/// then passed through both ways. WebSockets can't half-close, so the
+/// guest closing its side ends the tunnel.
+struct SocksStream {
+    url: String,
+    /// Taken when the bridge is opened.
+    handshake: Option<Handshake>,
+    tunnel: Option<Tunnel>,
+}
+
+struct Tunnel {
+    socket: WebSocket,
+    state: Rc<RefCell<TunnelState>>,
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+struct TunnelState {
+    handshake: Handshake,
+    open: bool,
+    /// Guest data that arrived before the tunnel opened.
+    pending: Vec<u8>,
+}
+
+impl NatStream for SocksStream {
+    fn on_open(&mut self, handle: &NatHandle) {
+        let Some(handshake) = self.handshake.take() else { return };
+        match open_tunnel(&self.url, handshake, handle) {
+            Ok(tunnel) => self.tunnel = Some(tunnel),
+            Err(e) => {
+                log::warn!("Failed to open the SOCKS bridge: {}", e);
+                handle.close();
+            }
+        }
+    }
+
+    fn on_data(&mut self, data: &[u8], _handle: &NatHandle) {
+        let Some(tunnel) = self.tunnel.as_ref() else { return };
+        let mut state = tunnel.state.borrow_mut();
+        if !state.open {
+            state.pending.extend_from_slice(data);
+        } else if let Err(e) = tunnel.socket.send_with_u8_array(data) {
+            log::warn!("Failed to send to the SOCKS bridge: {:?}", e);
+        }
+    }
+
+    fn on_close(&mut self, _handle: &NatHandle) {
+        if let Some(tunnel) = self.tunnel.take() {
+            close(&tunnel.socket);
+        }
+    }
+}
+
+impl Drop for SocksStream {
+    fn drop(&mut self) {
+        if let Some(tunnel) = self.tunnel.take() {
+            close(&tunnel.socket);
+        }
+    }
+}
+
+/// Opens the bridge and drives the handshake from its callbacks.
+fn open_tunnel(url: &str, handshake: Handshake, handle: &NatHandle) -> DerpResult<Tunnel> {
+    let socket = transport::open_websocket(url, &[], &BTreeMap::new())?;
+    let greeting = handshake.greeting();
+    let state = Rc::new(RefCell::new(TunnelState { handshake, open: false, pending: Vec::new() }));
+
+    let greeter = socket.clone();
+    let on_open = Closure::wrap(Box::new(move || {
+        let _ = greeter.send_with_u8_array(&greeting);
+    }) as Box<dyn FnMut()>);
+
+    let (replier, shared, guest) = (socket.clone(), state.clone(), handle.clone());
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let data = match event.data().dyn_into::<ArrayBuffer>() {
+            Ok(buffer) => Uint8Array::new(&buffer).to_vec(),
+            Err(data) => data.as_string().unwrap_or_default().into_bytes(),
+        };
+        let mut state = shared.borrow_mut();
+        if state.open {
+            drop(state);
+            guest.send(&data);
+            return;
+        }
+        match state.handshake.receive(&data) {
+            Ok(Step::Pending) => {}
+            Ok(Step::Reply(reply)) => {
+                let _ = replier.send_with_u8_array(&reply);
+            }
+            Ok(Step::Open(data)) => {
+                state.open = true;
+                let pending = std::mem::take(&mut state.pending);
+                drop(state);
+                if !pending.is_empty() {
+                    let _ = replier.send_with_u8_array(&pending);
+                }
+                if !data.is_empty() {
+                    guest.send(&data);
+                }
+            }
+            Err(e) => {
+                log::warn!("SOCKS handshake failed: {}", e);
+                drop(state);
+                close(&replier);
+                guest.close();
+            }
+        }
+    }) as Box<dyn FnMut(MessageEvent)>);
+
+    let guest = handle.clone();
+    let on_close = Closure::wrap(Box::new(move || guest.close()) as Box<dyn FnMut()>);
+
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+    socket.set_onerror(Some(on_close.as_ref().unchecked_ref()));
+    Ok(Tunnel { socket, state, _on_open: on_open, _on_message: on_message, _on_close: on_close })
+}
+
+/// Closes the bridge without its handlers, which are about to be freed.
+fn close(socket: &WebSocket) {
+    socket.set_onopen(None);
+    socket.set_onmessage(None);
+    socket.set_onclose(None);
+    socket.set_onerror(None);
+    let _ = socket.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const TARGET: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 22);
+
+    #[wasm_bindgen_test]
+    fn test_handshake_with_password() {
+        let mut handshake = Handshake::new(TARGET, Some(("vm".into(), "pw".into())));
+        assert_eq!(handshake.greeting(), vec![5, 2, 0, 2]);
+
+        assert_eq!(handshake.receive(&[5]).unwrap(), Step::Pending);
+        assert_eq!(handshake.receive(&[2]).unwrap(), Step::Reply(vec![1, 2, b'v', b'm', 2, b'p', b'w']));
+        assert_eq!(handshake.receive(&[1, 0]).unwrap(), Step::Reply(vec![5, 1, 0, 1, 93, 184, 216, 34, 0, 22]));
+
+        // Bound to a domain name, with the server's banner right behind
+        let mut reply = vec![5, 0, 0, 3, 5];
+        reply.extend_from_slice(b"proxy");
+        reply.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(handshake.receive(&reply[..7]).unwrap(), Step::Pending);
+        let mut rest = reply[7..].to_vec();
+        rest.extend_from_slice(b"SSH-2.0");
+        assert_eq!(handshake.receive(&rest).unwrap(), Step::Open(b"SSH-2.0".to_vec()));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_handshake_failures() {
+        let mut handshake = Handshake::new(TARGET, None);
+        assert_eq!(handshake.greeting(), vec![5, 1, 0]);
+        assert!(matches!(handshake.receive(&[5, 0xFF]), Err(DerpError::AuthRejected(_))));
+
+        let mut handshake = Handshake::new(TARGET, None);
+        handshake.receive(&[5, 0]).unwrap();
+        let refused = handshake.receive(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert!(refused.to_string().contains("connection refused"));
+
+        assert!(Handshake::new(TARGET, None).receive(&[4, 0]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use wasm_bindgen::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use crate::network::NetworkState;
+use crate::shared::Shared;
+use crate::vm_network::{NicHandle, VmNetwork};
+use crate::DerpNetwork;
+
+/// Learned addresses are forgotten after this long without a frame from them.
+const MAC_AGING_MS: f64 = 300_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Port {
+    Nic(u32),
+    Uplink,
+}
+
+/// A learning Ethernet switch between the NICs of VMs on one page, with an
+/// optional uplink that carries frames for unknown destinations over a
+/// relay connection, straight to the peer that announced the destination
+/// MAC when there is one.
+#[wasm_bindgen]
+pub struct VirtualSwitch {
+    switch: Rc<RefCell<Switch>>,
+}
+
+#[derive(Default)]
+pub struct Switch {
+    ports: HashMap<u32, NicHandle>,
+    next_port: u32,
+    /// Source MAC to the port it was last seen on, and when.
+    table: HashMap<[u8; 6], (Port, f64)>,
+    uplink: Option<Shared<NetworkState>>,
+}
+
+/// How a NIC or the relay reaches the switch without keeping it alive.
+#[derive(Clone)]
+pub struct SwitchHandle(Weak<RefCell<Switch>>);
+
+#[wasm_bindgen]
+impl VirtualSwitch {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> VirtualSwitch {
+        VirtualSwitch {
+            switch: Rc::new(RefCell::new(Switch::default())),
+        }
+    }
+
+    /// Plugs a NIC into the switch and returns its port number. Frames the
+    /// guest sends to other guests are switched here instead of reaching
+    /// the NIC's gateway.
+    pub fn connect(&self, nic: &VmNetwork) -> u32 {
+        let nic = nic.handle();
+        let port = {
+            let mut switch = self.switch.borrow_mut();
+            let port = switch.next_port;
+            switch.next_port += 1;
+            switch.ports.insert(port, nic.clone());
+            port
+        };
+        nic.attach_to_switch(Some((self.handle(), port)));
+        port
+    }
+
+    /// Unplugs a port. Returns false if nothing was connected to it.
+    pub fn disconnect(&self, port: u32) -> bool {
+        let mut switch = self.switch.borrow_mut();
+        let Some(nic) = switch.ports.remove(&port) else { return false };
+        switch.table.retain(|_, (learned, _)| *learned != Port::Nic(port));
+        nic.attach_to_switch(None);
+        true
+    }
+
+    /// Sends frames for destinations not on the switch over `derp`'s relay
+    /// connection, and takes Ethernet frames it receives.
+    #[wasm_bindgen(js_name = setUplink)]
+    pub fn set_uplink(&self, derp: &DerpNetwork) {
+        self.switch.borrow_mut().uplink = Some(derp.network.clone());
+        derp.nics.borrow_mut().set_uplink(self.handle());
+    }
+
+    #[wasm_bindgen(js_name = portCount)]
+    pub fn port_count(&self) -> u32 {
+        self.switch.borrow().ports.len() as u32
+    }
+}
+
+impl Default for VirtualSwitch {
+    fn default() -> Self {
+        VirtualSwitch::new()
+    }
+}
+
+impl VirtualSwitch {
+    fn handle(&self) -> SwitchHandle {
+        SwitchHandle(Rc::downgrade(&self.switch))
+    }
+}
+
+impl Switch {
+    /// Learns the frame's source and returns the ports it should go out on:
+    /// the learned port for a known unicast destination, otherwise every
+    /// port but the one it came in on.
+    pub fn targets(&mut self, ingress: Port, frame: &[u8], now_ms: f64) -> Vec<Port> {
+        self.table.retain(|_, (_, seen)| now_ms - *seen < MAC_AGING_MS);
+
+        let mut src = [0u8; 6];
+        src.copy_from_slice(&frame[6..12]);
+        if src[0] & 0x01 == 0 {
+            self.table.insert(src, (ingress, now_ms));
+        }
+
+        let mut dst = [0u8; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        if dst[0] & 0x01 == 0 {
+            if let Some(&(port, _)) = self.table.get(&dst) {
+                // Promiscuous NICs see every frame, as if on a hub
+                let mut ports: Vec<Port> = self.ports.iter()
+                    .filter(|(_, nic)| nic.is_promiscuous())
+                    .map(|(&port, _)| Port::Nic(port))
+                    .collect();
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+                ports.retain(|&port| port != ingress);
+                return ports;
+            }
+        }
+
+        let mut ports: Vec<Port> = self.ports.keys().map(|&port| Port::Nic(port)).collect();
+        if self.uplink.is_some() {
+            ports.push(Port::Uplink);
+        }
+        ports.retain(|&port| port != ingress);
+        ports
+    }
+}
+
+impl SwitchHandle {
+    /// Switches a frame that came in on `ingress`.
+    pub fn forward(&self, ingress: Port, frame: &[u8]) -> Result<(), JsValue> {
+        let Some(switch) = self.0.upgrade() else { return Ok(()) };
+        // Delivery can re-enter the switch, so the borrow ends first
+        let (targets, nics, uplink) = {
+            let mut switch = switch.borrow_mut();
+            let targets = switch.targets(ingress, frame, js_sys::Date::now());
+            (targets, switch.ports.clone(), switch.uplink.clone())
+        };
+
+        for target in targets {
+            match target {
+                Port::Nic(port) => {
+                    if let Some(nic) = nics.get(&port) {
+                        nic.receive_frame(frame)?;
+                    }
+                }
+                Port::Uplink => {
+                    if let Some(network) = &uplink {
+                        NetworkState::lock(network).send_frame_to_mac(None, frame)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const A: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0A];
+    const B: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0B];
+
+    fn frame(dst: [u8; 6], src: [u8; 6]) -> Vec<u8> {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame
+    }
+
+    fn switch_with_ports(count: u32) -> Switch {
+        let mut switch = Switch::default();
+        for port in 0..count {
+            switch.ports.insert(port, NicHandle::default());
+        }
+        switch
+    }
+
+    #[wasm_bindgen_test]
+    fn test_floods_then_learns() {
+        let mut switch = switch_with_ports(3);
+
+        let mut flooded = switch.targets(Port::Nic(0), &frame(B, A), 0.0);
+        flooded.sort_by_key(|port| format!("{:?}", port));
+        assert_eq!(flooded, vec![Port::Nic(1), Port::Nic(2)]);
+
+        // B answers; A is known by now, so only its port gets the reply
+        assert_eq!(switch.targets(Port::Nic(1), &frame(A, B), 1.0), vec![Port::Nic(0)]);
+        assert_eq!(switch.targets(Port::Nic(0), &frame(B, A), 2.0), vec![Port::Nic(1)]);
+        // Broadcast always floods
+        assert_eq!(switch.targets(Port::Nic(0), &frame([0xFF; 6], A), 3.0).len(), 2);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_entries_age_out() {
+        let mut switch = switch_with_ports(3);
+        switch.targets(Port::Nic(1), &frame(A, B), 0.0);
+        assert_eq!(switch.targets(Port::Nic(0), &frame(B, A), 1.0), vec![Port::Nic(1)]);
+
+        assert_eq!(switch.targets(Port::Nic(0), &frame(B, A), MAC_AGING_MS + 2.0).len(), 2);
+        assert_eq!(switch.table.get(&B), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_connects_vms_on_one_page() {
+        let derp = DerpNetwork::new(None).unwrap();
+        let a = derp.create_vm_network(&A, None).unwrap();
+        let b = derp.create_vm_network(&B, None).unwrap();
+        let switch = VirtualSwitch::new();
+        switch.connect(&a);
+        let port_b = switch.connect(&b);
+
+        let received = js_sys::Array::new();
+        let sink = received.clone();
+        let callback = Closure::wrap(Box::new(move |frame: js_sys::Uint8Array| {
+            sink.push(&frame);
+        }) as Box<dyn FnMut(js_sys::Uint8Array)>);
+        b.set_receive_callback(wasm_bindgen::JsCast::unchecked_ref::<js_sys::Function>(callback.as_ref()).clone());
+
+        a.send_packet(&frame(B, A)).unwrap();
+        assert_eq!(received.length(), 1);
+        assert_eq!(js_sys::Uint8Array::from(received.get(0)).to_vec(), frame(B, A));
+
+        assert!(switch.disconnect(port_b));
+        a.send_packet(&frame(B, A)).unwrap();
+        assert_eq!(received.length(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_promiscuous_port_sees_other_traffic() {
+        const C: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0C];
+        let derp = DerpNetwork::new(None).unwrap();
+        let a = derp.create_vm_network(&A, None).unwrap();
+        let b = derp.create_vm_network(&B, None).unwrap();
+        let c = derp.create_vm_network(&C, None).unwrap();
+        let switch = VirtualSwitch::new();
+        for nic in [&a, &b, &c] {
+            switch.connect(nic);
+        }
+
+        let received = js_sys::Array::new();
+        let sink = received.clone();
+        let callback = Closure::wrap(Box::new(move |frame: js_sys::Uint8Array| {
+            sink.push(&frame);
+        }) as Box<dyn FnMut(js_sys::Uint8Array)>);
+        c.set_receive_callback(wasm_bindgen::JsCast::unchecked_ref::<js_sys::Function>(callback.as_ref()).clone());
+
+        // B is unknown at first, so the frame floods, but C's MAC filter drops it
+        a.send_packet(&frame(B, A)).unwrap();
+        b.send_packet(&frame(A, B)).unwrap();
+        assert_eq!(received.length(), 0);
+
+        c.set_promiscuous(true);
+        a.send_packet(&frame(B, A)).unwrap();
+        assert_eq!(received.length(), 1);
+        assert_eq!(js_sys::Uint8Array::from(received.get(0)).to_vec(), frame(B, A));
+    }
+}
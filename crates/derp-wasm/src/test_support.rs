@@ -0,0 +1,200 @@
+//! Scriptable stand-ins for a relay, for tests that need to decide exactly
+//! what the relay says and when. Built with the `test-support` feature, so
+//! crates embedding `NetworkState` can use them too.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::error::{DerpError, DerpResult};
+use crate::events::DisconnectEvent;
+use crate::protocol::{FrameType, ProtocolState, ServerInfo, PEER_KEY_LEN};
+use crate::transport::{CloseHandler, Receiver};
+use crate::wire::WireFormat;
+
+const MOCK_NAME: &str = "mock";
+
+/// A relay connection driven by the test instead of a server, injected
+/// with `NetworkState::connect_mock`. Nothing is answered unless the test
+/// delivers it, and the connection only drops when told to; the network's
+/// reconnects reopen the same mock.
+///
+/// Unlike `Loopback`, delivery is synchronous, so a test can assert on the
+/// network's state straight after each step.
+#[derive(Clone, Default)]
+pub struct MockWebSocket {
+    inner: Rc<RefCell<Mock>>,
+}
+
+#[derive(Default)]
+struct Mock {
+    receiver: Option<Receiver>,
+    on_close: Option<CloseHandler>,
+    /// Whether connections wait for `open` instead of opening at once.
+    delay_open: bool,
+    open: bool,
+    /// Sent while the connection was opening; they go out on `open`.
+    pending: Vec<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+    connections: u32,
+    fail_sends: bool,
+    buffered_amount: usize,
+}
+
+impl MockWebSocket {
+    pub fn new() -> MockWebSocket {
+        MockWebSocket::default()
+    }
+
+    /// Makes connections from now on wait for `open`, holding back what's
+    /// sent until then.
+    pub fn delay_open(&self, delay: bool) {
+        self.inner.borrow_mut().delay_open = delay;
+    }
+
+    /// Completes a delayed open.
+    pub fn open(&self) {
+        let mut mock = self.inner.borrow_mut();
+        mock.open = true;
+        let pending = std::mem::take(&mut mock.pending);
+        mock.sent.extend(pending);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.inner.borrow().open
+    }
+
+    /// Connections made through this mock, reconnects included.
+    pub fn connections(&self) -> u32 {
+        self.inner.borrow().connections
+    }
+
+    /// Messages sent since the last call.
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.inner.borrow_mut().sent)
+    }
+
+    /// Makes sends fail, as a socket's do once it's closing.
+    pub fn fail_sends(&self, fail: bool) {
+        self.inner.borrow_mut().fail_sends = fail;
+    }
+
+    /// Sets what `bufferedAmount` reports, to exercise backpressure.
+    pub fn set_buffered_amount(&self, bytes: usize) {
+        self.inner.borrow_mut().buffered_amount = bytes;
+    }
+
+    /// Hands `message` to the network as if the relay had sent it.
+    pub fn deliver(&self, message: &[u8]) -> DerpResult<()> {
+        let receiver = {
+            let mock = self.inner.borrow();
+            if !mock.open {
+                return Err(DerpError::InvalidState("Mock connection isn't open".into()));
+            }
+            mock.receiver.clone()
+        };
+        // Called without the borrow held, since the network sends replies
+        if let Some(receiver) = receiver {
+            receiver(message);
+        }
+        Ok(())
+    }
+
+    /// Delivers the ServerKey and ServerInfo frames a relay starts with,
+    /// which completes the handshake when there's no auth token.
+    pub fn complete_handshake(&self) -> DerpResult<()> {
+        let framing = ProtocolState::new();
+        let info = ServerInfo::new(MOCK_NAME, Vec::new());
+        self.deliver(&framing.encode_frame(FrameType::ServerKey, &[0; PEER_KEY_LEN]))?;
+        self.deliver(&framing.encode_control_frame(FrameType::ServerInfo, WireFormat::Bincode, &info)?)
+    }
+
+    /// Drops the connection uncleanly with `code` and `reason`, running the
+    /// network's close handling as a WebSocket's close event would.
+    pub fn drop_connection(&self, code: u16, reason: &str) {
+        let on_close = {
+            let mut mock = self.inner.borrow_mut();
+            mock.open = false;
+            mock.pending.clear();
+            mock.on_close.clone()
+        };
+        if let Some(on_close) = on_close {
+            on_close(&DisconnectEvent {
+                code,
+                reason: reason.to_string(),
+                was_clean: false,
+            });
+        }
+    }
+
+    /// Connects `NetworkState`'s handlers.
+    pub(crate) fn attach(&self, receiver: Receiver, on_close: CloseHandler) {
+        {
+            let mut mock = self.inner.borrow_mut();
+            mock.receiver = Some(receiver);
+            mock.on_close = Some(on_close);
+        }
+        self.reopen();
+    }
+
+    /// Starts another connection, open at once unless opens are delayed.
+    pub(crate) fn reopen(&self) {
+        let mut mock = self.inner.borrow_mut();
+        mock.connections += 1;
+        mock.open = !mock.delay_open;
+        mock.pending.clear();
+    }
+
+    pub fn send(&self, message: &[u8]) -> DerpResult<()> {
+        let mut mock = self.inner.borrow_mut();
+        if mock.fail_sends || mock.receiver.is_none() {
+            return Err(DerpError::WebSocketError("Failed to send data: mock connection closed".into()));
+        }
+        match mock.open {
+            true => mock.sent.push(message.to_vec()),
+            false => mock.pending.push(message.to_vec()),
+        }
+        Ok(())
+    }
+
+    pub fn buffered_amount(&self) -> usize {
+        self.inner.borrow().buffered_amount
+    }
+
+    /// Detaches the network, as closing a WebSocket whose handlers were
+    /// removed does.
+    pub fn close(&self) {
+        let mut mock = self.inner.borrow_mut();
+        mock.receiver = None;
+        mock.on_close = None;
+        mock.open = false;
+        mock.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_delayed_open() {
+        let mock = MockWebSocket::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let sink = received.clone();
+        mock.delay_open(true);
+        mock.attach(Rc::new(move |message: &[u8]| sink.borrow_mut().push(message.to_vec())), Rc::new(|_: &DisconnectEvent| {}));
+
+        mock.send(b"hello").unwrap();
+        assert!(mock.take_sent().is_empty());
+        assert!(mock.deliver(b"early").is_err());
+
+        mock.open();
+        assert_eq!(mock.take_sent(), vec![b"hello".to_vec()]);
+        mock.deliver(b"reply").unwrap();
+        assert_eq!(*received.borrow(), vec![b"reply".to_vec()]);
+
+        mock.close();
+        assert!(mock.send(b"late").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::rc::Rc;
+
+pub const TFTP_PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+const ERR_ILLEGAL: u16 = 4;
+const ERR_UNKNOWN_TID: u16 = 5;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MIN_BLOCK_SIZE: usize = 8;
+/// IPv4, UDP and TFTP DATA headers.
+const HEADERS_LEN: usize = 20 + 8 + 4;
+/// Transfers the guest stops acknowledging are forgotten after this long.
+const TRANSFER_TIMEOUT_MS: f64 = 30_000.0;
+/// Server-side transfer ports, one per transfer as RFC 1350 has it.
+const TRANSFER_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// A datagram to send back: from `src_port` on the server to `dst`.
+#[derive(Debug, PartialEq)]
+pub struct TftpReply {
+    pub src_port: u16,
+    pub dst: SocketAddrV4,
+    pub payload: Vec<u8>,
+}
+
+/// Read-only TFTP server (RFC 1350, with the blksize and tsize options of
+/// RFCs 2348 and 2349 that PXE ROMs rely on) for files handed over by the
+/// page. Lost DATA is resent when the guest repeats its last ACK.
+pub struct TftpServer {
+    files: HashMap<String, Rc<[u8]>>,
+    transfers: HashMap<u16, Transfer>,
+    next_port: u16,
+    max_block_size: usize,
+}
+
+struct Transfer {
+    client: SocketAddrV4,
+    data: Rc<[u8]>,
+    block_size: usize,
+    /// The block last sent; 0 while an OACK awaits its acknowledgement.
+    block: u32,
+    last_seen_ms: f64,
+}
+
+impl TftpServer {
+    /// Blocks are capped so DATA fits in one `mtu`-sized packet.
+    pub fn new(mtu: u16) -> Self {
+        TftpServer {
+            files: HashMap::new(),
+            transfers: HashMap::new(),
+            next_port: *TRANSFER_PORTS.start(),
+            max_block_size: (mtu as usize).saturating_sub(HEADERS_LEN).max(DEFAULT_BLOCK_SIZE),
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) {
+        self.files.insert(normalize(name).to_string(), data.into());
+    }
+
+    pub fn remove_file(&mut self, name: &str) -> bool {
+        self.files.remove(normalize(name)).is_some()
+    }
+
+    /// Whether datagrams to `port` on the gateway are this server's: the
+    /// well-known port once there's something to serve, and the ports of
+    /// transfers in progress.
+    pub fn handles(&self, port: u16) -> bool {
+        (port == TFTP_PORT && !self.files.is_empty()) || self.transfers.contains_key(&port)
+    }
+
+    pub fn handle(&mut self, client: SocketAddrV4, port: u16, packet: &[u8], now_ms: f64) -> Option<TftpReply> {
+        if packet.len() < 2 {
+            return None;
+        }
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+        if port == TFTP_PORT {
+            return match opcode {
+                OP_RRQ => Some(self.start(client, &packet[2..], now_ms)),
+                OP_WRQ => Some(error(TFTP_PORT, client, ERR_ACCESS, "Read only")),
+                _ => Some(error(TFTP_PORT, client, ERR_ILLEGAL, "Expected a read request")),
+            };
+        }
+
+        let transfer = self.transfers.get_mut(&port)?;
+        if transfer.client != client {
+            return Some(error(port, client, ERR_UNKNOWN_TID, "Unknown transfer ID"));
+        }
+        transfer.last_seen_ms = now_ms;
+
+        match opcode {
+            OP_ACK if packet.len() >= 4 => {
+                let acked = u16::from_be_bytes([packet[2], packet[3]]);
+                if acked == transfer.block as u16 {
+                    if transfer.block >= transfer.last_block() {
+                        self.transfers.remove(&port);
+                        return None;
+                    }
+                    transfer.block += 1;
+                } else if acked != transfer.block.wrapping_sub(1) as u16 || transfer.block == 0 {
+                    return None;
+                }
+                // Either the next block, or the last one again if the ACK is a repeat
+                Some(TftpReply { src_port: port, dst: client, payload: transfer.data_packet() })
+            }
+            OP_ERROR => {
+                self.transfers.remove(&port);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Answers a read request with an OACK if it asked for options this
+    /// server honours, otherwise with the first block.
+    fn start(&mut self, client: SocketAddrV4, request: &[u8], now_ms: f64) -> TftpReply {
+        self.transfers.retain(|_, transfer| now_ms - transfer.last_seen_ms < TRANSFER_TIMEOUT_MS);
+
+        let mut fields = request.split(|&byte| byte == 0).map(|field| String::from_utf8_lossy(field).into_owned());
+        let (Some(name), Some(_mode)) = (fields.next(), fields.next()) else {
+            return error(TFTP_PORT, client, ERR_ILLEGAL, "Malformed request");
+        };
+        let Some(data) = self.files.get(normalize(&name)).cloned() else {
+            return error(TFTP_PORT, client, ERR_NOT_FOUND, "File not found");
+        };
+
+        let mut block_size = DEFAULT_BLOCK_SIZE;
+        let mut accepted = Vec::new();
+        while let (Some(option), Some(value)) = (fields.next(), fields.next()) {
+            match option.to_ascii_lowercase().as_str() {
+                "blksize" => {
+                    let Ok(requested) = value.parse::<usize>() else { continue };
+                    block_size = requested.clamp(MIN_BLOCK_SIZE, self.max_block_size);
+                    accepted.push(("blksize", block_size.to_string()));
+                }
+                "tsize" => accepted.push(("tsize", data.len().to_string())),
+                _ => {}
+            }
+        }
+
+        let port = self.allocate_port();
+        let mut transfer = Transfer { client, data, block_size, block: 0, last_seen_ms: now_ms };
+        let payload = if accepted.is_empty() {
+            transfer.block = 1;
+            transfer.data_packet()
+        } else {
+            let mut oack = OP_OACK.to_be_bytes().to_vec();
+            for (option, value) in accepted {
+                oack.extend_from_slice(option.as_bytes());
+                oack.push(0);
+                oack.extend_from_slice(value.as_bytes());
+                oack.push(0);
+            }
+            oack
+        };
+        self.transfers.insert(port, transfer);
+        TftpReply { src_port: port, dst: client, payload }
+    }
+
+    fn allocate_port(&mut self) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = if port == *TRANSFER_PORTS.end() { *TRANSFER_PORTS.start() } else { port + 1 };
+            if !self.transfers.contains_key(&port) {
+                return port;
+            }
+        }
+    }
+}
+
+impl Transfer {
+    /// The final block is the first one shorter than the block size, empty
+    /// if the file is an exact multiple of it.
+    fn last_block(&self) -> u32 {
+        (self.data.len() / self.block_size) as u32 + 1
+    }
+
+    fn data_packet(&self) -> Vec<u8> {
+        let start = ((self.block - 1) as usize * self.block_size).min(self.data.len());
+        let end = (start + self.block_size).min(self.data.len());
+        let mut packet = OP_DATA.to_be_bytes().to_vec();
+        // Block numbers wrap for files past 65535 blocks, as most clients expect
+        packet.extend_from_slice(&(self.block as u16).to_be_bytes());
+        packet.extend_from_slice(&self.data[start..end]);
+        packet
+    }
+}
+
+fn error(src_port: u16, dst: SocketAddrV4, code: u16, message: &str) -> TftpReply {
+    let mut payload = OP_ERROR.to_be_bytes().to_vec();
+    payload.extend_from_slice(&code.to_be_bytes());
+    payload.extend_from_slice(message.as_bytes());
+    payload.push(0);
+    TftpReply { src_port, dst, payload }
+}
+
+/// Boot ROMs differ on whether paths start with a slash.
+fn normalize(name: &str) -> &str {
+    name.trim_start_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 86, 100), 2070);
+
+    fn read_request(name: &str, options: &[(&str, &str)]) -> Vec<u8> {
+        let mut packet = OP_RRQ.to_be_bytes().to_vec();
+        for field in [name, "octet"].into_iter().chain(options.iter().flat_map(|(option, value)| [*option, *value])) {
+            packet.extend_from_slice(field.as_bytes());
+            packet.push(0);
+        }
+        packet
+    }
+
+    fn ack(block: u16) -> Vec<u8> {
+        [OP_ACK.to_be_bytes(), block.to_be_bytes()].concat()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_plain_transfer() {
+        let mut server = TftpServer::new(1500);
+        server.add_file("/pxelinux.0", vec![7; 1024]);
+        assert!(server.handles(TFTP_PORT));
+
+        let first = server.handle(CLIENT, TFTP_PORT, &read_request("pxelinux.0", &[]), 0.0).unwrap();
+        let port = first.src_port;
+        assert_ne!(port, TFTP_PORT);
+        assert_eq!(&first.payload[..4], &[0, 3, 0, 1]);
+        assert_eq!(first.payload.len(), 4 + 512);
+
+        let second = server.handle(CLIENT, port, &ack(1), 1.0).unwrap();
+        assert_eq!(&second.payload[..4], &[0, 3, 0, 2]);
+        // A repeated ACK gets the block again
+        assert_eq!(server.handle(CLIENT, port, &ack(1), 2.0).unwrap(), second);
+
+        // 1024 bytes end with an empty third block
+        let last = server.handle(CLIENT, port, &ack(2), 3.0).unwrap();
+        assert_eq!(last.payload, vec![0, 3, 0, 3]);
+        assert_eq!(server.handle(CLIENT, port, &ack(3), 4.0), None);
+        assert!(!server.handles(port));
+
+        let missing = server.handle(CLIENT, TFTP_PORT, &read_request("missing", &[]), 5.0).unwrap();
+        assert_eq!(&missing.payload[..4], &[0, 5, 0, 1]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_options_are_acknowledged() {
+        let mut server = TftpServer::new(1500);
+        server.add_file("boot.img", vec![1; 3000]);
+
+        let request = read_request("boot.img", &[("tsize", "0"), ("blksize", "65464"), ("timeout", "5")]);
+        let oack = server.handle(CLIENT, TFTP_PORT, &request, 0.0).unwrap();
+        assert_eq!(oack.payload, b"\x00\x06tsize\x003000\x00blksize\x001468\x00".to_vec());
+
+        let first = server.handle(CLIENT, oack.src_port, &ack(0), 1.0).unwrap();
+        assert_eq!(first.payload.len(), 4 + 1468);
+        let other = SocketAddrV4::new(Ipv4Addr::new(192, 168, 86, 101), 2070);
+        assert_eq!(&server.handle(other, oack.src_port, &ack(1), 2.0).unwrap().payload[..4], &[0, 5, 0, 5]);
+    }
+}
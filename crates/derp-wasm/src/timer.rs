@@ -0,0 +1,27 @@
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+// The timer functions are globals in both windows and workers, whereas
+// web-sys only exposes them as methods on `Window` or `WorkerGlobalScope`.
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    pub fn set_timeout(handler: &Function, timeout_ms: i32) -> i32;
+
+    #[wasm_bindgen(js_name = clearTimeout)]
+    pub fn clear_timeout(handle: i32);
+
+    #[wasm_bindgen(js_name = setInterval)]
+    pub fn set_interval(handler: &Function, timeout_ms: i32) -> i32;
+
+    #[wasm_bindgen(js_name = clearInterval)]
+    pub fn clear_interval(handle: i32);
+}
+
+/// Resolves once `timeout_ms` has passed.
+pub async fn sleep(timeout_ms: i32) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        set_timeout(&resolve, timeout_ms);
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use tsify::Tsify;
+use crate::firewall::Direction;
+use crate::ip::{Ipv4Packet, Ipv6Packet, PROTO_TCP, PROTO_UDP};
+use crate::ndp::ETHERTYPE_IPV6;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const DEFAULT_TRACE_CAPACITY: usize = 256;
+/// Roughly what one kept summary costs: the struct and its address strings.
+pub const SUMMARY_BYTES: usize = std::mem::size_of::<FrameSummary>() + 64;
+
+/// What became of a traced frame at the `VmNetwork` boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Handed on: to the gateway, switch or relay when sent, to the
+    /// emulator when received.
+    Passed,
+    /// Stopped by a firewall rule or the default action.
+    Firewalled,
+    /// Lost to traffic shaping, by random loss or a full link.
+    Shaped,
+    /// Unicast for another MAC, dropped because the NIC isn't promiscuous.
+    Filtered,
+}
+
+/// The headers of one frame that matter when following traffic. Fields
+/// past the ethertype are unset when the frame doesn't carry them.
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameSummary {
+    pub direction: Direction,
+    pub outcome: Outcome,
+    pub time_ms: f64,
+    /// The whole frame, Ethernet header included.
+    pub length: usize,
+    pub src_mac: String,
+    pub dst_mac: String,
+    pub ethertype: u16,
+    #[tsify(optional)]
+    pub protocol: Option<u8>,
+    #[tsify(optional)]
+    pub src: Option<String>,
+    #[tsify(optional)]
+    pub dst: Option<String>,
+    #[tsify(optional)]
+    pub src_port: Option<u16>,
+    #[tsify(optional)]
+    pub dst_port: Option<u16>,
+}
+
+/// Recent frames, oldest first, as returned by `getTrace`.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Trace {
+    pub frames: Vec<FrameSummary>,
+}
+
+impl FrameSummary {
+    /// Decodes `frame`, which must hold at least an Ethernet header.
+    pub fn new(direction: Direction, outcome: Outcome, frame: &[u8], time_ms: f64) -> FrameSummary {
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let mut summary = FrameSummary {
+            direction,
+            outcome,
+            time_ms,
+            length: frame.len(),
+            src_mac: format_mac(&frame[6..12]),
+            dst_mac: format_mac(&frame[..6]),
+            ethertype,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_port: None,
+            dst_port: None,
+        };
+
+        let (src, dst, protocol, transport) = match ethertype {
+            ETHERTYPE_IPV4 => match Ipv4Packet::parse(&frame[14..]) {
+                Some(ip) => (ip.src.to_string(), ip.dst.to_string(), ip.protocol, ip.payload),
+                None => return summary,
+            },
+            ETHERTYPE_IPV6 => match Ipv6Packet::parse(&frame[14..]) {
+                Some(ip) => (ip.src.to_string(), ip.dst.to_string(), ip.next_header, ip.payload),
+                None => return summary,
+            },
+            _ => return summary,
+        };
+        summary.src = Some(src);
+        summary.dst = Some(dst);
+        summary.protocol = Some(protocol);
+        if (protocol == PROTO_TCP || protocol == PROTO_UDP) && transport.len() >= 4 {
+            summary.src_port = Some(u16::from_be_bytes([transport[0], transport[1]]));
+            summary.dst_port = Some(u16::from_be_bytes([transport[2], transport[3]]));
+        }
+        summary
+    }
+}
+
+/// Keeps the most recent frame summaries while recording is on. Off by
+/// default, so untraced NICs pay only for the check.
+pub struct Tracer {
+    recording: bool,
+    recent: VecDeque<FrameSummary>,
+    capacity: usize,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize) -> Self {
+        Tracer {
+            recording: false,
+            recent: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    /// Keeps `summary`, evicting the oldest once full.
+    pub fn record(&mut self, summary: FrameSummary) {
+        if !self.recording || self.capacity == 0 {
+            return;
+        }
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(summary);
+    }
+
+    pub fn trace(&self) -> Trace {
+        Trace { frames: self.recent.iter().cloned().collect() }
+    }
+
+    pub fn clear(&mut self) {
+        self.recent.clear();
+    }
+
+    /// Summaries kept, and how many fit.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.recent.len(), self.capacity)
+    }
+}
+
+fn format_mac(mac: &[u8]) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip;
+    use std::net::Ipv4Addr;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn udp_frame() -> Vec<u8> {
+        let mut frame = vec![0xFF; 6];
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let (src, dst) = (Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::BROADCAST);
+        frame.extend_from_slice(&ip::build_udp(src, dst, 68, 67, &[1, 2, 3]));
+        frame
+    }
+
+    #[wasm_bindgen_test]
+    fn test_summary_decodes_headers() {
+        let frame = udp_frame();
+        let summary = FrameSummary::new(Direction::Outbound, Outcome::Passed, &frame, 5.0);
+        assert_eq!(summary.length, frame.len());
+        assert_eq!(summary.src_mac, "52:54:00:12:34:56");
+        assert_eq!(summary.dst_mac, "ff:ff:ff:ff:ff:ff");
+        assert_eq!(summary.protocol, Some(PROTO_UDP));
+        assert_eq!(summary.dst.as_deref(), Some("255.255.255.255"));
+        assert_eq!((summary.src_port, summary.dst_port), (Some(68), Some(67)));
+
+        let mut arp = frame[..12].to_vec();
+        arp.extend_from_slice(&[0x08, 0x06, 0, 1]);
+        let summary = FrameSummary::new(Direction::Inbound, Outcome::Filtered, &arp, 6.0);
+        assert_eq!(summary.ethertype, 0x0806);
+        assert_eq!((summary.protocol, summary.src), (None, None));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_tracer_keeps_recent_frames() {
+        let mut tracer = Tracer::new(2);
+        let summary = |time_ms| FrameSummary::new(Direction::Outbound, Outcome::Passed, &udp_frame(), time_ms);
+        tracer.record(summary(1.0));
+        assert!(tracer.trace().frames.is_empty());
+
+        tracer.set_recording(true);
+        for time_ms in [2.0, 3.0, 4.0] {
+            tracer.record(summary(time_ms));
+        }
+        let times: Vec<f64> = tracer.trace().frames.iter().map(|frame| frame.time_ms).collect();
+        assert_eq!(times, vec![3.0, 4.0]);
+
+        tracer.clear();
+        assert!(tracer.trace().frames.is_empty());
+    }
+}
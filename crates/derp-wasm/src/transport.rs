@@ -0,0 +1,508 @@
+#[cfg(feature = "base64")]
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use tsify::Tsify;
+use wasm_bindgen::JsValue;
+use web_sys::WebSocket;
+use crate::crypto::{CryptoState, CIPHERTEXT_OVERHEAD};
+use crate::error::{DerpError, DerpResult};
+use crate::events::DisconnectEvent;
+use crate::simulate::SimulatedTransport;
+#[cfg(any(test, feature = "test-support"))]
+use crate::test_support::MockWebSocket;
+use crate::protocol::{cipher_suite_for, decode_handshake, ClientInfo, Frame, FrameType, PeerKey, ProtocolState, ServerInfo, FEATURE_CBOR, PEER_KEY_LEN};
+use crate::wire::WireFormat;
+
+const LOOPBACK_NAME: &str = "loopback";
+
+/// Called with each message a transport receives.
+pub type Receiver = Rc<dyn Fn(&[u8])>;
+
+/// Called when a transport's connection drops.
+pub type CloseHandler = Rc<dyn Fn(&DisconnectEvent)>;
+
+/// How frames are carried in WebSocket messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketMode {
+    #[default]
+    Binary,
+    /// Base64 in text messages, for servers and proxies that only pass text.
+    /// Needs the base64 feature.
+    Text,
+}
+
+/// Opens a relay socket offering `protocols`, with `query` added to the
+/// URL's query string.
+pub fn open_websocket(url: &str, protocols: &[String], query: &BTreeMap<String, String>) -> DerpResult<WebSocket> {
+    let url = with_query(url, query);
+    let ws = if protocols.is_empty() {
+        WebSocket::new(&url)
+    } else {
+        let protocols: js_sys::Array = protocols.iter().map(|protocol| JsValue::from_str(protocol)).collect();
+        WebSocket::new_with_str_sequence(&url, &protocols)
+    }.map_err(|e| DerpError::WebSocketError(format!("Failed to create WebSocket: {:?}", e)))?;
+
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    Ok(ws)
+}
+
+/// `url` with `query` appended, ahead of any fragment.
+pub fn with_query(url: &str, query: &BTreeMap<String, String>) -> String {
+    if query.is_empty() {
+        return url.to_string();
+    }
+    let (base, fragment) = url.split_once('#').map_or((url, None), |(base, fragment)| (base, Some(fragment)));
+    let separator = match base.find('?') {
+        None => "?",
+        Some(_) if base.ends_with('?') || base.ends_with('&') => "",
+        Some(_) => "&",
+    };
+    let pairs = query.iter()
+        .map(|(name, value)| format!("{}={}", String::from(js_sys::encode_uri_component(name)), String::from(js_sys::encode_uri_component(value))))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut url = format!("{}{}{}", base, separator, pairs);
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+/// Encodes frames for a text-mode socket.
+#[cfg(feature = "base64")]
+pub fn encode_text(message: &[u8]) -> DerpResult<String> {
+    Ok(BASE64.encode(message))
+}
+
+#[cfg(not(feature = "base64"))]
+pub fn encode_text(_message: &[u8]) -> DerpResult<String> {
+    Err(text_mode_unavailable())
+}
+
+/// Decodes a text-mode message back into its frames.
+#[cfg(feature = "base64")]
+pub fn decode_text(text: &str) -> DerpResult<Vec<u8>> {
+    BASE64.decode(text)
+        .map_err(|e| DerpError::InvalidProtocol(format!("Text message isn't base64: {}", e)))
+}
+
+#[cfg(not(feature = "base64"))]
+pub fn decode_text(_text: &str) -> DerpResult<Vec<u8>> {
+    Err(text_mode_unavailable())
+}
+
+#[cfg(not(feature = "base64"))]
+pub fn text_mode_unavailable() -> DerpError {
+    DerpError::InvalidState("Text mode needs the base64 feature".into())
+}
+
+/// Carries encoded frames between `NetworkState` and a relay.
+#[derive(Clone)]
+pub enum Transport {
+    WebSocket(WebSocket, SocketMode),
+    Loopback(Loopback),
+    Simulated(SimulatedTransport),
+    #[cfg(any(test, feature = "test-support"))]
+    Mock(MockWebSocket),
+}
+
+impl Transport {
+    /// Sends one message: a frame or, with batching, several.
+    pub fn send(&self, message: &[u8]) -> DerpResult<()> {
+        match self {
+            // The slice is handed to JS as a view of wasm memory; the socket
+            // copies it once when queueing.
+            Transport::WebSocket(ws, SocketMode::Binary) => ws.send_with_u8_array(message)
+                .map_err(|e| DerpError::WebSocketError(format!("Failed to send data: {:?}", e))),
+            Transport::WebSocket(ws, SocketMode::Text) => ws.send_with_str(&encode_text(message)?)
+                .map_err(|e| DerpError::WebSocketError(format!("Failed to send data: {:?}", e))),
+            Transport::Loopback(loopback) => loopback.send(message),
+            Transport::Simulated(simulated) => simulated.send(message),
+            #[cfg(any(test, feature = "test-support"))]
+            Transport::Mock(mock) => mock.send(message),
+        }
+    }
+
+    /// Bytes handed to `send` that haven't gone out on the network yet.
+    pub fn buffered_amount(&self) -> usize {
+        match self {
+            Transport::WebSocket(ws, _) => ws.buffered_amount() as usize,
+            Transport::Loopback(_) => 0,
+            Transport::Simulated(simulated) => simulated.inner().buffered_amount(),
+            #[cfg(any(test, feature = "test-support"))]
+            Transport::Mock(mock) => mock.buffered_amount(),
+        }
+    }
+
+    /// The socket underneath, if this is or wraps a WebSocket.
+    pub fn websocket(&self) -> Option<&WebSocket> {
+        match self {
+            Transport::WebSocket(ws, _) => Some(ws),
+            Transport::Loopback(_) => None,
+            Transport::Simulated(simulated) => simulated.inner().websocket(),
+            #[cfg(any(test, feature = "test-support"))]
+            Transport::Mock(_) => None,
+        }
+    }
+
+    pub fn close(&self) {
+        match self {
+            Transport::WebSocket(ws, _) => {
+                let _ = ws.close();
+            }
+            Transport::Loopback(loopback) => loopback.close(),
+            Transport::Simulated(simulated) => simulated.close(),
+            #[cfg(any(test, feature = "test-support"))]
+            Transport::Mock(mock) => mock.close(),
+        }
+    }
+}
+
+/// An in-memory stand-in for the relay, so the protocol, crypto and
+/// `VmNetwork` paths can be exercised end to end without a server. It
+/// answers the handshake, accepts any auth token, and re-encrypts each
+/// packet for the ends it's bound for, as the relay does. With a single
+/// end, packets come straight back to it as if from a peer; `pair` links
+/// two `NetworkState`s instead.
+///
+/// Messages arrive asynchronously, as from a WebSocket, so no handler ever
+/// runs inside the send that caused it.
+#[derive(Clone)]
+pub struct Loopback {
+    hub: Rc<RefCell<Hub>>,
+    end: usize,
+}
+
+struct Hub {
+    ends: Vec<End>,
+    /// Builds the relay's frames.
+    framing: ProtocolState,
+}
+
+struct End {
+    key: PeerKey,
+    /// The connection's session, which the relay would share.
+    crypto: Option<Arc<CryptoState>>,
+    receiver: Option<Receiver>,
+    connected: bool,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback::with_ends(1).remove(0)
+    }
+
+    pub fn pair() -> (Loopback, Loopback) {
+        let mut ends = Loopback::with_ends(2);
+        let second = ends.pop().expect("two ends");
+        (ends.pop().expect("two ends"), second)
+    }
+
+    fn with_ends(count: usize) -> Vec<Loopback> {
+        let hub = Rc::new(RefCell::new(Hub {
+            ends: (0..count).map(|end| End {
+                key: [end as u8 + 1; PEER_KEY_LEN],
+                crypto: None,
+                receiver: None,
+                connected: false,
+            }).collect(),
+            framing: ProtocolState::new(),
+        }));
+        (0..count).map(|end| Loopback { hub: hub.clone(), end }).collect()
+    }
+
+    /// The key other ends know this one by.
+    pub fn key(&self) -> PeerKey {
+        self.hub.borrow().ends[self.end].key
+    }
+
+    /// Connects this end, replacing whatever was attached to it before.
+    pub fn attach(&self, crypto: Arc<CryptoState>, receiver: Receiver) {
+        let mut hub = self.hub.borrow_mut();
+        let end = &mut hub.ends[self.end];
+        end.crypto = Some(crypto);
+        end.receiver = Some(receiver);
+        end.connected = false;
+    }
+
+    pub fn send(&self, message: &[u8]) -> DerpResult<()> {
+        let mut deliveries = Vec::new();
+        let mut rest = message;
+        while !rest.is_empty() {
+            let (frame, tail) = rest.split_at(ProtocolState::frame_len(rest)?);
+            self.hub.borrow_mut().relay(self.end, frame, &mut deliveries)?;
+            rest = tail;
+        }
+        self.deliver(deliveries);
+        Ok(())
+    }
+
+    /// Detaches this end; the others see it leave.
+    pub fn close(&self) {
+        let mut deliveries = Vec::new();
+        {
+            let mut hub = self.hub.borrow_mut();
+            hub.ends[self.end].receiver = None;
+            hub.leave(self.end, &mut deliveries);
+        }
+        self.deliver(deliveries);
+    }
+
+    fn deliver(&self, deliveries: Vec<(usize, Vec<u8>)>) {
+        for (to, message) in deliveries {
+            let hub = self.hub.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                // Looked up on arrival, so an end closed meanwhile gets nothing
+                let receiver = hub.borrow().ends[to].receiver.clone();
+                if let Some(receiver) = receiver {
+                    receiver(&message);
+                }
+            });
+        }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Loopback::new()
+    }
+}
+
+impl Hub {
+    /// Plays the relay's part for one frame from `from`.
+    fn relay(&mut self, from: usize, data: &[u8], out: &mut Vec<(usize, Vec<u8>)>) -> DerpResult<()> {
+        let frame = Frame::parse(data)?;
+        match frame.frame_type {
+            FrameType::ClientInfo => {
+                let info: ClientInfo = decode_handshake(frame.payload)?;
+                let server_info = ServerInfo::new(LOOPBACK_NAME, info.features().to_vec());
+                let format = if info.features().iter().any(|feature| feature == FEATURE_CBOR) {
+                    WireFormat::Cbor
+                } else {
+                    WireFormat::Bincode
+                };
+                out.push((from, self.framing.encode_frame(FrameType::ServerKey, &[0; PEER_KEY_LEN])));
+                out.push((from, self.framing.encode_control_frame(FrameType::ServerInfo, format, &server_info)?));
+            }
+            FrameType::Auth => {
+                out.push((from, self.framing.encode_frame(FrameType::AuthResult, &[0])));
+                self.join(from, out);
+            }
+            // Without a token, the first KeepAlive completes the handshake
+            FrameType::KeepAlive => self.join(from, out),
+            FrameType::Send => {
+                let plaintext = self.open(from, frame.flags, frame.payload)?;
+                for to in self.others(from) {
+                    out.push((to, self.seal(from, to, frame.flags, &plaintext)?));
+                }
+            }
+            FrameType::SendToPeer => {
+                if frame.payload.len() < PEER_KEY_LEN {
+                    return Err(DerpError::InvalidProtocol("Invalid SendToPeer length".into()));
+                }
+                let (key, ciphertext) = frame.payload.split_at(PEER_KEY_LEN);
+                let plaintext = self.open(from, frame.flags, ciphertext)?;
+                if let Some(to) = self.others(from).into_iter().find(|&to| self.ends[to].key == key) {
+                    out.push((to, self.seal(from, to, frame.flags, &plaintext)?));
+                }
+            }
+            FrameType::Goodbye => self.leave(from, out),
+            FrameType::ClosePeer => {
+                let target = self.ends.iter().position(|end| end.connected && end.key == frame.payload);
+                if let Some(target) = target {
+                    self.ends[target].connected = false;
+                    for to in self.others(target) {
+                        out.push((to, self.framing.encode_frame(FrameType::ClosePeer, frame.payload)));
+                    }
+                }
+            }
+            FrameType::WatchConns => {
+                for other in self.others(from) {
+                    out.push((from, self.framing.encode_frame(FrameType::PeerPresent, &self.ends[other].key)));
+                }
+            }
+            FrameType::MacAnnounce => {
+                let mut payload = self.ends[from].key.to_vec();
+                payload.extend_from_slice(frame.payload);
+                for to in self.others(from) {
+                    out.push((to, self.framing.encode_frame(FrameType::MacAnnounce, &payload)));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Marks `from` connected and introduces it and the other ends to each other.
+    fn join(&mut self, from: usize, out: &mut Vec<(usize, Vec<u8>)>) {
+        if std::mem::replace(&mut self.ends[from].connected, true) {
+            return;
+        }
+        for other in self.others(from) {
+            out.push((from, self.framing.encode_frame(FrameType::PeerPresent, &self.ends[other].key)));
+            if other != from {
+                out.push((other, self.framing.encode_frame(FrameType::PeerPresent, &self.ends[from].key)));
+            }
+        }
+    }
+
+    /// Marks `from` disconnected and tells the others it's gone.
+    fn leave(&mut self, from: usize, out: &mut Vec<(usize, Vec<u8>)>) {
+        if !std::mem::replace(&mut self.ends[from].connected, false) {
+            return;
+        }
+        let key = self.ends[from].key;
+        for to in self.others(from) {
+            out.push((to, self.framing.encode_frame(FrameType::PeerGone, &key)));
+        }
+    }
+
+    /// Where frames from `from` go: every other connected end, or `from`
+    /// itself when it's alone.
+    fn others(&self, from: usize) -> Vec<usize> {
+        if self.ends.len() == 1 {
+            return if self.ends[from].connected { vec![from] } else { Vec::new() };
+        }
+        (0..self.ends.len()).filter(|&end| end != from && self.ends[end].connected).collect()
+    }
+
+    fn crypto(&self, end: usize) -> DerpResult<&CryptoState> {
+        self.ends[end].crypto.as_deref()
+            .ok_or_else(|| DerpError::InvalidState("Loopback end not attached".into()))
+    }
+
+    /// Opens a packet from `from`, sealed against the RecvFromPeer header
+    /// it would have arrived under.
+    fn open(&self, from: usize, flags: u8, ciphertext: &[u8]) -> DerpResult<Vec<u8>> {
+        let header = self.framing.frame_header(FrameType::RecvFromPeer, flags, PEER_KEY_LEN + ciphertext.len());
+        let mut plaintext = Vec::new();
+        self.crypto(from)?.decrypt_into_with(cipher_suite_for(flags)?, ciphertext, &header, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Encrypts `plaintext` into a RecvFromPeer frame from `from` to `to`,
+    /// keeping the sender's flags since the plaintext may be compressed, and
+    /// its cipher suite.
+    fn seal(&self, from: usize, to: usize, flags: u8, plaintext: &[u8]) -> DerpResult<Vec<u8>> {
+        let header = self.framing.frame_header(FrameType::RecvFromPeer, flags, PEER_KEY_LEN + plaintext.len() + CIPHERTEXT_OVERHEAD);
+        let mut frame = header.to_vec();
+        frame.extend_from_slice(&self.ends[from].key);
+        self.crypto(to)?.encrypt_into_with(cipher_suite_for(flags)?, plaintext, &header, &mut frame)?;
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::flow::Framing;
+    use crate::network::NetworkState;
+    use futures::StreamExt;
+    use js_sys::Uint8Array;
+    use wasm_bindgen::JsValue;
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    /// Waits until every message in flight has been handled.
+    async fn settle() {
+        let promise = js_sys::Promise::new(&mut |resolve, _| {
+            crate::timer::set_timeout(&resolve, 0);
+        });
+        JsFuture::from(promise).await.unwrap();
+    }
+
+    fn network() -> NetworkState {
+        NetworkState::new(Arc::new(CryptoState::new().unwrap()))
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_single_end_gets_its_packets_back() {
+        let mut network = network();
+        let mut packets = network.events().subscribe(EventKind::Packet, 4);
+        network.connect_loopback(Loopback::new()).unwrap();
+        assert!(network.send_packet(b"early").is_err());
+
+        settle().await;
+        assert!(network.is_connected());
+        network.send_packet(b"hello").unwrap();
+        settle().await;
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(Uint8Array::new(&packet).to_vec(), b"hello");
+        assert_eq!(network.get_stats().packets_received, 1);
+
+        let timings = network.timings();
+        assert_eq!(timings.handshake.count, 1);
+        assert_eq!((timings.encrypt.count, timings.decrypt.count), (1, 1));
+        assert!(timings.dispatch.count >= 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_query_goes_before_the_fragment() {
+        let query = BTreeMap::from([("token".to_string(), "a b&c".to_string()), ("v".to_string(), "2".to_string())]);
+        assert_eq!(with_query("wss://relay/derp", &query), "wss://relay/derp?token=a%20b%26c&v=2");
+        assert_eq!(with_query("wss://relay/derp?x=1#top", &query), "wss://relay/derp?x=1&token=a%20b%26c&v=2#top");
+        assert_eq!(with_query("wss://relay/derp?", &BTreeMap::new()), "wss://relay/derp?");
+    }
+
+    #[cfg(feature = "base64")]
+    #[wasm_bindgen_test]
+    fn test_text_mode_roundtrip() {
+        assert_eq!(decode_text(&encode_text(&[1, 2, 3]).unwrap()).unwrap(), vec![1, 2, 3]);
+        assert!(decode_text("not base64!").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_pair_links_two_networks() {
+        let (first_end, second_end) = Loopback::pair();
+        let second_key = second_end.key();
+        let mut first = network();
+        let mut second = network();
+        let mut peers = first.events().subscribe(EventKind::PeerPresent, 4);
+        let mut packets = second.events().subscribe(EventKind::Packet, 4);
+        let mut gone = second.events().subscribe(EventKind::PeerGone, 4);
+        first.connect_loopback(first_end).unwrap();
+        second.connect_loopback(second_end).unwrap();
+        settle().await;
+
+        assert!(peers.next().await.is_some());
+        first.send_to_peer(&second_key, None, Framing::Ip, b"direct").unwrap();
+        first.send_packet(b"to all").unwrap();
+        settle().await;
+        let received: Vec<JsValue> = vec![packets.next().await.unwrap(), packets.next().await.unwrap()];
+        let received: Vec<Vec<u8>> = received.iter().map(|packet| Uint8Array::new(packet).to_vec()).collect();
+        assert_eq!(received, vec![b"direct".to_vec(), b"to all".to_vec()]);
+
+        first.close();
+        settle().await;
+        assert!(gone.next().await.is_some());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_packets_are_attributed_by_the_sending_end() {
+        let (first_end, second_end) = Loopback::pair();
+        let first_key = first_end.key();
+        let mut first = network();
+        let mut second = network();
+        second.set_allowed_peers(Some(std::collections::HashSet::from([[9; PEER_KEY_LEN]])));
+        first.connect_loopback(first_end).unwrap();
+        second.connect_loopback(second_end).unwrap();
+        settle().await;
+
+        first.send_packet(b"let me in").unwrap();
+        settle().await;
+        let stats = second.acl_stats();
+        assert_eq!((stats.peers[0].peer.clone(), stats.peers[0].inbound), (crate::protocol::hex_encode(&first_key), 1));
+        assert_eq!(second.get_stats().packets_received, 0);
+    }
+}
@@ -0,0 +1,1805 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{Function, Uint8Array};
+use serde::{Serialize, Deserialize};
+use tsify::Tsify;
+use web_sys::{MessageChannel, MessagePort};
+use std::cell::{Cell, RefCell};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use crate::arp::{ArpResponder, ETHERTYPE_ARP};
+use crate::config::DerpConfig;
+use crate::control;
+use crate::dhcp::{DhcpServer, DHCP_CLIENT_PORT, DHCP_SERVER_PORT};
+use crate::dhcpv6::{Dhcpv6Server, ALL_DHCP_SERVERS, DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT};
+use crate::dns::{self, DnsProxy, DNS_PORT};
+use crate::error::DerpError;
+use crate::ethernet::{self, Cast};
+use crate::fetch::FetchBackend;
+use crate::firewall::{Direction, Firewall, FirewallConfig, FirewallHits};
+use crate::flow::Framing;
+use crate::forward::{GuestConnection, PortStream};
+use crate::httpd::HttpFileServer;
+use crate::icmp;
+use crate::ip::{self, Ipv4Packet, Ipv6Packet, UdpDatagram, PROTO_UDP};
+use crate::mdns::{MdnsResponder, MDNS_GROUP, MDNS_MAC, MDNS_PORT, MDNS_TTL};
+use crate::meter::{TrafficMeter, TrafficStats};
+use crate::nat::{NatGateway, NatHandle, NatStream, RelayBackend, Verdict};
+use crate::ndp::{self, NdpResponder, ETHERTYPE_IPV6};
+use crate::network::{NetworkState, StatsCounters};
+use crate::pmtu;
+use crate::publish::PublishedPort;
+use crate::ring::{SharedRing, SharedRings};
+use crate::shared::Shared;
+use crate::shape::{Link, ShapingConfig};
+use crate::snapshot::{self, NicSnapshot};
+use crate::socks::SocksBackend;
+use crate::switch::{Port, SwitchHandle};
+use crate::tftp::TftpServer;
+use crate::timer;
+use crate::trace::{FrameSummary, Outcome, Trace, Tracer, DEFAULT_TRACE_CAPACITY};
+
+#[wasm_bindgen]
+extern "C" {
+    /// v86's `BusConnector`, the object its network adapters are given.
+    pub type BusConnector;
+
+    #[wasm_bindgen(method)]
+    fn register(this: &BusConnector, name: &str, handler: &Function, this_value: &JsValue);
+
+    #[wasm_bindgen(method)]
+    fn send(this: &BusConnector, name: &str, value: &JsValue);
+}
+
+/// Per-NIC settings for `DerpNetwork.createVmNetwork`, for guests with more
+/// than one interface.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Tsify)]
+#[serde(default, rename_all = "camelCase")]
+#[tsify(from_wasm_abi)]
+pub struct VmNetworkOptions {
+    /// Overrides the instance's MTU for this NIC.
+    #[tsify(optional)]
+    pub mtu: Option<u16>,
+    /// 802.1Q VLAN id this NIC's packets are tagged with on the relay. NICs
+    /// sharing a connection need distinct ids to receive anything but bare
+    /// IP, which goes to the first NIC without one.
+    #[tsify(optional)]
+    pub vlan: Option<u16>,
+    /// Overrides the address handed to the guest over DHCP, so VMs on one
+    /// `VirtualSwitch` don't collide.
+    #[tsify(optional, type = "string")]
+    pub guest_ip: Option<Ipv4Addr>,
+    /// Start in promiscuous mode; see `setPromiscuous`.
+    #[tsify(optional)]
+    pub promiscuous: bool,
+}
+
+/// A virtual NIC. The state lives behind an `Rc` so bus handlers registered
+/// with the emulator can reach it.
+#[wasm_bindgen]
+pub struct VmNetwork {
+    nic: Rc<Nic>,
+}
+
+struct Nic {
+    /// For timers that need to reach the NIC later without keeping it alive.
+    this: Weak<Nic>,
+    network: Shared<NetworkState>,
+    stats: Arc<StatsCounters>,
+    mtu: u16,
+    vlan: Option<u16>,
+    mac_address: Cell<[u8; 6]>,
+    gateway_mac: [u8; 6],
+    /// Deliver frames addressed to other MACs too.
+    promiscuous: Cell<bool>,
+    receive_callback: RefCell<Option<Function>>,
+    /// Set by `attachToBus`: the bus and its `net{id}-receive` event name.
+    bus: RefCell<Option<(BusConnector, String)>>,
+    /// Set by `enableSharedRings`: frames to and from the emulator.
+    shared_rings: RefCell<Option<(SharedRing, SharedRing)>>,
+    /// Set by `VirtualSwitch.connect`: the switch and this NIC's port on it.
+    switch: RefCell<Option<(SwitchHandle, u32)>>,
+    firewall: RefCell<Firewall>,
+    /// Set by `setShaping`: link emulation for frames from and to the guest.
+    egress: RefCell<Option<Link>>,
+    ingress: RefCell<Option<Link>>,
+    /// Frame summaries kept for `getTrace`, and the `onTrace` hook.
+    tracer: RefCell<Tracer>,
+    meter: RefCell<TrafficMeter>,
+    trace_hook: RefCell<Option<Function>>,
+    arp: RefCell<ArpResponder>,
+    dhcp: RefCell<DhcpServer>,
+    /// Serves files the page provides, for network boot.
+    tftp: RefCell<TftpServer>,
+    ndp: NdpResponder,
+    /// Set when the config enables stateless DHCPv6.
+    dhcpv6: Option<Dhcpv6Server>,
+    /// Set when the config names a DoH endpoint.
+    dns: Option<Rc<DnsProxy>>,
+    /// Set when the config names an mDNS hostname for the gateway.
+    mdns: Option<MdnsResponder>,
+    /// Set in NAT mode, or to run the file server: terminates guest TCP/UDP
+    /// instead of relaying raw IP.
+    nat: Option<RefCell<NatGateway>>,
+    /// Whether `nat` takes every guest flow, as in NAT mode, or only those
+    /// for the file server.
+    nat_egress: bool,
+    /// Set when the config names a file server address.
+    file_server: Option<Rc<HttpFileServer>>,
+    /// Pending `setTimeout` that next polls the NAT gateway, and when it fires.
+    nat_timer: Cell<Option<(i32, f64)>>,
+}
+
+#[wasm_bindgen]
+impl VmNetwork {
+    #[wasm_bindgen(js_name = setReceiveCallback)]
+    pub fn set_receive_callback(&self, callback: Function) {
+        *self.nic.receive_callback.borrow_mut() = Some(callback);
+    }
+
+    /// Plugs the NIC into a v86 emulator the way its built-in network
+    /// adapters do: frames published on `net{id}-send` go out over the relay,
+    /// received frames are sent on `net{id}-receive`, and `net{id}-mac`
+    /// updates the guest MAC.
+    #[wasm_bindgen(js_name = attachToBus)]
+    pub fn attach_to_bus(&self, bus: BusConnector, id: u32) {
+        let nic = self.nic.clone();
+        let send_handler = Closure::wrap(Box::new(move |frame: Uint8Array| {
+            if let Err(e) = nic.send_frame(&frame.to_vec()) {
+                log::warn!("Failed to send a frame from the bus: {:?}", e);
+            }
+        }) as Box<dyn FnMut(Uint8Array)>);
+
+        let nic = self.nic.clone();
+        let mac_handler = Closure::wrap(Box::new(move |mac: String| {
+            match parse_mac(&mac) {
+                Some(mac) => nic.set_mac(mac),
+                None => log::warn!("Invalid MAC address: {}", mac),
+            }
+        }) as Box<dyn FnMut(String)>);
+
+        bus.register(&format!("net{}-send", id), send_handler.as_ref().unchecked_ref(), &JsValue::UNDEFINED);
+        bus.register(&format!("net{}-mac", id), mac_handler.as_ref().unchecked_ref(), &JsValue::UNDEFINED);
+        send_handler.forget();
+        mac_handler.forget();
+
+        *self.nic.bus.borrow_mut() = Some((bus, format!("net{}-receive", id)));
+    }
+
+    /// Switches packet exchange to a pair of SharedArrayBuffer rings of
+    /// `capacity` bytes each (a power of two). Received frames are written to
+    /// `toVm` instead of going through the receive callback; the emulator
+    /// writes outgoing frames to `fromVm` and calls `pollSharedRing`.
+    /// Requires a cross-origin isolated page.
+    #[wasm_bindgen(js_name = enableSharedRings)]
+    pub fn enable_shared_rings(&self, capacity: u32) -> Result<SharedRings, JsValue> {
+        let to_vm = SharedRing::new(capacity)?;
+        let from_vm = SharedRing::new(capacity)?;
+        let rings = SharedRings::new(&to_vm, &from_vm);
+        *self.nic.shared_rings.borrow_mut() = Some((to_vm, from_vm));
+        Ok(rings)
+    }
+
+    /// Sends every frame queued in the `fromVm` ring. Returns how many were read.
+    #[wasm_bindgen(js_name = pollSharedRing)]
+    pub fn poll_shared_ring(&self) -> Result<u32, JsValue> {
+        self.nic.poll_shared_ring()
+    }
+
+    /// Called by v86 when the VM sends a network packet
+    #[wasm_bindgen(js_name = sendPacket)]
+    pub fn send_packet(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.nic.send_frame(data)
+    }
+
+    /// Called by the network stack when a packet is received from the network
+    #[wasm_bindgen(js_name = receivePacket)]
+    pub fn receive_packet(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.nic.receive_packet(data)
+    }
+
+    /// Opens a TCP connection from the gateway to a service in the guest,
+    /// such as SSH, and returns a MessagePort carrying it. Post Uint8Arrays
+    /// to send and receive them back; `null` in either direction means the
+    /// stream closed. Requires NAT mode, whose stack terminates the
+    /// connection.
+    #[wasm_bindgen(js_name = forwardPort)]
+    pub fn forward_port(&self, guest_ip: &str, guest_port: u16) -> Result<MessagePort, JsValue> {
+        let guest_ip: Ipv4Addr = guest_ip.parse()
+            .map_err(|_| DerpError::InvalidState("Invalid guest IP address".into()))?;
+        self.nic.forward_port(SocketAddrV4::new(guest_ip, guest_port))
+    }
+
+    /// Opens a TCP connection from the gateway to `port` on the guest's
+    /// DHCP address, with `send`/`ondata` on the page's side. Requires NAT
+    /// mode, like `forwardPort`.
+    #[wasm_bindgen(js_name = connectToGuest)]
+    pub fn connect_to_guest(&self, port: u16) -> Result<GuestConnection, JsValue> {
+        let guest_ip = self.nic.dhcp.borrow().lease_ip();
+        let mut connection = None;
+        self.nic.connect(SocketAddrV4::new(guest_ip, port), |handle| {
+            let (opened, stream) = GuestConnection::open(handle);
+            connection = Some(opened);
+            stream
+        })?;
+        Ok(connection.expect("connect opens the stream"))
+    }
+
+    /// Publishes `port` on the guest's DHCP address through the WebSocket
+    /// relay at `url`, bridging each client it pairs with to a new
+    /// connection into the guest. See `PublishedPort` for what the relay
+    /// is expected to do. Requires NAT mode, like `forwardPort`.
+    #[wasm_bindgen(js_name = publishPort)]
+    pub fn publish_port(&self, url: &str, port: u16) -> Result<PublishedPort, JsValue> {
+        if !self.nic.can_connect() {
+            return Err(DerpError::InvalidState("Publishing a port requires NAT mode".into()).into());
+        }
+        let guest_ip = self.nic.dhcp.borrow().lease_ip();
+        Ok(PublishedPort::new(url, SocketAddrV4::new(guest_ip, port), self.handle())?)
+    }
+
+    /// Replaces the firewall rules applied to every frame the guest sends
+    /// or receives, including those the gateway answers itself. Resets the
+    /// hit counters.
+    #[wasm_bindgen(js_name = setFirewall)]
+    pub fn set_firewall(&self, config: FirewallConfig) -> Result<(), JsValue> {
+        *self.nic.firewall.borrow_mut() = Firewall::new(&config)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = getFirewallHits)]
+    pub fn get_firewall_hits(&self) -> FirewallHits {
+        self.nic.firewall.borrow().hits()
+    }
+
+    /// Emulates a constrained link between the guest and the network:
+    /// limited bandwidth, added latency and jitter, and random loss, set
+    /// separately for each direction. Frames still queued under the
+    /// previous settings are dropped.
+    #[wasm_bindgen(js_name = setShaping)]
+    pub fn set_shaping(&self, config: ShapingConfig) -> Result<(), JsValue> {
+        let egress = config.egress.map(Link::new).transpose()?;
+        let ingress = config.ingress.map(Link::new).transpose()?;
+        self.nic.replace_link(Direction::Outbound, egress);
+        self.nic.replace_link(Direction::Inbound, ingress);
+        Ok(())
+    }
+
+    /// Makes `data` (an ArrayBuffer or typed array, copied) available to
+    /// the guest over TFTP from the gateway under `name`.
+    #[wasm_bindgen(js_name = addTftpFile)]
+    pub fn add_tftp_file(&self, name: &str, data: &JsValue) {
+        let data = Uint8Array::new(data).to_vec();
+        self.nic.tftp.borrow_mut().add_file(name, data);
+    }
+
+    #[wasm_bindgen(js_name = removeTftpFile)]
+    pub fn remove_tftp_file(&self, name: &str) -> bool {
+        self.nic.tftp.borrow_mut().remove_file(name)
+    }
+
+    /// Has the gateway's DNS proxy answer `name` (e.g. "myapp.test") with
+    /// `address`, an IPv4 or IPv6 address such as a bridged peer VM's.
+    /// One address of each family is kept per name. Needs `dohEndpoint`.
+    #[wasm_bindgen(js_name = addDnsHost)]
+    pub fn add_dns_host(&self, name: &str, address: &str) -> Result<(), JsValue> {
+        let proxy = self.nic.dns.as_ref()
+            .ok_or_else(|| DerpError::InvalidState("The DNS proxy is off; set dohEndpoint".into()))?;
+        let address = address.parse()
+            .map_err(|_| DerpError::InvalidState(format!("Invalid address: {}", address)))?;
+        Ok(proxy.add_host(name, address)?)
+    }
+
+    /// Stops answering `name` locally. Returns false if it wasn't added.
+    #[wasm_bindgen(js_name = removeDnsHost)]
+    pub fn remove_dns_host(&self, name: &str) -> bool {
+        self.nic.dns.as_ref().is_some_and(|proxy| proxy.remove_host(name))
+    }
+
+    /// Serves `data` (an ArrayBuffer or typed array, copied) from the file
+    /// server, so `wget http://<fileServerIp>/<name>` in the guest fetches
+    /// it. Needs `fileServerIp`.
+    #[wasm_bindgen(js_name = addHttpFile)]
+    pub fn add_http_file(&self, name: &str, data: &JsValue) -> Result<(), JsValue> {
+        let server = self.nic.file_server.as_ref()
+            .ok_or_else(|| DerpError::InvalidState("The file server is off; set fileServerIp".into()))?;
+        server.add_file(name, Uint8Array::new(data).to_vec());
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = removeHttpFile)]
+    pub fn remove_http_file(&self, name: &str) -> bool {
+        self.nic.file_server.as_ref().is_some_and(|server| server.remove_file(name))
+    }
+
+    /// Offers `name` as the boot file in DHCP replies so a guest that
+    /// network-boots (PXE) fetches it over TFTP. Null stops offering one.
+    #[wasm_bindgen(js_name = setBootFile)]
+    pub fn set_boot_file(&self, name: Option<String>) -> Result<(), JsValue> {
+        if !self.nic.dhcp.borrow_mut().set_boot_file(name) {
+            return Err(DerpError::InvalidState("Boot file name too long".into()).into());
+        }
+        Ok(())
+    }
+
+    /// In promiscuous mode the guest receives unicast frames addressed to
+    /// other MACs: those the relay sends this NIC's way, and every frame
+    /// crossing its `VirtualSwitch`, as on a hub. Needed by guests that
+    /// capture traffic or bridge it on. Can be changed at any time.
+    #[wasm_bindgen(js_name = setPromiscuous)]
+    pub fn set_promiscuous(&self, enabled: bool) {
+        self.nic.promiscuous.set(enabled);
+    }
+
+    #[wasm_bindgen(js_name = isPromiscuous)]
+    pub fn is_promiscuous(&self) -> bool {
+        self.nic.promiscuous.get()
+    }
+
+    /// Starts or stops keeping summaries of the most recent frames the
+    /// guest sends and receives, with what became of each, for `getTrace`.
+    #[wasm_bindgen(js_name = setTracing)]
+    pub fn set_tracing(&self, enabled: bool) {
+        self.nic.tracer.borrow_mut().set_recording(enabled);
+    }
+
+    #[wasm_bindgen(js_name = getTrace)]
+    pub fn get_trace(&self) -> Trace {
+        self.nic.tracer.borrow().trace()
+    }
+
+    #[wasm_bindgen(js_name = clearTrace)]
+    pub fn clear_trace(&self) {
+        self.nic.tracer.borrow_mut().clear();
+    }
+
+    /// Calls `callback` with the summary of every frame crossing the NIC,
+    /// whether or not tracing is on. Null removes it.
+    #[wasm_bindgen(js_name = onTrace)]
+    pub fn on_trace(&self, callback: Option<Function>) {
+        *self.nic.trace_hook.borrow_mut() = callback;
+    }
+
+    /// What the guest has sent and received, broken down by ethertype, IP
+    /// protocol and TCP/UDP port. Counts frames the firewall passed, after
+    /// shaping on the way in.
+    #[wasm_bindgen(js_name = getTrafficStats)]
+    pub fn get_traffic_stats(&self) -> TrafficStats {
+        self.nic.meter.borrow().stats()
+    }
+
+    #[wasm_bindgen(js_name = resetTrafficStats)]
+    pub fn reset_traffic_stats(&self) {
+        self.nic.meter.borrow_mut().reset();
+    }
+
+    #[wasm_bindgen(js_name = getMacAddress)]
+    pub fn get_mac_address(&self) -> Uint8Array {
+        Uint8Array::from(&self.nic.mac_address.get()[..])
+    }
+
+    #[wasm_bindgen(js_name = getGatewayMac)]
+    pub fn get_gateway_mac(&self) -> Uint8Array {
+        Uint8Array::from(&self.nic.gateway_mac[..])
+    }
+
+    #[wasm_bindgen(js_name = getMtu)]
+    pub fn get_mtu(&self) -> u16 {
+        self.nic.mtu
+    }
+
+    /// Saves the adapter's settings and learned tables to keep alongside a
+    /// v86 snapshot; see `NicSnapshot` for what's left out.
+    #[wasm_bindgen(js_name = serializeState)]
+    pub fn serialize_state(&self) -> Result<Vec<u8>, JsValue> {
+        Ok(snapshot::encode(&self.nic.save())?)
+    }
+
+    /// Applies state saved by `serializeState`, replacing the current
+    /// settings. Nothing changes if the state can't be read.
+    #[wasm_bindgen(js_name = restoreState)]
+    pub fn restore_state(&self, bytes: &[u8]) -> Result<(), JsValue> {
+        let state = snapshot::decode(bytes)?;
+        self.nic.restore(&state)
+    }
+}
+
+impl VmNetwork {
+    pub fn new(network: Shared<NetworkState>, mac_address: &[u8], gateway_mac: [u8; 6], config: &DerpConfig) -> Result<VmNetwork, JsValue> {
+        VmNetwork::new_on_vlan(network, mac_address, gateway_mac, config, None)
+    }
+
+    /// Like `new`, for one of several NICs sharing the relay connection.
+    pub fn new_on_vlan(
+        network: Shared<NetworkState>,
+        mac_address: &[u8],
+        gateway_mac: [u8; 6],
+        config: &DerpConfig,
+        vlan: Option<u16>,
+    ) -> Result<VmNetwork, JsValue> {
+        if mac_address.len() != 6 {
+            return Err(DerpError::InvalidState("Invalid MAC address length".into()).into());
+        }
+
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(mac_address);
+
+        let stats = {
+            let mut network = NetworkState::lock(&network);
+            network.add_local_mac(mac)?;
+            network.stats()
+        };
+        let relay = if config.relay_routes.is_empty() {
+            RelayBackend::all()
+        } else {
+            let routes = config.relay_routes.iter()
+                .map(|route| ip::parse_cidr(route).ok_or_else(|| DerpError::InvalidState("Invalid relay route".into())))
+                .collect::<Result<_, _>>()?;
+            RelayBackend::new(routes)
+        };
+        let ndp = NdpResponder::new(gateway_mac, config.ipv6_prefix, config.mtu);
+        let mut arp = ArpResponder::new(config.gateway_ip, gateway_mac);
+        let file_server = config.file_server_ip.map(|address| {
+            arp.add_alias(address);
+            Rc::new(HttpFileServer::new(address))
+        });
+        // As over DHCP, the gateway proxies DNS unless the config names servers
+        let ipv6_dns = match config.ipv6_dns_servers.is_empty() {
+            true if config.doh_endpoint.is_some() => ndp.gateway_address().into_iter().collect(),
+            true => Vec::new(),
+            false => config.ipv6_dns_servers.clone(),
+        };
+
+        Ok(VmNetwork {
+            nic: Rc::new_cyclic(|nic: &Weak<Nic>| Nic {
+                this: nic.clone(),
+                network,
+                stats,
+                mtu: config.mtu,
+                vlan,
+                mac_address: Cell::new(mac),
+                gateway_mac,
+                promiscuous: Cell::new(false),
+                receive_callback: RefCell::new(None),
+                bus: RefCell::new(None),
+                shared_rings: RefCell::new(None),
+                switch: RefCell::new(None),
+                firewall: RefCell::new(Firewall::default()),
+                egress: RefCell::new(None),
+                ingress: RefCell::new(None),
+                tracer: RefCell::new(Tracer::new(DEFAULT_TRACE_CAPACITY)),
+                meter: RefCell::new(TrafficMeter::default()),
+                trace_hook: RefCell::new(None),
+                arp: RefCell::new(arp),
+                dhcp: RefCell::new(DhcpServer::from_config(config)),
+                tftp: RefCell::new(TftpServer::new(config.mtu)),
+                ndp: ndp.with_dns(ipv6_dns.clone(), config.dhcpv6),
+                dhcpv6: config.dhcpv6.then(|| Dhcpv6Server::new(gateway_mac, ipv6_dns)),
+                dns: config.doh_endpoint.clone().map(|endpoint| Rc::new(DnsProxy::new(endpoint))),
+                mdns: config.mdns_hostname.as_deref().map(|hostname| {
+                    let mut responder = MdnsResponder::new();
+                    responder.add(hostname, config.gateway_ip);
+                    responder
+                }),
+                nat: (config.nat || file_server.is_some()).then(|| {
+                    let nic = nic.clone();
+                    let wake = Rc::new(move || {
+                        if let Some(nic) = nic.upgrade() {
+                            nic.schedule_nat_poll(0);
+                        }
+                    });
+                    let mut gateway = NatGateway::new(config.gateway_ip, config.netmask, config.mtu, wake);
+                    if let Some(server) = &file_server {
+                        gateway.add_backend(server.clone());
+                    }
+                    if config.nat {
+                        if config.fetch_egress {
+                            gateway.add_backend(Rc::new(FetchBackend::new(config.fetch_proxy.clone(), config.fetch_upgrade_https)));
+                        }
+                        if let Some(socks) = &config.socks {
+                            gateway.add_backend(Rc::new(SocksBackend::new(socks.clone())));
+                        }
+                        gateway.add_backend(Rc::new(relay));
+                    }
+                    RefCell::new(gateway)
+                }),
+                nat_egress: config.nat,
+                file_server,
+                nat_timer: Cell::new(None),
+            }),
+        })
+    }
+
+    pub(crate) fn handle(&self) -> NicHandle {
+        NicHandle(Rc::downgrade(&self.nic))
+    }
+}
+
+impl Drop for Nic {
+    fn drop(&mut self) {
+        let _ = NetworkState::lock(&self.network).remove_local_mac(self.mac_address.get());
+    }
+}
+
+/// A reference to a NIC that doesn't keep it alive, for routing packets to
+/// it from outside.
+#[derive(Clone, Default)]
+pub struct NicHandle(Weak<Nic>);
+
+impl NicHandle {
+    pub fn is_alive(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+
+    pub fn vlan(&self) -> Option<u16> {
+        self.0.upgrade().and_then(|nic| nic.vlan)
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.0.upgrade().map_or([0; 6], |nic| nic.mac_address.get())
+    }
+
+    pub fn is_promiscuous(&self) -> bool {
+        self.0.upgrade().is_some_and(|nic| nic.promiscuous.get())
+    }
+
+    /// See `Tracer::usage`.
+    pub fn trace_usage(&self) -> (usize, usize) {
+        self.0.upgrade().map_or((0, 0), |nic| nic.tracer.borrow().usage())
+    }
+
+    /// Delivers an IP packet from the relay to the guest.
+    pub fn receive_packet(&self, packet: &[u8]) -> Result<(), JsValue> {
+        match self.0.upgrade() {
+            Some(nic) => nic.receive_packet(packet),
+            None => Ok(()),
+        }
+    }
+
+    /// Opens a gateway connection to `guest`, as `VmNetwork.forwardPort` does.
+    pub fn connect(&self, guest: SocketAddrV4, open: impl FnOnce(&NatHandle) -> Box<dyn NatStream>) -> Result<(), JsValue> {
+        match self.0.upgrade() {
+            Some(nic) => nic.connect(guest, open),
+            None => Err(DerpError::InvalidState("NIC is gone".into()).into()),
+        }
+    }
+
+    pub fn attach_to_switch(&self, port: Option<(SwitchHandle, u32)>) {
+        if let Some(nic) = self.0.upgrade() {
+            *nic.switch.borrow_mut() = port;
+        }
+    }
+
+    /// Delivers a whole Ethernet frame to the guest unchanged. Unicast for
+    /// other MACs is filtered out, as by a real NIC, unless promiscuous.
+    pub fn receive_frame(&self, frame: &[u8]) -> Result<(), JsValue> {
+        let Some(nic) = self.0.upgrade() else { return Ok(()) };
+        if frame.len() < 14 || frame.len() > nic.mtu as usize + 14 {
+            return Err(DerpError::InvalidState("Invalid ethernet frame".into()).into());
+        }
+        let cast = ethernet::cast(&frame[..6]);
+        if cast == Cast::Unicast && frame[..6] != nic.mac_address.get() && !nic.promiscuous.get() {
+            nic.trace(Direction::Inbound, Outcome::Filtered, frame);
+            return Ok(());
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if !nic.firewall.borrow_mut().allows(Direction::Inbound, ethertype, &frame[14..]) {
+            nic.trace(Direction::Inbound, Outcome::Firewalled, frame);
+            return Ok(());
+        }
+        nic.stats.record_cast_received(cast);
+        nic.deliver(frame)
+    }
+}
+
+impl Nic {
+    fn save(&self) -> NicSnapshot {
+        let profile = |direction| self.link(direction).borrow().as_ref().map(|link: &Link| link.profile().clone());
+        let firewall = self.firewall.borrow();
+        NicSnapshot {
+            mac_address: self.mac_address.get(),
+            promiscuous: self.promiscuous.get(),
+            arp_table: self.arp.borrow().entries(),
+            firewall: firewall.config().clone(),
+            firewall_hits: firewall.hits(),
+            shaping: ShapingConfig {
+                egress: profile(Direction::Outbound),
+                ingress: profile(Direction::Inbound),
+            },
+            boot_file: self.dhcp.borrow().boot_file().map(str::to_string),
+        }
+    }
+
+    fn restore(&self, snapshot: &NicSnapshot) -> Result<(), JsValue> {
+        // Everything that can fail goes first, so a bad snapshot changes nothing
+        let mut firewall = Firewall::new(&snapshot.firewall)?;
+        firewall.restore_hits(&snapshot.firewall_hits);
+        let egress = snapshot.shaping.egress.clone().map(Link::new).transpose()?;
+        let ingress = snapshot.shaping.ingress.clone().map(Link::new).transpose()?;
+        if !self.dhcp.borrow_mut().set_boot_file(snapshot.boot_file.clone()) {
+            return Err(DerpError::InvalidState("Boot file name too long".into()).into());
+        }
+
+        *self.firewall.borrow_mut() = firewall;
+        self.replace_link(Direction::Outbound, egress);
+        self.replace_link(Direction::Inbound, ingress);
+        self.arp.borrow_mut().restore(&snapshot.arp_table);
+        self.promiscuous.set(snapshot.promiscuous);
+        self.set_mac(snapshot.mac_address);
+        Ok(())
+    }
+
+    /// Follows a MAC change from the emulator, so peers learn where the
+    /// guest's frames should go now.
+    fn set_mac(&self, mac: [u8; 6]) {
+        let old = self.mac_address.replace(mac);
+        if old == mac {
+            return;
+        }
+        let mut network = NetworkState::lock(&self.network);
+        let result = network.remove_local_mac(old).and_then(|_| network.add_local_mac(mac));
+        if let Err(e) = result {
+            log::warn!("Failed to move the relay route to the new MAC: {}", e);
+        }
+    }
+
+    fn poll_shared_ring(self: &Rc<Self>) -> Result<u32, JsValue> {
+        let rings = self.shared_rings.borrow();
+        let (_, from_vm) = rings.as_ref()
+            .ok_or_else(|| DerpError::InvalidState("Shared rings not enabled".into()))?;
+
+        let mut frame = Vec::with_capacity(self.mtu as usize + 14);
+        let mut count = 0;
+        while from_vm.pop(&mut frame)? {
+            self.send_frame(&frame)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn send_frame(self: &Rc<Self>, data: &[u8]) -> Result<(), JsValue> {
+        if !self.shape(Direction::Outbound, data) {
+            return Ok(());
+        }
+        self.transmit_frame(data)
+    }
+
+    fn transmit_frame(self: &Rc<Self>, data: &[u8]) -> Result<(), JsValue> {
+        // Validate ethernet frame
+        if data.len() < 14 {
+            return Err(DerpError::InvalidState("Invalid ethernet frame".into()).into());
+        }
+
+        // Extract destination MAC and ethertype
+        let dst_mac = &data[0..6];
+        let ethertype = u16::from_be_bytes([data[12], data[13]]);
+        if !self.firewall.borrow_mut().allows(Direction::Outbound, ethertype, &data[14..]) {
+            self.trace(Direction::Outbound, Outcome::Firewalled, data);
+            return Ok(());
+        }
+        self.trace(Direction::Outbound, Outcome::Passed, data);
+        self.meter.borrow_mut().record(Direction::Outbound, data);
+
+        let cast = ethernet::cast(dst_mac);
+        self.stats.record_cast_sent(cast);
+
+        // On a switch, everything not for the gateway is other guests'
+        // business; broadcasts reach both
+        let switch = self.switch.borrow().clone();
+        if dst_mac != self.gateway_mac {
+            if let Some((switch, port)) = &switch {
+                switch.forward(Port::Nic(*port), data)?;
+            }
+        }
+
+        // Unicast for anyone but the gateway is for a guest on another page,
+        // unless the switch already took care of it
+        if cast == Cast::Unicast && dst_mac != self.gateway_mac {
+            return match switch {
+                Some(_) => Ok(()),
+                None => self.relay_to_peer(data),
+            };
+        }
+        
+        // ARP, NDP and gateway services are answered locally. Other unicast
+        // IP goes to the relay as a packet, and other broadcast and multicast
+        // as a whole frame so the far end delivers it the same way.
+        match ethertype {
+            ETHERTYPE_ARP => {
+                let reply = self.arp.borrow_mut().handle(&data[14..]);
+                match reply {
+                    Some(reply) => self.deliver_ethernet(reply.target_mac, ETHERTYPE_ARP, &reply.to_bytes()),
+                    None if cast != Cast::Unicast => self.relay_frame(data),
+                    None => Ok(()),
+                }
+            }
+            ETHERTYPE_IPV4 => {
+                if let Some(result) = self.handle_local_ipv4(&data[14..]) {
+                    return result;
+                }
+                if cast != Cast::Unicast {
+                    return self.relay_frame(data);
+                }
+                if let Some(nat) = self.nat.as_ref().filter(|_| self.terminates(&data[14..])) {
+                    let verdict = nat.borrow_mut().route(&data[14..], js_sys::Date::now());
+                    if verdict == Verdict::Terminated {
+                        self.poll_nat();
+                        return Ok(());
+                    }
+                }
+                self.relay(&data[14..])
+            }
+            ETHERTYPE_IPV6 => {
+                let mut sender_mac = [0u8; 6];
+                sender_mac.copy_from_slice(&data[6..12]);
+                if let Some(reply) = self.ndp.handle(&data[14..], sender_mac) {
+                    return self.deliver_ethernet(reply.dst_mac, ETHERTYPE_IPV6, &reply.packet);
+                }
+                if icmp::is_echo_request_v6(&data[14..], &self.ndp.addresses()) {
+                    return self.answer_ping(icmp::echo_reply_v6(&data[14..]), ETHERTYPE_IPV6);
+                }
+                if let Some(result) = self.handle_local_ipv6(&data[14..], sender_mac) {
+                    return result;
+                }
+                if cast != Cast::Unicast {
+                    return self.relay_frame(data);
+                }
+                self.relay(&data[14..])
+            }
+            _ if cast != Cast::Unicast => self.relay_frame(data),
+            _ => Ok(())
+        }
+    }
+
+    /// Sends a broadcast or multicast frame over the relay, for every NIC at
+    /// the far end. Best effort: without a connection there's no one to
+    /// hear it.
+    fn relay_frame(&self, frame: &[u8]) -> Result<(), JsValue> {
+        let mut network = NetworkState::lock(&self.network);
+        if !network.is_connected() || frame.len() > self.mtu as usize + 14 {
+            return Ok(());
+        }
+        network.send_packet_on_vlan(self.vlan, Framing::Ethernet, frame).map_err(JsValue::from)
+    }
+
+    /// Sends a frame for a guest on another page to the peer its MAC was
+    /// announced by. Frames for MACs no peer announced are dropped.
+    fn relay_to_peer(&self, frame: &[u8]) -> Result<(), JsValue> {
+        let mut network = NetworkState::lock(&self.network);
+        if !network.is_connected() || frame.len() > self.mtu as usize + 14 {
+            return Ok(());
+        }
+        let mut dst = [0u8; 6];
+        dst.copy_from_slice(&frame[..6]);
+        match network.peer_for_mac(&dst) {
+            Some(peer) => network.send_to_peer(&peer, self.vlan, Framing::Ethernet, frame).map_err(JsValue::from),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a guest packet over the relay, holding it to the MTU: oversized
+    /// packets are fragmented or bounced back with an ICMP error, and TCP
+    /// SYNs have their MSS clamped so the connection never needs either.
+    fn relay(&self, packet: &[u8]) -> Result<(), JsValue> {
+        if packet.len() > self.mtu as usize {
+            return self.relay_oversized(packet);
+        }
+
+        let clamped = pmtu::clamp_mss(packet, self.mtu);
+        let mut network = NetworkState::lock(&self.network);
+        send_routed(&mut network, self.vlan, clamped.as_deref().unwrap_or(packet))
+    }
+
+    fn relay_oversized(&self, packet: &[u8]) -> Result<(), JsValue> {
+        let guest_mac = self.mac_address.get();
+        if packet.first().is_some_and(|version| version >> 4 == 6) {
+            let gateway = self.ndp.gateway_address().unwrap_or_else(|| ndp::link_local(self.gateway_mac));
+            return match pmtu::packet_too_big(packet, self.mtu, gateway) {
+                Some(error) => self.deliver_ethernet(guest_mac, ETHERTYPE_IPV6, &error),
+                None => Ok(()),
+            };
+        }
+
+        if pmtu::dont_fragment(packet) {
+            return match pmtu::fragmentation_needed(packet, self.mtu, self.dhcp.borrow().server_ip()) {
+                Some(error) => self.deliver_ethernet(guest_mac, ETHERTYPE_IPV4, &error),
+                None => Ok(()),
+            };
+        }
+
+        let mut network = NetworkState::lock(&self.network);
+        for fragment in pmtu::fragment_ipv4(packet, self.mtu).unwrap_or_default() {
+            send_routed(&mut network, self.vlan, &fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Handles IPv4 addressed to services the virtual gateway provides.
+    /// Returns None if the packet should go to the relay instead.
+    fn handle_local_ipv4(self: &Rc<Self>, packet: &[u8]) -> Option<Result<(), JsValue>> {
+        if icmp::is_echo_request(packet, self.dhcp.borrow().server_ip()) {
+            return Some(self.answer_ping(icmp::echo_reply(packet), ETHERTYPE_IPV4));
+        }
+
+        let ip = Ipv4Packet::parse(packet)?;
+        if ip.protocol != PROTO_UDP {
+            return None;
+        }
+
+        let udp = UdpDatagram::parse(ip.payload)?;
+        let gateway = self.dhcp.borrow().server_ip();
+        if udp.dst_port == MDNS_PORT && self.mdns.is_some() && (ip.dst == MDNS_GROUP || ip.dst == gateway) {
+            // Multicast queries still reach the switch and relay, so guests
+            // can answer each other for their own names
+            let result = self.handle_mdns(&ip, &udp);
+            return (ip.dst == gateway || result.is_err()).then_some(result);
+        }
+        match udp.dst_port {
+            DHCP_SERVER_PORT => Some(self.handle_dhcp(&udp)),
+            port if ip.dst == gateway && self.tftp.borrow().handles(port) => Some(self.handle_tftp(&ip, &udp)),
+            DNS_PORT if self.dns.is_some() && dns::is_query(udp.payload) => {
+                Some(self.handle_dns(ip.dst.into(), ip.src.into(), &udp))
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles IPv6 UDP for the gateway's own services: stateless DHCPv6
+    /// and, at the gateway's addresses, DNS. Returns None if the packet
+    /// should go to the relay instead.
+    fn handle_local_ipv6(self: &Rc<Self>, packet: &[u8], sender_mac: [u8; 6]) -> Option<Result<(), JsValue>> {
+        let ip = Ipv6Packet::parse(packet)?;
+        if ip.next_header != PROTO_UDP {
+            return None;
+        }
+
+        let udp = UdpDatagram::parse(ip.payload)?;
+        match udp.dst_port {
+            DHCPV6_SERVER_PORT if ip.dst == ALL_DHCP_SERVERS => Some(self.handle_dhcpv6(&ip, &udp, sender_mac)),
+            DNS_PORT if self.dns.is_some() && self.ndp.addresses().contains(&ip.dst) && dns::is_query(udp.payload) => {
+                Some(self.handle_dns(ip.dst.into(), ip.src.into(), &udp))
+            }
+            _ => None,
+        }
+    }
+
+    /// Answers from the gateway's link-local address, as RFC 8415 expects
+    /// of a server on the client's link.
+    fn handle_dhcpv6(&self, ip: &Ipv6Packet, udp: &UdpDatagram, sender_mac: [u8; 6]) -> Result<(), JsValue> {
+        // Like DHCP, never relayed, answered or not
+        let Some(reply) = self.dhcpv6.as_ref().and_then(|server| server.handle(udp.payload)) else { return Ok(()) };
+        let gateway = ndp::link_local(self.gateway_mac);
+        let packet = ip::build_udp_v6(gateway, ip.src, DHCPV6_SERVER_PORT, DHCPV6_CLIENT_PORT, UDP_HOP_LIMIT, &reply);
+        self.deliver_ethernet(sender_mac, ETHERTYPE_IPV6, &packet)
+    }
+
+    /// Pings to the gateway never reach the relay, so they work before it's
+    /// connected. Corrupt requests are counted but go unanswered.
+    fn answer_ping(&self, reply: Option<Vec<u8>>, ethertype: u16) -> Result<(), JsValue> {
+        self.stats.record_echo_request();
+        let Some(reply) = reply else { return Ok(()) };
+        self.stats.record_echo_reply();
+        self.deliver_ethernet(self.mac_address.get(), ethertype, &reply)
+    }
+
+    /// Whether an outgoing IPv4 packet is for `nat` rather than the relay.
+    fn terminates(&self, packet: &[u8]) -> bool {
+        let Some(server) = self.file_server.as_ref() else { return self.nat_egress };
+        self.nat_egress || Ipv4Packet::parse(packet).is_some_and(|ip| ip.dst == server.address())
+    }
+
+    fn forward_port(self: &Rc<Self>, guest: SocketAddrV4) -> Result<MessagePort, JsValue> {
+        let channel = MessageChannel::new()?;
+        let port = channel.port1();
+        self.connect(guest, |handle| Box::new(PortStream::new(port, handle)))?;
+        Ok(channel.port2())
+    }
+
+    /// Whether the gateway can open connections to the guest, which needs
+    /// the NAT stack.
+    fn can_connect(&self) -> bool {
+        self.nat.is_some() && self.nat_egress
+    }
+
+    /// Opens a gateway connection to `guest` and sends its SYN.
+    fn connect(self: &Rc<Self>, guest: SocketAddrV4, open: impl FnOnce(&NatHandle) -> Box<dyn NatStream>) -> Result<(), JsValue> {
+        let Some(nat) = self.nat.as_ref().filter(|_| self.can_connect()) else {
+            return Err(DerpError::InvalidState("Connecting to the guest requires NAT mode".into()).into());
+        };
+        nat.borrow_mut().connect(guest, js_sys::Date::now(), open).map_err(JsValue::from)?;
+        self.poll_nat();
+        Ok(())
+    }
+
+    fn handle_dhcp(&self, udp: &UdpDatagram) -> Result<(), JsValue> {
+
+        // DHCP never leaves the virtual network, answered or not
+        let reply = match self.dhcp.borrow().handle(udp.payload) {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        let packet = ip::build_udp(
+            self.dhcp.borrow().server_ip(),
+            Ipv4Addr::BROADCAST,
+            DHCP_SERVER_PORT,
+            DHCP_CLIENT_PORT,
+            &reply.payload,
+        );
+        self.deliver_ethernet(reply.client_mac, ETHERTYPE_IPV4, &packet)
+    }
+
+    fn handle_tftp(&self, ip: &Ipv4Packet, udp: &UdpDatagram) -> Result<(), JsValue> {
+        let client = SocketAddrV4::new(ip.src, udp.src_port);
+        let reply = self.tftp.borrow_mut().handle(client, udp.dst_port, udp.payload, js_sys::Date::now());
+        let Some(reply) = reply else { return Ok(()) };
+        let packet = ip::build_udp(ip.dst, *reply.dst.ip(), reply.src_port, reply.dst.port(), &reply.payload);
+        self.deliver_ethernet(self.mac_address.get(), ETHERTYPE_IPV4, &packet)
+    }
+
+    /// Answers mDNS queries for the gateway's name: to the group from port
+    /// 5353, and straight back to the querier for a legacy query.
+    fn handle_mdns(&self, ip: &Ipv4Packet, udp: &UdpDatagram) -> Result<(), JsValue> {
+        let Some(responder) = self.mdns.as_ref() else { return Ok(()) };
+        let legacy = udp.src_port != MDNS_PORT;
+        let Some(response) = responder.answer(udp.payload, legacy) else { return Ok(()) };
+
+        let gateway = self.dhcp.borrow().server_ip();
+        if legacy {
+            let packet = ip::build_udp(gateway, ip.src, MDNS_PORT, udp.src_port, &response);
+            return self.deliver_ethernet(self.mac_address.get(), ETHERTYPE_IPV4, &packet);
+        }
+        let mut packet = ip::build_udp(gateway, MDNS_GROUP, MDNS_PORT, MDNS_PORT, &response);
+        ip::set_ttl(&mut packet, MDNS_TTL);
+        self.deliver_ethernet(MDNS_MAC, ETHERTYPE_IPV4, &packet)
+    }
+
+    /// Answers a DNS query through the DoH proxy, whichever server the
+    /// guest addressed it to. Static hosts, peer names and cache hits are
+    /// answered synchronously.
+    fn handle_dns(self: &Rc<Self>, server: IpAddr, client: IpAddr, udp: &UdpDatagram) -> Result<(), JsValue> {
+        let Some(proxy) = self.dns.clone() else { return Ok(()) };
+        let client_port = udp.src_port;
+
+        let local = proxy.answer_static(udp.payload)
+            .or_else(|| self.answer_peer_name(udp.payload))
+            .or_else(|| proxy.cached(udp.payload, js_sys::Date::now()));
+        if let Some(response) = local {
+            let (ethertype, packet) = dns_reply(server, client, client_port, &response);
+            return self.deliver_ethernet(self.mac_address.get(), ethertype, &packet);
+        }
+
+        let nic = self.clone();
+        let query = udp.payload.to_vec();
+        wasm_bindgen_futures::spawn_local(async move {
+            let response = proxy.resolve(&query).await;
+            let (ethertype, packet) = dns_reply(server, client, client_port, &response);
+            if let Err(e) = nic.deliver_ethernet(nic.mac_address.get(), ethertype, &packet) {
+                log::warn!("Failed to deliver a DNS response: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Answers queries for names in `control::PEER_DOMAIN` from the
+    /// coordination server's peer list.
+    fn answer_peer_name(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let network = NetworkState::lock(&self.network);
+        dns::answer_zone(query, control::PEER_DOMAIN, network.peer_names())
+    }
+
+    /// Runs the NAT gateway and delivers what it produced. Reschedules
+    /// itself for the stack's next retransmit or delayed ACK.
+    fn poll_nat(self: &Rc<Self>) {
+        let Some(nat) = self.nat.as_ref() else { return };
+        let now = js_sys::Date::now();
+        // The borrow must end before delivery, which may re-enter send_frame
+        let (packets, delay) = {
+            let mut nat = nat.borrow_mut();
+            let packets = nat.poll(now);
+            (packets, nat.poll_delay(now))
+        };
+
+        for packet in packets {
+            if let Err(e) = self.deliver_ethernet(self.mac_address.get(), ETHERTYPE_IPV4, &packet) {
+                log::warn!("Failed to deliver a NAT packet: {:?}", e);
+            }
+        }
+        if let Some(delay) = delay {
+            self.schedule_nat_poll(delay.min(i32::MAX as u64) as i32);
+        }
+    }
+
+    /// Polls the gateway after `delay_ms`, unless a poll is already due sooner.
+    fn schedule_nat_poll(self: &Rc<Self>, delay_ms: i32) {
+        let deadline = js_sys::Date::now() + delay_ms as f64;
+        match self.nat_timer.get() {
+            Some((_, pending)) if pending <= deadline => return,
+            Some((handle, _)) => timer::clear_timeout(handle),
+            None => {}
+        }
+
+        let nic = Rc::downgrade(self);
+        let callback = Closure::once_into_js(move || {
+            if let Some(nic) = nic.upgrade() {
+                nic.nat_timer.set(None);
+                nic.poll_nat();
+            }
+        });
+        self.nat_timer.set(Some((timer::set_timeout(callback.unchecked_ref(), delay_ms), deadline)));
+    }
+
+    fn link(&self, direction: Direction) -> &RefCell<Option<Link>> {
+        match direction {
+            Direction::Outbound => &self.egress,
+            Direction::Inbound => &self.ingress,
+        }
+    }
+
+    fn replace_link(&self, direction: Direction, link: Option<Link>) {
+        let old = self.link(direction).replace(link);
+        if let Some(handle) = old.and_then(|mut old| old.take_timer()) {
+            timer::clear_timeout(handle);
+        }
+    }
+
+    /// Runs a frame through the shaper for `direction`. Returns true if it
+    /// should be handled right away; otherwise it was lost or queued.
+    fn shape(&self, direction: Direction, frame: &[u8]) -> bool {
+        let mut link = self.link(direction).borrow_mut();
+        let Some(link) = link.as_mut() else { return true };
+
+        // Frames shaped while the network is busy keep the last estimate
+        if link.matches_relay() {
+            if let Some(network) = self.network.try_lock() {
+                link.set_relay_kbps(network.estimated_bandwidth_kbps());
+            }
+        }
+
+        let now = js_sys::Date::now();
+        let Some(at) = link.admit(frame.len(), now, js_sys::Math::random) else {
+            self.trace(direction, Outcome::Shaped, frame);
+            return false;
+        };
+        if at <= now && link.is_idle() {
+            return true;
+        }
+
+        // Only the head of the queue needs a timer; each release arms the next
+        if link.is_idle() {
+            link.set_timer(Some(self.schedule_release(direction, at - now)));
+        }
+        link.push(at, frame.to_vec());
+        false
+    }
+
+    fn schedule_release(&self, direction: Direction, delay_ms: f64) -> i32 {
+        let nic = self.this.clone();
+        let callback = Closure::once_into_js(move || {
+            if let Some(nic) = nic.upgrade() {
+                nic.release(direction);
+            }
+        });
+        timer::set_timeout(callback.unchecked_ref(), delay_ms.ceil() as i32)
+    }
+
+    /// Sends or delivers every queued frame that's due, then arms the timer
+    /// for the next.
+    fn release(self: &Rc<Self>, direction: Direction) {
+        let now = js_sys::Date::now();
+        loop {
+            // Sending can re-enter the shaper, so the borrow ends first
+            let frame = match self.link(direction).borrow_mut().as_mut() {
+                Some(link) => link.pop_due(now),
+                None => return,
+            };
+            let Some(frame) = frame else { break };
+            let result = match direction {
+                Direction::Outbound => self.transmit_frame(&frame),
+                Direction::Inbound => self.deliver_now(&frame),
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to pass on a shaped frame: {:?}", e);
+            }
+        }
+
+        let mut link = self.link(direction).borrow_mut();
+        let Some(link) = link.as_mut() else { return };
+        let timer = link.next_due().map(|at| self.schedule_release(direction, at - now));
+        link.set_timer(timer);
+    }
+
+    fn receive_packet(&self, data: &[u8]) -> Result<(), JsValue> {
+        if data.len() > (self.mtu as usize) {
+            return Err(DerpError::InvalidState("Packet too large".into()).into());
+        }
+
+        let ethertype = match data.first() {
+            Some(version) if version >> 4 == 6 => ETHERTYPE_IPV6,
+            _ => ETHERTYPE_IPV4,
+        };
+        // Peers may advertise an MSS sized for their own, larger MTU
+        let clamped = pmtu::clamp_mss(data, self.mtu);
+        self.deliver_ethernet(self.mac_address.get(), ethertype, clamped.as_deref().unwrap_or(data))
+    }
+
+    /// Wraps `payload` in an Ethernet header from the gateway and delivers it.
+    fn deliver_ethernet(&self, dst_mac: [u8; 6], ethertype: u16, payload: &[u8]) -> Result<(), JsValue> {
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        
+        // Destination, then the per-instance virtual gateway as source
+        frame.extend_from_slice(&dst_mac);
+        frame.extend_from_slice(&self.gateway_mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        if !self.firewall.borrow_mut().allows(Direction::Inbound, ethertype, payload) {
+            self.trace(Direction::Inbound, Outcome::Firewalled, &frame);
+            return Ok(());
+        }
+        self.deliver(&frame)
+    }
+
+    /// Records what became of `frame` if tracing is on or a hook is set.
+    fn trace(&self, direction: Direction, outcome: Outcome, frame: &[u8]) {
+        let hook = self.trace_hook.borrow().clone();
+        if frame.len() < 14 || (hook.is_none() && !self.tracer.borrow().is_recording()) {
+            return;
+        }
+
+        let summary = FrameSummary::new(direction, outcome, frame, js_sys::Date::now());
+        let value = hook.as_ref().and_then(|_| serde_wasm_bindgen::to_value(&summary).ok());
+        self.tracer.borrow_mut().record(summary);
+        // Called last, so the hook may read the trace itself
+        if let (Some(hook), Some(value)) = (hook, value) {
+            if let Err(e) = hook.call1(&JsValue::NULL, &value) {
+                log::warn!("onTrace callback threw: {:?}", e);
+            }
+        }
+    }
+
+    /// Hands a frame to the emulator once ingress shaping lets it through.
+    fn deliver(&self, frame: &[u8]) -> Result<(), JsValue> {
+        if !self.shape(Direction::Inbound, frame) {
+            return Ok(());
+        }
+        self.deliver_now(frame)
+    }
+
+    /// Hands a frame to whichever emulator this NIC is attached to. Without
+    /// a ring, bus or callback the NIC behaves like an unplugged cable.
+    fn deliver_now(&self, frame: &[u8]) -> Result<(), JsValue> {
+        self.trace(Direction::Inbound, Outcome::Passed, frame);
+        self.meter.borrow_mut().record(Direction::Inbound, frame);
+
+        // A full ring drops the frame, as a NIC with no free descriptors would
+        if let Some((to_vm, _)) = self.shared_rings.borrow().as_ref() {
+            to_vm.push(frame)?;
+            return Ok(());
+        }
+
+        if let Some((bus, receive_event)) = self.bus.borrow().as_ref() {
+            bus.send(receive_event, &Uint8Array::from(frame));
+            return Ok(());
+        }
+
+        if let Some(callback) = self.receive_callback.borrow().as_ref() {
+            callback.call1(&JsValue::NULL, &Uint8Array::from(frame))
+                .map_err(|e| DerpError::InvalidState(format!("Failed to deliver packet: {:?}", e)))?;
+        }
+
+        Ok(())
+    }
+}
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// Hop limit of the UDP the gateway sends the guest over IPv6.
+const UDP_HOP_LIMIT: u8 = 64;
+
+/// A DNS response from `server` back to the guest, in the family it asked in.
+fn dns_reply(server: IpAddr, client: IpAddr, client_port: u16, response: &[u8]) -> (u16, Vec<u8>) {
+    match (server, client) {
+        (IpAddr::V6(server), IpAddr::V6(client)) => {
+            (ETHERTYPE_IPV6, ip::build_udp_v6(server, client, DNS_PORT, client_port, UDP_HOP_LIMIT, response))
+        }
+        (IpAddr::V4(server), IpAddr::V4(client)) => {
+            (ETHERTYPE_IPV4, ip::build_udp(server, client, DNS_PORT, client_port, response))
+        }
+        _ => unreachable!("DNS is answered in the family it was asked in"),
+    }
+}
+
+/// Sends a guest packet to the peer the coordination server assigned its
+/// destination to, or else to every peer.
+fn send_routed(network: &mut NetworkState, vlan: Option<u16>, packet: &[u8]) -> Result<(), JsValue> {
+    match ip::destination(packet).and_then(|dst| network.peer_for_ip(dst)) {
+        Some(peer) => network.send_to_peer(&peer, vlan, Framing::Ip, packet),
+        None => network.send_packet_on_vlan(vlan, Framing::Ip, packet),
+    }
+    .map_err(JsValue::from)
+}
+
+/// Parses v86's "52:54:00:12:34:56" MAC notation.
+fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = text.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+    use wasm_bindgen::JsCast;
+    use crate::crypto::CryptoState;
+    use crate::registry;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn create_test_network() -> VmNetwork {
+        let crypto = CryptoState::new().unwrap();
+        let network = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        VmNetwork::new(network, &mac, registry::gateway_mac(1), &DerpConfig::default()).unwrap()
+    }
+
+    /// Collects the frames `network` delivers to the guest.
+    fn capture_frames(network: &VmNetwork) -> js_sys::Array {
+        let received = js_sys::Array::new();
+        let sink = received.clone();
+        let callback = Closure::wrap(Box::new(move |frame: Uint8Array| {
+            sink.push(&frame);
+        }) as Box<dyn FnMut(Uint8Array)>);
+        network.set_receive_callback(callback.as_ref().unchecked_ref::<Function>().clone());
+        callback.forget();
+        received
+    }
+
+    #[wasm_bindgen_test]
+    fn test_mac_address() {
+        let network = create_test_network();
+        let mac = network.get_mac_address();
+        assert_eq!(mac.length(), 6);
+        assert_eq!(mac.to_vec(), vec![0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_mtu() {
+        let network = create_test_network();
+        assert_eq!(network.get_mtu(), 1500);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_send_packet() {
+        let network = create_test_network();
+        
+        // Create test IPv4 packet
+        let mut packet = vec![0u8; 64];
+        packet[0..6].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]); // Dest MAC
+        packet[6..12].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x57]); // Source MAC
+        packet[12..14].copy_from_slice(&[0x08, 0x00]); // IPv4 ethertype
+        
+        let result = network.send_packet(&packet);
+        assert!(result.is_ok());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_receive_packet() {
+        let network = create_test_network();
+        
+        // Create test IPv4 payload
+        let payload = vec![0u8; 40];
+        
+        let result = network.receive_packet(&payload);
+        assert!(result.is_ok());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_receive_packet_uses_callback() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        network.receive_packet(&[0u8; 40]).unwrap();
+        assert_eq!(received.length(), 1);
+
+        let frame = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&frame[0..6], &[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(&frame[6..12], &registry::gateway_mac(1));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_receive_ipv6_packet() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let packet = ip::build_ipv6("2001:db8::1".parse().unwrap(), "fd86:86::2".parse().unwrap(), ip::PROTO_UDP, 64, &[0; 8]);
+        network.receive_packet(&packet).unwrap();
+
+        let frame = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&frame[12..14], &ETHERTYPE_IPV6.to_be_bytes());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_attach_to_bus() {
+        // Minimal stand-in for v86's BusConnector
+        let bus: BusConnector = js_sys::Function::new_no_args(
+            "const handlers = {}; const sent = [];
+             return {
+                 handlers, sent,
+                 register(name, fn, self) { handlers[name] = fn.bind(self); },
+                 send(name, value) { sent.push([name, value]); },
+             };",
+        ).call0(&JsValue::NULL).unwrap().unchecked_into();
+
+        let network = create_test_network();
+        network.attach_to_bus(bus.clone().unchecked_into(), 0);
+
+        let handlers = js_sys::Reflect::get(&bus, &"handlers".into()).unwrap();
+        let on_mac: Function = js_sys::Reflect::get(&handlers, &"net0-mac".into()).unwrap().unchecked_into();
+        on_mac.call1(&JsValue::NULL, &"52:54:00:aa:bb:cc".into()).unwrap();
+        assert_eq!(network.get_mac_address().to_vec(), vec![0x52, 0x54, 0x00, 0xAA, 0xBB, 0xCC]);
+
+        network.receive_packet(&[0u8; 40]).unwrap();
+        let sent: js_sys::Array = js_sys::Reflect::get(&bus, &"sent".into()).unwrap().unchecked_into();
+        let event: js_sys::Array = sent.get(0).unchecked_into();
+        assert_eq!(event.get(0).as_string().unwrap(), "net0-receive");
+        assert_eq!(Uint8Array::from(event.get(1)).to_vec()[..6], [0x52, 0x54, 0x00, 0xAA, 0xBB, 0xCC]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_arp_for_gateway_is_answered() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let request = crate::arp::ArpPacket {
+            operation: 1,
+            sender_mac: guest_mac,
+            sender_ip: "192.168.86.100".parse().unwrap(),
+            target_mac: [0; 6],
+            target_ip: crate::config::DEFAULT_GATEWAY_IP,
+        };
+        let mut frame = vec![0xFF; 6];
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+        frame.extend_from_slice(&request.to_bytes());
+
+        network.send_packet(&frame).unwrap();
+        assert_eq!(received.length(), 1);
+
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&reply[0..6], &guest_mac);
+        assert_eq!(&reply[12..14], &ETHERTYPE_ARP.to_be_bytes());
+        let reply = crate::arp::ArpPacket::parse(&reply[14..]).unwrap();
+        assert_eq!(reply.sender_mac, registry::gateway_mac(1));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_dhcp_discover_is_offered() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let mut discover = vec![0u8; 240];
+        discover[0] = 1;
+        discover[1] = 1;
+        discover[2] = 6;
+        discover[28..34].copy_from_slice(&guest_mac);
+        discover[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        discover.extend_from_slice(&[53, 1, 1, 255]);
+
+        let mut frame = vec![0xFF; 6];
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_udp(Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST, 68, 67, &discover));
+
+        network.send_packet(&frame).unwrap();
+        assert_eq!(received.length(), 1);
+
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&reply[0..6], &guest_mac);
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        assert_eq!(packet.src, crate::config::DEFAULT_GATEWAY_IP);
+        let offer = UdpDatagram::parse(packet.payload).unwrap();
+        assert_eq!(offer.dst_port, DHCP_CLIENT_PORT);
+        assert_eq!(&offer.payload[16..20], &crate::config::DEFAULT_GUEST_IP.octets());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_ping_to_gateway_is_answered() {
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let stats = NetworkState::lock(&state).stats();
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let network = VmNetwork::new(state, &guest_mac, registry::gateway_mac(1), &DerpConfig::default()).unwrap();
+
+        let received = capture_frames(&network);
+
+        let gateway = crate::config::DEFAULT_GATEWAY_IP;
+        let mut echo = vec![8, 0, 0, 0, 0, 1, 0, 1];
+        let checksum = ip::checksum(&echo);
+        echo[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_ipv4(crate::config::DEFAULT_GUEST_IP, gateway, ip::PROTO_ICMP, &echo));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        assert_eq!(packet.src, gateway);
+        assert_eq!(packet.payload[0], 0);
+
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.echo_requests, snapshot.echo_replies), (1, 1));
+        assert_eq!(snapshot.packets_sent, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_mdns_query_for_gateway_is_answered() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x04host\x05local\x00\x00\x01\x00\x01");
+        let mut frame = MDNS_MAC.to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_udp(crate::config::DEFAULT_GUEST_IP, MDNS_GROUP, MDNS_PORT, MDNS_PORT, &query));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&reply[..6], &MDNS_MAC);
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        assert_eq!((packet.dst, packet.ttl), (MDNS_GROUP, MDNS_TTL));
+        assert_eq!(ip::checksum(packet.header), 0);
+        let udp = UdpDatagram::parse(packet.payload).unwrap();
+        assert_eq!(&udp.payload[udp.payload.len() - 4..], &crate::config::DEFAULT_GATEWAY_IP.octets());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_static_dns_host_is_answered_locally() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+        network.add_dns_host("myapp.test", "192.168.86.101").unwrap();
+        assert!(network.add_dns_host("myapp.test", "not an address").is_err());
+
+        let mut query = vec![0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x05myapp\x04test\x00\x00\x01\x00\x01");
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let (guest, gateway) = (crate::config::DEFAULT_GUEST_IP, crate::config::DEFAULT_GATEWAY_IP);
+        frame.extend_from_slice(&ip::build_udp(guest, gateway, 40000, DNS_PORT, &query));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        let udp = UdpDatagram::parse(packet.payload).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (DNS_PORT, 40000));
+        assert_eq!(&udp.payload[..2], &[0x12, 0x34]);
+        assert_eq!(&udp.payload[udp.payload.len() - 4..], &[192, 168, 86, 101]);
+        assert!(network.remove_dns_host("myapp.test"));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_dhcpv6_information_request_gets_gateway_dns() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let guest = ndp::link_local(guest_mac);
+        // Information-Request with an empty option list
+        let request = [11, 1, 2, 3];
+        let mut frame = ndp::multicast_mac(ALL_DHCP_SERVERS).to_vec();
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&ip::build_udp_v6(guest, ALL_DHCP_SERVERS, DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT, 1, &request));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        assert_eq!(&reply[..6], &guest_mac);
+        let packet = Ipv6Packet::parse(&reply[14..]).unwrap();
+        assert_eq!(packet.dst, guest);
+        assert_eq!(ip::checksum(&ip::pseudo_header_v6(packet.src, packet.dst, PROTO_UDP, packet.payload)), 0);
+        let udp = UdpDatagram::parse(packet.payload).unwrap();
+        assert_eq!(&udp.payload[..4], &[7, 1, 2, 3]);
+        // The gateway's address in the default prefix, fd86:86::1
+        let gateway = crate::config::DEFAULT_IPV6_PREFIX.octets();
+        assert!(udp.payload.windows(16).any(|window| window[..15] == gateway[..15] && window[15] == 1));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_tftp_serves_page_files() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let image = Uint8Array::from(&[0xEB, 0x3C, 0x90][..]);
+        network.add_tftp_file("pxelinux.0", &image.buffer().into());
+        network.set_boot_file(Some("pxelinux.0".into())).unwrap();
+
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let guest = crate::config::DEFAULT_GUEST_IP;
+        let gateway = crate::config::DEFAULT_GATEWAY_IP;
+        frame.extend_from_slice(&ip::build_udp(guest, gateway, 2070, crate::tftp::TFTP_PORT, b"\x00\x01pxelinux.0\x00octet\x00"));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        assert_eq!((packet.src, packet.dst), (gateway, guest));
+        let udp = UdpDatagram::parse(packet.payload).unwrap();
+        assert_eq!(udp.dst_port, 2070);
+        assert_eq!(udp.payload, &[0, 3, 0, 1, 0xEB, 0x3C, 0x90]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_shaping_delays_and_drops_frames() {
+        use crate::shape::LinkProfile;
+
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let stats = NetworkState::lock(&state).stats();
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let network = VmNetwork::new(state, &guest_mac, registry::gateway_mac(1), &DerpConfig::default()).unwrap();
+
+        let received = capture_frames(&network);
+
+        let mut echo = vec![8, 0, 0, 0, 0, 1, 0, 1];
+        let checksum = ip::checksum(&echo);
+        echo[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let mut ping = registry::gateway_mac(1).to_vec();
+        ping.extend_from_slice(&guest_mac);
+        ping.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        ping.extend_from_slice(&ip::build_ipv4(crate::config::DEFAULT_GUEST_IP, crate::config::DEFAULT_GATEWAY_IP, ip::PROTO_ICMP, &echo));
+
+        // The reply is held back by the ingress latency
+        network.set_shaping(ShapingConfig {
+            ingress: Some(LinkProfile { latency_ms: 50.0, ..LinkProfile::default() }),
+            ..ShapingConfig::default()
+        }).unwrap();
+        network.send_packet(&ping).unwrap();
+        assert_eq!(stats.snapshot().echo_replies, 1);
+        assert_eq!(received.length(), 0);
+
+        // The request never reaches the gateway
+        network.set_shaping(ShapingConfig {
+            egress: Some(LinkProfile { loss_percent: 100.0, ..LinkProfile::default() }),
+            ..ShapingConfig::default()
+        }).unwrap();
+        network.send_packet(&ping).unwrap();
+        assert_eq!(stats.snapshot().echo_requests, 1);
+
+        let invalid = ShapingConfig {
+            egress: Some(LinkProfile { jitter_ms: -1.0, ..LinkProfile::default() }),
+            ..ShapingConfig::default()
+        };
+        assert!(network.set_shaping(invalid).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_nat_mode_terminates_unrouted_tcp() {
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let config = DerpConfig::builder()
+            .nat(true)
+            .relay_routes(vec!["100.64.0.0/10".into()])
+            .build()
+            .unwrap();
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let network = VmNetwork::new(state, &guest_mac, registry::gateway_mac(1), &config).unwrap();
+
+        let received = capture_frames(&network);
+
+        let guest = config.guest_ip;
+        let remote = Ipv4Addr::new(93, 184, 216, 34);
+        let mut syn = vec![0u8; 20];
+        syn[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        syn[2..4].copy_from_slice(&80u16.to_be_bytes());
+        syn[12] = 5 << 4;
+        syn[13] = 0x02;
+        let checksum = ip::checksum(&ip::pseudo_header(guest, remote, ip::PROTO_TCP, &syn));
+        syn[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_ipv4(guest, remote, ip::PROTO_TCP, &syn));
+        network.send_packet(&frame).unwrap();
+
+        // No backend claims the flow, so the gateway resets it locally
+        assert_eq!(received.length(), 1);
+        let reply = Uint8Array::from(received.get(0)).to_vec();
+        let packet = Ipv4Packet::parse(&reply[14..]).unwrap();
+        assert_eq!(packet.src, remote);
+        assert_ne!(packet.payload[13] & 0x04, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("52:54:0:1:2:3"), Some([0x52, 0x54, 0, 1, 2, 3]));
+        assert_eq!(parse_mac("52:54:00:12:34"), None);
+        assert_eq!(parse_mac("52:54:00:12:34:56:78"), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_receive_packet_uses_shared_ring() {
+        if !crate::ring::shared_memory_available() {
+            return;
+        }
+
+        let network = create_test_network();
+        let rings = network.enable_shared_rings(4096).unwrap();
+        network.receive_packet(&[0u8; 40]).unwrap();
+
+        let to_vm = SharedRing::from_buffer(rings.to_vm()).unwrap();
+        let mut frame = Vec::new();
+        assert!(to_vm.pop(&mut frame).unwrap());
+        assert_eq!(frame.len(), 54);
+        assert_eq!(&frame[6..12], &registry::gateway_mac(1));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_forward_port_connects_to_guest() {
+        assert!(create_test_network().forward_port("192.168.86.100", 22).is_err());
+
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let config = DerpConfig::builder().nat(true).build().unwrap();
+        let network = VmNetwork::new(state, &[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], registry::gateway_mac(1), &config).unwrap();
+
+        let received = capture_frames(&network);
+
+        let _port = network.forward_port("192.168.86.100", 22).unwrap();
+        assert_eq!(received.length(), 1);
+        let frame = Uint8Array::from(received.get(0)).to_vec();
+        let syn = Ipv4Packet::parse(&frame[14..]).unwrap();
+        assert_eq!((syn.src, syn.dst), (config.gateway_ip, config.guest_ip));
+        assert_eq!(&syn.payload[2..4], &22u16.to_be_bytes());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_connect_to_guest_opens_from_the_gateway() {
+        assert!(create_test_network().connect_to_guest(6379).is_err());
+
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let config = DerpConfig::builder().nat(true).build().unwrap();
+        let network = VmNetwork::new(state, &[0x52, 0x54, 0x00, 0x12, 0x34, 0x56], registry::gateway_mac(1), &config).unwrap();
+
+        let received = capture_frames(&network);
+
+        let connection = network.connect_to_guest(6379).unwrap();
+        assert_eq!(received.length(), 1);
+        let frame = Uint8Array::from(received.get(0)).to_vec();
+        let syn = Ipv4Packet::parse(&frame[14..]).unwrap();
+        assert_eq!((syn.src, syn.dst), (config.gateway_ip, config.guest_ip));
+        assert_eq!(&syn.payload[2..4], &6379u16.to_be_bytes());
+
+        connection.send(&"PING\r\n".into()).unwrap();
+        connection.close();
+        assert!(connection.send(&"PING\r\n".into()).is_err());
+        assert!(!connection.guest_closed());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_publish_port_requires_nat() {
+        assert!(create_test_network().publish_port("wss://relay.example/publish", 22).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_firewall_blocks_guest_traffic() {
+        let network = create_test_network();
+        let received = capture_frames(&network);
+
+        let rule = crate::firewall::FirewallRule {
+            action: crate::firewall::Action::Deny,
+            direction: Some(Direction::Outbound),
+            ethertype: None,
+            remote: Some(crate::config::DEFAULT_GATEWAY_IP.to_string()),
+            protocol: Some(ip::PROTO_ICMP),
+            ports: None,
+        };
+        network.set_firewall(FirewallConfig { rules: vec![rule], ..FirewallConfig::default() }).unwrap();
+
+        let mut echo = vec![8, 0, 0, 0, 0, 1, 0, 1];
+        let checksum = ip::checksum(&echo);
+        echo[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_ipv4(crate::config::DEFAULT_GUEST_IP, crate::config::DEFAULT_GATEWAY_IP, ip::PROTO_ICMP, &echo));
+        network.send_packet(&frame).unwrap();
+
+        assert_eq!(received.length(), 0);
+        assert_eq!(network.get_firewall_hits().rules, vec![1]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_trace_records_outcomes() {
+        let network = create_test_network();
+        let hooked = js_sys::Array::new();
+        let sink = hooked.clone();
+        let hook = Closure::wrap(Box::new(move |summary: JsValue| {
+            sink.push(&summary);
+        }) as Box<dyn FnMut(JsValue)>);
+        network.on_trace(Some(hook.as_ref().unchecked_ref::<Function>().clone()));
+        network.set_tracing(true);
+        network.set_firewall(FirewallConfig { default_action: crate::firewall::Action::Deny, ..FirewallConfig::default() }).unwrap();
+
+        let mut frame = registry::gateway_mac(1).to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ip::build_udp(crate::config::DEFAULT_GUEST_IP, crate::config::DEFAULT_GATEWAY_IP, 5000, 7, &[0; 4]));
+        network.send_packet(&frame).unwrap();
+
+        let trace = network.get_trace();
+        assert_eq!(trace.frames.len(), 1);
+        let summary = &trace.frames[0];
+        assert_eq!((summary.direction, summary.outcome), (Direction::Outbound, Outcome::Firewalled));
+        assert_eq!((summary.protocol, summary.dst_port), (Some(PROTO_UDP), Some(7)));
+        assert_eq!(hooked.length(), 1);
+
+        network.clear_trace();
+        network.set_tracing(false);
+        network.send_packet(&frame).unwrap();
+        assert!(network.get_trace().frames.is_empty());
+        assert_eq!(hooked.length(), 2);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_state_survives_serialization() {
+        let network = create_test_network();
+        network.set_promiscuous(true);
+        network.set_boot_file(Some("pxelinux.0".into())).unwrap();
+        network.set_firewall(FirewallConfig { default_action: crate::firewall::Action::Deny, ..FirewallConfig::default() }).unwrap();
+        network.nic.arp.borrow_mut().restore(&[(crate::config::DEFAULT_GUEST_IP, [0x52, 0x54, 0x00, 0x12, 0x34, 0x56])]);
+        let state = network.serialize_state().unwrap();
+
+        let restored = create_test_network();
+        restored.restore_state(&state).unwrap();
+        assert!(restored.is_promiscuous());
+        assert_eq!(restored.nic.dhcp.borrow().boot_file(), Some("pxelinux.0"));
+        assert_eq!(restored.nic.firewall.borrow().config().default_action, crate::firewall::Action::Deny);
+        assert_eq!(restored.nic.arp.borrow().lookup(crate::config::DEFAULT_GUEST_IP), Some([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+
+        assert!(restored.restore_state(&state[..state.len() - 1]).is_err());
+        assert!(restored.restore_state(&[]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_broadcast_and_multicast_are_counted() {
+        let crypto = CryptoState::new().unwrap();
+        let state = Shared::new(NetworkState::new(Arc::new(crypto)));
+        let stats = NetworkState::lock(&state).stats();
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let network = VmNetwork::new(state, &guest_mac, registry::gateway_mac(1), &DerpConfig::default()).unwrap();
+
+        let guest = crate::config::DEFAULT_GUEST_IP;
+        for (dst_mac, dst_ip) in [([0xFF; 6], Ipv4Addr::BROADCAST), ([0x01, 0x00, 0x5E, 0x00, 0x00, 0xFB], Ipv4Addr::new(224, 0, 0, 251))] {
+            let mut frame = dst_mac.to_vec();
+            frame.extend_from_slice(&guest_mac);
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            frame.extend_from_slice(&ip::build_udp(guest, dst_ip, 5353, 5353, b"announce"));
+            // Without a relay connection there's no one else to hear it
+            network.send_packet(&frame).unwrap();
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.broadcast_sent, snapshot.multicast_sent), (1, 1));
+        assert_eq!(snapshot.packets_sent, 0);
+    }
+}
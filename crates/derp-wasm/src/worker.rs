@@ -0,0 +1,194 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{Array, Function, Object, Reflect, Uint8Array};
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+use std::cell::RefCell;
+use std::rc::Rc;
+use super::{
+    config::DerpConfig,
+    error::DerpError,
+    events::{EventDispatcher, EventKind},
+    network::ConnectOptions,
+    DerpNetwork,
+};
+
+/// Serves a `DerpNetwork` to the main thread when called from a dedicated
+/// worker, so crypto, compression and framing stay off the UI thread.
+///
+/// Requests are `{ id, method, args }` and are answered with `{ id, result }`
+/// or `{ id, error: { name, message, code?, detail? } }`. Events are posted as
+/// `{ event, payload }`, with packet buffers transferred rather than copied.
+/// `DerpWorkerProxy` (src/browser/derp_worker_proxy.js) speaks this protocol.
+#[wasm_bindgen(js_name = runWorker)]
+pub fn run_worker() -> Result<(), JsValue> {
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().dyn_into().map_err(|_| {
+        DerpError::InvalidState("runWorker must be called inside a dedicated worker".into())
+    })?;
+
+    let network: Rc<RefCell<Option<Rc<DerpNetwork>>>> = Rc::new(RefCell::new(None));
+    let reply_scope = scope.clone();
+    let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
+        let scope = reply_scope.clone();
+        let network = network.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let request = e.data();
+            let id = Reflect::get(&request, &"id".into()).unwrap_or(JsValue::UNDEFINED);
+            let method = Reflect::get(&request, &"method".into())
+                .ok()
+                .and_then(|method| method.as_string())
+                .unwrap_or_default();
+            let args: Array = Reflect::get(&request, &"args".into())
+                .ok()
+                .and_then(|args| args.dyn_into().ok())
+                .unwrap_or_default();
+
+            let result = dispatch(&scope, &network, &method, &args).await;
+            post_reply(&scope, &id, result);
+        });
+    }) as Box<dyn FnMut(MessageEvent)>);
+
+    scope.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+    onmessage_callback.forget();
+    Ok(())
+}
+
+async fn dispatch(
+    scope: &DedicatedWorkerGlobalScope,
+    network: &RefCell<Option<Rc<DerpNetwork>>>,
+    method: &str,
+    args: &Array,
+) -> Result<JsValue, JsValue> {
+    if method == "init" {
+        let config: DerpConfig = match args.get(0) {
+            config if config.is_undefined() => DerpConfig::default(),
+            config => serde_wasm_bindgen::from_value(config)?,
+        };
+        let derp = DerpNetwork::with_config(config)?;
+        forward_events(scope, &derp.events);
+        *network.borrow_mut() = Some(Rc::new(derp));
+        return Ok(JsValue::UNDEFINED);
+    }
+
+    let derp = network.borrow().clone()
+        .ok_or_else(|| DerpError::InvalidState("Worker network not initialized".into()))?;
+
+    match method {
+        "connect" => {
+            let url = args.get(0).as_string()
+                .ok_or_else(|| DerpError::InvalidState("connect expects a URL".into()))?;
+            let options: ConnectOptions = match args.get(1) {
+                options if options.is_undefined() => ConnectOptions::default(),
+                options => serde_wasm_bindgen::from_value(options)?,
+            };
+            derp.connect_with_options(&url, options).await?;
+            Ok(JsValue::UNDEFINED)
+        }
+        "sendPacket" => {
+            derp.send_packet(&Uint8Array::new(&args.get(0)).to_vec())?;
+            Ok(JsValue::UNDEFINED)
+        }
+        "getStats" => Ok(serde_wasm_bindgen::to_value(&derp.get_stats())?),
+        "drain" => Ok(serde_wasm_bindgen::to_value(&derp.drain())?),
+        "drainProgress" => Ok(serde_wasm_bindgen::to_value(&derp.drain_progress())?),
+        "shutdown" => {
+            derp.shutdown().await?;
+            Ok(JsValue::UNDEFINED)
+        }
+        _ => Err(DerpError::InvalidState(format!("Unknown method: {}", method)).into()),
+    }
+}
+
+/// Re-posts every event to the main thread. Packets go as a copy whose
+/// buffer is transferred: the original is shared with the other listeners
+/// and subscribers, like the NIC demux, which mustn't find it detached.
+fn forward_events(scope: &DedicatedWorkerGlobalScope, events: &EventDispatcher) {
+    for kind in EventKind::ALL {
+        let scope = scope.clone();
+        let callback = Closure::wrap(Box::new(move |payload: JsValue| {
+            let transfer = Array::new();
+            let payload = match kind {
+                EventKind::Error => error_shape(&payload),
+                EventKind::Packet => match payload.dyn_ref::<Uint8Array>() {
+                    Some(packet) => {
+                        let copy = packet.slice(0, packet.length());
+                        transfer.push(&copy.buffer());
+                        copy.into()
+                    }
+                    None => payload,
+                },
+                _ => payload,
+            };
+
+            let message = Object::new();
+            let _ = Reflect::set(&message, &"event".into(), &kind.name().into());
+            let _ = Reflect::set(&message, &"payload".into(), &payload);
+            if let Err(e) = scope.post_message_with_transfer(&message, &transfer) {
+                log::warn!("Failed to post a {} event: {:?}", kind.name(), e);
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+
+        events.on(kind, callback.as_ref().unchecked_ref::<Function>().clone());
+        callback.forget();
+    }
+}
+
+fn post_reply(scope: &DedicatedWorkerGlobalScope, id: &JsValue, result: Result<JsValue, JsValue>) {
+    let reply = Object::new();
+    let _ = Reflect::set(&reply, &"id".into(), id);
+    let _ = match result {
+        Ok(value) => Reflect::set(&reply, &"result".into(), &value),
+        Err(error) => Reflect::set(&reply, &"error".into(), &error_shape(&error)),
+    };
+
+    if let Err(e) = scope.post_message(&reply) {
+        log::warn!("Failed to post a reply: {:?}", e);
+    }
+}
+
+/// Structured clone drops custom error names, so errors cross the worker
+/// boundary as plain `{ name, message }` objects, with `code` and `detail`
+/// carried over from a `DerpError`.
+fn error_shape(error: &JsValue) -> JsValue {
+    let (name, message) = match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => (error.name().into(), error.message().into()),
+        None => (JsValue::from("Error"), error.as_string().unwrap_or_default().into()),
+    };
+
+    let shape = Object::new();
+    let _ = Reflect::set(&shape, &"name".into(), &name);
+    let _ = Reflect::set(&shape, &"message".into(), &message);
+    for field in ["code", "detail"] {
+        if let Ok(value) = Reflect::get(error, &field.into()) {
+            if !value.is_undefined() {
+                let _ = Reflect::set(&shape, &field.into(), &value);
+            }
+        }
+    }
+    shape.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DerpErrorCode;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_run_worker_outside_worker() {
+        assert!(run_worker().is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_error_shape_keeps_kind() {
+        let error: JsValue = DerpError::AuthRejected("bad token".into()).into();
+        let shape = error_shape(&error);
+
+        let name = Reflect::get(&shape, &"name".into()).unwrap();
+        assert_eq!(name.as_string().unwrap(), "AuthRejected");
+        let code = Reflect::get(&shape, &"code".into()).unwrap();
+        assert_eq!(code.as_f64(), Some(DerpErrorCode::AuthRejected as u32 as f64));
+        assert!(!shape.is_instance_of::<js_sys::Error>());
+    }
+}
@@ -0,0 +1,42 @@
+//! The `hot_path` benchmarks in a browser, where criterion can't run. They
+//! are ignored in normal test runs; run them with
+//! `wasm-pack test --headless --chrome --release -- --include-ignored wasm_bench`.
+#![cfg(all(target_arch = "wasm32", feature = "compression"))]
+
+#[path = "../benches/common/mod.rs"]
+mod common;
+
+use derp_wasm::clock::{Clock, SystemClock};
+use wasm_bindgen_test::*;
+
+use common::{Fixture, OPERATIONS, PACKET_SIZES};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// Each operation runs for about this long per packet size.
+const MEASURE_MS: f64 = 200.0;
+
+#[wasm_bindgen_test]
+#[ignore]
+fn bench_hot_path() {
+    for operation in OPERATIONS {
+        for size in PACKET_SIZES {
+            let mut fixture = Fixture::new(size);
+            // Warm up the buffers and the JIT
+            for _ in 0..100 {
+                fixture.run(operation);
+            }
+
+            let start = SystemClock.now_ms();
+            let mut iterations = 0u64;
+            while SystemClock.now_ms() - start < MEASURE_MS {
+                fixture.run(operation);
+                iterations += 1;
+            }
+            let elapsed_ms = SystemClock.now_ms() - start;
+            let ns_per_iter = elapsed_ms * 1e6 / iterations as f64;
+            let mb_s = (fixture.packet_len() as f64 * iterations as f64) / (elapsed_ms / 1000.0) / 1e6;
+            console_log!("{:>12}/{:<5} {:>10.0} ns/iter {:>8.1} MB/s", operation.name(), size, ns_per_iter, mb_s);
+        }
+    }
+}
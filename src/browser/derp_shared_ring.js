@@ -0,0 +1,78 @@
+"use strict";
+
+/**
+ * Emulator side of the SharedArrayBuffer rings returned by
+ * `VmNetwork.enableSharedRings`.
+ *
+ * Layout: two Int32 indices (bytes written, bytes read) followed by a
+ * power-of-two data region. Each record is a little-endian u16 length and
+ * the frame bytes. Must match crates/derp-wasm/src/ring.rs.
+ */
+const HEAD = 0;
+const TAIL = 1;
+const HEADER_LEN = 8;
+const LEN_PREFIX = 2;
+
+class DerpSharedRing {
+    constructor(buffer) {
+        this.indices = new Int32Array(buffer, 0, 2);
+        this.data = new Uint8Array(buffer, HEADER_LEN);
+        this.capacity = this.data.length;
+    }
+
+    /** Appends a frame. Returns false if the ring is full. */
+    push(frame) {
+        const head = Atomics.load(this.indices, HEAD) >>> 0;
+        const tail = Atomics.load(this.indices, TAIL) >>> 0;
+        const needed = LEN_PREFIX + frame.length;
+        if (frame.length > 0xFFFF || needed > this.capacity - ((head - tail) >>> 0)) {
+            return false;
+        }
+
+        this.writeAt(head, [frame.length & 0xFF, frame.length >> 8]);
+        this.writeAt(head + LEN_PREFIX, frame);
+        this.publish(HEAD, head + needed);
+        return true;
+    }
+
+    /** Removes and returns the oldest frame, or null if the ring is empty. */
+    pop() {
+        const head = Atomics.load(this.indices, HEAD) >>> 0;
+        const tail = Atomics.load(this.indices, TAIL) >>> 0;
+        if (head === tail) {
+            return null;
+        }
+
+        const prefix = this.readAt(tail, LEN_PREFIX);
+        const length = prefix[0] | (prefix[1] << 8);
+        const frame = this.readAt(tail + LEN_PREFIX, length);
+        this.publish(TAIL, tail + LEN_PREFIX + length);
+        return frame;
+    }
+
+    publish(index, value) {
+        Atomics.store(this.indices, index, value | 0);
+        Atomics.notify(this.indices, index);
+    }
+
+    writeAt(position, bytes) {
+        const start = (position >>> 0) % this.capacity;
+        const first = Math.min(bytes.length, this.capacity - start);
+        for (let i = 0; i < bytes.length; i++) {
+            this.data[i < first ? start + i : i - first] = bytes[i];
+        }
+    }
+
+    readAt(position, length) {
+        const start = (position >>> 0) % this.capacity;
+        const first = Math.min(length, this.capacity - start);
+        const out = new Uint8Array(length);
+        out.set(this.data.subarray(start, start + first));
+        out.set(this.data.subarray(0, length - first), first);
+        return out;
+    }
+}
+
+if (typeof module !== "undefined" && module.exports) {
+    module.exports = DerpSharedRing;
+}
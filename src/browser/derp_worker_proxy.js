@@ -0,0 +1,122 @@
+"use strict";
+
+/**
+ * Main-thread handle to a DerpNetwork running inside a Web Worker.
+ *
+ * The worker script loads the derp-wasm module and calls
+ * `runWorker()`; this class only forwards calls and events, so crypto,
+ * compression and framing never run on the v86 UI thread.
+ */
+class DerpWorkerProxy {
+    constructor(worker) {
+        this.worker = worker;
+        this.nextId = 1;
+        this.pending = new Map();
+        this.listeners = new Map();
+        this.worker.onmessage = (event) => this.handleMessage(event.data);
+    }
+
+    init(config) {
+        return this.call("init", [config]);
+    }
+
+    connect(url, options) {
+        return this.call("connect", [url, options]);
+    }
+
+    /**
+     * Sends a packet. `data` is copied, and only the copy's buffer is
+     * transferred, so the caller's buffer stays usable even if shared.
+     */
+    sendPacket(data) {
+        const packet = data.slice();
+        return this.call("sendPacket", [packet], [packet.buffer]);
+    }
+
+    getStats() {
+        return this.call("getStats", []);
+    }
+
+    drain() {
+        return this.call("drain", []);
+    }
+
+    drainProgress() {
+        return this.call("drainProgress", []);
+    }
+
+    shutdown() {
+        return this.call("shutdown", []);
+    }
+
+    on(event, callback) {
+        if (!this.listeners.has(event)) {
+            this.listeners.set(event, []);
+        }
+        this.listeners.get(event).push(callback);
+    }
+
+    off(event, callback) {
+        const callbacks = this.listeners.get(event) || [];
+        const index = callbacks.indexOf(callback);
+        if (index === -1) {
+            return false;
+        }
+        callbacks.splice(index, 1);
+        return true;
+    }
+
+    terminate() {
+        this.worker.terminate();
+        for (const { reject } of this.pending.values()) {
+            reject(new Error("Worker terminated"));
+        }
+        this.pending.clear();
+    }
+
+    call(method, args, transfer = []) {
+        const id = this.nextId++;
+        return new Promise((resolve, reject) => {
+            this.pending.set(id, { resolve, reject });
+            this.worker.postMessage({ id, method, args }, transfer);
+        });
+    }
+
+    handleMessage(message) {
+        if (message.event !== undefined) {
+            const payload = message.event === "error" ? toError(message.payload) : message.payload;
+            for (const callback of [...(this.listeners.get(message.event) || [])]) {
+                callback(payload);
+            }
+            return;
+        }
+
+        const pending = this.pending.get(message.id);
+        if (!pending) {
+            return;
+        }
+        this.pending.delete(message.id);
+
+        if (message.error) {
+            pending.reject(toError(message.error));
+        } else {
+            pending.resolve(message.result);
+        }
+    }
+}
+
+// Errors arrive as { name, message, code?, detail? } since structured clone
+// drops custom names and properties
+function toError(shape) {
+    const error = new Error(shape.message);
+    error.name = shape.name;
+    if (shape.code !== undefined) {
+        error.code = shape.code;
+        error.detail = shape.detail;
+    }
+    return error;
+}
+
+if (typeof module !== "undefined" && module.exports) {
+    module.exports = DerpWorkerProxy;
+}
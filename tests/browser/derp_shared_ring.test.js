@@ -0,0 +1,32 @@
+"use strict";
+
+const assert = require("assert");
+const DerpSharedRing = require("../../src/browser/derp_shared_ring");
+
+describe("DerpSharedRing", () => {
+    let ring;
+
+    beforeEach(() => {
+        ring = new DerpSharedRing(new SharedArrayBuffer(8 + 16));
+    });
+
+    it("should round-trip frames across the wrap point", () => {
+        for (let round = 0; round < 4; round++) {
+            const frame = new Uint8Array(9).fill(round);
+            assert.strictEqual(ring.push(frame), true);
+            assert.deepStrictEqual(ring.pop(), frame);
+        }
+        assert.strictEqual(ring.pop(), null);
+    });
+
+    it("should refuse frames when full", () => {
+        assert.strictEqual(ring.push(new Uint8Array(10)), true);
+        assert.strictEqual(ring.push(new Uint8Array(10)), false);
+    });
+
+    it("should use a little-endian length prefix", () => {
+        ring.push(new Uint8Array([7, 8, 9]));
+        assert.deepStrictEqual([...ring.data.subarray(0, 5)], [3, 0, 7, 8, 9]);
+        assert.strictEqual(Atomics.load(ring.indices, 0), 5);
+    });
+});
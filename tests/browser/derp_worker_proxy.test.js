@@ -0,0 +1,83 @@
+"use strict";
+
+const assert = require("assert");
+const sinon = require("sinon");
+const DerpWorkerProxy = require("../../src/browser/derp_worker_proxy");
+
+class MockWorker {
+    constructor() {
+        this.onmessage = null;
+        this.postMessage = sinon.spy();
+        this.terminate = sinon.spy();
+    }
+
+    reply(data) {
+        this.onmessage({ data });
+    }
+}
+
+describe("DerpWorkerProxy", () => {
+    let worker;
+    let proxy;
+
+    beforeEach(() => {
+        worker = new MockWorker();
+        proxy = new DerpWorkerProxy(worker);
+    });
+
+    it("should forward calls and resolve replies", async () => {
+        const statsPromise = proxy.getStats();
+        const [message] = worker.postMessage.firstCall.args;
+        assert.strictEqual(message.method, "getStats");
+
+        worker.reply({ id: message.id, result: { packets_sent: 3 } });
+        assert.deepStrictEqual(await statsPromise, { packets_sent: 3 });
+    });
+
+    it("should reject with the error kind", async () => {
+        const connectPromise = proxy.connect("wss://test.example.com");
+        const [message] = worker.postMessage.firstCall.args;
+
+        worker.reply({ id: message.id, error: { name: "WebSocketError", message: "failed" } });
+        await assert.rejects(connectPromise, { name: "WebSocketError", message: "failed" });
+    });
+
+    it("should transfer whole packet buffers", () => {
+        const packet = new Uint8Array([1, 2, 3]);
+        proxy.sendPacket(packet);
+
+        const [message, transfer] = worker.postMessage.firstCall.args;
+        assert.strictEqual(message.args[0], packet);
+        assert.deepStrictEqual(transfer, [packet.buffer]);
+    });
+
+    it("should copy packets that are views into a larger buffer", () => {
+        const memory = new Uint8Array(16);
+        const packet = memory.subarray(4, 8);
+        proxy.sendPacket(packet);
+
+        const [message, transfer] = worker.postMessage.firstCall.args;
+        assert.notStrictEqual(message.args[0].buffer, memory.buffer);
+        assert.notStrictEqual(transfer[0], memory.buffer);
+    });
+
+    it("should dispatch events to listeners", () => {
+        const callback = sinon.spy();
+        proxy.on("packet", callback);
+
+        const packet = new Uint8Array([1, 2, 3]);
+        worker.reply({ event: "packet", payload: packet });
+        assert(callback.calledOnceWith(packet));
+
+        assert.strictEqual(proxy.off("packet", callback), true);
+        worker.reply({ event: "packet", payload: packet });
+        assert(callback.calledOnce);
+    });
+
+    it("should reject pending calls on terminate", async () => {
+        const drainPromise = proxy.drain();
+        proxy.terminate();
+        await assert.rejects(drainPromise, /Worker terminated/);
+        assert(worker.terminate.calledOnce);
+    });
+});
@@ -0,0 +1,30 @@
+#!/bin/sh
+# Builds derp-wasm for wasm with each optional feature left out in turn,
+# then with none of them, and prints each size against the default build.
+# Features off by default are measured added to it instead.
+set -e
+cd "$(dirname "$0")/../crates/derp-wasm"
+
+target=wasm32-unknown-unknown
+wasm="${CARGO_TARGET_DIR:-../../target}/$target/minimal/derp_wasm.wasm"
+features="compression base64 uuid ratchet siv passphrase"
+extra_features="pq"
+
+size() {
+    cargo build --quiet --lib --target $target --profile minimal "$@"
+    wc -c < "$wasm"
+}
+
+default=$(size)
+printf '%-20s %9d bytes\n' default "$default"
+for feature in $features; do
+    others=$(echo $features | tr ' ' '\n' | grep -vx "$feature" | paste -sd, -)
+    bytes=$(size --no-default-features --features "$others")
+    printf '%-20s %9d bytes (%+d)\n' "without $feature" "$bytes" $((bytes - default))
+done
+for feature in $extra_features; do
+    bytes=$(size --features "$feature")
+    printf '%-20s %9d bytes (%+d)\n' "with $feature" "$bytes" $((bytes - default))
+done
+bytes=$(size --no-default-features)
+printf '%-20s %9d bytes (%+d)\n' minimal "$bytes" $((bytes - default))